@@ -6,9 +6,10 @@ extern crate env_logger;
 extern crate futures;
 extern crate ire;
 extern crate tokio;
+extern crate tokio_signal;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
-use futures::{Future, Sink};
+use futures::{future::lazy, Future, Sink, Stream};
 use ire::{
     data, i2np,
     netdb::reseed::HttpsReseeder,
@@ -18,6 +19,7 @@ use ire::{
     },
     transport,
 };
+use std::io;
 
 fn main() {
     env_logger::init();
@@ -97,13 +99,40 @@ fn cli_router(args: &ArgMatches) -> i32 {
     };
 
     let mut r = builder.build().unwrap();
+    let shutdown = r.shutdown_handle();
 
     let runner = r.start();
 
-    tokio::run(runner.map_err(|_| ()));
+    tokio::run(lazy(move || {
+        // Shut down gracefully when asked to
+        let signal = shutdown_signal().then(move |res| {
+            match res {
+                Ok(()) => {
+                    info!("Received shutdown signal");
+                    shutdown.shutdown();
+                }
+                Err(e) => error!("Failed to listen for shutdown signals: {}", e),
+            }
+            Ok(())
+        });
+        runner.join(signal).map(|_| ())
+    }));
     0
 }
 
+/// Resolves once the process receives SIGINT, or SIGTERM on Unix.
+fn shutdown_signal() -> impl Future<Item = (), Error = io::Error> {
+    let ctrl_c = tokio_signal::ctrl_c().flatten_stream();
+    #[cfg(unix)]
+    let signals = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        ctrl_c.select(Signal::new(SIGTERM).flatten_stream().map(|_| ()))
+    };
+    #[cfg(not(unix))]
+    let signals = ctrl_c;
+    signals.into_future().map(|_| ()).map_err(|(e, _)| e)
+}
+
 fn cli_client(args: &ArgMatches) -> i32 {
     let rsk = data::RouterSecretKeys::from_file(args.value_of("routerKeys").unwrap()).unwrap();
     let peer_ri = data::RouterInfo::from_file(args.value_of("peerInfo").unwrap()).unwrap();
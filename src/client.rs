@@ -0,0 +1,133 @@
+//! Destinations hosted by the router on behalf of local clients.
+//!
+//! Each local destination has its own tunnel pool, rather than sharing the
+//! router's exploratory tunnels, so that the traffic of the services hosted by
+//! a router can't be linked to each other, or to the router itself.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::data::{dest::DestinationSecretKeys, Destination, Hash};
+use crate::router::types::OutboundTunnelPool;
+use crate::tunnel::TunnelPool;
+
+/// A destination that is hosted by this router.
+pub struct LocalDestination {
+    keys: DestinationSecretKeys,
+    ob_tunnels: Arc<TunnelPool>,
+}
+
+impl LocalDestination {
+    /// Creates a destination with the given keys, and an empty pool that
+    /// wants `outbound_tunnels` live tunnels.
+    pub fn new(keys: DestinationSecretKeys, outbound_tunnels: usize) -> Self {
+        LocalDestination {
+            keys,
+            ob_tunnels: Arc::new(TunnelPool::new(outbound_tunnels)),
+        }
+    }
+
+    pub fn destination(&self) -> &Destination {
+        &self.keys.dest
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.keys.dest.hash()
+    }
+
+    /// Returns the pool of outbound tunnels that this destination's messages
+    /// are sent through.
+    pub fn outbound_tunnels(&self) -> Arc<TunnelPool> {
+        self.ob_tunnels.clone()
+    }
+}
+
+/// The destinations hosted by this router, by hash.
+#[derive(Default)]
+pub struct LocalDestinations(Mutex<HashMap<Hash, Arc<LocalDestination>>>);
+
+impl LocalDestinations {
+    pub fn new() -> Self {
+        LocalDestinations::default()
+    }
+
+    /// Starts hosting a destination, returning it.
+    ///
+    /// If we already host a destination with the same keys, it is replaced,
+    /// and its tunnels are released.
+    pub fn add(
+        &self,
+        keys: DestinationSecretKeys,
+        outbound_tunnels: usize,
+    ) -> Arc<LocalDestination> {
+        let dest = Arc::new(LocalDestination::new(keys, outbound_tunnels));
+        if let Some(old) = self.0.lock().unwrap().insert(dest.hash(), dest.clone()) {
+            old.ob_tunnels.shutdown();
+        }
+        dest
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<Arc<LocalDestination>> {
+        self.0.lock().unwrap().get(hash).cloned()
+    }
+
+    /// Stops hosting a destination, releasing its tunnels.
+    pub fn remove(&self, hash: &Hash) -> Option<Arc<LocalDestination>> {
+        let dest = self.0.lock().unwrap().remove(hash);
+        if let Some(ref dest) = dest {
+            dest.ob_tunnels.shutdown();
+        }
+        dest
+    }
+
+    /// Returns the number of destinations we host.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalDestinations;
+    use crate::data::{dest::DestinationSecretKeys, Hash, RouterInfo, RouterSecretKeys, TunnelId};
+    use crate::i2np::{Message, MessagePayload};
+    use crate::router::types::OutboundTunnelPool;
+
+    #[test]
+    fn independent_tunnel_pools() {
+        let dests = LocalDestinations::new();
+        let alice = dests.add(DestinationSecretKeys::new(), 2);
+        let bob = dests.add(DestinationSecretKeys::new(), 3);
+        assert_eq!(dests.len(), 2);
+        assert!(dests.get(&alice.hash()).is_some());
+
+        // Each destination builds its own tunnels
+        assert_eq!(alice.outbound_tunnels().tunnels_to_build(), 2);
+        assert_eq!(bob.outbound_tunnels().tunnels_to_build(), 3);
+
+        let first_hop = RouterInfo::new(RouterSecretKeys::new().rid);
+        assert!(alice.outbound_tunnels().add(first_hop.clone(), TunnelId(1), vec![]));
+        assert!(bob.outbound_tunnels().add(first_hop.clone(), TunnelId(2), vec![]));
+        assert!(bob.outbound_tunnels().add(first_hop, TunnelId(3), vec![]));
+        assert_eq!(alice.outbound_tunnels().live_tunnels(), 1);
+        assert_eq!(bob.outbound_tunnels().live_tunnels(), 2);
+        let (_, msgs) = alice
+            .outbound_tunnels()
+            .wrap_message(&Hash([1; 32]), &Message::dummy_data())
+            .unwrap();
+        match msgs[0].payload {
+            MessagePayload::TunnelData(ref td) => assert_eq!(td.tid, TunnelId(1)),
+            ref payload => panic!("Unexpected payload: {:?}", payload),
+        }
+
+        // Removing a destination only releases its own tunnels
+        assert!(dests.remove(&bob.hash()).is_some());
+        assert!(dests.get(&bob.hash()).is_none());
+        assert_eq!(bob.outbound_tunnels().live_tunnels(), 0);
+        assert_eq!(alice.outbound_tunnels().live_tunnels(), 1);
+    }
+}
@@ -11,6 +11,11 @@ lazy_static! {
         spec.padding = Some('=');
         spec.encoding().unwrap()
     };
+    pub static ref I2P_BASE32: Encoding = {
+        let mut spec = Specification::new();
+        spec.symbols.push_str("abcdefghijklmnopqrstuvwxyz234567");
+        spec.encoding().unwrap()
+    };
 }
 
 // Sig types
@@ -0,0 +1,300 @@
+//! Key blinding and layer encryption for encrypted LeaseSets.
+//!
+//! A Destination with an Ed25519 signing key can publish its LeaseSet under
+//! a blinded key that changes every day. Only clients that already know the
+//! Destination can derive the blinded key, find the encrypted LeaseSet in
+//! the network database, and derive the keys needed to decrypt it.
+
+use c2_chacha::guts::ChaCha;
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE,
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    scalar::Scalar,
+};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, Rng};
+use sha2::{Digest, Sha256, Sha512};
+use signatory::{
+    ed25519,
+    signature::{Signature as SignatorySignature, Verifier},
+};
+use signatory_dalek::Ed25519Verifier;
+use std::fmt;
+
+use super::{Error, SigType, SigningPrivateKey, SigningPublicKey};
+
+/// Signature type code for RedDSA_SHA512_Ed25519, the type of blinded keys.
+pub const BLINDED_SIG_TYPE: u16 = 11;
+
+pub const BLINDED_KEY_SIZE: usize = 32;
+pub const BLINDED_SIGNATURE_SIZE: usize = 64;
+
+const LAYER_SALT_SIZE: usize = 32;
+const CHACHA20_DOUBLE_ROUNDS: u32 = 10;
+
+/// The blinded public key that an encrypted LeaseSet is signed with.
+#[derive(Clone, PartialEq)]
+pub struct BlindedPublicKey(pub(crate) [u8; BLINDED_KEY_SIZE]);
+
+impl BlindedPublicKey {
+    /// Blinds the signing key of a Destination for the given UTC date, in
+    /// the form `yyyyMMdd`.
+    ///
+    /// Returns `Error::UnsupportedSigType` if the key is not Ed25519.
+    pub fn new(key: &SigningPublicKey, date: &str) -> Result<Self, Error> {
+        let point = ed25519_point(key)?;
+        let alpha = generate_alpha(key, date);
+        let blinded = point + &alpha * &ED25519_BASEPOINT_TABLE;
+        Ok(BlindedPublicKey(blinded.compress().to_bytes()))
+    }
+
+    pub fn from_bytes(data: &[u8; BLINDED_KEY_SIZE]) -> Self {
+        BlindedPublicKey(*data)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; BLINDED_KEY_SIZE] {
+        &self.0
+    }
+
+    /// Verifies a RedDSA signature. These are checked exactly like Ed25519
+    /// signatures.
+    pub fn verify(
+        &self,
+        message: &[u8],
+        signature: &[u8; BLINDED_SIGNATURE_SIZE],
+    ) -> Result<(), Error> {
+        let pk = ed25519::PublicKey::from_bytes(&self.0).ok_or(Error::InvalidKey)?;
+        let sig =
+            ed25519::Signature::from_bytes(&signature[..]).map_err(|_| Error::InvalidSignature)?;
+        Ed25519Verifier::from(&pk)
+            .verify(message, &sig)
+            .map_err(|_| Error::InvalidSignature)
+    }
+}
+
+impl fmt::Debug for BlindedPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlindedPublicKey(")?;
+        for b in &self.0[..] {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The private key matching a `BlindedPublicKey`.
+pub struct BlindedSigningKey {
+    scalar: Scalar,
+    public: BlindedPublicKey,
+}
+
+impl BlindedSigningKey {
+    /// Blinds the signing key of a Destination for the given UTC date, in
+    /// the form `yyyyMMdd`.
+    ///
+    /// Returns `Error::UnsupportedSigType` if the key is not Ed25519.
+    pub fn new(sk: &SigningPrivateKey, date: &str) -> Result<Self, Error> {
+        if sk.sig_type() != SigType::Ed25519 {
+            return Err(Error::UnsupportedSigType);
+        }
+        let pk = SigningPublicKey::from_secret(sk)?;
+
+        // The Ed25519 secret scalar is the clamped first half of the hashed seed.
+        let h = Sha512::digest(sk.as_bytes());
+        let mut a = [0; 32];
+        a.copy_from_slice(&h[..32]);
+        a[0] &= 248;
+        a[31] &= 127;
+        a[31] |= 64;
+
+        let scalar = Scalar::from_bytes_mod_order(a) + generate_alpha(&pk, date);
+        let public = BlindedPublicKey((&scalar * &ED25519_BASEPOINT_TABLE).compress().to_bytes());
+        Ok(BlindedSigningKey { scalar, public })
+    }
+
+    pub fn public_key(&self) -> &BlindedPublicKey {
+        &self.public
+    }
+
+    /// Creates a RedDSA signature over `message`.
+    pub fn sign(&self, message: &[u8]) -> [u8; BLINDED_SIGNATURE_SIZE] {
+        // RedDSA mixes fresh randomness into the nonce, instead of deriving
+        // it from the secret key like Ed25519 does.
+        let mut t = [0; 80];
+        OsRng.fill(&mut t[..]);
+        let r = Scalar::from_hash(
+            Sha512::new()
+                .chain(&t[..])
+                .chain(&self.public.0[..])
+                .chain(message),
+        );
+        let big_r = (&r * &ED25519_BASEPOINT_TABLE).compress();
+        let k = Scalar::from_hash(
+            Sha512::new()
+                .chain(big_r.as_bytes())
+                .chain(&self.public.0[..])
+                .chain(message),
+        );
+        let s = r + k * self.scalar;
+
+        let mut sig = [0; BLINDED_SIGNATURE_SIZE];
+        sig[..32].copy_from_slice(big_r.as_bytes());
+        sig[32..].copy_from_slice(s.as_bytes());
+        sig
+    }
+}
+
+fn ed25519_point(key: &SigningPublicKey) -> Result<EdwardsPoint, Error> {
+    match key {
+        SigningPublicKey::Ed25519(pk) => {
+            let mut bytes = [0; 32];
+            bytes.copy_from_slice(pk.as_bytes());
+            CompressedEdwardsY(bytes)
+                .decompress()
+                .ok_or(Error::InvalidKey)
+        }
+        _ => Err(Error::UnsupportedSigType),
+    }
+}
+
+/// H(p, d) from the specification.
+fn hash(personalization: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(personalization);
+    for d in data {
+        hasher.input(d);
+    }
+    let mut out = [0; 32];
+    out.copy_from_slice(&hasher.result());
+    out
+}
+
+/// The unblinded key, its type, and the blinded key type.
+fn key_data(key: &SigningPublicKey) -> Vec<u8> {
+    let mut data = Vec::from(key.as_bytes());
+    data.extend_from_slice(&key.sig_type().code().to_be_bytes());
+    data.extend_from_slice(&BLINDED_SIG_TYPE.to_be_bytes());
+    data
+}
+
+fn generate_alpha(key: &SigningPublicKey, date: &str) -> Scalar {
+    let salt = hash(b"I2PGenerateAlpha", &[&key_data(key)[..]]);
+    let mut seed = [0; 64];
+    Hkdf::<Sha256>::new(Some(&salt), date.as_bytes())
+        .expand(b"i2pblinding1", &mut seed)
+        .expect("64 bytes is a valid HKDF output length");
+    Scalar::from_bytes_mod_order_wide(&seed)
+}
+
+/// Derives the subcredential that the layer keys of an encrypted LeaseSet
+/// are bound to. Only clients that know the unblinded key can compute it.
+pub fn subcredential(key: &SigningPublicKey, blinded: &BlindedPublicKey) -> [u8; 32] {
+    let credential = hash(b"credential", &[&key_data(key)[..]]);
+    hash(b"subcredential", &[&credential[..], &blinded.0[..]])
+}
+
+/// Applies the ChaCha20 keystream for a layer, keyed from `salt`, `input`
+/// and `info`.
+fn apply_layer_keystream(salt: &[u8], input: &[u8], info: &[u8], data: &mut [u8]) {
+    let mut keys = [0; 44];
+    Hkdf::<Sha256>::new(Some(salt), input)
+        .expand(info, &mut keys)
+        .expect("44 bytes is a valid HKDF output length");
+
+    let mut state = ChaCha::new(array_ref![keys, 0, 32], &keys[32..]);
+    // The block counter starts at 1. It shares a stream parameter with the
+    // first word of the nonce.
+    let nonce = state.get_stream_param(0) & 0xffff_ffff_0000_0000;
+    state.set_stream_param(0, nonce | 1);
+
+    let mut block = [0; 64];
+    for chunk in data.chunks_mut(64) {
+        state.refill(CHACHA20_DOUBLE_ROUNDS, &mut block);
+        for (b, k) in chunk.iter_mut().zip(block.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+/// Encrypts one layer of an encrypted LeaseSet under keys derived from
+/// `input` and `info`, returning the random salt followed by the ciphertext.
+pub fn encrypt_layer(input: &[u8], info: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut salt = [0; LAYER_SALT_SIZE];
+    OsRng.fill(&mut salt);
+
+    let mut data = Vec::with_capacity(LAYER_SALT_SIZE + plaintext.len());
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(plaintext);
+    apply_layer_keystream(&salt, input, info, &mut data[LAYER_SALT_SIZE..]);
+    data
+}
+
+/// Decrypts one layer of an encrypted LeaseSet.
+///
+/// The layers are not authenticated; callers must check that the plaintext
+/// parses and is correctly signed.
+pub fn decrypt_layer(input: &[u8], info: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    if ciphertext.len() < LAYER_SALT_SIZE {
+        return Err(Error::InvalidCiphertext);
+    }
+    let (salt, ciphertext) = ciphertext.split_at(LAYER_SALT_SIZE);
+    let mut data = Vec::from(ciphertext);
+    apply_layer_keystream(salt, input, info, &mut data);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt_layer, encrypt_layer, subcredential, BlindedPublicKey, BlindedSigningKey};
+    use crate::crypto::{Error, SigningPrivateKey, SigningPublicKey};
+
+    #[test]
+    fn blinded_keys_match() {
+        let sk = SigningPrivateKey::new();
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+
+        let blinded_sk = BlindedSigningKey::new(&sk, "20191017").unwrap();
+        let blinded_pk = BlindedPublicKey::new(&pk, "20191017").unwrap();
+        assert_eq!(blinded_sk.public_key(), &blinded_pk);
+        assert_ne!(&blinded_pk.0[..], pk.as_bytes());
+
+        // The blinded key changes every day
+        assert_ne!(BlindedPublicKey::new(&pk, "20191018").unwrap(), blinded_pk);
+
+        let msg = b"encrypted LeaseSet";
+        let sig = blinded_sk.sign(msg);
+        assert_eq!(blinded_pk.verify(msg, &sig), Ok(()));
+        assert_eq!(
+            blinded_pk.verify(b"something else", &sig),
+            Err(Error::InvalidSignature)
+        );
+
+        // RedDSA signatures are randomized
+        assert_ne!(&blinded_sk.sign(msg)[..], &sig[..]);
+    }
+
+    #[test]
+    fn layer_round_trip() {
+        let pk = SigningPublicKey::from_secret(&SigningPrivateKey::new()).unwrap();
+        let blinded = BlindedPublicKey::new(&pk, "20191017").unwrap();
+        let input = subcredential(&pk, &blinded);
+
+        let ciphertext = encrypt_layer(&input, b"ELS2_L1K", b"plaintext");
+        assert_eq!(ciphertext.len(), 32 + 9);
+        assert_ne!(&ciphertext[32..], b"plaintext");
+        assert_eq!(
+            decrypt_layer(&input, b"ELS2_L1K", &ciphertext).unwrap(),
+            b"plaintext"
+        );
+
+        // A different key produces garbage
+        assert_ne!(
+            decrypt_layer(&input, b"ELS2_L2K", &ciphertext).unwrap(),
+            b"plaintext"
+        );
+        assert_eq!(
+            decrypt_layer(&input, b"ELS2_L1K", &ciphertext[..31]),
+            Err(Error::InvalidCiphertext)
+        );
+    }
+}
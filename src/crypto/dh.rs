@@ -1,5 +1,6 @@
 use num_bigint::BigUint;
 use rand::{rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
 use std::iter::repeat;
 
 use crate::constants::{ELGAMAL_G, ELGAMAL_P};
@@ -25,29 +26,52 @@ impl DHSessionKeyBuilder {
         rectify(&self.dh_pub, 256)
     }
 
-    pub fn build_session_key(&self, peer_pub: &[u8; 256]) -> SessionKey {
-        // Calculate the exchanged DH key
+    /// Calculates the exchanged DH key, represented as a positive minimal-length
+    /// two's-complement big-endian byte array. If the most significant bit is 1,
+    /// a zero-byte is prepended (to match Java's BigInteger.toByteArray()
+    /// representation).
+    fn exchanged_key(&self, peer_pub: &[u8; 256]) -> Vec<u8> {
         let peer_pub = BigUint::from_bytes_be(peer_pub);
         let dh_key = peer_pub.modpow(&self.dh_priv, &ELGAMAL_P);
-        // Represent the exchanged key as a positive minimal-length two's-complement
-        // big-endian byte array. If most significant bit is 1, prepend a zero-byte
-        // (to match Java's BigInteger.toByteArray() representation).
         let mut buf = dh_key.to_bytes_be();
         if buf[0] & 0x80 != 0 {
             buf.insert(0, 0x00);
         }
-        // If that byte array is less than 32 bytes, append 0x00 bytes to extend to
-        // 32 bytes. This is vanishingly unlikely, but have to do it for compatibility.
-        let length = buf.len();
-        if length < 32 {
-            buf.extend(repeat(0).take(32 - length));
+        buf
+    }
+
+    pub fn build_session_key(&self, peer_pub: &[u8; 256]) -> SessionKey {
+        session_key(self.exchanged_key(peer_pub))
+    }
+
+    /// Derives the session key and MAC key used by SSU.
+    ///
+    /// The MAC key is the 32 bytes of the exchanged key that follow the session
+    /// key, or the SHA-256 hash of the exchanged key if it is too short.
+    pub fn build_ssu_keys(&self, peer_pub: &[u8; 256]) -> (SessionKey, SessionKey) {
+        let buf = self.exchanged_key(peer_pub);
+        let mut mac_key = [0u8; 32];
+        if buf.len() >= 64 {
+            mac_key.copy_from_slice(&buf[32..64]);
+        } else {
+            mac_key.copy_from_slice(&Sha256::digest(&buf));
         }
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&buf[0..32]);
-        SessionKey(key)
+        (session_key(buf), SessionKey(mac_key))
     }
 }
 
+fn session_key(mut buf: Vec<u8>) -> SessionKey {
+    // If the exchanged key is less than 32 bytes, append 0x00 bytes to extend to
+    // 32 bytes. This is vanishingly unlikely, but have to do it for compatibility.
+    let length = buf.len();
+    if length < 32 {
+        buf.extend(repeat(0).take(32 - length));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&buf[0..32]);
+    SessionKey(key)
+}
+
 #[cfg(test)]
 mod tests {
     use num_bigint::BigUint;
@@ -201,4 +225,19 @@ mod tests {
             assert_eq!(session_key.0, tv.session_key.0);
         }
     }
+
+    #[test]
+    fn build_ssu_keys() {
+        let alice = DHSessionKeyBuilder::new();
+        let bob = DHSessionKeyBuilder::new();
+        let mut alice_pub = [0; 256];
+        let mut bob_pub = [0; 256];
+        alice_pub.copy_from_slice(&alice.get_pub());
+        bob_pub.copy_from_slice(&bob.get_pub());
+
+        let (session_key, mac_key) = alice.build_ssu_keys(&bob_pub);
+        assert_eq!(bob.build_ssu_keys(&alice_pub), (session_key.clone(), mac_key.clone()));
+        assert_eq!(session_key, alice.build_session_key(&bob_pub));
+        assert_ne!(session_key, mac_key);
+    }
 }
@@ -1,13 +1,18 @@
 //! Cryptographic types and operations.
 
 use aes::{self, cipher::generic_array::GenericArray as AesGenericArray};
-use block_modes::{block_padding::NoPadding, Cbc, BlockMode};
+use block_modes::{
+    block_padding::{NoPadding, Pkcs7},
+    BlockMode, Cbc,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
 use nom::Err;
-use rand::Rng;
+use rand::{rngs::OsRng, Rng};
 use ring::signature::{
     UnparsedPublicKey, RSA_PKCS1_2048_8192_SHA256_RAW, RSA_PKCS1_3072_8192_SHA384_RAW,
     RSA_PKCS1_4096_8192_SHA512_RAW,
 };
+use ring::{hmac, pbkdf2};
 use signatory::{
     ecdsa::{
         self,
@@ -22,6 +27,7 @@ use signatory::{
 use signatory_dalek::{Ed25519Signer, Ed25519Verifier};
 use signatory_ring::ecdsa::{p256, p384};
 use std::fmt;
+use std::num::NonZeroU32;
 
 use crate::constants;
 use crate::util::fmt_colon_delimited_hex;
@@ -29,16 +35,21 @@ use crate::util::fmt_colon_delimited_hex;
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) mod frame;
 
+pub mod blinding;
 pub(crate) mod dh;
 mod dsa;
 pub(crate) mod elgamal;
 pub(crate) mod math;
+pub(crate) mod verify;
+pub mod x25519;
 
 pub(crate) const AES_BLOCK_SIZE: usize = 16;
 
 /// Cryptographic errors
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Error {
+    CertificateMismatch,
+    DecryptionFailed,
     InvalidCiphertext,
     InvalidKey,
     InvalidMessage,
@@ -46,12 +57,15 @@ pub enum Error {
     NoSignature,
     SigningFailed,
     TypeMismatch,
+    UnsupportedSigType,
 }
 
 #[cfg_attr(tarpaulin, skip)]
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Error::CertificateMismatch => "Key certificate doesn't match signing key".fmt(f),
+            Error::DecryptionFailed => "Wrong passphrase or corrupted data".fmt(f),
             Error::InvalidCiphertext => "Invalid ciphertext".fmt(f),
             Error::InvalidKey => "Invalid cryptographic key".fmt(f),
             Error::InvalidMessage => "Invalid message".fmt(f),
@@ -59,6 +73,7 @@ impl fmt::Display for Error {
             Error::NoSignature => "No signature".fmt(f),
             Error::SigningFailed => "Failed to create a signature".fmt(f),
             Error::TypeMismatch => "Signature type doesn't match key type".fmt(f),
+            Error::UnsupportedSigType => "Unsupported signature type".fmt(f),
         }
     }
 }
@@ -276,7 +291,15 @@ impl SigningPublicKey {
 }
 
 impl SigningPublicKey {
+    /// Parses a public key of the given type.
+    ///
+    /// Returns `Error::InvalidKey` if the length of `data` doesn't match the
+    /// key length for `sig_type`.
     pub fn from_bytes(sig_type: SigType, data: &[u8]) -> Result<Self, Error> {
+        if data.len() != sig_type.pubkey_len() as usize {
+            return Err(Error::InvalidKey);
+        }
+
         match sig_type {
             SigType::DsaSha1 => Ok(SigningPublicKey::DsaSha1(dsa::DsaPublicKey::from_bytes(
                 data,
@@ -350,6 +373,65 @@ impl SigningPublicKey {
     }
 }
 
+/// Returns whether an encoded Ed25519 point is valid and not of small order.
+fn ed25519_batchable(point: &[u8]) -> bool {
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(point);
+    CompressedEdwardsY(bytes)
+        .decompress()
+        .map(|p| !p.is_small_order())
+        .unwrap_or(false)
+}
+
+/// Verifies a set of signatures, returning the result for each.
+///
+/// Ed25519 signatures are verified together in a single batch, which is much
+/// faster than verifying them one at a time. If the batch fails, they are
+/// verified individually to find the invalid ones. Signatures of other types
+/// are always verified individually.
+///
+/// The batch equation can disagree with single verification on signatures
+/// whose public key or R is of small order, so those are always
+/// verified individually. A key with a mixed-order component could still make
+/// the two disagree, but only for signatures made with that key; as both are
+/// sound for honestly generated keys, this can't be used to forge a signature
+/// under someone else's key.
+pub fn verify_batch(items: &[(&SigningPublicKey, &[u8], &Signature)]) -> Vec<Result<(), Error>> {
+    let mut results = Vec::with_capacity(items.len());
+    let mut batch = vec![];
+    let mut messages = vec![];
+    let mut signatures = vec![];
+    let mut public_keys = vec![];
+    for (i, &(pk, message, signature)) in items.iter().enumerate() {
+        if let (SigningPublicKey::Ed25519(key), Signature::Ed25519(s)) = (pk, signature) {
+            let s = s.to_bytes();
+            if ed25519_batchable(key.as_bytes()) && ed25519_batchable(&s[..32]) {
+                let key = ed25519_dalek::PublicKey::from_bytes(key.as_bytes());
+                let s = ed25519_dalek::Signature::from_bytes(&s[..]);
+                if let (Ok(key), Ok(s)) = (key, s) {
+                    batch.push(i);
+                    messages.push(message);
+                    signatures.push(s);
+                    public_keys.push(key);
+                    results.push(Ok(()));
+                    continue;
+                }
+            }
+        }
+        results.push(pk.verify(message, signature));
+    }
+
+    if !batch.is_empty()
+        && ed25519_dalek::verify_batch(&messages, &signatures, &public_keys).is_err()
+    {
+        for i in batch {
+            let (pk, message, signature) = items[i];
+            results[i] = pk.verify(message, signature);
+        }
+    }
+    results
+}
+
 /// The private component of a signature keypair.
 pub enum SigningPrivateKey {
     DsaSha1,
@@ -361,19 +443,26 @@ pub enum SigningPrivateKey {
 
 impl SigningPrivateKey {
     pub fn new() -> Self {
-        SigningPrivateKey::with_type(SigType::Ed25519)
+        SigningPrivateKey::Ed25519(ed25519::Seed::generate())
+    }
+
+    pub fn sig_type(&self) -> SigType {
+        match *self {
+            SigningPrivateKey::DsaSha1 => SigType::DsaSha1,
+            SigningPrivateKey::EcdsaSha256P256 => SigType::EcdsaSha256P256,
+            SigningPrivateKey::EcdsaSha384P384 => SigType::EcdsaSha384P384,
+            SigningPrivateKey::EcdsaSha512P521 => SigType::EcdsaSha512P521,
+            SigningPrivateKey::Ed25519(_) => SigType::Ed25519,
+        }
     }
 
-    pub fn with_type(sig_type: SigType) -> Self {
+    /// Generates a new key of the given type.
+    ///
+    /// Returns `Error::UnsupportedSigType` if we can't sign with that type.
+    pub fn with_type(sig_type: SigType) -> Result<Self, Error> {
         match sig_type {
-            SigType::DsaSha1 => unimplemented!(),
-            SigType::EcdsaSha256P256 => unimplemented!(),
-            SigType::EcdsaSha384P384 => unimplemented!(),
-            SigType::EcdsaSha512P521 => unimplemented!(),
-            SigType::Rsa2048Sha256 | SigType::Rsa3072Sha384 | SigType::Rsa4096Sha512 => {
-                panic!("Online signing not supported")
-            }
-            SigType::Ed25519 => SigningPrivateKey::Ed25519(ed25519::Seed::generate()),
+            SigType::Ed25519 => Ok(SigningPrivateKey::new()),
+            _ => Err(Error::UnsupportedSigType),
         }
     }
 
@@ -436,6 +525,7 @@ pub enum OfflineSigningPublicKey {
     Rsa2048Sha256(Vec<u8>),
     Rsa3072Sha384(Vec<u8>),
     Rsa4096Sha512(Vec<u8>),
+    Ed25519(ed25519::PublicKey),
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -458,6 +548,11 @@ impl fmt::Debug for OfflineSigningPublicKey {
                 fmt_colon_delimited_hex(f, key)?;
                 write!(f, ")")?;
             }
+            OfflineSigningPublicKey::Ed25519(key) => {
+                write!(f, "Ed25519(")?;
+                fmt_colon_delimited_hex(f, key.as_bytes())?;
+                write!(f, ")")?;
+            }
         };
         write!(f, ")")
     }
@@ -476,6 +571,9 @@ impl OfflineSigningPublicKey {
                     _ => unreachable!(),
                 })
             }
+            SigType::Ed25519 => ed25519::PublicKey::from_bytes(data)
+                .map(OfflineSigningPublicKey::Ed25519)
+                .ok_or(Error::InvalidKey),
             _ => panic!("Invalid offline SigType"),
         }
     }
@@ -497,6 +595,11 @@ impl OfflineSigningPublicKey {
                     .verify(message, &s)
                     .map_err(|_| Error::InvalidSignature)
             }
+            (OfflineSigningPublicKey::Ed25519(pk), Signature::Ed25519(s)) => {
+                Ed25519Verifier::from(pk)
+                    .verify(message, s)
+                    .map_err(|_| Error::InvalidSignature)
+            }
             _ => Err(Error::TypeMismatch),
         }
     }
@@ -558,6 +661,23 @@ impl Signature {
             Signature::Unsupported(ref s) => s.clone(),
         }
     }
+
+    /// Parses a base64-encoded signature of the given type.
+    pub fn from_base64(data: &str, sig_type: SigType) -> Result<Self, Error> {
+        let bytes = constants::I2P_BASE64
+            .decode(data.as_bytes())
+            .map_err(|_| Error::InvalidSignature)?;
+        if bytes.len() != sig_type.sig_len() as usize {
+            return Err(Error::InvalidSignature);
+        }
+        Signature::from_bytes(sig_type, &bytes)
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", constants::I2P_BASE64.encode(&self.to_bytes()))
+    }
 }
 
 /// A symmetric key used for AES-256 encryption.
@@ -626,10 +746,107 @@ impl Aes256 {
     }
 }
 
+const PASSPHRASE_SALT_LEN: usize = 16;
+const PASSPHRASE_MAC_LEN: usize = 32;
+const PASSPHRASE_KDF_ITERATIONS: u32 = 100_000;
+
+/// Derives an AES key and a MAC key from a passphrase.
+fn passphrase_keys(passphrase: &str, salt: &[u8]) -> (SessionKey, hmac::Key) {
+    let mut keys = [0; 64];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PASSPHRASE_KDF_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut keys,
+    );
+    (
+        SessionKey::from_bytes(array_ref![keys, 0, 32]),
+        hmac::Key::new(hmac::HMAC_SHA256, &keys[32..]),
+    )
+}
+
+/// Encrypts data with a key derived from a passphrase.
+///
+/// The output is `salt || IV || ciphertext || MAC`, where the ciphertext is
+/// AES-256-CBC with PKCS#7 padding, and the MAC is HMAC-SHA256 over everything
+/// before it.
+pub(crate) fn encrypt_with_passphrase(passphrase: &str, plaintext: &[u8]) -> Vec<u8> {
+    let mut rng = OsRng;
+    let mut salt = [0; PASSPHRASE_SALT_LEN];
+    let mut iv = [0; AES_BLOCK_SIZE];
+    rng.fill(&mut salt);
+    rng.fill(&mut iv);
+    let (key, mac_key) = passphrase_keys(passphrase, &salt);
+
+    let cipher: Cbc<aes::Aes256, Pkcs7> = Cbc::new_fix(
+        AesGenericArray::from_slice(&key.0),
+        AesGenericArray::from_slice(&iv),
+    );
+    let mut data = Vec::with_capacity(
+        PASSPHRASE_SALT_LEN + 2 * AES_BLOCK_SIZE + plaintext.len() + PASSPHRASE_MAC_LEN,
+    );
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&iv);
+    data.extend_from_slice(&cipher.encrypt_vec(plaintext));
+    let mac = hmac::sign(&mac_key, &data);
+    data.extend_from_slice(mac.as_ref());
+    data
+}
+
+/// Decrypts data that was encrypted with `encrypt_with_passphrase`.
+///
+/// Returns `Error::DecryptionFailed` if the passphrase is wrong or the data has
+/// been modified.
+pub(crate) fn decrypt_with_passphrase(passphrase: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < PASSPHRASE_SALT_LEN + 2 * AES_BLOCK_SIZE + PASSPHRASE_MAC_LEN {
+        return Err(Error::InvalidCiphertext);
+    }
+    let (data, mac) = data.split_at(data.len() - PASSPHRASE_MAC_LEN);
+    let (salt, rest) = data.split_at(PASSPHRASE_SALT_LEN);
+    let (iv, ciphertext) = rest.split_at(AES_BLOCK_SIZE);
+    let (key, mac_key) = passphrase_keys(passphrase, salt);
+
+    hmac::verify(&mac_key, data, mac).map_err(|_| Error::DecryptionFailed)?;
+
+    let cipher: Cbc<aes::Aes256, Pkcs7> = Cbc::new_fix(
+        AesGenericArray::from_slice(&key.0),
+        AesGenericArray::from_slice(iv),
+    );
+    cipher
+        .decrypt_vec(ciphertext)
+        .map_err(|_| Error::InvalidCiphertext)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn signature_base64() {
+        let spk = SigningPrivateKey::new();
+        let sig = spk.sign(b"hello").unwrap();
+        let encoded = sig.to_string();
+        assert_eq!(
+            Signature::from_base64(&encoded, SigType::Ed25519),
+            Ok(sig.clone())
+        );
+
+        // The length must match the signature type
+        assert_eq!(
+            Signature::from_base64(&encoded, SigType::EcdsaSha384P384),
+            Err(Error::InvalidSignature)
+        );
+        assert_eq!(
+            Signature::from_base64(&encoded[..40], SigType::Ed25519),
+            Err(Error::InvalidSignature)
+        );
+        assert_eq!(
+            Signature::from_base64("not base64!", SigType::Ed25519),
+            Err(Error::InvalidSignature)
+        );
+    }
+
     #[test]
     fn test_sig_type_pad_len() {
         assert_eq!(SigType::DsaSha1.pad_len(EncType::ElGamal2048), 0);
@@ -657,6 +874,71 @@ mod tests {
         assert_eq!(SigType::Ed25519.extra_data_len(EncType::ElGamal2048), 0);
     }
 
+    #[test]
+    fn signing_public_key_from_bytes() {
+        let dsa_key = [0x11; 128];
+        match SigningPublicKey::from_bytes(SigType::DsaSha1, &dsa_key) {
+            Ok(SigningPublicKey::DsaSha1(_)) => (),
+            _ => panic!("Expected a DSA-SHA1 key"),
+        }
+        for len in &[0, 127, 129] {
+            let data = vec![0x11; *len];
+            assert_eq!(
+                SigningPublicKey::from_bytes(SigType::DsaSha1, &data).err(),
+                Some(Error::InvalidKey)
+            );
+        }
+
+        let spk = SigningPrivateKey::with_type(SigType::Ed25519).unwrap();
+        let ed_key = SigningPublicKey::from_secret(&spk).unwrap();
+        match SigningPublicKey::from_bytes(SigType::Ed25519, ed_key.as_bytes()) {
+            Ok(SigningPublicKey::Ed25519(_)) => (),
+            _ => panic!("Expected an Ed25519 key"),
+        }
+        for len in &[0, 31, 33] {
+            let data = vec![0x11; *len];
+            assert_eq!(
+                SigningPublicKey::from_bytes(SigType::Ed25519, &data).err(),
+                Some(Error::InvalidKey)
+            );
+        }
+
+        // A key that is valid for one type is rejected for another
+        assert_eq!(
+            SigningPublicKey::from_bytes(SigType::Ed25519, &dsa_key).err(),
+            Some(Error::InvalidKey)
+        );
+        assert_eq!(
+            SigningPublicKey::from_bytes(SigType::DsaSha1, ed_key.as_bytes()).err(),
+            Some(Error::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn verify_batch_small_order() {
+        let spk = SigningPrivateKey::with_type(SigType::Ed25519).unwrap();
+        let pk = SigningPublicKey::from_secret(&spk).unwrap();
+        let sig = spk.sign(b"hello").unwrap();
+
+        // The identity point as both the public key and R, with s = 0
+        let mut identity = [0; 64];
+        identity[0] = 1;
+        let weak_pk = SigningPublicKey::from_bytes(SigType::Ed25519, &identity[..32]).unwrap();
+        let weak_sig = Signature::from_bytes(SigType::Ed25519, &identity).unwrap();
+
+        // The results match those of verifying individually
+        let items = vec![
+            (&pk, &b"hello"[..], &sig),
+            (&weak_pk, &b"hello"[..], &weak_sig),
+            (&pk, &b"world"[..], &sig),
+        ];
+        let individual: Vec<_> = items
+            .iter()
+            .map(|&(pk, message, signature)| pk.verify(message, signature))
+            .collect();
+        assert_eq!(verify_batch(&items), individual);
+    }
+
     #[test]
     fn aes_256_cbc_test_vectors() {
         struct TestVector {
@@ -0,0 +1,148 @@
+//! Signature verification off the reactor.
+//!
+//! Verifying signatures (particularly DSA and ECDSA ones) is expensive enough
+//! that a burst of them would stall every other task on the reactor, so they
+//! are run on a small dedicated thread pool instead. The number of pending
+//! verifications is bounded; once the bound is reached, further verifications
+//! are refused, and callers should drop the work rather than queue it.
+
+use futures::{future, Future};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio_threadpool::{Builder, ThreadPool};
+
+/// Default number of threads that verify signatures.
+const DEFAULT_VERIFY_THREADS: usize = 2;
+
+/// Default maximum number of verifications waiting or in progress.
+const DEFAULT_MAX_PENDING: usize = 256;
+
+/// Releases a pending slot when the verification completes or is dropped.
+struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A bounded queue of verifications, run on a dedicated thread pool.
+pub(crate) struct Verifier {
+    pool: ThreadPool,
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Verifier::new(DEFAULT_VERIFY_THREADS, DEFAULT_MAX_PENDING)
+    }
+}
+
+impl Verifier {
+    pub(crate) fn new(threads: usize, max_pending: usize) -> Self {
+        Verifier {
+            pool: Builder::new()
+                .pool_size(threads)
+                .name_prefix("verify-")
+                .build(),
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending,
+        }
+    }
+
+    /// Runs `verify` on the verification pool, returning a future that
+    /// resolves to its result.
+    ///
+    /// Returns `None` without running it if the maximum number of
+    /// verifications are already pending.
+    pub(crate) fn run<F, T>(&self, verify: F) -> Option<impl Future<Item = T, Error = ()>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.max_pending {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        let pending = Pending(self.pending.clone());
+        Some(self.pool.spawn_handle(future::lazy(move || {
+            let res = verify();
+            drop(pending);
+            Ok::<_, ()>(res)
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+
+    use super::Verifier;
+
+    #[test]
+    fn reactor_stays_responsive() {
+        let verifier = Verifier::new(2, 1000);
+        let verified = Arc::new(AtomicUsize::new(0));
+
+        // Each stands in for an expensive verification
+        let verifications: Vec<_> = (0..100)
+            .map(|_| {
+                let verified = verified.clone();
+                verifier
+                    .run(move || {
+                        thread::sleep(Duration::from_millis(10));
+                        verified.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .unwrap()
+            })
+            .collect();
+
+        // A timer on the reactor fires on schedule while they run
+        let mut rt = Runtime::new().unwrap();
+        let start = Instant::now();
+        rt.block_on(Delay::new(start + Duration::from_millis(50)))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(verified.load(Ordering::SeqCst) < 100);
+
+        rt.block_on(future::join_all(verifications)).unwrap();
+        assert_eq!(verified.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn bounded() {
+        let verifier = Verifier::new(1, 2);
+        let lock = Arc::new(Mutex::new(()));
+
+        // Hold up the verifications until we are done submitting them
+        let guard = lock.lock().unwrap();
+        let blocked: Vec<_> = (0..2)
+            .map(|i| {
+                let lock = lock.clone();
+                verifier
+                    .run(move || {
+                        let _guard = lock.lock().unwrap();
+                        i
+                    })
+                    .unwrap()
+            })
+            .collect();
+        assert!(verifier.run(|| 2).is_none());
+
+        drop(guard);
+        assert_eq!(future::join_all(blocked).wait(), Ok(vec![0, 1]));
+        assert_eq!(verifier.run(|| 3).unwrap().wait(), Ok(3));
+    }
+}
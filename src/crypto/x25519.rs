@@ -0,0 +1,121 @@
+//! X25519 key agreement, for handshakes that manage their own ephemeral keys.
+
+use rand::{rngs::OsRng, Rng};
+use std::fmt;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::Zeroize;
+
+use super::Error;
+
+/// The private component of an X25519 keypair. It is zeroed when dropped.
+#[derive(PartialEq)]
+pub struct X25519PrivateKey(pub [u8; 32]);
+
+impl fmt::Debug for X25519PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "X25519PrivateKey([redacted])")
+    }
+}
+
+impl Drop for X25519PrivateKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// The public component of an X25519 keypair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct X25519PublicKey(pub [u8; 32]);
+
+impl X25519PrivateKey {
+    /// Derives the public key for this private key.
+    pub fn public_key(&self) -> X25519PublicKey {
+        X25519PublicKey(x25519(self.0, X25519_BASEPOINT_BYTES))
+    }
+}
+
+/// Generates a new keypair.
+pub fn generate_keypair() -> (X25519PrivateKey, X25519PublicKey) {
+    let mut private = [0; 32];
+    OsRng.fill(&mut private);
+    let private = X25519PrivateKey(private);
+    let public = private.public_key();
+    (private, public)
+}
+
+/// Returns the secret shared between the owner of `private` and the owner of
+/// the private key for `public`.
+///
+/// Fails if the shared secret is zero, which happens when `public` is a
+/// low-order point that the peer can use to force the outcome.
+pub fn diffie_hellman(
+    private: &X25519PrivateKey,
+    public: &X25519PublicKey,
+) -> Result<[u8; 32], Error> {
+    let shared = x25519(private.0, public.0);
+    if shared == [0; 32] {
+        Err(Error::InvalidKey)
+    } else {
+        Ok(shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diffie_hellman, generate_keypair, X25519PrivateKey, X25519PublicKey};
+    use crate::crypto::Error;
+
+    #[test]
+    fn shared_secret() {
+        let (alice_priv, alice_pub) = generate_keypair();
+        let (bob_priv, bob_pub) = generate_keypair();
+        assert_ne!(alice_pub, bob_pub);
+
+        let alice_shared = diffie_hellman(&alice_priv, &bob_pub).unwrap();
+        let bob_shared = diffie_hellman(&bob_priv, &alice_pub).unwrap();
+        assert_eq!(alice_shared, bob_shared);
+
+        let (eve_priv, _) = generate_keypair();
+        assert_ne!(diffie_hellman(&eve_priv, &bob_pub).unwrap(), alice_shared);
+    }
+
+    #[test]
+    fn rfc7748_vector() {
+        // From RFC 7748, section 6.1
+        let alice_priv = X25519PrivateKey([
+            0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2,
+            0x66, 0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5,
+            0x1d, 0xb9, 0x2c, 0x2a,
+        ]);
+        let bob_pub = X25519PublicKey([
+            0xde, 0x9e, 0xdb, 0x7d, 0x7b, 0x7d, 0xc1, 0xb4, 0xd3, 0x5b, 0x61, 0xc2, 0xec, 0xe4,
+            0x35, 0x37, 0x3f, 0x83, 0x43, 0xc8, 0x5b, 0x78, 0x67, 0x4d, 0xad, 0xfc, 0x7e, 0x14,
+            0x6f, 0x88, 0x2b, 0x4f,
+        ]);
+        assert_eq!(
+            alice_priv.public_key().0,
+            [
+                0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e,
+                0xf7, 0x5a, 0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e,
+                0xaa, 0x9b, 0x4e, 0x6a,
+            ]
+        );
+        assert_eq!(
+            diffie_hellman(&alice_priv, &bob_pub).unwrap(),
+            [
+                0x4a, 0x5d, 0x9d, 0x5b, 0xa4, 0xce, 0x2d, 0xe1, 0x72, 0x8e, 0x3b, 0xf4, 0x80, 0x35,
+                0x0f, 0x25, 0xe0, 0x7e, 0x21, 0xc9, 0x47, 0xd1, 0x9e, 0x33, 0x76, 0xf0, 0x9b, 0x3c,
+                0x1e, 0x16, 0x17, 0x42,
+            ]
+        );
+
+        // A low-order point gives no shared secret
+        assert_eq!(
+            diffie_hellman(&alice_priv, &X25519PublicKey([0; 32])),
+            Err(Error::InvalidKey)
+        );
+
+        // The private key is never printed
+        assert_eq!(format!("{:?}", alice_priv), "X25519PrivateKey([redacted])");
+    }
+}
@@ -1,10 +1,16 @@
-use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+use std::cmp;
+use std::collections::HashMap;
+use std::time::Duration;
 
 use super::{cert_and_padding_from_keys, Certificate, Padding};
+use crate::constants::I2P_BASE64;
 use crate::crypto::{
-    self, elgamal, PrivateKey, PublicKey, Signature, SigningPrivateKey, SigningPublicKey,
+    self,
+    blinding::{self, BlindedPublicKey, BlindedSigningKey, BLINDED_SIGNATURE_SIZE},
+    elgamal, EncType, PrivateKey, PublicKey, Signature, SigningPrivateKey, SigningPublicKey,
 };
-use crate::data::{Hash, I2PDate, TunnelId};
+use crate::data::{Hash, I2PDate, Mapping, ReadError, TunnelId};
 use crate::util::serialize;
 
 pub(crate) mod frame;
@@ -20,20 +26,45 @@ pub struct Destination {
 }
 
 impl Destination {
-    pub fn from_keys(public_key: PublicKey, signing_key: SigningPublicKey) -> Self {
-        let (certificate, padding) = cert_and_padding_from_keys(&public_key, &signing_key);
-        Destination {
+    /// Returns `crypto::Error::UnsupportedSigType` if the signing key can't be
+    /// described by a certificate.
+    pub fn from_keys(
+        public_key: PublicKey,
+        signing_key: SigningPublicKey,
+    ) -> Result<Self, crypto::Error> {
+        let (certificate, padding) = cert_and_padding_from_keys(&public_key, &signing_key)?;
+        Ok(Destination {
             public_key,
             padding,
             signing_key,
             certificate,
-        }
+        })
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (_, dest) = frame::destination(data)?;
+        Ok(dest)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         serialize(|input| frame::gen_destination(input, self))
     }
 
+    /// Parses a Destination from its I2P base64 encoding.
+    pub fn from_base64(data: &str) -> Result<Self, ReadError> {
+        let bytes = I2P_BASE64
+            .decode(data.as_bytes())
+            .map_err(|e| ReadError::Base64(format!("{}", e)))?;
+        Destination::from_bytes(&bytes)
+    }
+
+    /// Returns the I2P base64 encoding of this Destination.
+    pub fn to_base64(&self) -> String {
+        I2P_BASE64.encode(&self.to_bytes())
+    }
+
+    /// Returns the SHA-256 hash of this Destination, which is the key that
+    /// its LeaseSet is stored under in the network database.
     pub fn hash(&self) -> Hash {
         Hash::digest(&self.to_bytes()[..])
     }
@@ -52,7 +83,7 @@ impl DestinationSecretKeys {
         let signing_private_key = SigningPrivateKey::new();
         let signing_key = SigningPublicKey::from_secret(&signing_private_key).unwrap();
         DestinationSecretKeys {
-            dest: Destination::from_keys(public_key, signing_key),
+            dest: Destination::from_keys(public_key, signing_key).unwrap(),
             private_key,
             signing_private_key,
         }
@@ -76,6 +107,10 @@ impl Lease {
             end_date,
         }
     }
+
+    pub fn is_expired(&self) -> bool {
+        self.end_date.is_expired()
+    }
 }
 
 /// Contains all of the currently authorized Leases for a particular Destination,
@@ -126,29 +161,254 @@ impl LeaseSet {
         }
     }
 
+    /// Returns the latest end date of the leases in this LeaseSet.
+    pub fn expiration(&self) -> I2PDate {
+        self.leases
+            .iter()
+            .map(|lease| lease.end_date)
+            .max()
+            .unwrap_or(I2PDate(1))
+    }
+
+    /// Returns true if any of the leases in this LeaseSet have not expired.
     pub fn is_current(&self) -> bool {
-        let expiry = self.leases.iter().fold(I2PDate(1), |expiry, lease| {
-            if lease.end_date > expiry {
-                lease.end_date
-            } else {
-                expiry
+        self.leases.iter().any(|lease| !lease.is_expired())
+    }
+}
+
+/// The DatabaseStore type of a LeaseSet2, which prefixes its signed data.
+const LS2_STORE_TYPE: u8 = 3;
+
+/// The DatabaseStore type of an EncryptedLeaseSet.
+const ENCRYPTED_LS_STORE_TYPE: u8 = 5;
+
+/// Set if a LeaseSet2 is signed by an offline transient key.
+const LS2_FLAG_OFFLINE_KEYS: u16 = 1;
+
+const LAYER1_INFO: &[u8] = b"ELS2_L1K";
+const LAYER2_INFO: &[u8] = b"ELS2_L2K";
+
+/// A LeaseSet2 can advertise several encryption keys and a set of options,
+/// and uses shorter dates than a LeaseSet. It is signed by the Destination.
+#[derive(Clone)]
+pub struct LeaseSet2 {
+    pub dest: Destination,
+    pub(super) published: I2PDate,
+    pub(super) expires: I2PDate,
+    pub(super) flags: u16,
+    pub(super) properties: Mapping,
+    pub(super) enc_keys: Vec<(u16, Vec<u8>)>,
+    pub(super) leases: Vec<Lease>,
+    pub(super) signature: Option<Signature>,
+}
+
+impl LeaseSet2 {
+    /// Creates a LeaseSet2 published now, that expires after `lifetime`.
+    pub fn new(dest: Destination, lifetime: Duration) -> Self {
+        // Dates in a LeaseSet2 only have a resolution of seconds.
+        let published = I2PDate(I2PDate::now().0 / 1_000 * 1_000);
+        let lifetime = cmp::min(lifetime.as_secs(), u64::from(u16::max_value()));
+        LeaseSet2 {
+            dest,
+            published,
+            expires: I2PDate(published.0 + lifetime * 1_000),
+            flags: 0,
+            properties: Mapping(HashMap::new()),
+            enc_keys: vec![],
+            leases: vec![],
+            signature: None,
+        }
+    }
+
+    pub fn add_enc_key(&mut self, key: &PublicKey) {
+        self.enc_keys
+            .push((EncType::ElGamal2048.code(), key.0.to_vec()));
+    }
+
+    pub fn add_lease(&mut self, lease: Lease) {
+        self.leases.push(lease);
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (_, ls) = frame::lease_set2(data)?;
+        Ok(ls)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(|input| frame::gen_lease_set2(input, self))
+    }
+
+    fn signature_bytes(&self) -> Vec<u8> {
+        let mut data = vec![LS2_STORE_TYPE];
+        data.extend(serialize(|input| {
+            frame::gen_lease_set2_minus_sig(input, self)
+        }));
+        data
+    }
+
+    pub fn sign(&mut self, sk: &SigningPrivateKey) -> Result<(), crypto::Error> {
+        self.signature = Some(sk.sign(&self.signature_bytes())?);
+        Ok(())
+    }
+
+    pub fn verify(&self) -> Result<(), crypto::Error> {
+        match self.signature.as_ref() {
+            Some(s) => self.dest.signing_key.verify(&self.signature_bytes(), s),
+            None => Err(crypto::Error::NoSignature),
+        }
+    }
+}
+
+/// Returns the UTC date that keys are blinded for, in the form `yyyyMMdd`.
+fn blinding_date(date: I2PDate) -> String {
+    DateTime::<Utc>::from(date.to_system_time())
+        .format("%Y%m%d")
+        .to_string()
+}
+
+/// Both encryption layers are keyed from the subcredential and the time the
+/// EncryptedLeaseSet was published.
+fn layer_input(subcredential: &[u8; 32], published: I2PDate) -> Vec<u8> {
+    let mut input = Vec::from(&subcredential[..]);
+    input.extend_from_slice(&((published.0 / 1_000) as u32).to_be_bytes());
+    input
+}
+
+/// A LeaseSet2 that has been encrypted and then signed with a blinded copy of
+/// the Destination's signing key. Only clients that already know the
+/// Destination can find it in the network database and decrypt it.
+///
+/// The blinded key changes every day, and the EncryptedLeaseSet is stored
+/// under the hash of the blinded key instead of the Destination.
+#[derive(Clone)]
+pub struct EncryptedLeaseSet {
+    pub(super) blinded_key: BlindedPublicKey,
+    pub(super) published: I2PDate,
+    pub(super) expires: I2PDate,
+    pub(super) flags: u16,
+    pub(super) ciphertext: Vec<u8>,
+    pub(super) signature: Option<[u8; BLINDED_SIGNATURE_SIZE]>,
+}
+
+impl EncryptedLeaseSet {
+    /// Encrypts a signed LeaseSet2, and signs the result with the key of its
+    /// Destination blinded for the day the LeaseSet2 was published.
+    ///
+    /// Returns `crypto::Error::InvalidKey` if `sk` is not the signing key of
+    /// the Destination.
+    pub fn encrypt(ls: &LeaseSet2, sk: &SigningPrivateKey) -> Result<Self, crypto::Error> {
+        if ls.signature.is_none() {
+            return Err(crypto::Error::NoSignature);
+        }
+        if SigningPublicKey::from_secret(sk)? != ls.dest.signing_key {
+            return Err(crypto::Error::InvalidKey);
+        }
+
+        let blinded_sk = BlindedSigningKey::new(sk, &blinding_date(ls.published))?;
+        let subcredential = blinding::subcredential(&ls.dest.signing_key, blinded_sk.public_key());
+        let input = layer_input(&subcredential, ls.published);
+
+        let mut inner = vec![LS2_STORE_TYPE];
+        inner.extend(ls.to_bytes());
+
+        // We don't support per-client authorization, so the first layer only
+        // has the flag saying that it isn't used.
+        let mut outer = vec![0];
+        outer.extend(blinding::encrypt_layer(&input, LAYER2_INFO, &inner));
+
+        let mut els = EncryptedLeaseSet {
+            blinded_key: blinded_sk.public_key().clone(),
+            published: ls.published,
+            expires: ls.expires,
+            flags: 0,
+            ciphertext: blinding::encrypt_layer(&input, LAYER1_INFO, &outer),
+            signature: None,
+        };
+        els.signature = Some(blinded_sk.sign(&els.signature_bytes()));
+        Ok(els)
+    }
+
+    /// Decrypts the LeaseSet2 inside, given the signing key of the
+    /// Destination that published it.
+    ///
+    /// Returns `crypto::Error::InvalidKey` if this was published by a
+    /// different Destination.
+    pub fn decrypt(&self, key: &SigningPublicKey) -> Result<LeaseSet2, crypto::Error> {
+        self.verify()?;
+        if BlindedPublicKey::new(key, &blinding_date(self.published))? != self.blinded_key {
+            return Err(crypto::Error::InvalidKey);
+        }
+
+        let subcredential = blinding::subcredential(key, &self.blinded_key);
+        let input = layer_input(&subcredential, self.published);
+
+        let outer = blinding::decrypt_layer(&input, LAYER1_INFO, &self.ciphertext)?;
+        let inner = match outer.split_first() {
+            Some((&0, inner)) => blinding::decrypt_layer(&input, LAYER2_INFO, inner)?,
+            _ => return Err(crypto::Error::DecryptionFailed),
+        };
+        let ls = match inner.split_first() {
+            Some((&LS2_STORE_TYPE, data)) => {
+                LeaseSet2::from_bytes(data).map_err(|_| crypto::Error::DecryptionFailed)?
             }
-        });
-        expiry < I2PDate::from_system_time(SystemTime::now())
+            _ => return Err(crypto::Error::DecryptionFailed),
+        };
+
+        if ls.dest.signing_key != *key {
+            return Err(crypto::Error::InvalidKey);
+        }
+        ls.verify()?;
+        Ok(ls)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (_, els) = frame::encrypted_lease_set(data)?;
+        Ok(els)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(|input| frame::gen_encrypted_lease_set(input, self))
+    }
+
+    /// Returns the key that this EncryptedLeaseSet is stored under in the
+    /// network database.
+    pub fn hash(&self) -> Hash {
+        let mut data = Vec::from(&blinding::BLINDED_SIG_TYPE.to_be_bytes()[..]);
+        data.extend_from_slice(self.blinded_key.as_bytes());
+        Hash::digest(&data)
+    }
+
+    fn signature_bytes(&self) -> Vec<u8> {
+        let mut data = vec![ENCRYPTED_LS_STORE_TYPE];
+        data.extend(serialize(|input| {
+            frame::gen_encrypted_lease_set_minus_sig(input, self)
+        }));
+        data
+    }
+
+    pub fn verify(&self) -> Result<(), crypto::Error> {
+        match self.signature.as_ref() {
+            Some(s) => self.blinded_key.verify(&self.signature_bytes(), s),
+            None => Err(crypto::Error::NoSignature),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::SystemTime;
+    use std::time::{Duration, SystemTime};
 
-    use super::{Destination, DestinationSecretKeys, Lease, LeaseSet};
+    use super::{
+        blinding_date, frame, Destination, DestinationSecretKeys, EncryptedLeaseSet, Lease,
+        LeaseSet, LeaseSet2,
+    };
+    use crate::util::serialize;
     use crate::{
         crypto::{
-            self, elgamal::KeyPairGenerator, PublicKey, SigType, SigningPrivateKey,
-            SigningPublicKey,
+            self, blinding::BlindedPublicKey, elgamal::KeyPairGenerator, PublicKey, SigType,
+            SigningPrivateKey, SigningPublicKey,
         },
-        data::{Certificate, Hash, I2PDate, TunnelId},
+        data::{Certificate, Hash, I2PDate, ReadError, TunnelId},
     };
 
     #[test]
@@ -169,6 +429,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn dest_base64() {
+        const DEST_B64: &str = concat!(
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygpKissLS4vMDEyMzQ1Njc4OTo7",
+            "PD0-P0BBQkNERUZHSElKS0xNTk9QUVJTVFVWV1hZWltcXV5fYGFiY2RlZmdoaWprbG1ub3BxcnN0dXZ3",
+            "eHl6e3x9fn-AgYKDhIWGh4iJiouMjY6PkJGSk5SVlpeYmZqbnJ2en6ChoqOkpaanqKmqq6ytrq-wsbKz",
+            "tLW2t7i5uru8vb6~wMHCw8TFxsfIycrLzM3Oz9DR0tPU1dbX2Nna29zd3t~g4eLj5OXm5-jp6uvs7e7v",
+            "8PHy8~T19vf4-fr7~P3-~1VVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVV",
+            "VVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVddamAGCsQq3",
+            "1Uv-08lkBzoO4XLz2qYjJa8CGmj3B1EaBQAEAAcAAA==",
+        );
+
+        let dest = Destination::from_base64(DEST_B64).unwrap();
+        assert_eq!(dest.signing_key.sig_type(), SigType::Ed25519);
+        assert_eq!(
+            dest.hash(),
+            Hash([
+                0x41, 0x6e, 0xed, 0x8a, 0x44, 0xe9, 0x1e, 0x3e, 0xf1, 0x57, 0x4a, 0x6f, 0x3b, 0x81,
+                0x64, 0x80, 0x91, 0x8c, 0x7e, 0x8e, 0x76, 0xa8, 0xdd, 0xf3, 0x2b, 0xd1, 0x6e, 0xfa,
+                0xed, 0xa8, 0x70, 0x03
+            ])
+        );
+        assert_eq!(dest.to_base64(), DEST_B64);
+
+        // Round trip through bytes
+        let dest = Destination::from_bytes(&dest.to_bytes()).unwrap();
+        assert_eq!(dest.to_base64(), DEST_B64);
+
+        match Destination::from_base64("not base64!") {
+            Err(ReadError::Base64(_)) => (),
+            _ => panic!("Expected a base64 error"),
+        }
+    }
+
     #[test]
     fn ls_sign() {
         let dsk = DestinationSecretKeys::new();
@@ -193,4 +487,119 @@ mod tests {
         ls.sign(&dsk.signing_private_key).unwrap();
         assert_eq!(ls.verify(), Ok(()));
     }
+
+    #[test]
+    fn ls_is_current() {
+        let dsk = DestinationSecretKeys::new();
+        let (_, enc_key) = KeyPairGenerator::generate();
+        let sig_key = SigningPublicKey::from_secret(&SigningPrivateKey::new()).unwrap();
+        let mut ls = LeaseSet::new(dsk.dest, enc_key, sig_key);
+        assert!(!ls.is_current());
+
+        let expired = Lease::new(
+            Hash([1; 32]),
+            TunnelId(1),
+            I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(60)),
+        );
+        assert!(expired.is_expired());
+        ls.add_lease(expired.clone());
+        assert!(!ls.is_current());
+
+        // One current lease is enough
+        let current = Lease::new(
+            Hash([2; 32]),
+            TunnelId(2),
+            I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(60)),
+        );
+        assert!(!current.is_expired());
+        ls.add_lease(current.clone());
+        ls.add_lease(expired);
+        assert!(ls.is_current());
+        assert_eq!(ls.expiration(), current.end_date);
+    }
+
+    #[test]
+    fn ls_verify_received() {
+        let dsk = DestinationSecretKeys::new();
+        let (_, enc_key) = KeyPairGenerator::generate();
+        let sig_key = SigningPublicKey::from_secret(&SigningPrivateKey::new()).unwrap();
+
+        let mut ls = LeaseSet::new(dsk.dest, enc_key, sig_key);
+        ls.add_lease(Lease::new(
+            Hash([1; 32]),
+            TunnelId(1),
+            I2PDate::from_system_time(SystemTime::now()),
+        ));
+        ls.sign(&dsk.signing_private_key).unwrap();
+
+        // A signed LeaseSet still verifies after a round trip through the wire format
+        let data = serialize(|input| frame::gen_lease_set(input, &ls));
+        let mut received = match frame::lease_set(&data) {
+            Ok((_, received)) => received,
+            Err(e) => panic!("LeaseSet parsing failed: {}", e),
+        };
+        assert_eq!(received.verify(), Ok(()));
+
+        // Tampering with the leases invalidates the signature
+        received.leases[0].tid = TunnelId(2);
+        assert_eq!(received.verify(), Err(crypto::Error::InvalidSignature));
+    }
+
+    #[test]
+    fn encrypted_ls2_round_trip() {
+        let dsk = DestinationSecretKeys::new();
+        let (_, enc_key) = KeyPairGenerator::generate();
+        let mut ls = LeaseSet2::new(dsk.dest.clone(), Duration::from_secs(600));
+        ls.add_enc_key(&enc_key);
+        for i in 1..3 {
+            ls.add_lease(Lease::new(
+                Hash([i; 32]),
+                TunnelId(i.into()),
+                I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(600)),
+            ));
+        }
+
+        // Only signed LeaseSet2s can be encrypted
+        match EncryptedLeaseSet::encrypt(&ls, &dsk.signing_private_key) {
+            Err(crypto::Error::NoSignature) => (),
+            _ => panic!("Expected a missing signature"),
+        }
+        ls.sign(&dsk.signing_private_key).unwrap();
+        assert_eq!(ls.verify(), Ok(()));
+
+        // Publish the EncryptedLeaseSet, and receive it as a floodfill would
+        let els = EncryptedLeaseSet::encrypt(&ls, &dsk.signing_private_key).unwrap();
+        let published = els.to_bytes();
+        let received = EncryptedLeaseSet::from_bytes(&published).unwrap();
+        assert_eq!(received.verify(), Ok(()));
+        assert_eq!(received.hash(), els.hash());
+
+        // The Destination can't be seen without decrypting
+        assert_ne!(received.hash(), dsk.dest.hash());
+        let sig_key = dsk.dest.signing_key.as_bytes();
+        assert!(!published.windows(sig_key.len()).any(|w| w == sig_key));
+
+        // A client that knows the Destination derives the same blinded key,
+        // and can decrypt the LeaseSet2
+        let blinded =
+            BlindedPublicKey::new(&dsk.dest.signing_key, &blinding_date(received.published))
+                .unwrap();
+        assert_eq!(received.blinded_key, blinded);
+        let decrypted = received.decrypt(&dsk.dest.signing_key).unwrap();
+        assert_eq!(decrypted.verify(), Ok(()));
+        assert_eq!(decrypted.to_bytes(), ls.to_bytes());
+        assert_eq!(decrypted.dest.hash(), dsk.dest.hash());
+
+        // Other Destinations can't decrypt it
+        let other = DestinationSecretKeys::new();
+        match received.decrypt(&other.dest.signing_key) {
+            Err(crypto::Error::InvalidKey) => (),
+            _ => panic!("Expected an invalid key"),
+        }
+
+        // Tampering with the ciphertext invalidates the signature
+        let mut tampered = received.clone();
+        tampered.ciphertext[40] ^= 1;
+        assert_eq!(tampered.verify(), Err(crypto::Error::InvalidSignature));
+    }
 }
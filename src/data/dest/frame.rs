@@ -1,21 +1,24 @@
 use cookie_factory::*;
 use nom::*;
 
-use super::{Destination, Lease, LeaseSet};
+use super::{Destination, EncryptedLeaseSet, Lease, LeaseSet, LeaseSet2, LS2_FLAG_OFFLINE_KEYS};
 use crate::constants;
-use crate::crypto::frame::{
-    gen_public_key, gen_signature, gen_signing_key, public_key, signature, signing_key,
+use crate::crypto::{
+    blinding::{BlindedPublicKey, BLINDED_KEY_SIZE, BLINDED_SIGNATURE_SIZE, BLINDED_SIG_TYPE},
+    frame::{gen_public_key, gen_signature, gen_signing_key, public_key, signature, signing_key},
 };
 use crate::data::frame::{
-    certificate, gen_certificate, gen_hash, gen_i2p_date, gen_truncated_signing_key, gen_tunnel_id,
-    hash, i2p_date, keycert_padding, split_signing_key, tunnel_id,
+    certificate, gen_certificate, gen_hash, gen_i2p_date, gen_mapping, gen_short_expiry,
+    gen_truncated_signing_key, gen_tunnel_id, hash, i2p_date, keycert_padding, mapping,
+    short_expiry, split_signing_key, tunnel_id,
 };
+use crate::data::I2PDate;
 
 // Destination
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 named!(
-    destination<Destination>,
+    pub destination<Destination>,
     do_parse!(
         public_key:   public_key >>
         signing_data: take!(constants::KEYCERT_SIGKEY_BYTES) >>
@@ -124,3 +127,164 @@ pub fn gen_lease_set<'a>(
         gen_lease_set_minus_sig(ls) >> gen_signature(ls.signature.as_ref().unwrap())
     )
 }
+
+// LeaseSet2
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(
+    lease2<Lease>,
+    do_parse!(
+        tunnel_gw: hash >>
+        tid:       tunnel_id >>
+        end_date:  short_expiry >>
+        (Lease {
+            tunnel_gw,
+            tid,
+            end_date,
+        })
+    )
+);
+
+fn gen_lease2<'a>(
+    input: (&'a mut [u8], usize),
+    lease: &Lease,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_hash(&lease.tunnel_gw)
+            >> gen_tunnel_id(&lease.tid)
+            >> gen_short_expiry(&lease.end_date)
+    )
+}
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(
+    enc_key<(u16, Vec<u8>)>,
+    do_parse!(
+        enc_type: be_u16 >>
+        data:     length_bytes!(be_u16) >>
+        ((enc_type, data.to_vec()))
+    )
+);
+
+fn gen_enc_key<'a>(
+    input: (&'a mut [u8], usize),
+    key: &(u16, Vec<u8>),
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u16!(key.0) >> gen_be_u16!(key.1.len() as u16) >> gen_slice!(&key.1)
+    )
+}
+
+/// Converts the expiry offset in a LeaseSet2 header into a date.
+fn expires_after(published: I2PDate, offset: u16) -> I2PDate {
+    I2PDate(published.0 + u64::from(offset) * 1_000)
+}
+
+fn expires_offset(published: I2PDate, expires: I2PDate) -> u16 {
+    ((expires.0.saturating_sub(published.0)) / 1_000) as u16
+}
+
+named!(pub lease_set2<LeaseSet2>,
+    do_parse!(
+        dest:       destination >>
+        published:  short_expiry >>
+        expires:    be_u16 >>
+        flags:      verify!(be_u16, |flags| flags & LS2_FLAG_OFFLINE_KEYS == 0) >>
+        properties: mapping >>
+        enc_keys:   length_count!(be_u8, enc_key) >>
+        leases:     length_count!(be_u8, lease2) >>
+        sig:        call!(signature, dest.signing_key.sig_type()) >>
+        (LeaseSet2 {
+            dest,
+            published,
+            expires: expires_after(published, expires),
+            flags,
+            properties,
+            enc_keys,
+            leases,
+            signature: Some(sig),
+        })
+    )
+);
+
+pub fn gen_lease_set2_minus_sig<'a>(
+    input: (&'a mut [u8], usize),
+    ls: &LeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_destination(&ls.dest)
+            >> gen_short_expiry(&ls.published)
+            >> gen_be_u16!(expires_offset(ls.published, ls.expires))
+            >> gen_be_u16!(ls.flags)
+            >> gen_mapping(&ls.properties)
+            >> gen_be_u8!(ls.enc_keys.len() as u8)
+            >> gen_many!(&ls.enc_keys, gen_enc_key)
+            >> gen_be_u8!(ls.leases.len() as u8)
+            >> gen_many!(&ls.leases, gen_lease2)
+    )
+}
+
+pub fn gen_lease_set2<'a>(
+    input: (&'a mut [u8], usize),
+    ls: &LeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_lease_set2_minus_sig(ls) >> gen_signature(ls.signature.as_ref().unwrap())
+    )
+}
+
+// EncryptedLeaseSet
+
+named!(pub encrypted_lease_set<EncryptedLeaseSet>,
+    do_parse!(
+        verify!(be_u16, |sig_type| sig_type == BLINDED_SIG_TYPE) >>
+        blinded_key: take!(BLINDED_KEY_SIZE) >>
+        published:   short_expiry >>
+        expires:     be_u16 >>
+        flags:       verify!(be_u16, |flags| flags & LS2_FLAG_OFFLINE_KEYS == 0) >>
+        ciphertext:  length_bytes!(be_u16) >>
+        sig:         take!(BLINDED_SIGNATURE_SIZE) >>
+        (EncryptedLeaseSet {
+            blinded_key: BlindedPublicKey::from_bytes(array_ref![
+                blinded_key,
+                0,
+                BLINDED_KEY_SIZE
+            ]),
+            published,
+            expires: expires_after(published, expires),
+            flags,
+            ciphertext: ciphertext.to_vec(),
+            signature: Some(*array_ref![sig, 0, BLINDED_SIGNATURE_SIZE]),
+        })
+    )
+);
+
+pub fn gen_encrypted_lease_set_minus_sig<'a>(
+    input: (&'a mut [u8], usize),
+    els: &EncryptedLeaseSet,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u16!(BLINDED_SIG_TYPE)
+            >> gen_slice!(els.blinded_key.as_bytes())
+            >> gen_short_expiry(&els.published)
+            >> gen_be_u16!(expires_offset(els.published, els.expires))
+            >> gen_be_u16!(els.flags)
+            >> gen_be_u16!(els.ciphertext.len() as u16)
+            >> gen_slice!(&els.ciphertext)
+    )
+}
+
+pub fn gen_encrypted_lease_set<'a>(
+    input: (&'a mut [u8], usize),
+    els: &EncryptedLeaseSet,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_encrypted_lease_set_minus_sig(els) >> gen_slice!(&els.signature.as_ref().unwrap()[..])
+    )
+}
@@ -78,8 +78,14 @@ pub fn gen_mapping<'a>(
     do_gen!(
         input,
         size:  gen_skip!(2) >>
-        // Some structures require the Mapping be sorted, so just sort them all
-        start: gen_many!(m.0.iter().sorted().into_iter(), gen_mapping_pair) >>
+        // Signed structures require the Mapping be sorted by key, so that the
+        // signature is reproducible. Just sort them all.
+        start: gen_many!(
+            m.0.iter()
+                .sorted_by(|(a, _), (b, _)| a.0.as_bytes().cmp(b.0.as_bytes()))
+                .into_iter(),
+            gen_mapping_pair
+        ) >>
         end:   gen_at_offset!(size, gen_be_u16!(end - start))
     )
 }
@@ -270,11 +276,24 @@ pub fn gen_certificate<'a>(
 
 // RouterIdentity
 
+/// Parses a certificate, checking that the length declared by a key certificate
+/// matches the extra key data implied by its signing and encryption types.
+fn consistent_certificate<'a>(input: &'a [u8]) -> IResult<&'a [u8], Certificate> {
+    let (_, (_, declared_len)) = peek!(input, tuple!(be_u8, be_u16))?;
+    let (i, cert) = certificate(input)?;
+    if let Certificate::Key(ref kc) = cert {
+        if declared_len as usize != 4 + kc.sig_data.len() + kc.enc_data.len() {
+            return Err(Err::Error(error_position!(input, ErrorKind::Custom(1))));
+        }
+    }
+    Ok((i, cert))
+}
+
 named!(pub router_identity<RouterIdentity>,
     do_parse!(
         public_key:   public_key >>
         signing_data: take!(constants::KEYCERT_SIGKEY_BYTES) >>
-        certificate:  certificate >>
+        certificate:  consistent_certificate >>
         padding:      call!(keycert_padding,
                             array_ref![signing_data, 0, constants::KEYCERT_SIGKEY_BYTES],
                             &certificate) >>
@@ -458,4 +477,72 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn mapping_sorted() {
+        let mut m = Mapping(HashMap::new());
+        for (k, v) in &[("caps", "LR"), ("netId", "2"), ("coreVersion", "0.9.38"), ("a", "b")] {
+            m.0.insert(I2PString::new(k), I2PString::new(v));
+        }
+
+        let first = serialize(|input| gen_mapping(input, &m));
+        let second = serialize(|input| gen_mapping(input, &m.clone()));
+        assert_eq!(first, second);
+
+        // Keys are in byte order
+        let mut pairs = vec![];
+        for (k, v) in &[("a", "b"), ("caps", "LR"), ("coreVersion", "0.9.38"), ("netId", "2")] {
+            pairs.push(k.len() as u8);
+            pairs.extend(k.as_bytes());
+            pairs.push(b'=');
+            pairs.push(v.len() as u8);
+            pairs.extend(v.as_bytes());
+            pairs.push(b';');
+        }
+        assert_eq!(&first[..2], &[0, pairs.len() as u8]);
+        assert_eq!(&first[2..], &pairs[..]);
+
+        match mapping(&first) {
+            Ok((_, parsed)) => assert_eq!(parsed, m),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn router_info_options_round_trip() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        for (k, v) in &[("netId", "2"), ("caps", "XfR"), ("router.version", "0.9.38")] {
+            ri.options.0.insert(I2PString::new(k), I2PString::new(v));
+        }
+        ri.sign(&rsk.signing_private_key);
+
+        let data = ri.to_bytes();
+        match router_info(&data) {
+            Ok((_, parsed)) => {
+                assert!(parsed.verify().is_ok());
+                assert_eq!(parsed.to_bytes(), data);
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn router_identity_inconsistent_cert() {
+        // Change the key certificate's sig type from Ed25519 to ECDSA-P521,
+        // which requires four bytes of extra key data that the declared
+        // certificate length does not include.
+        let mut data = ROUTER_INFO.to_vec();
+        assert_eq!(&data[384..391], &[5, 0, 4, 0, 7, 0, 0]);
+        data[388] = 3;
+
+        match router_identity(&data) {
+            Err(Err::Error(_)) => (),
+            _ => panic!("Inconsistent certificate should not parse"),
+        }
+        match router_info(&data) {
+            Err(Err::Error(_)) => (),
+            _ => panic!("Inconsistent certificate should not parse"),
+        }
+    }
 }
@@ -6,11 +6,13 @@ use chrono::{DateTime, Utc};
 use nom::{self, Needed};
 use rand::{rngs::OsRng, Rng};
 use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::constants;
@@ -25,23 +27,58 @@ pub mod dest;
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) mod frame;
 
-pub use self::dest::{Destination, Lease, LeaseSet};
+pub use self::dest::{Destination, EncryptedLeaseSet, Lease, LeaseSet, LeaseSet2};
 
 lazy_static! {
     pub(crate) static ref OPT_NET_ID: I2PString = "netId".into();
-    static ref OPT_ROUTER_VERSION: I2PString = "router.version".into();
+    pub(crate) static ref OPT_ROUTER_VERSION: I2PString = "router.version".into();
+    pub(crate) static ref OPT_CORE_VERSION: I2PString = "coreVersion".into();
     static ref OPT_CAPS: I2PString = "caps".into();
 }
 
+lazy_static! {
+    pub(crate) static ref NTCP2_OPT_V: I2PString = "v".into();
+    pub(crate) static ref NTCP2_OPT_S: I2PString = "s".into();
+    pub(crate) static ref NTCP2_OPT_I: I2PString = "i".into();
+}
+
+lazy_static! {
+    pub(crate) static ref SSU_OPT_KEY: I2PString = "key".into();
+    static ref SSU_OPT_CAPS: I2PString = "caps".into();
+}
+
+/// The SSU address capability of a router that introduces firewalled peers.
+const SSU_CAP_INTRODUCER: char = 'C';
+
 lazy_static! {
     pub static ref NET_ID: I2PString = "2".into();
-    static ref ROUTER_VERSION: I2PString = "0.9.37".into();
+    static ref ROUTER_VERSION: I2PString = I2P_VERSION.into();
     static ref CAPS: I2PString = "KU".into();
 }
 
+/// The version of the I2P router that ire is compatible with, which it
+/// advertises by default.
+pub const I2P_VERSION: &str = "0.9.37";
+
+/// The bandwidth tiers a router can advertise in its caps, from lowest to
+/// highest.
+pub const BANDWIDTH_TIERS: &str = "KLMNOPX";
+
+/// The other capabilities a router can advertise in its caps.
+const OTHER_CAPS: &str = "fHRUDEG";
+
+/// The suffix of a base32 address.
+const B32_SUFFIX: &str = ".b32.i2p";
+
+/// Maximum age of a RouterInfo.
+pub(crate) const ROUTER_INFO_EXPIRATION: u64 = 27 * 60 * 60;
+
 /// Data read errors
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ReadError {
+    Base32(String),
+    Base64(String),
+    Crypto(crypto::Error),
     FileIo(String),
     Incomplete(Needed),
     Parser,
@@ -51,6 +88,9 @@ pub enum ReadError {
 impl fmt::Display for ReadError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            ReadError::Base32(e) => format!("Invalid base32: {}", e).fmt(f),
+            ReadError::Base64(e) => format!("Invalid base64: {}", e).fmt(f),
+            ReadError::Crypto(e) => format!("Crypto error: {}", e).fmt(f),
             ReadError::FileIo(e) => format!("File IO error: {}", e).fmt(f),
             ReadError::Incomplete(n) => format!("Data is incomplete (needed: {:?})", n).fmt(f),
             ReadError::Parser => "Parser error".fmt(f),
@@ -58,6 +98,12 @@ impl fmt::Display for ReadError {
     }
 }
 
+impl From<crypto::Error> for ReadError {
+    fn from(e: crypto::Error) -> Self {
+        ReadError::Crypto(e)
+    }
+}
+
 impl From<io::Error> for ReadError {
     fn from(e: io::Error) -> Self {
         ReadError::FileIo(format!("{}", e))
@@ -78,7 +124,10 @@ impl<T> From<nom::Err<T>> for ReadError {
 //
 
 /// The SHA-256 hash of some data.
-#[derive(Clone, Eq, Hash, PartialEq)]
+///
+/// Hashes are ordered as 256-bit big-endian integers, so the distances returned
+/// by [`Hash::distance`] can be compared directly.
+#[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Hash(pub [u8; 32]);
 
 impl Hash {
@@ -100,6 +149,52 @@ impl Hash {
             self.0[i] ^= other.0[i];
         }
     }
+
+    /// Returns the Kademlia distance between two hashes, which is their XOR.
+    pub fn distance(&self, other: &Hash) -> Hash {
+        let mut d = self.clone();
+        d.xor(other);
+        d
+    }
+
+    /// Parses a hash from a base32 address, with or without the `.b32.i2p`
+    /// suffix.
+    pub fn from_b32(addr: &str) -> Result<Self, ReadError> {
+        let addr = addr.to_ascii_lowercase();
+        let label = if addr.ends_with(B32_SUFFIX) {
+            &addr[..addr.len() - B32_SUFFIX.len()]
+        } else {
+            &addr[..]
+        };
+        let bytes = constants::I2P_BASE32
+            .decode(label.as_bytes())
+            .map_err(|e| ReadError::Base32(format!("{}", e)))?;
+        if bytes.len() != 32 {
+            return Err(ReadError::Base32(format!(
+                "expected 32 bytes, got {}",
+                bytes.len()
+            )));
+        }
+        Ok(Hash::from_bytes(array_ref![bytes, 0, 32]))
+    }
+
+    /// Returns the base32 address for this hash, including the `.b32.i2p`
+    /// suffix.
+    pub fn to_b32(&self) -> String {
+        format!("{}{}", constants::I2P_BASE32.encode(&self.0), B32_SUFFIX)
+    }
+
+    /// Returns the key under which this hash is stored in the network database
+    /// on the given (UTC) date.
+    ///
+    /// The routing key is the SHA-256 of the hash followed by the date as an
+    /// eight-character `yyyyMMdd` string, so it changes every day at midnight.
+    pub fn routing_key(&self, date: DateTime<Utc>) -> Hash {
+        let mut data = [0u8; 40];
+        data[0..32].copy_from_slice(&self.0);
+        data[32..40].copy_from_slice(date.format("%Y%m%d").to_string().as_bytes());
+        Hash::digest(&data)
+    }
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -120,10 +215,14 @@ impl fmt::Display for Hash {
 
 /// The number of milliseconds since midnight on January 1, 1970 in the GMT
 /// timezone. If the number is 0, the date is undefined or null.
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct I2PDate(pub(crate) u64);
 
 impl I2PDate {
+    pub fn now() -> Self {
+        I2PDate::from_system_time(SystemTime::now())
+    }
+
     pub fn from_system_time(t: SystemTime) -> Self {
         let d = t
             .duration_since(UNIX_EPOCH)
@@ -134,6 +233,11 @@ impl I2PDate {
     pub fn to_system_time(&self) -> SystemTime {
         UNIX_EPOCH + Duration::from_millis(self.0)
     }
+
+    /// Returns true if this date is in the past.
+    pub fn is_expired(&self) -> bool {
+        *self < I2PDate::now()
+    }
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -163,6 +267,80 @@ impl<'a> From<&'a str> for I2PString {
     }
 }
 
+/// A dotted router version, such as `0.9.50`.
+///
+/// Versions are compared component-wise, with missing components treated as
+/// zero (so `0.9` is equal to `0.9.0`).
+#[derive(Clone, Debug)]
+pub struct Version(Vec<u32>);
+
+impl Version {
+    /// Reads the `router.version` option from a RouterInfo, if present and
+    /// valid.
+    pub fn from_option(ri: &RouterInfo) -> Option<Self> {
+        ri.options
+            .0
+            .get(&OPT_ROUTER_VERSION)
+            .and_then(|v| v.0.parse().ok())
+    }
+
+    fn component(&self, i: usize) -> u32 {
+        self.0.get(i).cloned().unwrap_or(0)
+    }
+}
+
+impl FromStr for Version {
+    type Err = ReadError;
+
+    /// Parses a dotted version. Any non-numeric suffix (such as the build
+    /// number in `0.9.50-1`) terminates the version.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut components = Vec::new();
+        for part in s.split('.') {
+            let digits = part.find(|c: char| !c.is_ascii_digit()).unwrap_or(part.len());
+            if digits == 0 {
+                return Err(ReadError::Parser);
+            }
+            components.push(part[..digits].parse().map_err(|_| ReadError::Parser)?);
+            if digits < part.len() {
+                break;
+            }
+        }
+        Ok(Version(components))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (0..self.0.len().max(other.0.len()))
+            .map(|i| self.component(i).cmp(&other.component(i)))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<_> = self.0.iter().map(|c| c.to_string()).collect();
+        parts.join(".").fmt(f)
+    }
+}
+
 /// A set of key/value mappings or properties.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Mapping(pub HashMap<I2PString, I2PString>);
@@ -243,19 +421,23 @@ impl fmt::Debug for Padding {
     }
 }
 
+/// Returns `crypto::Error::UnsupportedSigType` if the signing key doesn't
+/// fit in a KeyCertificate without extra key data, which we don't store.
 fn cert_and_padding_from_keys(
     _public_key: &PublicKey,
     signing_key: &SigningPublicKey,
-) -> (Certificate, Option<Padding>) {
+) -> Result<(Certificate, Option<Padding>), crypto::Error> {
     let certificate = match signing_key.sig_type() {
         SigType::DsaSha1 => Certificate::Null,
-        SigType::Ed25519 => Certificate::Key(KeyCertificate {
-            sig_type: SigType::Ed25519,
+        sig_type @ SigType::EcdsaSha256P256
+        | sig_type @ SigType::EcdsaSha384P384
+        | sig_type @ SigType::Ed25519 => Certificate::Key(KeyCertificate {
+            sig_type,
             enc_type: EncType::ElGamal2048,
             sig_data: vec![],
             enc_data: vec![],
         }),
-        _ => panic!("Not implemented!"),
+        _ => return Err(crypto::Error::UnsupportedSigType),
     };
     let padding = match signing_key.sig_type().pad_len(EncType::ElGamal2048) {
         0 => None,
@@ -267,7 +449,7 @@ fn cert_and_padding_from_keys(
             Some(Padding(padding))
         }
     };
-    (certificate, padding)
+    Ok((certificate, padding))
 }
 
 /// Defines the way to uniquely identify a particular router.
@@ -288,14 +470,17 @@ impl RouterIdentity {
         Ok(res)
     }
 
-    fn from_keys(public_key: PublicKey, signing_key: SigningPublicKey) -> Self {
-        let (certificate, padding) = cert_and_padding_from_keys(&public_key, &signing_key);
-        RouterIdentity {
+    fn from_keys(
+        public_key: PublicKey,
+        signing_key: SigningPublicKey,
+    ) -> Result<Self, crypto::Error> {
+        let (certificate, padding) = cert_and_padding_from_keys(&public_key, &signing_key)?;
+        Ok(RouterIdentity {
             public_key,
             padding,
             signing_key,
             certificate,
-        }
+        })
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -310,6 +495,27 @@ impl RouterIdentity {
     pub fn hash(&self) -> Hash {
         Hash::digest(&self.to_bytes()[..])
     }
+
+    /// Checks that the certificate describes the signing key we have.
+    ///
+    /// The signature type must match, and the padding and extra key data must
+    /// be the lengths that the certificate's key types require.
+    pub fn check_certificate(&self) -> Result<(), crypto::Error> {
+        let (sig_type, enc_type, extra_len) = match self.certificate {
+            Certificate::Key(ref kc) => (kc.sig_type, kc.enc_type, kc.sig_data.len()),
+            _ => (SigType::DsaSha1, EncType::ElGamal2048, 0),
+        };
+        let pad_len = self.padding.as_ref().map(|p| p.0.len()).unwrap_or(0);
+
+        if self.signing_key.sig_type() != sig_type
+            || pad_len != sig_type.pad_len(enc_type)
+            || extra_len != sig_type.extra_data_len(enc_type)
+        {
+            Err(crypto::Error::CertificateMismatch)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// Key material for a RouterIdentity.
@@ -322,14 +528,21 @@ pub struct RouterSecretKeys {
 
 impl RouterSecretKeys {
     pub fn new() -> Self {
+        RouterSecretKeys::with_sig_type(SigType::Ed25519).unwrap()
+    }
+
+    /// Generates keys for a RouterIdentity that signs with the given type.
+    ///
+    /// Returns `crypto::Error::UnsupportedSigType` if we can't sign with it.
+    pub fn with_sig_type(sig_type: SigType) -> Result<Self, crypto::Error> {
+        let signing_private_key = SigningPrivateKey::with_type(sig_type)?;
+        let signing_key = SigningPublicKey::from_secret(&signing_private_key)?;
         let (private_key, public_key) = elgamal::KeyPairGenerator::generate();
-        let signing_private_key = SigningPrivateKey::new();
-        let signing_key = SigningPublicKey::from_secret(&signing_private_key).unwrap();
-        RouterSecretKeys {
-            rid: RouterIdentity::from_keys(public_key, signing_key),
+        Ok(RouterSecretKeys {
+            rid: RouterIdentity::from_keys(public_key, signing_key)?,
             private_key,
             signing_private_key,
-        }
+        })
     }
 
     pub fn from_file(path: &str) -> Result<Self, ReadError> {
@@ -348,6 +561,26 @@ impl RouterSecretKeys {
         let mut rid = File::create(path)?;
         rid.write(&self.to_bytes()).map(|_| ())
     }
+
+    /// Reads keys that were written with `to_file_encrypted`.
+    ///
+    /// Fails with `crypto::Error::DecryptionFailed` if the passphrase is wrong.
+    pub fn from_file_encrypted(path: &str, passphrase: &str) -> Result<Self, ReadError> {
+        let mut rsk = File::open(path)?;
+        let mut data: Vec<u8> = Vec::new();
+        rsk.read_to_end(&mut data)?;
+        let data = crypto::decrypt_with_passphrase(passphrase, &data)?;
+        let (_, res) = frame::router_secret_keys(&data[..])?;
+        Ok(res)
+    }
+
+    /// Writes the keys to a file, encrypted with a key derived from the given
+    /// passphrase.
+    pub fn to_file_encrypted(&self, path: &str, passphrase: &str) -> io::Result<()> {
+        let data = crypto::encrypt_with_passphrase(passphrase, &self.to_bytes());
+        let mut rsk = File::create(path)?;
+        rsk.write_all(&data)
+    }
 }
 
 /// Defines the means to contact a router through a transport protocol.
@@ -378,6 +611,15 @@ impl RouterAddress {
         }
     }
 
+    /// Returns the relative cost of using this address. Lower is preferred.
+    pub fn cost(&self) -> u8 {
+        self.cost
+    }
+
+    pub fn transport_style(&self) -> &I2PString {
+        &self.transport_style
+    }
+
     pub fn option(&self, key: &I2PString) -> Option<&I2PString> {
         self.options.0.get(key)
     }
@@ -386,6 +628,51 @@ impl RouterAddress {
         self.options.0.insert(key, value);
     }
 
+    /// Sets the options that peers need to connect to this address over NTCP2:
+    /// our static public key, the IV for obfuscating it, and the supported
+    /// protocol versions.
+    pub fn set_ntcp2(&mut self, static_key: &[u8], iv: &[u8; 16], versions: &[&str]) {
+        self.set_option(NTCP2_OPT_V.clone(), I2PString(versions.join(",")));
+        self.set_option(
+            NTCP2_OPT_S.clone(),
+            I2PString(constants::I2P_BASE64.encode(static_key)),
+        );
+        self.set_option(
+            NTCP2_OPT_I.clone(),
+            I2PString(constants::I2P_BASE64.encode(iv)),
+        );
+    }
+
+    /// Sets the option that peers need to connect to this address over SSU: our
+    /// introduction key.
+    pub fn set_ssu(&mut self, intro_key: &[u8; 32]) {
+        self.set_option(
+            SSU_OPT_KEY.clone(),
+            I2PString(constants::I2P_BASE64.encode(intro_key)),
+        );
+    }
+
+    /// Advertises that we introduce firewalled peers to routers that connect
+    /// to this address.
+    pub fn set_introducer(&mut self) {
+        if !self.is_introducer() {
+            let mut caps = self
+                .option(&SSU_OPT_CAPS)
+                .map(|caps| caps.0.clone())
+                .unwrap_or_default();
+            caps.push(SSU_CAP_INTRODUCER);
+            self.set_option(SSU_OPT_CAPS.clone(), I2PString(caps));
+        }
+    }
+
+    /// Returns whether the router introduces firewalled peers to routers that
+    /// connect to this address.
+    pub fn is_introducer(&self) -> bool {
+        self.option(&SSU_OPT_CAPS)
+            .map(|caps| caps.0.contains(SSU_CAP_INTRODUCER))
+            .unwrap_or(false)
+    }
+
     pub fn addr(&self) -> Option<SocketAddr> {
         let host = self.options.0.get(&I2PString(String::from("host")));
         let port = self.options.0.get(&I2PString(String::from("port")));
@@ -399,6 +686,22 @@ impl RouterAddress {
     }
 }
 
+/// The findings from validating a [`RouterInfo`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RouterInfoValidation {
+    /// The result of verifying the signature.
+    pub signature: Result<(), crypto::Error>,
+    /// Whether the RouterInfo is for our network.
+    pub net_id: bool,
+    /// If the RouterInfo has expired, how long ago it was published.
+    pub expired: Option<Duration>,
+    /// Whether the RouterInfo has at least one address with a valid host and
+    /// port.
+    pub has_valid_address: bool,
+    /// Whether the RouterInfo has caps containing only known capabilities.
+    pub caps_parseable: bool,
+}
+
 /// Defines all of the data that a router wants to publish for the network to
 /// see.
 ///
@@ -424,7 +727,7 @@ impl RouterInfo {
 
         RouterInfo {
             router_id: rid,
-            published: I2PDate::from_system_time(SystemTime::now()),
+            published: I2PDate::now(),
             addresses: Vec::new(),
             peers: Vec::new(),
             options: Mapping(options),
@@ -440,33 +743,175 @@ impl RouterInfo {
         self.signature = None;
     }
 
+    pub fn option(&self, key: &I2PString) -> Option<&I2PString> {
+        self.options.0.get(key)
+    }
+
+    /// Set an option in this RouterInfo.
+    ///
+    /// Caller must re-sign the RouterInfo afterwards.
+    pub fn set_option(&mut self, key: I2PString, value: I2PString) {
+        self.options.0.insert(key, value);
+        self.signature = None;
+    }
+
+    /// Returns all of the addresses in this RouterInfo, including those
+    /// without a host.
+    pub fn addresses(&self) -> &[RouterAddress] {
+        &self.addresses
+    }
+
+    /// Returns the cheapest address with the given transport style that
+    /// matches the filter. Of addresses with the same cost, IPv4 addresses are
+    /// preferred, and then the first is returned.
     pub fn address<F>(&self, style: &I2PString, filter: F) -> Option<RouterAddress>
     where
         F: Fn(&RouterAddress) -> bool,
     {
-        self.addresses
+        self.matching_addresses(style, filter)
+            .into_iter()
+            .next()
+            .cloned()
+    }
+
+    /// Returns all of the addresses with the given transport style that match
+    /// the filter, cheapest first. Of addresses with the same cost, IPv4
+    /// addresses come before IPv6 addresses, and are otherwise kept in the
+    /// order they were published.
+    pub fn matching_addresses<F>(&self, style: &I2PString, filter: F) -> Vec<&RouterAddress>
+    where
+        F: Fn(&RouterAddress) -> bool,
+    {
+        let mut addresses: Vec<_> = self
+            .addresses
             .iter()
             .filter(|a| a.transport_style == *style)
-            .filter(|a| match a.addr() {
-                Some(addr) => addr.is_ipv4(),
-                None => false,
-            })
-            .find(|a| filter(a))
-            .map(|a| (*a).clone())
+            .filter(|a| a.addr().is_some())
+            .filter(|a| filter(a))
+            .collect();
+        addresses.sort_by_key(|a| (a.cost, a.addr().map(|addr| addr.is_ipv6())));
+        addresses
     }
 
     pub fn network_id(&self) -> Option<&I2PString> {
         self.options.0.get(&OPT_NET_ID)
     }
 
-    pub fn is_floodfill(&self) -> bool {
+    fn has_cap(&self, cap: char) -> bool {
         self.options
             .0
             .get(&OPT_CAPS)
-            .map(|caps| caps.0.contains('f'))
+            .map(|caps| caps.0.contains(cap))
             .unwrap_or(false)
     }
 
+    pub fn is_floodfill(&self) -> bool {
+        self.has_cap('f')
+    }
+
+    /// Returns whether this router advertises that it is reachable.
+    pub fn is_reachable(&self) -> bool {
+        self.has_cap('R')
+    }
+
+    /// Returns whether this router advertises that it is unreachable (for
+    /// example, because it is firewalled).
+    pub fn is_unreachable(&self) -> bool {
+        self.has_cap('U')
+    }
+
+    /// Returns the highest bandwidth tier advertised in this router's caps, if
+    /// any.
+    pub fn bandwidth_tier(&self) -> Option<char> {
+        self.options.0.get(&OPT_CAPS).and_then(|caps| {
+            caps.0
+                .chars()
+                .filter(|c| BANDWIDTH_TIERS.contains(*c))
+                .max_by_key(|c| BANDWIDTH_TIERS.find(*c))
+        })
+    }
+
+    /// Returns whether this RouterInfo was published within `max_age` of now.
+    ///
+    /// RouterInfos published in the future are considered current.
+    pub fn is_current(&self, max_age: Duration) -> bool {
+        SystemTime::now()
+            .duration_since(self.published.to_system_time())
+            .map(|age| age <= max_age)
+            .unwrap_or(true)
+    }
+
+    /// Returns how long ago this RouterInfo was published, if it is older than
+    /// the maximum age of a RouterInfo.
+    pub fn expired(&self) -> Option<Duration> {
+        SystemTime::now()
+            .duration_since(self.published.to_system_time())
+            .ok()
+            .filter(|age| *age > Duration::from_secs(ROUTER_INFO_EXPIRATION))
+    }
+
+    /// Validates this RouterInfo, reporting each finding separately.
+    pub fn validate(&self) -> RouterInfoValidation {
+        self.validate_with(self.verify())
+    }
+
+    /// Validates a set of RouterInfos, verifying their signatures as a batch
+    /// with [`crypto::verify_batch`].
+    pub fn validate_batch(ris: &[RouterInfo]) -> Vec<RouterInfoValidation> {
+        let sig_msgs: Vec<_> = ris.iter().map(RouterInfo::signature_bytes).collect();
+        let signatures: Vec<_> = ris
+            .iter()
+            .map(|ri| {
+                ri.router_id
+                    .check_certificate()
+                    .and(ri.signature.as_ref().ok_or(crypto::Error::NoSignature))
+            })
+            .collect();
+
+        let (batch, items): (Vec<_>, Vec<_>) = signatures
+            .iter()
+            .enumerate()
+            .filter_map(|(i, signature)| {
+                signature.as_ref().ok().map(|signature| {
+                    let item = (&ris[i].router_id.signing_key, &sig_msgs[i][..], *signature);
+                    (i, item)
+                })
+            })
+            .unzip();
+        let verified = crypto::verify_batch(&items);
+
+        let mut results: Vec<_> = signatures.into_iter().map(|s| s.map(|_| ())).collect();
+        for (i, res) in batch.into_iter().zip(verified) {
+            results[i] = res;
+        }
+        ris.iter()
+            .zip(results)
+            .map(|(ri, signature)| ri.validate_with(signature))
+            .collect()
+    }
+
+    fn validate_with(&self, signature: Result<(), crypto::Error>) -> RouterInfoValidation {
+        RouterInfoValidation {
+            signature,
+            net_id: self
+                .network_id()
+                .map(|net_id| *net_id == *NET_ID)
+                .unwrap_or(false),
+            expired: self.expired(),
+            has_valid_address: self.addresses.iter().any(|ra| ra.addr().is_some()),
+            caps_parseable: self
+                .options
+                .0
+                .get(&OPT_CAPS)
+                .map(|caps| {
+                    caps.0
+                        .chars()
+                        .all(|c| BANDWIDTH_TIERS.contains(c) || OTHER_CAPS.contains(c))
+                })
+                .unwrap_or(false),
+        }
+    }
+
     pub fn from_file(path: &str) -> Result<Self, ReadError> {
         let mut ri = File::open(path)?;
         let mut data: Vec<u8> = Vec::new();
@@ -493,7 +938,28 @@ impl RouterInfo {
         self.signature = Some(spk.sign(&sig_msg).unwrap());
     }
 
+    /// Sets the published date, and then signs the RouterInfo.
+    ///
+    /// Ed25519 signatures are deterministic, so signing the same RouterInfo
+    /// with the same key and date always produces the same bytes.
+    pub fn sign_at(&mut self, spk: &SigningPrivateKey, published: I2PDate) {
+        self.published = published;
+        self.sign(spk);
+    }
+
+    /// Returns the key under which this RouterInfo is stored in the network
+    /// database on the given (UTC) date.
+    pub fn routing_key(&self, date: DateTime<Utc>) -> Hash {
+        self.router_id.hash().routing_key(date)
+    }
+
+    /// Verifies the RouterInfo's signature.
+    ///
+    /// Returns `crypto::Error::CertificateMismatch` without checking the
+    /// signature if the RouterIdentity's certificate is inconsistent with its
+    /// signing key.
     pub fn verify(&self) -> Result<(), crypto::Error> {
+        self.router_id.check_certificate()?;
         match self.signature.as_ref() {
             Some(s) => {
                 let sig_msg = self.signature_bytes();
@@ -506,6 +972,9 @@ impl RouterInfo {
 
 #[cfg(test)]
 mod tests {
+    use chrono::TimeZone;
+    use cookie_factory::GenError;
+
     use super::*;
     use crate::tests::{RI_SIGTYPE_1, RI_SIGTYPE_2, ROUTER_INFO};
 
@@ -527,6 +996,101 @@ mod tests {
         assert_eq!(h, h0);
     }
 
+    #[test]
+    fn hash_b32() {
+        const B32: &str = "aeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaq";
+        let h = Hash([1; 32]);
+        assert_eq!(h.to_b32(), format!("{}.b32.i2p", B32));
+
+        // The suffix is optional, and case doesn't matter
+        assert_eq!(Hash::from_b32(&h.to_b32()), Ok(h.clone()));
+        assert_eq!(Hash::from_b32(B32), Ok(h.clone()));
+        assert_eq!(Hash::from_b32(&B32.to_uppercase()), Ok(h.clone()));
+        assert_eq!(Hash::from_b32(&h.to_b32().to_uppercase()), Ok(h));
+
+        match Hash::from_b32("not base32!.b32.i2p") {
+            Err(ReadError::Base32(_)) => (),
+            _ => panic!("Expected a base32 error"),
+        }
+        match Hash::from_b32("aeaqcaib.b32.i2p") {
+            Err(ReadError::Base32(_)) => (),
+            _ => panic!("Expected a length error"),
+        }
+    }
+
+    #[test]
+    fn i2p_date_ordering() {
+        let now = I2PDate::now();
+        let earlier = I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        let later = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(60));
+
+        assert!(earlier < now && now < later);
+        assert_eq!(vec![later, now, earlier].into_iter().max(), Some(later));
+        assert_eq!(vec![later, now, earlier].into_iter().min(), Some(earlier));
+
+        assert!(earlier.is_expired());
+        assert!(!later.is_expired());
+        assert!(I2PDate(0).is_expired());
+    }
+
+    #[test]
+    fn hash_distance() {
+        let key_min = Hash([0; 32]);
+        let key_max = Hash([0xff; 32]);
+        let mut hash1 = Hash([0; 32]);
+        hash1.0[31] = 1;
+        let mut hash2 = Hash([0; 32]);
+        hash2.0[31] = 2;
+        let mut hash1le = Hash([0; 32]);
+        hash1le.0[0] = 1;
+        let hashes = [&key_min, &key_max, &hash1, &hash2, &hash1le];
+
+        for a in &hashes {
+            // Self-distance is zero
+            assert_eq!(a.distance(a), key_min);
+            for b in &hashes {
+                // Distance is symmetric, and only zero between equal hashes
+                assert_eq!(a.distance(b), b.distance(a));
+                assert_eq!(a.distance(b) == key_min, a == b);
+            }
+        }
+
+        assert!(key_min.distance(&key_min) < hash1.distance(&key_min));
+        assert!(hash1.distance(&key_min) < hash2.distance(&key_min));
+        assert!(hash1.distance(&key_min) < hash1le.distance(&key_min));
+
+        assert!(key_max.distance(&key_max) < hash1.distance(&key_max));
+        assert!(hash1.distance(&key_max) > hash2.distance(&key_max));
+        assert!(hash1.distance(&key_max) > hash1le.distance(&key_max));
+    }
+
+    #[test]
+    fn hash_routing_key() {
+        let date = Utc.ymd(2019, 2, 14).and_hms(23, 59, 59);
+        assert_eq!(
+            Hash([0; 32]).routing_key(date),
+            Hash([
+                0xf5, 0xf6, 0x83, 0x3d, 0x44, 0x62, 0xee, 0x4e, 0x7c, 0x92, 0x86, 0x4f, 0xdf, 0xff,
+                0x78, 0x82, 0xea, 0xa9, 0x5d, 0x0a, 0x91, 0x7a, 0xdc, 0x9c, 0x5d, 0xbc, 0x31, 0xd3,
+                0x24, 0x4a, 0xba, 0x34,
+            ])
+        );
+
+        // The key only depends on the date
+        let same_day = Utc.ymd(2019, 2, 14).and_hms(0, 0, 0);
+        let next_day = Utc.ymd(2019, 2, 15).and_hms(0, 0, 0);
+        let h = Hash([1; 32]);
+        assert_eq!(h.routing_key(date), h.routing_key(same_day));
+        assert_ne!(h.routing_key(date), h.routing_key(next_day));
+    }
+
+    #[test]
+    fn router_info_routing_key() {
+        let ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        let date = Utc::now();
+        assert_eq!(ri.routing_key(date), ri.router_id.hash().routing_key(date));
+    }
+
     #[test]
     fn i2pstring_to_csv() {
         let s1 = I2PString(String::from("a-b,c/d,1,2"));
@@ -544,6 +1108,47 @@ mod tests {
         assert_eq!(s2.to_csv(), vec![s2]);
     }
 
+    #[test]
+    fn version_parse() {
+        let v = |s: &str| s.parse::<Version>().unwrap().0;
+        assert_eq!(v("0"), vec![0]);
+        assert_eq!(v("0.9.50"), vec![0, 9, 50]);
+        assert_eq!(v("0.9.50-1"), vec![0, 9, 50]);
+        assert_eq!(v("0.9.50rc2.3"), vec![0, 9, 50]);
+        assert_eq!(v("1.5.0-0ubuntu1"), vec![1, 5, 0]);
+
+        for bad in &["", ".", "0.", ".9", "a.b", "0..9", "-1", "99999999999"] {
+            assert_eq!(bad.parse::<Version>(), Err(ReadError::Parser));
+        }
+    }
+
+    #[test]
+    fn version_ord() {
+        let v = |s: &str| s.parse::<Version>().unwrap();
+        assert!(v("0.9.37") < v("0.9.50"));
+        assert!(v("0.9.9") < v("0.9.10"));
+        assert!(v("0.9.50") < v("1.5.0"));
+        assert!(v("0.9") < v("0.9.1"));
+        assert!(v("0.9.50-1") < v("0.9.51"));
+        assert_eq!(v("0.9"), v("0.9.0"));
+        assert_eq!(v("0.9.50-1"), v("0.9.50"));
+    }
+
+    #[test]
+    fn version_from_option() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        assert_eq!(Version::from_option(&ri), Some(Version(vec![0, 9, 37])));
+
+        ri.options.0.remove(&OPT_ROUTER_VERSION);
+        assert_eq!(Version::from_option(&ri), None);
+
+        ri.options
+            .0
+            .insert(OPT_ROUTER_VERSION.clone(), I2PString::new("garbage"));
+        assert_eq!(Version::from_option(&ri), None);
+    }
+
     #[test]
     fn router_identity_hash() {
         let ri_hash = Hash([
@@ -559,6 +1164,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn router_identity_sig_types() {
+        let rsk = RouterSecretKeys::with_sig_type(SigType::Ed25519).unwrap();
+        assert!(rsk.rid.check_certificate().is_ok());
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign(&rsk.signing_private_key);
+        assert!(ri.verify().is_ok());
+
+        // We can't generate keys for types we can't sign with
+        for &sig_type in &[
+            SigType::DsaSha1,
+            SigType::EcdsaSha256P256,
+            SigType::EcdsaSha384P384,
+            SigType::EcdsaSha512P521,
+            SigType::Rsa2048Sha256,
+        ] {
+            assert_eq!(
+                RouterSecretKeys::with_sig_type(sig_type).err(),
+                Some(crypto::Error::UnsupportedSigType)
+            );
+        }
+
+        // ECDSA public keys fit in a KeyCertificate
+        for &sig_type in &[SigType::EcdsaSha256P256, SigType::EcdsaSha384P384] {
+            let key_data = vec![1; sig_type.pubkey_len() as usize];
+            let signing_key = SigningPublicKey::from_bytes(sig_type, &key_data).unwrap();
+            let rid = RouterIdentity::from_keys(PublicKey([1; 256]), signing_key).unwrap();
+            assert_eq!(rid.certificate.code(), constants::KEY_CERT);
+            assert!(rid.check_certificate().is_ok());
+            match frame::router_identity(&rid.to_bytes()) {
+                Ok((_, parsed)) => assert_eq!(parsed, rid),
+                Err(e) => panic!("Failed to parse RouterIdentity: {:?}", e),
+            }
+        }
+        assert_eq!(
+            RouterIdentity::from_keys(PublicKey([1; 256]), SigningPublicKey::EcdsaSha512P521).err(),
+            Some(crypto::Error::UnsupportedSigType)
+        );
+    }
+
     #[test]
     fn router_address_options() {
         let style = I2PString::new("test");
@@ -572,6 +1217,20 @@ mod tests {
         assert_eq!(ra.option(&key).unwrap(), &value);
     }
 
+    #[test]
+    fn router_address_introducer() {
+        let style = I2PString::new("SSU2");
+        let mut ra = RouterAddress::new(&style, "127.0.0.1:0".parse().unwrap());
+        assert!(!ra.is_introducer());
+
+        // Other capabilities are kept, and the cap is only added once
+        ra.set_option(I2PString::new("caps"), I2PString::new("B"));
+        ra.set_introducer();
+        ra.set_introducer();
+        assert!(ra.is_introducer());
+        assert_eq!(ra.option(&I2PString::new("caps")), Some(&I2PString::new("BC")));
+    }
+
     #[test]
     fn router_info_address() {
         let rsk = RouterSecretKeys::new();
@@ -612,6 +1271,282 @@ mod tests {
         assert!(ri.verify().is_ok());
     }
 
+    fn router_info_with_addresses(count: u16) -> RouterInfo {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.set_addresses(
+            (0..count)
+                .map(|i| {
+                    let addr = SocketAddr::from(([203, 0, 113, i as u8], 10_000 + i));
+                    let mut ra = RouterAddress::new(&I2PString::new("NTCP2"), addr);
+                    ra.options
+                        .0
+                        .insert(I2PString::new("s"), I2PString(format!("{:0>44}", i)));
+                    ra.options
+                        .0
+                        .insert(I2PString::new("i"), I2PString(format!("{:0>24}", i)));
+                    ra.options
+                        .0
+                        .insert(I2PString::new("v"), I2PString::new("2"));
+                    ra
+                })
+                .collect(),
+        );
+        ri.sign(&rsk.signing_private_key);
+        ri
+    }
+
+    #[test]
+    fn router_info_to_bytes_many_addresses() {
+        // Grows the buffer one field at a time, as serialize() used to
+        fn serialize_incrementally(ri: &RouterInfo) -> Vec<u8> {
+            let mut buf = vec![];
+            loop {
+                match frame::gen_router_info((&mut buf, 0), ri).map(|tup| tup.1) {
+                    Ok(sz) => {
+                        buf.truncate(sz);
+                        return buf;
+                    }
+                    Err(GenError::BufferTooSmall(sz)) => buf.resize(sz, 0),
+                    Err(_) => panic!("Couldn't serialize"),
+                }
+            }
+        }
+
+        let ri = router_info_with_addresses(12);
+        let data = ri.to_bytes();
+        assert!(data.len() > 1024);
+        assert_eq!(data, serialize_incrementally(&ri));
+
+        let (_, parsed) = frame::router_info(&data).unwrap();
+        assert_eq!(parsed.addresses().len(), 12);
+        assert!(parsed.verify().is_ok());
+    }
+
+    #[test]
+    fn router_info_sign_at() {
+        let published = I2PDate(1_500_000_000_000);
+        let signed = || {
+            let spk = SigningPrivateKey::from_bytes(SigType::Ed25519, &[7; 32]).unwrap();
+            let signing_key = SigningPublicKey::from_secret(&spk).unwrap();
+            let mut rid = RouterIdentity::from_keys(PublicKey([1; 256]), signing_key).unwrap();
+            let pad_len = SigType::Ed25519.pad_len(EncType::ElGamal2048);
+            rid.padding = Some(Padding(vec![0; pad_len]));
+
+            let mut ri = RouterInfo::new(rid);
+            ri.sign_at(&spk, published);
+            ri
+        };
+
+        let ri = signed();
+        assert_eq!(ri.published, published);
+        assert!(ri.verify().is_ok());
+        assert_eq!(ri.to_bytes(), signed().to_bytes());
+
+        // Re-signing at a different date changes the signature
+        let mut resigned = signed();
+        let spk = SigningPrivateKey::from_bytes(SigType::Ed25519, &[7; 32]).unwrap();
+        resigned.sign_at(&spk, I2PDate(1_500_000_001_000));
+        assert!(resigned.verify().is_ok());
+        assert_ne!(resigned.signature, ri.signature);
+    }
+
+    #[test]
+    fn router_info_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let ri_file = dir.path().join("router.info");
+        let ri_file = ri_file.to_str().unwrap();
+
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        assert_eq!(ri.option(&OPT_NET_ID), Some(&*NET_ID));
+        ri.sign(&rsk.signing_private_key);
+        assert!(ri.verify().is_ok());
+
+        // Setting an option invalidates the signature
+        let key = I2PString::new("key");
+        let value = I2PString::new("value");
+        assert!(ri.option(&key).is_none());
+        ri.set_option(key.clone(), value.clone());
+        ri.set_option(OPT_NET_ID.clone(), I2PString::new("3"));
+        assert_eq!(ri.verify(), Err(crypto::Error::NoSignature));
+        ri.sign(&rsk.signing_private_key);
+
+        ri.to_file(ri_file).unwrap();
+        let read = RouterInfo::from_file(ri_file).unwrap();
+        assert!(read.verify().is_ok());
+        assert_eq!(read.option(&key), Some(&value));
+        assert_eq!(read.network_id(), Some(&I2PString::new("3")));
+    }
+
+    #[test]
+    fn router_secret_keys_encrypted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let rsk_file = dir.path().join("router.keys.enc");
+        let rsk_file = rsk_file.to_str().unwrap();
+
+        let rsk = RouterSecretKeys::new();
+        rsk.to_file_encrypted(rsk_file, "correct horse").unwrap();
+
+        // The keys are not stored in the clear
+        let data = std::fs::read(rsk_file).unwrap();
+        assert!(!data
+            .windows(rsk.private_key.0.len())
+            .any(|w| w == &rsk.private_key.0[..]));
+
+        match RouterSecretKeys::from_file_encrypted(rsk_file, "battery staple") {
+            Err(e) => assert_eq!(e, ReadError::Crypto(crypto::Error::DecryptionFailed)),
+            Ok(_) => panic!("Decrypted keys with the wrong passphrase"),
+        }
+
+        let read = RouterSecretKeys::from_file_encrypted(rsk_file, "correct horse").unwrap();
+        assert_eq!(read.to_bytes(), rsk.to_bytes());
+        assert_eq!(read.rid.hash(), rsk.rid.hash());
+    }
+
+    #[test]
+    fn router_info_address_cost() {
+        let style = I2PString::new("NTCP2");
+        let address = |port: u16, cost: u8| {
+            let mut ra = RouterAddress::new(&style, ([127, 0, 0, 1], port).into());
+            ra.cost = cost;
+            ra
+        };
+
+        let mut ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        ri.set_addresses(vec![
+            address(1, 10),
+            address(2, 5),
+            address(3, 5),
+            address(4, 0),
+            RouterAddress::new(&I2PString::new("SSU"), ([127, 0, 0, 1], 5).into()),
+        ]);
+
+        let port = |ra: Option<RouterAddress>| ra.and_then(|ra| ra.addr()).map(|a| a.port());
+        assert_eq!(port(ri.address(&style, |_| true)), Some(4));
+        assert_eq!(ri.address(&style, |_| true).unwrap().cost(), 0);
+
+        // Ties go to the first address
+        assert_eq!(port(ri.address(&style, |ra| ra.cost() > 0)), Some(2));
+        assert_eq!(port(ri.address(&style, |ra| ra.cost() > 5)), Some(1));
+    }
+
+    #[test]
+    fn router_info_address_family() {
+        let style = I2PString::new("NTCP2");
+        let v4 = RouterAddress::new(&style, "127.0.0.1:1".parse().unwrap());
+        let v6 = RouterAddress::new(&style, "[::1]:2".parse().unwrap());
+
+        // IPv6-only routers are reachable
+        let mut ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        ri.set_addresses(vec![v6.clone()]);
+        assert_eq!(ri.address(&style, |_| true), Some(v6.clone()));
+
+        // IPv4 is preferred between addresses of the same cost
+        ri.set_addresses(vec![v6.clone(), v4.clone()]);
+        assert_eq!(ri.matching_addresses(&style, |_| true), vec![&v4, &v6]);
+
+        // but not over a cheaper IPv6 address
+        let mut expensive = v4.clone();
+        expensive.cost = 10;
+        ri.set_addresses(vec![expensive.clone(), v6.clone()]);
+        assert_eq!(ri.matching_addresses(&style, |_| true), vec![&v6, &expensive]);
+    }
+
+    #[test]
+    fn router_info_is_current() {
+        let rsk = RouterSecretKeys::new();
+        let max_age = Duration::from_secs(60 * 60);
+
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.published = I2PDate::from_system_time(SystemTime::now());
+        assert!(ri.is_current(max_age));
+        assert_eq!(ri.expired(), None);
+
+        ri.published = I2PDate::from_system_time(SystemTime::now() - max_age * 24);
+        assert!(!ri.is_current(max_age));
+        assert!(ri.expired().unwrap() >= max_age * 24);
+
+        // The epoch is the oldest possible date
+        ri.published = I2PDate(0);
+        assert!(!ri.is_current(max_age));
+
+        ri.published = I2PDate::from_system_time(SystemTime::now() + max_age);
+        assert!(ri.is_current(max_age));
+    }
+
+    #[test]
+    fn router_info_validate() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.set_addresses(vec![RouterAddress::new(
+            &I2PString::new("test"),
+            "127.0.0.1:12345".parse().unwrap(),
+        )]);
+        ri.sign(&rsk.signing_private_key);
+        assert_eq!(
+            ri.validate(),
+            RouterInfoValidation {
+                signature: Ok(()),
+                net_id: true,
+                expired: None,
+                has_valid_address: true,
+                caps_parseable: true,
+            }
+        );
+
+        // Validly signed, but expired
+        ri.published = I2PDate::from_system_time(
+            SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+        );
+        ri.sign(&rsk.signing_private_key);
+        let validation = ri.validate();
+        assert_eq!(validation.signature, Ok(()));
+        assert!(validation.expired.unwrap() > Duration::from_secs(ROUTER_INFO_EXPIRATION));
+
+        // Tampered with after signing
+        ri.options.0.insert(OPT_CAPS.clone(), "KU?".into());
+        let validation = ri.validate();
+        assert_eq!(validation.signature, Err(crypto::Error::InvalidSignature));
+        assert!(!validation.caps_parseable);
+    }
+
+    #[test]
+    fn router_info_certificate_mismatch() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign(&rsk.signing_private_key);
+        assert_eq!(ri.router_id.check_certificate(), Ok(()));
+        assert_eq!(ri.verify(), Ok(()));
+
+        // Certificate claims a different signature type
+        let mut forged = ri.clone();
+        match forged.router_id.certificate {
+            Certificate::Key(ref mut kc) => kc.sig_type = SigType::EcdsaSha256P256,
+            _ => panic!("Expected a key certificate"),
+        }
+        assert_eq!(
+            forged.router_id.check_certificate(),
+            Err(crypto::Error::CertificateMismatch)
+        );
+        assert_eq!(forged.verify(), Err(crypto::Error::CertificateMismatch));
+
+        // Null certificates imply DSA-SHA1 keys
+        let mut forged = ri.clone();
+        forged.router_id.certificate = Certificate::Null;
+        assert_eq!(forged.verify(), Err(crypto::Error::CertificateMismatch));
+
+        // Padding is missing
+        let mut forged = ri.clone();
+        forged.router_id.padding = None;
+        assert_eq!(forged.verify(), Err(crypto::Error::CertificateMismatch));
+
+        // A consistent identity with a bad signature is still reported as such
+        let mut tampered = ri;
+        tampered.published = I2PDate(tampered.published.0 + 1000);
+        assert_eq!(tampered.verify(), Err(crypto::Error::InvalidSignature));
+    }
+
     fn router_info_verify(data: &[u8]) {
         match frame::router_info(data) {
             Ok((_, ri)) => {
@@ -635,4 +1570,50 @@ mod tests {
     fn router_info_verify_sigtype_7() {
         router_info_verify(ROUTER_INFO)
     }
+
+    #[test]
+    fn router_info_validate_batch() {
+        let mut ris: Vec<_> = (0..4)
+            .map(|_| {
+                let rsk = RouterSecretKeys::new();
+                let mut ri = RouterInfo::new(rsk.rid);
+                ri.sign(&rsk.signing_private_key);
+                ri
+            })
+            .collect();
+        ris[2].published = I2PDate(ris[2].published.0 + 1000);
+        ris[3].signature = None;
+        ris.push(frame::router_info(RI_SIGTYPE_1).unwrap().1);
+
+        let signatures: Vec<_> = RouterInfo::validate_batch(&ris)
+            .into_iter()
+            .map(|v| v.signature)
+            .collect();
+        assert_eq!(
+            signatures,
+            vec![
+                Ok(()),
+                Ok(()),
+                Err(crypto::Error::InvalidSignature),
+                Err(crypto::Error::NoSignature),
+                Ok(()),
+            ]
+        );
+
+        // The results match those of validating individually
+        for (ri, v) in ris.iter().zip(RouterInfo::validate_batch(&ris)) {
+            assert_eq!(ri.validate().signature, v.signature);
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    mod bench {
+        use test::Bencher;
+
+        #[bench]
+        fn router_info_to_bytes(b: &mut Bencher) {
+            let ri = super::router_info_with_addresses(12);
+            b.iter(|| ri.to_bytes());
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use cookie_factory::*;
 use nom::*;
 use std::io::{Cursor, Read};
 use std::str::from_utf8;
@@ -5,7 +6,7 @@ use zip;
 
 use super::{Error, Su3Content, Su3File, SU3_MAGIC};
 use crate::crypto::{
-    frame::{sig_type, signature},
+    frame::{gen_sig_type, sig_type, signature},
     SigType,
 };
 use crate::data::{frame::router_info, ReadError};
@@ -86,6 +87,37 @@ named!(pub su3_file<Su3File>, do_parse!(
     })
 ));
 
+pub fn gen_su3_file_minus_sig<'a>(
+    input: (&'a mut [u8], usize),
+    version: &[u8],
+    signer: &str,
+    sig_type: SigType,
+    content: &[u8],
+) -> Result<(&'a mut [u8], usize), GenError> {
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    do_gen!(
+        input,
+        gen_slice!(SU3_MAGIC) >>
+        gen_be_u8!(0) >>
+        gen_be_u8!(0) >>
+        gen_sig_type(sig_type) >>
+        gen_be_u16!(sig_type.sig_len() as u16) >>
+        gen_be_u8!(0) >>
+        gen_be_u8!(version.len() as u8) >>
+        gen_be_u8!(0) >>
+        gen_be_u8!(signer.len() as u8) >>
+        gen_be_u64!(content.len() as u64) >>
+        gen_be_u8!(0) >>
+        gen_be_u8!(0x00) >>
+        gen_be_u8!(0) >>
+        gen_be_u8!(0x03) >>
+        gen_slice!(&[0; 12]) >>
+        gen_slice!(version) >>
+        gen_slice!(signer.as_bytes()) >>
+        gen_slice!(content)
+    )
+}
+
 // Simple HTTP parser to convert status code into an error
 named!(pub http_status_line<Result<(), Error>>, do_parse!(
     tag!("HTTP/1.") >> one_of!("01") >> char!(' ') >> status: take!(3) >> (
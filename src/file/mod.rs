@@ -1,13 +1,19 @@
 use nom::{self, take_until};
 use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
 
-use crate::crypto::{self, OfflineSigningPublicKey, SigType, Signature};
+use crate::crypto::{self, OfflineSigningPublicKey, SigType, Signature, SigningPrivateKey};
 use crate::data::{ReadError, RouterInfo};
+use crate::util::serialize;
 
 mod frame;
 
 const SU3_MAGIC: &[u8; 6] = b"I2Psu3";
 
+/// The version field is zero-padded to at least this length.
+const SU3_MIN_VERSION_LEN: usize = 16;
+
 /// SU3 errors
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
@@ -15,6 +21,7 @@ pub enum Error {
     Http(u16),
     Read(ReadError),
     UnknownSigner,
+    Write(String),
 }
 
 impl From<crypto::Error> for Error {
@@ -34,6 +41,26 @@ pub enum Su3Content {
     Reseed(Vec<RouterInfo>),
 }
 
+impl Su3Content {
+    /// Serializes the content as a zip file, with each RouterInfo stored under
+    /// its canonical filename.
+    fn to_zip(&self) -> Result<Vec<u8>, Error> {
+        let Su3Content::Reseed(ris) = self;
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for ri in ris {
+            zip.start_file(format!("routerInfo-{}.dat", ri.router_id.hash()), options)
+                .map_err(|e| Error::Write(e.to_string()))?;
+            zip.write_all(&ri.to_bytes()).map_err(|e| Error::Write(e.to_string()))?;
+        }
+
+        zip.finish()
+            .map(|cursor| cursor.into_inner())
+            .map_err(|e| Error::Write(e.to_string()))
+    }
+}
+
 #[derive(Debug)]
 pub struct Su3File {
     version: String,
@@ -73,6 +100,30 @@ impl Su3File {
 
         Ok(su3_file)
     }
+
+    /// Creates a signed SU3 file containing the given content.
+    pub fn create_signed(
+        version: &str,
+        signer: &str,
+        content: &Su3Content,
+        spk: &SigningPrivateKey,
+    ) -> Result<Vec<u8>, Error> {
+        if version.len() > 255 || signer.len() > 255 {
+            return Err(Error::Write("Version or signer too long".to_string()));
+        }
+        let mut version = version.as_bytes().to_vec();
+        if version.len() < SU3_MIN_VERSION_LEN {
+            version.resize(SU3_MIN_VERSION_LEN, 0);
+        }
+
+        let content = content.to_zip()?;
+        let mut data = serialize(|input| {
+            frame::gen_su3_file_minus_sig(input, &version, signer, spk.sig_type(), &content)
+        });
+        let sig = spk.sign(&data)?;
+        data.extend(sig.to_bytes());
+        Ok(data)
+    }
 }
 
 #[cfg(test)]
@@ -15,9 +15,8 @@ use crate::crypto::frame::{gen_session_key, session_key};
 use crate::data::{
     dest::frame::{gen_lease_set, lease_set},
     frame::{
-        certificate, gen_certificate, gen_hash, gen_i2p_date, gen_router_info, gen_session_tag,
-        gen_short_expiry, gen_tunnel_id, hash, i2p_date, router_info, session_tag, short_expiry,
-        tunnel_id,
+        certificate, gen_certificate, gen_hash, gen_i2p_date, gen_session_tag, gen_short_expiry,
+        gen_tunnel_id, hash, i2p_date, router_info, session_tag, short_expiry, tunnel_id,
     },
 };
 
@@ -164,11 +163,18 @@ pub fn gen_build_response_record<'a>(
 
 // DatabaseStore
 
+/// The largest RouterInfo we will inflate from a DatabaseStore. Real
+/// RouterInfos are a few kilobytes; this guards against decompression bombs.
+const MAX_INFLATED_RI_LEN: u64 = 64 * 1024;
+
 fn compressed_ri<'a>(input: &'a [u8]) -> IResult<&'a [u8], RouterInfo> {
     let (i, payload) = do_parse!(input, size: be_u16 >> payload: take!(size) >> (payload))?;
     let mut buf = Vec::new();
-    let mut d = GzDecoder::new(payload);
+    let mut d = GzDecoder::new(payload).take(MAX_INFLATED_RI_LEN + 1);
     match d.read_to_end(&mut buf) {
+        Ok(n) if n as u64 > MAX_INFLATED_RI_LEN => {
+            Err(Err::Error(error_position!(input, ErrorKind::Custom(1))))
+        }
         Ok(_) => match router_info(&buf) {
             Ok((_, ri)) => Ok((i, ri)),
             Err(Err::Incomplete(n)) => Err(Err::Incomplete(n)),
@@ -183,12 +189,9 @@ fn gen_compressed_ri<'a>(
     input: (&'a mut [u8], usize),
     ri: &RouterInfo,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    let mut buf = Vec::new();
-    gen_router_info((&mut buf, 0), ri)?;
     let mut e = GzEncoder::new(Vec::new(), Compression::best());
-    match e.write(&buf) {
-        Ok(n) if n < buf.len() => Err(GenError::CustomError(1)),
-        Ok(_) => match e.finish() {
+    match e.write_all(&ri.to_bytes()) {
+        Ok(()) => match e.finish() {
             Ok(payload) => do_gen!(input, gen_be_u16!(payload.len()) >> gen_slice!(payload)),
             Err(_) => Err(GenError::CustomError(1)),
         },
@@ -519,22 +522,22 @@ fn gen_garlic_clove<'a>(
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 named!(
-    garlic<MessagePayload>,
+    pub garlic_cloves<Garlic>,
     do_parse!(
         cloves:     length_count!(be_u8, garlic_clove) >>
         cert:       certificate >>
         msg_id:     be_u32 >>
         expiration: i2p_date >>
-        (MessagePayload::Garlic(Garlic {
+        (Garlic {
             cloves,
             cert,
             msg_id,
             expiration,
-        }))
+        })
     )
 );
 
-fn gen_garlic<'a>(
+pub fn gen_garlic_cloves<'a>(
     input: (&'a mut [u8], usize),
     g: &Garlic,
 ) -> Result<(&'a mut [u8], usize), GenError> {
@@ -548,6 +551,55 @@ fn gen_garlic<'a>(
     )
 }
 
+/// The decrypted AES block of a Garlic message, without its padding.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(
+    pub garlic_aes_block<(Vec<SessionTag>, Hash, Option<SessionKey>, &[u8])>,
+    do_parse!(
+        tags:         length_count!(be_u16, session_tag) >>
+        payload_size: be_u32 >>
+        payload_hash: hash >>
+        flag:         be_u8 >>
+        new_key:      cond!(flag == 0x01, call!(session_key)) >>
+        payload:      take!(payload_size) >>
+        ((tags, payload_hash, new_key, payload))
+    )
+);
+
+pub fn gen_garlic_aes_block<'a>(
+    input: (&'a mut [u8], usize),
+    tags: &[SessionTag],
+    new_key: Option<&SessionKey>,
+    payload: &[u8],
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let payload_hash = Hash::digest(payload);
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    do_gen!(
+        input,
+        gen_be_u16!(tags.len() as u16) >>
+        gen_many!(tags, gen_session_tag) >>
+        gen_be_u32!(payload.len() as u32) >>
+        gen_hash(&payload_hash) >>
+        gen_be_u8!(if new_key.is_some() { 0x01 } else { 0x00 }) >>
+        gen_cond!(new_key.is_some(), do_gen!(gen_session_key(new_key.unwrap()))) >>
+        gen_slice!(payload)
+    )
+}
+
+named!(
+    garlic<MessagePayload>,
+    do_parse!(
+        data: length_bytes!(be_u32) >> (MessagePayload::Garlic(EncryptedGarlic(Vec::from(data))))
+    )
+);
+
+fn gen_garlic<'a>(
+    input: (&'a mut [u8], usize),
+    g: &EncryptedGarlic,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(input, gen_be_u32!(g.0.len()) >> gen_slice!(g.0))
+}
+
 // TunnelData
 
 named!(
@@ -584,7 +636,10 @@ fn gen_tunnel_gateway<'a>(
     input: (&'a mut [u8], usize),
     tg: &TunnelGateway,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    do_gen!(input, gen_tunnel_id(&tg.tid) >> gen_slice!(tg.data))
+    do_gen!(
+        input,
+        gen_tunnel_id(&tg.tid) >> gen_be_u16!(tg.data.len() as u16) >> gen_slice!(tg.data)
+    )
 }
 
 // Data
@@ -733,6 +788,16 @@ fn validate_checksum<'a>(input: &'a [u8], cs: u8, buf: &[u8]) -> IResult<&'a [u8
     }
 }
 
+// The fixed-size messages must declare exactly the size of their records
+fn validate_size(input: &[u8], msg_type: u8, size: u16) -> IResult<&[u8], ()> {
+    match msg_type {
+        21 | 22 if usize::from(size) != 8 * 528 => {
+            Err(Err::Error(error_position!(input, ErrorKind::Custom(1))))
+        }
+        _ => Ok((input, ())),
+    }
+}
+
 fn gen_checksum(
     input: (&mut [u8], usize),
     start: usize,
@@ -763,7 +828,7 @@ named!(
     )
 );
 
-fn payload<'a>(input: &'a [u8], msg_type: u8) -> IResult<&'a [u8], MessagePayload> {
+fn payload<'a>(input: &'a [u8], msg_type: u8, size: usize) -> IResult<&'a [u8], MessagePayload> {
     switch!(input, value!(msg_type),
         1  => call!(database_store) |
         2  => call!(database_lookup) |
@@ -776,16 +841,23 @@ fn payload<'a>(input: &'a [u8], msg_type: u8) -> IResult<&'a [u8], MessagePayloa
         21 => call!(tunnel_build) |
         22 => call!(tunnel_build_reply) |
         23 => call!(variable_tunnel_build) |
-        24 => call!(variable_tunnel_build_reply)
+        24 => call!(variable_tunnel_build_reply) |
+        _  => map!(take!(size), |data| MessagePayload::Unknown(msg_type, Vec::from(data)))
     )
 }
 
+/// NTCP2 messages have no size field; the payload is the rest of the block.
+fn ntcp2_payload(input: &[u8], msg_type: u8) -> IResult<&[u8], MessagePayload> {
+    payload(input, msg_type, input.len())
+}
+
 named!(pub message<Message>,
     do_parse!(
         hdr:           header >>
+                       call!(validate_size, hdr.0, hdr.3) >>
         payload_bytes: peek!(take!(hdr.3)) >>
                        call!(validate_checksum, hdr.4, payload_bytes) >>
-        payload: call!(payload, hdr.0) >>
+        payload: call!(payload, hdr.0, usize::from(hdr.3)) >>
         (Message {
             id: hdr.1,
             expiration: hdr.2,
@@ -797,7 +869,7 @@ named!(pub message<Message>,
 named!(pub ntcp2_message<Message>,
     do_parse!(
         hdr:     ntcp2_header >>
-        payload: call!(payload, hdr.0) >>
+        payload: call!(ntcp2_payload, hdr.0) >>
         (Message {
             id: hdr.1,
             expiration: hdr.2,
@@ -806,6 +878,22 @@ named!(pub ntcp2_message<Message>,
     )
 );
 
+/// SSU messages have neither an ID nor a size field; the ID is that of the
+/// fragments that carried the message, and the payload is the rest of them.
+pub fn ssu_message(input: &[u8], msg_id: u32) -> IResult<&[u8], Message> {
+    do_parse!(
+        input,
+        msg_type: be_u8
+            >> expiration: short_expiry
+            >> payload: call!(ntcp2_payload, msg_type)
+            >> (Message {
+                id: msg_id,
+                expiration,
+                payload,
+            })
+    )
+}
+
 fn gen_message_type<'a>(
     input: (&'a mut [u8], usize),
     msg: &Message,
@@ -823,6 +911,7 @@ fn gen_message_type<'a>(
         MessagePayload::TunnelBuildReply(_) => 22,
         MessagePayload::VariableTunnelBuild(_) => 23,
         MessagePayload::VariableTunnelBuildReply(_) => 24,
+        MessagePayload::Unknown(msg_type, _) => msg_type,
     };
     gen_be_u8!(input, msg_type)
 }
@@ -846,6 +935,7 @@ fn gen_payload<'a>(
         MessagePayload::VariableTunnelBuildReply(ref vtbr) => {
             gen_variable_tunnel_build_reply(input, &vtbr)
         }
+        MessagePayload::Unknown(_, ref data) => gen_slice!(input, data),
     }
 }
 
@@ -878,12 +968,24 @@ pub fn gen_ntcp2_message<'a>(
     )
 }
 
+pub fn gen_ssu_message<'a>(
+    input: (&'a mut [u8], usize),
+    msg: &Message,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_message_type(msg) >> gen_short_expiry(&msg.expiration) >> gen_payload(&msg.payload)
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use std::time::UNIX_EPOCH;
 
+    use crate::data::RouterSecretKeys;
+
     macro_rules! bake_and_eat {
         ($oven:expr, $monster:expr, $value:expr, $expected:expr) => {
             let mut res = vec![];
@@ -1108,6 +1210,70 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tunnel_build() {
+        let mut records = [[0u8; 528]; 8];
+        for (i, record) in records.iter_mut().enumerate() {
+            record[0] = i as u8;
+            record[527] = 0xff - i as u8;
+        }
+
+        for payload in vec![
+            MessagePayload::TunnelBuild(records),
+            MessagePayload::TunnelBuildReply(records),
+        ] {
+            let msg = Message {
+                id: 42,
+                expiration: I2PDate::from_system_time(UNIX_EPOCH + Duration::new(1_524_874_654, 0)),
+                payload,
+            };
+            let data = serialize(|input| gen_message(input, &msg));
+            assert_eq!(data.len(), 16 + 8 * 528);
+
+            match message(&data) {
+                Ok((rest, parsed)) => {
+                    assert!(rest.is_empty());
+                    assert_eq!(parsed, msg);
+                    match (parsed.payload, msg.payload) {
+                        (MessagePayload::TunnelBuild(a), MessagePayload::TunnelBuild(b))
+                        | (
+                            MessagePayload::TunnelBuildReply(a),
+                            MessagePayload::TunnelBuildReply(b),
+                        ) => assert!(a.iter().zip(b.iter()).all(|(a, b)| a[..] == b[..])),
+                        _ => panic!("Payload type changed"),
+                    }
+                }
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tunnel_build_wrong_record_count() {
+        // Builds a TunnelBuild message containing the given number of records
+        let tunnel_build = |count: usize| {
+            let payload = vec![0x5a; count * 528];
+            let mut data = vec![21, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+            data.push((payload.len() >> 8) as u8);
+            data.push((payload.len() & 0xff) as u8);
+            data.push(checksum(&payload));
+            data.extend(payload);
+            data
+        };
+
+        match message(&tunnel_build(8)) {
+            Ok((rest, _)) => assert!(rest.is_empty()),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+        for &count in &[0, 7, 9] {
+            match message(&tunnel_build(count)) {
+                Err(Err::Error(_)) => (),
+                Ok(_) => panic!("Accepted TunnelBuild with {} records", count),
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+
     #[test]
     fn test_validate_checksum() {
         let a = b"payloadspam";
@@ -1134,6 +1300,47 @@ mod tests {
         assert_eq!(n, 1);
     }
 
+    #[test]
+    fn test_database_store_ri() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign(&rsk.signing_private_key);
+
+        let mut buf = vec![0; 4096];
+        let len = gen_database_store((&mut buf, 0), &DatabaseStore::from_ri(ri.clone(), None))
+            .unwrap()
+            .1;
+        let uncompressed_len = ri.to_bytes().len();
+        // key + type + reply token + RI size + RI
+        assert!(len < 32 + 1 + 4 + 2 + uncompressed_len);
+
+        match database_store(&buf[..len]) {
+            Ok((rest, MessagePayload::DatabaseStore(ds))) => {
+                assert!(rest.is_empty());
+                assert_eq!(ds.key, ri.router_id.hash());
+                match ds.data {
+                    DatabaseStoreData::RI(parsed) => assert_eq!(parsed, ri),
+                    DatabaseStoreData::LS(_) => panic!("Expected a RouterInfo"),
+                }
+            }
+            _ => panic!("Failed to parse DatabaseStore"),
+        }
+    }
+
+    #[test]
+    fn test_compressed_ri_too_large() {
+        let mut e = GzEncoder::new(Vec::new(), Compression::best());
+        e.write_all(&[0; 1024 * 1024]).unwrap();
+        let payload = e.finish().unwrap();
+
+        let mut data = vec![(payload.len() >> 8) as u8, payload.len() as u8];
+        data.extend_from_slice(&payload);
+        match compressed_ri(&data) {
+            Err(Err::Error(_)) => (),
+            _ => panic!("Should have refused to inflate more than the limit"),
+        }
+    }
+
     #[test]
     fn test_message() {
         macro_rules! eval {
@@ -1168,6 +1375,16 @@ mod tests {
                 0x0c, 0xf9, 0x7b, 0x3f, 0xbb, 0xa9, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
             ]
         );
+
+        // Unknown message types are passed through as-is
+        eval!(
+            Message {
+                id: 0,
+                expiration: I2PDate::from_system_time(UNIX_EPOCH),
+                payload: MessagePayload::Unknown(0x7f, vec![1, 2, 3]),
+            },
+            [0x7f, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 3, 1, 2, 3]
+        );
     }
 
     #[test]
@@ -1201,5 +1418,37 @@ mod tests {
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
             ]
         );
+
+        // Unknown message types take up the rest of the block
+        eval!(
+            Message {
+                id: 0,
+                expiration: I2PDate::from_system_time(UNIX_EPOCH),
+                payload: MessagePayload::Unknown(0x7f, vec![1, 2, 3]),
+            },
+            [0x7f, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_ssu_message() {
+        let msg = Message {
+            id: 0x1234_5678,
+            expiration: I2PDate::from_system_time(UNIX_EPOCH),
+            payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+        };
+        let expected = [20, 0, 0, 0, 0, 0, 0, 0, 10, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+        let mut res = vec![0; expected.len()];
+        match gen_ssu_message((&mut res, 0), &msg) {
+            Ok(_) => assert_eq!(&res[..], &expected[..]),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        // The ID comes from the fragments that carried the message
+        match ssu_message(&res, msg.id) {
+            Ok((_, m)) => assert_eq!(m, msg),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
     }
 }
@@ -8,10 +8,14 @@
 //!
 //! [I2NP specification](https://geti2p.net/spec/i2np)
 
+use aes::{self, cipher::generic_array::GenericArray as AesGenericArray};
+use block_modes::{block_padding::NoPadding, BlockMode, Cbc};
 use nom;
 use rand::{rngs::OsRng, thread_rng, Rng};
+use std::collections::HashMap;
 use std::fmt;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::crypto::{self, elgamal, SessionKey};
 use crate::data::{
@@ -46,6 +50,36 @@ impl<T> From<nom::Err<T>> for BuildRequestError {
     }
 }
 
+/// Errors while decrypting the reply to a tunnel build request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildReplyError {
+    /// The response of the given hop was invalid.
+    Read(usize, ReadError),
+    /// The reply had no record at the given index.
+    MissingRecord(usize),
+}
+
+/// Garlic message errors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GarlicError {
+    Crypto(crypto::Error),
+    Read(ReadError),
+    /// The payload didn't match its hash, or came with too many session tags.
+    Invalid,
+}
+
+impl From<crypto::Error> for GarlicError {
+    fn from(e: crypto::Error) -> Self {
+        GarlicError::Crypto(e)
+    }
+}
+
+impl<T> From<nom::Err<T>> for GarlicError {
+    fn from(e: nom::Err<T>) -> Self {
+        GarlicError::Read(e.into())
+    }
+}
+
 //
 // Common structures
 //
@@ -128,6 +162,15 @@ impl BuildRequestRecord {
     }
 }
 
+/// Why a hop declined to participate in a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelRejection {
+    ProbabilisticReject,
+    TransientOverload,
+    Bandwidth,
+    Critical,
+}
+
 /// Reply to a BuildRequestRecord stating whether or not a particular hop agrees
 /// to participate.
 #[derive(Debug, PartialEq)]
@@ -135,6 +178,56 @@ pub struct BuildResponseRecord {
     pub reply: u8,
 }
 
+impl BuildResponseRecord {
+    /// Returns whether the hop agreed to participate, or why it declined.
+    ///
+    /// Codes between the defined ones are treated as the defined code below
+    /// them, and codes below all of them as a probabilistic reject.
+    pub fn result(&self) -> Result<(), TunnelRejection> {
+        match self.reply {
+            0 => Ok(()),
+            1..=19 => Err(TunnelRejection::ProbabilisticReject),
+            20..=29 => Err(TunnelRejection::TransientOverload),
+            30..=49 => Err(TunnelRejection::Bandwidth),
+            _ => Err(TunnelRejection::Critical),
+        }
+    }
+
+    /// Decrypts the responses in the reply to a tunnel build request that we
+    /// sent.
+    ///
+    /// `hops` are the records we sent, in the order of the hops in the tunnel,
+    /// each with the index of the record it was sent in. Every hop writes its
+    /// response into its own record and then encrypts all of the records with
+    /// its reply key, so a response is decrypted with the reply keys of the
+    /// last hop back to the hop that wrote it.
+    ///
+    /// Returns the responses in the same order as `hops`.
+    pub fn decrypt_reply(
+        records: &[[u8; 528]],
+        hops: &[(usize, &BuildRequestRecord)],
+    ) -> Result<Vec<Self>, BuildReplyError> {
+        hops.iter()
+            .enumerate()
+            .map(|(hop, &(i, _))| {
+                let mut record = *records.get(i).ok_or(BuildReplyError::MissingRecord(i))?;
+                for (_, brr) in hops[hop..].iter().rev() {
+                    let cipher: Cbc<aes::Aes256, NoPadding> = Cbc::new_fix(
+                        AesGenericArray::from_slice(&brr.reply_key.0),
+                        AesGenericArray::from_slice(&brr.reply_iv),
+                    );
+                    cipher
+                        .decrypt(&mut record)
+                        .expect("Records are a whole number of blocks");
+                }
+                frame::build_response_record(&record)
+                    .map(|(_, response)| response)
+                    .map_err(|e| BuildReplyError::Read(hop, e.into()))
+            })
+            .collect()
+    }
+}
+
 //
 // Messages
 //
@@ -277,8 +370,8 @@ impl fmt::Display for DatabaseSearchReply {
 /// and wrapped in a Garlic message with the message itself, to be returned by
 /// the destination.
 pub struct DeliveryStatus {
-    msg_id: u32,
-    time_stamp: I2PDate,
+    pub(crate) msg_id: u32,
+    pub(crate) time_stamp: I2PDate,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -292,6 +385,9 @@ impl fmt::Display for DeliveryStatus {
     }
 }
 
+/// Clove delivery types.
+const CLOVE_DELIVERY_LOCAL: u8 = 0;
+
 pub struct GarlicCloveDeliveryInstructions {
     encrypted: bool,
     delivery_type: u8,
@@ -301,6 +397,22 @@ pub struct GarlicCloveDeliveryInstructions {
     tid: Option<TunnelId>,
     delay: Option<u32>,
 }
+
+impl GarlicCloveDeliveryInstructions {
+    /// Instructions to deliver a clove to the router that unwraps it.
+    pub fn local() -> Self {
+        GarlicCloveDeliveryInstructions {
+            encrypted: false,
+            delivery_type: CLOVE_DELIVERY_LOCAL,
+            delay_set: false,
+            session_key: None,
+            to_hash: None,
+            tid: None,
+            delay: None,
+        }
+    }
+}
+
 pub struct GarlicClove {
     delivery_instructions: GarlicCloveDeliveryInstructions,
     msg: Message,
@@ -309,6 +421,27 @@ pub struct GarlicClove {
     cert: Certificate,
 }
 
+impl GarlicClove {
+    pub fn new(delivery_instructions: GarlicCloveDeliveryInstructions, msg: Message) -> Self {
+        GarlicClove {
+            delivery_instructions,
+            expiration: msg.expiration,
+            msg,
+            clove_id: thread_rng().gen(),
+            cert: Certificate::Null,
+        }
+    }
+
+    /// Returns true if the clove is meant for the router that unwraps it.
+    pub fn is_local(&self) -> bool {
+        self.delivery_instructions.delivery_type == CLOVE_DELIVERY_LOCAL
+    }
+
+    pub fn into_message(self) -> Message {
+        self.msg
+    }
+}
+
 /// Used to wrap multiple encrypted I2NP messages.
 pub struct Garlic {
     cloves: Vec<GarlicClove>,
@@ -317,6 +450,177 @@ pub struct Garlic {
     expiration: I2PDate,
 }
 
+impl Garlic {
+    pub fn new(cloves: Vec<GarlicClove>) -> Self {
+        Garlic {
+            cloves,
+            cert: Certificate::Null,
+            msg_id: thread_rng().gen(),
+            expiration: I2PDate::from_system_time(
+                SystemTime::now() + Duration::from_millis(MESSAGE_EXPIRATION_MS),
+            ),
+        }
+    }
+
+    pub fn into_cloves(self) -> Vec<GarlicClove> {
+        self.cloves
+    }
+
+    /// Encrypts the message to a recipient that we have no session with,
+    /// starting a session under `key`. The recipient can then use `tags` to
+    /// decrypt later messages that we encrypt with `key`.
+    pub fn encrypt(
+        &self,
+        encryptor: &elgamal::Encryptor,
+        key: &SessionKey,
+        tags: &[SessionTag],
+    ) -> EncryptedGarlic {
+        // The ElGamal block holds the session key and the pre-IV
+        let mut elg_pt = [0; 222];
+        OsRng.fill(&mut elg_pt[..]);
+        elg_pt[..32].copy_from_slice(&key.0);
+
+        let mut data = encryptor
+            .encrypt(&elg_pt, true)
+            .expect("222 bytes fit in an ElGamal block");
+        data.extend(self.aes_block(key, &garlic_iv(&elg_pt[32..64]), tags));
+        EncryptedGarlic(data)
+    }
+
+    /// Encrypts the message with a session tag that the recipient was given
+    /// along with `key`. Each tag can only be used once.
+    pub fn encrypt_with_tag(&self, key: &SessionKey, tag: &SessionTag) -> EncryptedGarlic {
+        let mut data = Vec::from(&tag.0[..]);
+        data.extend(self.aes_block(key, &garlic_iv(&tag.0), &[]));
+        EncryptedGarlic(data)
+    }
+
+    fn aes_block(&self, key: &SessionKey, iv: &[u8; 16], tags: &[SessionTag]) -> Vec<u8> {
+        let payload = serialize(|input| frame::gen_garlic_cloves(input, self));
+        let mut block =
+            serialize(|input| frame::gen_garlic_aes_block(input, tags, None, &payload));
+
+        // Pad to a whole number of AES blocks
+        let mut rng = OsRng;
+        let padding = (16 - block.len() % 16) % 16;
+        block.extend((0..padding).map(|_| rng.gen::<u8>()));
+
+        let cipher: Cbc<aes::Aes256, NoPadding> = Cbc::new_fix(
+            AesGenericArray::from_slice(&key.0),
+            AesGenericArray::from_slice(iv),
+        );
+        cipher.encrypt_vec(&block)
+    }
+}
+
+/// The size of the ElGamal block that starts a Garlic message for a new
+/// session.
+const GARLIC_ELGAMAL_BLOCK_SIZE: usize = 514;
+
+/// The most session tags that a single Garlic message may deliver.
+const MAX_SESSION_TAGS_PER_MESSAGE: usize = 200;
+
+/// The most session tags that we store at once.
+const MAX_SESSION_TAGS: usize = 10_000;
+
+/// How long a session tag can be used for after we receive it.
+const SESSION_TAG_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+/// The IV of a Garlic message's AES block, derived from its pre-IV or its
+/// session tag.
+fn garlic_iv(data: &[u8]) -> [u8; 16] {
+    let mut iv = [0; 16];
+    iv.copy_from_slice(&Hash::digest(data).0[..16]);
+    iv
+}
+
+/// The session tags that peers have delivered to us in Garlic messages, along
+/// with the session keys they go with. Each tag lets a peer send us one more
+/// Garlic message without an ElGamal block.
+#[derive(Clone, Default)]
+pub struct SessionTags(Arc<Mutex<HashMap<[u8; 32], (SessionKey, Instant)>>>);
+
+impl SessionTags {
+    fn add(&self, key: &SessionKey, tags: Vec<SessionTag>) {
+        let mut stored = self.0.lock().unwrap();
+        if stored.len() + tags.len() > MAX_SESSION_TAGS {
+            stored.retain(|_, (_, received)| received.elapsed() < SESSION_TAG_LIFETIME);
+        }
+        for tag in tags {
+            if stored.len() >= MAX_SESSION_TAGS {
+                debug!("Too many session tags, dropping new ones");
+                break;
+            }
+            stored.insert(tag.0, (key.clone(), Instant::now()));
+        }
+    }
+
+    /// Removes and returns the session key for a tag.
+    fn take(&self, tag: &[u8]) -> Option<SessionKey> {
+        match self.0.lock().unwrap().remove(tag) {
+            Some((key, received)) if received.elapsed() < SESSION_TAG_LIFETIME => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// A Garlic message as it is sent between routers, encrypted to the recipient
+/// with ElGamal/AES+SessionTags.
+pub struct EncryptedGarlic(pub(crate) Vec<u8>);
+
+impl EncryptedGarlic {
+    /// Decrypts a Garlic message that was sent to us. A message that starts
+    /// with one of our session tags is decrypted with the tag's session key;
+    /// any other message must start with an ElGamal block for our key.
+    ///
+    /// Session tags delivered with the message are added to `tags`.
+    pub fn decrypt(
+        &self,
+        decryptor: &elgamal::Decryptor,
+        tags: &SessionTags,
+    ) -> Result<Garlic, GarlicError> {
+        let existing = self
+            .0
+            .get(..32)
+            .and_then(|tag| tags.take(tag).map(|key| (key, tag)));
+        let (key, iv, block) = match existing {
+            Some((key, tag)) => (key, garlic_iv(tag), &self.0[32..]),
+            None => {
+                if self.0.len() < GARLIC_ELGAMAL_BLOCK_SIZE {
+                    return Err(GarlicError::Crypto(crypto::Error::InvalidCiphertext));
+                }
+                let (elg_ct, block) = self.0.split_at(GARLIC_ELGAMAL_BLOCK_SIZE);
+                let elg_pt = decryptor.decrypt(elg_ct, true)?;
+                if elg_pt.len() < 64 {
+                    return Err(GarlicError::Crypto(crypto::Error::InvalidCiphertext));
+                }
+                let key = SessionKey(*array_ref![elg_pt, 0, 32]);
+                (key, garlic_iv(&elg_pt[32..64]), block)
+            }
+        };
+        if block.is_empty() || block.len() % 16 != 0 {
+            return Err(GarlicError::Crypto(crypto::Error::InvalidCiphertext));
+        }
+
+        let mut block = Vec::from(block);
+        let cipher: Cbc<aes::Aes256, NoPadding> = Cbc::new_fix(
+            AesGenericArray::from_slice(&key.0),
+            AesGenericArray::from_slice(&iv),
+        );
+        cipher
+            .decrypt(&mut block)
+            .expect("Block is a whole number of AES blocks");
+
+        let (_, (new_tags, payload_hash, new_key, payload)) = frame::garlic_aes_block(&block)?;
+        if new_tags.len() > MAX_SESSION_TAGS_PER_MESSAGE || Hash::digest(payload) != payload_hash {
+            return Err(GarlicError::Invalid);
+        }
+        let (_, garlic) = frame::garlic_cloves(payload)?;
+        tags.add(new_key.as_ref().unwrap_or(&key), new_tags);
+        Ok(garlic)
+    }
+}
+
 /// A message sent from a tunnel's gateway or participant to the next participant
 /// or endpoint. The data is of fixed length, containing I2NP messages that are
 /// fragmented, batched, padded, and encrypted.
@@ -336,8 +640,17 @@ impl TunnelData {
 /// Wraps another I2NP message to be sent into a tunnel at the tunnel's inbound
 /// gateway.
 pub struct TunnelGateway {
-    tid: TunnelId,
-    data: Vec<u8>,
+    pub tid: TunnelId,
+    pub data: Vec<u8>,
+}
+
+impl TunnelGateway {
+    pub fn create_msg(tid: TunnelId, msg: &Message) -> Message {
+        Message::from_payload(MessagePayload::TunnelGateway(TunnelGateway {
+            tid,
+            data: serialize(|input| frame::gen_message(input, msg)),
+        }))
+    }
 }
 
 pub enum MessagePayload {
@@ -345,7 +658,7 @@ pub enum MessagePayload {
     DatabaseLookup(DatabaseLookup),
     DatabaseSearchReply(DatabaseSearchReply),
     DeliveryStatus(DeliveryStatus),
-    Garlic(Garlic),
+    Garlic(EncryptedGarlic),
     TunnelData(TunnelData),
     TunnelGateway(TunnelGateway),
 
@@ -355,6 +668,9 @@ pub enum MessagePayload {
     TunnelBuildReply([[u8; 528]; 8]),
     VariableTunnelBuild(Vec<[u8; 528]>),
     VariableTunnelBuildReply(Vec<[u8; 528]>),
+
+    /// A message type that we don't know how to parse, with its raw payload.
+    Unknown(u8, Vec<u8>),
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -389,6 +705,9 @@ impl fmt::Debug for MessagePayload {
             MessagePayload::VariableTunnelBuildReply(_) => {
                 "VariableTunnelBuildReply".fmt(formatter)
             }
+            MessagePayload::Unknown(msg_type, _) => {
+                format!("Unknown (type: {})", msg_type).fmt(formatter)
+            }
         }
     }
 }
@@ -415,6 +734,9 @@ impl fmt::Display for MessagePayload {
             MessagePayload::VariableTunnelBuildReply(_) => {
                 "VariableTunnelBuildReply".fmt(formatter)
             }
+            MessagePayload::Unknown(msg_type, _) => {
+                format!("Unknown (type: {})", msg_type).fmt(formatter)
+            }
         }
     }
 }
@@ -495,6 +817,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn build_reply_decryption() {
+        let brrs: Vec<_> = (0..3)
+            .map(|i| {
+                BuildRequestRecord::new(
+                    TunnelId(i),
+                    Hash([i as u8; 32]),
+                    TunnelId(i + 1),
+                    Hash([i as u8 + 1; 32]),
+                    ParticipantType::Intermediate,
+                )
+            })
+            .collect();
+        // The hops are given records out of order, and one record is unused
+        let hops: Vec<_> = vec![2, 0, 3].into_iter().zip(brrs.iter()).collect();
+
+        // Each hop writes its response and encrypts every record
+        let mut records = vec![[0; 528]; 4];
+        for (&(i, brr), &reply) in hops.iter().zip(&[0, 30, 0]) {
+            let response = BuildResponseRecord { reply };
+            frame::gen_build_response_record((&mut records[i][..], 0), &response).unwrap();
+            for record in records.iter_mut() {
+                let cipher: Cbc<aes::Aes256, NoPadding> = Cbc::new_fix(
+                    AesGenericArray::from_slice(&brr.reply_key.0),
+                    AesGenericArray::from_slice(&brr.reply_iv),
+                );
+                let ct = cipher.encrypt_vec(&record[..]);
+                record.copy_from_slice(&ct);
+            }
+        }
+
+        let responses = BuildResponseRecord::decrypt_reply(&records, &hops).unwrap();
+        assert_eq!(
+            responses.iter().map(|r| r.result()).collect::<Vec<_>>(),
+            vec![Ok(()), Err(TunnelRejection::Bandwidth), Ok(())]
+        );
+
+        // Decrypting with the keys in the wrong order fails
+        let swapped = vec![hops[1], hops[0], hops[2]];
+        match BuildResponseRecord::decrypt_reply(&records, &swapped) {
+            Err(BuildReplyError::Read(0, _)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // As does a missing record
+        assert_eq!(
+            BuildResponseRecord::decrypt_reply(&records[..3], &hops),
+            Err(BuildReplyError::MissingRecord(3))
+        );
+    }
+
+    #[test]
+    fn build_response_result() {
+        let result = |reply| BuildResponseRecord { reply }.result();
+        assert_eq!(result(0), Ok(()));
+        assert_eq!(result(10), Err(TunnelRejection::ProbabilisticReject));
+        assert_eq!(result(20), Err(TunnelRejection::TransientOverload));
+        assert_eq!(result(30), Err(TunnelRejection::Bandwidth));
+        assert_eq!(result(40), Err(TunnelRejection::Bandwidth));
+        assert_eq!(result(50), Err(TunnelRejection::Critical));
+    }
+
     macro_rules! check_size {
         ($size_func:ident, $header_size:expr) => {{
             assert_eq!(Message::dummy_data().$size_func(), $header_size + 4 + 10);
@@ -509,6 +893,49 @@ mod tests {
         }};
     }
 
+    #[test]
+    fn garlic_encryption() {
+        let (priv_key, pub_key) = elgamal::KeyPairGenerator::generate();
+        let encryptor = elgamal::Encryptor::from(&pub_key);
+        let decryptor = elgamal::Decryptor::from(&priv_key);
+        let tags = SessionTags::default();
+
+        let clove = || {
+            GarlicClove::new(
+                GarlicCloveDeliveryInstructions::local(),
+                Message::dummy_data(),
+            )
+        };
+        let garlic = Garlic::new(vec![clove(), clove()]);
+        let key = SessionKey([3; 32]);
+        let new_tags = vec![SessionTag([4; 32]), SessionTag([5; 32])];
+
+        // A new session is started with ElGamal, and delivers session tags
+        let ct = garlic.encrypt(&encryptor, &key, &new_tags);
+        let pt = ct.decrypt(&decryptor, &tags).unwrap();
+        assert_eq!(pt.msg_id, garlic.msg_id);
+        assert_eq!(pt.into_cloves().len(), 2);
+
+        // Each tag can then be used once
+        let ct = garlic.encrypt_with_tag(&key, &new_tags[0]);
+        assert_eq!(ct.0.len() % 16, 0);
+        assert!(ct.decrypt(&decryptor, &tags).is_ok());
+        assert_eq!(
+            ct.decrypt(&decryptor, &tags).map(|_| ()),
+            Err(GarlicError::Crypto(crypto::Error::InvalidCiphertext))
+        );
+
+        // Unknown tags and cleartext cloves are rejected
+        let ct = garlic.encrypt_with_tag(&key, &SessionTag([6; 32]));
+        assert!(ct.decrypt(&decryptor, &tags).is_err());
+        let cleartext = serialize(|input| frame::gen_garlic_cloves(input, &garlic));
+        assert!(EncryptedGarlic(cleartext).decrypt(&decryptor, &tags).is_err());
+
+        // A tag only works with its session key
+        let ct = garlic.encrypt_with_tag(&SessionKey([7; 32]), &new_tags[1]);
+        assert!(ct.decrypt(&decryptor, &tags).is_err());
+    }
+
     #[test]
     fn message_size() {
         check_size!(size, 16)
@@ -15,6 +15,7 @@ extern crate log;
 #[cfg(all(test, feature = "nightly"))]
 extern crate test;
 
+pub mod client;
 mod constants;
 pub mod crypto;
 pub mod data;
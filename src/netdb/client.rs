@@ -1,17 +1,20 @@
 //! An asynchronous NetDB client.
 
 use futures::{
+    future,
     sync::{mpsc, oneshot},
     Async, Future, Poll,
 };
 use std::sync::Arc;
 use tokio::spawn;
 
-use super::{errors::*, LocalNetworkDatabase};
+use super::{errors::*, LocalNetworkDatabase, NetDbStats, RouterInfoSource};
 use crate::data::{Hash, LeaseSet, RouterInfo};
 
 pub enum Query {
     KnownRouters(oneshot::Sender<usize>),
+    Stats(oneshot::Sender<NetDbStats>),
+    WaitForPeers(usize, oneshot::Sender<usize>),
     SelectClosestFloodfill(Hash, oneshot::Sender<Option<RouterInfo>>),
     LookupRouterInfo(
         Hash,
@@ -28,14 +31,23 @@ pub enum Query {
     StoreRouterInfo(
         Hash,
         RouterInfo,
-        bool,
+        RouterInfoSource,
         oneshot::Sender<Result<Option<RouterInfo>, StoreError>>,
     ),
+    /// Handled by the netDb engine, which verifies the RouterInfos off the
+    /// reactor.
+    StoreRouterInfos(
+        Vec<RouterInfo>,
+        RouterInfoSource,
+        oneshot::Sender<Vec<Result<Option<RouterInfo>, StoreError>>>,
+    ),
     StoreLeaseSet(
         Hash,
         LeaseSet,
         oneshot::Sender<Result<Option<LeaseSet>, StoreError>>,
     ),
+    /// Handled by the netDb engine, which owns the publishing timer.
+    PublishRouterInfo,
 }
 
 impl Query {
@@ -46,6 +58,14 @@ impl Query {
                     warn!("Completed known routers query, but client gave up");
                 }
             }
+            Query::Stats(ret) => {
+                if ret.send(netdb.stats()).is_err() {
+                    warn!("Completed netDb stats query, but client gave up");
+                }
+            }
+            Query::WaitForPeers(n, ret) => netdb.wait_for_peers(n, ret),
+            // Handled by the netDb engine
+            Query::PublishRouterInfo | Query::StoreRouterInfos(..) => (),
             Query::SelectClosestFloodfill(key, ret) => {
                 if ret.send(netdb.select_closest_ff(&key)).is_err() {
                     warn!("Completed floodfill selection, but client gave up");
@@ -71,9 +91,9 @@ impl Query {
                         }),
                 );
             }
-            Query::StoreRouterInfo(key, ri, from_reseed, ret) => {
+            Query::StoreRouterInfo(key, ri, source, ret) => {
                 if ret
-                    .send(netdb.store_router_info(key.clone(), ri, from_reseed))
+                    .send(netdb.store_router_info(key.clone(), ri, source))
                     .is_err()
                 {
                     warn!("Completed RouterInfo store at {}, but client gave up", key);
@@ -121,6 +141,74 @@ impl Future for KnownRouters {
     }
 }
 
+pub struct Stats {
+    client: Client,
+    response_rx: Option<oneshot::Receiver<NetDbStats>>,
+}
+
+impl Stats {
+    fn new(client: Client) -> Self {
+        Stats {
+            client,
+            response_rx: None,
+        }
+    }
+}
+
+impl Future for Stats {
+    type Item = NetDbStats;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.response_rx.is_none() {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.response_rx = Some(response_rx);
+            self.client.send(Query::Stats(response_tx))?;
+        }
+
+        self.response_rx
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Error::Closed)
+    }
+}
+
+pub struct WaitForPeers {
+    client: Client,
+    query: Option<usize>,
+    response_rx: Option<oneshot::Receiver<usize>>,
+}
+
+impl WaitForPeers {
+    fn new(client: Client, n: usize) -> Self {
+        WaitForPeers {
+            client,
+            query: Some(n),
+            response_rx: None,
+        }
+    }
+}
+
+impl Future for WaitForPeers {
+    type Item = usize;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(n) = self.query.take() {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.response_rx = Some(response_rx);
+            self.client.send(Query::WaitForPeers(n, response_tx))?;
+        }
+
+        self.response_rx
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Error::Closed)
+    }
+}
+
 pub struct SelectClosestFloodfill {
     client: Client,
     query: Option<Hash>,
@@ -241,15 +329,15 @@ impl Future for LookupLeaseSet {
 
 pub struct StoreRouterInfo {
     client: Client,
-    query: Option<(Hash, RouterInfo, bool)>,
+    query: Option<(Hash, RouterInfo, RouterInfoSource)>,
     response_rx: Option<oneshot::Receiver<Result<Option<RouterInfo>, StoreError>>>,
 }
 
 impl StoreRouterInfo {
-    fn new(client: Client, key: Hash, ri: RouterInfo, from_reseed: bool) -> Self {
+    fn new(client: Client, key: Hash, ri: RouterInfo, source: RouterInfoSource) -> Self {
         StoreRouterInfo {
             client,
-            query: Some((key, ri, from_reseed)),
+            query: Some((key, ri, source)),
             response_rx: None,
         }
     }
@@ -260,11 +348,11 @@ impl Future for StoreRouterInfo {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Some((key, ri, from_reseed)) = self.query.take() {
+        if let Some((key, ri, source)) = self.query.take() {
             let (response_tx, response_rx) = oneshot::channel();
             self.response_rx = Some(response_rx);
             self.client
-                .send(Query::StoreRouterInfo(key, ri, from_reseed, response_tx))?;
+                .send(Query::StoreRouterInfo(key, ri, source, response_tx))?;
         }
 
         match self.response_rx.as_mut().unwrap().poll() {
@@ -276,6 +364,42 @@ impl Future for StoreRouterInfo {
     }
 }
 
+pub struct StoreRouterInfos {
+    client: Client,
+    query: Option<(Vec<RouterInfo>, RouterInfoSource)>,
+    response_rx: Option<oneshot::Receiver<Vec<Result<Option<RouterInfo>, StoreError>>>>,
+}
+
+impl StoreRouterInfos {
+    fn new(client: Client, ris: Vec<RouterInfo>, source: RouterInfoSource) -> Self {
+        StoreRouterInfos {
+            client,
+            query: Some((ris, source)),
+            response_rx: None,
+        }
+    }
+}
+
+impl Future for StoreRouterInfos {
+    type Item = Vec<Result<Option<RouterInfo>, StoreError>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some((ris, source)) = self.query.take() {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.response_rx = Some(response_rx);
+            self.client
+                .send(Query::StoreRouterInfos(ris, source, response_tx))?;
+        }
+
+        self.response_rx
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Error::Closed)
+    }
+}
+
 pub struct StoreLeaseSet {
     client: Client,
     query: Option<(Hash, LeaseSet)>,
@@ -331,6 +455,18 @@ impl Client {
         KnownRouters::new(self.clone())
     }
 
+    /// Returns a snapshot of the routers in this database, by their
+    /// capabilities and versions.
+    pub fn stats(&self) -> Stats {
+        Stats::new(self.clone())
+    }
+
+    /// Waits until this database contains at least `n` RouterInfos, and then
+    /// returns the number that it contains.
+    pub fn wait_for_peers(&self, n: usize) -> WaitForPeers {
+        WaitForPeers::new(self.clone(), n)
+    }
+
     /// Returns the closest floodfill router to the given netDb key.
     pub fn select_closest_ff(&self, key: Hash) -> SelectClosestFloodfill {
         SelectClosestFloodfill::new(self.clone(), key)
@@ -359,6 +495,20 @@ impl Client {
         LookupLeaseSet::new(self.clone(), key, timeout_ms, from_local_dest)
     }
 
+    /// Finds the LeaseSet for the Destination with the given base32 address.
+    ///
+    /// Fails with [`LookupError::InvalidKey`] if the address can't be parsed.
+    pub fn lookup_destination(
+        &self,
+        b32: &str,
+        timeout_ms: u64,
+    ) -> impl Future<Item = LeaseSet, Error = Error> {
+        let client = self.clone();
+        future::result(Hash::from_b32(b32))
+            .map_err(|_| Error::Lookup(LookupError::InvalidKey))
+            .and_then(move |key| client.lookup_lease_set(key, timeout_ms, None))
+    }
+
     /// Stores a RouterInfo locally.
     ///
     /// Returns the RouterInfo that was previously at this key.
@@ -366,9 +516,21 @@ impl Client {
         &self,
         key: Hash,
         ri: RouterInfo,
-        from_reseed: bool,
+        source: RouterInfoSource,
     ) -> StoreRouterInfo {
-        StoreRouterInfo::new(self.clone(), key, ri, from_reseed)
+        StoreRouterInfo::new(self.clone(), key, ri, source)
+    }
+
+    /// Stores a set of RouterInfos locally, verifying their signatures as a
+    /// batch.
+    ///
+    /// Returns the result of storing each RouterInfo, in order.
+    pub fn store_router_infos(
+        &self,
+        ris: Vec<RouterInfo>,
+        source: RouterInfoSource,
+    ) -> StoreRouterInfos {
+        StoreRouterInfos::new(self.clone(), ris, source)
     }
 
     /// Stores a LeaseSet locally.
@@ -377,4 +539,10 @@ impl Client {
     pub fn store_lease_set(&self, key: Hash, ls: LeaseSet) -> StoreLeaseSet {
         StoreLeaseSet::new(self.clone(), key, ls)
     }
+
+    /// Publishes our current RouterInfo now, rather than waiting for the next
+    /// scheduled publication.
+    pub fn publish_router_info(&self) -> Result<(), Error> {
+        self.send(Query::PublishRouterInfo)
+    }
 }
@@ -37,6 +37,7 @@ impl fmt::Display for Error {
 /// Network database lookup errors
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LookupError {
+    InvalidKey,
     NotFound,
     NoPath,
     SendFailure,
@@ -48,6 +49,7 @@ pub enum LookupError {
 impl fmt::Display for LookupError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            LookupError::InvalidKey => "Invalid key".fmt(f),
             LookupError::NotFound => "Key not found".fmt(f),
             LookupError::NoPath => "No path to send lookup".fmt(f),
             LookupError::SendFailure => "Send failure".fmt(f),
@@ -63,7 +65,9 @@ pub enum StoreError {
     Crypto(crypto::Error),
     Expired(Duration),
     InvalidKey,
+    Outdated,
     PublishedInFuture,
+    TooManyAddresses(usize),
     WrongNetwork,
 }
 
@@ -82,7 +86,9 @@ impl fmt::Display for StoreError {
                 format!("Too old (published {} seconds ago)", age.as_secs()).fmt(f)
             }
             StoreError::InvalidKey => "Key does not match RouterInfo's RouterIdentity".fmt(f),
+            StoreError::Outdated => "Older than the entry we have".fmt(f),
             StoreError::PublishedInFuture => "Published in future".fmt(f),
+            StoreError::TooManyAddresses(n) => format!("Too many addresses ({})", n).fmt(f),
             StoreError::WrongNetwork => "Not in our network".fmt(f),
         }
     }
@@ -0,0 +1,103 @@
+//! Adaptive expiry of RouterInfos.
+//!
+//! While we know few routers (for example, while bootstrapping), we keep every
+//! RouterInfo that is still valid. As the netDb grows, the maximum age of the
+//! RouterInfos we keep shrinks linearly, until it reaches a configurable minimum
+//! at a configurable netDb size.
+
+use std::cmp;
+use std::time::Duration;
+
+use super::KEEP_ROUTERS;
+use crate::data::ROUTER_INFO_EXPIRATION;
+use crate::router::config::{self, Config};
+
+/// Default minimum age at which we expire RouterInfos.
+const DEFAULT_MIN_AGE: u64 = 4 * 60 * 60;
+
+/// Default number of known routers at which we expire RouterInfos once they
+/// reach the minimum age.
+const DEFAULT_AGGRESSIVE_ROUTERS: usize = 4000;
+
+/// Maps the number of routers we know to the age at which we expire them.
+pub(super) struct ExpiryCurve {
+    min_age: Duration,
+    aggressive_routers: usize,
+}
+
+impl Default for ExpiryCurve {
+    fn default() -> Self {
+        ExpiryCurve::new(
+            Duration::from_secs(DEFAULT_MIN_AGE),
+            DEFAULT_AGGRESSIVE_ROUTERS,
+        )
+    }
+}
+
+impl ExpiryCurve {
+    fn new(min_age: Duration, aggressive_routers: usize) -> Self {
+        ExpiryCurve {
+            min_age: cmp::min(min_age, Duration::from_secs(ROUTER_INFO_EXPIRATION)),
+            aggressive_routers,
+        }
+    }
+
+    pub(super) fn from_config(config: &Config) -> Self {
+        // Checked by config::Validate
+        let default = ExpiryCurve::default();
+        ExpiryCurve::new(
+            config
+                .get_int(config::NETDB_EXPIRY_MIN_AGE)
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(default.min_age),
+            config
+                .get_int(config::NETDB_EXPIRY_AGGRESSIVE_ROUTERS)
+                .map(|routers| routers as usize)
+                .unwrap_or(default.aggressive_routers),
+        )
+    }
+
+    /// Returns the maximum age of the RouterInfos to keep when we know `known`
+    /// routers, or `None` if we know too few routers to expire any.
+    pub(super) fn max_age(&self, known: usize) -> Option<Duration> {
+        if known < KEEP_ROUTERS {
+            return None;
+        }
+        if known >= self.aggressive_routers {
+            return Some(self.min_age);
+        }
+
+        let max_age = Duration::from_secs(ROUTER_INFO_EXPIRATION);
+        let range = (max_age - self.min_age).as_secs();
+        let reduction =
+            range * (known - KEEP_ROUTERS) as u64 / (self.aggressive_routers - KEEP_ROUTERS) as u64;
+        Some(max_age - Duration::from_secs(reduction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ExpiryCurve, KEEP_ROUTERS, ROUTER_INFO_EXPIRATION};
+
+    #[test]
+    fn max_age() {
+        let hour = Duration::from_secs(60 * 60);
+        let curve = ExpiryCurve::new(hour, KEEP_ROUTERS + 1000);
+
+        assert_eq!(curve.max_age(0), None);
+        assert_eq!(curve.max_age(KEEP_ROUTERS - 1), None);
+        assert_eq!(
+            curve.max_age(KEEP_ROUTERS),
+            Some(Duration::from_secs(ROUTER_INFO_EXPIRATION))
+        );
+        assert_eq!(curve.max_age(KEEP_ROUTERS + 500), Some(14 * hour));
+        assert_eq!(curve.max_age(KEEP_ROUTERS + 1000), Some(hour));
+        assert_eq!(curve.max_age(KEEP_ROUTERS + 5000), Some(hour));
+
+        // The minimum age can't be longer than a RouterInfo is valid for
+        let curve = ExpiryCurve::new(100 * hour, KEEP_ROUTERS + 1000);
+        assert_eq!(curve.max_age(KEEP_ROUTERS + 1000), Some(27 * hour));
+    }
+}
@@ -0,0 +1,120 @@
+//! Flooding of netDb entries to floodfill routers.
+
+use futures::{future, Future};
+use std::sync::Arc;
+
+use super::send_message;
+use crate::data::RouterInfo;
+use crate::i2np::{DatabaseStore, Message, MessagePayload};
+use crate::router::Context;
+
+type FloodFuture<T> = Box<dyn Future<Item = T, Error = ()> + Send>;
+
+/// Stores a RouterInfo with each of the given floodfills in turn, until
+/// `target` stores have been sent successfully.
+///
+/// Floodfills should be ordered from closest to furthest; if a store fails,
+/// the next floodfill is tried instead. Resolves to the number of successful
+/// stores, which is less than `target` if every floodfill was tried.
+pub(super) fn flood_router_info(
+    ctx: Arc<Context>,
+    ri: RouterInfo,
+    floodfills: Vec<RouterInfo>,
+    target: usize,
+) -> FloodFuture<usize> {
+    Box::new(future::loop_fn(
+        (floodfills.into_iter(), 0),
+        move |(mut remaining, stored)| {
+            let ff = match remaining.next() {
+                Some(ff) if stored < target => ff,
+                _ => {
+                    let done: FloodFuture<_> = Box::new(future::ok(future::Loop::Break(stored)));
+                    return done;
+                }
+            };
+
+            let ff_hash = ff.router_id.hash();
+            let msg = Message::from_payload(MessagePayload::DatabaseStore(
+                DatabaseStore::from_ri(ri.clone(), None),
+            ));
+            let sent: FloodFuture<bool> = match send_message(&ctx, ff, msg) {
+                Ok(f) => Box::new(f.then(move |res| {
+                    if let Err(e) = &res {
+                        debug!("Failed to store RouterInfo with {}: {}", ff_hash, e);
+                    }
+                    Ok(res.is_ok())
+                })),
+                Err(_) => {
+                    debug!("Could not send RouterInfo store to {}", ff_hash);
+                    Box::new(future::ok(false))
+                }
+            };
+
+            Box::new(sent.map(move |ok| {
+                let stored = if ok { stored + 1 } else { stored };
+                future::Loop::Continue((remaining, stored))
+            }))
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use std::collections::HashSet;
+
+    use super::flood_router_info;
+    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::i2np::MessagePayload;
+    use crate::router::mock::MockContextBuilder;
+
+    fn router_info() -> RouterInfo {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign(&rsk.signing_private_key);
+        ri
+    }
+
+    #[test]
+    fn retry_failed_stores() {
+        let floodfills: Vec<_> = (0..5).map(|_| router_info()).collect();
+        let hashes: Vec<_> = floodfills.iter().map(|ri| ri.router_id.hash()).collect();
+
+        // The two closest floodfills can't be reached
+        let unreachable: HashSet<_> = hashes[..2].iter().cloned().collect();
+        let (ctx, sent) = MockContextBuilder::new()
+            .unreachable_peers(unreachable)
+            .build();
+        let ri = ctx.published_router_info();
+
+        let stored = flood_router_info(ctx, ri.clone(), floodfills, 2)
+            .wait()
+            .unwrap();
+        assert_eq!(stored, 2);
+
+        // The third and fourth floodfills were used instead
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        for ((peer, msg), hash) in sent.iter().zip(&hashes[2..4]) {
+            assert_eq!(peer, hash);
+            match &msg.payload {
+                MessagePayload::DatabaseStore(ds) => assert_eq!(ds.key, ri.router_id.hash()),
+                _ => panic!("Expected a DatabaseStore"),
+            }
+        }
+    }
+
+    #[test]
+    fn stop_when_out_of_floodfills() {
+        let floodfills: Vec<_> = (0..3).map(|_| router_info()).collect();
+        let unreachable: HashSet<_> = floodfills.iter().map(|ri| ri.router_id.hash()).collect();
+        let (ctx, sent) = MockContextBuilder::new()
+            .unreachable_peers(unreachable)
+            .build();
+        let ri = ctx.published_router_info();
+
+        let stored = flood_router_info(ctx, ri, floodfills, 2).wait().unwrap();
+        assert_eq!(stored, 0);
+        assert!(sent.lock().unwrap().is_empty());
+    }
+}
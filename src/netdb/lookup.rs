@@ -11,7 +11,7 @@ use tokio::timer::Timeout;
 use super::{
     create_routing_key,
     errors::{Error, LookupError},
-    PendingLookup, PendingTx, XorMetric,
+    send_message, PendingLookup, PendingTx, XorMetric,
 };
 use crate::data::{Hash, RouterInfo};
 use crate::i2np::{DatabaseLookup, DatabaseLookupType, DatabaseSearchReply, Message};
@@ -33,7 +33,7 @@ fn wait_for_search_reply(
     dlm: Message,
 ) -> LookupFuture<Option<DatabaseSearchReply>, Error> {
     let peer_hash = peer.router_id.hash();
-    match ctx.comms.read().unwrap().send(peer, dlm) {
+    match send_message(ctx, peer, dlm) {
         Ok(f) => {
             // Set up a channel so we get notified if a DatabaseSearchReply arrives
             let (tx_dsr, rx_dsr) = oneshot::channel();
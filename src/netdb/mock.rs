@@ -2,7 +2,7 @@ use futures::{sync::mpsc, try_ready, Future, Poll, Stream};
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use super::client::Query;
+use super::{client::Query, NetDbStats};
 use crate::{
     data::{Hash, RouterInfo},
     netdb::errors::LookupError,
@@ -35,6 +35,10 @@ impl Future for MockNetDb {
         loop {
             if let Some(query) = try_ready!(self.client_rx.poll()) {
                 match query {
+                    Query::KnownRouters(ret) => ret.send(self.ri_ds.len()).unwrap(),
+                    Query::Stats(ret) => ret
+                        .send(NetDbStats::from_router_infos(self.ri_ds.values()))
+                        .unwrap(),
                     Query::LookupRouterInfo(key, _, _, ret) => ret
                         .send(self.ri_ds.get(&key).cloned().ok_or(LookupError::NotFound))
                         .unwrap(),
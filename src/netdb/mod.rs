@@ -3,35 +3,45 @@
 use chrono::offset::Utc;
 use futures::{
     future,
+    stream::FuturesUnordered,
     sync::{mpsc, oneshot},
     Async, Future, Poll, Stream,
 };
 use rand::{thread_rng, Rng};
-use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 use tokio::{
     executor::{spawn, DefaultExecutor},
+    io,
     timer::Delay,
 };
 
-use crate::data::{Hash, LeaseSet, RouterInfo, NET_ID};
+use crate::crypto::verify::Verifier;
+use crate::data::{Hash, LeaseSet, RouterInfo, RouterInfoValidation, ROUTER_INFO_EXPIRATION};
 use crate::i2np::{
     DatabaseLookupType, DatabaseSearchReply, DatabaseStoreData, Message, MessagePayload,
 };
 use crate::router::{config, Context};
+use crate::util::{check_future_skew, DEFAULT_MAX_FUTURE_SKEW};
 
 pub mod client;
 mod errors;
+mod expiry;
+mod flood;
 mod lookup;
 pub mod mock;
+mod persist;
 pub mod reseed;
+mod stats;
 
-use errors::{LookupError, StoreError};
+pub use errors::{Error, LookupError, StoreError};
+use expiry::ExpiryCurve;
+pub use stats::NetDbStats;
 
-/// Maximum age of a local RouterInfo.
-const ROUTER_INFO_EXPIRATION: u64 = 27 * 60 * 60;
+/// Maximum lifetime of a Lease.
+const LEASE_LIFETIME: u64 = 10 * 60;
 
 /// Interval on which we expire RouterInfos.
 const EXPIRE_RI_INTERVAL: u64 = 5 * 60;
@@ -41,17 +51,48 @@ const EXPIRE_LS_INTERVAL: u64 = 60;
 const MINIMUM_ROUTERS: usize = 50;
 /// If we know fewer than this many routers, we won't expire RouterInfos.
 const KEEP_ROUTERS: usize = 150;
+/// Default maximum number of addresses in a RouterInfo that we will store.
+const DEFAULT_MAX_ROUTER_ADDRESSES: usize = 16;
 /// Don't explore the network more often than this.
 const EXPLORE_MIN_INTERVAL: u64 = 30;
 /// Explore the network at least this often.
 const EXPLORE_MAX_INTERVAL: u64 = 15 * 60;
 /// Explore quickly if we have fewer than this many routers.
 const EXPLORE_MIN_ROUTERS: usize = 250;
+/// The peer selector chooses an exploration starting point from this many of
+/// the floodfills closest to the exploration key.
+const EXPLORE_FLOODFILL_CANDIDATES: usize = 3;
+/// Delay before we first publish our RouterInfo.
+const PUBLISH_RI_DELAY: u64 = 60;
+/// Interval on which we republish our RouterInfo.
+const PUBLISH_RI_INTERVAL: u64 = 30 * 60;
+/// Number of floodfills we try to store our RouterInfo with.
+const FLOOD_TARGET: usize = 2;
+/// Maximum number of floodfills we try when storing our RouterInfo.
+const FLOOD_MAX_ATTEMPTS: usize = 6;
+
+type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
 type PendingLookups = HashMap<(Hash, Hash), oneshot::Sender<DatabaseSearchReply>>;
+type StoreRouterInfosTx = oneshot::Sender<Vec<Result<Option<RouterInfo>, StoreError>>>;
+type PendingValidation = Box<dyn Future<Item = Validated, Error = ()> + Send>;
 pub(crate) type PendingTx = mpsc::Sender<(Hash, Hash, oneshot::Sender<DatabaseSearchReply>)>;
 type PendingRx = mpsc::Receiver<(Hash, Hash, oneshot::Sender<DatabaseSearchReply>)>;
 
+/// RouterInfos that have been validated off the reactor, ready to be stored.
+enum Validated {
+    /// A RouterInfo received from a peer, with the results of validating it.
+    Network(Hash, Hash, RouterInfo, RouterInfoValidation),
+    /// A set of RouterInfos from a client, such as the contents of a reseed
+    /// bundle, with the results of validating them as a batch.
+    Batch(
+        Vec<RouterInfo>,
+        Vec<RouterInfoValidation>,
+        RouterInfoSource,
+        StoreRouterInfosTx,
+    ),
+}
+
 enum EngineState {
     CheckReseed,
     Timers,
@@ -64,14 +105,18 @@ pub struct Engine {
     netdb: LocalNetworkDatabase,
     ctx: Arc<Context>,
     active_reseed: Option<oneshot::SpawnHandle<(), ()>>,
+    active_persist: Option<oneshot::SpawnHandle<(), ()>>,
     pending_lookups: PendingLookups,
     register_pending: PendingTx,
     pending_rx: PendingRx,
     ib_rx: mpsc::Receiver<(Hash, Message)>,
     client_rx: mpsc::UnboundedReceiver<client::Query>,
+    verifier: Verifier,
+    pending_validations: FuturesUnordered<PendingValidation>,
     expire_ri_timer: Delay,
     expire_ls_timer: Delay,
     explore_timer: Delay,
+    publish_ri_timer: Delay,
 }
 
 impl Engine {
@@ -87,14 +132,66 @@ impl Engine {
             netdb: LocalNetworkDatabase::new(ctx.clone(), register_pending.clone()),
             ctx,
             active_reseed: None,
+            active_persist: None,
             pending_lookups: HashMap::new(),
             register_pending,
             pending_rx,
             ib_rx,
             client_rx,
+            verifier: Verifier::default(),
+            pending_validations: FuturesUnordered::new(),
             expire_ri_timer: Delay::new(Instant::now() + Duration::from_secs(EXPIRE_RI_INTERVAL)),
             expire_ls_timer: Delay::new(Instant::now() + Duration::from_secs(EXPIRE_LS_INTERVAL)),
             explore_timer: Delay::new(Instant::now() + Duration::from_secs(0)),
+            publish_ri_timer: Delay::new(Instant::now() + Duration::from_secs(PUBLISH_RI_DELAY)),
+        }
+    }
+
+    /// Loads RouterInfos that were previously saved to the given directory.
+    ///
+    /// Invalid or unreadable files are logged and skipped.
+    pub fn load_from_dir(&mut self, dir: &Path) {
+        if let Err(e) = self.netdb.load_from_dir(dir) {
+            warn!("Failed to load netDb from {}: {}", dir.display(), e);
+        }
+    }
+
+    /// Returns whether a reseed is in progress.
+    pub fn is_reseeding(&self) -> bool {
+        self.active_reseed.is_some()
+    }
+
+    fn netdb_dir(&self) -> Option<PathBuf> {
+        self.ctx
+            .config
+            .read()
+            .unwrap()
+            .get_str(config::NETDB_DIR)
+            .ok()
+            .map(PathBuf::from)
+    }
+
+    /// Stores RouterInfos that have finished validation.
+    fn store_validated(&mut self, validated: Validated) {
+        match validated {
+            Validated::Network(from, key, ri, validation) => {
+                if let Err(e) = self.netdb.store_validated_router_info(
+                    key,
+                    ri,
+                    validation,
+                    RouterInfoSource::Network,
+                ) {
+                    warn!("Rejected RouterInfo from {}: {}", from, e);
+                }
+            }
+            Validated::Batch(ris, validations, source, ret) => {
+                let results = self
+                    .netdb
+                    .store_validated_router_infos(ris, validations, source);
+                if ret.send(results).is_err() {
+                    warn!("Completed RouterInfo batch store, but client gave up");
+                }
+            }
         }
     }
 }
@@ -135,10 +232,32 @@ impl Future for Engine {
                     EngineState::Timers
                 }
                 EngineState::Timers => {
+                    // Update state of any ongoing save to disk
+                    if let Some(mut active) = self.active_persist.take() {
+                        if let Ok(Async::NotReady) = active.poll() {
+                            self.active_persist = Some(active);
+                        }
+                    }
+
                     if let Ok(Async::Ready(())) = self.expire_ri_timer.poll() {
                         // Expire RouterInfos
-                        if self.netdb.known_routers() >= KEEP_ROUTERS {
-                            self.netdb.expire_router_infos(Some(self.ctx.clone()));
+                        self.netdb.expire_router_infos(Some(self.ctx.clone()));
+                        // Save the RouterInfos we still know about, off the
+                        // executor, unless the last save is still running
+                        match self.netdb_dir() {
+                            Some(dir) if self.active_persist.is_none() => {
+                                let persist = self
+                                    .netdb
+                                    .persist_to_dir_blocking(dir.clone())
+                                    .map(|_| ())
+                                    .map_err(move |e| {
+                                        warn!("Failed to persist netDb to {}: {}", dir.display(), e)
+                                    });
+                                self.active_persist =
+                                    Some(oneshot::spawn(persist, &DefaultExecutor::current()));
+                            }
+                            Some(_) => debug!("Still persisting netDb, skipping"),
+                            None => (),
                         }
                         // Reset timer
                         self.expire_ri_timer =
@@ -158,7 +277,7 @@ impl Future for Engine {
                         let mut key = Hash([0u8; 32]);
                         thread_rng().fill(&mut key.0);
 
-                        if let Some(ff) = self.netdb.select_closest_ff(&key) {
+                        if let Some(ff) = self.netdb.select_exploratory_ff(&key) {
                             debug!("Exploring netDB for RouterInfo with key {}", key);
 
                             // Fire off an exploration job
@@ -190,6 +309,40 @@ impl Future for Engine {
                             Delay::new(Instant::now() + Duration::from_secs(interval));
                     }
 
+                    if let Ok(Async::Ready(())) = self.publish_ri_timer.poll() {
+                        // Store our RouterInfo with the closest floodfills
+                        let ri = self.ctx.published_router_info();
+                        let floodfills = self
+                            .netdb
+                            .closest_floodfills(&ri.router_id.hash(), FLOOD_MAX_ATTEMPTS);
+                        if floodfills.is_empty() {
+                            warn!("No floodfills known, not publishing our RouterInfo");
+                        } else {
+                            spawn(
+                                flood::flood_router_info(
+                                    self.ctx.clone(),
+                                    ri,
+                                    floodfills,
+                                    FLOOD_TARGET,
+                                )
+                                .map(|stored| {
+                                    if stored < FLOOD_TARGET {
+                                        warn!(
+                                            "Only stored our RouterInfo with {} floodfills",
+                                            stored
+                                        );
+                                    } else {
+                                        debug!("Published our RouterInfo");
+                                    }
+                                }),
+                            );
+                        }
+
+                        // Reset timer
+                        self.publish_ri_timer =
+                            Delay::new(Instant::now() + Duration::from_secs(PUBLISH_RI_INTERVAL));
+                    }
+
                     EngineState::Messages
                 }
                 EngineState::Messages => {
@@ -203,6 +356,19 @@ impl Future for Engine {
                         }
                     }
 
+                    // Clean up after lookups that were dropped before they completed
+                    forget_dropped_search_replies(&mut self.pending_lookups);
+                    self.netdb.forget_dropped_lookups();
+
+                    // Store RouterInfos that have finished validation
+                    loop {
+                        match self.pending_validations.poll() {
+                            Ok(Async::Ready(Some(validated))) => self.store_validated(validated),
+                            Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                            Err(()) => warn!("RouterInfo validation was cancelled"),
+                        }
+                    }
+
                     // Fetch the next network and client messages
                     let (next_ib, next_client) = match (self.ib_rx.poll(), self.client_rx.poll()) {
                         (Err(_), _) | (_, Err(_)) => {
@@ -227,14 +393,25 @@ impl Future for Engine {
                         match msg.payload {
                             MessagePayload::DatabaseStore(ds) => match ds.data {
                                 DatabaseStoreData::RI(ri) => {
-                                    self.netdb
-                                        .store_router_info(ds.key, ri, false)
-                                        .expect("Failed to store RouterInfo");
+                                    // Verifying the signature is expensive, so we don't do
+                                    // it on the reactor.
+                                    let peer = from.clone();
+                                    let key = ds.key;
+                                    match self.verifier.run(move || {
+                                        let validation = ri.validate();
+                                        Validated::Network(peer, key, ri, validation)
+                                    }) {
+                                        Some(f) => self.pending_validations.push(Box::new(f)),
+                                        None => warn!(
+                                            "Dropping RouterInfo from {}: verifier is busy",
+                                            from
+                                        ),
+                                    }
                                 }
                                 DatabaseStoreData::LS(ls) => {
-                                    self.netdb
-                                        .store_lease_set(ds.key, ls)
-                                        .expect("Failed to store LeaseSet");
+                                    if let Err(e) = self.netdb.store_lease_set(ds.key, ls) {
+                                        warn!("Rejected LeaseSet from {}: {}", from, e);
+                                    }
                                 }
                             },
                             MessagePayload::DatabaseSearchReply(dsr) => {
@@ -261,8 +438,28 @@ impl Future for Engine {
                     }
 
                     // Handle the client message
-                    if let Some(query) = next_client {
-                        query.handle(&mut self.netdb);
+                    match next_client {
+                        Some(client::Query::PublishRouterInfo) => {
+                            // Publish when the timers are next checked
+                            self.publish_ri_timer.reset(Instant::now());
+                        }
+                        Some(client::Query::StoreRouterInfos(ris, source, ret)) => {
+                            // A reseed bundle can hold hundreds of RouterInfos, so
+                            // we verify them off the reactor like single ones.
+                            let count = ris.len();
+                            match self.verifier.run(move || {
+                                let validations = RouterInfo::validate_batch(&ris);
+                                Validated::Batch(ris, validations, source, ret)
+                            }) {
+                                Some(f) => self.pending_validations.push(Box::new(f)),
+                                None => warn!(
+                                    "Dropping {} RouterInfos from {:?}: verifier is busy",
+                                    count, source
+                                ),
+                            }
+                        }
+                        Some(query) => query.handle(&mut self.netdb),
+                        None => (),
                     }
 
                     EngineState::CheckReseed
@@ -273,51 +470,169 @@ impl Future for Engine {
     }
 }
 
-fn router_info_is_current(ri: &RouterInfo) -> Result<(), StoreError> {
-    let published = ri.published.to_system_time();
-    let now = SystemTime::now();
-
-    if published < now - Duration::from_secs(ROUTER_INFO_EXPIRATION) {
-        return Err(StoreError::Expired(now.duration_since(published).unwrap()));
+fn router_info_is_current(ri: &RouterInfo, max_future_skew: Duration) -> Result<(), StoreError> {
+    if let Some(age) = ri.expired() {
+        return Err(StoreError::Expired(age));
     }
 
-    // Allow RouterInfos published up to 2 minutes in the future, to handle clock drift.
-    if published > now + Duration::from_secs(2 * 60) {
-        return Err(StoreError::PublishedInFuture);
+    // Allow RouterInfos published slightly in the future, to handle clock drift.
+    check_future_skew(ri.published.to_system_time(), max_future_skew)
+        .map_err(|_| StoreError::PublishedInFuture)
+}
+
+/// Sends a netDb message to a peer.
+///
+/// If the router is configured to send netDb traffic via tunnels, the message
+/// is sent through one of our outbound tunnels, whose outbound endpoint sends
+/// it on to the peer. If no outbound tunnel is available yet, the message is
+/// sent directly instead.
+///
+/// The message is only returned if nothing was sent. Once the first tunnel
+/// message has been handed to the transports, any later failure is reported
+/// through the returned future, so that the caller does not resend a message
+/// the peer may already have received.
+fn send_message(
+    ctx: &Context,
+    peer: RouterInfo,
+    msg: Message,
+) -> Result<IoFuture<()>, (RouterInfo, Message)> {
+    let via_tunnels = ctx
+        .config
+        .read()
+        .unwrap()
+        .get_bool(config::NETDB_VIA_TUNNELS)
+        .unwrap_or(false);
+    if !via_tunnels {
+        return ctx.comms.read().unwrap().send(peer, msg);
     }
 
-    Ok(())
+    let wrapped = ctx
+        .ob_tunnels
+        .as_ref()
+        .and_then(|pool| pool.wrap_message(&peer.router_id.hash(), &msg));
+    match wrapped {
+        Some((first_hop, tunnel_msgs)) => {
+            debug!(
+                "Sending msg {} for {} via outbound tunnel at {}",
+                msg.id,
+                peer.router_id.hash(),
+                first_hop.router_id.hash()
+            );
+            let comms = ctx.comms.read().unwrap();
+            let mut sends = Vec::with_capacity(tunnel_msgs.len());
+            for tunnel_msg in tunnel_msgs {
+                match comms.send(first_hop.clone(), tunnel_msg) {
+                    Ok(f) => sends.push(f),
+                    Err(_) if sends.is_empty() => return Err((peer, msg)),
+                    Err(_) => {
+                        let failed: IoFuture<()> = Box::new(future::err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "Could not send all tunnel messages",
+                        )));
+                        sends.push(failed);
+                        break;
+                    }
+                }
+            }
+            Ok(Box::new(future::join_all(sends).map(|_| ())))
+        }
+        None => {
+            debug!(
+                "No outbound tunnels available, sending msg {} directly to {}",
+                msg.id,
+                peer.router_id.hash()
+            );
+            ctx.comms.read().unwrap().send(peer, msg)
+        }
+    }
 }
 
 fn create_routing_key(key: &Hash) -> Hash {
-    let mut data = [0u8; 40];
-    data[0..32].copy_from_slice(&key.0);
-    data[32..40].copy_from_slice(Utc::now().format("%Y%m%d").to_string().as_bytes());
-    let mut out = [0u8; 32];
-    out.copy_from_slice(Sha256::digest(&data).as_slice());
-    Hash(out)
+    key.routing_key(Utc::now())
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-struct XorMetric([u8; 32]);
+type PendingLookup<T> = HashMap<Hash, Vec<oneshot::Sender<T>>>;
+
+/// Forgets the waiters for lookups that have been dropped.
+///
+/// The first waiter for each key is the lookup itself. If it has been dropped
+/// (or has timed out), nothing will complete the other waiters, so they are
+/// dropped as well.
+fn forget_dropped_lookups<T>(pending: &mut PendingLookup<T>) {
+    pending.retain(|_, waiters| {
+        let live = waiters
+            .first()
+            .map(|lookup| !lookup.is_canceled())
+            .unwrap_or(false);
+        if live {
+            waiters.retain(|w| !w.is_canceled());
+        }
+        live
+    });
+}
 
-impl XorMetric {
-    fn for_hash(hash: &Hash, key: &Hash) -> Self {
-        let mut metric = hash.clone();
-        metric.xor(key);
-        XorMetric(metric.0)
+/// Forgets the DatabaseSearchReply correlators for lookups that have been
+/// dropped.
+fn forget_dropped_search_replies(pending: &mut PendingLookups) {
+    pending.retain(|_, tx| !tx.is_canceled());
+}
+
+/// Where we obtained a RouterInfo from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouterInfoSource {
+    Reseed,
+    Disk,
+    Network,
+}
+
+impl RouterInfoSource {
+    /// RouterInfos from a reseed or from disk may be old, and are treated
+    /// leniently when they have expired.
+    fn may_be_old(self) -> bool {
+        match self {
+            RouterInfoSource::Reseed | RouterInfoSource::Disk => true,
+            RouterInfoSource::Network => false,
+        }
     }
 }
 
-type PendingLookup<T> = HashMap<Hash, Vec<oneshot::Sender<T>>>;
+/// Information about a router that we derive ourselves, and store alongside
+/// its RouterInfo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouterInfoMetadata {
+    /// When we first stored a RouterInfo for the router.
+    pub first_seen: SystemTime,
+    /// When we last successfully connected to the router.
+    pub last_connected: Option<SystemTime>,
+    /// Where we obtained the router's current RouterInfo from.
+    pub source: RouterInfoSource,
+    /// Whether the RouterInfo had already expired when we loaded it from disk.
+    /// Quarantined routers are kept until they are expired as usual, in case
+    /// a current RouterInfo for them arrives, but until then they are neither
+    /// selected as peers nor counted as known routers.
+    pub quarantined: bool,
+}
+
+impl RouterInfoMetadata {
+    fn new(source: RouterInfoSource) -> Self {
+        RouterInfoMetadata {
+            first_seen: SystemTime::now(),
+            last_connected: None,
+            source,
+            quarantined: false,
+        }
+    }
+}
 
 /// A NetworkDatabase that never publishes data to the network.
 pub struct LocalNetworkDatabase {
     ctx: Arc<Context>,
     ri_ds: HashMap<Hash, RouterInfo>,
+    ri_metadata: HashMap<Hash, RouterInfoMetadata>,
     ls_ds: HashMap<Hash, LeaseSet>,
     pending_ri: PendingLookup<RouterInfo>,
     pending_ls: PendingLookup<LeaseSet>,
+    peer_waiters: Vec<(usize, oneshot::Sender<usize>)>,
     register_pending: PendingTx,
 }
 
@@ -326,32 +641,176 @@ impl LocalNetworkDatabase {
         LocalNetworkDatabase {
             ctx,
             ri_ds: HashMap::new(),
+            ri_metadata: HashMap::new(),
             ls_ds: HashMap::new(),
             pending_ri: HashMap::new(),
             pending_ls: HashMap::new(),
+            peer_waiters: Vec::new(),
             register_pending: pending_tx,
         }
     }
 
+    /// Returns the number of routers that may be selected as peers.
     fn known_routers(&self) -> usize {
-        self.ri_ds.len()
+        self.selectable_routers().count()
+    }
+
+    /// Returns a snapshot of the routers we know, by their capabilities and
+    /// versions.
+    fn stats(&self) -> NetDbStats {
+        NetDbStats::from_router_infos(self.ri_ds.values())
+    }
+
+    /// Returns the metadata for the router with the given hash, if we have its
+    /// RouterInfo.
+    pub fn router_info_metadata(&self, key: &Hash) -> Option<&RouterInfoMetadata> {
+        self.ri_metadata.get(key)
+    }
+
+    /// Returns the metadata for the router with the given hash for updating, if
+    /// we have its RouterInfo.
+    pub fn router_info_metadata_mut(&mut self, key: &Hash) -> Option<&mut RouterInfoMetadata> {
+        self.ri_metadata.get_mut(key)
+    }
+
+    fn max_future_skew(&self) -> Duration {
+        // Checked by config::Validate
+        self.ctx
+            .config
+            .read()
+            .unwrap()
+            .get_int(config::MAX_FUTURE_SKEW)
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(DEFAULT_MAX_FUTURE_SKEW)
+    }
+
+    fn max_router_addresses(&self) -> usize {
+        // Checked by config::Validate
+        self.ctx
+            .config
+            .read()
+            .unwrap()
+            .get_int(config::NETDB_MAX_ROUTER_ADDRESSES)
+            .map(|n| n as usize)
+            .unwrap_or(DEFAULT_MAX_ROUTER_ADDRESSES)
+    }
+
+    /// Returns whether a RouterInfo that was published `age` ago, and has
+    /// expired, should be stored anyway.
+    ///
+    /// While we know too few routers to expire any, we accept RouterInfos that
+    /// expired within the configured grace period, so that a router
+    /// bootstrapping from an old reseed isn't left with nothing.
+    fn in_bootstrap_grace(&self, age: Duration) -> bool {
+        if self.known_routers() >= KEEP_ROUTERS {
+            return false;
+        }
+        // Checked by config::Validate
+        let grace = self
+            .ctx
+            .config
+            .read()
+            .unwrap()
+            .get_int(config::NETDB_BOOTSTRAP_GRACE)
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or_default();
+        age <= Duration::from_secs(ROUTER_INFO_EXPIRATION) + grace
+    }
+
+    /// Sends the number of known routers to `ret` once it is at least `n`.
+    fn wait_for_peers(&mut self, n: usize, ret: oneshot::Sender<usize>) {
+        self.peer_waiters.push((n, ret));
+        self.notify_peer_waiters();
+    }
+
+    fn notify_peer_waiters(&mut self) {
+        // Counting the routers is a full scan, which bulk imports would
+        // otherwise run for every RouterInfo they store.
+        if self.peer_waiters.is_empty() {
+            return;
+        }
+        let known = self.known_routers();
+        let (ready, waiting): (Vec<_>, Vec<_>) = self
+            .peer_waiters
+            .drain(..)
+            .partition(|(n, _)| known >= *n);
+        self.peer_waiters = waiting;
+        for (_, ret) in ready {
+            if ret.send(known).is_err() {
+                warn!("Reached {} known routers, but client gave up", known);
+            }
+        }
+    }
+
+    /// Returns whether the router with the given hash has been quarantined,
+    /// and should not be selected as a peer.
+    fn is_quarantined(&self, key: &Hash) -> bool {
+        self.ri_metadata
+            .get(key)
+            .map(|metadata| metadata.quarantined)
+            .unwrap_or(false)
+    }
+
+    /// Returns the RouterInfos of the routers that may be selected as peers.
+    fn selectable_routers(&self) -> impl Iterator<Item = &RouterInfo> {
+        self.ri_ds
+            .iter()
+            .filter(move |(key, _)| !self.is_quarantined(key))
+            .map(|(_, ri)| ri)
     }
 
     fn select_closest_ff(&self, key: &Hash) -> Option<RouterInfo> {
         let key = create_routing_key(key);
-        self.ri_ds
-            .values()
+        self.selectable_routers()
             .filter(|ri| ri.is_floodfill())
-            .min_by_key(|ri| XorMetric::for_hash(&ri.router_id.hash(), &key))
+            .min_by_key(|ri| ri.router_id.hash().distance(&key))
             .cloned()
     }
 
+    /// Returns up to `n` routers that satisfy `filter`, ordered by their
+    /// closeness to the given netDb key.
+    fn closest_routers<F>(&self, key: &Hash, n: usize, filter: F) -> Vec<RouterInfo>
+    where
+        F: Fn(&RouterInfo) -> bool,
+    {
+        let key = create_routing_key(key);
+        let mut routers: Vec<_> = self.selectable_routers().filter(|ri| filter(ri)).collect();
+        routers.sort_by_key(|ri| ri.router_id.hash().distance(&key));
+        routers.into_iter().take(n).cloned().collect()
+    }
+
+    /// Returns up to `n` known routers, ordered by their closeness to the given
+    /// netDb key.
+    pub fn closest_to(&self, key: &Hash, n: usize) -> Vec<RouterInfo> {
+        self.closest_routers(key, n, |_| true)
+    }
+
+    /// Returns up to `n` floodfill routers, ordered by their closeness to the
+    /// given netDb key.
+    fn closest_floodfills(&self, key: &Hash, n: usize) -> Vec<RouterInfo> {
+        self.closest_routers(key, n, RouterInfo::is_floodfill)
+    }
+
+    /// Selects a floodfill router to start exploring from, using the router's
+    /// peer selection policy.
+    fn select_exploratory_ff(&self, key: &Hash) -> Option<RouterInfo> {
+        let candidates = self.closest_floodfills(key, EXPLORE_FLOODFILL_CANDIDATES);
+        self.ctx.peer_selector.select_peers(&candidates, 1).pop()
+    }
+
+    fn forget_dropped_lookups(&mut self) {
+        forget_dropped_lookups(&mut self.pending_ri);
+        forget_dropped_lookups(&mut self.pending_ls);
+    }
+
     fn lookup_router_info(
         &mut self,
         key: &Hash,
         timeout_ms: u64,
         from_peer: Option<RouterInfo>,
     ) -> Box<dyn Future<Item = RouterInfo, Error = LookupError> + Send> {
+        self.forget_dropped_lookups();
+
         // First look for it locally, either available or pending
         let local: Option<Box<dyn Future<Item = RouterInfo, Error = LookupError> + Send>> =
             match self.ri_ds.get(key) {
@@ -393,6 +852,8 @@ impl LocalNetworkDatabase {
         timeout_ms: u64,
         _from_local_dest: Option<Hash>,
     ) -> Box<dyn Future<Item = LeaseSet, Error = LookupError> + Send> {
+        self.forget_dropped_lookups();
+
         // First look for it locally, either available or pending
         let local: Option<Box<dyn Future<Item = LeaseSet, Error = LookupError> + Send>> =
             match self.ls_ds.get(key) {
@@ -433,24 +894,79 @@ impl LocalNetworkDatabase {
         &mut self,
         key: Hash,
         ri: RouterInfo,
-        from_reseed: bool,
+        source: RouterInfoSource,
+    ) -> Result<Option<RouterInfo>, StoreError> {
+        let validation = ri.validate();
+        self.store_validated_router_info(key, ri, validation, source)
+    }
+
+    /// Stores a set of RouterInfos, such as those saved on disk, verifying
+    /// their signatures as a batch.
+    fn store_router_infos(
+        &mut self,
+        ris: Vec<RouterInfo>,
+        source: RouterInfoSource,
+    ) -> Vec<Result<Option<RouterInfo>, StoreError>> {
+        let validations = RouterInfo::validate_batch(&ris);
+        self.store_validated_router_infos(ris, validations, source)
+    }
+
+    /// Stores a set of RouterInfos that have already been validated.
+    fn store_validated_router_infos(
+        &mut self,
+        ris: Vec<RouterInfo>,
+        validations: Vec<RouterInfoValidation>,
+        source: RouterInfoSource,
+    ) -> Vec<Result<Option<RouterInfo>, StoreError>> {
+        ris.into_iter()
+            .zip(validations)
+            .map(|(ri, validation)| {
+                let key = ri.router_id.hash();
+                self.store_validated_router_info(key, ri, validation, source)
+            })
+            .collect()
+    }
+
+    /// Stores a RouterInfo that has already been validated.
+    fn store_validated_router_info(
+        &mut self,
+        key: Hash,
+        ri: RouterInfo,
+        validation: RouterInfoValidation,
+        source: RouterInfoSource,
     ) -> Result<Option<RouterInfo>, StoreError> {
-        // Validate the RouterInfo
         if key != ri.router_id.hash() {
             return Err(StoreError::InvalidKey);
         }
-        ri.verify()?;
-        if ri
-            .network_id()
-            .map(|net_id| *net_id != *NET_ID)
-            .unwrap_or(true)
-        {
+        // Don't store bloated RouterInfos that could be used for amplification
+        if ri.addresses().len() > self.max_router_addresses() {
+            return Err(StoreError::TooManyAddresses(ri.addresses().len()));
+        }
+        validation.signature?;
+        if !validation.net_id {
             return Err(StoreError::WrongNetwork);
         }
+        if !validation.caps_parseable {
+            debug!("RouterInfo at key {} has unrecognised caps", key);
+        }
+
+        let mut quarantine = false;
+        match router_info_is_current(&ri, self.max_future_skew()) {
+            Err(StoreError::Expired(age)) if self.in_bootstrap_grace(age) => {
+                debug!("Accepting expired RouterInfo {} while bootstrapping", key)
+            }
+            // RouterInfos from a reseed or from disk may be old. We keep the
+            // expired ones in case a current RouterInfo arrives, but don't use
+            // them until then.
+            Err(StoreError::Expired(_)) if source.may_be_old() => quarantine = true,
+            res => res?,
+        }
 
-        // Don't require RouterInfos from reseeds to satisfy liveness
-        if !from_reseed {
-            router_info_is_current(&ri)?;
+        // Don't let anyone roll back our view of the router
+        if let Some(ours) = self.ri_ds.get(&key) {
+            if ri.published < ours.published {
+                return Err(StoreError::Outdated);
+            }
         }
 
         // If anyone was waiting on this RouterInfo, notify them
@@ -463,10 +979,31 @@ impl LocalNetworkDatabase {
         }
 
         debug!("Storing RouterInfo at key {}", key);
-        Ok(self.ri_ds.insert(key, ri))
+        let metadata = self
+            .ri_metadata
+            .entry(key.clone())
+            .or_insert_with(|| RouterInfoMetadata::new(source));
+        metadata.source = source;
+        if quarantine {
+            debug!("Quarantining expired RouterInfo {}", key);
+            metadata.quarantined = true;
+        } else if metadata.quarantined {
+            debug!("Releasing router {} from quarantine", key);
+            metadata.quarantined = false;
+        }
+        let old = self.ri_ds.insert(key, ri);
+        self.notify_peer_waiters();
+        Ok(old)
     }
 
     fn store_lease_set(&mut self, key: Hash, ls: LeaseSet) -> Result<Option<LeaseSet>, StoreError> {
+        // Leases can't end later than a tunnel lifetime from now, plus clock drift
+        check_future_skew(
+            ls.expiration().to_system_time(),
+            self.max_future_skew() + Duration::from_secs(LEASE_LIFETIME),
+        )
+        .map_err(|_| StoreError::PublishedInFuture)?;
+
         // If anyone was waiting on this LeaseSet, notify them
         if let Some(pending) = self.pending_ls.remove(&key) {
             for p in pending {
@@ -480,8 +1017,59 @@ impl LocalNetworkDatabase {
         Ok(self.ls_ds.insert(key, ls))
     }
 
+    /// Imports the entries from another network database that are newer than
+    /// the ones we have. Entries that fail validation are skipped.
+    ///
+    /// RouterInfos keep the source that the other database recorded for them.
+    /// If it has none, they are treated like RouterInfos loaded from disk.
+    ///
+    /// Returns the number of entries imported.
+    pub fn merge_from(&mut self, other: &LocalNetworkDatabase) -> usize {
+        let mut merged = 0;
+
+        for (key, ri) in &other.ri_ds {
+            if let Some(ours) = self.ri_ds.get(key) {
+                if ours.published >= ri.published {
+                    continue;
+                }
+            }
+            let source = other
+                .ri_metadata
+                .get(key)
+                .map_or(RouterInfoSource::Disk, |metadata| metadata.source);
+            match self.store_router_info(key.clone(), ri.clone(), source) {
+                Ok(_) => merged += 1,
+                Err(e) => debug!("Not merging RouterInfo at key {}: {}", key, e),
+            }
+        }
+
+        for (key, ls) in &other.ls_ds {
+            if let Some(ours) = self.ls_ds.get(key) {
+                if ours.expiration() >= ls.expiration() {
+                    continue;
+                }
+            }
+            match self.store_lease_set(key.clone(), ls.clone()) {
+                Ok(_) => merged += 1,
+                Err(e) => debug!("Not merging LeaseSet at key {}: {}", key, e),
+            }
+        }
+
+        merged
+    }
+
+    /// Expires RouterInfos that are older than the expiry curve allows for the
+    /// number of routers we currently know.
     fn expire_router_infos(&mut self, ctx: Option<Arc<Context>>) {
+        let max_age = {
+            let config = self.ctx.config.read().unwrap();
+            match ExpiryCurve::from_config(&config).max_age(self.known_routers()) {
+                Some(max_age) => max_age,
+                None => return,
+            }
+        };
         let comms = ctx.as_ref().map(|ctx| ctx.comms.read().unwrap());
+        let max_future_skew = self.max_future_skew();
 
         let before = self.ri_ds.len();
         self.ri_ds.retain(|_, ri| {
@@ -492,8 +1080,10 @@ impl LocalNetworkDatabase {
                 }
             }
 
-            router_info_is_current(ri).is_ok()
+            router_info_is_current(ri, max_future_skew).is_ok() && ri.is_current(max_age)
         });
+        let ri_ds = &self.ri_ds;
+        self.ri_metadata.retain(|key, _| ri_ds.contains_key(key));
         let expired = before - self.ri_ds.len();
         if expired > 0 {
             debug!("Expired {} RouterInfos", expired);
@@ -512,47 +1102,145 @@ impl LocalNetworkDatabase {
 
 #[cfg(test)]
 mod tests {
-    use futures::{sync::mpsc, Async};
-    use std::time::{Duration, SystemTime};
+    use futures::{lazy, sync::mpsc, Async, Future, Stream};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
 
     use super::{
-        errors::StoreError, router_info_is_current, LocalNetworkDatabase, XorMetric,
-        ROUTER_INFO_EXPIRATION,
+        client::Client,
+        create_routing_key,
+        errors::{Error, LookupError, StoreError},
+        forget_dropped_search_replies, router_info_is_current, send_message, LocalNetworkDatabase,
+        NetDbStats, RouterInfoMetadata, RouterInfoSource, KEEP_ROUTERS,
     };
-    use crate::crypto;
-    use crate::data::{Hash, I2PDate, RouterInfo, RouterSecretKeys, OPT_NET_ID};
-    use crate::router::mock::mock_context;
+    use crate::crypto::{self, elgamal::KeyPairGenerator, SigningPublicKey};
+    use crate::data::{
+        dest::DestinationSecretKeys, Hash, I2PDate, Lease, LeaseSet, RouterAddress, RouterInfo,
+        RouterSecretKeys, TunnelId, OPT_NET_ID, ROUTER_INFO_EXPIRATION,
+    };
+    use crate::i2np::{DatabaseStore, Message, MessagePayload};
+    use crate::router::{
+        config::{self, Config},
+        mock::{mock_context, MockContextBuilder, MockPeerSelector},
+    };
+    use crate::transport::ntcp2::NTCP2_STYLE;
+    use crate::tunnel::TunnelPool;
+    use crate::util::DEFAULT_MAX_FUTURE_SKEW;
+
+    fn new_router_info() -> RouterInfo {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign(&rsk.signing_private_key);
+        ri
+    }
 
     #[test]
-    fn xor_metric() {
-        let key_min = Hash([0; 32]);
-        let key_max = Hash([0xff; 32]);
+    fn closest_to() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        assert!(netdb.closest_to(&Hash([0; 32]), 3).is_empty());
 
-        assert_eq!(
-            XorMetric::for_hash(&key_min, &key_min),
-            XorMetric::for_hash(&key_max, &key_max)
-        );
+        let ris: Vec<_> = (0..5).map(|_| new_router_info()).collect();
+        for ri in &ris {
+            netdb
+                .store_router_info(ri.router_id.hash(), ri.clone(), RouterInfoSource::Network)
+                .unwrap();
+        }
 
-        let hash1 = Hash([
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 1,
-        ]);
-        let hash2 = Hash([
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 2,
-        ]);
-        let hash1le = Hash([
-            1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0,
-        ]);
+        // Each router is closest to its own key
+        for ri in &ris {
+            let closest = netdb.closest_to(&ri.router_id.hash(), 3);
+            assert_eq!(closest.len(), 3);
+            assert_eq!(closest[0], *ri);
+        }
 
-        assert!(XorMetric::for_hash(&key_min, &key_min) < XorMetric::for_hash(&hash1, &key_min));
-        assert!(XorMetric::for_hash(&hash1, &key_min) < XorMetric::for_hash(&hash2, &key_min));
-        assert!(XorMetric::for_hash(&hash1, &key_min) < XorMetric::for_hash(&hash1le, &key_min));
+        // Routers are ordered by distance to the routing key
+        let key = Hash([0x42; 32]);
+        let rk = create_routing_key(&key);
+        let closest = netdb.closest_to(&key, 10);
+        assert_eq!(closest.len(), 5);
+        for pair in closest.windows(2) {
+            assert!(
+                pair[0].router_id.hash().distance(&rk) < pair[1].router_id.hash().distance(&rk)
+            );
+        }
+    }
 
-        assert!(XorMetric::for_hash(&key_max, &key_max) < XorMetric::for_hash(&hash1, &key_max));
-        assert!(XorMetric::for_hash(&hash1, &key_max) > XorMetric::for_hash(&hash2, &key_max));
-        assert!(XorMetric::for_hash(&hash1, &key_max) > XorMetric::for_hash(&hash1le, &key_max));
+    #[test]
+    fn exploration_uses_peer_selector() {
+        let selected = new_router_info();
+        let (ctx, _) = MockContextBuilder::new()
+            .peer_selector(MockPeerSelector(vec![selected.clone()]))
+            .build();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx, tx);
+        for _ in 0..5 {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.set_option("caps".into(), "fR".into());
+            ri.sign(&rsk.signing_private_key);
+            netdb
+                .store_router_info(ri.router_id.hash(), ri, RouterInfoSource::Network)
+                .unwrap();
+        }
+
+        // The selector picks the floodfill, even one we don't know
+        assert_eq!(netdb.select_exploratory_ff(&Hash([0; 32])), Some(selected));
+    }
+
+    #[test]
+    fn merge_from() {
+        let published = |ago: u64| {
+            I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(ago))
+        };
+        let signed = |rsk: &RouterSecretKeys, ago: u64| {
+            let mut ri = RouterInfo::new(rsk.rid.clone());
+            ri.published = published(ago);
+            ri.sign(&rsk.signing_private_key);
+            ri
+        };
+        let keys: Vec<_> = (0..4).map(|_| RouterSecretKeys::new()).collect();
+
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let (tx, _) = mpsc::channel(0);
+        let mut other = LocalNetworkDatabase::new(mock_context(), tx);
+
+        // The other netdb has a newer RouterInfo for the first router, an older
+        // one for the second, and the only one for the third
+        let store = |netdb: &mut LocalNetworkDatabase, ri: RouterInfo| {
+            netdb
+                .store_router_info(ri.router_id.hash(), ri, RouterInfoSource::Network)
+                .unwrap();
+        };
+        store(&mut netdb, signed(&keys[0], 600));
+        store(&mut netdb, signed(&keys[1], 0));
+        store(&mut other, signed(&keys[0], 0));
+        store(&mut other, signed(&keys[1], 600));
+        store(&mut other, signed(&keys[2], 0));
+
+        // The fourth router's RouterInfo was tampered with
+        let mut tampered = signed(&keys[3], 0);
+        tampered.published = published(60);
+        other.ri_ds.insert(tampered.router_id.hash(), tampered);
+
+        assert_eq!(netdb.merge_from(&other), 2);
+        assert_eq!(netdb.known_routers(), 3);
+
+        let newest = |netdb: &LocalNetworkDatabase, rsk: &RouterSecretKeys| {
+            netdb.ri_ds[&rsk.rid.hash()].published
+        };
+        assert_eq!(newest(&netdb, &keys[0]), newest(&other, &keys[0]));
+        assert!(newest(&netdb, &keys[1]) > newest(&other, &keys[1]));
+        assert_eq!(newest(&netdb, &keys[2]), newest(&other, &keys[2]));
+        assert!(!netdb.ri_ds.contains_key(&keys[3].rid.hash()));
+
+        // Merging again changes nothing
+        assert_eq!(netdb.merge_from(&other), 0);
     }
 
     #[test]
@@ -577,14 +1265,14 @@ mod tests {
         // Storing a RouterInfo modified after signing should fail
         let old_netid = ri.options.0.insert(OPT_NET_ID.clone(), "0".into()).unwrap();
         assert_eq!(
-            netdb.store_router_info(key.clone(), ri.clone(), false),
+            netdb.store_router_info(key.clone(), ri.clone(), RouterInfoSource::Network),
             Err(StoreError::Crypto(crypto::Error::InvalidSignature))
         );
         ri.sign(&rsk.signing_private_key);
 
         // Storing with a different netId should fail
         assert_eq!(
-            netdb.store_router_info(key.clone(), ri.clone(), false),
+            netdb.store_router_info(key.clone(), ri.clone(), RouterInfoSource::Network),
             Err(StoreError::WrongNetwork)
         );
         ri.options.0.insert(OPT_NET_ID.clone(), old_netid);
@@ -592,7 +1280,7 @@ mod tests {
 
         // Storing the new RouterInfo should return no data
         assert_eq!(
-            netdb.store_router_info(key.clone(), ri.clone(), false),
+            netdb.store_router_info(key.clone(), ri.clone(), RouterInfoSource::Network),
             Ok(None)
         );
         assert_eq!(netdb.known_routers(), 1);
@@ -604,17 +1292,614 @@ mod tests {
         }
     }
 
+    #[test]
+    fn drop_lookup() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let ff = new_router_info();
+        let key = Hash([1; 32]);
+
+        // Start a lookup, and another that waits on it
+        let mut lookup = netdb.lookup_router_info(&key, 60_000, Some(ff.clone()));
+        let mut waiter = netdb.lookup_router_info(&key, 60_000, None);
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            assert_eq!(lookup.poll(), Ok(Async::NotReady));
+            assert_eq!(waiter.poll(), Ok(Async::NotReady));
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+        assert_eq!(netdb.pending_ri[&key].len(), 2);
+
+        // The lookup registered for a DatabaseSearchReply from the floodfill
+        let mut pending_lookups = HashMap::new();
+        match rx.into_future().wait() {
+            Ok((Some((from, dsr_key, dsr_tx)), _)) => {
+                assert_eq!(from, ff.router_id.hash());
+                assert_eq!(dsr_key, key);
+                pending_lookups.insert((from, dsr_key), dsr_tx);
+            }
+            _ => panic!("Expected a registration"),
+        }
+
+        // Dropping the lookup releases everything that was waiting on it
+        drop(lookup);
+        forget_dropped_search_replies(&mut pending_lookups);
+        assert!(pending_lookups.is_empty());
+        netdb.forget_dropped_lookups();
+        assert!(netdb.pending_ri.is_empty());
+        assert_eq!(waiter.wait(), Err(LookupError::TimedOut));
+    }
+
+    #[test]
+    fn store_future_router_info() {
+        let ctx = mock_context();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx.clone(), tx);
+
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.published =
+            I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(10 * 60));
+        ri.sign(&rsk.signing_private_key);
+        let key = ri.router_id.hash();
+
+        // Rejected with the default bound
+        assert_eq!(
+            netdb.store_router_info(key.clone(), ri.clone(), RouterInfoSource::Network),
+            Err(StoreError::PublishedInFuture)
+        );
+        assert_eq!(netdb.known_routers(), 0);
+
+        // including from sources that may hold expired RouterInfos
+        assert_eq!(
+            netdb.store_router_info(key.clone(), ri.clone(), RouterInfoSource::Reseed),
+            Err(StoreError::PublishedInFuture)
+        );
+        assert_eq!(netdb.known_routers(), 0);
+
+        // Accepted once the bound is raised
+        ctx.config
+            .write()
+            .unwrap()
+            .set(config::MAX_FUTURE_SKEW, 20 * 60)
+            .unwrap();
+        assert_eq!(
+            netdb.store_router_info(key, ri, RouterInfoSource::Network),
+            Ok(None)
+        );
+        assert_eq!(netdb.known_routers(), 1);
+    }
+
+    #[test]
+    fn store_expired_router_info_while_bootstrapping() {
+        let ctx = mock_context();
+        ctx.config
+            .write()
+            .unwrap()
+            .set(config::NETDB_BOOTSTRAP_GRACE, 60 * 60)
+            .unwrap();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx, tx);
+
+        let router_info = |expired_for: u64| {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.published = I2PDate::from_system_time(
+                SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + expired_for),
+            );
+            ri.sign(&rsk.signing_private_key);
+            (ri.router_id.hash(), ri)
+        };
+
+        // Within the grace period
+        let (key, ri) = router_info(30 * 60);
+        assert_eq!(
+            netdb.store_router_info(key, ri, RouterInfoSource::Network),
+            Ok(None)
+        );
+
+        // Beyond it
+        let (key, ri) = router_info(2 * 60 * 60);
+        match netdb.store_router_info(key, ri, RouterInfoSource::Network) {
+            Err(StoreError::Expired(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Once we know enough routers, the grace period no longer applies
+        while netdb.known_routers() < KEEP_ROUTERS {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.sign(&rsk.signing_private_key);
+            netdb
+                .store_router_info(ri.router_id.hash(), ri, RouterInfoSource::Network)
+                .unwrap();
+        }
+        let (key, ri) = router_info(30 * 60);
+        match netdb.store_router_info(key, ri, RouterInfoSource::Network) {
+            Err(StoreError::Expired(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reseed_expired_router_infos_while_bootstrapping() {
+        let ctx = mock_context();
+        ctx.config
+            .write()
+            .unwrap()
+            .set(config::NETDB_BOOTSTRAP_GRACE, 60 * 60)
+            .unwrap();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx, tx);
+
+        let router_info = |expired_for: u64| {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.published = I2PDate::from_system_time(
+                SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + expired_for),
+            );
+            ri.sign(&rsk.signing_private_key);
+            ri
+        };
+        let quarantined = |netdb: &LocalNetworkDatabase, ri: &RouterInfo| {
+            netdb
+                .router_info_metadata(&ri.router_id.hash())
+                .unwrap()
+                .quarantined
+        };
+
+        // RouterInfos within the grace period are used, and the others are
+        // stored but quarantined
+        let within = router_info(30 * 60);
+        let beyond = router_info(2 * 60 * 60);
+        let results = netdb.store_router_infos(
+            vec![within.clone(), beyond.clone()],
+            RouterInfoSource::Reseed,
+        );
+        assert_eq!(results, vec![Ok(None), Ok(None)]);
+        assert!(!quarantined(&netdb, &within));
+        assert!(quarantined(&netdb, &beyond));
+        assert_eq!(netdb.known_routers(), 1);
+
+        // Once we know enough routers, the grace period no longer applies
+        while netdb.known_routers() < KEEP_ROUTERS {
+            let ri = new_router_info();
+            netdb
+                .store_router_info(ri.router_id.hash(), ri, RouterInfoSource::Network)
+                .unwrap();
+        }
+        let late = router_info(30 * 60);
+        assert_eq!(
+            netdb.store_router_infos(vec![late.clone()], RouterInfoSource::Reseed),
+            vec![Ok(None)]
+        );
+        assert!(quarantined(&netdb, &late));
+        assert_eq!(netdb.known_routers(), KEEP_ROUTERS);
+    }
+
+    #[test]
+    fn stats() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        assert_eq!(netdb.stats(), NetDbStats::default());
+
+        let mut store = |caps: &str, version: &str| {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.set_option("caps".into(), caps.into());
+            ri.set_option("router.version".into(), version.into());
+            ri.sign(&rsk.signing_private_key);
+            netdb
+                .store_router_info(ri.router_id.hash(), ri, RouterInfoSource::Network)
+                .unwrap();
+        };
+        store("XfR", "0.9.50");
+        store("OfR", "0.9.50");
+        store("LU", "0.9.49");
+        store("OR", "0.9.50-1");
+        store("U", "garbage");
+        store("", "");
+
+        let stats = netdb.stats();
+        assert_eq!(stats.known_routers, 6);
+        assert_eq!(stats.floodfills, 2);
+        assert_eq!(stats.reachable, 3);
+        assert_eq!(stats.firewalled, 2);
+        assert_eq!(
+            stats.bandwidth_tiers.into_iter().collect::<Vec<_>>(),
+            vec![('L', 1), ('O', 2), ('X', 1)]
+        );
+        assert_eq!(
+            stats.versions.into_iter().collect::<Vec<_>>(),
+            vec![("0.9.49".to_string(), 1), ("0.9.50".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn store_router_info_with_too_many_addresses() {
+        let ctx = mock_context();
+        ctx.config
+            .write()
+            .unwrap()
+            .set(config::NETDB_MAX_ROUTER_ADDRESSES, 4)
+            .unwrap();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx, tx);
+
+        let router_info = |addresses: u16| {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.set_addresses(
+                (0..addresses)
+                    .map(|i| {
+                        let addr = SocketAddr::from(([127, 0, 0, 1], 10000 + i));
+                        RouterAddress::new(&NTCP2_STYLE, addr)
+                    })
+                    .collect(),
+            );
+            ri.sign(&rsk.signing_private_key);
+            (ri.router_id.hash(), ri)
+        };
+
+        let (key, ri) = router_info(4);
+        assert_eq!(
+            netdb.store_router_info(key, ri, RouterInfoSource::Network),
+            Ok(None)
+        );
+
+        let (key, ri) = router_info(200);
+        assert_eq!(
+            netdb.store_router_info(key.clone(), ri, RouterInfoSource::Network),
+            Err(StoreError::TooManyAddresses(200))
+        );
+        assert!(netdb.ri_ds.get(&key).is_none());
+    }
+
+    #[test]
+    fn store_older_router_info() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+
+        let rsk = RouterSecretKeys::new();
+        let now = SystemTime::now();
+        let mut older = RouterInfo::new(rsk.rid.clone());
+        older.sign_at(
+            &rsk.signing_private_key,
+            I2PDate::from_system_time(now - Duration::from_secs(60)),
+        );
+        let mut newer = RouterInfo::new(rsk.rid);
+        newer.sign_at(&rsk.signing_private_key, I2PDate::from_system_time(now));
+        let key = newer.router_id.hash();
+
+        assert_eq!(
+            netdb.store_router_info(key.clone(), newer.clone(), RouterInfoSource::Network),
+            Ok(None)
+        );
+
+        // The older RouterInfo doesn't replace the newer one
+        assert_eq!(
+            netdb.store_router_info(key.clone(), older.clone(), RouterInfoSource::Network),
+            Err(StoreError::Outdated)
+        );
+        assert_eq!(netdb.ri_ds[&key], newer);
+
+        // Storing the same RouterInfo again is fine
+        assert_eq!(
+            netdb.store_router_info(key.clone(), newer.clone(), RouterInfoSource::Network),
+            Ok(Some(newer.clone()))
+        );
+
+        // Even from a reseed
+        assert_eq!(
+            netdb.store_router_info(key.clone(), older, RouterInfoSource::Reseed),
+            Err(StoreError::Outdated)
+        );
+        assert_eq!(netdb.ri_ds[&key], newer);
+    }
+
+    #[test]
+    fn wait_for_peers() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let (client_tx, mut client_rx) = mpsc::unbounded();
+        let client = Client::new(client_tx);
+
+        let store_new_ri = |netdb: &mut LocalNetworkDatabase| {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.sign(&rsk.signing_private_key);
+            assert_eq!(
+                netdb.store_router_info(ri.router_id.hash(), ri, RouterInfoSource::Network),
+                Ok(None)
+            );
+        };
+
+        // Run on a task context
+        lazy(move || {
+            let mut handle_query = |netdb: &mut LocalNetworkDatabase| match client_rx.poll() {
+                Ok(Async::Ready(Some(query))) => query.handle(netdb),
+                _ => panic!("Client should have sent a query"),
+            };
+
+            // Wait for two peers
+            let mut wait = client.wait_for_peers(2);
+            match wait.poll() {
+                Ok(Async::NotReady) => (),
+                _ => panic!("Query should be pending"),
+            }
+            handle_query(&mut netdb);
+            match wait.poll() {
+                Ok(Async::NotReady) => (),
+                _ => panic!("NetDB is empty"),
+            }
+
+            store_new_ri(&mut netdb);
+            match wait.poll() {
+                Ok(Async::NotReady) => (),
+                _ => panic!("NetDB only contains one peer"),
+            }
+
+            store_new_ri(&mut netdb);
+            match wait.poll() {
+                Ok(Async::Ready(2)) => (),
+                _ => panic!("NetDB contains two peers"),
+            }
+
+            // A threshold that has already been reached resolves immediately
+            let mut wait = client.wait_for_peers(1);
+            match wait.poll() {
+                Ok(Async::NotReady) => (),
+                _ => panic!("Query should be pending"),
+            }
+            handle_query(&mut netdb);
+            match wait.poll() {
+                Ok(Async::Ready(2)) => (),
+                _ => panic!("NetDB contains two peers"),
+            }
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn lookup_destination() {
+        let (ctx, sent) = MockContextBuilder::new()
+            .ob_tunnels(Arc::new(TunnelPool::new(1)))
+            .build();
+        let (register_tx, _register_rx) = mpsc::channel(8);
+        let mut netdb = LocalNetworkDatabase::new(ctx, register_tx);
+        let (client_tx, mut client_rx) = mpsc::unbounded();
+        let client = Client::new(client_tx);
+
+        // A floodfill to ask
+        let rsk = RouterSecretKeys::new();
+        let mut ff = RouterInfo::new(rsk.rid);
+        ff.options.0.insert("caps".into(), "f".into());
+        ff.sign(&rsk.signing_private_key);
+        let ff_hash = ff.router_id.hash();
+        assert_eq!(
+            netdb.store_router_info(ff_hash.clone(), ff, RouterInfoSource::Network),
+            Ok(None)
+        );
+
+        // The LeaseSet that it has
+        let dsk = DestinationSecretKeys::new();
+        let (_, enc_key) = KeyPairGenerator::generate();
+        let sig_key = SigningPublicKey::from_secret(&dsk.signing_private_key).unwrap();
+        let mut ls = LeaseSet::new(dsk.dest, enc_key, sig_key);
+        ls.add_lease(Lease::new(
+            Hash([1; 32]),
+            TunnelId(1),
+            I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(60)),
+        ));
+        ls.sign(&dsk.signing_private_key).unwrap();
+        let key = ls.dest.hash();
+
+        let mut rt = Runtime::new().unwrap();
+        let mut lookup = client.lookup_destination(&key.to_b32(), 10_000);
+        rt.block_on(lazy(|| {
+            match lookup.poll() {
+                Ok(Async::NotReady) => (),
+                _ => panic!("Lookup should be pending"),
+            }
+            match client_rx.poll() {
+                Ok(Async::Ready(Some(query))) => query.handle(&mut netdb),
+                _ => panic!("Client should have sent a query"),
+            }
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+
+        // Give the lookup a chance to be sent
+        rt.block_on(Delay::new(Instant::now() + Duration::from_millis(100)))
+            .unwrap();
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].0, ff_hash);
+            match sent[0].1.payload {
+                MessagePayload::DatabaseLookup(ref dl) => assert_eq!(dl.key, key),
+                ref payload => panic!("Unexpected payload: {:?}", payload),
+            }
+        }
+
+        // The floodfill replies with the LeaseSet
+        assert!(netdb.store_lease_set(key.clone(), ls).is_ok());
+        match rt.block_on(lookup) {
+            Ok(found) => assert_eq!(found.dest.hash(), key),
+            Err(e) => panic!("Lookup failed: {}", e),
+        }
+
+        // Addresses that aren't base32 are rejected
+        match client.lookup_destination("example.i2p", 10_000).wait() {
+            Err(Error::Lookup(LookupError::InvalidKey)) => (),
+            _ => panic!("Expected an invalid key"),
+        }
+    }
+
+    #[test]
+    fn send_via_outbound_tunnel() {
+        let first_hop = new_router_info();
+        let peer = new_router_info();
+        let tid = TunnelId(42);
+        let store_msg = || {
+            Message::from_payload(MessagePayload::DatabaseStore(DatabaseStore::from_ri(
+                peer.clone(),
+                None,
+            )))
+        };
+        let pool = || {
+            let pool = TunnelPool::new(1);
+            assert!(pool.add(first_hop.clone(), tid, vec![]));
+            Arc::new(pool)
+        };
+
+        // By default, messages are sent directly
+        let (ctx, sent) = MockContextBuilder::new().ob_tunnels(pool()).build();
+        assert!(send_message(&ctx, peer.clone(), store_msg()).is_ok());
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].0, peer.router_id.hash());
+            match sent[0].1.payload {
+                MessagePayload::DatabaseStore(_) => (),
+                ref payload => panic!("Unexpected payload: {:?}", payload),
+            }
+        }
+
+        // When configured, messages are sent through an outbound tunnel
+        let mut config = Config::default();
+        config.set(config::NETDB_VIA_TUNNELS, true).unwrap();
+        let (ctx, sent) = MockContextBuilder::new()
+            .config(config.clone())
+            .ob_tunnels(pool())
+            .build();
+        assert!(send_message(&ctx, peer.clone(), store_msg()).is_ok());
+        {
+            let sent = sent.lock().unwrap();
+            assert!(!sent.is_empty());
+            for (to, msg) in sent.iter() {
+                assert_eq!(to, &first_hop.router_id.hash());
+                match msg.payload {
+                    MessagePayload::TunnelData(ref td) => assert_eq!(td.tid, tid),
+                    ref payload => panic!("Unexpected payload: {:?}", payload),
+                }
+            }
+        }
+
+        // Without an outbound tunnel, messages are sent directly
+        let (ctx, sent) = MockContextBuilder::new()
+            .config(config)
+            .ob_tunnels(Arc::new(TunnelPool::new(1)))
+            .build();
+        assert!(send_message(&ctx, peer.clone(), store_msg()).is_ok());
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].0, peer.router_id.hash());
+            match sent[0].1.payload {
+                MessagePayload::DatabaseStore(_) => (),
+                ref payload => panic!("Unexpected payload: {:?}", payload),
+            }
+        }
+    }
+
+    #[test]
+    fn router_info_metadata() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let ri = new_router_info();
+        let key = ri.router_id.hash();
+        assert_eq!(netdb.router_info_metadata(&key), None);
+
+        netdb
+            .store_router_info(key.clone(), ri.clone(), RouterInfoSource::Reseed)
+            .unwrap();
+        let first_seen = netdb.router_info_metadata(&key).unwrap().first_seen;
+        assert_eq!(
+            netdb.router_info_metadata(&key),
+            Some(&RouterInfoMetadata {
+                first_seen,
+                last_connected: None,
+                source: RouterInfoSource::Reseed,
+                quarantined: false,
+            })
+        );
+
+        // Record a connection to the peer
+        let connected = SystemTime::now();
+        netdb.router_info_metadata_mut(&key).unwrap().last_connected = Some(connected);
+
+        // Storing the RouterInfo again only updates where it came from
+        netdb
+            .store_router_info(key.clone(), ri, RouterInfoSource::Network)
+            .unwrap();
+        assert_eq!(
+            netdb.router_info_metadata(&key),
+            Some(&RouterInfoMetadata {
+                first_seen,
+                last_connected: Some(connected),
+                source: RouterInfoSource::Network,
+                quarantined: false,
+            })
+        );
+    }
+
+    #[test]
+    fn ri_adaptive_expiry() {
+        let ctx = mock_context();
+        {
+            let mut config = ctx.config.write().unwrap();
+            config.set(config::NETDB_EXPIRY_MIN_AGE, 60 * 60).unwrap();
+            config
+                .set(config::NETDB_EXPIRY_AGGRESSIVE_ROUTERS, 300)
+                .unwrap();
+        }
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx, tx);
+
+        // Too old for a large netDb, but not for a small one
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign_at(
+            &rsk.signing_private_key,
+            I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(5 * 60 * 60)),
+        );
+        let fill = |netdb: &mut LocalNetworkDatabase, count: u64| {
+            netdb.ri_ds.clear();
+            for i in 0..count {
+                let mut key = [0; 32];
+                key[..8].copy_from_slice(&i.to_le_bytes());
+                netdb.ri_ds.insert(Hash(key), ri.clone());
+            }
+        };
+
+        fill(&mut netdb, 200);
+        netdb.expire_router_infos(None);
+        assert_eq!(netdb.known_routers(), 200);
+
+        fill(&mut netdb, 300);
+        netdb.expire_router_infos(None);
+        assert_eq!(netdb.known_routers(), 0);
+    }
+
     #[test]
     fn ri_expiry() {
         let rsk = RouterSecretKeys::new();
         let mut ri = RouterInfo::new(rsk.rid);
-        assert_eq!(router_info_is_current(&ri), Ok(()));
+        assert_eq!(
+            router_info_is_current(&ri, DEFAULT_MAX_FUTURE_SKEW),
+            Ok(())
+        );
 
         // Expire the RouterInfo
         ri.published = I2PDate::from_system_time(
             SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
         );
-        match router_info_is_current(&ri) {
+        match router_info_is_current(&ri, DEFAULT_MAX_FUTURE_SKEW) {
             Ok(()) => panic!("RouterInfo should have expired"),
             Err(StoreError::Expired(_)) => (),
             Err(e) => panic!("Unexpected error: {}", e),
@@ -625,7 +1910,7 @@ mod tests {
             SystemTime::now() + Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
         );
         assert_eq!(
-            router_info_is_current(&ri),
+            router_info_is_current(&ri, DEFAULT_MAX_FUTURE_SKEW),
             Err(StoreError::PublishedInFuture)
         );
     }
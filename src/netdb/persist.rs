@@ -0,0 +1,310 @@
+//! Storage of the network database on disk, so that known peers survive a
+//! restart.
+
+use futures::{future, Async, Future};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio_threadpool::blocking;
+
+use super::{LocalNetworkDatabase, RouterInfoSource};
+use crate::data::{frame, Hash, RouterInfo};
+
+const RI_FILE_PREFIX: &str = "routerInfo-";
+const RI_FILE_SUFFIX: &str = ".dat";
+
+fn router_info_filename(hash: &Hash) -> String {
+    format!("{}{}{}", RI_FILE_PREFIX, hash, RI_FILE_SUFFIX)
+}
+
+fn is_router_info_filename(name: &str) -> bool {
+    name.starts_with(RI_FILE_PREFIX) && name.ends_with(RI_FILE_SUFFIX)
+}
+
+/// Reads the RouterInfo in a file, returning `None` if it can't be parsed.
+type ReadRouterInfo = fn(&Path) -> io::Result<Option<RouterInfo>>;
+
+fn parse_router_info(data: &[u8]) -> Option<RouterInfo> {
+    frame::router_info(data).ok().map(|(_, ri)| ri)
+}
+
+#[cfg(any(test, not(feature = "mmap")))]
+fn read_router_info(path: &Path) -> io::Result<Option<RouterInfo>> {
+    fs::read(path).map(|data| parse_router_info(&data))
+}
+
+#[cfg(feature = "mmap")]
+fn map_router_info(path: &Path) -> io::Result<Option<RouterInfo>> {
+    let file = fs::File::open(path)?;
+    // The netDb directory belongs to us, so nothing else should modify the
+    // file while it is mapped. If another process truncated it, reading the
+    // mapping would crash the router, which is why this loader is opt-in.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(parse_router_info(&map))
+}
+
+/// Writes the given RouterInfos to a directory, creating it if necessary.
+/// RouterInfo files for other routers are removed.
+fn write_router_infos(dir: &Path, ris: &[(Hash, RouterInfo)]) -> io::Result<usize> {
+    fs::create_dir_all(dir)?;
+
+    let mut written = HashSet::new();
+    for (key, ri) in ris {
+        let filename = router_info_filename(key);
+        fs::write(dir.join(&filename), ri.to_bytes())?;
+        written.insert(filename);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if is_router_info_filename(name) && !written.contains(name) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    debug!("Persisted {} RouterInfos to {}", written.len(), dir.display());
+    Ok(written.len())
+}
+
+impl LocalNetworkDatabase {
+    fn router_infos(&self) -> Vec<(Hash, RouterInfo)> {
+        self.ri_ds
+            .iter()
+            .map(|(key, ri)| (key.clone(), ri.clone()))
+            .collect()
+    }
+
+    /// Writes every known RouterInfo to the given directory straight away.
+    #[cfg(test)]
+    pub(super) fn persist_to_dir(&self, dir: &Path) -> io::Result<usize> {
+        write_router_infos(dir, &self.router_infos())
+    }
+
+    /// Returns a future that writes every currently known RouterInfo to the
+    /// given directory, creating it if necessary. RouterInfo files for routers
+    /// we no longer know are removed.
+    ///
+    /// The files are written on the [`blocking()`] threadpool, or directly if
+    /// the future isn't run on a threadpool. It resolves to the number of
+    /// RouterInfos written.
+    pub(super) fn persist_to_dir_blocking(
+        &self,
+        dir: PathBuf,
+    ) -> impl Future<Item = usize, Error = io::Error> {
+        let ris = self.router_infos();
+        future::poll_fn(move || match blocking(|| write_router_infos(&dir, &ris)) {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => write_router_infos(&dir, &ris).map(Async::Ready),
+        })
+    }
+
+    /// Loads the RouterInfos in the given directory that were written by
+    /// [`LocalNetworkDatabase::persist_to_dir`]. Files that can't be read, or
+    /// that contain an invalid RouterInfo, are skipped.
+    ///
+    /// With the `mmap` feature, each file is parsed directly from a memory map
+    /// instead of being read into a buffer first.
+    ///
+    /// Returns the number of RouterInfos loaded.
+    pub(super) fn load_from_dir(&mut self, dir: &Path) -> io::Result<usize> {
+        #[cfg(feature = "mmap")]
+        let read = map_router_info;
+        #[cfg(not(feature = "mmap"))]
+        let read = read_router_info;
+        self.load_from_dir_with(dir, read)
+    }
+
+    fn load_from_dir_with(&mut self, dir: &Path, read: ReadRouterInfo) -> io::Result<usize> {
+        let mut paths = vec![];
+        let mut ris = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if is_router_info_filename(name) => (),
+                _ => continue,
+            }
+
+            let ri = match read(&path) {
+                Ok(Some(ri)) => ri,
+                Ok(None) => {
+                    warn!("Skipping corrupt RouterInfo file {}", path.display());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            paths.push(path);
+            ris.push(ri);
+        }
+
+        // Like RouterInfos from a reseed, these may be old; they are expired as
+        // usual once we know enough routers. Until then, the ones that expired
+        // before the bootstrap grace period are quarantined.
+        let results = self.store_router_infos(ris, RouterInfoSource::Disk);
+
+        let mut loaded = 0;
+        for (res, path) in results.into_iter().zip(paths) {
+            match res {
+                Ok(_) => loaded += 1,
+                Err(e) => warn!("Skipping RouterInfo in {}: {}", path.display(), e),
+            }
+        }
+
+        info!("Loaded {} RouterInfos from {}", loaded, dir.display());
+        Ok(loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sync::mpsc;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    use super::router_info_filename;
+    #[cfg(feature = "mmap")]
+    use super::{map_router_info, read_router_info, ReadRouterInfo};
+    use crate::data::{I2PDate, RouterInfo, RouterSecretKeys, ROUTER_INFO_EXPIRATION};
+    use crate::netdb::{LocalNetworkDatabase, RouterInfoSource};
+    use crate::router::mock::mock_context;
+
+    fn new_router_info() -> RouterInfo {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign(&rsk.signing_private_key);
+        ri
+    }
+
+    #[test]
+    fn persist_and_load() {
+        let dir = tempdir().unwrap();
+        let netdb_dir = dir.path().join("netDb");
+
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let ris: Vec<_> = (0..3).map(|_| new_router_info()).collect();
+        for ri in &ris {
+            netdb
+                .store_router_info(ri.router_id.hash(), ri.clone(), RouterInfoSource::Network)
+                .unwrap();
+        }
+        assert_eq!(netdb.persist_to_dir(&netdb_dir).unwrap(), 3);
+
+        // Add a corrupt file and a RouterInfo with a bad signature
+        let mut bad_sig = new_router_info();
+        bad_sig.published = I2PDate(1);
+        fs::write(
+            netdb_dir.join(router_info_filename(&bad_sig.router_id.hash())),
+            bad_sig.to_bytes(),
+        )
+        .unwrap();
+        let corrupt = netdb_dir.join(router_info_filename(&new_router_info().router_id.hash()));
+        fs::write(&corrupt, b"not a RouterInfo").unwrap();
+
+        let (tx, _) = mpsc::channel(0);
+        let mut loaded = LocalNetworkDatabase::new(mock_context(), tx);
+        assert_eq!(loaded.load_from_dir(&netdb_dir).unwrap(), 3);
+        assert_eq!(loaded.known_routers(), 3);
+        for ri in &ris {
+            assert_eq!(loaded.ri_ds.get(&ri.router_id.hash()), Some(ri));
+        }
+
+        // Persisting again, on the blocking threadpool, removes the files
+        // that weren't loaded
+        let mut rt = Runtime::new().unwrap();
+        let persist = loaded.persist_to_dir_blocking(netdb_dir.clone());
+        assert_eq!(rt.block_on(persist).unwrap(), 3);
+        assert!(!corrupt.exists());
+        assert_eq!(fs::read_dir(&netdb_dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn quarantine_expired() {
+        let dir = tempdir().unwrap();
+
+        // An expired floodfill, as we might find after a long time offline
+        let rsk = RouterSecretKeys::new();
+        let router_info = |age: u64| {
+            let mut ri = RouterInfo::new(rsk.rid.clone());
+            ri.set_option("caps".into(), "fR".into());
+            ri.published = I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(age));
+            ri.sign(&rsk.signing_private_key);
+            ri
+        };
+        let key = rsk.rid.hash();
+        let expired = router_info(ROUTER_INFO_EXPIRATION + 60 * 60);
+        fs::write(
+            dir.path().join(router_info_filename(&key)),
+            expired.to_bytes(),
+        )
+        .unwrap();
+
+        // It is loaded, but not selected
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        assert_eq!(netdb.load_from_dir(dir.path()).unwrap(), 1);
+        assert_eq!(netdb.ri_ds.get(&key), Some(&expired));
+        let metadata = netdb.router_info_metadata(&key).unwrap();
+        assert_eq!(metadata.source, RouterInfoSource::Disk);
+        assert!(metadata.quarantined);
+        assert_eq!(netdb.known_routers(), 0);
+        assert!(netdb.closest_to(&key, 10).is_empty());
+        assert!(netdb.select_closest_ff(&key).is_none());
+
+        // Until a fresh version arrives
+        let fresh = router_info(0);
+        assert_eq!(
+            netdb.store_router_info(key.clone(), fresh.clone(), RouterInfoSource::Network),
+            Ok(Some(expired))
+        );
+        assert!(!netdb.router_info_metadata(&key).unwrap().quarantined);
+        assert_eq!(netdb.known_routers(), 1);
+        assert_eq!(netdb.closest_to(&key, 10), vec![fresh.clone()]);
+        assert_eq!(netdb.select_closest_ff(&key), Some(fresh));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn load_mapped() {
+        let dir = tempdir().unwrap();
+        let netdb_dir = dir.path().join("netDb");
+
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        for _ in 0..5 {
+            let ri = new_router_info();
+            netdb
+                .store_router_info(ri.router_id.hash(), ri, RouterInfoSource::Network)
+                .unwrap();
+        }
+        assert_eq!(netdb.persist_to_dir(&netdb_dir).unwrap(), 5);
+        let corrupt = netdb_dir.join(router_info_filename(&new_router_info().router_id.hash()));
+        fs::write(&corrupt, b"").unwrap();
+
+        let load = |read: ReadRouterInfo| {
+            let (tx, _) = mpsc::channel(0);
+            let mut loaded = LocalNetworkDatabase::new(mock_context(), tx);
+            assert_eq!(loaded.load_from_dir_with(&netdb_dir, read).unwrap(), 5);
+            loaded.ri_ds
+        };
+
+        // Both loaders find the same RouterInfos
+        let mapped = load(map_router_info);
+        assert_eq!(mapped, load(read_router_info));
+        assert_eq!(mapped, netdb.ri_ds);
+    }
+}
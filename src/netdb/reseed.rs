@@ -1,13 +1,17 @@
-use futures::{future, Async, Future, Poll};
+use futures::{Async, Future, Poll};
 use native_tls::{Certificate, TlsConnector};
 use rand::{seq::SliceRandom, thread_rng};
 use std::collections::HashMap;
 use std::net::ToSocketAddrs;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::{io, net::tcp::TcpStream, timer::Timeout};
 
-use super::client::{Client, StoreRouterInfo};
-use crate::crypto::{OfflineSigningPublicKey, SigType};
+use super::{
+    client::{Client, StoreRouterInfos},
+    RouterInfoSource,
+};
+use crate::crypto::{OfflineSigningPublicKey, SigType, SigningPrivateKey};
+use crate::data::RouterInfo;
 use crate::file::{Error as FileError, Su3Content, Su3File};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
@@ -98,6 +102,25 @@ macro_rules! try_poll {
     };
 }
 
+/// Builds a reseed bundle containing the given RouterInfos, signed by `signer`.
+///
+/// The returned bytes are an SU3 file that can be served to other routers, who
+/// must have `signer_id` configured as a trusted reseed signer.
+pub fn build_bundle(
+    ris: &[RouterInfo],
+    signer_id: &str,
+    signer: &SigningPrivateKey,
+) -> Result<Vec<u8>, FileError> {
+    // Like the Java router, use the current time as the version
+    let version = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+        .to_string();
+
+    Su3File::create_signed(&version, signer_id, &Su3Content::Reseed(ris.to_vec()), signer)
+}
+
 fn reseed_from_host(
     cx: &TlsConnector,
     (host, path): ((&'static str, u16), &'static str),
@@ -167,7 +190,7 @@ fn reseed_from_host(
 
 enum ReseedState {
     Fetching(IoFuture<Su3File>),
-    Storing(future::SelectAll<StoreRouterInfo>),
+    Storing(StoreRouterInfos),
     NextHost,
 }
 
@@ -222,12 +245,9 @@ impl Future for HttpsReseeder {
                                 self.succeeded += 1;
                                 self.fetched += new_ri.len();
 
-                                ReseedState::Storing(future::select_all(new_ri.into_iter().map(
-                                    |ri| {
-                                        let hash = ri.router_id.hash();
-                                        self.netdb.store_router_info(hash, ri, true)
-                                    },
-                                )))
+                                let store =
+                                    self.netdb.store_router_infos(new_ri, RouterInfoSource::Reseed);
+                                ReseedState::Storing(store)
                             }
                         },
                         Err(e) => {
@@ -237,30 +257,29 @@ impl Future for HttpsReseeder {
                     }
                 }
                 ReseedState::Storing(mut f) => {
-                    let remaining = match try_poll!(f.poll(), self, ReseedState::Storing(f)) {
-                        Ok((_, _, remaining)) => {
-                            self.valid += 1;
-                            remaining
-                        }
-                        Err((e, _, remaining)) => {
-                            error!("Invalid RouterInfo received from reseed: {}", e);
-                            remaining
+                    match try_poll!(f.poll(), self, ReseedState::Storing(f)) {
+                        Ok(results) => {
+                            for res in results {
+                                match res {
+                                    Ok(_) => self.valid += 1,
+                                    Err(e) => {
+                                        error!("Invalid RouterInfo received from reseed: {}", e)
+                                    }
+                                }
+                            }
                         }
-                    };
+                        Err(e) => error!("Failed to store RouterInfos from reseed: {}", e),
+                    }
 
-                    if remaining.is_empty() {
-                        // Check if we are done reseeding
-                        if self.valid >= MIN_RI_WANTED && self.succeeded >= MIN_RESEED_SERVERS {
-                            info!(
-                                "Fetched {} RouterInfos from {} servers ({} valid)",
-                                self.fetched, self.succeeded, self.valid
-                            );
-                            return Ok(Async::Ready(()));
-                        } else {
-                            ReseedState::NextHost
-                        }
+                    // Check if we are done reseeding
+                    if self.valid >= MIN_RI_WANTED && self.succeeded >= MIN_RESEED_SERVERS {
+                        info!(
+                            "Fetched {} RouterInfos from {} servers ({} valid)",
+                            self.fetched, self.succeeded, self.valid
+                        );
+                        return Ok(Async::Ready(()));
                     } else {
-                        ReseedState::Storing(future::select_all(remaining))
+                        ReseedState::NextHost
                     }
                 }
                 ReseedState::NextHost => {
@@ -288,3 +307,51 @@ impl Future for HttpsReseeder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::build_bundle;
+    use crate::crypto::{OfflineSigningPublicKey, SigType, SigningPrivateKey, SigningPublicKey};
+    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::file::{Error as FileError, Su3Content, Su3File};
+
+    #[test]
+    fn bundle_round_trip() {
+        let ris: Vec<_> = (0..3)
+            .map(|_| {
+                let rsk = RouterSecretKeys::new();
+                let mut ri = RouterInfo::new(rsk.rid);
+                ri.sign(&rsk.signing_private_key);
+                ri
+            })
+            .collect();
+
+        let signer = SigningPrivateKey::new();
+        let bundle = build_bundle(&ris, "test@mail.i2p", &signer).unwrap();
+
+        let mut signers = HashMap::new();
+        signers.insert(
+            "test@mail.i2p",
+            OfflineSigningPublicKey::from_bytes(
+                SigType::Ed25519,
+                SigningPublicKey::from_secret(&signer).unwrap().as_bytes(),
+            )
+            .unwrap(),
+        );
+
+        match Su3File::from_bytes(&bundle, &signers) {
+            Ok(su3_file) => match su3_file.content {
+                Su3Content::Reseed(parsed) => assert_eq!(parsed, ris),
+            },
+            Err(e) => panic!("Error while parsing reseed bundle: {:?}", e),
+        }
+
+        // Bundles from unknown signers are rejected
+        match Su3File::from_bytes(&bundle, &HashMap::new()) {
+            Ok(_) => panic!("Accepted bundle from unknown signer"),
+            Err(e) => assert_eq!(e, FileError::UnknownSigner),
+        }
+    }
+}
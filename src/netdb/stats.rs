@@ -0,0 +1,51 @@
+//! Statistics about the RouterInfos in the network database.
+
+use std::collections::BTreeMap;
+
+use crate::data::{RouterInfo, Version};
+
+/// A snapshot of the routers in the network database, by the capabilities and
+/// versions they advertise.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetDbStats {
+    pub known_routers: usize,
+    pub floodfills: usize,
+    /// Routers that advertise the `R` cap.
+    pub reachable: usize,
+    /// Routers that advertise the `U` cap.
+    pub firewalled: usize,
+    /// The number of routers in each bandwidth tier. Routers that don't
+    /// advertise a tier are not counted.
+    pub bandwidth_tiers: BTreeMap<char, usize>,
+    /// The number of routers running each version. Routers that don't
+    /// advertise a valid version are not counted.
+    pub versions: BTreeMap<String, usize>,
+}
+
+impl NetDbStats {
+    pub(super) fn from_router_infos<'a, I>(ris: I) -> Self
+    where
+        I: IntoIterator<Item = &'a RouterInfo>,
+    {
+        let mut stats = NetDbStats::default();
+        for ri in ris {
+            stats.known_routers += 1;
+            if ri.is_floodfill() {
+                stats.floodfills += 1;
+            }
+            if ri.is_reachable() {
+                stats.reachable += 1;
+            }
+            if ri.is_unreachable() {
+                stats.firewalled += 1;
+            }
+            if let Some(tier) = ri.bandwidth_tier() {
+                *stats.bandwidth_tiers.entry(tier).or_insert(0) += 1;
+            }
+            if let Some(version) = Version::from_option(ri) {
+                *stats.versions.entry(version.to_string()).or_insert(0) += 1;
+            }
+        }
+        stats
+    }
+}
@@ -5,10 +5,19 @@ use std::fs;
 use std::io;
 use std::sync::{Arc, RwLock};
 
-use super::{types::CommSystem, Context, Distributor, Router};
-use crate::data::{ReadError, RouterInfo, RouterSecretKeys};
+use super::{
+    replay::Recorder,
+    types::{self, CommSystem, OutboundTunnelPool, PeerSelector},
+    Context, DeliveryStatuses, Distributor, Router, TierWeightedSelector,
+    EXPLORATORY_OUTBOUND_TUNNELS,
+};
+use crate::crypto::elgamal;
+use crate::data::{
+    I2PString, ReadError, RouterInfo, RouterSecretKeys, I2P_VERSION, OPT_CORE_VERSION,
+    OPT_ROUTER_VERSION,
+};
 use crate::netdb::{client::Client as NetDbClient, Engine as NetDbEngine};
-use crate::router::config;
+use crate::router::config::{self, Validate};
 use crate::transport;
 use crate::tunnel;
 use std::panic::panic_any;
@@ -16,6 +25,7 @@ use std::panic::panic_any;
 /// Builder errors
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
+    Config(config::Error),
     Read(ReadError),
     Write(String),
 }
@@ -24,12 +34,19 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Error::Config(e) => format!("{}", e).fmt(f),
             Error::Read(e) => format!("{}", e).fmt(f),
             Error::Write(e) => e.fmt(f),
         }
     }
 }
 
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Self {
+        Error::Config(e)
+    }
+}
+
 impl From<ReadError> for Error {
     fn from(e: ReadError) -> Self {
         Error::Read(e)
@@ -47,6 +64,11 @@ pub struct Builder {
     keys: Option<RouterSecretKeys>,
     ri_file: Option<String>,
     comms: Option<Arc<RwLock<dyn CommSystem>>>,
+    ob_tunnels: Option<Arc<dyn OutboundTunnelPool>>,
+    peer_selector: Option<Box<dyn PeerSelector>>,
+    transports: Vec<transport::TransportFactory>,
+    reseed: bool,
+    ssu_introducer: bool,
 }
 
 impl Builder {
@@ -57,6 +79,11 @@ impl Builder {
             keys: None,
             ri_file: None,
             comms: None,
+            ob_tunnels: None,
+            peer_selector: None,
+            transports: vec![],
+            reseed: true,
+            ssu_introducer: false,
         }
     }
 
@@ -80,16 +107,67 @@ impl Builder {
         self
     }
 
+    pub fn outbound_tunnel_pool(mut self, ob_tunnels: Arc<dyn OutboundTunnelPool>) -> Self {
+        self.ob_tunnels = Some(ob_tunnels);
+        self
+    }
+
+    /// Overrides the policy used to select peers for tunnels and netDb
+    /// interactions. Defaults to [`TierWeightedSelector`].
+    pub fn peer_selector(mut self, peer_selector: Box<dyn PeerSelector>) -> Self {
+        self.peer_selector = Some(peer_selector);
+        self
+    }
+
+    /// Registers an additional transport, such as one implemented outside this
+    /// crate. `factory` is called with the distributor that the transport must
+    /// pass received messages to.
+    ///
+    /// Has no effect if a comm system is set with [`Builder::comm_system`].
+    pub fn transport<F>(mut self, factory: F) -> Self
+    where
+        F: FnOnce(transport::SharedDistributor) -> Box<dyn transport::Transport> + Send + 'static,
+    {
+        self.transports.push(Box::new(factory));
+        self
+    }
+
+    /// Never reseed, even if the network database is empty, overriding the
+    /// `reseed.enable` config option. The router will only know the routers
+    /// whose RouterInfos are on disk or are imported.
+    pub fn no_reseed(mut self) -> Self {
+        self.reseed = false;
+        self
+    }
+
+    /// Advertise in our SSU address that we introduce firewalled peers, as if
+    /// the `transport.ssu.introducer` config option were set. Has no effect
+    /// unless SSU is configured.
+    pub fn ssu_introducer(mut self) -> Self {
+        self.ssu_introducer = true;
+        self
+    }
+
     /// Build a Router.
     pub fn build(self) -> Result<Router, Error> {
         let mut settings = Config::default();
 
         // Default config options
         settings.set_default(config::RESEED_ENABLE, true).unwrap();
+        settings.set_default(config::NETDB_VIA_TUNNELS, false).unwrap();
+        settings.set_default(config::ROUTER_VERSION, I2P_VERSION).unwrap();
+        settings.set_default(config::CORE_VERSION, I2P_VERSION).unwrap();
 
         if let Some(ref cfg_file) = self.cfg_file {
             settings.merge(File::with_name(&cfg_file)).unwrap();
         }
+        if !self.reseed {
+            settings.set(config::RESEED_ENABLE, false).unwrap();
+        }
+        if self.ssu_introducer {
+            settings.set(config::SSU_INTRODUCER, true).unwrap();
+        }
+        settings.validate()?;
 
         let keys = match self.keys {
             Some(keys) => keys,
@@ -140,24 +218,50 @@ impl Builder {
         let (new_participating_tx, new_participating_rx) = mpsc::channel(1024);
         let (tunnel_data_ib_tx, tunnel_data_ib_rx) = mpsc::channel(1024);
 
-        let distributor = Distributor::new(netdb_ib_tx, tunnel_build_ib_tx, tunnel_data_ib_tx);
+        let delivery_statuses = DeliveryStatuses::default();
+        let distributor = Distributor::new(
+            netdb_ib_tx,
+            tunnel_build_ib_tx,
+            tunnel_data_ib_tx,
+            delivery_statuses.clone(),
+            elgamal::Decryptor::from(&keys.private_key),
+        );
         let netdb_client = NetDbClient::new(netdb_client_tx);
 
         let comms = match self.comms {
-            Some(comms) => comms,
-            None => Arc::new(RwLock::new(transport::Manager::from_config(
-                &settings,
-                distributor,
-            ))),
+            Some(comms) => {
+                if !self.transports.is_empty() {
+                    warn!("Ignoring registered transports, as a comm system was provided");
+                }
+                comms
+            }
+            None => match settings.get_str(config::MESSAGE_LOG) {
+                Ok(message_log) => {
+                    info!("Recording received messages to {}", message_log);
+                    let recorder = Recorder::create(&message_log, distributor)?;
+                    comm_system(&settings, recorder, self.transports)
+                }
+                Err(_) => comm_system(&settings, distributor, self.transports),
+            },
         };
 
+        let participating_bandwidth = tunnel::ParticipatingBandwidth::from_config(&settings);
         let tunnel_participant = Some(tunnel::Participant::new(
             new_participating_rx,
             tunnel_data_ib_rx,
             comms.clone(),
+            participating_bandwidth.clone(),
         ));
 
         let mut ri = RouterInfo::new(keys.rid.clone());
+        ri.set_option(
+            OPT_ROUTER_VERSION.clone(),
+            I2PString(settings.get_str(config::ROUTER_VERSION).unwrap()),
+        );
+        ri.set_option(
+            OPT_CORE_VERSION.clone(),
+            I2PString(settings.get_str(config::CORE_VERSION).unwrap()),
+        );
         ri.set_addresses(comms.read().unwrap().addresses());
         ri.sign(&keys.signing_private_key);
 
@@ -170,12 +274,26 @@ impl Builder {
             Err(e) => panic_any(e),
         }
 
+        // Unless we were given a pool, keep our own exploratory tunnels
+        let (ob_tunnels, exploratory_tunnels) = match self.ob_tunnels {
+            Some(ob_tunnels) => (ob_tunnels, None),
+            None => {
+                let pool = Arc::new(tunnel::TunnelPool::new(EXPLORATORY_OUTBOUND_TUNNELS));
+                (pool.clone() as Arc<dyn OutboundTunnelPool>, Some(pool))
+            }
+        };
+
         let ctx = Arc::new(Context {
             config: RwLock::new(settings),
             keys,
             ri: Arc::new(RwLock::new(ri)),
             netdb: netdb_client,
             comms,
+            ob_tunnels: Some(ob_tunnels),
+            peer_selector: self
+                .peer_selector
+                .unwrap_or_else(|| Box::new(TierWeightedSelector)),
+            delivery_statuses,
         });
 
         let netdb_engine = Some(NetDbEngine::new(
@@ -189,14 +307,152 @@ impl Builder {
         let tunnel_listener = Some(tunnel::Listener::new(
             ctx.clone(),
             new_participating_tx,
+            participating_bandwidth,
             tunnel_build_ib_rx,
         ));
 
-        Ok(Router {
+        Ok(Router::new(
             ctx,
             netdb_engine,
             tunnel_listener,
             tunnel_participant,
-        })
+            exploratory_tunnels,
+        ))
+    }
+}
+
+/// Creates the default comm system, with the given transports registered
+/// alongside the built-in ones.
+fn comm_system<D: types::Distributor>(
+    settings: &Config,
+    distributor: D,
+    transports: Vec<transport::TransportFactory>,
+) -> Arc<RwLock<dyn CommSystem>> {
+    let mut manager = transport::Manager::from_config(settings, distributor.clone());
+    for factory in transports {
+        manager.register(factory(transport::SharedDistributor::new(
+            distributor.clone(),
+        )));
+    }
+    Arc::new(RwLock::new(manager))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{lazy, Async, Future};
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, RwLock};
+    use tempfile::tempdir;
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::Builder;
+    use crate::data::{I2PString, Version, I2P_VERSION, OPT_CORE_VERSION};
+    use crate::router::{config, mock::MockCommSystem, EXPLORATORY_OUTBOUND_TUNNELS};
+    use crate::tunnel::TunnelPool;
+
+    #[test]
+    fn no_reseed() {
+        let mut router = Builder::new()
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .no_reseed()
+            .build()
+            .unwrap();
+        let enabled = router
+            .ctx
+            .config
+            .read()
+            .unwrap()
+            .get_bool(config::RESEED_ENABLE);
+        assert!(!enabled.unwrap());
+
+        // The netDb is empty, but it doesn't start a reseed
+        let mut engine = router.netdb_engine.take().unwrap();
+        let mut rt = Runtime::new().unwrap();
+        assert_eq!(rt.block_on(lazy(|| engine.poll())), Ok(Async::NotReady));
+        assert!(!engine.is_reseeding());
+    }
+
+    #[test]
+    fn router_info_versions() {
+        let router = Builder::new()
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .build()
+            .unwrap();
+        let ri = router.ctx.published_router_info();
+        assert!(ri.verify().is_ok());
+
+        let version: Version = I2P_VERSION.parse().unwrap();
+        assert_eq!(Version::from_option(&ri), Some(version.clone()));
+        let core_version = ri.option(&OPT_CORE_VERSION).unwrap();
+        assert_eq!(core_version.0.parse::<Version>(), Ok(version));
+        assert_eq!(*core_version, I2PString::new(I2P_VERSION));
+    }
+
+    #[test]
+    fn exploratory_tunnels() {
+        let router = Builder::new()
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .build()
+            .unwrap();
+        let pool = router.exploratory_tunnels.clone().unwrap();
+        assert_eq!(pool.tunnels_to_build(), EXPLORATORY_OUTBOUND_TUNNELS);
+        assert!(router.ctx.ob_tunnels.is_some());
+
+        // A pool that we are given is used instead
+        let router = Builder::new()
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .outbound_tunnel_pool(Arc::new(TunnelPool::new(1)))
+            .build()
+            .unwrap();
+        assert!(router.exploratory_tunnels.is_none());
+        assert_eq!(router.ctx.ob_tunnels.as_ref().unwrap().live_tunnels(), 0);
+    }
+
+    #[test]
+    fn ssu_introducer() {
+        let dir = tempdir().unwrap();
+        let cfg_file = dir.path().join("router.toml");
+        fs::write(
+            &cfg_file,
+            format!(
+                r#"
+[transport.ntcp]
+listen = "127.0.0.1:0"
+
+[transport.ntcp2]
+listen = "127.0.0.2:0"
+keyfile = "{}"
+
+[transport.ssu]
+listen = "127.0.0.3:0"
+keyfile = "{}"
+"#,
+                dir.path().join("ntcp2.keys.dat").display(),
+                dir.path().join("ssu.keys.dat").display(),
+            ),
+        )
+        .unwrap();
+        let ssu_addr: SocketAddr = "127.0.0.3:0".parse().unwrap();
+
+        let build = |introducer: bool| {
+            let builder = Builder::new().config_file(cfg_file.to_str().unwrap().to_owned());
+            if introducer {
+                builder.ssu_introducer().build().unwrap()
+            } else {
+                builder.build().unwrap()
+            }
+        };
+
+        // Only the SSU address advertises the introducer cap
+        let ri = build(true).published_router_info();
+        assert!(ri.verify().is_ok());
+        assert!(ri.addresses().iter().any(|ra| ra.is_introducer()));
+        for ra in ri.addresses() {
+            assert_eq!(ra.is_introducer(), ra.addr() == Some(ssu_addr));
+        }
+
+        let ri = build(false).published_router_info();
+        assert!(ri.addresses().iter().all(|ra| !ra.is_introducer()));
     }
 }
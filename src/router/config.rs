@@ -1,13 +1,699 @@
 pub use config::Config;
+use config::ConfigError;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::data::Version;
+use crate::transport::{ntcp2, PeerFilter, UnknownMessagePolicy};
 
 // Router
 pub const ROUTER_KEYFILE: &str = "router.keyfile";
 pub const RI_FILE: &str = "router.infofile";
+pub const MAX_FUTURE_SKEW: &str = "router.max_future_skew";
+pub const MESSAGE_LOG: &str = "router.message_log";
+pub const ROUTER_VERSION: &str = "router.version";
+pub const CORE_VERSION: &str = "router.core_version";
+pub const SHUTDOWN_TIMEOUT: &str = "router.shutdown_timeout";
 
 // Reseeding
 pub const RESEED_ENABLE: &str = "reseed.enable";
 
+// Network database
+pub const NETDB_VIA_TUNNELS: &str = "netdb.via_tunnels";
+pub const NETDB_DIR: &str = "netdb.dir";
+pub const NETDB_EXPIRY_MIN_AGE: &str = "netdb.expiry_min_age";
+pub const NETDB_EXPIRY_AGGRESSIVE_ROUTERS: &str = "netdb.expiry_aggressive_routers";
+pub const NETDB_BOOTSTRAP_GRACE: &str = "netdb.bootstrap_grace";
+pub const NETDB_MAX_ROUTER_ADDRESSES: &str = "netdb.max_router_addresses";
+
+// Tunnels
+pub const TUNNEL_PARTICIPATING_SHARE: &str = "tunnel.participating_share";
+
 // Transports
+pub const TRANSPORT_ALLOWLIST: &str = "transport.allowlist";
+pub const TRANSPORT_BLOCKLIST: &str = "transport.blocklist";
+pub const TRANSPORT_MAX_OUTBOUND_DIALS: &str = "transport.max_outbound_dials";
+pub const TRANSPORT_UNKNOWN_MESSAGES: &str = "transport.unknown_messages";
+pub const TRANSPORT_INBOUND_BANDWIDTH: &str = "transport.inbound_bandwidth";
+pub const TRANSPORT_INBOUND_BURST: &str = "transport.inbound_burst";
+pub const TRANSPORT_OUTBOUND_BANDWIDTH: &str = "transport.outbound_bandwidth";
+pub const TRANSPORT_OUTBOUND_BURST: &str = "transport.outbound_burst";
 pub const NTCP_LISTEN: &str = "transport.ntcp.listen";
 pub const NTCP2_LISTEN: &str = "transport.ntcp2.listen";
 pub const NTCP2_KEYFILE: &str = "transport.ntcp2.keyfile";
+pub const NTCP2_IDLE_TIMEOUT: &str = "transport.ntcp2.idle_timeout";
+pub const NTCP2_KEEPALIVE: &str = "transport.ntcp2.keepalive";
+pub const NTCP2_CIPHER_SUITE: &str = "transport.ntcp2.cipher_suite";
+pub const NTCP2_HANDSHAKE_TIMEOUT: &str = "transport.ntcp2.handshake_timeout";
+pub const NTCP2_HANDSHAKE_STEP_TIMEOUT: &str = "transport.ntcp2.handshake_step_timeout";
+pub const NTCP2_MAX_RECONNECTS: &str = "transport.ntcp2.max_reconnects";
+pub const NTCP2_MAX_HANDSHAKES_PER_IP: &str = "transport.ntcp2.max_handshakes_per_ip";
+pub const NTCP2_MAX_CONCURRENT_HANDSHAKES_PER_IP: &str =
+    "transport.ntcp2.max_concurrent_handshakes_per_ip";
+pub const NTCP2_HANDSHAKE_TRACE: &str = "transport.ntcp2.handshake_trace";
+pub const NTCP2_LISTEN_V6: &str = "transport.ntcp2.listen_v6";
+pub const NTCP2_PREFER_IPV6: &str = "transport.ntcp2.prefer_ipv6";
+pub const NTCP2_SOCKS_PROXY: &str = "transport.ntcp2.socks_proxy";
+pub const NTCP2_EXTERNAL_IP: &str = "transport.ntcp2.external_ip";
+pub const NTCP2_MAX_PAST_SKEW: &str = "transport.ntcp2.max_past_skew";
+pub const NTCP2_MAX_HANDSHAKE_PADDING: &str = "transport.ntcp2.max_handshake_padding";
+pub const NTCP2_MIN_PADDING_RATIO: &str = "transport.ntcp2.min_padding_ratio";
+pub const NTCP2_MAX_PADDING_RATIO: &str = "transport.ntcp2.max_padding_ratio";
+pub const NTCP2_MAX_RECEIVED_PADDING_RATIO: &str = "transport.ntcp2.max_received_padding_ratio";
+pub const SSU_LISTEN: &str = "transport.ssu.listen";
+pub const SSU_KEYFILE: &str = "transport.ssu.keyfile";
+pub const SSU_INTRODUCER: &str = "transport.ssu.introducer";
+pub const SSU2_LISTEN: &str = "transport.ssu2.listen";
+
+/// The listen addresses of all transports, which must not collide.
+const TRANSPORT_LISTEN: [&str; 5] = [
+    NTCP_LISTEN,
+    NTCP2_LISTEN,
+    NTCP2_LISTEN_V6,
+    SSU_LISTEN,
+    SSU2_LISTEN,
+];
+
+/// The versions advertised in our RouterInfo.
+const VERSIONS: [&str; 2] = [ROUTER_VERSION, CORE_VERSION];
+
+/// Options that are a number of seconds, which may be zero.
+const DURATIONS: [&str; 5] = [
+    MAX_FUTURE_SKEW,
+    NTCP2_MAX_PAST_SKEW,
+    SHUTDOWN_TIMEOUT,
+    NETDB_EXPIRY_MIN_AGE,
+    NETDB_BOOTSTRAP_GRACE,
+];
+
+/// Options that are a number of seconds, which must be at least one.
+const TIMEOUTS: [&str; 2] = [NTCP2_HANDSHAKE_TIMEOUT, NTCP2_HANDSHAKE_STEP_TIMEOUT];
+
+/// Padding ratios, which must not be negative.
+const PADDING_RATIOS: [&str; 3] = [
+    NTCP2_MIN_PADDING_RATIO,
+    NTCP2_MAX_PADDING_RATIO,
+    NTCP2_MAX_RECEIVED_PADDING_RATIO,
+];
+
+/// Options that are a number of things, which must be at least one.
+const COUNTS: [&str; 6] = [
+    NETDB_EXPIRY_AGGRESSIVE_ROUTERS,
+    NETDB_MAX_ROUTER_ADDRESSES,
+    TRANSPORT_MAX_OUTBOUND_DIALS,
+    NTCP2_MAX_RECONNECTS,
+    NTCP2_MAX_HANDSHAKES_PER_IP,
+    NTCP2_MAX_CONCURRENT_HANDSHAKES_PER_IP,
+];
+
+const BANDWIDTHS: [&str; 4] = [
+    TRANSPORT_INBOUND_BANDWIDTH,
+    TRANSPORT_INBOUND_BURST,
+    TRANSPORT_OUTBOUND_BANDWIDTH,
+    TRANSPORT_OUTBOUND_BURST,
+];
+
+/// Config validation errors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    InvalidAddress(&'static str, String),
+    AddressConflict(&'static str, &'static str, SocketAddr),
+    InvalidVersion(&'static str, String),
+    InvalidValue(&'static str, String),
+    MustBeLess(&'static str, &'static str),
+    MustNotExceed(&'static str, &'static str),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidAddress(key, value) => {
+                write!(f, "Invalid address:port for {}: {}", key, value)
+            }
+            Error::AddressConflict(a, b, addr) => {
+                write!(f, "{} and {} both listen on {}", a, b, addr)
+            }
+            Error::InvalidVersion(key, value) => {
+                write!(f, "Invalid version for {}: {}", key, value)
+            }
+            Error::InvalidValue(key, value) => write!(f, "Invalid value for {}: {}", key, value),
+            Error::MustBeLess(a, b) => write!(f, "{} must be less than {}", a, b),
+            Error::MustNotExceed(a, b) => write!(f, "{} must not be more than {}", a, b),
+        }
+    }
+}
+
+pub trait Validate {
+    /// Checks that the configuration is consistent, before it is used to
+    /// build a router.
+    fn validate(&self) -> Result<(), Error>;
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Result<(), Error> {
+        let mut listening: Vec<(&'static str, SocketAddr)> = vec![];
+        for &key in TRANSPORT_LISTEN.iter() {
+            let value = match self.get_str(key) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let addr: SocketAddr = value
+                .parse()
+                .map_err(|_| Error::InvalidAddress(key, value.clone()))?;
+
+            // Port 0 lets the OS pick a free port, so it can't collide. IPv6
+            // listeners don't accept IPv4 connections, so a wildcard address
+            // only collides with addresses in its own family.
+            if addr.port() != 0 {
+                if let Some((other, _)) = listening.iter().find(|(_, other)| {
+                    other.port() == addr.port()
+                        && other.is_ipv4() == addr.is_ipv4()
+                        && (other.ip() == addr.ip()
+                            || other.ip().is_unspecified()
+                            || addr.ip().is_unspecified())
+                }) {
+                    return Err(Error::AddressConflict(*other, key, addr));
+                }
+            }
+
+            listening.push((key, addr));
+        }
+
+        for &key in VERSIONS.iter() {
+            if let Ok(value) = self.get_str(key) {
+                if value.parse::<Version>().is_err() {
+                    return Err(Error::InvalidVersion(key, value));
+                }
+            }
+        }
+
+        if let Ok(value) = self.get_str(TRANSPORT_UNKNOWN_MESSAGES) {
+            if value.parse::<UnknownMessagePolicy>().is_err() {
+                return Err(Error::InvalidValue(TRANSPORT_UNKNOWN_MESSAGES, value));
+            }
+        }
+
+        if let Ok(value) = self.get_str(NTCP2_CIPHER_SUITE) {
+            if value.parse::<ntcp2::CipherSuite>().is_err() {
+                return Err(Error::InvalidValue(NTCP2_CIPHER_SUITE, value));
+            }
+        }
+
+        if let Ok(value) = self.get_str(NTCP2_SOCKS_PROXY) {
+            if value.parse::<SocketAddr>().is_err() {
+                return Err(Error::InvalidAddress(NTCP2_SOCKS_PROXY, value));
+            }
+        }
+
+        if let Ok(value) = self.get_str(NTCP2_EXTERNAL_IP) {
+            if value.parse::<IpAddr>().is_err() {
+                return Err(Error::InvalidValue(NTCP2_EXTERNAL_IP, value));
+            }
+        }
+
+        PeerFilter::from_config(self)?;
+
+        for &key in DURATIONS.iter() {
+            int_at_least(self, key, 0)?;
+        }
+        for &key in TIMEOUTS.iter() {
+            int_at_least(self, key, 1)?;
+        }
+        for &key in COUNTS.iter() {
+            int_at_least(self, key, 1)?;
+        }
+        for &key in BANDWIDTHS.iter() {
+            int_at_least(self, key, 1)?;
+        }
+        if let Some(share) = int_at_least(self, TUNNEL_PARTICIPATING_SHARE, 1)? {
+            if share > 100 {
+                return Err(Error::InvalidValue(TUNNEL_PARTICIPATING_SHARE, share.to_string()));
+            }
+        }
+
+        // Handshake padding has to fit in the smallest handshake message, or
+        // peers will reject our SessionRequests.
+        if let Some(padding) = int_at_least(self, NTCP2_MAX_HANDSHAKE_PADDING, 0)? {
+            if padding as usize > ntcp2::SESSION_REQUEST_MAX_PADDING {
+                return Err(Error::InvalidValue(
+                    NTCP2_MAX_HANDSHAKE_PADDING,
+                    padding.to_string(),
+                ));
+            }
+        }
+        for &key in PADDING_RATIOS.iter() {
+            float_at_least(self, key, 0.0)?;
+        }
+        let default_padding = ntcp2::PaddingConfig::default();
+        let min_ratio = self
+            .get_float(NTCP2_MIN_PADDING_RATIO)
+            .unwrap_or(default_padding.min_ratio);
+        let max_ratio = self
+            .get_float(NTCP2_MAX_PADDING_RATIO)
+            .unwrap_or(default_padding.max_ratio);
+        if min_ratio > max_ratio {
+            return Err(Error::MustNotExceed(
+                NTCP2_MIN_PADDING_RATIO,
+                NTCP2_MAX_PADDING_RATIO,
+            ));
+        }
+
+        // Each step of a handshake has to fit within the whole handshake.
+        let handshake_timeout = match int_at_least(self, NTCP2_HANDSHAKE_TIMEOUT, 1)? {
+            Some(secs) => Some(secs as u64),
+            None => ntcp2::HandshakeConfig::default().timeout.map(|t| t.as_secs()),
+        };
+        if let (Some(step_timeout), Some(handshake_timeout)) = (
+            int_at_least(self, NTCP2_HANDSHAKE_STEP_TIMEOUT, 1)?,
+            handshake_timeout,
+        ) {
+            if step_timeout as u64 > handshake_timeout {
+                return Err(Error::MustNotExceed(
+                    NTCP2_HANDSHAKE_STEP_TIMEOUT,
+                    NTCP2_HANDSHAKE_TIMEOUT,
+                ));
+            }
+        }
+
+        // A keepalive is only useful if it is sent before the session times
+        // out. An idle timeout of 0 disables it.
+        let idle_timeout = match int_at_least(self, NTCP2_IDLE_TIMEOUT, 0)? {
+            Some(0) => None,
+            Some(secs) => Some(secs as u64),
+            None => ntcp2::IdleConfig::default().timeout.map(|t| t.as_secs()),
+        };
+        if let (Some(keepalive), Some(idle_timeout)) =
+            (int_at_least(self, NTCP2_KEEPALIVE, 1)?, idle_timeout)
+        {
+            if keepalive as u64 >= idle_timeout {
+                return Err(Error::MustBeLess(NTCP2_KEEPALIVE, NTCP2_IDLE_TIMEOUT));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the value of an integer option if it is set, or an error if it is
+/// not an integer or is less than `min`.
+fn int_at_least(config: &Config, key: &'static str, min: i64) -> Result<Option<i64>, Error> {
+    match config.get_int(key) {
+        Ok(value) if value >= min => Ok(Some(value)),
+        Ok(value) => Err(Error::InvalidValue(key, value.to_string())),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(_) => Err(Error::InvalidValue(key, config.get_str(key).unwrap_or_default())),
+    }
+}
+
+/// Returns the value of a numeric option if it is set, or an error if it is
+/// not a number or is less than `min`.
+fn float_at_least(config: &Config, key: &'static str, min: f64) -> Result<Option<f64>, Error> {
+    match config.get_float(key) {
+        Ok(value) if value >= min => Ok(Some(value)),
+        Ok(value) => Err(Error::InvalidValue(key, value.to_string())),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(_) => Err(Error::InvalidValue(key, config.get_str(key).unwrap_or_default())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_transport_addresses() {
+        let mut config = Config::default();
+        config.set(NTCP_LISTEN, "127.0.0.1:12345").unwrap();
+        config.set(NTCP2_LISTEN, "127.0.0.1:12346").unwrap();
+        config.set(SSU2_LISTEN, "127.0.0.1:12347").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        // Out-of-range port
+        config.set(SSU2_LISTEN, "127.0.0.1:65536").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidAddress(SSU2_LISTEN, "127.0.0.1:65536".to_string()))
+        );
+
+        // Colliding ports
+        config.set(SSU2_LISTEN, "127.0.0.1:12346").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::AddressConflict(
+                NTCP2_LISTEN,
+                SSU2_LISTEN,
+                "127.0.0.1:12346".parse().unwrap()
+            ))
+        );
+
+        // Wildcard addresses collide with everything
+        config.set(SSU2_LISTEN, "0.0.0.0:12346").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::AddressConflict(
+                NTCP2_LISTEN,
+                SSU2_LISTEN,
+                "0.0.0.0:12346".parse().unwrap()
+            ))
+        );
+
+        // Same port on different addresses is fine
+        config.set(SSU2_LISTEN, "127.0.0.2:12346").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        // Including wildcard addresses in different families
+        config.set(NTCP2_LISTEN, "0.0.0.0:12346").unwrap();
+        config.set(NTCP2_LISTEN_V6, "[::]:12346").unwrap();
+        config.set(SSU2_LISTEN, "127.0.0.1:12347").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_versions() {
+        let mut config = Config::default();
+        config.set(ROUTER_VERSION, "0.9.50").unwrap();
+        config.set(CORE_VERSION, "0.9.50").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(CORE_VERSION, "latest").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidVersion(CORE_VERSION, "latest".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_named_values() {
+        let mut config = Config::default();
+        config.set(TRANSPORT_UNKNOWN_MESSAGES, "disconnect").unwrap();
+        config.set(NTCP2_CIPHER_SUITE, "AESGCM_SHA256").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(TRANSPORT_UNKNOWN_MESSAGES, "explode").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(TRANSPORT_UNKNOWN_MESSAGES, "explode".to_string()))
+        );
+
+        config.set(TRANSPORT_UNKNOWN_MESSAGES, "log").unwrap();
+        config.set(NTCP2_CIPHER_SUITE, "ROT13").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_CIPHER_SUITE, "ROT13".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_socks_proxy() {
+        let mut config = Config::default();
+        config.set(NTCP2_SOCKS_PROXY, "127.0.0.1:9050").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(NTCP2_SOCKS_PROXY, "localhost").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidAddress(NTCP2_SOCKS_PROXY, "localhost".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_external_ip() {
+        let mut config = Config::default();
+        config.set(NTCP2_EXTERNAL_IP, "203.0.113.1").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.set(NTCP2_EXTERNAL_IP, "2001:db8::1").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(NTCP2_EXTERNAL_IP, "203.0.113.1:12345").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_EXTERNAL_IP, "203.0.113.1:12345".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_peer_lists() {
+        let mut config = Config::default();
+        config
+            .set(TRANSPORT_BLOCKLIST, vec![crate::data::Hash([1; 32]).to_string()])
+            .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(TRANSPORT_ALLOWLIST, "everyone").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(TRANSPORT_ALLOWLIST, "everyone".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_bandwidth() {
+        let mut config = Config::default();
+        config.set(TRANSPORT_INBOUND_BANDWIDTH, 100).unwrap();
+        config.set(TRANSPORT_INBOUND_BURST, 50).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(TRANSPORT_OUTBOUND_BANDWIDTH, -1).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(TRANSPORT_OUTBOUND_BANDWIDTH, "-1".to_string()))
+        );
+
+        config.set(TRANSPORT_OUTBOUND_BANDWIDTH, 100).unwrap();
+        config.set(TRANSPORT_INBOUND_BURST, 0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(TRANSPORT_INBOUND_BURST, "0".to_string()))
+        );
+
+        // The participating share is a percentage
+        config.set(TRANSPORT_INBOUND_BURST, 50).unwrap();
+        config.set(TUNNEL_PARTICIPATING_SHARE, 100).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.set(TUNNEL_PARTICIPATING_SHARE, 101).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(TUNNEL_PARTICIPATING_SHARE, "101".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_durations() {
+        let mut config = Config::default();
+        config.set(SHUTDOWN_TIMEOUT, 0).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(SHUTDOWN_TIMEOUT, -1).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(SHUTDOWN_TIMEOUT, "-1".to_string()))
+        );
+
+        config.set(SHUTDOWN_TIMEOUT, 30).unwrap();
+        config.set(NETDB_EXPIRY_MIN_AGE, -60).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NETDB_EXPIRY_MIN_AGE, "-60".to_string()))
+        );
+
+        config.set(NETDB_EXPIRY_MIN_AGE, 60).unwrap();
+        config.set(MAX_FUTURE_SKEW, -1).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(MAX_FUTURE_SKEW, "-1".to_string()))
+        );
+
+        config.set(MAX_FUTURE_SKEW, 60).unwrap();
+        config.set(NTCP2_MAX_PAST_SKEW, -60).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_MAX_PAST_SKEW, "-60".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_timeouts() {
+        let mut config = Config::default();
+        config.set(NTCP2_HANDSHAKE_TIMEOUT, 1).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(NTCP2_HANDSHAKE_TIMEOUT, 0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_HANDSHAKE_TIMEOUT, "0".to_string()))
+        );
+
+        config.set(NTCP2_HANDSHAKE_TIMEOUT, -10).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_HANDSHAKE_TIMEOUT, "-10".to_string()))
+        );
+
+        config.set(NTCP2_HANDSHAKE_TIMEOUT, 10).unwrap();
+        config.set(NTCP2_HANDSHAKE_STEP_TIMEOUT, 0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_HANDSHAKE_STEP_TIMEOUT, "0".to_string()))
+        );
+        config.set(NTCP2_HANDSHAKE_STEP_TIMEOUT, -1).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_HANDSHAKE_STEP_TIMEOUT, "-1".to_string()))
+        );
+
+        // A step can take as long as the whole handshake, but no longer
+        config.set(NTCP2_HANDSHAKE_STEP_TIMEOUT, 10).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.set(NTCP2_HANDSHAKE_STEP_TIMEOUT, 11).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::MustNotExceed(NTCP2_HANDSHAKE_STEP_TIMEOUT, NTCP2_HANDSHAKE_TIMEOUT))
+        );
+    }
+
+    #[test]
+    fn validate_padding() {
+        let mut config = Config::default();
+        config.set(NTCP2_MAX_HANDSHAKE_PADDING, 64).unwrap();
+        config.set(NTCP2_MIN_PADDING_RATIO, 0.5).unwrap();
+        config.set(NTCP2_MAX_PADDING_RATIO, 1.5).unwrap();
+        config.set(NTCP2_MAX_RECEIVED_PADDING_RATIO, 4.0).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(NTCP2_MAX_HANDSHAKE_PADDING, -1).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_MAX_HANDSHAKE_PADDING, "-1".to_string()))
+        );
+
+        // Padding must fit in a SessionRequest
+        let max = ntcp2::SESSION_REQUEST_MAX_PADDING as i64;
+        config.set(NTCP2_MAX_HANDSHAKE_PADDING, max).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.set(NTCP2_MAX_HANDSHAKE_PADDING, max + 1).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(
+                NTCP2_MAX_HANDSHAKE_PADDING,
+                (max + 1).to_string()
+            ))
+        );
+
+        config.set(NTCP2_MAX_HANDSHAKE_PADDING, 64).unwrap();
+        config.set(NTCP2_MIN_PADDING_RATIO, -0.5).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_MIN_PADDING_RATIO, "-0.5".to_string()))
+        );
+
+        config.set(NTCP2_MIN_PADDING_RATIO, 0.5).unwrap();
+        config.set(NTCP2_MAX_PADDING_RATIO, -1.5).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_MAX_PADDING_RATIO, "-1.5".to_string()))
+        );
+
+        config.set(NTCP2_MAX_PADDING_RATIO, 1.5).unwrap();
+        config.set(NTCP2_MAX_RECEIVED_PADDING_RATIO, -4.0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_MAX_RECEIVED_PADDING_RATIO, "-4".to_string()))
+        );
+
+        // The range of ratios we send must not be empty
+        config.set(NTCP2_MAX_RECEIVED_PADDING_RATIO, 4.0).unwrap();
+        config.set(NTCP2_MIN_PADDING_RATIO, 2.0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::MustNotExceed(NTCP2_MIN_PADDING_RATIO, NTCP2_MAX_PADDING_RATIO))
+        );
+
+        // including with the default maximum
+        let mut config = Config::default();
+        config.set(NTCP2_MIN_PADDING_RATIO, 1.0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::MustNotExceed(NTCP2_MIN_PADDING_RATIO, NTCP2_MAX_PADDING_RATIO))
+        );
+    }
+
+    #[test]
+    fn validate_counts() {
+        let mut config = Config::default();
+        config.set(TRANSPORT_MAX_OUTBOUND_DIALS, 1).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(TRANSPORT_MAX_OUTBOUND_DIALS, 0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(TRANSPORT_MAX_OUTBOUND_DIALS, "0".to_string()))
+        );
+
+        config.set(TRANSPORT_MAX_OUTBOUND_DIALS, 1).unwrap();
+        config.set(NETDB_EXPIRY_AGGRESSIVE_ROUTERS, 0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NETDB_EXPIRY_AGGRESSIVE_ROUTERS, "0".to_string()))
+        );
+
+        config.set(NETDB_EXPIRY_AGGRESSIVE_ROUTERS, 1000).unwrap();
+        config.set(NTCP2_MAX_RECONNECTS, -5).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_MAX_RECONNECTS, "-5".to_string()))
+        );
+
+        config.set(NTCP2_MAX_RECONNECTS, 10).unwrap();
+        config.set(NTCP2_MAX_CONCURRENT_HANDSHAKES_PER_IP, 0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_MAX_CONCURRENT_HANDSHAKES_PER_IP, "0".to_string()))
+        );
+    }
+
+    #[test]
+    fn validate_ntcp2_idle() {
+        let mut config = Config::default();
+        config.set(NTCP2_KEEPALIVE, 60).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        // The keepalive must be shorter than the default idle timeout
+        config.set(NTCP2_KEEPALIVE, 300).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::MustBeLess(NTCP2_KEEPALIVE, NTCP2_IDLE_TIMEOUT))
+        );
+
+        // or the configured one
+        config.set(NTCP2_IDLE_TIMEOUT, 600).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.set(NTCP2_IDLE_TIMEOUT, 120).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::MustBeLess(NTCP2_KEEPALIVE, NTCP2_IDLE_TIMEOUT))
+        );
+
+        // unless idle sessions are never closed
+        config.set(NTCP2_IDLE_TIMEOUT, 0).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set(NTCP2_IDLE_TIMEOUT, -1).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_IDLE_TIMEOUT, "-1".to_string()))
+        );
+        config.set(NTCP2_IDLE_TIMEOUT, "never").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_IDLE_TIMEOUT, "never".to_string()))
+        );
+
+        config.set(NTCP2_IDLE_TIMEOUT, 0).unwrap();
+        config.set(NTCP2_KEEPALIVE, 0).unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(Error::InvalidValue(NTCP2_KEEPALIVE, "0".to_string()))
+        );
+    }
+}
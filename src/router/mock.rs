@@ -5,17 +5,24 @@
 
 use config::Config;
 use futures::{future, sync::mpsc, Future};
+use rand::{rngs::OsRng, Rng};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, Mutex, RwLock};
 use tokio::io;
 
-use super::types::{CommSystem, Distributor, DistributorResult};
-use crate::data::{Hash, RouterAddress, RouterInfo, RouterSecretKeys};
+use super::types::{CommSystem, Distributor, DistributorResult, OutboundTunnelPool, PeerSelector};
+use crate::data::{Hash, I2PString, RouterAddress, RouterInfo, RouterSecretKeys};
 use crate::i2np::Message;
 use crate::netdb::{client::Client as NetDbClient, mock::MockNetDb};
-use crate::router::Context;
+use crate::router::{Context, DeliveryStatuses, TierWeightedSelector};
+use crate::transport::{ntcp2::NTCP2_STYLE, Direction, TrafficSummary};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
+/// The port of the NTCP2 address published by [`MockCommSystem`].
+pub const MOCK_PORT: u16 = 12345;
+
 #[derive(Clone)]
 pub struct MockDistributor {
     pub received: Arc<Mutex<Vec<(Hash, Message)>>>,
@@ -36,58 +43,196 @@ impl Distributor for MockDistributor {
     }
 }
 
-pub(super) struct MockCommSystem;
+pub(super) struct MockCommSystem {
+    sent: Arc<Mutex<Vec<(Hash, Message)>>>,
+    pub(super) sessions: Vec<(Hash, Direction)>,
+    unreachable: HashSet<Hash>,
+    unresponsive: bool,
+    external_ip: Option<IpAddr>,
+    ntcp2_key: Mutex<[u8; 32]>,
+}
 
 impl MockCommSystem {
     pub(super) fn new() -> Self {
-        MockCommSystem {}
+        MockCommSystem {
+            sent: Arc::new(Mutex::new(vec![])),
+            sessions: vec![],
+            unreachable: HashSet::new(),
+            unresponsive: false,
+            external_ip: None,
+            ntcp2_key: Mutex::new([0; 32]),
+        }
     }
 }
 
 impl CommSystem for MockCommSystem {
     fn addresses(&self) -> Vec<RouterAddress> {
-        vec![]
+        let ip = self
+            .external_ip
+            .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut ra = RouterAddress::new(&NTCP2_STYLE, SocketAddr::new(ip, MOCK_PORT));
+        ra.set_ntcp2(&self.ntcp2_key.lock().unwrap()[..], &[0; 16], &["2"]);
+        vec![ra]
     }
 
     fn start(&mut self, _ctx: Arc<Context>) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         Box::new(future::ok(()))
     }
 
-    fn is_established(&self, _hash: &Hash) -> bool {
-        false
+    fn is_established(&self, hash: &Hash) -> bool {
+        self.sessions.iter().any(|(peer, _)| peer == hash)
+    }
+
+    fn sessions(&self) -> Vec<(Hash, Direction)> {
+        self.sessions.clone()
+    }
+
+    fn rotate_keys(&self, style: &I2PString) -> io::Result<()> {
+        if *style != *NTCP2_STYLE {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No {} transport", style.0),
+            ));
+        }
+        OsRng.fill(&mut *self.ntcp2_key.lock().unwrap());
+        Ok(())
+    }
+
+    fn set_external_ip(&mut self, ip: IpAddr) {
+        self.external_ip = Some(ip);
+    }
+
+    fn traffic(&self) -> TrafficSummary {
+        TrafficSummary::default()
+    }
+
+    fn stop(&mut self) {}
+
+    fn shutdown(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if self.unresponsive {
+            // Peers never acknowledge that their sessions are closed
+            Box::new(future::empty())
+        } else {
+            Box::new(future::ok(()))
+        }
     }
 
     fn send(
         &self,
-        _peer: RouterInfo,
-        _msg: Message,
+        peer: RouterInfo,
+        msg: Message,
     ) -> Result<IoFuture<()>, (RouterInfo, Message)> {
+        let hash = peer.router_id.hash();
+        if self.unreachable.contains(&hash) {
+            return Err((peer, msg));
+        }
+        self.sent.lock().unwrap().push((hash, msg));
         Ok(Box::new(future::ok(())))
     }
 }
 
-pub fn mock_context() -> Arc<Context> {
-    let (tx, _) = mpsc::unbounded();
-    mock_context_with_netdb(NetDbClient::new(tx))
+/// Always selects the same peers, regardless of the candidates.
+pub struct MockPeerSelector(pub Vec<RouterInfo>);
+
+impl PeerSelector for MockPeerSelector {
+    fn select_peers(&self, _candidates: &[RouterInfo], count: usize) -> Vec<RouterInfo> {
+        self.0.iter().take(count).cloned().collect()
+    }
 }
 
-pub fn mock_context_and_netdb() -> (Arc<Context>, MockNetDb) {
-    let (client_tx, client_rx) = mpsc::unbounded();
-    let ctx = mock_context_with_netdb(NetDbClient::new(client_tx));
-    let netdb = MockNetDb::new(ctx.clone(), client_rx);
-    (ctx, netdb)
+/// The messages sent by a [`MockCommSystem`], by recipient.
+pub type SentMessages = Arc<Mutex<Vec<(Hash, Message)>>>;
+
+/// Builds a [`Context`] with a [`MockCommSystem`], for tests.
+pub struct MockContextBuilder {
+    config: Config,
+    comms: MockCommSystem,
+    ob_tunnels: Option<Arc<dyn OutboundTunnelPool>>,
+    peer_selector: Box<dyn PeerSelector>,
 }
 
-fn mock_context_with_netdb(netdb: NetDbClient) -> Arc<Context> {
-    let keys = RouterSecretKeys::new();
-    let mut ri = RouterInfo::new(keys.rid.clone());
-    ri.sign(&keys.signing_private_key);
-
-    Arc::new(Context {
-        config: RwLock::new(Config::default()),
-        keys,
-        ri: Arc::new(RwLock::new(ri)),
-        netdb,
-        comms: Arc::new(RwLock::new(MockCommSystem::new())),
-    })
+impl MockContextBuilder {
+    pub fn new() -> Self {
+        MockContextBuilder {
+            config: Config::default(),
+            comms: MockCommSystem::new(),
+            ob_tunnels: None,
+            peer_selector: Box::new(TierWeightedSelector),
+        }
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn ob_tunnels(mut self, ob_tunnels: Arc<dyn OutboundTunnelPool>) -> Self {
+        self.ob_tunnels = Some(ob_tunnels);
+        self
+    }
+
+    pub fn peer_selector(mut self, peer_selector: MockPeerSelector) -> Self {
+        self.peer_selector = Box::new(peer_selector);
+        self
+    }
+
+    /// The comm system can't send messages to these peers.
+    pub fn unreachable_peers(mut self, unreachable: HashSet<Hash>) -> Self {
+        self.comms.unreachable = unreachable;
+        self
+    }
+
+    /// The comm system never finishes closing its sessions.
+    pub fn unresponsive_peers(mut self) -> Self {
+        self.comms.unresponsive = true;
+        self
+    }
+
+    /// The comm system reports these sessions as open.
+    pub(super) fn sessions(mut self, sessions: Vec<(Hash, Direction)>) -> Self {
+        self.comms.sessions = sessions;
+        self
+    }
+
+    /// Returns the context, along with the messages its comm system sends.
+    pub fn build(self) -> (Arc<Context>, SentMessages) {
+        let (tx, _) = mpsc::unbounded();
+        self.build_with(NetDbClient::new(tx))
+    }
+
+    /// Returns the context, along with a network database that answers its
+    /// queries.
+    pub fn build_with_netdb(self) -> (Arc<Context>, MockNetDb) {
+        let (client_tx, client_rx) = mpsc::unbounded();
+        let (ctx, _) = self.build_with(NetDbClient::new(client_tx));
+        let netdb = MockNetDb::new(ctx.clone(), client_rx);
+        (ctx, netdb)
+    }
+
+    fn build_with(self, netdb: NetDbClient) -> (Arc<Context>, SentMessages) {
+        let keys = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(keys.rid.clone());
+        ri.sign(&keys.signing_private_key);
+
+        let sent = self.comms.sent.clone();
+        let ctx = Arc::new(Context {
+            config: RwLock::new(self.config),
+            keys,
+            ri: Arc::new(RwLock::new(ri)),
+            netdb,
+            comms: Arc::new(RwLock::new(self.comms)),
+            ob_tunnels: self.ob_tunnels,
+            peer_selector: self.peer_selector,
+            delivery_statuses: DeliveryStatuses::default(),
+        });
+        (ctx, sent)
+    }
+}
+
+pub fn mock_context() -> Arc<Context> {
+    MockContextBuilder::new().build().0
+}
+
+pub fn mock_context_and_netdb() -> (Arc<Context>, MockNetDb) {
+    MockContextBuilder::new().build_with_netdb()
 }
@@ -1,14 +1,20 @@
 use futures::{
-    future::{self, lazy},
-    sync::mpsc,
+    future::{self, lazy, Shared},
+    sync::{mpsc, oneshot},
     Future, Sink,
 };
-use std::sync::{Arc, RwLock};
-use tokio::{io, spawn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::{io, spawn, timer::Timeout};
 
-use crate::data::{Hash, RouterInfo, RouterSecretKeys};
-use crate::i2np::{Message, MessagePayload};
+use crate::crypto::elgamal;
+use crate::data::{Hash, I2PDate, LeaseSet, RouterAddress, RouterInfo, RouterSecretKeys};
+use crate::i2np::{Message, MessagePayload, SessionTags};
 use crate::netdb;
+use crate::transport::{ntcp2::NTCP2_STYLE, Throughput, TrafficSummary};
 use crate::tunnel;
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
@@ -16,18 +22,96 @@ type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 mod builder;
 pub mod config;
 pub mod mock;
+pub mod replay;
+mod selector;
 pub mod types;
 
 pub use self::builder::Builder;
+pub use self::selector::TierWeightedSelector;
 use self::config::Config;
 
 pub(crate) type DistributorTx = mpsc::Sender<(Hash, Message)>;
 
+/// How long we wait for sessions to close when shutting down, if the config
+/// doesn't say.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of exploratory outbound tunnels the router keeps, when it
+/// isn't given a tunnel pool to use.
+const EXPLORATORY_OUTBOUND_TUNNELS: usize = 2;
+
+/// How long we wait for the LeaseSet of a Destination to be found.
+const DESTINATION_LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we wait for a DeliveryStatus message, and then remember it for.
+const DELIVERY_STATUS_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// The most DeliveryStatus messages that we wait for at once.
+const MAX_DELIVERY_STATUSES: usize = 1_000;
+
+/// The DeliveryStatus messages we are waiting for, and those that have arrived.
+///
+/// The sender of a message can wrap a DeliveryStatus with it, to be returned
+/// by the recipient. Looking up the message ID here then tells the sender
+/// whether the message arrived, and lets it measure the round-trip time.
+/// DeliveryStatus messages that nobody is waiting for are dropped.
+#[derive(Clone, Default)]
+pub struct DeliveryStatuses(Arc<Mutex<HashMap<u32, (Instant, Option<(I2PDate, Instant)>)>>>);
+
+impl DeliveryStatuses {
+    /// Starts waiting for the DeliveryStatus with the given message ID.
+    pub fn expect(&self, msg_id: u32) {
+        let mut statuses = self.0.lock().unwrap();
+        if statuses.len() >= MAX_DELIVERY_STATUSES {
+            statuses.retain(|_, (expected, _)| expected.elapsed() < DELIVERY_STATUS_LIFETIME);
+        }
+        if statuses.len() >= MAX_DELIVERY_STATUSES {
+            debug!(
+                "Too many pending DeliveryStatus messages, not waiting for {}",
+                msg_id
+            );
+            return;
+        }
+        statuses.insert(msg_id, (Instant::now(), None));
+    }
+
+    /// Records a received DeliveryStatus. Returns false if we weren't waiting
+    /// for it.
+    pub(crate) fn record(&self, msg_id: u32, time_stamp: I2PDate) -> bool {
+        match self.0.lock().unwrap().get_mut(&msg_id) {
+            Some((_, status @ None)) => {
+                *status = Some((time_stamp, Instant::now()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes and returns the DeliveryStatus for the given message ID, as the
+    /// timestamp it carries and the time at which we received it. Returns None,
+    /// and keeps waiting, if it hasn't arrived yet.
+    pub fn take(&self, msg_id: u32) -> Option<(I2PDate, Instant)> {
+        let mut statuses = self.0.lock().unwrap();
+        let received = statuses.get(&msg_id).and_then(|(_, status)| *status)?;
+        statuses.remove(&msg_id);
+        Some(received)
+    }
+
+    /// Stops waiting for the DeliveryStatus with the given message ID.
+    pub fn forget(&self, msg_id: u32) {
+        self.0.lock().unwrap().remove(&msg_id);
+    }
+}
+
 #[derive(Clone)]
 struct Distributor {
     netdb: DistributorTx,
     tunnel_acceptor: DistributorTx,
     tunnel_processor: DistributorTx,
+    delivery_statuses: DeliveryStatuses,
+    /// Decrypts the Garlic messages sent to us.
+    decryptor: elgamal::Decryptor,
+    session_tags: SessionTags,
 }
 
 impl Distributor {
@@ -35,11 +119,16 @@ impl Distributor {
         netdb: DistributorTx,
         tunnel_acceptor: DistributorTx,
         tunnel_processor: DistributorTx,
+        delivery_statuses: DeliveryStatuses,
+        decryptor: elgamal::Decryptor,
     ) -> Self {
         Distributor {
             netdb,
             tunnel_acceptor,
             tunnel_processor,
+            delivery_statuses,
+            decryptor,
+            session_tags: SessionTags::default(),
         }
     }
 }
@@ -64,6 +153,41 @@ impl types::Distributor for Distributor {
                     Box::new(self.tunnel_acceptor.clone().send((from, msg)).map(|_| ()));
                 f
             }
+            MessagePayload::DeliveryStatus(ds) => {
+                if self.delivery_statuses.record(ds.msg_id, ds.time_stamp) {
+                    debug!("Received DeliveryStatus for {} from {}", ds.msg_id, from);
+                } else {
+                    debug!(
+                        "Dropping unexpected DeliveryStatus {} from {}",
+                        ds.msg_id, from
+                    );
+                }
+                let f: types::DistributorResult = Box::new(future::ok(()));
+                f
+            }
+            MessagePayload::Garlic(garlic) => {
+                let garlic = match garlic.decrypt(&self.decryptor, &self.session_tags) {
+                    Ok(garlic) => garlic,
+                    Err(e) => {
+                        debug!("Dropping Garlic message from {}: {:?}", from, e);
+                        let f: types::DistributorResult = Box::new(future::ok(()));
+                        return f;
+                    }
+                };
+
+                // Handle each clove meant for us as if we received it directly
+                let mut handled = vec![];
+                for clove in garlic.into_cloves() {
+                    if clove.is_local() {
+                        handled.push(self.handle(from.clone(), clove.into_message()));
+                    } else {
+                        debug!("Dropping garlic clove from {} for another recipient", from);
+                    }
+                }
+                let f: types::DistributorResult =
+                    Box::new(future::join_all(handled).map(|_| ()));
+                f
+            }
             _ => {
                 debug!("Dropping unhandled message from {}:\n{}", from, msg);
                 let f: types::DistributorResult = Box::new(future::ok(()));
@@ -79,6 +203,9 @@ pub struct Router {
     netdb_engine: Option<netdb::Engine>,
     tunnel_listener: Option<tunnel::Listener>,
     tunnel_participant: Option<tunnel::Participant>,
+    exploratory_tunnels: Option<Arc<tunnel::TunnelPool>>,
+    shutdown: ShutdownHandle,
+    shutdown_rx: Option<oneshot::Receiver<()>>,
 }
 
 pub struct Context {
@@ -87,9 +214,104 @@ pub struct Context {
     pub ri: Arc<RwLock<RouterInfo>>,
     pub netdb: netdb::client::Client,
     pub comms: Arc<RwLock<dyn types::CommSystem>>,
+    pub ob_tunnels: Option<Arc<dyn types::OutboundTunnelPool>>,
+    pub peer_selector: Box<dyn types::PeerSelector>,
+    pub delivery_statuses: DeliveryStatuses,
+}
+
+impl Context {
+    /// Returns a copy of the RouterInfo that the router most recently signed
+    /// and published.
+    pub fn published_router_info(&self) -> RouterInfo {
+        self.ri.read().unwrap().clone()
+    }
+
+    /// Replaces the addresses in our RouterInfo, signs it, and has the network
+    /// database publish it.
+    pub(crate) fn update_router_info(&self, addresses: Vec<RouterAddress>) -> io::Result<()> {
+        let ri = {
+            let mut ri = self.ri.write().unwrap();
+            ri.set_addresses(addresses);
+            ri.published = I2PDate::now();
+            ri.sign(&self.keys.signing_private_key);
+            ri.clone()
+        };
+        if let Ok(ri_file) = self.config.read().unwrap().get_str(config::RI_FILE) {
+            ri.to_file(&ri_file)?;
+        }
+        if self.netdb.publish_router_info().is_err() {
+            debug!("Network database isn't running, RouterInfo will be published later");
+        }
+        Ok(())
+    }
+
+    /// Returns a future that resolves once the network database knows at least
+    /// `n` peers.
+    pub fn wait_for_peers(&self, n: usize) -> netdb::client::WaitForPeers {
+        self.netdb.wait_for_peers(n)
+    }
+}
+
+/// Runs a subsystem until it ends, or until the router has shut down.
+fn until_stopped<F>(
+    subsystem: F,
+    stopped: Shared<oneshot::Receiver<()>>,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    subsystem.select2(stopped).then(|_| Ok(()))
+}
+
+/// Stops accepting connections, releases our outbound tunnels, and closes our
+/// sessions with peers.
+fn stop(ctx: &Context) -> impl Future<Item = (), Error = ()> {
+    info!("Shutting down router");
+    ctx.comms.write().unwrap().stop();
+    if let Some(ob_tunnels) = &ctx.ob_tunnels {
+        ob_tunnels.shutdown();
+    }
+
+    // Checked by config::Validate
+    let timeout = ctx
+        .config
+        .read()
+        .unwrap()
+        .get_int(config::SHUTDOWN_TIMEOUT)
+        .map(|secs| Duration::from_secs(secs as u64))
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+    let sessions = ctx.comms.write().unwrap().shutdown();
+
+    Timeout::new(sessions, timeout).or_else(move |e| {
+        if e.is_elapsed() {
+            warn!("Sessions did not close within {:?}, dropping them", timeout);
+        } else {
+            error!("Error while closing sessions: {:?}", e);
+        }
+        Ok(())
+    })
 }
 
 impl Router {
+    fn new(
+        ctx: Arc<Context>,
+        netdb_engine: Option<netdb::Engine>,
+        tunnel_listener: Option<tunnel::Listener>,
+        tunnel_participant: Option<tunnel::Participant>,
+        exploratory_tunnels: Option<Arc<tunnel::TunnelPool>>,
+    ) -> Self {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        Router {
+            ctx,
+            netdb_engine,
+            tunnel_listener,
+            tunnel_participant,
+            exploratory_tunnels,
+            shutdown: ShutdownHandle(Arc::new(Mutex::new(Some(shutdown_tx)))),
+            shutdown_rx: Some(shutdown_rx),
+        }
+    }
+
     /// Returns a handle that can be used to interact with the router.
     pub fn handle(&self) -> Handle {
         Handle {
@@ -97,17 +319,29 @@ impl Router {
         }
     }
 
+    /// Returns a handle that can be used to shut down the running router.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
     /// Start the router.
     ///
     /// This returns a Future that must be polled in order to drive the Router.
+    /// It resolves once the router has been shut down with a [`ShutdownHandle`],
+    /// or once the router and all of its shutdown handles have been dropped.
+    /// Shutting down stops the router's subsystems, but leaves the runtime
+    /// running.
     pub fn start(&mut self) -> impl Future<Item = (), Error = ()> {
         info!("Our router hash is {}", self.ctx.keys.rid.hash());
 
         let comms_engine = self.ctx.comms.write().unwrap().start(self.ctx.clone());
-        let netdb_engine = self
+        let mut netdb_engine = self
             .netdb_engine
             .take()
             .expect("Can only call start() once");
+        if let Ok(dir) = self.ctx.config.read().unwrap().get_str(config::NETDB_DIR) {
+            netdb_engine.load_from_dir(Path::new(&dir));
+        }
 
         let tunnel_listener = self
             .tunnel_listener
@@ -119,21 +353,189 @@ impl Router {
             .take()
             .expect("Can only call start() once");
 
-        lazy(|| {
+        let exploratory_tunnels = self
+            .exploratory_tunnels
+            .take()
+            .map(|pool| (pool, self.ctx.clone()));
+
+        let shutdown_rx = self
+            .shutdown_rx
+            .take()
+            .expect("Can only call start() once");
+        let (stop_subsystems, stopped) = oneshot::channel();
+        let stopped = stopped.shared();
+        let ctx = self.ctx.clone();
+
+        lazy(move || {
             // Start the transport system
             spawn(comms_engine);
 
             // Start the TunnelBuildRequest listener subsystem
-            spawn(tunnel_listener);
+            spawn(until_stopped(tunnel_listener, stopped.clone()));
 
             // Start the tunnel participant subsystem
-            spawn(tunnel_participant);
+            spawn(until_stopped(tunnel_participant, stopped.clone()));
+
+            // Test our exploratory tunnels. This stops by itself once the pool
+            // is shut down.
+            if let Some((pool, ctx)) = exploratory_tunnels {
+                spawn(pool.run_tests(ctx));
+            }
 
             // Start network database operations
-            spawn(netdb_engine);
+            spawn(until_stopped(netdb_engine, stopped));
 
             Ok(())
         })
+        .and_then(|()| shutdown_rx.then(|_| Ok(())))
+        .and_then(move |()| stop(&ctx))
+        .map(move |()| {
+            // Only stop the subsystems once sessions have closed, so that
+            // messages received in the meantime are still handled.
+            let _ = stop_subsystems.send(());
+        })
+    }
+
+    /// Shut down the router.
+    ///
+    /// We stop accepting new connections. Outbound tunnels are released
+    /// immediately rather than being left to expire, and no new tunnels are
+    /// built. Sessions with peers are then closed gracefully.
+    ///
+    /// The returned future resolves once all sessions have closed, or when the
+    /// shutdown timeout passes. Any connections still open at that point are
+    /// dropped along with the runtime.
+    pub fn shutdown(&self) -> impl Future<Item = (), Error = ()> {
+        stop(&self.ctx)
+    }
+
+    /// Replaces our NTCP2 static key, and republishes our RouterInfo with the
+    /// new key.
+    ///
+    /// Sessions established with the old key stay open until they go idle.
+    /// New handshakes, including inbound ones from peers that have our new
+    /// RouterInfo, use the new key.
+    pub fn rotate_ntcp2_key(&self) -> io::Result<()> {
+        let addresses = {
+            let comms = self.ctx.comms.read().unwrap();
+            comms.rotate_keys(&NTCP2_STYLE)?;
+            comms.addresses()
+        };
+        self.ctx.update_router_info(addresses)
+    }
+
+    /// Looks up the LeaseSet for the Destination with the given `.b32.i2p`
+    /// address, asking the closest floodfill if we don't have it locally.
+    pub fn lookup_destination(
+        &self,
+        b32: &str,
+    ) -> impl Future<Item = LeaseSet, Error = netdb::Error> {
+        self.ctx
+            .netdb
+            .lookup_destination(b32, DESTINATION_LOOKUP_TIMEOUT.as_millis() as u64)
+    }
+
+    /// Returns the throughput of all our sessions, averaged over the last one,
+    /// five and fifteen minutes.
+    pub fn traffic(&self) -> TrafficSummary {
+        self.ctx.comms.read().unwrap().traffic()
+    }
+
+    /// Summarizes the state of the router as JSON, for debugging.
+    ///
+    /// The summary contains the router's config, the hash of its published
+    /// RouterInfo, statistics about the routers in the network database, our
+    /// open sessions and their throughput, and the state of our outbound tunnel
+    /// pool. The network database statistics are `null` if the network
+    /// database isn't running.
+    pub fn dump_state(&self) -> impl Future<Item = serde_json::Value, Error = ()> {
+        let config = self
+            .ctx
+            .config
+            .read()
+            .unwrap()
+            .clone()
+            .try_into::<serde_json::Value>()
+            .unwrap_or_default();
+        let router_info = self
+            .ctx
+            .published_router_info()
+            .router_id
+            .hash()
+            .to_string();
+        let sessions: Vec<_> = self
+            .ctx
+            .comms
+            .read()
+            .unwrap()
+            .sessions()
+            .into_iter()
+            .map(|(peer, direction)| {
+                json!({
+                    "peer": peer.to_string(),
+                    "direction": direction.to_string(),
+                })
+            })
+            .collect();
+        let traffic = {
+            let throughput = |t: Throughput| {
+                json!({
+                    "1m": t.one_minute,
+                    "5m": t.five_minutes,
+                    "15m": t.fifteen_minutes,
+                })
+            };
+            let traffic = self.traffic();
+            json!({
+                "bytes_received": throughput(traffic.bytes_received),
+                "bytes_sent": throughput(traffic.bytes_sent),
+                "messages_received": throughput(traffic.messages_received),
+                "messages_sent": throughput(traffic.messages_sent),
+                "throttled_reads": throughput(traffic.throttled_reads),
+                "throttled_writes": throughput(traffic.throttled_writes),
+            })
+        };
+        let tunnels = self.ctx.ob_tunnels.as_ref().map(|pool| {
+            json!({
+                "outbound": { "live": pool.live_tunnels() },
+            })
+        });
+
+        self.ctx.netdb.stats().then(move |stats| {
+            let netdb = stats.ok().map(|stats| {
+                json!({
+                    "known_routers": stats.known_routers,
+                    "floodfills": stats.floodfills,
+                    "reachable": stats.reachable,
+                    "firewalled": stats.firewalled,
+                    "bandwidth_tiers": stats.bandwidth_tiers,
+                    "versions": stats.versions,
+                })
+            });
+            Ok(json!({
+                "config": config,
+                "router_info": router_info,
+                "netdb": netdb,
+                "sessions": sessions,
+                "traffic": traffic,
+                "tunnels": tunnels,
+            }))
+        })
+    }
+}
+
+/// Signals a running router to shut down.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Mutex<Option<oneshot::Sender<()>>>>);
+
+impl ShutdownHandle {
+    /// Asks the router to shut down, in the same way as [`Router::shutdown`].
+    ///
+    /// Has no effect if the router has already been asked to shut down.
+    pub fn shutdown(&self) {
+        if let Some(tx) = self.0.lock().unwrap().take() {
+            let _ = tx.send(());
+        }
     }
 }
 
@@ -154,4 +556,270 @@ impl Handle {
     ) -> Result<IoFuture<()>, (RouterInfo, Message)> {
         self.ctx.comms.read().unwrap().send(peer, msg)
     }
+
+    /// Returns the RouterInfo that the router most recently published.
+    pub fn published_router_info(&self) -> RouterInfo {
+        self.ctx.published_router_info()
+    }
+
+    /// Waits until the router knows at least `n` peers.
+    pub fn wait_for_peers(&self, n: usize) -> netdb::client::WaitForPeers {
+        self.ctx.wait_for_peers(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, sync::mpsc, Async, Future, Stream};
+    use std::sync::{Arc, RwLock};
+    use std::time::{Duration, Instant, SystemTime};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::config;
+    use super::mock::{mock_context, MockCommSystem, MockContextBuilder};
+    use super::types::Distributor as _;
+    use super::{Builder, Context, DeliveryStatuses, Distributor, Router};
+    use crate::crypto::{elgamal, SessionKey};
+    use crate::data::{
+        Hash, I2PDate, LeaseSet, RouterInfo, RouterSecretKeys, I2P_VERSION, NTCP2_OPT_S,
+    };
+    use crate::i2np::{
+        frame::gen_garlic_cloves, DatabaseStore, DatabaseStoreData, DeliveryStatus,
+        EncryptedGarlic, Garlic, GarlicClove, GarlicCloveDeliveryInstructions, Message,
+        MessagePayload,
+    };
+    use crate::transport::{ntcp2::NTCP2_STYLE, Direction};
+    use crate::util::serialize;
+
+    fn mock_router(ctx: Arc<Context>) -> Router {
+        Router::new(ctx, None, None, None, None)
+    }
+
+    #[test]
+    fn published_router_info() {
+        let ctx = mock_context();
+
+        let ri = ctx.published_router_info();
+        assert_eq!(ri, *ctx.ri.read().unwrap());
+        assert!(ri.verify().is_ok());
+
+        // Republish with a new timestamp
+        let mut new_ri = ri.clone();
+        new_ri.published = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(60));
+        new_ri.sign(&ctx.keys.signing_private_key);
+        *ctx.ri.write().unwrap() = new_ri.clone();
+
+        let ri = ctx.published_router_info();
+        assert_eq!(ri, new_ri);
+        assert!(ri.verify().is_ok());
+    }
+
+    #[test]
+    fn distribute_garlic() {
+        let (netdb_tx, netdb_rx) = mpsc::channel(8);
+        let (tunnel_acceptor_tx, _tunnel_acceptor_rx) = mpsc::channel(8);
+        let (tunnel_processor_tx, _tunnel_processor_rx) = mpsc::channel(8);
+        let delivery_statuses = DeliveryStatuses::default();
+        delivery_statuses.expect(1234);
+        let own_keys = RouterSecretKeys::new();
+        let distributor = Distributor::new(
+            netdb_tx,
+            tunnel_acceptor_tx,
+            tunnel_processor_tx,
+            delivery_statuses.clone(),
+            elgamal::Decryptor::from(&own_keys.private_key),
+        );
+
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid.clone());
+        ri.sign(&rsk.signing_private_key);
+        let time_stamp = I2PDate::from_system_time(SystemTime::now());
+
+        let store = MessagePayload::DatabaseStore(DatabaseStore::from_ri(ri.clone(), None));
+        let status = MessagePayload::DeliveryStatus(DeliveryStatus {
+            msg_id: 1234,
+            time_stamp,
+        });
+        let local = GarlicCloveDeliveryInstructions::local;
+        let clove = |payload| GarlicClove::new(local(), Message::from_payload(payload));
+        let garlic = Garlic::new(vec![clove(store), clove(status)]);
+        let from = Hash([1; 32]);
+
+        // Garlic that isn't encrypted to us is dropped
+        let (_, other_key) = elgamal::KeyPairGenerator::generate();
+        for ct in vec![
+            EncryptedGarlic(serialize(|input| gen_garlic_cloves(input, &garlic))),
+            garlic.encrypt(&elgamal::Encryptor::from(&other_key), &SessionKey([2; 32]), &[]),
+        ] {
+            let msg = Message::from_payload(MessagePayload::Garlic(ct));
+            assert!(distributor.handle(from.clone(), msg).wait().is_ok());
+        }
+        assert!(delivery_statuses.take(1234).is_none());
+
+        let ct = garlic.encrypt(
+            &elgamal::Encryptor::from(&own_keys.rid.public_key),
+            &SessionKey([2; 32]),
+            &[],
+        );
+        let msg = Message::from_payload(MessagePayload::Garlic(ct));
+        assert!(distributor.handle(from.clone(), msg).wait().is_ok());
+
+        // The RouterInfo is passed to the netdb
+        match netdb_rx.wait().next() {
+            Some(Ok((peer, msg))) => {
+                assert_eq!(peer, from);
+                match msg.payload {
+                    MessagePayload::DatabaseStore(DatabaseStore {
+                        data: DatabaseStoreData::RI(stored),
+                        ..
+                    }) => assert_eq!(stored, ri),
+                    _ => panic!("Expected a DatabaseStore"),
+                }
+            }
+            _ => panic!("Expected a message for the netdb"),
+        }
+
+        // The DeliveryStatus is recorded
+        assert_eq!(delivery_statuses.take(1234).map(|(ts, _)| ts), Some(time_stamp));
+        assert!(delivery_statuses.take(1234).is_none());
+    }
+
+    #[test]
+    fn unexpected_delivery_status() {
+        let statuses = DeliveryStatuses::default();
+        let time_stamp = I2PDate::now();
+
+        // Statuses that nobody is waiting for are dropped
+        assert!(!statuses.record(1, time_stamp));
+        assert!(statuses.take(1).is_none());
+
+        statuses.expect(1);
+        assert!(statuses.take(1).is_none());
+        assert!(statuses.record(1, time_stamp));
+        assert!(!statuses.record(1, time_stamp));
+        assert_eq!(statuses.take(1).map(|(ts, _)| ts), Some(time_stamp));
+
+        // Once we stop waiting, the status is dropped
+        statuses.expect(2);
+        statuses.forget(2);
+        assert!(!statuses.record(2, time_stamp));
+    }
+
+    #[test]
+    fn shutdown_timeout() {
+        let mut rt = Runtime::new().unwrap();
+
+        // Sessions close immediately
+        let start = Instant::now();
+        assert!(rt.block_on(mock_router(mock_context()).shutdown()).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Peers never close their sessions
+        let (ctx, _) = MockContextBuilder::new().unresponsive_peers().build();
+        ctx.config
+            .write()
+            .unwrap()
+            .set(config::SHUTDOWN_TIMEOUT, 1)
+            .unwrap();
+        let start = Instant::now();
+        assert!(rt.block_on(mock_router(ctx).shutdown()).is_ok());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn shutdown_handle() {
+        let mut router = Builder::new()
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .build()
+            .unwrap();
+        router
+            .ctx
+            .config
+            .write()
+            .unwrap()
+            .set(config::RESEED_ENABLE, false)
+            .unwrap();
+        let handle = router.shutdown_handle();
+
+        let mut rt = Runtime::new().unwrap();
+        let mut running = router.start();
+
+        // The router keeps running until it is told to stop
+        assert_eq!(rt.block_on(future::lazy(|| running.poll())), Ok(Async::NotReady));
+
+        handle.shutdown();
+        assert!(rt.block_on(running).is_ok());
+
+        // Repeated signals are ignored
+        handle.shutdown();
+    }
+
+    #[test]
+    fn dump_state() {
+        let peer = Hash([1; 32]);
+        let (ctx, mut netdb) = MockContextBuilder::new()
+            .sessions(vec![(peer.clone(), Direction::Inbound)])
+            .build_with_netdb();
+        for _ in 0..3 {
+            let ri = RouterInfo::new(RouterSecretKeys::new().rid);
+            netdb.store_router_info(ri.router_id.hash(), ri);
+        }
+
+        ctx.config
+            .write()
+            .unwrap()
+            .set(config::RESEED_ENABLE, false)
+            .unwrap();
+        let router = mock_router(ctx.clone());
+
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(netdb);
+        let state = rt.block_on(router.dump_state()).unwrap();
+
+        let keys: Vec<_> = state.as_object().unwrap().keys().cloned().collect();
+        assert_eq!(
+            keys,
+            vec!["config", "netdb", "router_info", "sessions", "traffic", "tunnels"]
+        );
+        assert_eq!(state["config"]["reseed"]["enable"], false);
+        assert_eq!(
+            state["router_info"],
+            ctx.published_router_info().router_id.hash().to_string()
+        );
+        assert_eq!(state["netdb"]["known_routers"], 3);
+        assert_eq!(state["netdb"]["bandwidth_tiers"]["K"], 3);
+        assert_eq!(state["netdb"]["versions"][I2P_VERSION], 3);
+        assert_eq!(state["sessions"][0]["peer"], peer.to_string());
+        assert_eq!(state["sessions"][0]["direction"], "inbound");
+        assert_eq!(state["traffic"]["bytes_received"]["1m"], 0.0);
+        assert!(state["tunnels"].is_null());
+    }
+
+    #[test]
+    fn rotate_ntcp2_key() {
+        let ctx = mock_context();
+        let old_ri = ctx.published_router_info();
+        let router = mock_router(ctx.clone());
+
+        router.rotate_ntcp2_key().unwrap();
+        let new_ri = ctx.published_router_info();
+        assert!(new_ri.published >= old_ri.published);
+        assert!(new_ri.verify().is_ok());
+
+        // The republished NTCP2 address has a new static key
+        let static_key = |ri: &RouterInfo| {
+            ri.address(&NTCP2_STYLE, |_| true)
+                .and_then(|ra| ra.option(&NTCP2_OPT_S).cloned())
+        };
+        let first_key = static_key(&new_ri);
+        assert!(first_key.is_some());
+
+        router.rotate_ntcp2_key().unwrap();
+        let rotated_ri = ctx.published_router_info();
+        assert!(rotated_ri.verify().is_ok());
+        assert!(static_key(&rotated_ri).is_some());
+        assert_ne!(static_key(&rotated_ri), first_key);
+    }
 }
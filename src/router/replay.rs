@@ -0,0 +1,169 @@
+//! Recording and replaying of received I2NP messages, for debugging.
+//!
+//! A [`Recorder`] sits in front of a [`Distributor`] and appends every message
+//! it handles to a log file. The log can later be read back with [`read_log`]
+//! and fed into a `Distributor` with [`replay`], in the order the messages were
+//! originally received.
+
+use cookie_factory::*;
+use futures::{stream, Future, Stream};
+use nom::*;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use super::types::{Distributor, DistributorResult};
+use crate::data::{
+    frame::{gen_hash, gen_i2p_date, hash, i2p_date},
+    Hash, I2PDate,
+};
+use crate::i2np::{
+    frame::{gen_message, message},
+    Message,
+};
+use crate::util::serialize;
+
+/// A message received by the router.
+#[derive(Debug)]
+pub struct Record {
+    pub from: Hash,
+    pub received: I2PDate,
+    pub msg: Message,
+}
+
+named!(record<Record>,
+    do_parse!(
+        from:     hash >>
+        received: i2p_date >>
+        msg:      length_value!(be_u32, message) >>
+        (Record { from, received, msg })
+    )
+);
+
+fn gen_record<'a>(
+    input: (&'a mut [u8], usize),
+    from: &Hash,
+    received: &I2PDate,
+    msg: &[u8],
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_hash(from)
+            >> gen_i2p_date(received)
+            >> gen_be_u32!(msg.len() as u32)
+            >> gen_slice!(msg)
+    )
+}
+
+/// Records every message handled by the wrapped [`Distributor`].
+#[derive(Clone)]
+pub struct Recorder<D: Distributor> {
+    inner: D,
+    log: Arc<Mutex<File>>,
+}
+
+impl<D: Distributor> Recorder<D> {
+    /// Creates a recorder that writes to the given path, replacing any
+    /// existing log.
+    pub fn create(path: &str, inner: D) -> io::Result<Self> {
+        Ok(Recorder {
+            inner,
+            log: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+}
+
+impl<D: Distributor> Distributor for Recorder<D> {
+    fn handle(&self, from: Hash, msg: Message) -> DistributorResult {
+        let received = I2PDate::now();
+        let msg_bytes = serialize(|input| gen_message(input, &msg));
+        let record = serialize(|input| gen_record(input, &from, &received, &msg_bytes));
+        if let Err(e) = self.log.lock().unwrap().write_all(&record) {
+            warn!("Failed to record message from {}: {}", from, e);
+        }
+        self.inner.handle(from, msg)
+    }
+}
+
+/// Reads the messages recorded in the given log, in the order they were
+/// received.
+pub fn read_log(path: &str) -> io::Result<Vec<Record>> {
+    let data = fs::read(path)?;
+    let mut input = &data[..];
+    let mut records = vec![];
+    while !input.is_empty() {
+        match record(input) {
+            Ok((rest, r)) => {
+                records.push(r);
+                input = rest;
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid message log record: {:?}", e),
+                ));
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Feeds recorded messages to a [`Distributor`] in order, waiting for each
+/// message to be handled before the next is sent.
+pub fn replay<D: Distributor>(
+    records: Vec<Record>,
+    distributor: D,
+) -> impl Future<Item = (), Error = io::Error> {
+    stream::iter_ok(records).for_each(move |r| {
+        distributor.handle(r.from, r.msg).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "Distributor stopped accepting messages")
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use tempfile::tempdir;
+
+    use super::{read_log, replay, Recorder};
+    use crate::data::Hash;
+    use crate::i2np::{Message, MessagePayload};
+    use crate::router::{mock::MockDistributor, types::Distributor};
+
+    #[test]
+    fn record_and_replay() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("messages.log");
+        let log = log.to_str().unwrap();
+
+        let received = MockDistributor::new();
+        let recorder = Recorder::create(log, received.clone()).unwrap();
+        for i in 0..3 {
+            let msg = Message::from_payload(MessagePayload::Data(vec![i; 10 * i as usize]));
+            recorder.handle(Hash([i; 32]), msg).wait().unwrap();
+        }
+        let received = received.received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+
+        let records = read_log(log).unwrap();
+        assert_eq!(records.len(), 3);
+
+        let replayed = MockDistributor::new();
+        replay(records, replayed.clone()).wait().unwrap();
+        let replayed = replayed.received.lock().unwrap();
+
+        // The same messages are seen in the same order
+        assert_eq!(replayed.len(), received.len());
+        for ((from, msg), (orig_from, orig_msg)) in replayed.iter().zip(received.iter()) {
+            assert_eq!(from, orig_from);
+            assert_eq!(msg, orig_msg);
+            match (&msg.payload, &orig_msg.payload) {
+                (MessagePayload::Data(data), MessagePayload::Data(orig_data)) => {
+                    assert_eq!(data, orig_data)
+                }
+                _ => panic!("Unexpected payload"),
+            }
+        }
+    }
+}
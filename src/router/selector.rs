@@ -0,0 +1,91 @@
+//! Peer selection policies.
+
+use rand::{thread_rng, Rng};
+
+use super::types::PeerSelector;
+use crate::data::{RouterInfo, BANDWIDTH_TIERS};
+
+/// Selects peers at random, weighted by their advertised bandwidth tier.
+///
+/// Peers that don't advertise a tier are weighted as if they were in the
+/// lowest tier.
+pub struct TierWeightedSelector;
+
+fn tier_weight(ri: &RouterInfo) -> u32 {
+    ri.bandwidth_tier()
+        .and_then(|tier| BANDWIDTH_TIERS.find(tier))
+        .map(|i| i as u32 + 1)
+        .unwrap_or(1)
+}
+
+impl PeerSelector for TierWeightedSelector {
+    fn select_peers(&self, candidates: &[RouterInfo], count: usize) -> Vec<RouterInfo> {
+        let mut rng = thread_rng();
+        let mut remaining: Vec<_> = candidates.iter().map(|ri| (tier_weight(ri), ri)).collect();
+        let mut selected = Vec::with_capacity(count.min(candidates.len()));
+
+        while selected.len() < count && !remaining.is_empty() {
+            let total: u32 = remaining.iter().map(|(weight, _)| weight).sum();
+            let mut choice = rng.gen_range(0, total);
+            let i = remaining
+                .iter()
+                .position(|(weight, _)| {
+                    if choice < *weight {
+                        true
+                    } else {
+                        choice -= weight;
+                        false
+                    }
+                })
+                .unwrap();
+            selected.push(remaining.swap_remove(i).1.clone());
+        }
+
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TierWeightedSelector;
+    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::router::types::PeerSelector;
+
+    fn router_info(caps: &str) -> RouterInfo {
+        let sk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(sk.rid);
+        ri.options.0.insert("caps".into(), caps.into());
+        ri
+    }
+
+    #[test]
+    fn select_distinct_peers() {
+        let candidates: Vec<_> = ["K", "LR", "Of", "XR", "U"]
+            .iter()
+            .map(|caps| router_info(caps))
+            .collect();
+
+        let selected = TierWeightedSelector.select_peers(&candidates, 3);
+        assert_eq!(selected.len(), 3);
+        for (i, ri) in selected.iter().enumerate() {
+            assert!(candidates.contains(ri));
+            assert!(!selected[i + 1..].contains(ri));
+        }
+
+        // Can't select more peers than there are candidates
+        assert_eq!(TierWeightedSelector.select_peers(&candidates, 10).len(), 5);
+        assert!(TierWeightedSelector.select_peers(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn prefer_higher_tiers() {
+        let slow = router_info("KR");
+        let fast = router_info("XR");
+        let candidates = vec![slow, fast.clone()];
+
+        let fast_selected = (0..1000)
+            .filter(|_| TierWeightedSelector.select_peers(&candidates, 1)[0] == fast)
+            .count();
+        assert!(fast_selected > 500);
+    }
+}
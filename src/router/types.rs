@@ -1,12 +1,14 @@
 //! The traits for the various router components.
 
 use futures::{sync::mpsc, Future};
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::io;
 
 use super::Context;
-use crate::data::{Hash, RouterAddress, RouterInfo};
+use crate::data::{Hash, I2PString, RouterAddress, RouterInfo};
 use crate::i2np::Message;
+use crate::transport::{Direction, TrafficSummary};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
@@ -32,8 +34,57 @@ pub trait CommSystem: Send + Sync {
     /// Returns true if there is an open session with the given peer.
     fn is_established(&self, hash: &Hash) -> bool;
 
+    /// Returns the peers we have open sessions with, and which side opened
+    /// each session.
+    fn sessions(&self) -> Vec<(Hash, Direction)>;
+
+    /// Replaces the keys published in the address of the transport with the
+    /// given style. Established sessions are unaffected.
+    fn rotate_keys(&self, style: &I2PString) -> io::Result<()>;
+
+    /// Sets the IP address that peers can reach us at, in the addresses of the
+    /// transports that publish one. The caller must republish our RouterInfo.
+    fn set_external_ip(&mut self, ip: IpAddr);
+
+    /// Returns the throughput of all sessions, averaged over the last one,
+    /// five and fifteen minutes.
+    fn traffic(&self) -> TrafficSummary;
+
+    /// Stops accepting new connections. Handshakes that are already in
+    /// progress are allowed to complete.
+    fn stop(&mut self);
+
+    /// Gracefully closes all sessions, sending any queued messages first.
+    ///
+    /// Returns a future that resolves once every session has closed.
+    fn shutdown(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
     /// Send an I2NP message to a peer.
     ///
     /// Returns an Err giving back the message if it cannot be sent.
     fn send(&self, peer: RouterInfo, msg: Message) -> Result<IoFuture<()>, (RouterInfo, Message)>;
 }
+
+/// A policy for choosing which peers the router interacts with.
+pub trait PeerSelector: Send + Sync {
+    /// Selects up to `count` distinct peers from `candidates`, in order of
+    /// preference.
+    fn select_peers(&self, candidates: &[RouterInfo], count: usize) -> Vec<RouterInfo>;
+}
+
+/// The router's pool of outbound tunnels.
+pub trait OutboundTunnelPool: Send + Sync {
+    /// Wraps a message to be sent through one of the pool's tunnels, with
+    /// instructions for the tunnel's outbound endpoint to send it on to the
+    /// router `to`. Returns the first hop of the tunnel, and the messages to
+    /// send it.
+    ///
+    /// Returns None if the pool has no usable tunnels.
+    fn wrap_message(&self, to: &Hash, msg: &Message) -> Option<(RouterInfo, Vec<Message>)>;
+
+    /// Returns the number of tunnels in the pool that have not expired.
+    fn live_tunnels(&self) -> usize;
+
+    /// Releases the pool's tunnels, and stops building new ones.
+    fn shutdown(&self);
+}
@@ -0,0 +1,70 @@
+//! Limits on the bandwidth used by transport sessions.
+//!
+//! Every session on every transport draws from a pair of token buckets, one
+//! for the bytes it reads and one for the bytes it writes, which refill at the
+//! configured rates. A bucket can hold up to a configured burst of bytes, so
+//! that short spikes in traffic aren't delayed. Once a bucket is empty, reads
+//! or writes in that direction wait until it has refilled. SSU sessions share
+//! a socket, so their outbound packets wait and their inbound packets are
+//! dropped instead.
+
+use crate::router::config::{self, Config};
+use crate::util::TokenBucket;
+
+/// The bandwidth available to all sessions, shared between transports.
+#[derive(Clone, Default)]
+pub(super) struct BandwidthLimiter {
+    pub(super) inbound: TokenBucket,
+    pub(super) outbound: TokenBucket,
+}
+
+impl BandwidthLimiter {
+    /// Reads the limits from the router config. Directions without a limit
+    /// may use as much bandwidth as they like. Bursts default to one second's
+    /// worth of traffic.
+    pub(super) fn from_config(config: &Config) -> Self {
+        // Checked by config::Validate
+        let bucket = |rate_key: &str, burst_key: &str| match config.get_int(rate_key) {
+            Ok(kbps) => {
+                let rate = kbps as u64 * 1024;
+                let burst = config
+                    .get_int(burst_key)
+                    .map(|kb| kb as u64 * 1024)
+                    .unwrap_or(rate);
+                TokenBucket::new(rate, burst)
+            }
+            Err(_) => TokenBucket::default(),
+        };
+        BandwidthLimiter {
+            inbound: bucket(
+                config::TRANSPORT_INBOUND_BANDWIDTH,
+                config::TRANSPORT_INBOUND_BURST,
+            ),
+            outbound: bucket(
+                config::TRANSPORT_OUTBOUND_BANDWIDTH,
+                config::TRANSPORT_OUTBOUND_BURST,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BandwidthLimiter;
+    use crate::router::config::{self, Config};
+
+    #[test]
+    fn from_config() {
+        let mut cfg = Config::default();
+        let bandwidth = BandwidthLimiter::from_config(&cfg);
+        assert_eq!(bandwidth.inbound.available(), None);
+        assert_eq!(bandwidth.outbound.available(), None);
+
+        cfg.set(config::TRANSPORT_INBOUND_BANDWIDTH, 10).unwrap();
+        cfg.set(config::TRANSPORT_OUTBOUND_BANDWIDTH, 20).unwrap();
+        cfg.set(config::TRANSPORT_OUTBOUND_BURST, 5).unwrap();
+        let bandwidth = BandwidthLimiter::from_config(&cfg);
+        assert_eq!(bandwidth.inbound.available(), Some(10 * 1024));
+        assert_eq!(bandwidth.outbound.available(), Some(5 * 1024));
+    }
+}
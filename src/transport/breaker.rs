@@ -0,0 +1,267 @@
+//! Per-peer circuit breaking for outbound messages.
+//!
+//! If sends or connections to a peer keep failing, we stop trying for a while
+//! instead of repeatedly reconnecting. After `threshold` consecutive failures
+//! the circuit for that peer opens, and sends are rejected immediately. Once
+//! `cooldown` has passed the circuit is half-open: a single send is let
+//! through, and the circuit closes once we connect to the peer, or reopens if
+//! the send or the connection fails. If neither happens within another
+//! `cooldown`, another send is let through.
+//!
+//! A transport accepts a message as soon as it is queued, so successful sends
+//! don't close the circuit; the transports report the outcome of connecting to
+//! the peer with [`CircuitBreaker::record`].
+
+use futures::Future;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::IoFuture;
+use crate::data::{Hash, RouterInfo};
+use crate::i2np::Message;
+
+/// Number of consecutive failures after which we stop sending to a peer.
+pub(super) const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+
+/// Number of seconds to wait before trying a peer again.
+pub(super) const DEFAULT_COOLDOWN: u64 = 60;
+
+/// Maximum number of peers that we track. Once reached, peers whose circuits
+/// have been quiet for a cooldown are forgotten, and then the peer that changed
+/// state longest ago, to make room for a new one.
+const MAX_PEERS: usize = 10_000;
+
+enum Circuit {
+    /// The number of consecutive failures, and the time of the last one.
+    Closed(u32, Instant),
+    Open(Instant),
+    HalfOpen(Instant),
+}
+
+impl Circuit {
+    /// The time at which the circuit last changed.
+    fn since(&self) -> Instant {
+        match self {
+            Circuit::Closed(_, since) | Circuit::Open(since) | Circuit::HalfOpen(since) => *since,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    max_peers: usize,
+    peers: Arc<Mutex<HashMap<Hash, Circuit>>>,
+}
+
+impl CircuitBreaker {
+    pub(super) fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            max_peers: MAX_PEERS,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns whether a send to the peer should be attempted.
+    fn allow(&self, peer: &Hash) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get(peer) {
+            None | Some(Circuit::Closed(..)) => true,
+            Some(Circuit::Open(since)) | Some(Circuit::HalfOpen(since))
+                if since.elapsed() >= self.cooldown =>
+            {
+                debug!("Retrying sends to {} after cooldown", peer);
+                peers.insert(peer.clone(), Circuit::HalfOpen(Instant::now()));
+                true
+            }
+            Some(Circuit::Open(_)) | Some(Circuit::HalfOpen(_)) => false,
+        }
+    }
+
+    /// Records whether a send or a connection to the peer succeeded.
+    pub(super) fn record(&self, peer: Hash, success: bool) {
+        let mut peers = self.peers.lock().unwrap();
+        if success {
+            peers.remove(&peer);
+            return;
+        }
+
+        let failures = match peers.get(&peer) {
+            Some(Circuit::Closed(failures, _)) => failures + 1,
+            Some(Circuit::HalfOpen(_)) => self.threshold,
+            // A send that was allowed before the circuit opened
+            Some(Circuit::Open(_)) => return,
+            None => 1,
+        };
+        if peers.len() >= self.max_peers && !peers.contains_key(&peer) {
+            let cooldown = self.cooldown;
+            peers.retain(|_, circuit| circuit.since().elapsed() < cooldown);
+            if peers.len() >= self.max_peers {
+                let oldest = peers
+                    .iter()
+                    .min_by_key(|(_, circuit)| circuit.since())
+                    .map(|(peer, _)| peer.clone())
+                    .expect("Peers are not empty");
+                peers.remove(&oldest);
+            }
+        }
+        if failures >= self.threshold {
+            warn!("Too many failed sends to {}, backing off", peer);
+            peers.insert(peer, Circuit::Open(Instant::now()));
+        } else {
+            peers.insert(peer, Circuit::Closed(failures, Instant::now()));
+        }
+    }
+
+    /// Sends a message with the given function, unless the circuit for the
+    /// peer is open. A failed send is recorded.
+    ///
+    /// If the message is not sent, it is returned.
+    pub(super) fn send<F>(
+        &self,
+        peer: RouterInfo,
+        msg: Message,
+        send: F,
+    ) -> Result<IoFuture<()>, (RouterInfo, Message)>
+    where
+        F: FnOnce(RouterInfo, Message) -> Result<IoFuture<()>, (RouterInfo, Message)>,
+    {
+        let hash = peer.router_id.hash();
+        if !self.allow(&hash) {
+            return Err((peer, msg));
+        }
+
+        let breaker = self.clone();
+        send(peer, msg).map(|f| -> IoFuture<()> {
+            Box::new(f.map_err(move |e| {
+                breaker.record(hash, false);
+                e
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+    use std::cell::Cell;
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{CircuitBreaker, IoFuture};
+    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::i2np::Message;
+
+    #[test]
+    fn short_circuit_failing_peer() {
+        let breaker = CircuitBreaker::new(3, Duration::from_millis(100));
+        let peer = RouterInfo::new(RouterSecretKeys::new().rid);
+        let attempts = Cell::new(0);
+
+        let send = |succeed: bool| {
+            breaker.send(peer.clone(), Message::dummy_data(), |_, _| {
+                attempts.set(attempts.get() + 1);
+                let f: IoFuture<()> = if succeed {
+                    Box::new(future::ok(()))
+                } else {
+                    Box::new(future::err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "connection dropped",
+                    )))
+                };
+                Ok(f)
+            })
+        };
+
+        // The circuit opens after three failures
+        for _ in 0..3 {
+            assert!(send(false).unwrap().wait().is_err());
+        }
+        assert_eq!(attempts.get(), 3);
+
+        // Sends now short-circuit without being attempted
+        assert!(send(false).is_err());
+        assert!(send(true).is_err());
+        assert_eq!(attempts.get(), 3);
+
+        // After the cooldown, one send is let through, and it fails
+        thread::sleep(Duration::from_millis(150));
+        let trial = send(false).unwrap();
+        assert!(send(false).is_err());
+        assert!(trial.wait().is_err());
+        assert_eq!(attempts.get(), 4);
+        assert!(send(true).is_err());
+
+        // A trial that is sent but doesn't connect reopens the circuit
+        thread::sleep(Duration::from_millis(150));
+        assert!(send(true).unwrap().wait().is_ok());
+        assert!(send(true).is_err());
+        breaker.record(peer.router_id.hash(), false);
+        assert!(send(true).is_err());
+        assert_eq!(attempts.get(), 5);
+
+        // A trial that connects closes the circuit
+        thread::sleep(Duration::from_millis(150));
+        assert!(send(true).unwrap().wait().is_ok());
+        breaker.record(peer.router_id.hash(), true);
+        assert!(send(true).unwrap().wait().is_ok());
+        assert_eq!(attempts.get(), 7);
+    }
+
+    #[test]
+    fn failed_connections() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(100));
+        let peer = RouterInfo::new(RouterSecretKeys::new().rid);
+        let hash = peer.router_id.hash();
+        let send = || {
+            breaker.send(peer.clone(), Message::dummy_data(), |_, _| {
+                let f: IoFuture<()> = Box::new(future::ok(()));
+                Ok(f)
+            })
+        };
+
+        // Sends are queued successfully, but connecting fails
+        for _ in 0..2 {
+            assert!(send().unwrap().wait().is_ok());
+            breaker.record(hash.clone(), false);
+        }
+        assert!(send().is_err());
+
+        // If we never learn the outcome of the trial, another is let through
+        thread::sleep(Duration::from_millis(150));
+        assert!(send().is_ok());
+        assert!(send().is_err());
+        thread::sleep(Duration::from_millis(150));
+        assert!(send().is_ok());
+    }
+
+    #[test]
+    fn bounded_peers() {
+        let breaker = CircuitBreaker {
+            max_peers: 2,
+            ..CircuitBreaker::new(2, Duration::from_millis(100))
+        };
+        let peers: Vec<_> = (0..3).map(|_| RouterSecretKeys::new().rid.hash()).collect();
+
+        breaker.record(peers[0].clone(), false);
+        breaker.record(peers[0].clone(), false);
+        thread::sleep(Duration::from_millis(10));
+        breaker.record(peers[1].clone(), false);
+        breaker.record(peers[2].clone(), false);
+
+        // The peer that changed longest ago is forgotten, so its circuit closes
+        assert_eq!(breaker.peers.lock().unwrap().len(), 2);
+        assert!(breaker.allow(&peers[0]));
+        assert!(breaker.peers.lock().unwrap().get(&peers[0]).is_none());
+
+        // Quiet circuits are pruned first
+        thread::sleep(Duration::from_millis(150));
+        breaker.record(peers[0].clone(), false);
+        assert_eq!(breaker.peers.lock().unwrap().len(), 1);
+    }
+}
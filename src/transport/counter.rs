@@ -0,0 +1,238 @@
+//! Byte accounting for transport connections.
+
+use futures::{Async, Future, Poll};
+use std::io::{self, Read, Write};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
+
+use super::bandwidth::BandwidthLimiter;
+use super::stats::TrafficStats;
+use crate::util::TokenBucket;
+
+/// Takes up to `max` bytes of bandwidth from `bucket`. If none are available,
+/// calls `throttled`, returns a `WouldBlock` error, and arranges for the current
+/// task to be woken once they might be.
+fn throttle<F>(
+    bucket: &TokenBucket,
+    wait: &mut Option<Delay>,
+    max: usize,
+    throttled: F,
+) -> io::Result<usize>
+where
+    F: Fn(),
+{
+    loop {
+        if let Some(delay) = wait {
+            match delay.poll() {
+                Ok(Async::Ready(())) => (),
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+            *wait = None;
+        }
+        match bucket.take(max) {
+            Ok(n) => return Ok(n),
+            Err(duration) => {
+                throttled();
+                *wait = Some(Delay::new(Instant::now() + duration));
+            }
+        }
+    }
+}
+
+/// Wraps a connection, counting the bytes read from and written to it.
+///
+/// If a read limit is set, reads fail once the total number of bytes read
+/// exceeds it. If traffic statistics are set, the bytes are also recorded in
+/// them. If a bandwidth limiter is set, reads and writes wait for bandwidth to
+/// be available, and must be driven by a task.
+pub(super) struct ByteCounter<T> {
+    inner: T,
+    read: usize,
+    written: usize,
+    read_limit: Option<usize>,
+    stats: Option<TrafficStats>,
+    bandwidth: Option<BandwidthLimiter>,
+    read_wait: Option<Delay>,
+    write_wait: Option<Delay>,
+}
+
+impl<T> ByteCounter<T> {
+    pub(super) fn new(inner: T) -> Self {
+        ByteCounter {
+            inner,
+            read: 0,
+            written: 0,
+            read_limit: None,
+            stats: None,
+            bandwidth: None,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+
+    pub(super) fn with_read_limit(inner: T, limit: usize) -> Self {
+        let mut counter = ByteCounter::new(inner);
+        counter.read_limit = Some(limit);
+        counter
+    }
+
+    /// Sets the maximum total number of bytes that can be read, or removes the
+    /// limit if `None`.
+    pub(super) fn set_read_limit(&mut self, limit: Option<usize>) {
+        self.read_limit = limit;
+    }
+
+    /// Records the bytes read and written from now on in `stats`.
+    pub(super) fn set_stats(&mut self, stats: TrafficStats) {
+        self.stats = Some(stats);
+    }
+
+    /// Limits the bandwidth used from now on to that available from `bandwidth`.
+    pub(super) fn set_bandwidth_limiter(&mut self, bandwidth: BandwidthLimiter) {
+        self.bandwidth = Some(bandwidth);
+    }
+
+    pub(super) fn bytes_read(&self) -> usize {
+        self.read
+    }
+
+    pub(super) fn bytes_written(&self) -> usize {
+        self.written
+    }
+}
+
+impl<T: Read> Read for ByteCounter<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match &self.bandwidth {
+            Some(bandwidth) => {
+                let stats = &self.stats;
+                let allowed = throttle(&bandwidth.inbound, &mut self.read_wait, buf.len(), || {
+                    if let Some(stats) = stats {
+                        stats.throttled_read();
+                    }
+                })?;
+                let res = self.inner.read(&mut buf[..allowed]);
+                let used = *res.as_ref().unwrap_or(&0);
+                bandwidth.inbound.give_back(allowed - used);
+                res?
+            }
+            None => self.inner.read(buf)?,
+        };
+        self.read += n;
+        if let Some(stats) = &self.stats {
+            stats.received_bytes(n);
+        }
+        match self.read_limit {
+            Some(limit) if self.read > limit => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Read limit of {} bytes exceeded", limit),
+            )),
+            _ => Ok(n),
+        }
+    }
+}
+
+impl<T: Write> Write for ByteCounter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match &self.bandwidth {
+            Some(bandwidth) => {
+                let stats = &self.stats;
+                let allowed = throttle(&bandwidth.outbound, &mut self.write_wait, buf.len(), || {
+                    if let Some(stats) = stats {
+                        stats.throttled_write();
+                    }
+                })?;
+                let res = self.inner.write(&buf[..allowed]);
+                let used = *res.as_ref().unwrap_or(&0);
+                bandwidth.outbound.give_back(allowed - used);
+                res?
+            }
+            None => self.inner.write(buf)?,
+        };
+        self.written += n;
+        if let Some(stats) = &self.stats {
+            stats.sent_bytes(n);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for ByteCounter<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for ByteCounter<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::lazy;
+    use std::io::{self, Cursor, Read, Write};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::ByteCounter;
+    use crate::transport::bandwidth::BandwidthLimiter;
+    use crate::util::TokenBucket;
+
+    #[test]
+    fn count_bytes() {
+        let mut counter = ByteCounter::new(Cursor::new(vec![0; 10]));
+        let mut buf = [0; 4];
+        assert_eq!(counter.read(&mut buf).unwrap(), 4);
+        assert_eq!(counter.read(&mut buf).unwrap(), 4);
+        assert_eq!(counter.bytes_read(), 8);
+
+        assert_eq!(counter.write(&[1; 5]).unwrap(), 5);
+        assert_eq!(counter.bytes_written(), 5);
+        assert_eq!(counter.bytes_read(), 8);
+    }
+
+    #[test]
+    fn read_limit() {
+        let mut counter = ByteCounter::with_read_limit(Cursor::new(vec![0; 10]), 6);
+        let mut buf = [0; 4];
+        assert_eq!(counter.read(&mut buf).unwrap(), 4);
+        assert!(counter.read(&mut buf).is_err());
+
+        // Removing the limit allows reading to continue
+        counter.set_read_limit(None);
+        assert_eq!(counter.read(&mut buf).unwrap(), 2);
+        assert_eq!(counter.bytes_read(), 10);
+    }
+
+    #[test]
+    fn bandwidth_limit() {
+        let mut counter = ByteCounter::new(Cursor::new(vec![0; 10]));
+        counter.set_bandwidth_limiter(BandwidthLimiter {
+            inbound: TokenBucket::new(1, 6),
+            outbound: TokenBucket::default(),
+        });
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            // Reads are cut short to the available bandwidth
+            let mut buf = [0; 4];
+            assert_eq!(counter.read(&mut buf).unwrap(), 4);
+            assert_eq!(counter.read(&mut buf).unwrap(), 2);
+
+            // and then wait for more
+            match counter.read(&mut buf) {
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+                Ok(_) => panic!("Read should have been throttled"),
+            }
+
+            // Writes are unlimited
+            assert_eq!(counter.write(&[1; 20]).unwrap(), 20);
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+        assert_eq!(counter.bytes_read(), 6);
+    }
+}
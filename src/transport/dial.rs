@@ -0,0 +1,144 @@
+//! Limits on concurrent outbound connection attempts.
+//!
+//! A burst of messages to peers we aren't connected to (for example, when
+//! building many tunnels at once) would otherwise open a socket for each of
+//! them at the same time. Dials beyond the limit wait in a queue until an
+//! earlier dial completes. Established sessions don't count towards the limit.
+
+use futures::{
+    future::{self, Either},
+    sync::oneshot,
+    Future,
+};
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Default maximum number of outbound connection attempts in progress.
+const DEFAULT_MAX_OUTBOUND_DIALS: usize = 32;
+
+struct Shared {
+    max: usize,
+    in_flight: usize,
+    queue: VecDeque<oneshot::Sender<DialPermit>>,
+}
+
+/// Shared between transports, so that the limit applies to all of them.
+#[derive(Clone)]
+pub(super) struct DialLimiter(Arc<Mutex<Shared>>);
+
+impl Default for DialLimiter {
+    fn default() -> Self {
+        DialLimiter::new(DEFAULT_MAX_OUTBOUND_DIALS)
+    }
+}
+
+/// Allows a single dial to proceed. The slot is released when it is dropped.
+struct DialPermit(DialLimiter);
+
+impl Drop for DialPermit {
+    fn drop(&mut self) {
+        let next = {
+            let mut s = (self.0).0.lock().unwrap();
+            match s.queue.pop_front() {
+                Some(next) => next,
+                None => {
+                    s.in_flight -= 1;
+                    return;
+                }
+            }
+        };
+
+        // Hand our slot to the next queued dial. If that dial was cancelled,
+        // the permit is dropped in turn and passed further along the queue.
+        let _ = next.send(DialPermit(self.0.clone()));
+    }
+}
+
+impl DialLimiter {
+    /// Allows `max` dials at once. At least one dial is always allowed.
+    pub(super) fn new(max: usize) -> Self {
+        DialLimiter(Arc::new(Mutex::new(Shared {
+            max: cmp::max(max, 1),
+            in_flight: 0,
+            queue: VecDeque::new(),
+        })))
+    }
+
+    fn acquire(&self) -> impl Future<Item = DialPermit, Error = oneshot::Canceled> {
+        let mut s = self.0.lock().unwrap();
+        if s.in_flight < s.max {
+            s.in_flight += 1;
+            Either::A(future::ok(DialPermit(self.clone())))
+        } else {
+            debug!("{} outbound dials in progress, queueing", s.in_flight);
+            let (tx, rx) = oneshot::channel();
+            s.queue.push_back(tx);
+            Either::B(rx)
+        }
+    }
+
+    /// Returns a future that runs `dial` once fewer than the maximum number of
+    /// dials are in progress.
+    ///
+    /// The dial should not open its connection until it is first polled.
+    pub(super) fn limit<F>(&self, dial: F) -> impl Future<Item = F::Item, Error = F::Error>
+    where
+        F: Future,
+    {
+        // A queued dial is always handed a permit eventually, so acquiring
+        // never fails. The permit is released once the dial completes.
+        self.acquire().then(move |permit| {
+            dial.then(move |res| {
+                drop(permit);
+                res
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+
+    use super::DialLimiter;
+
+    #[test]
+    fn concurrent_dials_capped() {
+        let limiter = DialLimiter::new(3);
+        let connecting = Arc::new(AtomicUsize::new(0));
+        let max_connecting = Arc::new(AtomicUsize::new(0));
+
+        let mut dials: Vec<_> = (0..10)
+            .map(|i| {
+                let connecting = connecting.clone();
+                let max_connecting = max_connecting.clone();
+                limiter.limit(future::lazy(move || {
+                    let now = connecting.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_connecting.fetch_max(now, Ordering::SeqCst);
+                    Delay::new(Instant::now() + Duration::from_millis(10)).then(move |_| {
+                        connecting.fetch_sub(1, Ordering::SeqCst);
+                        Ok::<_, ()>(i)
+                    })
+                }))
+            })
+            .collect();
+
+        // Dropping a queued dial doesn't hold up the rest
+        assert_eq!(limiter.0.lock().unwrap().queue.len(), 7);
+        drop(dials.remove(5));
+
+        let mut rt = Runtime::new().unwrap();
+        let done = rt.block_on(future::join_all(dials)).unwrap();
+        assert_eq!(done, vec![0, 1, 2, 3, 4, 6, 7, 8, 9]);
+        assert_eq!(max_connecting.load(Ordering::SeqCst), 3);
+        assert_eq!(limiter.0.lock().unwrap().in_flight, 0);
+    }
+}
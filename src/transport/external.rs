@@ -0,0 +1,186 @@
+//! Estimation of our external address from the addresses that peers observe
+//! us connecting from.
+//!
+//! A single peer can lie about, or be confused about, our address. We only
+//! believe an address once enough distinct peers agree on it, and they make up
+//! a majority of the peers that have recently reported an address.
+//!
+//! Peers report our address while establishing SSU sessions. When they agree
+//! on a new one, its IP address is published in the addresses of our other
+//! transports, and our RouterInfo is republished. Routers configured with an
+//! external IP address skip all of this.
+
+use futures::{sync::mpsc, Future, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::data::Hash;
+use crate::router::Context;
+
+/// Number of distinct peers that must agree on an address before we use it.
+const DEFAULT_MIN_AGREEING_PEERS: usize = 3;
+
+/// Number of peers whose most recent observation we keep.
+const MAX_OBSERVATIONS: usize = 20;
+
+/// Aggregates the addresses that peers report for us into a consensus.
+pub struct ExternalAddressEstimator {
+    min_agreeing: usize,
+    /// The most recent observation from each peer, oldest first.
+    observations: VecDeque<(Hash, SocketAddr)>,
+    current: Option<SocketAddr>,
+}
+
+impl Default for ExternalAddressEstimator {
+    fn default() -> Self {
+        ExternalAddressEstimator::new(DEFAULT_MIN_AGREEING_PEERS)
+    }
+}
+
+impl ExternalAddressEstimator {
+    pub fn new(min_agreeing: usize) -> Self {
+        ExternalAddressEstimator {
+            min_agreeing,
+            observations: VecDeque::with_capacity(MAX_OBSERVATIONS),
+            current: None,
+        }
+    }
+
+    /// Returns the current consensus on our external address, if there is one.
+    pub fn current(&self) -> Option<SocketAddr> {
+        self.current
+    }
+
+    /// Records the address that a peer observed us connecting from. A newer
+    /// observation from the same peer replaces its earlier one.
+    ///
+    /// Returns the new external address if the consensus changed.
+    pub fn observe(&mut self, peer: Hash, addr: SocketAddr) -> Option<SocketAddr> {
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            debug!("Ignoring invalid address {} reported by {}", addr, peer);
+            return None;
+        }
+
+        self.observations.retain(|(p, _)| *p != peer);
+        self.observations.push_back((peer, addr));
+        if self.observations.len() > MAX_OBSERVATIONS {
+            self.observations.pop_front();
+        }
+
+        let mut counts = HashMap::new();
+        for (_, addr) in &self.observations {
+            *counts.entry(addr).or_insert(0) += 1;
+        }
+        let (&consensus, count) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+
+        if count < self.min_agreeing
+            || count * 2 <= self.observations.len()
+            || self.current == Some(consensus)
+        {
+            return None;
+        }
+
+        info!("Peers agree that our external address is {}", consensus);
+        self.current = Some(consensus);
+        self.current
+    }
+}
+
+/// Estimates our external address from the addresses that peers report, and
+/// publishes its IP address whenever it changes.
+///
+/// The port that a peer sees is the one that was mapped for the connection the
+/// peer observed, so it isn't necessarily the port that our other transports
+/// are reachable on, and isn't published.
+pub(super) fn track(
+    ctx: Arc<Context>,
+    observed: mpsc::UnboundedReceiver<(Hash, SocketAddr)>,
+) -> impl Future<Item = (), Error = ()> {
+    let mut estimator = ExternalAddressEstimator::default();
+    observed.for_each(move |(peer, addr)| {
+        if let Some(addr) = estimator.observe(peer, addr) {
+            let addresses = {
+                let mut comms = ctx.comms.write().unwrap();
+                comms.set_external_ip(addr.ip());
+                comms.addresses()
+            };
+            if let Err(e) = ctx.update_router_info(addresses) {
+                error!("Failed to save our updated RouterInfo: {}", e);
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Future};
+    use std::net::SocketAddr;
+
+    use super::{track, ExternalAddressEstimator};
+    use crate::data::Hash;
+    use crate::router::mock::{mock_context, MOCK_PORT};
+
+    #[test]
+    fn consensus() {
+        let a: SocketAddr = "203.0.113.1:12345".parse().unwrap();
+        let b: SocketAddr = "203.0.113.2:12345".parse().unwrap();
+        let c: SocketAddr = "203.0.113.3:12345".parse().unwrap();
+        let peer = |i: u8| Hash([i; 32]);
+
+        let mut estimator = ExternalAddressEstimator::new(3);
+        assert_eq!(estimator.current(), None);
+
+        // Repeated observations from one peer don't count
+        assert_eq!(estimator.observe(peer(1), a), None);
+        assert_eq!(estimator.observe(peer(1), a), None);
+        assert_eq!(estimator.observe(peer(2), a), None);
+        assert_eq!(estimator.current(), None);
+
+        // A third peer agrees
+        assert_eq!(estimator.observe(peer(3), a), Some(a));
+        assert_eq!(estimator.current(), Some(a));
+        assert_eq!(estimator.observe(peer(4), a), None);
+
+        // Disagreeing peers that aren't a majority are ignored
+        assert_eq!(estimator.observe(peer(5), b), None);
+        assert_eq!(estimator.observe(peer(6), c), None);
+        assert_eq!(estimator.observe(peer(7), b), None);
+        assert_eq!(estimator.observe(peer(8), b), None);
+        assert_eq!(estimator.current(), Some(a));
+
+        // Invalid addresses are ignored
+        assert_eq!(
+            estimator.observe(peer(9), "0.0.0.0:12345".parse().unwrap()),
+            None
+        );
+
+        // Once most peers see a new address, we switch to it
+        assert_eq!(estimator.observe(peer(1), b), None);
+        assert_eq!(estimator.observe(peer(2), b), Some(b));
+        assert_eq!(estimator.current(), Some(b));
+    }
+
+    #[test]
+    fn publish_consensus() {
+        let ctx = mock_context();
+        let addr: SocketAddr = "203.0.113.1:23456".parse().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        for i in 1..=3 {
+            tx.unbounded_send((Hash([i; 32]), addr)).unwrap();
+        }
+        drop(tx);
+        track(ctx.clone(), rx).wait().unwrap();
+
+        // Our RouterInfo has the new IP address, with our own port, and is
+        // still validly signed
+        let ri = ctx.published_router_info();
+        assert_eq!(ri.addresses().len(), 1);
+        assert_eq!(
+            ri.addresses()[0].addr(),
+            Some(SocketAddr::new(addr.ip(), MOCK_PORT))
+        );
+        assert!(ri.verify().is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+//! Restrictions on the peers we communicate with.
+
+use config::ConfigError;
+use std::collections::HashSet;
+
+use crate::constants::I2P_BASE64;
+use crate::data::Hash;
+use crate::router::config::{self, Config};
+
+/// Decides which peers we are willing to communicate with.
+///
+/// Peers on the blocklist are always refused. If there is an allowlist, peers
+/// that aren't on it are also refused.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerFilter {
+    allow: Option<HashSet<Hash>>,
+    block: HashSet<Hash>,
+}
+
+impl PeerFilter {
+    pub fn new(allow: Option<HashSet<Hash>>, block: HashSet<Hash>) -> Self {
+        PeerFilter { allow, block }
+    }
+
+    /// Reads the allowlist and blocklist from the router config.
+    ///
+    /// Returns an error if either list contains something that isn't a router
+    /// hash.
+    pub(crate) fn from_config(config: &Config) -> Result<Self, config::Error> {
+        let hashes = |key: &'static str| -> Result<Option<HashSet<Hash>>, config::Error> {
+            let values = match config.get_array(key) {
+                Ok(values) => values,
+                Err(ConfigError::NotFound(_)) => return Ok(None),
+                Err(_) => {
+                    return Err(config::Error::InvalidValue(
+                        key,
+                        config.get_str(key).unwrap_or_default(),
+                    ))
+                }
+            };
+            values
+                .into_iter()
+                .map(|value| {
+                    let value = value.into_str().unwrap_or_default();
+                    match I2P_BASE64.decode(value.as_bytes()) {
+                        Ok(ref hash) if hash.len() == 32 => {
+                            Ok(Hash::from_bytes(array_ref![hash, 0, 32]))
+                        }
+                        _ => Err(config::Error::InvalidValue(key, value)),
+                    }
+                })
+                .collect::<Result<_, _>>()
+                .map(Some)
+        };
+
+        Ok(PeerFilter {
+            allow: hashes(config::TRANSPORT_ALLOWLIST)?,
+            block: hashes(config::TRANSPORT_BLOCKLIST)?.unwrap_or_default(),
+        })
+    }
+
+    /// Returns whether we may communicate with the given peer.
+    pub fn permits(&self, peer: &Hash) -> bool {
+        !self.block.contains(peer)
+            && self
+                .allow
+                .as_ref()
+                .map(|allow| allow.contains(peer))
+                .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::once;
+
+    use super::PeerFilter;
+    use crate::data::Hash;
+    use crate::router::config::{self, Config};
+
+    #[test]
+    fn permits() {
+        let a = Hash([1; 32]);
+        let b = Hash([2; 32]);
+
+        let filter = PeerFilter::default();
+        assert!(filter.permits(&a));
+        assert!(filter.permits(&b));
+
+        let filter = PeerFilter::new(None, once(a.clone()).collect());
+        assert!(!filter.permits(&a));
+        assert!(filter.permits(&b));
+
+        let filter = PeerFilter::new(Some(once(a.clone()).collect()), Default::default());
+        assert!(filter.permits(&a));
+        assert!(!filter.permits(&b));
+
+        // The blocklist wins
+        let filter = PeerFilter::new(Some(once(a.clone()).collect()), once(a.clone()).collect());
+        assert!(!filter.permits(&a));
+    }
+
+    #[test]
+    fn from_config() {
+        let a = Hash([1; 32]);
+        let b = Hash([2; 32]);
+
+        let mut cfg = Config::default();
+        assert_eq!(PeerFilter::from_config(&cfg), Ok(PeerFilter::default()));
+
+        cfg.set(config::TRANSPORT_ALLOWLIST, vec![a.to_string()])
+            .unwrap();
+        cfg.set(config::TRANSPORT_BLOCKLIST, vec![b.to_string()])
+            .unwrap();
+        assert_eq!(
+            PeerFilter::from_config(&cfg),
+            Ok(PeerFilter::new(Some(once(a).collect()), once(b).collect()))
+        );
+
+        // Invalid hashes are rejected instead of ignored
+        cfg.set(config::TRANSPORT_BLOCKLIST, vec!["not-a-hash"])
+            .unwrap();
+        assert_eq!(
+            PeerFilter::from_config(&cfg),
+            Err(config::Error::InvalidValue(
+                config::TRANSPORT_BLOCKLIST,
+                "not-a-hash".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Limits on inbound handshakes from each IP address.
+//!
+//! Responding to a SessionRequest costs us a Diffie-Hellman operation before
+//! we know who the peer is, so a single host opening connections in a loop can
+//! keep us busy doing handshakes. Before starting an inbound handshake, we
+//! check how many handshakes the connecting IP address has started recently,
+//! and how many of them are still in progress, and drop the handshake if
+//! either is over its limit.
+//!
+//! A single host is usually assigned a whole IPv6 /64, so handshakes from
+//! IPv6 addresses are counted per /64.
+
+use futures::{Future, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+/// Default number of handshakes an IP address may start within the window.
+const DEFAULT_MAX_HANDSHAKES: usize = 30;
+
+/// Default number of handshakes from an IP address that may be in progress at
+/// once.
+const DEFAULT_MAX_CONCURRENT: usize = 5;
+
+/// Default length of the window over which handshakes are counted.
+const DEFAULT_HANDSHAKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often we forget addresses that have no recent handshakes.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct Handshakes {
+    started: VecDeque<Instant>,
+    in_progress: usize,
+}
+
+impl Handshakes {
+    /// Forgets the handshakes that were started before the window.
+    fn trim(&mut self, window: Duration) {
+        while let Some(t) = self.started.front() {
+            if t.elapsed() < window {
+                break;
+            }
+            self.started.pop_front();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.in_progress == 0 && self.started.is_empty()
+    }
+}
+
+/// Returns the address that handshakes from `ip` are counted under.
+fn source(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                // IPv4-mapped, from a dual-stack socket
+                IpAddr::V4(ip.to_ipv4().unwrap())
+            } else {
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (!0 << 64)))
+            }
+        }
+    }
+}
+
+type State = Arc<Mutex<HashMap<IpAddr, Handshakes>>>;
+
+/// Counts recent and in-progress inbound handshakes from each IP address.
+#[derive(Debug)]
+pub struct HandshakeLimiter {
+    max: usize,
+    max_concurrent: usize,
+    window: Duration,
+    state: State,
+}
+
+impl Default for HandshakeLimiter {
+    fn default() -> Self {
+        HandshakeLimiter::new(
+            DEFAULT_MAX_HANDSHAKES,
+            DEFAULT_MAX_CONCURRENT,
+            DEFAULT_HANDSHAKE_WINDOW,
+        )
+    }
+}
+
+/// Marks a handshake as in progress. It is counted until this is dropped.
+pub(super) struct HandshakePermit {
+    ip: IpAddr,
+    state: State,
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        let remove = match state.get_mut(&self.ip) {
+            Some(handshakes) => {
+                handshakes.in_progress -= 1;
+                handshakes.is_empty()
+            }
+            None => false,
+        };
+        if remove {
+            state.remove(&self.ip);
+        }
+    }
+}
+
+impl HandshakeLimiter {
+    /// Allows each IP address to start at most `max` handshakes within any
+    /// `window`, and to have at most `max_concurrent` in progress at once.
+    pub fn new(max: usize, max_concurrent: usize, window: Duration) -> Self {
+        HandshakeLimiter {
+            max,
+            max_concurrent,
+            window,
+            state: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Sets the number of handshakes each IP address may start within the
+    /// window.
+    pub fn with_max_handshakes(mut self, max: usize) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// Sets the number of handshakes from each IP address that may be in
+    /// progress at once.
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = max_concurrent;
+        self
+    }
+
+    /// Records the start of a handshake from the given IP address.
+    ///
+    /// Returns `None`, without recording it, if the address has too many
+    /// handshakes in progress or has started too many within the window.
+    pub(super) fn permit(&self, ip: IpAddr) -> Option<HandshakePermit> {
+        let ip = source(ip);
+        let mut state = self.state.lock().unwrap();
+        let handshakes = state.entry(ip).or_default();
+        handshakes.trim(self.window);
+        if handshakes.in_progress >= self.max_concurrent || handshakes.started.len() >= self.max {
+            if handshakes.is_empty() {
+                // Only possible if a limit is zero
+                state.remove(&ip);
+            }
+            return None;
+        }
+        handshakes.started.push_back(Instant::now());
+        handshakes.in_progress += 1;
+        Some(HandshakePermit {
+            ip,
+            state: self.state.clone(),
+        })
+    }
+
+    /// Forgets the addresses that have no handshakes in progress, and haven't
+    /// started any within the window.
+    fn prune(&self) {
+        let window = self.window;
+        self.state.lock().unwrap().retain(|_, handshakes| {
+            handshakes.trim(window);
+            !handshakes.is_empty()
+        });
+    }
+}
+
+/// Returns a future that prunes the given limiter on a timer. It only
+/// completes if the timer fails.
+pub(super) fn prune_on_timer(
+    limiter: Arc<HandshakeLimiter>,
+) -> impl Future<Item = (), Error = io::Error> {
+    Interval::new_interval(PRUNE_INTERVAL)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .for_each(move |_| {
+            limiter.prune();
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::HandshakeLimiter;
+
+    #[test]
+    fn concurrent_handshakes() {
+        let a = "127.0.0.1".parse().unwrap();
+        let b = "::1".parse().unwrap();
+        let limiter = HandshakeLimiter::new(10, 2, Duration::from_secs(60));
+
+        let first = limiter.permit(a).unwrap();
+        let _second = limiter.permit(a).unwrap();
+        assert!(limiter.permit(a).is_none());
+
+        // Other addresses are counted separately
+        assert!(limiter.permit(b).is_some());
+
+        // Finishing a handshake frees a slot
+        drop(first);
+        assert!(limiter.permit(a).is_some());
+    }
+
+    #[test]
+    fn handshake_rate() {
+        let a = "127.0.0.1".parse().unwrap();
+        let limiter = HandshakeLimiter::new(2, 10, Duration::from_millis(50));
+
+        assert!(limiter.permit(a).is_some());
+        assert!(limiter.permit(a).is_some());
+        assert!(limiter.permit(a).is_none());
+
+        // Handshakes older than the window are forgotten
+        thread::sleep(Duration::from_millis(60));
+        assert!(limiter.permit(a).is_some());
+        thread::sleep(Duration::from_millis(60));
+        assert!(limiter.permit(a).is_some());
+        assert_eq!(limiter.state.lock().unwrap().len(), 1);
+
+        // And so is the address, once it is pruned
+        limiter.prune();
+        assert_eq!(limiter.state.lock().unwrap().len(), 1);
+        thread::sleep(Duration::from_millis(60));
+        limiter.prune();
+        assert!(limiter.state.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ipv6_prefix() {
+        let limiter = HandshakeLimiter::new(2, 10, Duration::from_secs(60));
+
+        // Addresses in the same /64 share a limit
+        assert!(limiter.permit("2001:db8::1".parse().unwrap()).is_some());
+        assert!(limiter.permit("2001:db8::2:3".parse().unwrap()).is_some());
+        assert!(limiter.permit("2001:db8::ffff:4".parse().unwrap()).is_none());
+        assert!(limiter.permit("2001:db8:0:1::1".parse().unwrap()).is_some());
+
+        // IPv4-mapped addresses are counted as IPv4
+        assert!(limiter.permit("::ffff:192.0.2.1".parse().unwrap()).is_some());
+        assert!(limiter.permit("192.0.2.1".parse().unwrap()).is_some());
+        assert!(limiter.permit("::ffff:192.0.2.1".parse().unwrap()).is_none());
+        assert!(limiter.permit("::ffff:192.0.2.2".parse().unwrap()).is_some());
+    }
+}
@@ -1,30 +1,58 @@
 //! Transports used for point-to-point communication between I2P routers.
 
-use futures::{future::lazy, Future, Poll, Sink, StartSend};
-use std::iter::once;
+use futures::{
+    future::{join_all, lazy},
+    sync::{mpsc, oneshot},
+    Future, Poll, Sink, StartSend,
+};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{executor::spawn, io};
 
 use crate::crypto::dh::DHSessionKeyBuilder;
-use crate::data::{Hash, RouterAddress, RouterInfo};
+use crate::data::{Hash, I2PString, RouterAddress, RouterInfo};
 use crate::i2np::Message;
 use crate::router::{
     config,
-    types::{CommSystem, Distributor},
+    types::{CommSystem, Distributor, DistributorResult},
     Context,
 };
-
+use crate::util::DEFAULT_MAX_FUTURE_SKEW;
+
+mod bandwidth;
+mod breaker;
+mod counter;
+mod dial;
+mod external;
+mod filter;
+mod limiter;
 pub mod ntcp;
 pub mod ntcp2;
+mod penalty;
+mod reconnect;
 mod session;
+mod socks;
+pub mod ssu;
+pub mod ssu2;
+mod stats;
+
+pub use self::external::ExternalAddressEstimator;
+pub use self::filter::PeerFilter;
+pub use self::limiter::HandshakeLimiter;
+pub use self::reconnect::ReconnectLimiter;
+pub use self::session::{DialObserver, Direction, UnknownMessagePolicy};
+pub use self::stats::{Throughput, TrafficSummary};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
 /// A bid from a transport indicating how much it thinks it will "cost" to
 /// send a particular message.
-struct Bid {
-    bid: u32,
-    sink: Box<dyn Sink<SinkItem = (RouterInfo, Message), SinkError = io::Error> + Send>,
+pub struct Bid {
+    /// Lower bids are preferred.
+    pub bid: u32,
+    /// Sends the message, connecting to the peer first if necessary.
+    pub sink: Box<dyn Sink<SinkItem = (RouterInfo, Message), SinkError = io::Error> + Send>,
 }
 
 impl Sink for Bid {
@@ -43,21 +71,106 @@ impl Sink for Bid {
     }
 }
 
+/// A [`Distributor`] that isn't generic over the router's distributor type,
+/// for transports that are implemented outside this crate.
+#[derive(Clone)]
+pub struct SharedDistributor(Arc<dyn Fn(Hash, Message) -> DistributorResult + Send + Sync>);
+
+impl SharedDistributor {
+    pub fn new<D: Distributor>(distributor: D) -> Self {
+        SharedDistributor(Arc::new(move |from, msg| distributor.handle(from, msg)))
+    }
+}
+
+impl Distributor for SharedDistributor {
+    fn handle(&self, from: Hash, msg: Message) -> DistributorResult {
+        (self.0)(from, msg)
+    }
+}
+
+/// Creates a transport that passes the messages it receives to the given
+/// distributor.
+pub type TransportFactory = Box<dyn FnOnce(SharedDistributor) -> Box<dyn Transport> + Send>;
+
 /// Coordinates the sending and receiving of frames over the various supported
 /// transports.
-pub struct Manager<D: Distributor> {
-    ntcp: ntcp::Manager<D>,
-    ntcp2: ntcp2::Manager<D>,
+pub struct Manager {
+    transports: Vec<Box<dyn Transport>>,
+    breaker: breaker::CircuitBreaker,
+    penalties: penalty::BidPenalties,
+    peer_filter: Arc<PeerFilter>,
+    traffic: stats::TrafficStats,
+    /// The addresses that peers see us at, if we are detecting our external
+    /// address.
+    observed_addrs: Option<mpsc::UnboundedReceiver<(Hash, SocketAddr)>>,
+    /// Stops the listeners when sent to, or dropped.
+    stop: Option<oneshot::Sender<()>>,
 }
 
-trait Transport {
+/// A way of communicating with other routers, such as NTCP2.
+///
+/// Transports are registered with a [`Manager`], which asks each of them to
+/// bid on every outbound message and sends it with the lowest bidder. A
+/// transport that fails to send to a peer has its bids for that peer raised
+/// for a while, so that other transports are preferred.
+///
+/// Transports can also be implemented outside this crate, and registered with
+/// [`Builder::transport`](crate::router::Builder::transport). Such a transport
+/// opens outbound sessions from the sinks in its bids, accepts inbound sessions
+/// in [`Transport::listen`], passes every message it receives to the
+/// [`SharedDistributor`] it was created with, and has its addresses published
+/// in our RouterInfo.
+pub trait Transport: Send + Sync {
+    /// Returns the style of the address that this transport listens on.
+    fn style(&self) -> &I2PString;
+
+    /// Returns the address that this transport listens on.
+    fn address(&self) -> RouterAddress;
+
+    /// Returns every address that this transport listens on, for transports
+    /// that listen on more than one (for example, on both IPv4 and IPv6).
+    fn addresses(&self) -> Vec<RouterAddress> {
+        vec![self.address()]
+    }
+
+    /// Starts accepting connections from peers.
+    ///
+    /// Returns a future that runs the listener.
+    fn listen(&mut self, ctx: Arc<Context>)
+        -> Box<dyn Future<Item = (), Error = io::Error> + Send>;
+
     fn is_established(&self, hash: &Hash) -> bool;
 
-    fn bid(&self, peer: &RouterInfo, msg_size: usize) -> Option<Bid>;
+    /// Returns the peers this transport has open sessions with.
+    fn sessions(&self) -> Vec<(Hash, Direction)>;
+
+    /// Replaces the keys that this transport publishes in its address, if it
+    /// has any. Established sessions are unaffected.
+    fn rotate_keys(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Publishes `ip` as the IP address that peers can reach this transport
+    /// at, for transports whose addresses contain one.
+    fn set_external_ip(&mut self, _ip: IpAddr) {}
+
+    /// Sets the observer that this transport tells whether each of its
+    /// connections to peers succeeds, so that peers and transports that keep
+    /// failing can be avoided.
+    fn set_dial_observer(&mut self, _observer: DialObserver) {}
+
+    /// Closes every session once the messages queued for it have been sent.
+    ///
+    /// Returns a future that resolves once all sessions have ended.
+    fn close_sessions(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Returns a bid for sending the message to the peer, or `None` if this
+    /// transport can't reach the peer or carry the message.
+    fn bid(&self, peer: &RouterInfo, msg: &Message) -> Option<Bid>;
 }
 
-impl<D: Distributor> Manager<D> {
-    pub fn from_config(config: &config::Config, distributor: D) -> Self {
+impl Manager {
+    pub fn from_config<D: Distributor>(config: &config::Config, distributor: D) -> Self {
         let ntcp_addr = config
             .get_str(config::NTCP_LISTEN)
             .expect("Must configure an NTCP address")
@@ -69,82 +182,362 @@ impl<D: Distributor> Manager<D> {
             .parse()
             .unwrap();
         let ntcp2_keyfile = config.get_str(config::NTCP2_KEYFILE).unwrap();
-
-        let ntcp_manager = ntcp::Manager::new(ntcp_addr, distributor.clone());
-        let ntcp2_manager =
+        let ntcp2_idle = ntcp2::IdleConfig {
+            timeout: match config.get_int(config::NTCP2_IDLE_TIMEOUT) {
+                Ok(0) => None,
+                Ok(secs) => Some(Duration::from_secs(secs as u64)),
+                Err(_) => ntcp2::IdleConfig::default().timeout,
+            },
+            keepalive: config
+                .get_int(config::NTCP2_KEEPALIVE)
+                .ok()
+                .map(|secs| Duration::from_secs(secs as u64)),
+        };
+        // Checked by config::Validate
+        let ntcp2_cipher_suite: ntcp2::CipherSuite = config
+            .get_str(config::NTCP2_CIPHER_SUITE)
+            .ok()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or_default();
+        // Checked by config::Validate
+        let ntcp2_handshake_timeout = config
+            .get_int(config::NTCP2_HANDSHAKE_TIMEOUT)
+            .ok()
+            .map(|secs| Duration::from_secs(secs as u64));
+        // Checked by config::Validate
+        let ntcp2_handshake_step_timeout = config
+            .get_int(config::NTCP2_HANDSHAKE_STEP_TIMEOUT)
+            .ok()
+            .map(|secs| Duration::from_secs(secs as u64));
+        let ntcp2_handshake_trace = config
+            .get_bool(config::NTCP2_HANDSHAKE_TRACE)
+            .unwrap_or(false);
+        let ntcp2_addr_v6 = config
+            .get_str(config::NTCP2_LISTEN_V6)
+            .ok()
+            .map(|addr| addr.parse().unwrap());
+        let ntcp2_prefer_ipv6 = config
+            .get_bool(config::NTCP2_PREFER_IPV6)
+            .unwrap_or(false);
+        // Checked by config::Validate
+        let ntcp2_external_ip: Option<IpAddr> = config
+            .get_str(config::NTCP2_EXTERNAL_IP)
+            .ok()
+            .and_then(|ip| ip.parse().ok());
+        // Checked by config::Validate
+        let ntcp2_socks_proxy: Option<SocketAddr> = config
+            .get_str(config::NTCP2_SOCKS_PROXY)
+            .ok()
+            .and_then(|addr| addr.parse().ok());
+        let proxied = ntcp2_socks_proxy.is_some();
+        // Checked by config::Validate
+        let ntcp2_max_past_skew = config
+            .get_int(config::NTCP2_MAX_PAST_SKEW)
+            .ok()
+            .map(|secs| Duration::from_secs(secs as u64));
+        // Checked by config::Validate
+        let ntcp2_padding = {
+            let default = ntcp2::PaddingConfig::default();
+            ntcp2::PaddingConfig {
+                handshake_max: config
+                    .get_int(config::NTCP2_MAX_HANDSHAKE_PADDING)
+                    .map(|max| max as u16)
+                    .unwrap_or(default.handshake_max),
+                min_ratio: config
+                    .get_float(config::NTCP2_MIN_PADDING_RATIO)
+                    .unwrap_or(default.min_ratio),
+                max_ratio: config
+                    .get_float(config::NTCP2_MAX_PADDING_RATIO)
+                    .unwrap_or(default.max_ratio),
+                max_received_ratio: config
+                    .get_float(config::NTCP2_MAX_RECEIVED_PADDING_RATIO)
+                    .unwrap_or(default.max_received_ratio),
+            }
+        };
+        // Checked by config::Validate
+        let max_future_skew = config
+            .get_int(config::MAX_FUTURE_SKEW)
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(DEFAULT_MAX_FUTURE_SKEW);
+        // Checked by config::Validate
+        let peer_filter = Arc::new(PeerFilter::from_config(config).unwrap_or_default());
+        // Checked by config::Validate
+        let dial_limiter = config
+            .get_int(config::TRANSPORT_MAX_OUTBOUND_DIALS)
+            .map(|max| dial::DialLimiter::new(max as usize))
+            .unwrap_or_default();
+        // Checked by config::Validate
+        let unknown_messages: UnknownMessagePolicy = config
+            .get_str(config::TRANSPORT_UNKNOWN_MESSAGES)
+            .ok()
+            .and_then(|policy| policy.parse().ok())
+            .unwrap_or_default();
+        // Checked by config::Validate
+        let reconnect_limiter = config
+            .get_int(config::NTCP2_MAX_RECONNECTS)
+            .map(|max| ReconnectLimiter::new(max as usize, reconnect::DEFAULT_RECONNECT_WINDOW))
+            .unwrap_or_default();
+        // Checked by config::Validate
+        let mut ntcp2_handshake_limiter = HandshakeLimiter::default();
+        if let Ok(max) = config.get_int(config::NTCP2_MAX_HANDSHAKES_PER_IP) {
+            ntcp2_handshake_limiter = ntcp2_handshake_limiter.with_max_handshakes(max as usize);
+        }
+        if let Ok(max) = config.get_int(config::NTCP2_MAX_CONCURRENT_HANDSHAKES_PER_IP) {
+            ntcp2_handshake_limiter = ntcp2_handshake_limiter.with_max_concurrent(max as usize);
+        }
+        let traffic = stats::TrafficStats::default();
+        let bandwidth = bandwidth::BandwidthLimiter::from_config(config);
+
+        let mut ntcp_manager = ntcp::Manager::new(ntcp_addr, distributor.clone());
+        ntcp_manager.set_dial_limiter(dial_limiter.clone());
+        ntcp_manager.set_unknown_message_policy(unknown_messages);
+        ntcp_manager.set_traffic_stats(traffic.clone());
+        ntcp_manager.set_bandwidth_limiter(bandwidth.clone());
+        ntcp_manager.set_peer_filter(peer_filter.clone());
+        // Only NTCP2 can connect through the proxy
+        if proxied {
+            ntcp_manager.disable_direct_dials();
+        }
+        let mut ntcp2_manager =
             match ntcp2::Manager::from_file(ntcp2_addr, &ntcp2_keyfile, distributor.clone()) {
                 Ok(ret) => ret,
                 Err(_) => {
-                    let ntcp2_manager = ntcp2::Manager::new(ntcp2_addr, distributor);
+                    let ntcp2_manager = ntcp2::Manager::new(ntcp2_addr, distributor.clone());
                     ntcp2_manager.to_file(&ntcp2_keyfile).unwrap();
                     ntcp2_manager
                 }
             };
-        Manager {
-            ntcp: ntcp_manager,
-            ntcp2: ntcp2_manager,
+        ntcp2_manager.set_keyfile(&ntcp2_keyfile);
+        ntcp2_manager.set_unknown_message_policy(unknown_messages);
+        ntcp2_manager.set_traffic_stats(traffic.clone());
+        ntcp2_manager.set_bandwidth_limiter(bandwidth.clone());
+        ntcp2_manager.set_idle_config(ntcp2_idle);
+        ntcp2_manager.set_cipher_suite(ntcp2_cipher_suite);
+        ntcp2_manager.set_max_future_skew(max_future_skew);
+        if let Some(max_past_skew) = ntcp2_max_past_skew {
+            ntcp2_manager.set_max_past_skew(max_past_skew);
+        }
+        if let Some(timeout) = ntcp2_handshake_timeout {
+            ntcp2_manager.set_handshake_timeout(Some(timeout));
         }
+        ntcp2_manager.set_handshake_step_timeout(ntcp2_handshake_step_timeout);
+        ntcp2_manager.set_handshake_trace(ntcp2_handshake_trace);
+        if let Some(addr) = ntcp2_addr_v6 {
+            ntcp2_manager.set_ipv6_addr(addr);
+        }
+        if let Some(ip) = ntcp2_external_ip {
+            ntcp2_manager.set_external_ip(ip);
+        }
+        ntcp2_manager.set_prefer_ipv6(ntcp2_prefer_ipv6);
+        ntcp2_manager.set_socks_proxy(ntcp2_socks_proxy);
+        ntcp2_manager.set_padding_config(ntcp2_padding);
+        ntcp2_manager.set_peer_filter(peer_filter.clone());
+
+        ntcp2_manager.set_reconnect_limiter(Arc::new(reconnect_limiter));
+        ntcp2_manager.set_handshake_limiter(Arc::new(ntcp2_handshake_limiter));
+        ntcp2_manager.set_dial_limiter(dial_limiter);
+
+        let mut transports: Vec<Box<dyn Transport>> =
+            vec![Box::new(ntcp_manager), Box::new(ntcp2_manager)];
+        let mut observed_addrs = None;
+
+        // SSU is only enabled if it is configured
+        if let Ok(ssu_addr) = config.get_str(config::SSU_LISTEN) {
+            let ssu_addr = ssu_addr.parse().unwrap();
+            let ssu_keyfile = config
+                .get_str(config::SSU_KEYFILE)
+                .unwrap_or_else(|_| "ssu.keys.dat".to_owned());
+            let mut ssu_manager =
+                match ssu::Manager::from_file(ssu_addr, &ssu_keyfile, distributor.clone()) {
+                    Ok(ret) => ret,
+                    Err(_) => {
+                        let ssu_manager = ssu::Manager::new(ssu_addr, distributor);
+                        ssu_manager.to_file(&ssu_keyfile).unwrap();
+                        ssu_manager
+                    }
+                };
+            ssu_manager.set_unknown_message_policy(unknown_messages);
+            ssu_manager.set_traffic_stats(traffic.clone());
+            ssu_manager.set_bandwidth_limiter(bandwidth);
+            ssu_manager.set_peer_filter(peer_filter.clone());
+            ssu_manager.set_introducer(config.get_bool(config::SSU_INTRODUCER).unwrap_or(false));
+            if proxied {
+                ssu_manager.disable_direct_dials();
+            }
+
+            // Learn our external address from SSU peers, unless it is configured.
+            // If we are hiding behind a proxy, we must not publish the address
+            // that our direct connections come from.
+            if ntcp2_external_ip.is_none() && !proxied {
+                let (tx, rx) = mpsc::unbounded();
+                ssu_manager.set_address_observer(tx);
+                observed_addrs = Some(rx);
+            }
+            transports.push(Box::new(ssu_manager));
+        }
+
+        let mut manager = Manager {
+            transports: vec![],
+            breaker: breaker::CircuitBreaker::new(
+                breaker::DEFAULT_FAILURE_THRESHOLD,
+                Duration::from_secs(breaker::DEFAULT_COOLDOWN),
+            ),
+            penalties: penalty::BidPenalties::new(Duration::from_secs(
+                penalty::DEFAULT_PENALTY_DURATION,
+            )),
+            peer_filter,
+            traffic,
+            observed_addrs,
+            stop: None,
+        };
+        for transport in transports {
+            manager.register(transport);
+        }
+        manager
+    }
+
+    /// Adds a transport, which will be started along with the others.
+    ///
+    /// Transports should be registered before the router starts.
+    pub fn register(&mut self, mut transport: Box<dyn Transport>) {
+        let breaker = self.breaker.clone();
+        let penalties = self.penalties.clone();
+        let index = self.transports.len();
+        transport.set_dial_observer(Arc::new(move |peer: &Hash, success| {
+            breaker.record(peer.clone(), success);
+            penalties.record(peer.clone(), index, success);
+        }));
+        self.transports.push(transport);
     }
 }
 
-impl<D: Distributor> CommSystem for Manager<D> {
+impl CommSystem for Manager {
     fn addresses(&self) -> Vec<RouterAddress> {
-        vec![self.ntcp.address(), self.ntcp2.address()]
+        self.transports.iter().flat_map(|t| t.addresses()).collect()
     }
 
     fn start(&mut self, ctx: Arc<Context>) -> Box<dyn Future<Item = (), Error = ()> + Send> {
-        self.ntcp.set_context(ctx.clone());
-        self.ntcp2.set_context(ctx.clone());
-
-        let listener = self
-            .ntcp
-            .listen(ctx.keys.rid.clone(), ctx.keys.signing_private_key.clone())
-            .map_err(|e| {
-                error!("NTCP listener error: {}", e);
-            });
-
-        let listener2 = self.ntcp2.listen(&ctx.keys.rid).map_err(|e| {
-            error!("NTCP2 listener error: {}", e);
-        });
-
-        Box::new(lazy(|| {
-            spawn(listener);
-            spawn(listener2);
+        let listeners: Vec<_> = self
+            .transports
+            .iter_mut()
+            .map(|t| {
+                let style = t.style().0.clone();
+                t.listen(ctx.clone()).map_err(move |e| {
+                    error!("{} listener error: {}", style, e);
+                })
+            })
+            .collect();
+
+        let external = self
+            .observed_addrs
+            .take()
+            .map(|observed| external::track(ctx.clone(), observed));
+
+        let (stop, stopped) = oneshot::channel();
+        self.stop = Some(stop);
+        let stopped = stopped.shared();
+
+        Box::new(lazy(move || {
+            for listener in listeners {
+                spawn(listener.select2(stopped.clone()).then(|_| Ok(())));
+            }
+            if let Some(external) = external {
+                spawn(external.select2(stopped).then(|_| Ok(())));
+            }
             Ok(())
         }))
     }
 
     fn is_established(&self, hash: &Hash) -> bool {
-        self.ntcp.is_established(hash) || self.ntcp2.is_established(hash)
+        self.transports.iter().any(|t| t.is_established(hash))
+    }
+
+    fn sessions(&self) -> Vec<(Hash, Direction)> {
+        self.transports.iter().flat_map(|t| t.sessions()).collect()
+    }
+
+    fn rotate_keys(&self, style: &I2PString) -> io::Result<()> {
+        match self.transports.iter().find(|t| t.style() == style) {
+            Some(transport) => transport.rotate_keys(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No {} transport", style.0),
+            )),
+        }
+    }
+
+    fn set_external_ip(&mut self, ip: IpAddr) {
+        for transport in &mut self.transports {
+            transport.set_external_ip(ip);
+        }
+    }
+
+    fn traffic(&self) -> TrafficSummary {
+        self.traffic.summary()
+    }
+
+    fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            info!("No longer accepting connections");
+            let _ = stop.send(());
+        }
+    }
+
+    fn shutdown(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(
+            join_all(
+                self.transports
+                    .iter_mut()
+                    .map(|t| t.close_sessions())
+                    .collect::<Vec<_>>(),
+            )
+            .map(|_| ()),
+        )
     }
 
     /// Send an I2NP message to a peer over one of our transports.
     ///
     /// Returns an Err giving back the message if it cannot be sent over any of
-    /// our transports.
+    /// our transports, if the peer is not permitted by our peer filter, or if
+    /// sends to the peer have been failing and we are backing off.
     fn send(&self, peer: RouterInfo, msg: Message) -> Result<IoFuture<()>, (RouterInfo, Message)> {
-        match once(self.ntcp.bid(&peer, msg.size()))
-            .chain(once(self.ntcp2.bid(&peer, msg.ntcp2_size())))
-            .filter_map(|b| b)
-            .min_by_key(|b| b.bid)
-        {
-            Some(bid) => Ok(Box::new(bid.send((peer, msg)).map(|_| ()).map_err(|_| {
-                io::Error::new(io::ErrorKind::Other, "Error in transport::Engine")
-            }))),
-            None => Err((peer, msg)),
+        if !self.peer_filter.permits(&peer.router_id.hash()) {
+            debug!("Not sending to filtered peer {}", peer.router_id.hash());
+            return Err((peer, msg));
         }
+
+        self.breaker.send(peer, msg, |peer, msg| {
+            let hash = peer.router_id.hash();
+            match self
+                .transports
+                .iter()
+                .enumerate()
+                .filter_map(|(i, t)| t.bid(&peer, &msg).map(|b| (i, b)))
+                .min_by_key(|(i, b)| b.bid.saturating_add(self.penalties.penalty(&hash, *i)))
+            {
+                Some((i, bid)) => {
+                    let penalties = self.penalties.clone();
+                    Ok(Box::new(bid.send((peer, msg)).map(|_| ()).map_err(move |_| {
+                        penalties.record(hash, i, false);
+                        io::Error::new(io::ErrorKind::Other, "Error in transport::Engine")
+                    })))
+                }
+                None => Err((peer, msg)),
+            }
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use futures::Async;
+    use futures::{future, sync::mpsc, Async, Stream};
     use std::net::SocketAddr;
     use std::sync::{Arc, Mutex};
     use tempfile::tempdir;
     use tokio::io::{self, AsyncRead, AsyncWrite, Read, Write};
 
     use super::*;
+    use crate::data::RouterSecretKeys;
     use crate::router::mock::MockDistributor;
 
     pub struct NetworkCable {
@@ -283,4 +676,224 @@ mod tests {
         assert_eq!(addrs[0].addr(), Some(ntcp_addr));
         assert_eq!(addrs[1].addr(), Some(ntcp2_addr));
     }
+
+    #[test]
+    fn manager_skips_blocklisted_peers() {
+        let dir = tempdir().unwrap();
+        let ntcp2_keyfile = dir.path().join("test.ntcp2.keys.dat");
+
+        let rsk = RouterSecretKeys::new();
+        let mut peer = RouterInfo::new(rsk.rid);
+        peer.set_addresses(vec![RouterAddress::new(
+            &ntcp::NTCP_STYLE,
+            "127.0.0.1:12345".parse().unwrap(),
+        )]);
+        peer.sign(&rsk.signing_private_key);
+
+        let mut config = config::Config::default();
+        config.set(config::NTCP_LISTEN, "127.0.0.1:0").unwrap();
+        config.set(config::NTCP2_LISTEN, "127.0.0.2:0").unwrap();
+        config
+            .set(config::NTCP2_KEYFILE, ntcp2_keyfile.to_str())
+            .unwrap();
+        config
+            .set(
+                config::TRANSPORT_BLOCKLIST,
+                vec![peer.router_id.hash().to_string()],
+            )
+            .unwrap();
+
+        let manager = Manager::from_config(&config, MockDistributor::new());
+        match manager.send(peer.clone(), Message::dummy_data()) {
+            Err((ri, _)) => assert_eq!(ri, peer),
+            Ok(_) => panic!("Message should not have been sent to a blocklisted peer"),
+        }
+    }
+
+    struct MockTransport {
+        style: I2PString,
+        bid: u32,
+        sent: mpsc::UnboundedSender<(RouterInfo, Message)>,
+        dial_observer: Arc<Mutex<Option<DialObserver>>>,
+    }
+
+    impl Transport for MockTransport {
+        fn style(&self) -> &I2PString {
+            &self.style
+        }
+
+        fn address(&self) -> RouterAddress {
+            RouterAddress::new(&self.style, "127.0.0.3:0".parse().unwrap())
+        }
+
+        fn listen(
+            &mut self,
+            _ctx: Arc<Context>,
+        ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+            Box::new(future::empty())
+        }
+
+        fn is_established(&self, _hash: &Hash) -> bool {
+            false
+        }
+
+        fn sessions(&self) -> Vec<(Hash, Direction)> {
+            vec![]
+        }
+
+        fn set_dial_observer(&mut self, observer: DialObserver) {
+            *self.dial_observer.lock().unwrap() = Some(observer);
+        }
+
+        fn close_sessions(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+            Box::new(future::ok(()))
+        }
+
+        fn bid(&self, peer: &RouterInfo, _msg: &Message) -> Option<Bid> {
+            peer.address(&self.style, |_| true)?;
+            Some(Bid {
+                bid: self.bid,
+                sink: Box::new(
+                    self.sent
+                        .clone()
+                        .sink_map_err(|_| io::Error::new(io::ErrorKind::Other, "Closed")),
+                ),
+            })
+        }
+    }
+
+    #[test]
+    fn manager_registered_transport() {
+        let dir = tempdir().unwrap();
+        let ntcp2_keyfile = dir.path().join("test.ntcp2.keys.dat");
+
+        let mut config = config::Config::default();
+        config.set(config::NTCP_LISTEN, "127.0.0.1:0").unwrap();
+        config.set(config::NTCP2_LISTEN, "127.0.0.2:0").unwrap();
+        config
+            .set(config::NTCP2_KEYFILE, ntcp2_keyfile.to_str())
+            .unwrap();
+        let mut manager = Manager::from_config(&config, MockDistributor::new());
+
+        let style = I2PString::new("MOCK");
+        let rsk = RouterSecretKeys::new();
+        let mut peer = RouterInfo::new(rsk.rid);
+        peer.set_addresses(vec![RouterAddress::new(
+            &style,
+            "127.0.0.1:12345".parse().unwrap(),
+        )]);
+        peer.sign(&rsk.signing_private_key);
+
+        // Neither NTCP nor NTCP2 can reach the peer
+        assert!(manager.send(peer.clone(), Message::dummy_data()).is_err());
+
+        let (tx, rx) = mpsc::unbounded();
+        manager.register(Box::new(MockTransport {
+            style: style.clone(),
+            bid: 1,
+            sent: tx,
+            dial_observer: Arc::default(),
+        }));
+        assert_eq!(manager.addresses().len(), 3);
+        assert_eq!(
+            manager.addresses()[2].addr(),
+            Some("127.0.0.3:0".parse().unwrap())
+        );
+
+        let msg = Message::dummy_data();
+        let msg_id = msg.id;
+        match manager.send(peer.clone(), msg) {
+            Ok(f) => f.wait().unwrap(),
+            Err(_) => panic!("Message should have been sent with the mock transport"),
+        }
+        match rx.wait().next() {
+            Some(Ok((ri, msg))) => {
+                assert_eq!(ri, peer);
+                assert_eq!(msg.id, msg_id);
+            }
+            _ => panic!("Mock transport should have sent the message"),
+        }
+    }
+
+    #[test]
+    fn shared_distributor() {
+        let distributor = MockDistributor::new();
+        let shared = SharedDistributor::new(distributor.clone());
+
+        let from = RouterSecretKeys::new().rid.hash();
+        let msg = Message::dummy_data();
+        let msg_id = msg.id;
+        shared.handle(from.clone(), msg).wait().unwrap();
+
+        let received = distributor.received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, from);
+        assert_eq!(received[0].1.id, msg_id);
+    }
+
+    #[test]
+    fn manager_penalises_failed_transport() {
+        let dir = tempdir().unwrap();
+        let ntcp2_keyfile = dir.path().join("test.ntcp2.keys.dat");
+
+        let mut config = config::Config::default();
+        config.set(config::NTCP_LISTEN, "127.0.0.1:0").unwrap();
+        config.set(config::NTCP2_LISTEN, "127.0.0.2:0").unwrap();
+        config
+            .set(config::NTCP2_KEYFILE, ntcp2_keyfile.to_str())
+            .unwrap();
+        let mut manager = Manager::from_config(&config, MockDistributor::new());
+
+        let cheap = I2PString::new("CHEAP");
+        let dear = I2PString::new("DEAR");
+        let rsk = RouterSecretKeys::new();
+        let mut peer = RouterInfo::new(rsk.rid);
+        peer.set_addresses(vec![
+            RouterAddress::new(&cheap, "127.0.0.1:12345".parse().unwrap()),
+            RouterAddress::new(&dear, "127.0.0.1:12346".parse().unwrap()),
+        ]);
+        peer.sign(&rsk.signing_private_key);
+
+        let (cheap_tx, cheap_rx) = mpsc::unbounded();
+        let (dear_tx, dear_rx) = mpsc::unbounded();
+        let cheap_dials = Arc::new(Mutex::new(None));
+        manager.register(Box::new(MockTransport {
+            style: cheap,
+            bid: 1,
+            sent: cheap_tx,
+            dial_observer: cheap_dials.clone(),
+        }));
+        manager.register(Box::new(MockTransport {
+            style: dear,
+            bid: 2,
+            sent: dear_tx,
+            dial_observer: Arc::default(),
+        }));
+
+        // The lowest bidder is tried first
+        match manager.send(peer.clone(), Message::dummy_data()) {
+            Ok(f) => f.wait().unwrap(),
+            Err(_) => panic!("Message should have been sent with the cheap transport"),
+        }
+        assert!(cheap_rx.wait().next().is_some());
+
+        // The cheap transport can't actually connect to the peer
+        let cheap_dials = cheap_dials.lock().unwrap().clone().unwrap();
+        cheap_dials(&peer.router_id.hash(), false);
+
+        // After it fails, the other transport wins
+        let msg = Message::dummy_data();
+        let msg_id = msg.id;
+        match manager.send(peer.clone(), msg) {
+            Ok(f) => f.wait().unwrap(),
+            Err(_) => panic!("Message should have been sent with the dear transport"),
+        }
+        match dear_rx.wait().next() {
+            Some(Ok((ri, msg))) => {
+                assert_eq!(ri, peer);
+                assert_eq!(msg.id, msg_id);
+            }
+            _ => panic!("Dear transport should have sent the message"),
+        }
+    }
 }
@@ -5,6 +5,7 @@
 use bytes::BytesMut;
 use cookie_factory::GenError;
 use futures::{
+    future::lazy,
     stream::{SplitSink, SplitStream},
     sync::mpsc,
     try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
@@ -23,8 +24,12 @@ use tokio::{
 };
 
 use super::{
-    session::{self, SessionContext, SessionManager, SessionRefs, SessionRx},
-    Bid, Transport,
+    bandwidth::BandwidthLimiter,
+    counter::ByteCounter,
+    dial::DialLimiter,
+    session::{self, DialObserver, SessionContext, SessionManager, SessionRefs, SessionRx},
+    stats::TrafficStats,
+    Bid, Direction, PeerFilter, Transport, UnknownMessagePolicy,
 };
 use crate::crypto::{Aes256, SigningPrivateKey};
 use crate::data::{Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo};
@@ -159,6 +164,7 @@ where
     pending_ib: Option<DistributorResult>,
     outbound: SessionRx<Frame>,
     cached_ob_frame: Option<Frame>,
+    traffic: TrafficStats,
 }
 
 impl<T, C, D> Session<T, C, D>
@@ -170,19 +176,21 @@ where
 {
     fn new(
         ri: RouterIdentity,
+        direction: Direction,
         upstream: Framed<T, C>,
         session_refs: SessionRefs<Frame, D>,
     ) -> Self {
         let (downstream, upstream) = upstream.split();
         let (tx, rx) = mpsc::unbounded();
-        let ctx = SessionContext::new(ri.hash(), session_refs.state, tx);
+        let ctx = SessionContext::new(ri.hash(), direction, session_refs.state, tx);
         Session {
-            ib: InboundSession::new(ctx, upstream),
+            ib: InboundSession::new(ctx, upstream, session_refs.unknown_messages),
             ob: OutboundSession::new(downstream),
             distributor: session_refs.distributor,
             pending_ib: None,
             outbound: rx,
             cached_ob_frame: None,
+            traffic: session_refs.traffic,
         }
     }
 }
@@ -211,21 +219,32 @@ where
         }
 
         // Write frames
+        let mut closed = false;
         while write_ready {
             match self.outbound.poll().unwrap() {
-                Async::Ready(Some(frame)) => match self.ob.start_send(frame)? {
-                    AsyncSink::Ready => (),
-                    AsyncSink::NotReady(frame) => {
+                Async::Ready(Some(frame)) => {
+                    if let Frame::Standard(_) = frame {
+                        self.traffic.sent_message();
+                    }
+                    if let AsyncSink::NotReady(frame) = self.ob.start_send(frame)? {
                         self.cached_ob_frame = Some(frame);
                         write_ready = false;
                     }
-                },
-                _ => break,
+                }
+                Async::Ready(None) => {
+                    closed = true;
+                    break;
+                }
+                Async::NotReady => break,
             }
         }
 
-        // Flush frames
-        self.ob.poll_complete()?;
+        // Flush frames, and close the session once everything queued for it
+        // has been sent, if the transport has closed its channel
+        if self.ob.poll_complete()?.is_ready() && closed {
+            info!("Closing session with {}", self.ib.ctx.hash);
+            return Ok(Async::Ready(()));
+        }
 
         // Read frames
         loop {
@@ -238,6 +257,7 @@ where
 
             let f = try_ready!(self.ib.poll());
             if let Some((from, msg)) = f {
+                self.traffic.received_message();
                 self.pending_ib = Some(self.distributor.handle(from, msg));
             } else {
                 // EOF was reached. The remote peer has disconnected.
@@ -255,6 +275,7 @@ where
 {
     ctx: SessionContext<Frame>,
     upstream: SplitStream<Framed<T, C>>,
+    unknown_messages: UnknownMessagePolicy,
 }
 
 impl<T, C> InboundSession<T, C>
@@ -263,8 +284,16 @@ where
     C: Decoder<Item = Frame, Error = io::Error>,
     C: Encoder<Item = Frame, Error = io::Error>,
 {
-    fn new(ctx: SessionContext<Frame>, upstream: SplitStream<Framed<T, C>>) -> Self {
-        InboundSession { ctx, upstream }
+    fn new(
+        ctx: SessionContext<Frame>,
+        upstream: SplitStream<Framed<T, C>>,
+        unknown_messages: UnknownMessagePolicy,
+    ) -> Self {
+        InboundSession {
+            ctx,
+            upstream,
+            unknown_messages,
+        }
     }
 }
 
@@ -282,7 +311,9 @@ where
             match try_ready!(self.upstream.poll()) {
                 Some(frame) => match frame {
                     Frame::Standard(msg) => {
-                        return Ok(Async::Ready(Some((self.ctx.hash.clone(), msg))));
+                        if let Some(msg) = self.unknown_messages.filter(&self.ctx.hash, msg)? {
+                            return Ok(Async::Ready(Some((self.ctx.hash.clone(), msg))));
+                        }
                     }
                     frame => {
                         // TODO: Do something
@@ -346,6 +377,11 @@ where
 pub struct Manager<D: Distributor> {
     addr: SocketAddr,
     session_manager: SessionManager<Frame, D>,
+    dial_limiter: DialLimiter,
+    /// Whether we connect to peers ourselves, or only send over sessions that
+    /// they opened.
+    dial_directly: bool,
+    peer_filter: Arc<PeerFilter>,
     ctx: Option<Arc<Context>>,
 }
 
@@ -354,14 +390,50 @@ impl<D: Distributor> Manager<D> {
         Manager {
             addr,
             session_manager: session::new_manager(distributor),
+            dial_limiter: DialLimiter::default(),
+            dial_directly: true,
+            peer_filter: Arc::new(PeerFilter::default()),
             ctx: None,
         }
     }
 
     pub fn set_context(&mut self, ctx: Arc<Context>) {
+        self.session_manager.set_own_hash(ctx.keys.rid.hash());
         self.ctx = Some(ctx);
     }
 
+    /// Sets what sessions do when the peer sends an unknown message type.
+    pub fn set_unknown_message_policy(&mut self, policy: UnknownMessagePolicy) {
+        self.session_manager.set_unknown_message_policy(policy);
+    }
+
+    /// Records the traffic of sessions opened from now on in `traffic`.
+    pub(super) fn set_traffic_stats(&mut self, traffic: TrafficStats) {
+        self.session_manager.set_traffic_stats(traffic);
+    }
+
+    /// Limits the bandwidth of sessions opened from now on.
+    pub(super) fn set_bandwidth_limiter(&mut self, bandwidth: BandwidthLimiter) {
+        self.session_manager.set_bandwidth_limiter(bandwidth);
+    }
+
+    /// Shares a limit on concurrent outbound dials with other transports.
+    pub(super) fn set_dial_limiter(&mut self, dial_limiter: DialLimiter) {
+        self.dial_limiter = dial_limiter;
+    }
+
+    /// Stops us from connecting to peers ourselves, for example because all
+    /// of our outbound connections must go through a proxy. Sessions that
+    /// peers open to us are still used.
+    pub(super) fn disable_direct_dials(&mut self) {
+        self.dial_directly = false;
+    }
+
+    /// Sets the peers that may open sessions with us.
+    pub(super) fn set_peer_filter(&mut self, peer_filter: Arc<PeerFilter>) {
+        self.peer_filter = peer_filter;
+    }
+
     pub fn sink(&self) -> OutboundSink<D> {
         let ctx = self
             .ctx
@@ -371,6 +443,7 @@ impl<D: Distributor> Manager<D> {
         OutboundSink {
             ctx,
             session_refs: self.session_manager.refs(),
+            dial_limiter: self.dial_limiter.clone(),
         }
     }
 
@@ -391,15 +464,34 @@ impl<D: Distributor> Manager<D> {
         // Give each incoming connection the references it needs
         let session_refs = self.session_manager.refs();
         let conns = listener.incoming().zip(session_refs);
+        let peer_filter = self.peer_filter.clone();
 
         // For each incoming connection:
         conns.for_each(move |(conn, session_refs)| {
             info!("Incoming connection!");
+            let mut conn = ByteCounter::new(conn);
+            conn.set_stats(session_refs.traffic.clone());
+            conn.set_bandwidth_limiter(session_refs.bandwidth.clone());
+
             // Execute the handshake
             let conn = handshake::IBHandshake::new(conn, own_ri.clone(), own_key.clone());
 
+            // Refuse peers that we don't communicate with
+            let peer_filter = peer_filter.clone();
+            let conn = conn.and_then(move |(ri, conn)| {
+                if peer_filter.permits(&ri.hash()) {
+                    Ok((ri, conn))
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("Rejected handshake from filtered peer {}", ri.hash()),
+                    ))
+                }
+            });
+
             // Once connected:
-            let process_conn = conn.and_then(|(ri, conn)| Session::new(ri, conn, session_refs));
+            let process_conn = conn
+                .and_then(|(ri, conn)| Session::new(ri, Direction::Inbound, conn, session_refs));
 
             spawn(process_conn.map_err(|_| ()));
 
@@ -417,13 +509,19 @@ impl<D: Distributor> Manager<D> {
     }
 }
 
+/// Returns whether we can connect to the address over NTCP. We don't support
+/// NTCP over IPv6, so only IPv4 addresses are usable.
+fn is_ntcp_address(ra: &RouterAddress) -> bool {
+    ra.addr().map(|addr| addr.is_ipv4()).unwrap_or(false)
+}
+
 fn connect<D: Distributor>(
     own_ri: RouterIdentity,
     own_key: SigningPrivateKey,
     peer_ri: RouterInfo,
     session_refs: SessionRefs<Frame, D>,
 ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
-    let addr = match peer_ri.address(&NTCP_STYLE, |_| true) {
+    let addr = match peer_ri.address(&NTCP_STYLE, is_ntcp_address) {
         Some(ra) => ra.addr().unwrap(),
         None => {
             return Err(io::Error::new(
@@ -434,8 +532,14 @@ fn connect<D: Distributor>(
     };
 
     // Connect to the peer
-    let conn = TcpStream::connect(&addr)
-        .and_then(|socket| handshake::OBHandshake::new(socket, own_ri, own_key, peer_ri.router_id));
+    let traffic = session_refs.traffic.clone();
+    let bandwidth = session_refs.bandwidth.clone();
+    let conn = TcpStream::connect(&addr).and_then(|socket| {
+        let mut socket = ByteCounter::new(socket);
+        socket.set_stats(traffic);
+        socket.set_bandwidth_limiter(bandwidth);
+        handshake::OBHandshake::new(socket, own_ri, own_key, peer_ri.router_id)
+    });
 
     // Add a timeout
     let timed = Timeout::new(conn, Duration::new(10, 0))
@@ -443,32 +547,67 @@ fn connect<D: Distributor>(
 
     // Once connected:
     Ok(timed.and_then(|(ri, conn)| {
-        let session = Session::new(ri, conn, session_refs);
+        let session = Session::new(ri, Direction::Outbound, conn, session_refs);
         spawn(session.map_err(|_| ()));
         Ok(())
     }))
 }
 
 impl<D: Distributor> Transport for Manager<D> {
+    fn style(&self) -> &I2PString {
+        &NTCP_STYLE
+    }
+
+    fn address(&self) -> RouterAddress {
+        Manager::address(self)
+    }
+
+    fn listen(
+        &mut self,
+        ctx: Arc<Context>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.set_context(ctx.clone());
+        Box::new(Manager::listen(
+            self,
+            ctx.keys.rid.clone(),
+            ctx.keys.signing_private_key.clone(),
+        ))
+    }
+
     fn is_established(&self, hash: &Hash) -> bool {
         self.session_manager.have_session(hash)
     }
 
-    fn bid(&self, peer: &RouterInfo, msg_size: usize) -> Option<Bid> {
-        if msg_size > NTCP_MTU {
+    fn sessions(&self) -> Vec<(Hash, Direction)> {
+        self.session_manager.sessions()
+    }
+
+    fn set_dial_observer(&mut self, observer: DialObserver) {
+        self.session_manager.set_dial_observer(observer);
+    }
+
+    fn close_sessions(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(self.session_manager.close_all())
+    }
+
+    fn bid(&self, peer: &RouterInfo, msg: &Message) -> Option<Bid> {
+        if msg.size() > NTCP_MTU {
             return None;
         }
 
-        if peer.address(&NTCP_STYLE, |_| true).is_none() {
+        if peer.address(&NTCP_STYLE, is_ntcp_address).is_none() {
             return None;
         }
 
+        // Reuse a session that is open or being opened, rather than racing
+        // another transport to open a second one
+        let hash = peer.router_id.hash();
+        let reuse = self.is_established(&hash) || self.session_manager.have_pending_session(&hash);
+        if !reuse && !self.dial_directly {
+            return None;
+        }
         Some(Bid {
-            bid: if self.is_established(&peer.router_id.hash()) {
-                25
-            } else {
-                70
-            },
+            bid: if reuse { 25 } else { 70 },
             sink: Box::new(self.sink()),
         })
     }
@@ -477,6 +616,7 @@ impl<D: Distributor> Transport for Manager<D> {
 pub struct OutboundSink<D: Distributor> {
     ctx: Arc<Context>,
     session_refs: SessionRefs<Frame, D>,
+    dial_limiter: DialLimiter,
 }
 
 impl<D: Distributor> Sink for OutboundSink<D> {
@@ -488,24 +628,23 @@ impl<D: Distributor> Sink for OutboundSink<D> {
         (peer, msg): Self::SinkItem,
     ) -> StartSend<Self::SinkItem, Self::SinkError> {
         let session_refs = self.session_refs.clone();
+        let state = self.session_refs.state.clone();
+        let dial_limiter = &self.dial_limiter;
 
         match self
             .session_refs
             .state
             .send(&peer.router_id.hash(), Frame::Standard(msg), || {
-                // Connect to the peer
+                // Connect to the peer, once we are under the dial limit
                 let own_rid = self.ctx.keys.rid.clone();
                 let own_key = self.ctx.keys.signing_private_key.clone();
                 let peer = peer.clone();
-                let session_refs = session_refs.clone();
-                match connect(own_rid, own_key, peer, session_refs) {
-                    Ok(f) => {
-                        spawn(f.map_err(|e| {
-                            error!("Error while connecting: {}", e);
-                        }));
-                    }
-                    Err(e) => error!("{}", e),
-                }
+                let hash = peer.router_id.hash();
+                let dial = lazy(move || connect(own_rid, own_key, peer, session_refs)).flatten();
+                spawn(dial_limiter.limit(dial).map_err(move |e| {
+                    error!("Error while connecting: {}", e);
+                    state.connect_failed(&hash);
+                }));
             }) {
             Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
             Ok(AsyncSink::NotReady(Frame::Standard(msg))) => Ok(AsyncSink::NotReady((peer, msg))),
@@ -535,7 +674,7 @@ mod tests {
         io::{self, Read, Write},
     };
 
-    use super::{frame, Frame, Manager, Session, NTCP_MTU};
+    use super::{frame, Direction, Frame, Manager, Session, NTCP_MTU};
     use crate::i2np::Message;
     use crate::router::mock::{mock_context, MockDistributor};
     use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
@@ -634,7 +773,12 @@ mod tests {
             assert!(received.is_empty());
 
             // Create a session
-            let mut session = Session::new(rid, alice_framed, manager.session_manager.refs());
+            let mut session = Session::new(
+                rid,
+                Direction::Outbound,
+                alice_framed,
+                manager.session_manager.refs(),
+            );
 
             // Pass it through the session, now it's on the wire
             session.poll().unwrap();
@@ -661,7 +805,12 @@ mod tests {
         let distributor = MockDistributor::new();
         let received = distributor.received.clone();
         let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
-        let mut session = Session::new(rid, bob_framed, manager.session_manager.refs());
+        let mut session = Session::new(
+            rid,
+            Direction::Inbound,
+            bob_framed,
+            manager.session_manager.refs(),
+        );
 
         // Run on a task context
         lazy(move || {
@@ -1,13 +1,12 @@
 use cookie_factory::*;
 use nom::*;
-use rand::{rngs::OsRng, Rng};
 
 use crate::data::frame::{gen_router_info, router_info};
 use crate::data::RouterInfo;
 use crate::i2np::frame::{gen_ntcp2_message, ntcp2_message};
 use crate::i2np::Message;
 
-use super::{Block, Frame, RouterInfoFlags};
+use super::{padding, Block, Frame, RouterInfoFlags, SessionOptions, TerminationReason};
 
 //
 // Blocks
@@ -26,16 +25,54 @@ fn gen_datetime(input: (&mut [u8], usize), ts: u32) -> Result<(&mut [u8], usize)
 
 // Options
 
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(
+    session_options<SessionOptions>,
+    do_parse!(
+        tmin:   be_u8 >>
+        tmax:   be_u8 >>
+        rmin:   be_u8 >>
+        rmax:   be_u8 >>
+        tdmy:   be_u16 >>
+        rdmy:   be_u16 >>
+        tdelay: be_u16 >>
+        rdelay: be_u16 >>
+        (SessionOptions {
+            tmin,
+            tmax,
+            rmin,
+            rmax,
+            tdmy,
+            rdmy,
+            tdelay,
+            rdelay,
+        })
+    )
+);
+
+// Any data after the options we know about is reserved for future extensions,
+// and is ignored.
 named!(
     options<Block>,
-    do_parse!(options: length_bytes!(be_u16) >> (Block::Options(options.to_vec())))
+    do_parse!(options: length_value!(be_u16, session_options) >> (Block::Options(options)))
 );
 
 fn gen_options<'a>(
     input: (&'a mut [u8], usize),
-    options: &[u8],
+    options: &SessionOptions,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    do_gen!(input, gen_be_u16!(options.len()) >> gen_slice!(options))
+    do_gen!(
+        input,
+        gen_be_u16!(12)
+            >> gen_be_u8!(options.tmin)
+            >> gen_be_u8!(options.tmax)
+            >> gen_be_u8!(options.rmin)
+            >> gen_be_u8!(options.rmax)
+            >> gen_be_u16!(options.tdmy)
+            >> gen_be_u16!(options.rdmy)
+            >> gen_be_u16!(options.tdelay)
+            >> gen_be_u16!(options.rdelay)
+    )
 }
 
 // RouterInfo
@@ -109,9 +146,9 @@ fn gen_message<'a>(
 named!(
     termination<Block>,
     do_parse!(
-        size: be_u16
+        size: verify!(be_u16, |size| size >= 9)
             >> valid_received: be_u64
-            >> rsn: be_u8
+            >> rsn: map!(be_u8, TerminationReason::from)
             >> addl_data: take!(size - 9)
             >> (Block::Termination(valid_received, rsn, addl_data.to_vec()))
     )
@@ -120,14 +157,14 @@ named!(
 fn gen_termination<'a>(
     input: (&'a mut [u8], usize),
     valid_received: u64,
-    rsn: u8,
+    rsn: TerminationReason,
     addl_data: &[u8],
 ) -> Result<(&'a mut [u8], usize), GenError> {
     do_gen!(
         input,
         size: gen_skip!(2)
             >> start: gen_be_u64!(valid_received)
-            >> gen_be_u8!(rsn)
+            >> gen_be_u8!(rsn.code())
             >> gen_slice!(addl_data)
             >> end: gen_at_offset!(size, gen_be_u16!(end - start))
     )
@@ -141,10 +178,9 @@ named!(
 );
 
 fn gen_padding(input: (&mut [u8], usize), size: u16) -> Result<(&mut [u8], usize), GenError> {
-    let mut padding = vec![0u8; size as usize];
-    let mut rng = OsRng;
-    rng.fill(&mut padding[..]);
-    do_gen!(input, gen_be_u16!(size) >> gen_slice!(padding))
+    let mut data = vec![0u8; size as usize];
+    padding::fill(&mut data[..]);
+    do_gen!(input, gen_be_u16!(size) >> gen_slice!(data))
 }
 
 // Unknown
@@ -161,6 +197,13 @@ fn gen_unknown<'a>(
     do_gen!(input, gen_be_u16!(data.len()) >> gen_slice!(data))
 }
 
+// Malformed
+
+named!(
+    malformed<Block>,
+    do_parse!(blk: be_u8 >> data: call!(unknown) >> (Block::Malformed(blk, data)))
+);
+
 //
 // Framing
 //
@@ -197,11 +240,16 @@ fn gen_block<'a>(
             blockgen!(4, gen_termination(valid_received, rsn, addl_data))
         }
         Block::Padding(size) => blockgen!(254, gen_padding(size)),
-        Block::Unknown(blk, ref data) => blockgen!(blk, gen_unknown(data)),
+        Block::Unknown(blk, ref data) | Block::Malformed(blk, ref data) => {
+            blockgen!(blk, gen_unknown(data))
+        }
     }
 }
 
-named!(pub frame<Frame>, many1!(complete!(block)));
+// A block that fails to parse is recoverable as long as its length is intact,
+// so we fall back to skipping over it. Anything left unparsed is a framing
+// error, which the caller must treat as fatal.
+named!(pub frame<Frame>, many1!(complete!(alt!(complete!(block) | malformed))));
 
 pub fn gen_frame<'a>(
     input: (&'a mut [u8], usize),
@@ -337,9 +385,45 @@ mod tests {
     #[test]
     fn test_options() {
         eval_block!(
-            Block::Options(vec![0x00, 0x01, 0x02, 0x03, 0x04]),
-            [0x01, 0x00, 0x05, 0x00, 0x01, 0x02, 0x03, 0x04]
+            Block::Options(SessionOptions {
+                tmin: 0x01,
+                tmax: 0x02,
+                rmin: 0x03,
+                rmax: 0x04,
+                tdmy: 0x0506,
+                rdmy: 0x0708,
+                tdelay: 0x090a,
+                rdelay: 0x0b0c,
+            }),
+            [
+                0x01, 0x00, 0x0c, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+                0x0c,
+            ]
         );
+
+        // Trailing data is ignored
+        match block(&[
+            0x01, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x64, 0xff, 0xff,
+        ]) {
+            Ok((rest, m)) => {
+                assert!(rest.is_empty());
+                assert_eq!(
+                    m,
+                    Block::Options(SessionOptions {
+                        rdelay: 100,
+                        ..Default::default()
+                    })
+                );
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        // Truncated options are malformed
+        match frame(&[0x01, 0x00, 0x02, 0x00, 0x00]) {
+            Ok((_, f)) => assert_eq!(f, vec![Block::Malformed(1, vec![0x00, 0x00])]),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
     }
 
     #[test]
@@ -375,9 +459,18 @@ mod tests {
     #[test]
     fn test_termination() {
         eval_block!(
-            Block::Termination(42, 7, vec![0xfe]),
+            Block::Termination(42, TerminationReason::ClockSkew, vec![0xfe]),
             [0x04, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x07, 0xfe,]
         );
+
+        // Reasons we don't know about are preserved
+        eval_block!(
+            Block::Termination(1, TerminationReason::Unknown(200), vec![]),
+            [0x04, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xc8]
+        );
+        for code in 0..=255 {
+            assert_eq!(TerminationReason::from(code).code(), code);
+        }
     }
 
     #[test]
@@ -418,6 +511,87 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_malformed() {
+        // Termination block that is too short
+        assert!(block(&[0x04, 0x00, 0x02, 0x12, 0x34]).is_err());
+        bake_and_eat!(
+            gen_block,
+            malformed,
+            Block::Malformed(4, vec![0x12, 0x34]),
+            [0x04, 0x00, 0x02, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn test_frame_skips_malformed() {
+        let data = [
+            // Unknown block
+            0xe0, 0x00, 0x02, 0x12, 0x34, //
+            // DateTime block with the wrong size
+            0x00, 0x00, 0x02, 0x56, 0x78, //
+            // Valid DateTime block
+            0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x2a,
+        ];
+        match frame(&data) {
+            Ok((rest, f)) => {
+                assert!(rest.is_empty());
+                assert_eq!(
+                    f,
+                    vec![
+                        Block::Unknown(0xe0, vec![0x12, 0x34]),
+                        Block::Malformed(0x00, vec![0x56, 0x78]),
+                        Block::DateTime(42),
+                    ]
+                );
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        // A truncated block is left unparsed
+        match frame(&data[..data.len() - 1]) {
+            Ok((rest, f)) => {
+                assert_eq!(rest.len(), 6);
+                assert_eq!(f.len(), 2);
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_frame_message_with_padding() {
+        let blocks = vec![
+            Block::Message(Message {
+                id: 42,
+                expiration: I2PDate::from_system_time(UNIX_EPOCH + Duration::new(1_524_874_654, 0)),
+                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            }),
+            Block::Padding(16),
+        ];
+
+        // 26-byte message block, and 19-byte padding block
+        let mut res = vec![0; 45];
+        match gen_frame((&mut res, 0), &blocks) {
+            Ok((_, n)) => assert_eq!(n, 45),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+
+        match frame(&res) {
+            Ok((rest, f)) => {
+                assert!(rest.is_empty());
+                assert_eq!(f, blocks);
+                match &f[0] {
+                    Block::Message(Message {
+                        payload: MessagePayload::Data(data),
+                        ..
+                    }) => assert_eq!(data, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                    _ => panic!("Expected a message block"),
+                }
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
     #[test]
     fn test_session_request() {
         let mut res = vec![];
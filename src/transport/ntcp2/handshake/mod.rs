@@ -1,24 +1,27 @@
 use cookie_factory::GenError;
-use futures::{Async, Future, Poll};
+use futures::{sink, Async, Future, Poll, Sink};
 use i2p_snow::{Builder, Session};
 use nom::Err;
-use rand::{rngs::OsRng, Rng};
 use siphasher::sip::SipHasher;
+use std::error;
+use std::fmt;
 use std::net::SocketAddr;
 use std::ops::AddAssign;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::{
     codec::{Decoder, Framed},
     io::{self, AsyncRead, AsyncWrite, ReadExact, WriteAll},
+    timer::Delay,
 };
 
 use super::{
-    frame, Block, Codec, NTCP2_MTU, NTCP2_NOISE_PROTOCOL_NAME, NTCP2_OPT_I, NTCP2_OPT_S,
-    NTCP2_OPT_V, NTCP2_STYLE, NTCP2_VERSION,
+    frame, is_reachable_ntcp2_address, negotiate_version, padding, Block, Codec, HandshakeConfig,
+    TerminationReason, NTCP2_MTU, NTCP2_OPT_I, NTCP2_OPT_S, NTCP2_STYLE, NTCP2_VERSIONS,
 };
 use crate::constants::I2P_BASE64;
 use crate::data::{RouterAddress, RouterIdentity, RouterInfo};
 use crate::transport::ntcp::NTCP_STYLE;
+use crate::util::{check_future_skew, check_past_skew};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
@@ -26,6 +29,11 @@ const SESSION_REQUEST_PT_LEN: usize = 16;
 const SESSION_REQUEST_CT_LEN: usize = 32 + SESSION_REQUEST_PT_LEN + 16;
 const SESSION_CREATED_PT_LEN: usize = 16;
 const SESSION_CREATED_CT_LEN: usize = 32 + SESSION_CREATED_PT_LEN + 16;
+const SESSION_CONFIRMED_PART1_LEN: usize = 32 + 16;
+
+// Each handshake message must fit in a single NTCP2 message
+pub(crate) const SESSION_REQUEST_MAX_PADDING: usize = NTCP2_MTU - SESSION_REQUEST_CT_LEN;
+const SESSION_CONFIRMED_MAX_PART2_LEN: usize = NTCP2_MTU - SESSION_CONFIRMED_PART1_LEN;
 
 macro_rules! try_poll {
     ($f:expr, $parent:expr, $noise:expr) => {
@@ -45,6 +53,167 @@ macro_rules! io_err {
     };
 }
 
+/// The error wrapped in the `TimedOut` errors returned by handshakes that take
+/// too long, identifying which timeout fired and in which state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeTimeout {
+    /// The handshake as a whole took longer than `HandshakeConfig::timeout`.
+    Handshake(&'static str),
+    /// A single state took longer than `HandshakeConfig::step_timeout`.
+    Step(&'static str),
+}
+
+impl fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeTimeout::Handshake(state) => write!(f, "Handshake timed out in {}", state),
+            HandshakeTimeout::Step(state) => write!(f, "Handshake step {} timed out", state),
+        }
+    }
+}
+
+impl error::Error for HandshakeTimeout {}
+
+impl From<HandshakeTimeout> for io::Error {
+    fn from(timeout: HandshakeTimeout) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, timeout)
+    }
+}
+
+/// Returns a new deadline `timeout` from now.
+fn deadline_after(timeout: Option<Duration>) -> Option<Delay> {
+    timeout.map(|timeout| Delay::new(Instant::now() + timeout))
+}
+
+/// Returns true if the deadline has passed.
+fn expired(deadline: &mut Option<Delay>) -> io::Result<bool> {
+    if let Some(deadline) = deadline {
+        match deadline.poll() {
+            Ok(Async::Ready(())) => return Ok(true),
+            Ok(Async::NotReady) => (),
+            Err(e) => return io_err!(Other, format!("Handshake timer error: {}", e)),
+        }
+    }
+    Ok(false)
+}
+
+/// Returns an error of kind `TimedOut` if the handshake deadline, or the
+/// deadline for the current state, has passed.
+fn check_deadlines(
+    deadline: &mut Option<Delay>,
+    step_deadline: &mut Option<Delay>,
+    state: &'static str,
+) -> io::Result<()> {
+    if expired(deadline)? {
+        return Err(HandshakeTimeout::Handshake(state).into());
+    }
+    if expired(step_deadline)? {
+        return Err(HandshakeTimeout::Step(state).into());
+    }
+    Ok(())
+}
+
+/// A step of a handshake, recorded if tracing is enabled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStep {
+    /// The state that the handshake was in.
+    pub state: &'static str,
+    /// The number of bytes read from the peer in this state.
+    pub read: usize,
+    /// The number of bytes written to the peer in this state.
+    pub written: usize,
+    /// What we parsed from the peer's message, or why the handshake failed.
+    pub result: Option<String>,
+}
+
+/// The steps that a handshake has taken so far.
+struct Trace {
+    role: &'static str,
+    steps: Option<Vec<TraceStep>>,
+}
+
+impl Trace {
+    fn new(role: &'static str, enabled: bool) -> Self {
+        Trace {
+            role,
+            steps: if enabled { Some(vec![]) } else { None },
+        }
+    }
+
+    fn record(&mut self, state: &'static str, read: usize, written: usize, result: Option<String>) {
+        if let Some(steps) = self.steps.as_mut() {
+            let step = TraceStep {
+                state,
+                read,
+                written,
+                result,
+            };
+            debug!("{} handshake: {:?}", self.role, step);
+            steps.push(step);
+        }
+    }
+}
+
+/// Decodes the protocol version, socket address, static key and IV needed to
+/// connect to an NTCP2 address.
+fn address_params(ra: &RouterAddress) -> Result<(u8, SocketAddr, Vec<u8>, [u8; 16]), String> {
+    let version = negotiate_version(ra, NTCP2_VERSIONS)
+        .ok_or_else(|| "No supported version in address".to_string())?;
+    let addr = ra.addr().ok_or_else(|| "No host in address".to_string())?;
+    let remote_key = match ra.option(&NTCP2_OPT_S) {
+        Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
+            Ok(ref key) if key.len() != 32 => {
+                return Err(format!(
+                    "Invalid static key length in address: {}",
+                    key.len()
+                ))
+            }
+            Ok(key) => key,
+            Err(e) => return Err(format!("Invalid static key in address: {}", e)),
+        },
+        None => return Err("No static key in address".to_string()),
+    };
+
+    let mut aesobfse_iv = [0; 16];
+    match ra.option(&NTCP2_OPT_I) {
+        Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
+            Ok(ref iv) if iv.len() != 16 => {
+                return Err(format!("Invalid IV length in address: {}", iv.len()))
+            }
+            Ok(iv) => aesobfse_iv.copy_from_slice(&iv),
+            Err(e) => return Err(format!("Invalid IV in address: {}", e)),
+        },
+        None => return Err("No IV in address".to_string()),
+    }
+
+    Ok((version, addr, remote_key, aesobfse_iv))
+}
+
+/// Returns whether the RouterInfo has an NTCP2 address advertising the given
+/// static key. The address may not have a host, if the router is firewalled.
+fn advertises_static_key(ri: &RouterInfo, static_key: &[u8]) -> bool {
+    ri.addresses()
+        .iter()
+        .filter(|ra| {
+            let style = ra.transport_style();
+            *style == *NTCP2_STYLE || *style == *NTCP_STYLE
+        })
+        .filter_map(|ra| ra.option(&NTCP2_OPT_S))
+        .any(|s| match I2P_BASE64.decode(s.0.as_bytes()) {
+            Ok(key) => key == static_key,
+            Err(_) => false,
+        })
+}
+
+/// Transitions the Noise state machine into transport mode once the handshake
+/// is complete.
+fn into_transport_mode(noise: Session) -> io::Result<Session> {
+    match noise.into_transport_mode() {
+        Ok(noise) => Ok(noise),
+        Err(e) => io_err!(Other, format!("Could not enter transport mode: {:?}", e)),
+    }
+}
+
 //
 // Establishment handshake
 //
@@ -55,12 +224,32 @@ enum IBHandshakeState<T> {
     SessionRequestPadding(ReadExact<T, Vec<u8>>),
     SessionCreated((WriteAll<T, Vec<u8>>, SystemTime)),
     SessionConfirmed((ReadExact<T, Vec<u8>>, SystemTime)),
+    /// The handshake completed, but we are refusing the session. Tells the
+    /// peer why before failing with the contained error.
+    Terminating((sink::Send<Framed<T, Codec>>, Option<io::Error>)),
+}
+
+impl<T> IBHandshakeState<T> {
+    fn name(&self) -> &'static str {
+        match self {
+            IBHandshakeState::SessionRequest(_) => "SessionRequest",
+            IBHandshakeState::SessionRequestPadding(_) => "SessionRequestPadding",
+            IBHandshakeState::SessionCreated(_) => "SessionCreated",
+            IBHandshakeState::SessionConfirmed(_) => "SessionConfirmed",
+            IBHandshakeState::Terminating(_) => "Terminating",
+        }
+    }
 }
 
 pub struct IBHandshake<T> {
     noise: Option<Session>,
+    previous: Option<Session>,
+    config: HandshakeConfig,
+    deadline: Option<Delay>,
+    step_deadline: Option<Delay>,
     sclen: usize,
     state: IBHandshakeState<T>,
+    trace: Trace,
 }
 
 impl<T> IBHandshake<T>
@@ -68,37 +257,76 @@ where
     T: AsyncRead + AsyncWrite,
     T: Send + 'static,
 {
-    pub fn new(conn: T, static_key: &[u8], aesobfse_key: &[u8], aesobfse_iv: &[u8; 16]) -> Self {
-        // Initialize our responder NoiseSession using a builder.
-        let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
-        let noise = builder
-            .local_private_key(&static_key)
-            .aesobfse(&aesobfse_key, &aesobfse_iv)
-            .enable_ask()
-            .build_responder()
-            .unwrap();
+    pub fn new(
+        conn: T,
+        static_key: &[u8],
+        aesobfse_key: &[u8],
+        aesobfse_iv: &[u8; 16],
+        config: HandshakeConfig,
+    ) -> Self {
+        let noise = responder(&config, static_key, aesobfse_key, aesobfse_iv);
         let state = IBHandshakeState::SessionRequest(io::read_exact(
             conn,
             vec![0u8; SESSION_REQUEST_CT_LEN],
         ));
         IBHandshake {
             noise: Some(noise),
+            previous: None,
+            deadline: deadline_after(config.timeout),
+            step_deadline: deadline_after(config.step_timeout),
+            trace: Trace::new("Inbound", config.trace),
+            config,
             sclen: 0,
             state,
         }
     }
+
+    /// Also accepts a SessionRequest encrypted to the static key and IV that
+    /// we published before our most recent rotation.
+    pub fn with_previous_keys(
+        mut self,
+        static_key: &[u8],
+        aesobfse_key: &[u8],
+        aesobfse_iv: &[u8; 16],
+    ) -> Self {
+        self.previous = Some(responder(&self.config, static_key, aesobfse_key, aesobfse_iv));
+        self
+    }
 }
 
-impl<T> Future for IBHandshake<T>
+/// Initializes our responder NoiseSession.
+fn responder(
+    config: &HandshakeConfig,
+    static_key: &[u8],
+    aesobfse_key: &[u8],
+    aesobfse_iv: &[u8; 16],
+) -> Session {
+    let builder: Builder<'_> = Builder::new(config.cipher_suite.protocol_name().parse().unwrap());
+    builder
+        .local_private_key(&static_key)
+        .aesobfse(&aesobfse_key, &aesobfse_iv)
+        .enable_ask()
+        .build_responder()
+        .unwrap()
+}
+
+impl<T> IBHandshake<T>
 where
     T: AsyncRead + AsyncWrite,
     T: Send + 'static,
 {
-    type Item = (RouterInfo, Framed<T, Codec>);
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    fn poll_handshake(&mut self) -> Poll<(RouterInfo, Framed<T, Codec>), io::Error> {
         loop {
+            check_deadlines(&mut self.deadline, &mut self.step_deadline, self.state.name())?;
+
+            if let IBHandshakeState::Terminating((ref mut f, ref mut err)) = self.state {
+                // The session is refused whether or not the peer hears about it
+                if let Ok(Async::NotReady) = f.poll() {
+                    return Ok(Async::NotReady);
+                }
+                return Err(err.take().unwrap());
+            }
+
             let mut noise = self.noise.take().unwrap();
             let next_state = match self.state {
                 IBHandshakeState::SessionRequest(ref mut f) => {
@@ -107,37 +335,110 @@ where
                     // <- e, es
                     debug!("S <- e, es");
                     let mut buf = [0u8; SESSION_REQUEST_PT_LEN];
-                    noise.read_message(&msg, &mut buf).unwrap();
+                    if noise.read_message(&msg, &mut buf).is_err() {
+                        // Alice may be using the keys we published before
+                        // rotating them.
+                        let previous = self.previous.take().and_then(|mut previous| {
+                            previous.read_message(&msg, &mut buf).ok().map(|_| previous)
+                        });
+                        match previous {
+                            Some(previous) => {
+                                debug!("SessionRequest used our previous static key and IV");
+                                noise = previous;
+                            }
+                            None => {
+                                // The peer's cipher suite is implied by the
+                                // SessionRequest successfully decrypting.
+                                return io_err!(
+                                    InvalidData,
+                                    format!(
+                                        "Could not decrypt SessionRequest with cipher suite {}",
+                                        self.config.cipher_suite
+                                    )
+                                );
+                            }
+                        }
+                    }
 
                     // SessionRequest
-                    let (padlen, sclen, _ts_a) = match frame::session_request(&buf) {
+                    let (padlen, sclen, ts_a) = match frame::session_request(&buf) {
                         Err(e) => {
                             return io_err!(Other, format!("SessionRequest parse error: {:?}", e));
                         }
-                        Ok((_, (ver, _, _, _))) if ver != 2 => {
+                        Ok((_, (ver, _, _, _)))
+                            if !NTCP2_VERSIONS.iter().any(|v| v.parse::<u8>() == Ok(ver)) =>
+                        {
                             return io_err!(InvalidData, "Unsupported version");
                         }
                         Ok((_, (_, padlen, sclen, ts_a))) => {
                             (padlen as usize, sclen as usize, ts_a)
                         }
                     };
+                    self.trace.record(
+                        "SessionRequest",
+                        msg.len(),
+                        0,
+                        Some(format!("padlen {}, sclen {}, ts_a {}", padlen, sclen, ts_a)),
+                    );
+
+                    // Don't let Alice make us allocate more than we need to
+                    if padlen > SESSION_REQUEST_MAX_PADDING {
+                        return io_err!(
+                            InvalidData,
+                            format!("SessionRequest padding too long: {}", padlen)
+                        );
+                    }
+                    if sclen > SESSION_CONFIRMED_MAX_PART2_LEN {
+                        return io_err!(
+                            InvalidData,
+                            format!("SessionConfirmed part 2 too long: {}", sclen)
+                        );
+                    }
                     self.sclen = sclen;
 
+                    // Check Alice's clock
+                    if let Err(ahead) = check_future_skew(
+                        UNIX_EPOCH + Duration::from_secs(ts_a.into()),
+                        self.config.max_future_skew,
+                    ) {
+                        return io_err!(
+                            InvalidData,
+                            format!("SessionRequest timestamp is {:?} in the future", ahead)
+                        );
+                    }
+                    if let Err(behind) = check_past_skew(
+                        UNIX_EPOCH + Duration::from_secs(ts_a.into()),
+                        self.config.max_past_skew,
+                    ) {
+                        return io_err!(
+                            InvalidData,
+                            format!("SessionRequest timestamp is {:?} in the past", behind)
+                        );
+                    }
+
+                    // Drop replayed SessionRequests. Alice's ephemeral key is
+                    // unique to each handshake, so is also unique in its
+                    // obfuscated form.
+                    if self.config.replay_filter.seen(&msg[..32]) {
+                        return io_err!(InvalidData, "Replayed SessionRequest");
+                    }
+
                     IBHandshakeState::SessionRequestPadding(io::read_exact(conn, vec![0u8; padlen]))
                 }
                 IBHandshakeState::SessionRequestPadding(ref mut f) => {
                     let (conn, padding) = try_poll!(f, self, noise);
+                    self.trace.record("SessionRequestPadding", padding.len(), 0, None);
 
-                    noise.set_h_data(2, &padding).unwrap();
+                    if let Err(e) = noise.set_h_data(2, &padding) {
+                        return io_err!(Other, format!("Could not set handshake data: {:?}", e));
+                    }
 
                     let now = SystemTime::now();
                     let mut ts_b = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
                     ts_b.add_assign(Duration::from_millis(500));
                     let ts_b = ts_b.as_secs() as u32;
 
-                    let mut rng = OsRng;
-                    // TODO: Sample padding sizes from an appropriate distribution
-                    let sc_padlen = rng.gen_range(0, 16);
+                    let sc_padlen = padding::handshake_padlen(self.config.padding.handshake_max);
 
                     // SessionCreated
                     let mut sc_buf = [0u8; SESSION_CREATED_PT_LEN];
@@ -159,17 +460,22 @@ where
                     // -> e, ee
                     debug!("S -> e, ee");
                     let mut buf = vec![0u8; SESSION_CREATED_CT_LEN + sc_padlen as usize];
-                    noise.write_message(&sc_buf, &mut buf).unwrap();
-                    rng.fill(&mut buf[SESSION_CREATED_CT_LEN..]);
-                    noise.set_h_data(3, &buf[SESSION_CREATED_CT_LEN..]).unwrap();
+                    if let Err(e) = noise.write_message(&sc_buf, &mut buf) {
+                        return io_err!(Other, format!("Could not encrypt SessionCreated: {:?}", e));
+                    }
+                    padding::fill(&mut buf[SESSION_CREATED_CT_LEN..]);
+                    if let Err(e) = noise.set_h_data(3, &buf[SESSION_CREATED_CT_LEN..]) {
+                        return io_err!(Other, format!("Could not set handshake data: {:?}", e));
+                    }
 
                     IBHandshakeState::SessionCreated((io::write_all(conn, buf), now))
                 }
                 IBHandshakeState::SessionCreated((ref mut f, rtt_timer)) => {
-                    let (conn, _) = try_poll!(f, self, noise);
+                    let (conn, buf) = try_poll!(f, self, noise);
+                    self.trace.record("SessionCreated", 0, buf.len(), None);
 
                     IBHandshakeState::SessionConfirmed((
-                        io::read_exact(conn, vec![0u8; self.sclen + 48]),
+                        io::read_exact(conn, vec![0u8; SESSION_CONFIRMED_PART1_LEN + self.sclen]),
                         rtt_timer,
                     ))
                 }
@@ -179,7 +485,15 @@ where
                     // <- s, se
                     debug!("S <- s, se");
                     let mut buf = vec![0u8; msg.len()];
-                    let len = noise.read_message(&msg, &mut buf).unwrap();
+                    let len = match noise.read_message(&msg, &mut buf) {
+                        Ok(len) => len,
+                        Err(e) => {
+                            return io_err!(
+                                InvalidData,
+                                format!("Could not decrypt SessionConfirmed: {:?}", e)
+                            );
+                        }
+                    };
 
                     // SessionConfirmed
                     let mut frames = match frame::session_confirmed(&buf[..len]) {
@@ -213,6 +527,57 @@ where
                         }
                     };
 
+                    // Sessions that we refuse once the handshake is complete,
+                    // along with the reason that we give the peer
+                    let static_key_matches = match noise.get_remote_static() {
+                        Some(s) => advertises_static_key(&ri_a, s),
+                        None => false,
+                    };
+                    let peer = ri_a.router_id.hash();
+                    let refusal = if let Err(e) = ri_a.verify() {
+                        Some((
+                            TerminationReason::RouterInfoSignature,
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("Invalid RouterInfo in SessionConfirmed: {}", e),
+                            ),
+                        ))
+                    } else if !static_key_matches {
+                        Some((
+                            TerminationReason::StaticKeyMismatch,
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Static key in SessionConfirmed doesn't match RouterInfo",
+                            ),
+                        ))
+                    } else {
+                        self.trace.record(
+                            "SessionConfirmed",
+                            msg.len(),
+                            0,
+                            Some(format!("RouterInfo for {}, {} other blocks", peer, frames.len())),
+                        );
+                        if !self.config.peer_filter.permits(&peer) {
+                            Some((
+                                TerminationReason::Banned,
+                                io::Error::new(
+                                    io::ErrorKind::PermissionDenied,
+                                    format!("Rejected handshake from filtered peer {}", peer),
+                                ),
+                            ))
+                        } else if self.config.reconnect_limiter.permits(&peer) {
+                            None
+                        } else {
+                            Some((
+                                TerminationReason::Banned,
+                                io::Error::new(
+                                    io::ErrorKind::ConnectionRefused,
+                                    format!("Peer {} is reconnecting too often", peer),
+                                ),
+                            ))
+                        }
+                    };
+
                     // Get peer skew
                     let rtt = rtt_timer.elapsed().expect("Time went backwards?");
                     debug!("Peer RTT: {:?}", rtt);
@@ -220,8 +585,15 @@ where
                     // Prepare length obfuscation keys and IVs
                     let (ek0, ek1, eiv, dk0, dk1, div) = {
                         let label = String::from("siphash");
-                        noise.initialize_ask(vec![label.clone()]).unwrap();
-                        let (ask0, ask1) = noise.finalize_ask(&label).unwrap();
+                        if let Err(e) = noise.initialize_ask(vec![label.clone()]) {
+                            return io_err!(Other, format!("Could not initialize ask: {:?}", e));
+                        }
+                        let (ask0, ask1) = match noise.finalize_ask(&label) {
+                            Ok(ask) => ask,
+                            Err(e) => {
+                                return io_err!(Other, format!("Could not finalize ask: {:?}", e));
+                            }
+                        };
 
                         // Bob to Alice
                         let mut ek0 = [0; 8];
@@ -249,8 +621,7 @@ where
                         )
                     };
 
-                    // Transition the state machine into transport mode now that the handshake is complete.
-                    let noise = noise.into_transport_mode().unwrap();
+                    let noise = into_transport_mode(noise)?;
                     info!("Connection established!");
 
                     let codec = Codec {
@@ -261,17 +632,50 @@ where
                         dec_len_masker: SipHasher::new_with_keys(dk0, dk1),
                         dec_len_iv: div,
                         next_len: None,
+                        frames_received: 0,
+                        padding: padding::DataPadding::new(self.config.padding),
                     };
 
+                    if let Some((reason, err)) = refusal {
+                        // Finish the handshake so that we can tell the peer why,
+                        // rather than just dropping the connection
+                        let termination = Block::Termination(0, reason, vec![]);
+                        self.state = IBHandshakeState::Terminating((
+                            codec.framed(conn).send(vec![termination]),
+                            Some(err),
+                        ));
+                        self.step_deadline = deadline_after(self.config.step_timeout);
+                        continue;
+                    }
+
                     return Ok(Async::Ready((ri_a, codec.framed(conn))));
                 }
             };
             self.noise = Some(noise);
             self.state = next_state;
+            self.step_deadline = deadline_after(self.config.step_timeout);
         }
     }
 }
 
+impl<T> Future for IBHandshake<T>
+where
+    T: AsyncRead + AsyncWrite,
+    T: Send + 'static,
+{
+    type Item = (RouterInfo, Framed<T, Codec>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let res = self.poll_handshake();
+        if let Err(ref e) = res {
+            let state = self.state.name();
+            self.trace.record(state, 0, 0, Some(format!("Failed: {}", e)));
+        }
+        res
+    }
+}
+
 enum OBHandshakeState<T> {
     Connecting(IoFuture<T>),
     SessionRequest((WriteAll<T, Vec<u8>>, SystemTime)),
@@ -280,12 +684,29 @@ enum OBHandshakeState<T> {
     SessionConfirmed(WriteAll<T, Vec<u8>>),
 }
 
+impl<T> OBHandshakeState<T> {
+    fn name(&self) -> &'static str {
+        match self {
+            OBHandshakeState::Connecting(_) => "Connecting",
+            OBHandshakeState::SessionRequest(_) => "SessionRequest",
+            OBHandshakeState::SessionCreated(_) => "SessionCreated",
+            OBHandshakeState::SessionCreatedPadding(_) => "SessionCreatedPadding",
+            OBHandshakeState::SessionConfirmed(_) => "SessionConfirmed",
+        }
+    }
+}
+
 pub struct OBHandshake<T> {
     noise: Option<Session>,
+    config: HandshakeConfig,
+    deadline: Option<Delay>,
+    step_deadline: Option<Delay>,
+    version: u8,
     sc_buf: Vec<u8>,
     sc_len: usize,
     peer_ri: RouterInfo,
     state: OBHandshakeState<T>,
+    trace: Trace,
 }
 
 impl<T> OBHandshake<T>
@@ -298,56 +719,44 @@ where
         static_key: &[u8],
         own_ri: &RouterInfo,
         peer_ri: RouterInfo,
+        config: HandshakeConfig,
     ) -> Result<OBHandshake<T>, String>
     where
-        F: FnOnce(&SocketAddr) -> IoFuture<T>,
+        F: FnOnce(&[SocketAddr]) -> IoFuture<T>,
     {
-        let filter = |ra: &RouterAddress| {
-            match ra.option(&NTCP2_OPT_V) {
-                Some(v) => {
-                    if !v.to_csv().contains(&NTCP2_VERSION) {
-                        return false;
-                    }
-                }
-                None => return false,
-            };
-            ra.option(&NTCP2_OPT_S).is_some() && ra.option(&NTCP2_OPT_I).is_some()
-        };
-
-        let ra = match peer_ri.address(&NTCP2_STYLE, filter) {
-            Some(ra) => ra,
-            None => match peer_ri.address(&NTCP_STYLE, filter) {
-                Some(ra) => ra,
-                None => return Err("No valid NTCP2 addresses".to_string()),
-            },
-        };
+        // A malformed address shouldn't stop us from using the peer's others
+        let mut candidates = vec![];
+        let is_reachable = |ra: &RouterAddress| is_reachable_ntcp2_address(ra, config.ipv6);
+        for ra in peer_ri
+            .matching_addresses(&NTCP2_STYLE, is_reachable)
+            .into_iter()
+            .chain(peer_ri.matching_addresses(&NTCP_STYLE, is_reachable))
+        {
+            match address_params(ra) {
+                Ok(params) => candidates.push(params),
+                Err(e) => debug!("Skipping address of {}: {}", peer_ri.router_id.hash(), e),
+            }
+        }
 
-        let addr = ra.addr().unwrap();
-        let remote_key = match ra.option(&NTCP2_OPT_S) {
-            Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
-                Ok(key) => key,
-                Err(e) => return Err(format!("Invalid static key in address: {}", e)),
-            },
-            None => return Err("No static key in address".to_string()),
+        // Try addresses in our preferred family first
+        candidates.sort_by_key(|(_, addr, _, _)| addr.is_ipv6() != config.prefer_ipv6);
+        let (version, _, remote_key, aesobfse_iv) = match candidates.first() {
+            Some(params) => params.clone(),
+            None => return Err("No valid NTCP2 addresses".to_string()),
         };
 
+        // If we can't connect to one address, fall back to the others that use
+        // the same keys, which may be in the other family
+        let addrs: Vec<_> = candidates
+            .iter()
+            .filter(|(_, _, key, iv)| *key == remote_key && *iv == aesobfse_iv)
+            .map(|(_, addr, _, _)| *addr)
+            .collect();
         let aesobfse_key = peer_ri.router_id.hash().0;
-        let mut aesobfse_iv = [0; 16];
-        match ra.option(&NTCP2_OPT_I) {
-            Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
-                Ok(iv) => aesobfse_iv.copy_from_slice(&iv),
-                Err(e) => return Err(format!("Invalid IV in address: {}", e)),
-            },
-            None => return Err("No IV in address".to_string()),
-        }
 
-        let sc_padlen = {
-            let mut rng = OsRng;
-            // TODO: Sample padding sizes from an appropriate distribution
-            rng.gen_range(0, 16)
-        };
+        let sc_padlen = padding::handshake_padlen(config.padding.handshake_max);
 
-        let mut sc_buf = vec![0u8; NTCP2_MTU - 16];
+        let mut sc_buf = vec![0u8; SESSION_CONFIRMED_MAX_PART2_LEN - 16];
         let sc_len = match frame::gen_session_confirmed((&mut sc_buf, 0), own_ri, sc_padlen)
             .map(|tup| tup.1)
         {
@@ -357,7 +766,7 @@ where
                     return Err(format!(
                         "SessionConfirmed message ({}) larger than MTU ({})",
                         sz,
-                        NTCP2_MTU - 16
+                        SESSION_CONFIRMED_MAX_PART2_LEN - 16
                     ));
                 }
                 GenError::InvalidOffset
@@ -369,7 +778,8 @@ where
         let sc_len = sc_len + 16;
 
         // Initialize our initiator NoiseSession using a builder.
-        let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
+        let builder: Builder<'_> =
+            Builder::new(config.cipher_suite.protocol_name().parse().unwrap());
         let noise = builder
             .local_private_key(&static_key)
             .remote_public_key(&remote_key)
@@ -378,9 +788,14 @@ where
             .build_initiator()
             .unwrap();
 
-        let state = OBHandshakeState::Connecting(conn(&addr));
+        let state = OBHandshakeState::Connecting(conn(&addrs));
         Ok(OBHandshake {
             noise: Some(noise),
+            deadline: deadline_after(config.timeout),
+            step_deadline: deadline_after(config.step_timeout),
+            trace: Trace::new("Outbound", config.trace),
+            config,
+            version,
             sc_buf,
             sc_len,
             peer_ri,
@@ -389,35 +804,33 @@ where
     }
 }
 
-impl<T> Future for OBHandshake<T>
+impl<T> OBHandshake<T>
 where
     T: AsyncRead + AsyncWrite,
     T: Send + 'static,
 {
-    type Item = (RouterIdentity, Framed<T, Codec>);
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    fn poll_handshake(&mut self) -> Poll<(RouterIdentity, Framed<T, Codec>), io::Error> {
         loop {
+            check_deadlines(&mut self.deadline, &mut self.step_deadline, self.state.name())?;
+
             let mut noise = self.noise.take().unwrap();
             let next_state = match self.state {
                 OBHandshakeState::Connecting(ref mut f) => {
                     let conn = try_poll!(f, self, noise);
+                    self.trace.record("Connecting", 0, 0, None);
 
                     let now = SystemTime::now();
                     let mut ts_a = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
                     ts_a.add_assign(Duration::from_millis(500));
                     let ts_a = ts_a.as_secs() as u32;
 
-                    let mut rng = OsRng;
-                    // TODO: Sample padding sizes from an appropriate distribution
-                    let padlen = rng.gen_range(0, 16);
+                    let padlen = padding::handshake_padlen(self.config.padding.handshake_max);
 
                     // SessionRequest
                     let mut sr_buf = [0u8; SESSION_REQUEST_PT_LEN];
                     match frame::gen_session_request(
                         (&mut sr_buf, 0),
-                        2,
+                        self.version,
                         padlen,
                         self.sc_len as u16,
                         ts_a,
@@ -439,15 +852,20 @@ where
                     // -> e, es
                     debug!("C -> e, es");
                     let mut buf = vec![0u8; SESSION_REQUEST_CT_LEN + padlen as usize];
-                    noise.write_message(&sr_buf, &mut buf).unwrap();
-                    rng.fill(&mut buf[SESSION_REQUEST_CT_LEN..]);
-                    noise.set_h_data(2, &buf[SESSION_REQUEST_CT_LEN..]).unwrap();
+                    if let Err(e) = noise.write_message(&sr_buf, &mut buf) {
+                        return io_err!(Other, format!("Could not encrypt SessionRequest: {:?}", e));
+                    }
+                    padding::fill(&mut buf[SESSION_REQUEST_CT_LEN..]);
+                    if let Err(e) = noise.set_h_data(2, &buf[SESSION_REQUEST_CT_LEN..]) {
+                        return io_err!(Other, format!("Could not set handshake data: {:?}", e));
+                    }
 
                     OBHandshakeState::SessionRequest((io::write_all(conn, buf), now))
                 }
 
                 OBHandshakeState::SessionRequest((ref mut f, rtt_timer)) => {
-                    let (conn, _) = try_poll!(f, self, noise);
+                    let (conn, buf) = try_poll!(f, self, noise);
+                    self.trace.record("SessionRequest", 0, buf.len(), None);
 
                     OBHandshakeState::SessionCreated((
                         io::read_exact(conn, vec![0u8; SESSION_CREATED_CT_LEN]),
@@ -460,15 +878,46 @@ where
                     // <- e, ee
                     debug!("C <- e, ee");
                     let mut buf = [0u8; SESSION_CREATED_PT_LEN];
-                    noise.read_message(&msg, &mut buf).unwrap();
+                    if let Err(e) = noise.read_message(&msg, &mut buf) {
+                        return io_err!(
+                            InvalidData,
+                            format!("Could not decrypt SessionCreated: {:?}", e)
+                        );
+                    }
 
                     // SessionCreated
-                    let (padlen, _ts_b) = match frame::session_created(&buf) {
+                    let (padlen, ts_b) = match frame::session_created(&buf) {
                         Err(e) => {
                             return io_err!(Other, format!("SessionCreated parse error: {:?}", e));
                         }
                         Ok((_, (padlen, ts_b))) => (padlen as usize, ts_b),
                     };
+                    self.trace.record(
+                        "SessionCreated",
+                        msg.len(),
+                        0,
+                        Some(format!("padlen {}, ts_b {}", padlen, ts_b)),
+                    );
+
+                    // Check Bob's clock
+                    if let Err(ahead) = check_future_skew(
+                        UNIX_EPOCH + Duration::from_secs(ts_b.into()),
+                        self.config.max_future_skew,
+                    ) {
+                        return io_err!(
+                            InvalidData,
+                            format!("SessionCreated timestamp is {:?} in the future", ahead)
+                        );
+                    }
+                    if let Err(behind) = check_past_skew(
+                        UNIX_EPOCH + Duration::from_secs(ts_b.into()),
+                        self.config.max_past_skew,
+                    ) {
+                        return io_err!(
+                            InvalidData,
+                            format!("SessionCreated timestamp is {:?} in the past", behind)
+                        );
+                    }
 
                     // Get peer skew
                     let rtt = rtt_timer.elapsed().expect("Time went backwards?");
@@ -478,27 +927,46 @@ where
                 }
                 OBHandshakeState::SessionCreatedPadding(ref mut f) => {
                     let (conn, padding) = try_poll!(f, self, noise);
+                    self.trace.record("SessionCreatedPadding", padding.len(), 0, None);
 
-                    noise.set_h_data(3, &padding).unwrap();
+                    if let Err(e) = noise.set_h_data(3, &padding) {
+                        return io_err!(Other, format!("Could not set handshake data: {:?}", e));
+                    }
 
                     // SessionConfirmed
 
                     // -> s, se
                     debug!("C -> s, se");
                     let mut buf = vec![0u8; NTCP2_MTU];
-                    let len = noise.write_message(&self.sc_buf, &mut buf).unwrap();
+                    let len = match noise.write_message(&self.sc_buf, &mut buf) {
+                        Ok(len) => len,
+                        Err(e) => {
+                            return io_err!(
+                                Other,
+                                format!("Could not encrypt SessionConfirmed: {:?}", e)
+                            );
+                        }
+                    };
                     buf.truncate(len);
 
                     OBHandshakeState::SessionConfirmed(io::write_all(conn, buf))
                 }
                 OBHandshakeState::SessionConfirmed(ref mut f) => {
-                    let (conn, _) = try_poll!(f, self, noise);
+                    let (conn, buf) = try_poll!(f, self, noise);
+                    self.trace.record("SessionConfirmed", 0, buf.len(), None);
 
                     // Prepare length obfuscation keys and IVs
                     let (ek0, ek1, eiv, dk0, dk1, div) = {
                         let label = String::from("siphash");
-                        noise.initialize_ask(vec![label.clone()]).unwrap();
-                        let (ask0, ask1) = noise.finalize_ask(&label).unwrap();
+                        if let Err(e) = noise.initialize_ask(vec![label.clone()]) {
+                            return io_err!(Other, format!("Could not initialize ask: {:?}", e));
+                        }
+                        let (ask0, ask1) = match noise.finalize_ask(&label) {
+                            Ok(ask) => ask,
+                            Err(e) => {
+                                return io_err!(Other, format!("Could not finalize ask: {:?}", e));
+                            }
+                        };
 
                         // Alice to Bob
                         let mut ek0 = [0; 8];
@@ -526,8 +994,7 @@ where
                         )
                     };
 
-                    // Transition the state machine into transport mode now that the handshake is complete.
-                    let noise = noise.into_transport_mode().unwrap();
+                    let noise = into_transport_mode(noise)?;
 
                     let codec = Codec {
                         noise,
@@ -537,6 +1004,8 @@ where
                         dec_len_masker: SipHasher::new_with_keys(dk0, dk1),
                         dec_len_iv: div,
                         next_len: None,
+                        frames_received: 0,
+                        padding: padding::DataPadding::new(self.config.padding),
                     };
 
                     return Ok(Async::Ready((
@@ -547,23 +1016,112 @@ where
             };
             self.noise = Some(noise);
             self.state = next_state;
+            self.step_deadline = deadline_after(self.config.step_timeout);
+        }
+    }
+}
+
+impl<T> Future for OBHandshake<T>
+where
+    T: AsyncRead + AsyncWrite,
+    T: Send + 'static,
+{
+    type Item = (RouterIdentity, Framed<T, Codec>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let res = self.poll_handshake();
+        if let Err(ref e) = res {
+            let state = self.state.name();
+            self.trace.record(state, 0, 0, Some(format!("Failed: {}", e)));
         }
+        res
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{IBHandshake, IBHandshakeState, OBHandshake, OBHandshakeState};
+    use super::{
+        into_transport_mode, HandshakeTimeout, IBHandshake, IBHandshakeState, OBHandshake,
+        OBHandshakeState, Trace, SESSION_CONFIRMED_MAX_PART2_LEN, SESSION_REQUEST_CT_LEN,
+    };
     use crate::transport::{
-        ntcp2::Manager,
+        counter::ByteCounter,
+        ntcp2::{
+            is_ntcp2_address, negotiate_version, Block, CipherSuite, HandshakeConfig, Manager,
+            PaddingConfig, TerminationReason, NTCP2_STYLE,
+        },
         tests::{AliceNet, BobNet, NetworkCable},
+        PeerFilter, ReconnectLimiter,
     };
 
-    use futures::{done, Async, Future};
-
-    use crate::data::{RouterInfo, RouterSecretKeys};
+    use bytes::BytesMut;
+    use futures::{done, Async, Future, Poll, Stream};
+    use i2p_snow::Builder;
+    use std::collections::HashSet;
+    use std::io::{self, Read, Write};
+    use std::iter::once;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::codec::{Decoder, Encoder};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::data::{
+        I2PDate, I2PString, RouterAddress, RouterInfo, RouterSecretKeys, NTCP2_OPT_I, NTCP2_OPT_S,
+    };
+    use crate::i2np::Message;
     use crate::router::mock::MockDistributor;
 
+    /// Flips every byte read from the inner connection after the first `offset`.
+    struct Corrupt<T> {
+        inner: T,
+        offset: usize,
+        read: usize,
+    }
+
+    impl<T> Corrupt<T> {
+        fn new(inner: T, offset: usize) -> Self {
+            Corrupt {
+                inner,
+                offset,
+                read: 0,
+            }
+        }
+    }
+
+    impl<T: Read> Read for Corrupt<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            for (i, b) in buf[..n].iter_mut().enumerate() {
+                if self.read + i >= self.offset {
+                    *b ^= 0xff;
+                }
+            }
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    impl<T: Write> Write for Corrupt<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<T: AsyncRead> AsyncRead for Corrupt<T> {}
+    impl<T: AsyncWrite> AsyncWrite for Corrupt<T> {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            self.inner.shutdown()
+        }
+    }
+
     macro_rules! test_poll {
         ($node:expr) => {
             match $node.poll() {
@@ -583,15 +1141,77 @@ mod tests {
         };
     }
 
-    #[test]
-    fn ntcp2_handshake() {
-        // Generate key material
+    fn handshake_pair(
+        alice_suite: CipherSuite,
+        bob_suite: CipherSuite,
+    ) -> (OBHandshake<AliceNet>, IBHandshake<BobNet>) {
+        handshake_pair_with(alice_suite, bob_suite, |bob_net| bob_net)
+    }
+
+    fn handshake_pair_with<B, F>(
+        alice_suite: CipherSuite,
+        bob_suite: CipherSuite,
+        bob_conn: F,
+    ) -> (OBHandshake<AliceNet>, IBHandshake<B>)
+    where
+        B: AsyncRead + AsyncWrite + Send + 'static,
+        F: FnOnce(BobNet) -> B,
+    {
+        handshake_pair_config(
+            RouterSecretKeys::new(),
+            HandshakeConfig {
+                cipher_suite: alice_suite,
+                // These tests poll the handshakes without a timer
+                timeout: None,
+                ..Default::default()
+            },
+            HandshakeConfig {
+                cipher_suite: bob_suite,
+                timeout: None,
+                ..Default::default()
+            },
+            bob_conn,
+        )
+    }
+
+    fn handshake_pair_config<B, F>(
+        alice_keys: RouterSecretKeys,
+        alice_config: HandshakeConfig,
+        bob_config: HandshakeConfig,
+        bob_conn: F,
+    ) -> (OBHandshake<AliceNet>, IBHandshake<B>)
+    where
+        B: AsyncRead + AsyncWrite + Send + 'static,
+        F: FnOnce(BobNet) -> B,
+    {
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
         let alice_ri = {
-            let sk = RouterSecretKeys::new();
-            let mut ri = RouterInfo::new(sk.rid.clone());
-            ri.sign(&sk.signing_private_key);
+            let mut ri = RouterInfo::new(alice_keys.rid.clone());
+            ri.set_addresses(vec![manager.address()]);
+            ri.sign(&alice_keys.signing_private_key);
             ri
         };
+        handshake_pair_ri(
+            alice_ri,
+            &manager.keys.read().unwrap().private,
+            alice_config,
+            bob_config,
+            bob_conn,
+        )
+    }
+
+    fn handshake_pair_ri<B, F>(
+        alice_ri: RouterInfo,
+        alice_static_private_key: &[u8],
+        alice_config: HandshakeConfig,
+        bob_config: HandshakeConfig,
+        bob_conn: F,
+    ) -> (OBHandshake<AliceNet>, IBHandshake<B>)
+    where
+        B: AsyncRead + AsyncWrite + Send + 'static,
+        F: FnOnce(BobNet) -> B,
+    {
+        // Generate key material
         let (
             bob_ri,
             bob_static_public_key,
@@ -605,12 +1225,13 @@ mod tests {
             let mut ri = RouterInfo::new(sk.rid.clone());
             ri.set_addresses(vec![manager.address()]);
             ri.sign(&sk.signing_private_key);
+            let keys = manager.keys.read().unwrap();
             (
                 ri,
-                manager.static_public_key,
-                manager.static_private_key,
+                keys.public.clone(),
+                keys.private.clone(),
                 sk.rid.hash().0,
-                manager.aesobfse_iv,
+                keys.aesobfse_iv,
             )
         };
 
@@ -620,19 +1241,26 @@ mod tests {
         let bob_net = BobNet::new(cable);
 
         // Set up the handshake
-        let mut alice = OBHandshake::new(
+        let alice = OBHandshake::new(
             |_| Box::new(done(Ok(alice_net))),
-            &bob_static_public_key,
+            alice_static_private_key,
             &alice_ri,
             bob_ri,
+            alice_config,
         )
         .unwrap();
-        let mut bob = IBHandshake::new(
-            bob_net,
+        let bob = IBHandshake::new(
+            bob_conn(bob_net),
             &bob_static_private_key,
             &bob_aesobfse_key,
             &bob_aesobfse_iv,
+            bob_config,
         );
+        (alice, bob)
+    }
+
+    fn complete_handshake(suite: CipherSuite) {
+        let (mut alice, mut bob) = handshake_pair(suite, suite);
         test_state!(alice, Connecting, bob, SessionRequest);
 
         // Connect Alice to Bob
@@ -659,6 +1287,810 @@ mod tests {
         }
     }
 
+    /// Prepares a handshake with a peer that publishes the given addresses.
+    fn handshake_to(addresses: Vec<RouterAddress>) -> Result<OBHandshake<AliceNet>, String> {
+        let peer_keys = RouterSecretKeys::new();
+        let mut peer_ri = RouterInfo::new(peer_keys.rid);
+        peer_ri.set_addresses(addresses);
+        peer_ri.sign(&peer_keys.signing_private_key);
+
+        let own_keys = RouterSecretKeys::new();
+        let mut own_ri = RouterInfo::new(own_keys.rid);
+        own_ri.sign(&own_keys.signing_private_key);
+
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        OBHandshake::new(
+            |_| Box::new(done(Ok(AliceNet::new(NetworkCable::new())))),
+            &manager.keys.read().unwrap().private,
+            &own_ri,
+            peer_ri,
+            HandshakeConfig::default(),
+        )
+    }
+
+    #[test]
+    fn ntcp2_address_options() {
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let mut ra = RouterAddress::new(&NTCP2_STYLE, addr);
+        assert!(!is_ntcp2_address(&ra));
+
+        ra.set_ntcp2(&[7; 32], &[9; 16], &["1"]);
+        assert!(!is_ntcp2_address(&ra));
+
+        ra.set_ntcp2(&[7; 32], &[9; 16], &["1", "2"]);
+        assert!(is_ntcp2_address(&ra));
+        assert_eq!(ra.addr(), Some(addr));
+
+        // We can start a handshake with a peer that publishes the address
+        assert!(handshake_to(vec![ra]).is_ok());
+    }
+
+    #[test]
+    fn ntcp2_address_fallthrough() {
+        let mut corrupt = RouterAddress::new(&NTCP2_STYLE, "127.0.0.1:12345".parse().unwrap());
+        corrupt.set_ntcp2(&[7; 32], &[9; 16], &["2"]);
+        corrupt.set_option(NTCP2_OPT_S.clone(), I2PString::new("not base64!"));
+        let mut short_iv = RouterAddress::new(&NTCP2_STYLE, "127.0.0.1:23456".parse().unwrap());
+        short_iv.set_ntcp2(&[7; 32], &[9; 16], &["2"]);
+        short_iv.set_option(NTCP2_OPT_I.clone(), I2PString::new("AAAA"));
+        let mut valid = RouterAddress::new(&NTCP2_STYLE, "127.0.0.1:34567".parse().unwrap());
+        valid.set_ntcp2(&[7; 32], &[9; 16], &["2"]);
+
+        assert!(handshake_to(vec![corrupt.clone(), short_iv.clone()]).is_err());
+
+        // The malformed addresses are skipped
+        let handshake = handshake_to(vec![corrupt, short_iv, valid]).unwrap();
+        match handshake.state {
+            OBHandshakeState::Connecting(_) => (),
+            _ => panic!("Unexpected state"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_address_family() {
+        let address = |addr: &str, static_key: u8| {
+            let mut ra = RouterAddress::new(&NTCP2_STYLE, addr.parse().unwrap());
+            ra.set_ntcp2(&[static_key; 32], &[9; 16], &["2"]);
+            ra
+        };
+        let v4: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let v6: SocketAddr = "[::1]:12345".parse().unwrap();
+
+        let peer_keys = RouterSecretKeys::new();
+        let own_keys = RouterSecretKeys::new();
+        let mut own_ri = RouterInfo::new(own_keys.rid);
+        own_ri.sign(&own_keys.signing_private_key);
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+
+        // Returns the addresses that a handshake would try to connect to
+        let connect_order = |addresses: Vec<RouterAddress>, prefer_ipv6: bool, ipv6: bool| {
+            let mut peer_ri = RouterInfo::new(peer_keys.rid.clone());
+            peer_ri.set_addresses(addresses);
+            peer_ri.sign(&peer_keys.signing_private_key);
+
+            let mut tried = vec![];
+            let res = OBHandshake::new(
+                |addrs: &[SocketAddr]| {
+                    tried = addrs.to_vec();
+                    Box::new(done(Ok(AliceNet::new(NetworkCable::new()))))
+                },
+                &manager.keys.read().unwrap().private,
+                &own_ri,
+                peer_ri,
+                HandshakeConfig {
+                    prefer_ipv6,
+                    ipv6,
+                    ..Default::default()
+                },
+            );
+            res.map(|_| tried)
+        };
+
+        // IPv6-only peers are reachable if we have IPv6
+        let v6_only = vec![address("[::1]:12345", 7)];
+        assert_eq!(connect_order(v6_only.clone(), false, true), Ok(vec![v6]));
+        assert!(connect_order(v6_only, false, false).is_err());
+
+        // The preferred family is tried first, falling back to the other
+        let both = vec![address("[::1]:12345", 7), address("127.0.0.1:12345", 7)];
+        assert_eq!(connect_order(both.clone(), false, true), Ok(vec![v4, v6]));
+        assert_eq!(connect_order(both.clone(), true, true), Ok(vec![v6, v4]));
+
+        // IPv6 addresses are ignored if we don't have IPv6
+        assert_eq!(connect_order(both, true, false), Ok(vec![v4]));
+
+        // Addresses with different keys can't be fallen back to
+        let different = vec![address("127.0.0.1:12345", 7), address("[::1]:12345", 8)];
+        assert_eq!(connect_order(different, true, true), Ok(vec![v6]));
+    }
+
+    #[test]
+    fn ntcp2_version_negotiation() {
+        let mut ra = RouterAddress::new(&NTCP2_STYLE, "127.0.0.1:12345".parse().unwrap());
+        ra.set_ntcp2(&[7; 32], &[9; 16], &["2", "3"]);
+        assert_eq!(negotiate_version(&ra, &["2"]), Some(2));
+        assert_eq!(negotiate_version(&ra, &["2", "3"]), Some(3));
+        assert_eq!(negotiate_version(&ra, &["1"]), None);
+
+        // We use the highest version that we support
+        let handshake = handshake_to(vec![ra]).unwrap();
+        assert_eq!(handshake.version, 2);
+    }
+
+    #[test]
+    fn ntcp2_handshake() {
+        complete_handshake(CipherSuite::default());
+    }
+
+    #[test]
+    fn ntcp2_termination() {
+        let (mut alice, mut bob) = handshake_pair(CipherSuite::default(), CipherSuite::default());
+        test_poll!(alice);
+        test_poll!(bob);
+        let (mut alice_codec, mut bob_codec) = match (alice.poll(), bob.poll()) {
+            (Ok(Async::Ready((_, alice))), Ok(Async::Ready((_, bob)))) => {
+                (alice.into_parts().codec, bob.into_parts().codec)
+            }
+            _ => panic!("Handshake should have completed"),
+        };
+
+        // Alice sends two frames to Bob
+        let mut buf = BytesMut::new();
+        for _ in 0..2 {
+            alice_codec
+                .encode(vec![Block::Padding(10)], &mut buf)
+                .unwrap();
+            assert!(bob_codec.decode(&mut buf).unwrap().is_some());
+        }
+
+        // Bob terminates the session, and his codec tells Alice how many frames
+        // he received (alongside his padding options, and possibly some padding)
+        let termination = Block::Termination(0, TerminationReason::IdleTimeout, vec![]);
+        bob_codec.encode(vec![termination], &mut buf).unwrap();
+        let frame = alice_codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            frame
+                .into_iter()
+                .filter(|block| match block {
+                    Block::Options(_) | Block::Padding(_) => false,
+                    _ => true,
+                })
+                .collect::<Vec<_>>(),
+            vec![Block::Termination(
+                2,
+                TerminationReason::IdleTimeout,
+                vec![]
+            )]
+        );
+    }
+
+    #[test]
+    fn ntcp2_padding_negotiation() {
+        // Alice wants to send far more padding than Bob accepts
+        let handshakes = || {
+            let (mut alice, mut bob) = handshake_pair_config(
+                RouterSecretKeys::new(),
+                HandshakeConfig {
+                    timeout: None,
+                    padding: PaddingConfig {
+                        min_ratio: 8.0,
+                        max_ratio: 8.0,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                HandshakeConfig {
+                    timeout: None,
+                    padding: PaddingConfig {
+                        max_received_ratio: 1.0,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                |bob_net| bob_net,
+            );
+            test_poll!(alice);
+            test_poll!(bob);
+            match (alice.poll(), bob.poll()) {
+                (Ok(Async::Ready((_, alice))), Ok(Async::Ready((_, bob)))) => {
+                    (alice.into_parts().codec, bob.into_parts().codec)
+                }
+                _ => panic!("Handshake should have completed"),
+            }
+        };
+        let data = || vec![Block::Message(Message::dummy_data())];
+        let mut buf = BytesMut::new();
+
+        // Before Alice has received Bob's options, she pads too much
+        let (mut alice_codec, mut bob_codec) = handshakes();
+        alice_codec.encode(data(), &mut buf).unwrap();
+        match bob_codec.decode(&mut buf) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("Bob should have rejected the padding"),
+        }
+
+        // Once she has, she pads as much as Bob accepts
+        let (mut alice_codec, mut bob_codec) = handshakes();
+        let mut buf = BytesMut::new();
+        bob_codec.encode(data(), &mut buf).unwrap();
+        assert!(alice_codec.decode(&mut buf).unwrap().is_some());
+        alice_codec.encode(data(), &mut buf).unwrap();
+        let frame = bob_codec.decode(&mut buf).unwrap().unwrap();
+        assert!(frame.iter().any(|block| match block {
+            Block::Message(_) => true,
+            _ => false,
+        }));
+        assert!(frame.iter().any(|block| match block {
+            Block::Padding(size) => *size > 0,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn ntcp2_handshake_cipher_suite() {
+        complete_handshake(CipherSuite::AesGcmSha256);
+
+        // Bob rejects a SessionRequest using a different suite
+        let (mut alice, mut bob) =
+            handshake_pair(CipherSuite::ChaChaPolySha256, CipherSuite::AesGcmSha256);
+        test_poll!(alice);
+        match bob.poll() {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("Bob should have rejected Alice's cipher suite"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_trace() {
+        let config = HandshakeConfig {
+            timeout: None,
+            trace: true,
+            ..Default::default()
+        };
+        let (mut alice, mut bob) = handshake_pair_config(
+            RouterSecretKeys::new(),
+            config.clone(),
+            config,
+            |bob_net| bob_net,
+        );
+        test_poll!(alice);
+        test_poll!(bob);
+        match (alice.poll(), bob.poll()) {
+            (Ok(Async::Ready(_)), Ok(Async::Ready(_))) => (),
+            _ => panic!("Handshake should have completed"),
+        }
+
+        let states = |trace: &Trace| -> Vec<_> {
+            trace.steps.as_ref().unwrap().iter().map(|s| s.state).collect()
+        };
+        assert_eq!(
+            states(&alice.trace),
+            vec![
+                "Connecting",
+                "SessionRequest",
+                "SessionCreated",
+                "SessionCreatedPadding",
+                "SessionConfirmed",
+            ]
+        );
+        assert_eq!(
+            states(&bob.trace),
+            vec![
+                "SessionRequest",
+                "SessionRequestPadding",
+                "SessionCreated",
+                "SessionConfirmed",
+            ]
+        );
+
+        // Each message was read in full by the peer
+        let alice_steps = alice.trace.steps.unwrap();
+        let bob_steps = bob.trace.steps.unwrap();
+        assert_eq!(alice_steps[1].written, bob_steps[0].read + bob_steps[1].read);
+        assert_eq!(bob_steps[2].written, alice_steps[2].read + alice_steps[3].read);
+        assert_eq!(alice_steps[4].written, bob_steps[3].read);
+        assert!(alice_steps[2].result.as_ref().unwrap().starts_with("padlen"));
+        assert!(bob_steps[0].result.as_ref().unwrap().starts_with("padlen"));
+        assert!(bob_steps[3].result.is_some());
+
+        // Nothing is recorded unless tracing is enabled
+        let (alice, bob) =
+            handshake_pair(CipherSuite::ChaChaPolySha256, CipherSuite::ChaChaPolySha256);
+        assert!(alice.trace.steps.is_none());
+        assert!(bob.trace.steps.is_none());
+    }
+
+    #[test]
+    fn ntcp2_handshake_peer_filter() {
+        let handshake = |alice_keys: RouterSecretKeys, peer_filter: PeerFilter| {
+            let (mut alice, mut bob) = handshake_pair_config(
+                alice_keys,
+                HandshakeConfig {
+                    timeout: None,
+                    ..Default::default()
+                },
+                HandshakeConfig {
+                    timeout: None,
+                    peer_filter: Arc::new(peer_filter),
+                    ..Default::default()
+                },
+                |bob_net| bob_net,
+            );
+            test_poll!(alice);
+            test_poll!(bob);
+            let mut alice_conn = match alice.poll() {
+                Ok(Async::Ready((_, conn))) => conn,
+                _ => panic!("Alice should have sent SessionConfirmed"),
+            };
+            let res = bob.poll().map(|_| ());
+
+            // Alice is told why the handshake was rejected
+            if res.is_err() {
+                match alice_conn.poll() {
+                    Ok(Async::Ready(Some(frame))) => {
+                        let termination = Block::Termination(0, TerminationReason::Banned, vec![]);
+                        assert!(frame.contains(&termination));
+                    }
+                    _ => panic!("Bob should have sent a Termination block"),
+                }
+            }
+            res
+        };
+        let rejected = |res: io::Result<()>| match res {
+            Err(e) => e.kind() == io::ErrorKind::PermissionDenied,
+            Ok(_) => false,
+        };
+
+        // Blocklisted peers are rejected once Bob knows who they are
+        let alice_keys = RouterSecretKeys::new();
+        let blocklist = once(alice_keys.rid.hash()).collect();
+        assert!(rejected(handshake(alice_keys, PeerFilter::new(None, blocklist))));
+
+        // With an allowlist, only the listed peers are accepted
+        let alice_keys = RouterSecretKeys::new();
+        let allowlist: HashSet<_> = once(alice_keys.rid.hash()).collect();
+        let filter = PeerFilter::new(Some(allowlist), HashSet::new());
+        assert!(handshake(alice_keys, filter.clone()).is_ok());
+        assert!(rejected(handshake(RouterSecretKeys::new(), filter)));
+    }
+
+    #[test]
+    fn ntcp2_handshake_reconnect_limit() {
+        let handshake = |alice_keys: RouterSecretKeys, limiter: Arc<ReconnectLimiter>| {
+            let (mut alice, mut bob) = handshake_pair_config(
+                alice_keys,
+                HandshakeConfig {
+                    timeout: None,
+                    ..Default::default()
+                },
+                HandshakeConfig {
+                    timeout: None,
+                    reconnect_limiter: limiter,
+                    ..Default::default()
+                },
+                |bob_net| bob_net,
+            );
+            test_poll!(alice);
+            test_poll!(bob);
+            let alice_conn = match alice.poll() {
+                Ok(Async::Ready((_, conn))) => conn,
+                _ => panic!("Alice should have sent SessionConfirmed"),
+            };
+            (alice_conn, bob.poll().map(|_| ()))
+        };
+
+        let limiter = Arc::new(ReconnectLimiter::new(3, Duration::from_secs(60)));
+        let alice_keys = RouterSecretKeys::new();
+        for _ in 0..3 {
+            assert!(handshake(alice_keys.clone(), limiter.clone()).1.is_ok());
+        }
+
+        // Further rapid reconnects are refused
+        let (mut alice_conn, res) = handshake(alice_keys.clone(), limiter.clone());
+        match res {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            Ok(_) => panic!("Bob should have throttled Alice"),
+        }
+
+        // Alice is told why
+        match alice_conn.poll() {
+            Ok(Async::Ready(Some(frame))) => {
+                let termination = Block::Termination(0, TerminationReason::Banned, vec![]);
+                assert!(frame.contains(&termination));
+            }
+            _ => panic!("Bob should have sent a Termination block"),
+        }
+
+        // Other peers are unaffected
+        assert!(handshake(RouterSecretKeys::new(), limiter).1.is_ok());
+    }
+
+    #[test]
+    fn ntcp2_handshake_replay() {
+        let bob_manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let bob_keys = RouterSecretKeys::new();
+        let mut bob_ri = RouterInfo::new(bob_keys.rid.clone());
+        bob_ri.set_addresses(vec![bob_manager.address()]);
+        bob_ri.sign(&bob_keys.signing_private_key);
+
+        let alice_manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let alice_keys = RouterSecretKeys::new();
+        let mut alice_ri = RouterInfo::new(alice_keys.rid);
+        alice_ri.sign(&alice_keys.signing_private_key);
+
+        // Every handshake with this config shares a replay filter
+        let config = HandshakeConfig {
+            timeout: None,
+            ..Default::default()
+        };
+
+        // Capture a SessionRequest from Alice
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let mut alice = OBHandshake::new(
+            |_| Box::new(done(Ok(alice_net))),
+            &alice_manager.keys.read().unwrap().private,
+            &alice_ri,
+            bob_ri,
+            config.clone(),
+        )
+        .unwrap();
+        test_poll!(alice);
+        let mut session_request = vec![0; 1024];
+        let n = BobNet::new(cable).read(&mut session_request).unwrap();
+        session_request.truncate(n);
+
+        // Sends the SessionRequest to a new handshake with Bob
+        let send_to_bob = || {
+            let cable = NetworkCable::new();
+            AliceNet::new(cable.clone())
+                .write_all(&session_request)
+                .unwrap();
+            let keys = bob_manager.keys.read().unwrap();
+            let mut bob = IBHandshake::new(
+                BobNet::new(cable),
+                &keys.private,
+                &bob_keys.rid.hash().0,
+                &keys.aesobfse_iv,
+                config.clone(),
+            );
+            bob.poll()
+        };
+
+        // Bob responds to the SessionRequest once
+        match send_to_bob() {
+            Ok(Async::NotReady) => (),
+            _ => panic!("Bob should have accepted the SessionRequest"),
+        }
+
+        // and drops it when it is replayed
+        match send_to_bob() {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("Bob should have dropped the replayed SessionRequest"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_session_created_skew() {
+        let (mut alice, mut bob) = handshake_pair_config(
+            RouterSecretKeys::new(),
+            HandshakeConfig {
+                timeout: None,
+                max_past_skew: Duration::from_secs(1),
+                ..Default::default()
+            },
+            HandshakeConfig {
+                timeout: None,
+                ..Default::default()
+            },
+            |bob_net| bob_net,
+        );
+        test_poll!(alice);
+        test_poll!(bob);
+
+        // By the time Alice reads SessionCreated, Bob's timestamp is stale
+        thread::sleep(Duration::from_secs(2));
+        match alice.poll() {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("Alice should have rejected the stale SessionCreated"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_verify_router_info() {
+        let handshake = |alice_ri: RouterInfo, alice_static_private_key: &[u8], reason| {
+            let (mut alice, mut bob) = handshake_pair_ri(
+                alice_ri,
+                alice_static_private_key,
+                HandshakeConfig {
+                    timeout: None,
+                    ..Default::default()
+                },
+                HandshakeConfig {
+                    timeout: None,
+                    ..Default::default()
+                },
+                |bob_net| bob_net,
+            );
+            test_poll!(alice);
+            test_poll!(bob);
+            let mut alice_conn = match alice.poll() {
+                Ok(Async::Ready((_, conn))) => conn,
+                _ => panic!("Alice should have sent SessionConfirmed"),
+            };
+            match bob.poll() {
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+                _ => panic!("Bob should have rejected Alice's RouterInfo"),
+            }
+            match alice_conn.poll() {
+                Ok(Async::Ready(Some(frame))) => {
+                    assert!(frame.contains(&Block::Termination(0, reason, vec![])));
+                }
+                _ => panic!("Bob should have sent a Termination block"),
+            }
+        };
+
+        let alice_keys = RouterSecretKeys::new();
+        let alice_mgr = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let other_mgr = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+
+        // RouterInfo advertises a different static key
+        let mut ri = RouterInfo::new(alice_keys.rid.clone());
+        ri.set_addresses(vec![other_mgr.address()]);
+        ri.sign(&alice_keys.signing_private_key);
+        let mismatch = TerminationReason::StaticKeyMismatch;
+        handshake(ri, &alice_mgr.keys.read().unwrap().private, mismatch);
+
+        // RouterInfo has no static key
+        let mut ri = RouterInfo::new(alice_keys.rid.clone());
+        ri.sign(&alice_keys.signing_private_key);
+        handshake(ri, &alice_mgr.keys.read().unwrap().private, mismatch);
+
+        // RouterInfo signature is invalid
+        let mut ri = RouterInfo::new(alice_keys.rid.clone());
+        ri.set_addresses(vec![alice_mgr.address()]);
+        ri.sign(&alice_keys.signing_private_key);
+        ri.published = I2PDate(1);
+        let signature = TerminationReason::RouterInfoSignature;
+        handshake(ri, &alice_mgr.keys.read().unwrap().private, signature);
+    }
+
+    #[test]
+    fn ntcp2_transport_mode_before_handshake() {
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let keys = manager.keys.read().unwrap();
+        let builder: Builder<'_> =
+            Builder::new(CipherSuite::default().protocol_name().parse().unwrap());
+        let noise = builder
+            .local_private_key(&keys.private)
+            .aesobfse(&[0; 32], &keys.aesobfse_iv)
+            .enable_ask()
+            .build_responder()
+            .unwrap();
+
+        // The handshake hasn't happened, so this must fail without panicking
+        match into_transport_mode(noise) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Other),
+            Ok(_) => panic!("Noise session should not be in transport mode"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_garbage_session_request() {
+        let cable = NetworkCable::new();
+        let mut alice_net = AliceNet::new(cable.clone());
+        let bob_net = BobNet::new(cable);
+
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let keys = manager.keys.read().unwrap();
+        let mut bob = IBHandshake::new(
+            bob_net,
+            &keys.private,
+            &[0; 32],
+            &keys.aesobfse_iv,
+            HandshakeConfig {
+                timeout: None,
+                ..Default::default()
+            },
+        );
+
+        alice_net.write_all(&[0xaa; 128]).unwrap();
+        match bob.poll() {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("Bob should have rejected the SessionRequest"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_session_confirmed_too_long() {
+        let (mut alice, mut bob) = handshake_pair(CipherSuite::default(), CipherSuite::default());
+
+        // Alice -> SessionRequest advertising an oversized SessionConfirmed
+        alice.sc_len = SESSION_CONFIRMED_MAX_PART2_LEN + 1;
+        test_poll!(alice);
+
+        // Bob rejects it before reading SessionConfirmed
+        match bob.poll() {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("Bob should have rejected the SessionRequest"),
+        }
+        match bob.state {
+            IBHandshakeState::SessionRequest(_) => (),
+            _ => panic!("Bob should not have moved past SessionRequest"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_garbage_session_confirmed() {
+        // Corrupt everything after the longest SessionRequest that Alice can
+        // send, so that only SessionConfirmed is corrupted.
+        let padding = PaddingConfig {
+            handshake_max: 15,
+            ..Default::default()
+        };
+        let offset = SESSION_REQUEST_CT_LEN + usize::from(padding.handshake_max) + 1;
+        let (mut alice, mut bob) = handshake_pair_config(
+            RouterSecretKeys::new(),
+            HandshakeConfig {
+                timeout: None,
+                padding,
+                ..Default::default()
+            },
+            HandshakeConfig {
+                timeout: None,
+                ..Default::default()
+            },
+            |bob_net| Corrupt::new(bob_net, offset),
+        );
+
+        // Alice -> SessionRequest
+        test_poll!(alice);
+
+        // Bob <- SessionRequest
+        // Bob -> SessionCreated
+        test_poll!(bob);
+
+        // Alice <- SessionCreated
+        // Alice -> SessionConfirmed
+        match alice.poll() {
+            Ok(Async::Ready(_)) => (),
+            _ => panic!("Alice should have sent SessionConfirmed"),
+        }
+
+        // Bob <- corrupted SessionConfirmed
+        match bob.poll() {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("Bob should have rejected the SessionConfirmed"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_timeout() {
+        // Alice connects but never sends anything
+        let cable = NetworkCable::new();
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let keys = manager.keys.read().unwrap();
+        let bob = IBHandshake::new(
+            BobNet::new(cable),
+            &keys.private,
+            &[0; 32],
+            &keys.aesobfse_iv,
+            HandshakeConfig {
+                timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        let mut rt = Runtime::new().unwrap();
+        match rt.block_on(bob) {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(
+                    e.get_ref().and_then(|e| e.downcast_ref::<HandshakeTimeout>()),
+                    Some(&HandshakeTimeout::Handshake("SessionRequest"))
+                );
+            }
+            _ => panic!("Bob should have timed out"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_step_timeout() {
+        // Alice connects but never sends anything, and Bob only limits how
+        // long each step can take
+        let cable = NetworkCable::new();
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let keys = manager.keys.read().unwrap();
+        let bob = IBHandshake::new(
+            BobNet::new(cable),
+            &keys.private,
+            &[0; 32],
+            &keys.aesobfse_iv,
+            HandshakeConfig {
+                timeout: None,
+                step_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        let mut rt = Runtime::new().unwrap();
+        match rt.block_on(bob) {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(
+                    e.get_ref().and_then(|e| e.downcast_ref::<HandshakeTimeout>()),
+                    Some(&HandshakeTimeout::Step("SessionRequest"))
+                );
+            }
+            _ => panic!("Bob should have timed out"),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_read_limit() {
+        // Bob aborts once Alice has sent more than the limit
+        let (mut alice, mut bob) = handshake_pair_with(
+            CipherSuite::default(),
+            CipherSuite::default(),
+            |bob_net| ByteCounter::with_read_limit(bob_net, 128),
+        );
+        test_poll!(alice);
+        match bob.poll() {
+            Ok(Async::NotReady) => {
+                // SessionRequest and its padding fit; SessionConfirmed won't
+                match alice.poll() {
+                    Ok(Async::Ready(_)) => (),
+                    _ => panic!("Alice should have sent SessionConfirmed"),
+                }
+                match bob.poll() {
+                    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+                    _ => panic!("Bob should have hit the read limit"),
+                }
+            }
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Ok(Async::Ready(_)) => panic!("Bob should not have completed the handshake"),
+        }
+    }
+
+    #[cfg(feature = "deterministic-padding")]
+    #[test]
+    fn ntcp2_deterministic_padding() {
+        use super::SESSION_REQUEST_CT_LEN;
+        use crate::transport::ntcp2::padding::DETERMINISTIC_PADDING_LEN;
+
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let peer_keys = RouterSecretKeys::new();
+        let mut peer_ri = RouterInfo::new(peer_keys.rid);
+        peer_ri.set_addresses(vec![manager.address()]);
+        peer_ri.sign(&peer_keys.signing_private_key);
+
+        let own_keys = RouterSecretKeys::new();
+        let mut own_ri = RouterInfo::new(own_keys.rid);
+        own_ri.sign(&own_keys.signing_private_key);
+
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let mut alice = OBHandshake::new(
+            |_| Box::new(done(Ok(alice_net))),
+            &manager.keys.read().unwrap().private,
+            &own_ri,
+            peer_ri,
+            HandshakeConfig {
+                timeout: None,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Alice -> SessionRequest
+        test_poll!(alice);
+
+        let mut buf = [0xff; 128];
+        let n = BobNet::new(cable).read(&mut buf).unwrap();
+        assert_eq!(n, SESSION_REQUEST_CT_LEN + DETERMINISTIC_PADDING_LEN as usize);
+        assert!(buf[SESSION_REQUEST_CT_LEN..n].iter().all(|&b| b == 0));
+    }
+
     #[cfg(all(test, feature = "nightly"))]
     mod transfer {
         use futures::*;
@@ -673,7 +2105,7 @@ mod tests {
         use crate::i2np::{Message, MessagePayload};
         use crate::transport::ntcp2::{
             handshake::{IBHandshake, OBHandshake},
-            Block, Codec, Manager,
+            Block, Codec, HandshakeConfig, Manager,
         };
 
         const MB: usize = 3 * 1024 * 1024;
@@ -755,12 +2187,13 @@ mod tests {
                 let mut ri = RouterInfo::new(sk.rid.clone());
                 ri.set_addresses(vec![mgr.address()]);
                 ri.sign(&sk.signing_private_key);
+                let keys = mgr.keys.read().unwrap();
                 (
                     ri,
-                    mgr.static_public_key,
-                    mgr.static_private_key,
+                    keys.public.clone(),
+                    keys.private.clone(),
                     sk.rid.hash().0,
-                    mgr.aesobfse_iv,
+                    keys.aesobfse_iv,
                 )
             };
 
@@ -781,6 +2214,10 @@ mod tests {
                             &bob_static_private_key,
                             &bob_aesobfse_key,
                             &bob_aesobfse_iv,
+                            HandshakeConfig {
+                                timeout: None,
+                                ..Default::default()
+                            },
                         )
                     })
                     .and_then(|(ri, conn)| {
@@ -795,6 +2232,10 @@ mod tests {
                     &bob_static_public_key,
                     &alice_ri,
                     bob_ri.clone(),
+                    HandshakeConfig {
+                        timeout: None,
+                        ..Default::default()
+                    },
                 )
                 .unwrap()
                 .and_then(move |(ri, conn)| Transfer {
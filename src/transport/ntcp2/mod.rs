@@ -30,11 +30,13 @@
 use bytes::BytesMut;
 use cookie_factory::GenError;
 use futures::{
+    future::{self, lazy, loop_fn, Either, Loop},
     stream::{SplitSink, SplitStream},
     sync::mpsc,
     try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
 };
 use i2p_snow::{self, Builder};
+use net2::TcpBuilder;
 use nom::Err;
 use rand::{rngs::OsRng, Rng};
 use siphasher::sip::SipHasher;
@@ -43,48 +45,232 @@ use std::fmt;
 use std::fs::File;
 use std::hash::Hasher;
 use std::iter::repeat;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio::{
     codec::{Decoder, Encoder, Framed},
     io::{self, AsyncRead, AsyncWrite, Read, Write},
     net::tcp::{TcpListener, TcpStream},
+    reactor::Handle,
     spawn,
-    timer::Timeout,
+    timer::Delay,
 };
 
 use super::{
+    bandwidth::BandwidthLimiter,
+    counter::ByteCounter,
+    dial::DialLimiter,
+    limiter::prune_on_timer,
     ntcp::NTCP_STYLE,
-    session::{self, SessionContext, SessionManager, SessionRefs, SessionRx},
-    Bid, Transport,
+    session::{
+        self, DialObserver, SessionContext, SessionManager, SessionRefs, SessionRx, SessionState,
+    },
+    socks,
+    stats::TrafficStats,
+    Bid, Direction, HandshakeLimiter, PeerFilter, ReconnectLimiter, Transport,
+    UnknownMessagePolicy,
+};
+use crate::data::{
+    Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo, NTCP2_OPT_I, NTCP2_OPT_S,
+    NTCP2_OPT_V,
 };
-use crate::constants::I2P_BASE64;
-use crate::data::{Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo};
 use crate::i2np::{DatabaseStore, Message, MessagePayload};
 use crate::router::{
     types::{Distributor, DistributorResult},
     Context,
 };
+use crate::util::DEFAULT_MAX_FUTURE_SKEW;
 
 #[allow(clippy::needless_pass_by_value)]
 mod frame;
 
 mod handshake;
+mod padding;
+mod replay;
+
+pub(crate) use self::handshake::SESSION_REQUEST_MAX_PADDING;
+pub use self::handshake::HandshakeTimeout;
+pub use self::padding::PaddingConfig;
+pub use self::replay::ReplayFilter;
 
 lazy_static! {
-    static ref NTCP2_STYLE: I2PString = I2PString::new("NTCP2");
-    static ref NTCP2_VERSION: I2PString = I2PString::new("2");
-    static ref NTCP2_OPT_V: I2PString = I2PString::new("v");
-    static ref NTCP2_OPT_S: I2PString = I2PString::new("s");
-    static ref NTCP2_OPT_I: I2PString = I2PString::new("i");
-    static ref NTCP2_NOISE_PROTOCOL_NAME: &'static str =
-        "Noise_XKaesobfse+hs2+hs3_25519_ChaChaPoly_SHA256";
+    pub(crate) static ref NTCP2_STYLE: I2PString = I2PString::new("NTCP2");
 }
 
 // Max NTCP2 message size is ~64kB
 const NTCP2_MTU: usize = 65535;
 
+// Maximum number of bytes a peer can send before the handshake completes
+const HANDSHAKE_READ_LIMIT: usize = 64 * 1024;
+
+// Default number of seconds a handshake may take before it is aborted
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
+// Default bound on how far in the past a SessionRequest's timestamp may be
+const DEFAULT_MAX_PAST_SKEW: Duration = Duration::from_secs(2 * 60);
+
+// Default number of seconds after a key rotation for which we still accept
+// handshakes using our previous static key and IV
+const DEFAULT_KEY_ROTATION_WINDOW: u64 = 30 * 60;
+
+// Default number of seconds without traffic after which a session is closed
+const DEFAULT_IDLE_TIMEOUT: u64 = 5 * 60;
+
+// Upper bound (exclusive) on the padding length of a keepalive frame
+const KEEPALIVE_MAX_PADDING: u16 = 16;
+
+// Number of received messages waiting to be distributed at which we ask the
+// peer to slow down
+const CONGESTION_BACKLOG: usize = 8;
+
+// Delay in milliseconds that we request between messages while congested
+const CONGESTION_DELAY: u16 = 50;
+
+// The NTCP2 protocol versions we support
+const NTCP2_VERSIONS: &[&str] = &["2"];
+
+/// Returns the highest protocol version that is both in `supported` and
+/// advertised by the address.
+fn negotiate_version(ra: &RouterAddress, supported: &[&str]) -> Option<u8> {
+    ra.option(&NTCP2_OPT_V)?
+        .to_csv()
+        .iter()
+        .map(|v| v.0.trim())
+        .filter(|v| supported.contains(v))
+        .filter_map(|v| v.parse().ok())
+        .max()
+}
+
+/// Returns whether the address has the options we need to connect to it over
+/// NTCP2, and supports one of our protocol versions.
+fn is_ntcp2_address(ra: &RouterAddress) -> bool {
+    negotiate_version(ra, NTCP2_VERSIONS).is_some()
+        && ra.option(&NTCP2_OPT_S).is_some()
+        && ra.option(&NTCP2_OPT_I).is_some()
+}
+
+/// Returns whether we can connect to the NTCP2 address. IPv6 addresses are
+/// only usable if we listen on IPv6 ourselves.
+fn is_reachable_ntcp2_address(ra: &RouterAddress, ipv6: bool) -> bool {
+    is_ntcp2_address(ra) && ra.addr().map(|addr| ipv6 || addr.is_ipv4()).unwrap_or(false)
+}
+
+/// The Noise cipher suites that NTCP2 handshakes can be restricted to.
+///
+/// The NTCP2 specification only permits `ChaChaPolySha256`. Noise has no cipher
+/// negotiation, so the other suites only interoperate with peers that have been
+/// configured to use the same suite.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CipherSuite {
+    ChaChaPolySha256,
+    AesGcmSha256,
+}
+
+impl CipherSuite {
+    fn protocol_name(self) -> &'static str {
+        match self {
+            CipherSuite::ChaChaPolySha256 => "Noise_XKaesobfse+hs2+hs3_25519_ChaChaPoly_SHA256",
+            CipherSuite::AesGcmSha256 => "Noise_XKaesobfse+hs2+hs3_25519_AESGCM_SHA256",
+        }
+    }
+}
+
+impl Default for CipherSuite {
+    fn default() -> Self {
+        CipherSuite::ChaChaPolySha256
+    }
+}
+
+impl FromStr for CipherSuite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ChaChaPoly_SHA256" => Ok(CipherSuite::ChaChaPolySha256),
+            "AESGCM_SHA256" => Ok(CipherSuite::AesGcmSha256),
+            _ => Err(format!("Unknown NTCP2 cipher suite: {}", s)),
+        }
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherSuite::ChaChaPolySha256 => write!(f, "ChaChaPoly_SHA256"),
+            CipherSuite::AesGcmSha256 => write!(f, "AESGCM_SHA256"),
+        }
+    }
+}
+
+/// Parameters controlling how handshakes are performed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeConfig {
+    /// The cipher suite that handshakes are restricted to.
+    pub cipher_suite: CipherSuite,
+    /// The furthest in the future that a peer's handshake timestamp may be.
+    pub max_future_skew: Duration,
+    /// The furthest in the past that the timestamp in a SessionRequest or
+    /// SessionCreated may be. Older messages are rejected as stale.
+    pub max_past_skew: Duration,
+    /// The SessionRequests that have already been received. This should
+    /// remember them for at least `max_future_skew + max_past_skew`.
+    pub replay_filter: Arc<ReplayFilter>,
+    /// How long a handshake may take before it is aborted, including the time
+    /// taken to connect for outbound handshakes. If `None`, handshakes can
+    /// take arbitrarily long.
+    pub timeout: Option<Duration>,
+    /// How long a handshake may stay in any one state, such as waiting for the
+    /// peer's next message. If `None`, only `timeout` applies.
+    pub step_timeout: Option<Duration>,
+    /// The peers that inbound handshakes are accepted from.
+    pub peer_filter: Arc<PeerFilter>,
+    /// Limits how often a peer may complete an inbound handshake.
+    pub reconnect_limiter: Arc<ReconnectLimiter>,
+    /// Whether to log each step of a handshake, for debugging handshake
+    /// failures.
+    pub trace: bool,
+    /// Whether outbound handshakes try a peer's IPv6 addresses before its IPv4
+    /// addresses. Addresses in the other family are tried if we can't connect
+    /// to any in the preferred family.
+    pub prefer_ipv6: bool,
+    /// Whether outbound handshakes may connect to a peer's IPv6 addresses.
+    /// This is set when we listen on IPv6, which we take to mean that we have
+    /// IPv6 connectivity.
+    pub ipv6: bool,
+    /// A SOCKS5 proxy that outbound connections are made through, such as the
+    /// one provided by Tor. Inbound connections are unaffected.
+    pub socks_proxy: Option<SocketAddr>,
+    /// How much padding is sent during and after the handshake, and accepted
+    /// from the peer after it.
+    pub padding: PaddingConfig,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig {
+            cipher_suite: CipherSuite::default(),
+            max_future_skew: DEFAULT_MAX_FUTURE_SKEW,
+            max_past_skew: DEFAULT_MAX_PAST_SKEW,
+            replay_filter: Arc::new(ReplayFilter::new(
+                DEFAULT_MAX_FUTURE_SKEW + DEFAULT_MAX_PAST_SKEW,
+            )),
+            timeout: Some(Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT)),
+            step_timeout: None,
+            peer_filter: Arc::new(PeerFilter::default()),
+            reconnect_limiter: Arc::new(ReconnectLimiter::default()),
+            trace: false,
+            prefer_ipv6: false,
+            ipv6: false,
+            socks_proxy: None,
+            padding: PaddingConfig::default(),
+        }
+    }
+}
+
 macro_rules! io_err {
     ($err_kind:ident, $err_msg:expr) => {
         Err(io::Error::new(io::ErrorKind::$err_kind, $err_msg))
@@ -100,15 +286,152 @@ pub struct RouterInfoFlags {
     flood: bool,
 }
 
+/// Session parameters sent in an Options block.
+///
+/// Padding ratios are 4.4 fixed-point, dummy traffic is in KB/s, and delays are
+/// in milliseconds. The `t` options are what the sender is willing to do, and
+/// the `r` options are what it requests of the receiver.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SessionOptions {
+    tmin: u8,
+    tmax: u8,
+    rmin: u8,
+    rmax: u8,
+    tdmy: u16,
+    rdmy: u16,
+    tdelay: u16,
+    rdelay: u16,
+}
+
+/// The reason given by a router for terminating a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminationReason {
+    NormalClose,
+    TerminationReceived,
+    IdleTimeout,
+    RouterShutdown,
+    AeadFailure,
+    IncompatibleOptions,
+    IncompatibleSigType,
+    ClockSkew,
+    PaddingViolation,
+    AeadFraming,
+    /// A block could not be parsed, or a message was too big.
+    PayloadFormat,
+    SessionRequestError,
+    SessionCreatedError,
+    SessionConfirmedError,
+    ReadTimeout,
+    RouterInfoSignature,
+    StaticKeyMismatch,
+    Banned,
+    Unknown(u8),
+}
+
+impl TerminationReason {
+    pub fn code(self) -> u8 {
+        match self {
+            TerminationReason::NormalClose => 0,
+            TerminationReason::TerminationReceived => 1,
+            TerminationReason::IdleTimeout => 2,
+            TerminationReason::RouterShutdown => 3,
+            TerminationReason::AeadFailure => 4,
+            TerminationReason::IncompatibleOptions => 5,
+            TerminationReason::IncompatibleSigType => 6,
+            TerminationReason::ClockSkew => 7,
+            TerminationReason::PaddingViolation => 8,
+            TerminationReason::AeadFraming => 9,
+            TerminationReason::PayloadFormat => 10,
+            TerminationReason::SessionRequestError => 11,
+            TerminationReason::SessionCreatedError => 12,
+            TerminationReason::SessionConfirmedError => 13,
+            TerminationReason::ReadTimeout => 14,
+            TerminationReason::RouterInfoSignature => 15,
+            TerminationReason::StaticKeyMismatch => 16,
+            TerminationReason::Banned => 17,
+            TerminationReason::Unknown(code) => code,
+        }
+    }
+
+    /// Returns true if the peer terminated the session because it won't talk
+    /// to us, so reconnecting straight away would fail in the same way.
+    fn is_rejection(self) -> bool {
+        match self {
+            TerminationReason::IncompatibleOptions
+            | TerminationReason::IncompatibleSigType
+            | TerminationReason::ClockSkew
+            | TerminationReason::Banned => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<u8> for TerminationReason {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => TerminationReason::NormalClose,
+            1 => TerminationReason::TerminationReceived,
+            2 => TerminationReason::IdleTimeout,
+            3 => TerminationReason::RouterShutdown,
+            4 => TerminationReason::AeadFailure,
+            5 => TerminationReason::IncompatibleOptions,
+            6 => TerminationReason::IncompatibleSigType,
+            7 => TerminationReason::ClockSkew,
+            8 => TerminationReason::PaddingViolation,
+            9 => TerminationReason::AeadFraming,
+            10 => TerminationReason::PayloadFormat,
+            11 => TerminationReason::SessionRequestError,
+            12 => TerminationReason::SessionCreatedError,
+            13 => TerminationReason::SessionConfirmedError,
+            14 => TerminationReason::ReadTimeout,
+            15 => TerminationReason::RouterInfoSignature,
+            16 => TerminationReason::StaticKeyMismatch,
+            17 => TerminationReason::Banned,
+            code => TerminationReason::Unknown(code),
+        }
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerminationReason::NormalClose => "normal close or unspecified",
+            TerminationReason::TerminationReceived => "termination received",
+            TerminationReason::IdleTimeout => "idle timeout",
+            TerminationReason::RouterShutdown => "router shutdown",
+            TerminationReason::AeadFailure => "data phase AEAD failure",
+            TerminationReason::IncompatibleOptions => "incompatible options",
+            TerminationReason::IncompatibleSigType => "incompatible signature type",
+            TerminationReason::ClockSkew => "clock skew",
+            TerminationReason::PaddingViolation => "padding violation",
+            TerminationReason::AeadFraming => "AEAD framing error",
+            TerminationReason::PayloadFormat => "payload format error",
+            TerminationReason::SessionRequestError => "message 1 error",
+            TerminationReason::SessionCreatedError => "message 2 error",
+            TerminationReason::SessionConfirmedError => "message 3 error",
+            TerminationReason::ReadTimeout => "intra-frame read timeout",
+            TerminationReason::RouterInfoSignature => "RI signature verification fail",
+            TerminationReason::StaticKeyMismatch => {
+                "s parameter missing, invalid, or mismatched in RouterInfo"
+            }
+            TerminationReason::Banned => "banned",
+            TerminationReason::Unknown(_) => "unknown",
+        }
+        .fmt(f)
+    }
+}
+
 #[derive(PartialEq)]
 pub enum Block {
     DateTime(u32),
-    Options(Vec<u8>),
+    Options(SessionOptions),
     RouterInfo(RouterInfo, RouterInfoFlags),
     Message(Message),
-    Termination(u64, u8, Vec<u8>),
+    Termination(u64, TerminationReason, Vec<u8>),
     Padding(u16),
     Unknown(u8, Vec<u8>),
+    Malformed(u8, Vec<u8>),
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -116,7 +439,7 @@ impl fmt::Debug for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Block::DateTime(ts) => write!(f, "DateTime ({})", ts),
-            Block::Options(_) => write!(f, "Options"),
+            Block::Options(ref options) => write!(f, "Options ({:?})", options),
             Block::RouterInfo(ref ri, ref flags) => write!(
                 f,
                 "RouterInfo ({}, flood: {})",
@@ -124,36 +447,16 @@ impl fmt::Debug for Block {
                 flags.flood
             ),
             Block::Message(ref msg) => write!(f, "I2NP message:\n{}", msg),
-            Block::Termination(_, rsn, _) => write!(
-                f,
-                "Termination (reason: {} - {})",
-                rsn,
-                match rsn {
-                    0 => "unspecified",
-                    1 => "termination received",
-                    2 => "idle timeout",
-                    3 => "router shutdown",
-                    4 => "data phase AEAD failure",
-                    5 => "incompatible options",
-                    6 => "incompatible signature type",
-                    7 => "clock skew",
-                    8 => "padding violation",
-                    9 => "AEAD framing error",
-                    10 => "payload format error",
-                    11 => "message 1 error",
-                    12 => "message 2 error",
-                    13 => "message 3 error",
-                    14 => "intra-frame read timeout",
-                    15 => "RI signature verification fail",
-                    16 => "s parameter missing, invalid, or mismatched in RouterInfo",
-                    17 => "banned",
-                    _ => "unknown",
-                }
-            ),
+            Block::Termination(_, rsn, _) => {
+                write!(f, "Termination (reason: {} - {})", rsn.code(), rsn)
+            }
             Block::Padding(size) => write!(f, "Padding ({} bytes)", size),
             Block::Unknown(blk, ref data) => {
                 write!(f, "Unknown (type: {}, {} bytes)", blk, data.len())
             }
+            Block::Malformed(blk, ref data) => {
+                write!(f, "Malformed (type: {}, {} bytes)", blk, data.len())
+            }
         }
     }
 }
@@ -168,12 +471,20 @@ pub struct Codec {
     dec_len_masker: SipHasher,
     dec_len_iv: u64,
     next_len: Option<usize>,
+    frames_received: u64,
+    padding: padding::DataPadding,
 }
 
 impl Decoder for Codec {
     type Item = Frame;
     type Error = io::Error;
 
+    /// Decodes the next frame.
+    ///
+    /// Errors that mean the session state can no longer be trusted (AEAD
+    /// failures, invalid framing) are returned, terminating the connection.
+    /// Individual blocks that cannot be parsed are returned as
+    /// `Block::Malformed`, so that the rest of the frame can still be handled.
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
         if self.next_len.is_none() {
             if buf.len() < 2 {
@@ -198,25 +509,41 @@ impl Decoder for Codec {
                 // Read the frame
                 let frame_len = match self.noise.read_message(&buf[..len], &mut self.noise_buf) {
                     Ok(len) => len,
-                    Err(e) => return io_err!(Other, format!("Decryption error: {:?}", e)),
+                    Err(e) => return io_err!(InvalidData, format!("Decryption error: {:?}", e)),
                 };
 
                 // Parse the frame
                 let f = match frame::frame(&self.noise_buf[..frame_len]) {
                     Err(Err::Incomplete(n)) => {
                         return io_err!(
-                            Other,
+                            InvalidData,
                             format!("received incomplete message, needed: {:?}", n)
                         );
                     }
                     Err(Err::Error(e)) | Err(Err::Failure(e)) => {
-                        return io_err!(Other, format!("parse error: {:?}", e));
+                        return io_err!(InvalidData, format!("parse error: {:?}", e));
+                    }
+                    Ok((rest, _)) if !rest.is_empty() => {
+                        return io_err!(
+                            InvalidData,
+                            format!("framing error: {} trailing bytes", rest.len())
+                        );
                     }
                     Ok((_, frame)) => frame,
                 };
+                if let Err(padding) = self.padding.received(&f, frame_len) {
+                    return io_err!(
+                        InvalidData,
+                        format!(
+                            "padding violation: {} bytes of padding in {} byte frame",
+                            padding, frame_len
+                        )
+                    );
+                }
 
                 buf.split_to(len);
                 self.next_len = None;
+                self.frames_received += 1;
 
                 Ok(Some(f))
             }
@@ -229,9 +556,26 @@ impl Encoder for Codec {
     type Item = Frame;
     type Error = io::Error;
 
-    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+    fn encode(&mut self, mut frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        for block in frame.iter_mut() {
+            if let Block::Termination(received, _, _) = block {
+                *received = self.frames_received;
+            }
+        }
+        self.padding.prepare(&mut frame);
         match frame::gen_frame((&mut self.noise_buf, 0), &frame).map(|tup| tup.1) {
             Ok(sz) => {
+                // Pad the frame, leaving room for the MAC
+                let sz = match self.padding.padlen(&frame, sz, NTCP2_MTU - 16) {
+                    Some(padlen) => {
+                        let padding = vec![Block::Padding(padlen)];
+                        match frame::gen_frame((&mut self.noise_buf, sz), &padding) {
+                            Ok((_, sz)) => sz,
+                            Err(_) => return io_err!(InvalidData, "could not generate padding"),
+                        }
+                    }
+                    None => sz,
+                };
                 let msg_len = sz + 16;
 
                 let start = buf.len();
@@ -276,6 +620,100 @@ impl Encoder for Codec {
 // Session handling
 //
 
+/// Timers controlling how an otherwise-idle session is treated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdleConfig {
+    /// Close the session after this long without sending or receiving a frame.
+    /// If `None`, idle sessions are kept open until the peer closes them.
+    pub timeout: Option<Duration>,
+    /// Send a padding-only frame after this long without sending a frame. Must
+    /// be shorter than `timeout` in order to keep the session open.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig {
+            timeout: Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT)),
+            keepalive: None,
+        }
+    }
+}
+
+/// A timer that fires after a period of inactivity.
+struct IdleTimer {
+    period: Duration,
+    delay: Delay,
+}
+
+impl IdleTimer {
+    fn new(period: Duration) -> Self {
+        IdleTimer {
+            period,
+            delay: Delay::new(Instant::now() + period),
+        }
+    }
+
+    /// Restarts the timer if there has been activity, and then returns whether
+    /// the timer has fired. The current task is notified when it fires.
+    fn expired(&mut self, activity: bool) -> io::Result<bool> {
+        if activity {
+            self.delay.reset(Instant::now() + self.period);
+        }
+        self.delay
+            .poll()
+            .map(|a| a.is_ready())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+/// Limits the rate at which messages are sent, by waiting for a minimum delay
+/// after each message.
+struct Pacer {
+    delay: Duration,
+    next: Option<Delay>,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Pacer {
+            delay: Duration::from_millis(0),
+            next: None,
+        }
+    }
+
+    fn set_delay(&mut self, delay: Duration) {
+        if delay < self.delay {
+            // Don't hold back the next message for longer than necessary
+            self.next = None;
+        }
+        self.delay = delay;
+    }
+
+    /// Returns whether another message can be sent. The current task is
+    /// notified when it can be.
+    fn ready(&mut self) -> io::Result<bool> {
+        let ready = match &mut self.next {
+            Some(next) => next
+                .poll()
+                .map(|a| a.is_ready())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+            None => true,
+        };
+        if ready {
+            self.next = None;
+        }
+        Ok(ready)
+    }
+
+    /// Records that a message has been sent.
+    fn sent(&mut self) {
+        if self.delay > Duration::from_millis(0) {
+            self.next = Some(Delay::new(Instant::now() + self.delay));
+        }
+    }
+}
+
 struct Session<T, C, D>
 where
     T: AsyncRead + AsyncWrite,
@@ -289,6 +727,13 @@ where
     pending_ib: Option<DistributorResult>,
     outbound: SessionRx<Block>,
     cached_ob_block: Option<Block>,
+    idle_timer: Option<IdleTimer>,
+    keepalive_timer: Option<IdleTimer>,
+    pacer: Pacer,
+    congested: bool,
+    /// Whether we have told the peer that we are terminating the session.
+    terminating: bool,
+    traffic: TrafficStats,
 }
 
 impl<T, C, D> Session<T, C, D>
@@ -300,20 +745,74 @@ where
 {
     fn new(
         ri: &RouterIdentity,
+        direction: Direction,
         upstream: Framed<T, C>,
         session_refs: SessionRefs<Block, D>,
+        idle: IdleConfig,
     ) -> Self {
         let (downstream, upstream) = upstream.split();
         let (tx, rx) = mpsc::unbounded();
-        let ctx = SessionContext::new(ri.hash(), session_refs.state, tx);
+        let ctx = SessionContext::new(ri.hash(), direction, session_refs.state, tx);
         Session {
-            ib: InboundSession::new(ctx, upstream),
+            ib: InboundSession::new(ctx, upstream, session_refs.unknown_messages),
             ob: OutboundSession::new(downstream),
             distributor: session_refs.distributor,
             pending_ib: None,
             outbound: rx,
             cached_ob_block: None,
+            idle_timer: idle.timeout.map(IdleTimer::new),
+            keepalive_timer: idle.keepalive.map(IdleTimer::new),
+            pacer: Pacer::new(),
+            congested: false,
+            terminating: false,
+            traffic: session_refs.traffic,
+        }
+    }
+
+    /// Tells the peer that we are terminating the session. Nothing more should
+    /// be sent after this.
+    fn terminate(&mut self, reason: TerminationReason) -> io::Result<()> {
+        debug!("Terminating session with {}: {}", self.ib.ctx.hash, reason);
+        self.terminating = true;
+        let termination = Block::Termination(0, reason, vec![]);
+        if let AsyncSink::NotReady(block) = self.ob.start_send(termination)? {
+            self.cached_ob_block = Some(block);
+        }
+        Ok(())
+    }
+
+    /// Tells the peer when we start or stop being able to keep up with the
+    /// messages it sends us.
+    fn signal_congestion(&mut self) -> io::Result<()> {
+        if self.cached_ob_block.is_some() {
+            // Try again once we can write
+            return Ok(());
+        }
+
+        let backlog = self.ib.cached_msgs.len() + self.pending_ib.is_some() as usize;
+        let congested = if self.congested {
+            backlog > 0
+        } else {
+            backlog >= CONGESTION_BACKLOG
+        };
+        if congested == self.congested {
+            return Ok(());
+        }
+
+        debug!(
+            "Inbound session with {} is {}congested",
+            self.ib.ctx.hash,
+            if congested { "" } else { "no longer " }
+        );
+        self.congested = congested;
+        let options = Block::Options(SessionOptions {
+            rdelay: if congested { CONGESTION_DELAY } else { 0 },
+            ..Default::default()
+        });
+        if let AsyncSink::NotReady(block) = self.ob.start_send(options)? {
+            self.cached_ob_block = Some(block);
         }
+        self.ob.poll_complete().map(|_| ())
     }
 }
 
@@ -324,15 +823,17 @@ where
     C: Encoder<Item = Frame, Error = io::Error>,
     D: Distributor,
 {
-    type Item = ();
+    /// The reason the peer gave for terminating the session, if it did.
+    type Item = Option<TerminationReason>;
     type Error = io::Error;
 
-    fn poll(&mut self) -> Poll<(), io::Error> {
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
         // Write cached block, if any
         let mut write_ready = true;
+        let mut wrote = false;
         if let Some(block) = self.cached_ob_block.take() {
             match self.ob.start_send(block)? {
-                AsyncSink::Ready => (),
+                AsyncSink::Ready => wrote = true,
                 AsyncSink::NotReady(block) => {
                     self.cached_ob_block = Some(block);
                     write_ready = false;
@@ -340,38 +841,113 @@ where
             }
         }
 
-        // Write blocks
-        while write_ready {
+        // Apply any changes the peer has requested
+        if let Some(options) = self.ib.take_options() {
+            let rdelay = Duration::from_millis(options.rdelay.into());
+            self.pacer.set_delay(rdelay);
+        }
+
+        // Write blocks, as fast as the peer wants them
+        let mut closed = false;
+        while write_ready && self.pacer.ready()? {
             match self.outbound.poll().unwrap() {
-                Async::Ready(Some(block)) => match self.ob.start_send(block)? {
-                    AsyncSink::Ready => (),
-                    AsyncSink::NotReady(block) => {
-                        self.cached_ob_block = Some(block);
-                        write_ready = false;
+                Async::Ready(Some(block)) => {
+                    match block {
+                        Block::Message(_) => self.traffic.sent_message(),
+                        Block::Termination(..) => self.terminating = true,
+                        _ => (),
                     }
-                },
-                _ => break,
+                    match self.ob.start_send(block)? {
+                        AsyncSink::Ready => {
+                            wrote = true;
+                            self.pacer.sent();
+                        }
+                        AsyncSink::NotReady(block) => {
+                            self.cached_ob_block = Some(block);
+                            write_ready = false;
+                        }
+                    }
+                }
+                Async::Ready(None) => {
+                    closed = true;
+                    break;
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        // Send a keepalive if we haven't written anything recently
+        if let Some(timer) = &mut self.keepalive_timer {
+            if timer.expired(wrote)? && write_ready {
+                let padding = Block::Padding(OsRng.gen_range(0, KEEPALIVE_MAX_PADDING));
+                trace!("Sending keepalive to {}", self.ib.ctx.hash);
+                if let AsyncSink::NotReady(block) = self.ob.start_send(padding)? {
+                    self.cached_ob_block = Some(block);
+                }
+                wrote = true;
+                timer.expired(true)?;
             }
         }
 
+        // Once the transport has closed its channel, tell the peer after
+        // everything queued for it
+        if closed && !self.terminating && self.cached_ob_block.is_none() {
+            self.terminate(TerminationReason::NormalClose)?;
+        }
+
         // Flush blocks
-        self.ob.poll_complete()?;
+        let flushed = self.ob.poll_complete()?.is_ready();
+
+        // Close the session once everything queued for it has been sent, if
+        // the transport has closed its channel
+        if closed && flushed && self.cached_ob_block.is_none() {
+            info!("Closing session with {}", self.ib.ctx.hash);
+            return Ok(Async::Ready(None));
+        }
+
+        // Close the session if it has been idle for too long
+        let read = self.ib.take_activity();
+        let idle = match &mut self.idle_timer {
+            Some(timer) => timer.expired(wrote || read)?,
+            None => false,
+        };
+        if idle {
+            info!("Closing idle session with {}", self.ib.ctx.hash);
+            if !self.terminating {
+                // Best effort; the peer may have gone away
+                let _ = self
+                    .terminate(TerminationReason::IdleTimeout)
+                    .and_then(|_| self.ob.poll_complete());
+            }
+            return Ok(Async::Ready(None));
+        }
 
         // Read blocks
         loop {
             if let Some(f) = &mut self.pending_ib {
-                try_ready!(f
+                let distributed = f
                     .poll()
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "A subsystem is down!")));
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "A subsystem is down!"))?;
+                if distributed.is_not_ready() {
+                    self.signal_congestion()?;
+                    return Ok(Async::NotReady);
+                }
                 self.pending_ib = None;
             }
 
-            let f = try_ready!(self.ib.poll());
+            let f = match self.ib.poll()? {
+                Async::Ready(f) => f,
+                Async::NotReady => {
+                    self.signal_congestion()?;
+                    return Ok(Async::NotReady);
+                }
+            };
             if let Some((from, msg)) = f {
+                self.traffic.received_message();
                 self.pending_ib = Some(self.distributor.handle(from, msg));
             } else {
-                // EOF was reached. The remote peer has disconnected.
-                return Ok(Async::Ready(()));
+                // EOF was reached, or the remote peer terminated the session.
+                return Ok(Async::Ready(self.ib.terminated));
             }
         }
     }
@@ -386,6 +962,10 @@ where
     ctx: SessionContext<Block>,
     upstream: SplitStream<Framed<T, C>>,
     cached_msgs: VecDeque<Message>,
+    activity: bool,
+    options: Option<SessionOptions>,
+    terminated: Option<TerminationReason>,
+    unknown_messages: UnknownMessagePolicy,
 }
 
 impl<T, C> InboundSession<T, C>
@@ -394,23 +974,44 @@ where
     C: Decoder<Item = Frame, Error = io::Error>,
     C: Encoder<Item = Frame, Error = io::Error>,
 {
-    fn new(ctx: SessionContext<Block>, upstream: SplitStream<Framed<T, C>>) -> Self {
+    fn new(
+        ctx: SessionContext<Block>,
+        upstream: SplitStream<Framed<T, C>>,
+        unknown_messages: UnknownMessagePolicy,
+    ) -> Self {
         InboundSession {
             ctx,
             upstream,
             cached_msgs: VecDeque::new(),
+            activity: false,
+            options: None,
+            terminated: None,
+            unknown_messages,
         }
     }
 
+    /// Returns whether any frames have been received since the last call.
+    fn take_activity(&mut self) -> bool {
+        let activity = self.activity;
+        self.activity = false;
+        activity
+    }
+
+    /// Returns the latest options received from the peer, if any have been
+    /// received since the last call.
+    fn take_options(&mut self) -> Option<SessionOptions> {
+        self.options.take()
+    }
+
     /// Handles a block at the session level. Optionally returns a message that
-    /// should be distributed.
-    fn handle_block(&self, block: Block) -> Option<Message> {
+    /// should be distributed, or an error if the session should be closed.
+    fn handle_block(&mut self, block: Block) -> io::Result<Option<Message>> {
         match block {
             Block::RouterInfo(ri, _flags) => {
                 // Validate hash
                 if ri.router_id.hash() != self.ctx.hash {
                     warn!("Received invalid RouterInfo block from {}", self.ctx.hash);
-                    return None;
+                    return Ok(None);
                 }
 
                 // Treat as a DatabaseStore
@@ -423,21 +1024,30 @@ where
                     DatabaseStore::from_ri(ri, None),
                 ));
 
-                Some(fake_ds)
+                Ok(Some(fake_ds))
+            }
+            Block::Message(msg) => self.unknown_messages.filter(&self.ctx.hash, msg),
+            Block::Options(options) => {
+                debug!("Peer {} sent options: {:?}", self.ctx.hash, options);
+                self.options = Some(options);
+                Ok(None)
             }
-            Block::Message(msg) => Some(msg),
             Block::Padding(_) => {
                 trace!("Dropping padding block from {}: {:?}", self.ctx.hash, block);
-                None
+                Ok(None)
             }
-            Block::Termination(_, _, _) => {
+            Block::Termination(_, rsn, _) => {
                 info!("Peer {} terminated session: {:?}", self.ctx.hash, block);
-                // TODO: Send a Termination in reply, then shut down
-                None
+                self.terminated = Some(rsn);
+                Ok(None)
             }
             Block::Unknown(_, _) => {
                 debug!("Dropping unknown block: {:?}", block);
-                None
+                Ok(None)
+            }
+            Block::Malformed(_, _) => {
+                warn!("Dropping malformed block from {}: {:?}", self.ctx.hash, block);
+                Ok(None)
             }
             block => {
                 // TODO: Do something
@@ -445,7 +1055,7 @@ where
                     "Dropping unhandled block from {}: {:?}",
                     self.ctx.hash, block
                 );
-                None
+                Ok(None)
             }
         }
     }
@@ -467,12 +1077,18 @@ where
                 return Ok(Async::Ready(Some((self.ctx.hash.clone(), msg))));
             }
 
+            // Nothing more will be sent after a Termination block
+            if self.terminated.is_some() {
+                return Ok(Async::Ready(None));
+            }
+
             // Read frames
             match try_ready!(self.upstream.poll()) {
                 Some(frame) => {
+                    self.activity = true;
                     // TODO: Validate block ordering within the frame
                     for block in frame {
-                        if let Some(msg) = self.handle_block(block) {
+                        if let Some(msg) = self.handle_block(block)? {
                             self.cached_msgs.push_back(msg);
                         }
                     }
@@ -526,7 +1142,6 @@ where
         if self.cached_blocks.len() >= BLOCKS_PER_FRAME {
             // Create frame from blocks
             // TODO: Limit frame size instead of blocks per frame
-            // TODO: Add padding
             let frame = self.cached_blocks.drain(0..BLOCKS_PER_FRAME).collect();
 
             match self.downstream.start_send(frame)? {
@@ -550,7 +1165,6 @@ where
         if !self.cached_blocks.is_empty() {
             // Create frame from blocks
             // TODO: Limit frame size instead of blocks per frame
-            // TODO: Add padding
             let frame = self.cached_blocks.drain(..).collect();
 
             if let AsyncSink::NotReady(frame) = self.downstream.start_send(frame)? {
@@ -568,30 +1182,84 @@ where
 // Connection management engine
 //
 
-pub struct Manager<D: Distributor> {
-    addr: SocketAddr,
-    static_private_key: Vec<u8>,
-    static_public_key: Vec<u8>,
+/// The static key and IV published in our NTCP2 address.
+struct StaticKeys {
+    private: Vec<u8>,
+    public: Vec<u8>,
     aesobfse_iv: [u8; 16],
-    session_manager: SessionManager<Block, D>,
-    ctx: Option<Arc<Context>>,
 }
 
-impl<D: Distributor> Manager<D> {
-    pub fn new(addr: SocketAddr, distributor: D) -> Self {
-        let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
+impl StaticKeys {
+    fn generate() -> Self {
+        let builder: Builder<'_> =
+            Builder::new(CipherSuite::default().protocol_name().parse().unwrap());
         let dh = builder.generate_keypair().unwrap();
 
         let mut aesobfse_iv = [0; 16];
         let mut rng = OsRng;
         rng.fill(&mut aesobfse_iv[..]);
 
+        StaticKeys {
+            private: dh.private,
+            public: dh.public,
+            aesobfse_iv,
+        }
+    }
+
+    fn to_file(&self, path: &str) -> io::Result<()> {
+        let mut data = Vec::with_capacity(96);
+        data.write_all(&self.private)?;
+        data.write_all(&self.public)?;
+        data.write_all(&self.aesobfse_iv)?;
+        let mut keys = File::create(path)?;
+        keys.write(&data).map(|_| ())
+    }
+}
+
+/// The static key and IV we published before our most recent rotation.
+struct PreviousKeys {
+    keys: StaticKeys,
+    /// When we stop accepting handshakes that use these keys.
+    until: Instant,
+}
+
+pub struct Manager<D: Distributor> {
+    addr: SocketAddr,
+    /// An additional IPv6 address to listen on and publish, if `addr` is IPv4.
+    addr_v6: Option<SocketAddr>,
+    /// The IP address that peers can reach us at, if it differs from the one
+    /// we listen on.
+    external_ip: Option<IpAddr>,
+    keys: Arc<RwLock<StaticKeys>>,
+    previous_keys: Arc<RwLock<Option<PreviousKeys>>>,
+    rotation_window: Duration,
+    keyfile: Option<String>,
+    session_manager: SessionManager<Block, D>,
+    idle: IdleConfig,
+    handshake_config: HandshakeConfig,
+    handshake_limiter: Arc<HandshakeLimiter>,
+    dial_limiter: DialLimiter,
+    ctx: Option<Arc<Context>>,
+}
+
+impl<D: Distributor> Manager<D> {
+    pub fn new(addr: SocketAddr, distributor: D) -> Self {
         Manager {
             addr,
-            static_private_key: dh.private,
-            static_public_key: dh.public,
-            aesobfse_iv,
+            addr_v6: None,
+            external_ip: None,
+            keys: Arc::new(RwLock::new(StaticKeys::generate())),
+            previous_keys: Arc::new(RwLock::new(None)),
+            rotation_window: Duration::from_secs(DEFAULT_KEY_ROTATION_WINDOW),
+            keyfile: None,
             session_manager: session::new_manager(distributor),
+            idle: IdleConfig::default(),
+            handshake_config: HandshakeConfig {
+                ipv6: addr.is_ipv6(),
+                ..Default::default()
+            },
+            handshake_limiter: Arc::new(HandshakeLimiter::default()),
+            dial_limiter: DialLimiter::default(),
             ctx: None,
         }
     }
@@ -611,27 +1279,191 @@ impl<D: Distributor> Manager<D> {
 
         Ok(Manager {
             addr,
-            static_private_key: static_private_key.clone(),
-            static_public_key,
-            aesobfse_iv,
+            addr_v6: None,
+            external_ip: None,
+            keys: Arc::new(RwLock::new(StaticKeys {
+                private: static_private_key,
+                public: static_public_key,
+                aesobfse_iv,
+            })),
+            previous_keys: Arc::new(RwLock::new(None)),
+            rotation_window: Duration::from_secs(DEFAULT_KEY_ROTATION_WINDOW),
+            keyfile: None,
             session_manager: session::new_manager(distributor),
+            idle: IdleConfig::default(),
+            handshake_config: HandshakeConfig {
+                ipv6: addr.is_ipv6(),
+                ..Default::default()
+            },
+            handshake_limiter: Arc::new(HandshakeLimiter::default()),
+            dial_limiter: DialLimiter::default(),
             ctx: None,
         })
     }
 
     pub fn to_file(&self, path: &str) -> io::Result<()> {
-        let mut data = Vec::with_capacity(96);
-        data.write_all(&self.static_private_key)?;
-        data.write_all(&self.static_public_key)?;
-        data.write_all(&self.aesobfse_iv)?;
-        let mut keys = File::create(path)?;
-        keys.write(&data).map(|_| ())
+        self.keys.read().unwrap().to_file(path)
+    }
+
+    /// Sets what sessions do when the peer sends an unknown message type.
+    pub fn set_unknown_message_policy(&mut self, policy: UnknownMessagePolicy) {
+        self.session_manager.set_unknown_message_policy(policy);
+    }
+
+    /// Records the traffic of sessions opened from now on in `traffic`.
+    pub(super) fn set_traffic_stats(&mut self, traffic: TrafficStats) {
+        self.session_manager.set_traffic_stats(traffic);
+    }
+
+    /// Limits the bandwidth of sessions opened from now on.
+    pub(super) fn set_bandwidth_limiter(&mut self, bandwidth: BandwidthLimiter) {
+        self.session_manager.set_bandwidth_limiter(bandwidth);
+    }
+
+    /// Saves the keys to the given file whenever they are rotated.
+    pub fn set_keyfile(&mut self, path: &str) {
+        self.keyfile = Some(path.to_owned());
+    }
+
+    /// Replaces our static key and IV with new ones, saving them to the
+    /// keyfile if one is set.
+    ///
+    /// Established sessions are unaffected, and end when they next go idle.
+    /// New sessions, both inbound and outbound, use the new keys. Our
+    /// RouterInfo needs to be republished with the new address; until peers
+    /// have seen it, inbound handshakes using the previous keys are accepted
+    /// for the rotation window.
+    pub fn rotate_static_key(&self) -> io::Result<()> {
+        let keys = StaticKeys::generate();
+        if let Some(keyfile) = &self.keyfile {
+            keys.to_file(keyfile)?;
+        }
+        let mut current = self.keys.write().unwrap();
+        let previous = mem::replace(&mut *current, keys);
+        *self.previous_keys.write().unwrap() = Some(PreviousKeys {
+            keys: previous,
+            until: Instant::now() + self.rotation_window,
+        });
+        info!("Rotated NTCP2 static key");
+        Ok(())
+    }
+
+    /// Sets how long after a key rotation we still accept inbound handshakes
+    /// that use our previous static key and IV.
+    pub fn set_key_rotation_window(&mut self, window: Duration) {
+        self.rotation_window = window;
     }
 
     pub fn set_context(&mut self, ctx: Arc<Context>) {
+        self.session_manager.set_own_hash(ctx.keys.rid.hash());
         self.ctx = Some(ctx);
     }
 
+    /// Configures the idle timeout and keepalive interval for new sessions.
+    ///
+    /// Keepalives only keep sessions open if they are sent more often than the
+    /// idle timeout. The router config is checked for this by
+    /// [`config::Validate`].
+    ///
+    /// [`config::Validate`]: crate::router::config::Validate
+    pub fn set_idle_config(&mut self, idle: IdleConfig) {
+        self.idle = idle;
+    }
+
+    /// Restricts handshakes to the given cipher suite.
+    pub fn set_cipher_suite(&mut self, cipher_suite: CipherSuite) {
+        self.handshake_config.cipher_suite = cipher_suite;
+    }
+
+    /// Rejects handshakes with peers whose timestamps are further than
+    /// `max_future_skew` in the future.
+    pub fn set_max_future_skew(&mut self, max_future_skew: Duration) {
+        self.handshake_config.max_future_skew = max_future_skew;
+        self.reset_replay_filter();
+    }
+
+    /// Rejects handshakes with peers whose timestamps are further than
+    /// `max_past_skew` in the past.
+    pub fn set_max_past_skew(&mut self, max_past_skew: Duration) {
+        self.handshake_config.max_past_skew = max_past_skew;
+        self.reset_replay_filter();
+    }
+
+    /// Sizes the replay filter to cover every SessionRequest timestamp that
+    /// we accept.
+    fn reset_replay_filter(&mut self) {
+        let config = &mut self.handshake_config;
+        config.replay_filter =
+            Arc::new(ReplayFilter::new(config.max_future_skew + config.max_past_skew));
+    }
+
+    /// Aborts handshakes that have not completed within `timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_config.timeout = timeout;
+    }
+
+    /// Aborts handshakes that spend longer than `timeout` in any one state.
+    pub fn set_handshake_step_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_config.step_timeout = timeout;
+    }
+
+    /// Also listens on the given IPv6 address, and publishes it alongside our
+    /// main address.
+    pub fn set_ipv6_addr(&mut self, addr: SocketAddr) {
+        self.addr_v6 = Some(addr);
+        self.handshake_config.ipv6 = true;
+    }
+
+    /// Publishes `ip` in place of the IP address we listen on, keeping our
+    /// port. It replaces whichever of our addresses is of the same family.
+    ///
+    /// Our RouterInfo needs to be republished with the new address.
+    pub fn set_external_ip(&mut self, ip: IpAddr) {
+        self.external_ip = Some(ip);
+    }
+
+    /// Makes outbound handshakes try peers' IPv6 addresses first.
+    pub fn set_prefer_ipv6(&mut self, prefer_ipv6: bool) {
+        self.handshake_config.prefer_ipv6 = prefer_ipv6;
+    }
+
+    /// Makes outbound connections through the given SOCKS5 proxy.
+    pub fn set_socks_proxy(&mut self, proxy: Option<SocketAddr>) {
+        self.handshake_config.socks_proxy = proxy;
+    }
+
+    /// Sets how much padding is sent during and after handshakes, and the
+    /// most that peers may send us.
+    pub fn set_padding_config(&mut self, padding: PaddingConfig) {
+        self.handshake_config.padding = padding;
+    }
+
+    /// Logs each step of every handshake, including the bytes read and
+    /// written, and what was parsed.
+    pub fn set_handshake_trace(&mut self, trace: bool) {
+        self.handshake_config.trace = trace;
+    }
+
+    /// Rejects inbound handshakes from peers that the filter doesn't permit.
+    pub fn set_peer_filter(&mut self, peer_filter: Arc<PeerFilter>) {
+        self.handshake_config.peer_filter = peer_filter;
+    }
+
+    /// Rejects inbound handshakes from peers that reconnect too often.
+    pub fn set_reconnect_limiter(&mut self, reconnect_limiter: Arc<ReconnectLimiter>) {
+        self.handshake_config.reconnect_limiter = reconnect_limiter;
+    }
+
+    /// Limits the inbound handshakes that each IP address may start.
+    pub fn set_handshake_limiter(&mut self, handshake_limiter: Arc<HandshakeLimiter>) {
+        self.handshake_limiter = handshake_limiter;
+    }
+
+    /// Shares a limit on concurrent outbound dials with other transports.
+    pub(super) fn set_dial_limiter(&mut self, dial_limiter: DialLimiter) {
+        self.dial_limiter = dial_limiter;
+    }
+
     pub fn sink(&self) -> OutboundSink<D> {
         let ctx = self
             .ctx
@@ -640,49 +1472,100 @@ impl<D: Distributor> Manager<D> {
             .expect("Should have called set_context()");
         OutboundSink {
             ctx,
-            static_private_key: self.static_private_key.clone(),
+            keys: self.keys.clone(),
             session_refs: self.session_manager.refs(),
+            idle: self.idle,
+            handshake_config: self.handshake_config.clone(),
+            dial_limiter: self.dial_limiter.clone(),
         }
     }
 
-    pub fn address(&self) -> RouterAddress {
-        let mut ra = RouterAddress::new(&NTCP2_STYLE, self.addr);
-        ra.set_option(NTCP2_OPT_V.clone(), NTCP2_VERSION.clone());
-        ra.set_option(
-            NTCP2_OPT_S.clone(),
-            I2PString(I2P_BASE64.encode(&self.static_public_key)),
-        );
-        ra.set_option(
-            NTCP2_OPT_I.clone(),
-            I2PString(I2P_BASE64.encode(&self.aesobfse_iv)),
-        );
+    fn address_for(&self, addr: SocketAddr) -> RouterAddress {
+        let addr = match self.external_ip {
+            Some(ip) if ip.is_ipv4() == addr.is_ipv4() => SocketAddr::new(ip, addr.port()),
+            _ => addr,
+        };
+        let keys = self.keys.read().unwrap();
+        let mut ra = RouterAddress::new(&NTCP2_STYLE, addr);
+        ra.set_ntcp2(&keys.public, &keys.aesobfse_iv, NTCP2_VERSIONS);
         ra
     }
 
-    pub fn listen(&self, own_rid: &RouterIdentity) -> impl Future<Item = (), Error = io::Error> {
-        info!("Listening on {}", self.addr);
+    pub fn address(&self) -> RouterAddress {
+        self.address_for(self.addr)
+    }
 
-        // Bind to the address
-        let listener = TcpListener::bind(&self.addr).unwrap();
-        let static_key = self.static_private_key.clone();
+    /// Returns our main address, and our IPv6 address if we have one. Both use
+    /// the same keys.
+    pub fn addresses(&self) -> Vec<RouterAddress> {
+        let mut addresses = vec![self.address()];
+        addresses.extend(self.addr_v6.map(|addr| self.address_for(addr)));
+        addresses
+    }
+
+    pub fn listen(&self, own_rid: &RouterIdentity) -> impl Future<Item = (), Error = io::Error> {
+        // Bind to the addresses
+        let mut incoming: Box<dyn Stream<Item = TcpStream, Error = io::Error> + Send> =
+            Box::new(bind(&self.addr).unwrap().incoming());
+        if let Some(addr_v6) = self.addr_v6 {
+            incoming = Box::new(incoming.select(bind(&addr_v6).unwrap().incoming()));
+        }
+        let keys = self.keys.clone();
+        let previous_keys = self.previous_keys.clone();
         let aesobfse_key = own_rid.hash().0;
-        let aesobfse_iv = self.aesobfse_iv;
+        let idle = self.idle;
+        let handshake_config = self.handshake_config.clone();
+        let handshake_limiter = self.handshake_limiter.clone();
 
         // Give each incoming connection the references it needs
         let session_refs = self.session_manager.refs();
-        let conns = listener.incoming().zip(session_refs);
+        let conns = incoming.zip(session_refs);
+
+        // Forget quiet addresses in the background
+        let prune = prune_on_timer(self.handshake_limiter.clone());
 
         // For each incoming connection:
-        conns.for_each(move |(conn, session_refs)| {
+        let listener = conns.for_each(move |(conn, session_refs)| {
             info!("Incoming connection!");
-            // Execute the handshake
-            let conn = handshake::IBHandshake::new(conn, &static_key, &aesobfse_key, &aesobfse_iv);
+            let peer_addr = match conn.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Dropping incoming connection: {}", e);
+                    return Ok(());
+                }
+            };
+
+            // Refuse to start the handshake if the peer is flooding us
+            let permit = match handshake_limiter.permit(peer_addr.ip()) {
+                Some(permit) => permit,
+                None => {
+                    debug!("Too many handshakes from {}, dropping", peer_addr.ip());
+                    return Ok(());
+                }
+            };
+
+            // Execute the handshake, with the keys we are currently publishing.
+            // It counts towards the peer's limit until it completes.
+            let conn = ib_handshake(
+                ByteCounter::with_read_limit(conn, HANDSHAKE_READ_LIMIT),
+                &keys,
+                &previous_keys,
+                &aesobfse_key,
+                handshake_config.clone(),
+            )
+            .then(move |res| {
+                drop(permit);
+                res
+            });
 
             // Once connected:
             let process_conn = conn
-                .and_then(|(ri, conn)| {
+                .and_then(move |(ri, mut conn)| {
                     let peer_hash = ri.router_id.hash();
-                    let session = Session::new(&ri.router_id, conn, session_refs);
+                    handshake_complete(&peer_hash, &mut conn, &session_refs);
+                    let state = session_refs.state.clone();
+                    let session =
+                        Session::new(&ri.router_id, Direction::Inbound, conn, session_refs, idle);
 
                     // Treat RouterInfo from handshake as a DatabaseStore
                     debug!(
@@ -693,18 +1576,24 @@ impl<D: Distributor> Manager<D> {
                     let fake_ds = Message::from_payload(MessagePayload::DatabaseStore(
                         DatabaseStore::from_ri(ri, None),
                     ));
-                    let stored = session.distributor.handle(peer_hash, fake_ds);
+                    let stored = session.distributor.handle(peer_hash.clone(), fake_ds);
 
                     // Start the session
                     stored
-                        .map(|_| session)
+                        .map(move |_| {
+                            session.then(move |res| {
+                                session_ended(&peer_hash, res, &state);
+                                Ok(())
+                            })
+                        })
                         .map_err(|_| io::Error::new(io::ErrorKind::Other, "A subsystem is down!"))
                 })
                 .and_then(|session| session);
 
             spawn(process_conn.map_err(|e| error!("Error while listening: {:?}", e)));
             Ok(())
-        })
+        });
+        listener.select(prune).map(|_| ()).map_err(|(e, _)| e)
     }
 
     pub fn connect(
@@ -713,77 +1602,243 @@ impl<D: Distributor> Manager<D> {
         peer_ri: RouterInfo,
     ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
         connect(
-            &self.static_private_key,
+            &self.keys.read().unwrap().private,
             own_ri,
             peer_ri,
             self.session_manager.refs(),
+            self.idle,
+            self.handshake_config.clone(),
         )
     }
 }
 
+/// Binds a listener to the address. IPv6 listeners only accept IPv6
+/// connections, so that they can share a port with an IPv4 listener.
+fn bind(addr: &SocketAddr) -> io::Result<TcpListener> {
+    info!("Listening on {}", addr);
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?;
+    let listener = builder.bind(addr)?.listen(1024)?;
+    TcpListener::from_std(listener, &Handle::default())
+}
+
+/// Connects to the first of the addresses that accepts a connection, trying
+/// them in order. If a proxy is given, the connections are made through it.
+fn connect_any(
+    addrs: Vec<SocketAddr>,
+    proxy: Option<SocketAddr>,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    loop_fn((addrs.into_iter(), None), move |(mut addrs, last_err)| match addrs.next() {
+        Some(addr) => Either::A(dial(addr, proxy).then(move |res| match res {
+            Ok(conn) => Ok(Loop::Break(conn)),
+            Err(e) => {
+                debug!("Failed to connect to {}: {}", addr, e);
+                Ok(Loop::Continue((addrs, Some(e))))
+            }
+        })),
+        None => Either::B(future::err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to")
+        }))),
+    })
+}
+
+/// Opens a TCP connection to the address, directly or through a SOCKS5 proxy.
+fn dial(
+    addr: SocketAddr,
+    proxy: Option<SocketAddr>,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    match proxy {
+        Some(proxy) => Either::A(socks::connect(proxy, addr)),
+        None => Either::B(TcpStream::connect(&addr)),
+    }
+}
+
+/// Starts an inbound handshake using the keys we are currently publishing, or
+/// the previous ones if we rotated them within the rotation window.
+fn ib_handshake<T>(
+    conn: T,
+    keys: &RwLock<StaticKeys>,
+    previous_keys: &RwLock<Option<PreviousKeys>>,
+    aesobfse_key: &[u8],
+    config: HandshakeConfig,
+) -> handshake::IBHandshake<T>
+where
+    T: AsyncRead + AsyncWrite,
+    T: Send + 'static,
+{
+    let current = keys.read().unwrap();
+    let handshake = handshake::IBHandshake::new(
+        conn,
+        &current.private,
+        aesobfse_key,
+        &current.aesobfse_iv,
+        config,
+    );
+    match previous_keys.read().unwrap().as_ref() {
+        Some(previous) if Instant::now() < previous.until => handshake.with_previous_keys(
+            &previous.keys.private,
+            aesobfse_key,
+            &previous.keys.aesobfse_iv,
+        ),
+        _ => handshake,
+    }
+}
+
+/// Removes the handshake read limit from a newly-established connection,
+/// starts recording its traffic, including that of the handshake, and limits
+/// its bandwidth from now on.
+fn handshake_complete<T, D: Distributor>(
+    peer: &Hash,
+    conn: &mut Framed<ByteCounter<T>, Codec>,
+    session_refs: &SessionRefs<Block, D>,
+) {
+    let traffic = &session_refs.traffic;
+    let counter = conn.get_mut();
+    debug!(
+        "Handshake with {} read {} bytes and wrote {} bytes",
+        peer,
+        counter.bytes_read(),
+        counter.bytes_written()
+    );
+    counter.set_read_limit(None);
+    traffic.received_bytes(counter.bytes_read());
+    traffic.sent_bytes(counter.bytes_written());
+    counter.set_stats(traffic.clone());
+    counter.set_bandwidth_limiter(session_refs.bandwidth.clone());
+}
+
 fn connect<D: Distributor>(
     static_private_key: &[u8],
     own_ri: &RouterInfo,
     peer_ri: RouterInfo,
     session_refs: SessionRefs<Block, D>,
+    idle: IdleConfig,
+    handshake_config: HandshakeConfig,
 ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
     // Connect to the peer
+    let proxy = handshake_config.socks_proxy;
     let transport = match handshake::OBHandshake::new(
-        |sa| Box::new(TcpStream::connect(sa)),
+        |addrs| {
+            Box::new(
+                connect_any(addrs.to_vec(), proxy)
+                    .map(|conn| ByteCounter::with_read_limit(conn, HANDSHAKE_READ_LIMIT)),
+            )
+        },
         static_private_key,
         own_ri,
         peer_ri,
+        handshake_config,
     ) {
         Ok(t) => t,
         Err(e) => return io_err!(InvalidData, e),
     };
 
-    // Add a timeout
-    let timed = Timeout::new(transport, Duration::new(10, 0))
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
-
     // Once connected:
-    Ok(timed.and_then(|(ri, conn)| {
-        let session = Session::new(&ri, conn, session_refs);
-        spawn(session.map_err(|_| ()));
+    Ok(transport.and_then(move |(ri, mut conn)| {
+        let peer = ri.hash();
+        handshake_complete(&peer, &mut conn, &session_refs);
+        let state = session_refs.state.clone();
+        let session = Session::new(&ri, Direction::Outbound, conn, session_refs, idle);
+        spawn(session.then(move |res| {
+            session_ended(&peer, res, &state);
+            Ok(())
+        }));
         Ok(())
     }))
 }
 
+/// Handles the end of a session with a peer. If the peer terminated it because
+/// it refuses to talk to us, the transport backs off from it.
+fn session_ended(
+    peer: &Hash,
+    res: io::Result<Option<TerminationReason>>,
+    state: &SessionState<Block>,
+) {
+    match res {
+        Ok(Some(reason)) => {
+            debug!("{} terminated the session: {}", peer, reason);
+            if reason.is_rejection() {
+                state.rejected_by_peer(peer);
+            }
+        }
+        Ok(None) => (),
+        Err(e) => debug!("Session with {} failed: {}", peer, e),
+    }
+}
+
 impl<D: Distributor> Transport for Manager<D> {
+    fn style(&self) -> &I2PString {
+        &NTCP2_STYLE
+    }
+
+    fn address(&self) -> RouterAddress {
+        Manager::address(self)
+    }
+
+    fn addresses(&self) -> Vec<RouterAddress> {
+        Manager::addresses(self)
+    }
+
+    fn listen(
+        &mut self,
+        ctx: Arc<Context>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.set_context(ctx.clone());
+        Box::new(Manager::listen(self, &ctx.keys.rid))
+    }
+
     fn is_established(&self, hash: &Hash) -> bool {
         self.session_manager.have_session(hash)
     }
 
-    fn bid(&self, peer: &RouterInfo, msg_size: usize) -> Option<Bid> {
-        if msg_size > NTCP2_MTU {
+    fn sessions(&self) -> Vec<(Hash, Direction)> {
+        self.session_manager.sessions()
+    }
+
+    fn rotate_keys(&self) -> io::Result<()> {
+        self.rotate_static_key()
+    }
+
+    fn set_external_ip(&mut self, ip: IpAddr) {
+        Manager::set_external_ip(self, ip)
+    }
+
+    fn set_dial_observer(&mut self, observer: DialObserver) {
+        self.session_manager.set_dial_observer(observer);
+    }
+
+    fn close_sessions(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        self.session_manager
+            .send_all(|| Block::Termination(0, TerminationReason::RouterShutdown, vec![]));
+        Box::new(self.session_manager.close_all())
+    }
+
+    fn bid(&self, peer: &RouterInfo, msg: &Message) -> Option<Bid> {
+        if msg.ntcp2_size() > NTCP2_MTU {
             return None;
         }
 
-        let filter = |ra: &RouterAddress| {
-            match ra.option(&NTCP2_OPT_V) {
-                Some(v) => {
-                    if !v.to_csv().contains(&NTCP2_VERSION) {
-                        return false;
-                    }
-                }
-                None => return false,
-            };
-            ra.option(&NTCP2_OPT_S).is_some() && ra.option(&NTCP2_OPT_I).is_some()
-        };
-
-        if peer.address(&NTCP2_STYLE, filter).is_none()
-            && peer.address(&NTCP_STYLE, filter).is_none()
+        let ipv6 = self.handshake_config.ipv6;
+        let is_reachable = |ra: &RouterAddress| is_reachable_ntcp2_address(ra, ipv6);
+        if peer.address(&NTCP2_STYLE, is_reachable).is_none()
+            && peer.address(&NTCP_STYLE, is_reachable).is_none()
         {
             return None;
         }
 
+        // Reuse a session that is open or being opened, rather than racing
+        // another transport to open a second one
+        let hash = peer.router_id.hash();
+        let reuse = self.is_established(&hash) || self.session_manager.have_pending_session(&hash);
         Some(Bid {
-            bid: if self.is_established(&peer.router_id.hash()) {
-                10
-            } else {
-                40
-            },
+            bid: if reuse { 10 } else { 40 },
             sink: Box::new(self.sink()),
         })
     }
@@ -791,8 +1846,11 @@ impl<D: Distributor> Transport for Manager<D> {
 
 pub struct OutboundSink<D: Distributor> {
     ctx: Arc<Context>,
-    static_private_key: Vec<u8>,
+    keys: Arc<RwLock<StaticKeys>>,
     session_refs: SessionRefs<Block, D>,
+    idle: IdleConfig,
+    handshake_config: HandshakeConfig,
+    dial_limiter: DialLimiter,
 }
 
 impl<D: Distributor> Sink for OutboundSink<D> {
@@ -803,28 +1861,37 @@ impl<D: Distributor> Sink for OutboundSink<D> {
         &mut self,
         (peer, msg): Self::SinkItem,
     ) -> StartSend<Self::SinkItem, Self::SinkError> {
-        let static_private_key = self.static_private_key.clone();
+        let ctx = self.ctx.clone();
+        let keys = self.keys.clone();
         let session_refs = self.session_refs.clone();
+        let idle = self.idle;
+        let handshake_config = self.handshake_config.clone();
+        let state = self.session_refs.state.clone();
+        let dial_limiter = &self.dial_limiter;
 
         match self
             .session_refs
             .state
             .send(&peer.router_id.hash(), Block::Message(msg), || {
-                // Connect to the peer
-                let session_refs = session_refs.clone();
-                match connect(
-                    &static_private_key,
-                    &self.ctx.ri.read().unwrap(),
-                    peer.clone(),
-                    session_refs,
-                ) {
-                    Ok(f) => {
-                        spawn(f.map_err(|e| {
-                            error!("Error while connecting: {}", e);
-                        }));
-                    }
-                    Err(e) => error!("{}", e),
-                }
+                // Connect to the peer, once we are under the dial limit
+                let peer = peer.clone();
+                let hash = peer.router_id.hash();
+                let dial = lazy(move || {
+                    let own_ri = ctx.ri.read().unwrap();
+                    connect(
+                        &keys.read().unwrap().private,
+                        &own_ri,
+                        peer,
+                        session_refs,
+                        idle,
+                        handshake_config,
+                    )
+                })
+                .flatten();
+                spawn(dial_limiter.limit(dial).map_err(move |e| {
+                    error!("Error while connecting: {}", e);
+                    state.connect_failed(&hash);
+                }));
             }) {
             Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
             Ok(AsyncSink::NotReady(Block::Message(msg))) => Ok(AsyncSink::NotReady((peer, msg))),
@@ -846,16 +1913,32 @@ impl<D: Distributor> Sink for OutboundSink<D> {
 mod tests {
     use bytes::BytesMut;
     use cookie_factory::GenError;
-    use futures::{lazy, Future, Sink};
+    use futures::{
+        future::{done, Either},
+        lazy, Async, Future, Sink, Stream,
+    };
     use nom::{Err, Offset};
     use std::io::{self, Read, Write};
     use std::iter::repeat;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
     use tokio::codec::{Decoder, Encoder};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
 
-    use super::{frame, Frame, Manager, Session, NTCP2_MTU};
+    use super::{
+        frame, handshake::OBHandshake, ib_handshake, session_ended, Block, Direction, Frame,
+        HandshakeConfig, IdleConfig, Manager, Session, SessionOptions, TerminationReason,
+        UnknownMessagePolicy, NTCP2_MTU,
+    };
+    use crate::data::{Hash, RouterAddress, RouterInfo, RouterSecretKeys, NTCP2_OPT_I, NTCP2_OPT_S};
     use crate::i2np::Message;
     use crate::router::mock::{mock_context, MockDistributor};
-    use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
+    use crate::transport::{
+        tests::{AliceNet, BobNet, NetworkCable},
+        Transport,
+    };
 
     struct TestCodec;
 
@@ -951,7 +2034,13 @@ mod tests {
             assert!(received.is_empty());
 
             // Create a session
-            let mut session = Session::new(&rid, alice_framed, manager.session_manager.refs());
+            let mut session = Session::new(
+                &rid,
+                Direction::Outbound,
+                alice_framed,
+                manager.session_manager.refs(),
+                IdleConfig::default(),
+            );
 
             // Pass it through the session, now it's on the wire
             session.poll().unwrap();
@@ -965,6 +2054,54 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn session_close() {
+        let ctx = mock_context();
+        let ri = ctx.ri.read().unwrap().clone();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let alice_framed = TestCodec {}.framed(AliceNet::new(cable.clone()));
+
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        manager.set_context(ctx);
+
+        // Run on a task context
+        lazy(move || {
+            // Queue a message for a session
+            let sink = manager.sink();
+            sink.send((ri, Message::dummy_data())).poll().unwrap();
+            let mut session = Session::new(
+                &rid,
+                Direction::Outbound,
+                alice_framed,
+                manager.session_manager.refs(),
+                IdleConfig::default(),
+            );
+
+            // Closing waits for the session to end
+            let mut closed = manager.session_manager.close_all();
+            assert_eq!(closed.poll(), Ok(Async::NotReady));
+
+            // The session sends the queued message, tells the peer that it is
+            // closing, and then closes
+            assert_eq!(session.poll().unwrap(), Async::Ready(None));
+            let mut bob_net = BobNet::new(cable);
+            let mut received = Vec::new();
+            assert!(bob_net.read_to_end(&mut received).is_err());
+            let mut expected = DUMMY_MSG_NTCP2_DATA.to_vec();
+            expected.extend_from_slice(&[0x04, 0x00, 0x09, 0, 0, 0, 0, 0, 0, 0, 0, 0x00]);
+            assert_eq!(received, expected);
+
+            drop(session);
+            assert_eq!(closed.poll(), Ok(Async::Ready(())));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
     #[test]
     fn session_receive() {
         let ctx = mock_context();
@@ -978,7 +2115,13 @@ mod tests {
         let distributor = MockDistributor::new();
         let received = distributor.received.clone();
         let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
-        let mut session = Session::new(&rid, bob_framed, manager.session_manager.refs());
+        let mut session = Session::new(
+            &rid,
+            Direction::Inbound,
+            bob_framed,
+            manager.session_manager.refs(),
+            IdleConfig::default(),
+        );
 
         // Run on a task context
         lazy(move || {
@@ -1002,4 +2145,497 @@ mod tests {
         .wait()
         .unwrap();
     }
+
+    #[test]
+    fn session_receive_skips_invalid_blocks() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let bob_framed = TestCodec {}.framed(BobNet::new(cable.clone()));
+
+        let distributor = MockDistributor::new();
+        let received = distributor.received.clone();
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
+        let mut session = Session::new(
+            &rid,
+            Direction::Inbound,
+            bob_framed,
+            manager.session_manager.refs(),
+            IdleConfig::default(),
+        );
+
+        // Run on a task context
+        lazy(move || {
+            let mut alice_net = AliceNet::new(cable);
+            // Unknown block
+            assert!(alice_net.write_all(&[0xe0, 0x00, 0x02, 0x12, 0x34]).is_ok());
+            // I2NP block that fails to parse
+            assert!(alice_net.write_all(&[0x03, 0x00, 0x02, 0x56, 0x78]).is_ok());
+            // Valid I2NP block
+            assert!(alice_net.write_all(DUMMY_MSG_NTCP2_DATA).is_ok());
+
+            // The session should still be open, and have received the valid block
+            assert_eq!(session.poll().unwrap(), Async::NotReady);
+            let r = received.lock().unwrap();
+            assert_eq!(r.len(), 1);
+            assert_eq!(r[0].1, *DUMMY_MSG);
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn session_receive_unknown_message() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+
+        for &policy in &[
+            UnknownMessagePolicy::Ignore,
+            UnknownMessagePolicy::Log,
+            UnknownMessagePolicy::Disconnect,
+        ] {
+            let cable = NetworkCable::new();
+            let bob_framed = TestCodec {}.framed(BobNet::new(cable.clone()));
+
+            let distributor = MockDistributor::new();
+            let received = distributor.received.clone();
+            let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
+            manager.set_unknown_message_policy(policy);
+            let mut session = Session::new(
+                &rid,
+                Direction::Inbound,
+                bob_framed,
+                manager.session_manager.refs(),
+                IdleConfig::default(),
+            );
+
+            lazy(move || {
+                let mut alice_net = AliceNet::new(cable);
+                // I2NP block containing a message of unknown type 0x7f
+                assert!(alice_net
+                    .write_all(&[
+                        0x03, 0x00, 0x0c, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                        0x01, 0x02, 0x03
+                    ])
+                    .is_ok());
+                // Valid I2NP block
+                assert!(alice_net.write_all(DUMMY_MSG_NTCP2_DATA).is_ok());
+
+                match policy {
+                    UnknownMessagePolicy::Ignore | UnknownMessagePolicy::Log => {
+                        // The session should still be open, and have received only the
+                        // valid block
+                        assert_eq!(session.poll().unwrap(), Async::NotReady);
+                        let r = received.lock().unwrap();
+                        assert_eq!(r.len(), 1);
+                        assert_eq!(r[0].1, *DUMMY_MSG);
+                    }
+                    UnknownMessagePolicy::Disconnect => {
+                        // The session should have been closed
+                        assert!(session.poll().is_err());
+                        assert!(received.lock().unwrap().is_empty());
+                    }
+                }
+
+                Ok::<(), ()>(())
+            })
+            .wait()
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn session_shutdown() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let alice_framed = TestCodec {}.framed(AliceNet::new(cable.clone()));
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+
+        // Run on a task context
+        lazy(move || {
+            let mut session = Session::new(
+                &rid,
+                Direction::Outbound,
+                alice_framed,
+                manager.session_manager.refs(),
+                IdleConfig::default(),
+            );
+
+            // The session tells the peer that the router is shutting down,
+            // and then closes
+            let mut closed = Transport::close_sessions(&mut manager);
+            assert_eq!(session.poll().unwrap(), Async::Ready(None));
+            let mut bob_framed = TestCodec {}.framed(BobNet::new(cable));
+            match bob_framed.poll() {
+                Ok(Async::Ready(Some(frame))) => assert_eq!(
+                    frame,
+                    vec![Block::Termination(
+                        0,
+                        TerminationReason::RouterShutdown,
+                        vec![]
+                    )]
+                ),
+                _ => panic!("Session should have sent a Termination block"),
+            }
+
+            drop(session);
+            assert_eq!(closed.poll(), Ok(Async::Ready(())));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn session_receive_termination() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let bob_framed = TestCodec {}.framed(BobNet::new(cable.clone()));
+
+        let distributor = MockDistributor::new();
+        let received = distributor.received.clone();
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
+        let mut session = Session::new(
+            &rid,
+            Direction::Inbound,
+            bob_framed,
+            manager.session_manager.refs(),
+            IdleConfig::default(),
+        );
+
+        // Run on a task context
+        lazy(move || {
+            let mut alice_net = AliceNet::new(cable);
+            assert!(alice_net.write_all(DUMMY_MSG_NTCP2_DATA).is_ok());
+            // Termination block for an idle timeout
+            assert!(alice_net
+                .write_all(&[0x04, 0x00, 0x09, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x02])
+                .is_ok());
+
+            // The message is received, and then the session is closed with
+            // the peer's reason
+            assert_eq!(
+                session.poll().unwrap(),
+                Async::Ready(Some(TerminationReason::IdleTimeout))
+            );
+            let r = received.lock().unwrap();
+            assert_eq!(r.len(), 1);
+            assert_eq!(r[0].1, *DUMMY_MSG);
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn peer_rejection() {
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        let outcomes = Arc::new(Mutex::new(vec![]));
+        let observed = outcomes.clone();
+        manager.set_dial_observer(Arc::new(move |peer: &Hash, success: bool| {
+            observed.lock().unwrap().push((peer.clone(), success))
+        }));
+        let state = manager.session_manager.refs().state;
+        let peer = Hash([1; 32]);
+
+        // Sessions that end normally don't count against the peer
+        session_ended(&peer, Ok(None), &state);
+        session_ended(&peer, Ok(Some(TerminationReason::IdleTimeout)), &state);
+        assert!(outcomes.lock().unwrap().is_empty());
+
+        // A peer that bans us is treated like one we can't reach
+        session_ended(&peer, Ok(Some(TerminationReason::Banned)), &state);
+        assert_eq!(*outcomes.lock().unwrap(), vec![(peer, false)]);
+    }
+
+    /// Sends messages over a session after the peer has sent the given options,
+    /// and returns the number of messages the peer receives in one poll.
+    fn send_after_options(options: Option<SessionOptions>, count: usize) -> usize {
+        let ctx = mock_context();
+        let ri = ctx.ri.read().unwrap().clone();
+        let rid = ctx.keys.rid.clone();
+
+        let cable = NetworkCable::new();
+        let mut bob_framed = TestCodec {}.framed(BobNet::new(cable.clone()));
+
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        manager.set_context(ctx);
+        let mut session = Session::new(
+            &rid,
+            Direction::Outbound,
+            TestCodec {}.framed(AliceNet::new(cable)),
+            manager.session_manager.refs(),
+            IdleConfig::default(),
+        );
+
+        let mut rt = Runtime::new().unwrap();
+        let received = rt
+            .block_on(lazy(move || {
+                if let Some(options) = options {
+                    bob_framed.start_send(vec![Block::Options(options)])?;
+                    bob_framed.poll_complete()?;
+                }
+
+                // Receive the options
+                assert_eq!(session.poll()?, Async::NotReady);
+
+                let mut sink = manager.sink();
+                for _ in 0..count {
+                    sink.start_send((ri.clone(), Message::dummy_data()))?;
+                }
+                assert_eq!(session.poll()?, Async::NotReady);
+
+                bob_framed.poll()
+            }))
+            .unwrap();
+
+        match received {
+            Async::Ready(Some(frame)) => frame
+                .iter()
+                .filter(|block| match block {
+                    Block::Message(_) => true,
+                    _ => false,
+                })
+                .count(),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn session_congestion_hint() {
+        // Without a hint, messages are sent as fast as possible
+        assert_eq!(send_after_options(None, 3), 3);
+        assert_eq!(send_after_options(Some(SessionOptions::default()), 3), 3);
+
+        // When the peer asks for a delay between messages, we slow down
+        let options = SessionOptions {
+            rdelay: 1000,
+            ..Default::default()
+        };
+        assert_eq!(send_after_options(Some(options), 3), 1);
+    }
+
+    #[test]
+    fn session_keepalive() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let idle_timeout = Duration::from_millis(100);
+
+        let cable = NetworkCable::new();
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        let mut rt = Runtime::new().unwrap();
+
+        // Without keepalives, an idle session is closed after the idle timeout
+        let session = Session::new(
+            &rid,
+            Direction::Outbound,
+            TestCodec {}.framed(AliceNet::new(cable.clone())),
+            manager.session_manager.refs(),
+            IdleConfig {
+                timeout: Some(idle_timeout),
+                keepalive: None,
+            },
+        );
+        assert!(manager.is_established(&rid.hash()));
+        let deadline = Delay::new(Instant::now() + idle_timeout * 4);
+        match rt.block_on(session.select2(deadline)) {
+            Ok(Either::A(_)) => (),
+            _ => panic!("Idle session should have been closed"),
+        }
+
+        // The peer is no longer connected
+        assert!(!manager.is_established(&rid.hash()));
+
+        // and the peer was told why
+        let mut bob_framed = TestCodec {}.framed(BobNet::new(cable.clone()));
+        match lazy(|| bob_framed.poll()).wait().unwrap() {
+            Async::Ready(Some(frame)) => assert_eq!(
+                frame,
+                vec![Block::Termination(0, TerminationReason::IdleTimeout, vec![])]
+            ),
+            _ => panic!("Idle session should have sent a Termination block"),
+        }
+
+        // With keepalives, the session stays open past the idle timeout
+        let session = Session::new(
+            &rid,
+            Direction::Outbound,
+            TestCodec {}.framed(AliceNet::new(cable.clone())),
+            manager.session_manager.refs(),
+            IdleConfig {
+                timeout: Some(idle_timeout),
+                keepalive: Some(idle_timeout / 4),
+            },
+        );
+        let deadline = Delay::new(Instant::now() + idle_timeout * 4);
+        match rt.block_on(session.select2(deadline)) {
+            Ok(Either::B(_)) => (),
+            _ => panic!("Session should have been kept alive"),
+        }
+
+        // The keepalives contained only padding (TestCodec has no frame
+        // boundaries, so all received blocks are parsed as a single frame)
+        let mut bob_framed = TestCodec {}.framed(BobNet::new(cable));
+        let received = lazy(move || bob_framed.poll()).wait().unwrap();
+        match received {
+            Async::Ready(Some(frame)) => {
+                assert!(frame.len() >= 3);
+                assert!(frame.iter().all(|block| match block {
+                    Block::Padding(_) => true,
+                    _ => false,
+                }));
+            }
+            _ => panic!("Keepalives should have been sent"),
+        }
+    }
+
+    /// Returns whether a peer that connects to the given address completes a
+    /// handshake with the manager's listener.
+    fn inbound_handshake(
+        manager: &Manager<MockDistributor>,
+        own_keys: &RouterSecretKeys,
+        address: RouterAddress,
+    ) -> bool {
+        let mut own_ri = RouterInfo::new(own_keys.rid.clone());
+        own_ri.set_addresses(vec![address]);
+        own_ri.sign(&own_keys.signing_private_key);
+
+        let peer_keys = RouterSecretKeys::new();
+        let peer_mgr = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let mut peer_ri = RouterInfo::new(peer_keys.rid.clone());
+        peer_ri.set_addresses(vec![peer_mgr.address()]);
+        peer_ri.sign(&peer_keys.signing_private_key);
+
+        let config = HandshakeConfig {
+            timeout: None,
+            ..Default::default()
+        };
+        let cable = NetworkCable::new();
+        let alice_net = AliceNet::new(cable.clone());
+        let mut alice = OBHandshake::new(
+            |_| Box::new(done(Ok(alice_net))),
+            &peer_mgr.keys.read().unwrap().private,
+            &peer_ri,
+            own_ri,
+            config.clone(),
+        )
+        .unwrap();
+        let mut bob = ib_handshake(
+            BobNet::new(cable),
+            &manager.keys,
+            &manager.previous_keys,
+            &own_keys.rid.hash().0,
+            config,
+        );
+
+        match alice.poll() {
+            Ok(Async::NotReady) => (),
+            _ => panic!("Alice should be waiting for Bob"),
+        }
+        if bob.poll().is_err() {
+            return false;
+        }
+        match alice.poll() {
+            Ok(Async::Ready(_)) => (),
+            _ => panic!("Alice should have finished the handshake"),
+        }
+        bob.poll().map(|a| a.is_ready()).unwrap_or(false)
+    }
+
+    #[test]
+    fn rotate_static_key() {
+        let dir = tempdir().unwrap();
+        let keyfile = dir.path().join("test.ntcp2.keys.dat");
+        let keyfile = keyfile.to_str().unwrap();
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        manager.set_keyfile(keyfile);
+        let old_address = manager.address();
+        assert!(inbound_handshake(&manager, &ctx.keys, old_address.clone()));
+
+        // A session established before the rotation
+        let session = Session::new(
+            &rid,
+            Direction::Inbound,
+            TestCodec {}.framed(BobNet::new(NetworkCable::new())),
+            manager.session_manager.refs(),
+            IdleConfig::default(),
+        );
+
+        manager.rotate_static_key().unwrap();
+        let new_address = manager.address();
+        assert_ne!(
+            new_address.option(&NTCP2_OPT_S),
+            old_address.option(&NTCP2_OPT_S)
+        );
+        assert_ne!(
+            new_address.option(&NTCP2_OPT_I),
+            old_address.option(&NTCP2_OPT_I)
+        );
+
+        // New handshakes use the new key, but the old one is still accepted
+        // during the rotation window
+        assert!(inbound_handshake(&manager, &ctx.keys, new_address.clone()));
+        assert!(inbound_handshake(&manager, &ctx.keys, old_address.clone()));
+
+        // After which it is not
+        if let Some(previous) = manager.previous_keys.write().unwrap().as_mut() {
+            previous.until = Instant::now();
+        }
+        assert!(inbound_handshake(&manager, &ctx.keys, new_address.clone()));
+        assert!(!inbound_handshake(&manager, &ctx.keys, old_address));
+
+        // The old session stays open until it ends
+        assert!(manager.session_manager.have_session(&rid.hash()));
+        drop(session);
+        assert!(!manager.session_manager.have_session(&rid.hash()));
+
+        // The new keys were saved
+        let reloaded = Manager::from_file(
+            "127.0.0.1:1234".parse().unwrap(),
+            keyfile,
+            MockDistributor::new(),
+        )
+        .unwrap();
+        assert_eq!(reloaded.address(), new_address);
+    }
+
+    #[test]
+    fn rotation_window() {
+        let ctx = mock_context();
+        let mut manager = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        manager.set_key_rotation_window(Duration::from_secs(60));
+        let first = manager.address();
+
+        manager.rotate_static_key().unwrap();
+        let second = manager.address();
+        assert_ne!(second.option(&NTCP2_OPT_I), first.option(&NTCP2_OPT_I));
+        assert!(inbound_handshake(&manager, &ctx.keys, first.clone()));
+        assert!(inbound_handshake(&manager, &ctx.keys, second.clone()));
+
+        // Only the keys from immediately before a rotation are accepted
+        manager.rotate_static_key().unwrap();
+        let third = manager.address();
+        assert!(!inbound_handshake(&manager, &ctx.keys, first));
+        assert!(inbound_handshake(&manager, &ctx.keys, second.clone()));
+        assert!(inbound_handshake(&manager, &ctx.keys, third.clone()));
+
+        // Without a window, only the current keys are accepted
+        manager.set_key_rotation_window(Duration::from_secs(0));
+        manager.rotate_static_key().unwrap();
+        assert!(!inbound_handshake(&manager, &ctx.keys, second));
+        assert!(!inbound_handshake(&manager, &ctx.keys, third));
+        assert!(inbound_handshake(&manager, &ctx.keys, manager.address()));
+    }
 }
@@ -0,0 +1,329 @@
+//! Padding for handshake messages and data-phase frames.
+//!
+//! Handshake messages are padded with a random length of up to a configured
+//! maximum. Data-phase frames are padded in proportion to their payload: each
+//! router sends the other an Options block giving the range of padding ratios
+//! that it is willing to send, and the most that it will accept. Frames are
+//! then padded with a ratio chosen at random from the range that both routers
+//! agree on, and a peer that sends more padding than it was asked to is
+//! committing a padding violation.
+//!
+//! With the `deterministic-padding` feature enabled, every handshake message
+//! gets the same length of padding, data-phase frames are padded with the
+//! lowest agreed ratio, and all padding is filled with zeros, so that
+//! handshakes can be compared against test vectors from other
+//! implementations. This makes NTCP2 connections trivial to fingerprint, so the
+//! feature must never be enabled in a router that talks to the network.
+
+#[cfg(not(feature = "deterministic-padding"))]
+use rand::{rngs::OsRng, Rng};
+use std::cmp;
+
+use super::{Block, Frame, SessionOptions};
+
+/// The length of the padding on each handshake message, when padding is
+/// deterministic.
+#[cfg(feature = "deterministic-padding")]
+pub(super) const DETERMINISTIC_PADDING_LEN: u16 = 8;
+
+/// Default upper bound (inclusive) on the padding length of a handshake
+/// message.
+const DEFAULT_HANDSHAKE_MAX_PADDING: u16 = 31;
+
+/// Any frame may carry this many bytes of padding regardless of the padding
+/// ratio, so that padding-only frames (such as keepalives) are permitted.
+const PADDING_ALLOWANCE: usize = 32;
+
+/// The length of the header of a padding block.
+const PADDING_HEADER_LEN: usize = 3;
+
+/// The largest padding ratio that can be sent in an Options block.
+const MAX_RATIO: f64 = 255.0 / 16.0;
+
+/// Converts a padding ratio to 4.4 fixed-point.
+fn to_fixed(ratio: f64) -> u8 {
+    (ratio.max(0.0).min(MAX_RATIO) * 16.0).round() as u8
+}
+
+/// Converts a padding ratio from 4.4 fixed-point.
+fn from_fixed(ratio: u8) -> f64 {
+    f64::from(ratio) / 16.0
+}
+
+/// Parameters controlling how much padding is sent and accepted.
+///
+/// Padding ratios are the length of the padding in a data-phase frame divided
+/// by the length of the rest of the frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaddingConfig {
+    /// Upper bound (inclusive) on the padding length of a handshake message.
+    pub handshake_max: u16,
+    /// The lowest padding ratio that we will send.
+    pub min_ratio: f64,
+    /// The highest padding ratio that we will send.
+    pub max_ratio: f64,
+    /// The highest padding ratio that we accept from peers.
+    pub max_received_ratio: f64,
+}
+
+impl Default for PaddingConfig {
+    fn default() -> Self {
+        PaddingConfig {
+            handshake_max: DEFAULT_HANDSHAKE_MAX_PADDING,
+            min_ratio: 0.0,
+            max_ratio: 0.5,
+            max_received_ratio: 2.0,
+        }
+    }
+}
+
+/// Returns the length of the padding to add to a handshake message.
+#[cfg(not(feature = "deterministic-padding"))]
+pub(super) fn handshake_padlen(max: u16) -> u16 {
+    OsRng.gen_range(0, u32::from(max) + 1) as u16
+}
+
+/// Returns the length of the padding to add to a handshake message.
+#[cfg(feature = "deterministic-padding")]
+pub(super) fn handshake_padlen(_max: u16) -> u16 {
+    DETERMINISTIC_PADDING_LEN
+}
+
+/// Returns the padding ratio to use for a data-phase frame.
+#[cfg(not(feature = "deterministic-padding"))]
+fn sample_ratio(min: f64, max: f64) -> f64 {
+    if max > min {
+        OsRng.gen_range(min, max)
+    } else {
+        min
+    }
+}
+
+/// Returns the padding ratio to use for a data-phase frame.
+#[cfg(feature = "deterministic-padding")]
+fn sample_ratio(min: f64, _max: f64) -> f64 {
+    min
+}
+
+/// Fills `buf` with padding.
+#[cfg(not(feature = "deterministic-padding"))]
+pub(super) fn fill(buf: &mut [u8]) {
+    OsRng.fill(buf);
+}
+
+/// Fills `buf` with padding.
+#[cfg(feature = "deterministic-padding")]
+pub(super) fn fill(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+}
+
+/// The padding parameters negotiated for one connection.
+pub(super) struct DataPadding {
+    config: PaddingConfig,
+    /// The range of ratios we send, narrowed to what the peer has requested.
+    min_ratio: f64,
+    max_ratio: f64,
+    options_sent: bool,
+}
+
+impl DataPadding {
+    pub(super) fn new(config: PaddingConfig) -> Self {
+        DataPadding {
+            config,
+            min_ratio: config.min_ratio,
+            max_ratio: config.max_ratio,
+            options_sent: false,
+        }
+    }
+
+    /// Sets the padding parameters in an Options block to ours.
+    fn fill_options(&self, options: &mut SessionOptions) {
+        options.tmin = to_fixed(self.config.min_ratio);
+        options.tmax = to_fixed(self.config.max_ratio);
+        options.rmin = 0;
+        options.rmax = to_fixed(self.config.max_received_ratio);
+    }
+
+    /// Prepares a frame to be sent. Our padding parameters are added to any
+    /// Options blocks in the frame, and sent in an Options block of their own
+    /// if the peer hasn't been sent them yet.
+    pub(super) fn prepare(&mut self, frame: &mut Frame) {
+        for block in frame.iter_mut() {
+            if let Block::Options(options) = block {
+                self.fill_options(options);
+                self.options_sent = true;
+            }
+        }
+
+        if !self.options_sent {
+            let mut options = SessionOptions::default();
+            self.fill_options(&mut options);
+            frame.insert(0, Block::Options(options));
+            self.options_sent = true;
+        }
+    }
+
+    /// Returns the length of the padding block to append to a frame that is
+    /// `len` bytes long, without making it longer than `max_len` bytes. Returns
+    /// `None` if the frame should not be padded.
+    pub(super) fn padlen(&self, frame: &[Block], len: usize, max_len: usize) -> Option<u16> {
+        // A frame can contain at most one padding block
+        if frame.iter().any(|block| match block {
+            Block::Padding(_) => true,
+            _ => false,
+        }) {
+            return None;
+        }
+
+        let room = max_len.checked_sub(len + PADDING_HEADER_LEN)?;
+        let padlen = (len as f64 * sample_ratio(self.min_ratio, self.max_ratio)) as usize;
+        match cmp::min(cmp::min(padlen, room), usize::from(u16::max_value())) {
+            0 => None,
+            padlen => Some(padlen as u16),
+        }
+    }
+
+    /// Handles a received frame that was `len` bytes long, applying any
+    /// padding parameters that the peer sent.
+    ///
+    /// Returns the length of the padding if the frame contains more padding
+    /// than we accept.
+    pub(super) fn received(&mut self, frame: &[Block], len: usize) -> Result<(), usize> {
+        let mut padding = 0;
+        for block in frame {
+            match block {
+                Block::Options(options) => {
+                    // Send no more padding than the peer accepts, and at least
+                    // as much as it requires if we are willing to
+                    self.max_ratio = self.config.max_ratio.min(from_fixed(options.rmax));
+                    self.min_ratio = self
+                        .config
+                        .min_ratio
+                        .max(from_fixed(options.rmin))
+                        .min(self.max_ratio);
+                }
+                Block::Padding(size) => padding += usize::from(*size) + PADDING_HEADER_LEN,
+                _ => (),
+            }
+        }
+
+        // Peers only see our maximum in fixed-point
+        let max_ratio = from_fixed(to_fixed(self.config.max_received_ratio));
+        let allowed = (len.saturating_sub(padding) as f64 * max_ratio) as usize + PADDING_ALLOWANCE;
+        if padding > allowed {
+            Err(padding)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_fixed, to_fixed, DataPadding, PaddingConfig, PADDING_ALLOWANCE};
+    use crate::i2np::Message;
+    use crate::transport::ntcp2::{Block, SessionOptions};
+
+    #[test]
+    fn fixed_point_ratios() {
+        assert_eq!(to_fixed(0.0), 0);
+        assert_eq!(to_fixed(0.5), 0x08);
+        assert_eq!(to_fixed(2.25), 0x24);
+        assert_eq!(to_fixed(100.0), 0xff);
+        assert_eq!(to_fixed(-1.0), 0);
+        assert_eq!(to_fixed(from_fixed(0x24)), 0x24);
+    }
+
+    #[test]
+    fn options_sent_once() {
+        let mut padding = DataPadding::new(PaddingConfig::default());
+
+        let mut frame = vec![Block::Message(Message::dummy_data())];
+        padding.prepare(&mut frame);
+        assert_eq!(frame.len(), 2);
+        match &frame[0] {
+            Block::Options(options) => {
+                assert_eq!(options.tmin, 0);
+                assert_eq!(options.tmax, 0x08);
+                assert_eq!(options.rmax, 0x20);
+            }
+            _ => panic!("First frame should contain our options"),
+        }
+
+        let mut frame = vec![Block::Message(Message::dummy_data())];
+        padding.prepare(&mut frame);
+        assert_eq!(frame.len(), 1);
+
+        // Options sent for other reasons carry our padding parameters
+        let mut frame = vec![Block::Options(SessionOptions {
+            rdelay: 50,
+            ..Default::default()
+        })];
+        padding.prepare(&mut frame);
+        match &frame[0] {
+            Block::Options(options) => {
+                assert_eq!(options.rdelay, 50);
+                assert_eq!(options.tmax, 0x08);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn padding_negotiation() {
+        let mut padding = DataPadding::new(PaddingConfig {
+            min_ratio: 0.25,
+            max_ratio: 1.0,
+            ..Default::default()
+        });
+
+        // Frames are padded within our range
+        for _ in 0..100 {
+            let padlen = padding.padlen(&[], 1000, 65535).unwrap();
+            assert!((250..=1000).contains(&padlen));
+        }
+
+        // Padding is limited by the space left in the frame
+        assert_eq!(padding.padlen(&[], 1000, 1103), Some(100));
+        assert_eq!(padding.padlen(&[], 1000, 1002), None);
+
+        // Frames that already contain padding are left alone
+        assert_eq!(padding.padlen(&[Block::Padding(1)], 1000, 65535), None);
+
+        // The peer can narrow our range
+        let options = Block::Options(SessionOptions {
+            rmin: to_fixed(0.5),
+            rmax: to_fixed(0.5),
+            ..Default::default()
+        });
+        assert_eq!(padding.received(&[options], 100), Ok(()));
+        assert_eq!(padding.padlen(&[], 1000, 65535), Some(500));
+
+        // and can ask for no padding
+        let options = Block::Options(SessionOptions::default());
+        assert_eq!(padding.received(&[options], 100), Ok(()));
+        assert_eq!(padding.padlen(&[], 1000, 65535), None);
+    }
+
+    #[test]
+    fn padding_violation() {
+        let mut padding = DataPadding::new(PaddingConfig {
+            max_received_ratio: 1.0,
+            ..Default::default()
+        });
+
+        // Padding-only frames are allowed, up to a point
+        let frame = [Block::Padding(10)];
+        assert_eq!(padding.received(&frame, 13), Ok(()));
+        let frame = [Block::Padding(PADDING_ALLOWANCE as u16)];
+        let len = PADDING_ALLOWANCE + 3;
+        assert_eq!(padding.received(&frame, len), Err(len));
+
+        // Otherwise, padding is limited by the ratio
+        let frame = [Block::Padding(1000)];
+        assert_eq!(padding.received(&frame, 2003), Ok(()));
+        assert_eq!(padding.received(&frame, 1500), Err(1003));
+    }
+}
@@ -0,0 +1,92 @@
+//! Detection of replayed SessionRequests.
+//!
+//! An attacker who captures a SessionRequest can send it to us again, and we
+//! would do the work of responding to it. Alice picks a new ephemeral key for
+//! every handshake, so we remember the (obfuscated) ephemeral keys we have
+//! seen, and drop any SessionRequest that reuses one. Keys only need to be
+//! remembered for as long as the timestamp in the SessionRequest would be
+//! accepted.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::util::DecayingBloomFilter;
+
+/// Number of SessionRequests we expect to see in each period.
+const REPLAY_FILTER_SIZE: u64 = 10_000;
+
+struct State {
+    filter: DecayingBloomFilter,
+    decayed: Instant,
+}
+
+/// Remembers the ephemeral keys of recent SessionRequests.
+pub struct ReplayFilter {
+    period: Duration,
+    state: Mutex<State>,
+}
+
+impl fmt::Debug for ReplayFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayFilter")
+            .field("period", &self.period)
+            .finish()
+    }
+}
+
+impl PartialEq for ReplayFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.period == other.period
+    }
+}
+
+impl ReplayFilter {
+    /// Remembers each key for at least `period`.
+    pub fn new(period: Duration) -> Self {
+        ReplayFilter {
+            period,
+            state: Mutex::new(State {
+                filter: DecayingBloomFilter::new(REPLAY_FILTER_SIZE),
+                decayed: Instant::now(),
+            }),
+        }
+    }
+
+    /// Records an ephemeral key.
+    ///
+    /// Returns true if the key has already been seen.
+    pub fn seen(&self, key: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.decayed.elapsed() >= self.period {
+            state.filter.decay();
+            state.decayed = Instant::now();
+        }
+        state.filter.feed(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::ReplayFilter;
+
+    #[test]
+    fn replayed_keys() {
+        let filter = ReplayFilter::new(Duration::from_millis(100));
+
+        assert!(!filter.seen(&[1; 32]));
+        assert!(filter.seen(&[1; 32]));
+        assert!(!filter.seen(&[2; 32]));
+
+        // Keys are remembered for at least one period
+        thread::sleep(Duration::from_millis(150));
+        assert!(filter.seen(&[1; 32]));
+
+        // and forgotten after two
+        thread::sleep(Duration::from_millis(100));
+        assert!(!filter.seen(&[2; 32]));
+    }
+}
@@ -0,0 +1,138 @@
+//! Per-peer reachability feedback for transport bids.
+//!
+//! A transport may bid for a peer that it can't actually reach, for example
+//! if the peer publishes an address that is firewalled. When a transport fails
+//! to connect or send to a peer, that transport's bids for the peer are raised
+//! for a while, so that any other transport that can reach the peer is tried
+//! instead.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::data::Hash;
+
+/// Added to a transport's bid for a peer that it recently failed to reach.
+/// Higher than any bid for a new session, so that other transports win.
+pub(super) const FAILED_BID_PENALTY: u32 = 100;
+
+/// Number of seconds that a failure counts against a transport.
+pub(super) const DEFAULT_PENALTY_DURATION: u64 = 5 * 60;
+
+/// Maximum number of failures that we remember. Once reached, the oldest
+/// failure is forgotten to make room for a new one.
+const MAX_FAILURES: usize = 10_000;
+
+#[derive(Clone)]
+pub(super) struct BidPenalties {
+    duration: Duration,
+    max_failures: usize,
+    /// Keyed by the peer, and the index of the transport in the manager.
+    failures: Arc<Mutex<HashMap<(Hash, usize), Instant>>>,
+}
+
+impl BidPenalties {
+    pub(super) fn new(duration: Duration) -> Self {
+        BidPenalties {
+            duration,
+            max_failures: MAX_FAILURES,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the penalty to add to the transport's bid for the peer.
+    pub(super) fn penalty(&self, peer: &Hash, transport: usize) -> u32 {
+        let mut failures = self.failures.lock().unwrap();
+        let key = (peer.clone(), transport);
+        match failures.get(&key) {
+            Some(failed) if failed.elapsed() < self.duration => FAILED_BID_PENALTY,
+            Some(_) => {
+                failures.remove(&key);
+                0
+            }
+            None => 0,
+        }
+    }
+
+    /// Records whether the transport reached the peer.
+    pub(super) fn record(&self, peer: Hash, transport: usize, success: bool) {
+        let mut failures = self.failures.lock().unwrap();
+        if success {
+            failures.remove(&(peer, transport));
+        } else {
+            debug!("Transport {} failed to reach {}, penalising", transport, peer);
+            let key = (peer, transport);
+            if failures.len() >= self.max_failures && !failures.contains_key(&key) {
+                let duration = self.duration;
+                failures.retain(|_, failed| failed.elapsed() < duration);
+                if failures.len() >= self.max_failures {
+                    let oldest = failures
+                        .iter()
+                        .min_by_key(|(_, failed)| **failed)
+                        .map(|(key, _)| key.clone())
+                        .expect("Failures are not empty");
+                    failures.remove(&oldest);
+                }
+            }
+            failures.insert(key, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{BidPenalties, FAILED_BID_PENALTY};
+    use crate::data::RouterSecretKeys;
+
+    #[test]
+    fn penalise_failed_transport() {
+        let penalties = BidPenalties::new(Duration::from_millis(100));
+        let peer = RouterSecretKeys::new().rid.hash();
+        let other = RouterSecretKeys::new().rid.hash();
+
+        assert_eq!(penalties.penalty(&peer, 0), 0);
+        penalties.record(peer.clone(), 0, false);
+        assert_eq!(penalties.penalty(&peer, 0), FAILED_BID_PENALTY);
+
+        // Only that transport and peer are penalised
+        assert_eq!(penalties.penalty(&peer, 1), 0);
+        assert_eq!(penalties.penalty(&other, 0), 0);
+
+        // A success clears the penalty
+        penalties.record(peer.clone(), 0, true);
+        assert_eq!(penalties.penalty(&peer, 0), 0);
+
+        // The penalty expires
+        penalties.record(peer.clone(), 0, false);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(penalties.penalty(&peer, 0), 0);
+    }
+
+    #[test]
+    fn bounded_failures() {
+        let penalties = BidPenalties {
+            max_failures: 2,
+            ..BidPenalties::new(Duration::from_millis(100))
+        };
+        let peers: Vec<_> = (0..3).map(|_| RouterSecretKeys::new().rid.hash()).collect();
+
+        penalties.record(peers[0].clone(), 0, false);
+        thread::sleep(Duration::from_millis(10));
+        penalties.record(peers[1].clone(), 0, false);
+        penalties.record(peers[2].clone(), 0, false);
+
+        // The oldest failure is forgotten
+        assert_eq!(penalties.failures.lock().unwrap().len(), 2);
+        assert_eq!(penalties.penalty(&peers[0], 0), 0);
+        assert_eq!(penalties.penalty(&peers[1], 0), FAILED_BID_PENALTY);
+        assert_eq!(penalties.penalty(&peers[2], 0), FAILED_BID_PENALTY);
+
+        // Expired failures are pruned first
+        thread::sleep(Duration::from_millis(150));
+        penalties.record(peers[0].clone(), 1, false);
+        assert_eq!(penalties.failures.lock().unwrap().len(), 1);
+    }
+}
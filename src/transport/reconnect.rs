@@ -0,0 +1,101 @@
+//! Limits on how often a peer may reconnect to us.
+//!
+//! A peer that repeatedly opens and drops sessions makes us redo the expensive
+//! parts of the handshake each time. Once we learn who a peer is, we refuse
+//! its handshake if it has already completed too many of them recently.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::data::Hash;
+
+/// Default number of handshakes a peer may complete within the window.
+const DEFAULT_MAX_RECONNECTS: usize = 10;
+
+/// Default length of the window over which handshakes are counted.
+pub(super) const DEFAULT_RECONNECT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Counts recent inbound handshakes from each peer.
+#[derive(Debug)]
+pub struct ReconnectLimiter {
+    max: usize,
+    window: Duration,
+    recent: Mutex<HashMap<Hash, VecDeque<Instant>>>,
+}
+
+impl Default for ReconnectLimiter {
+    fn default() -> Self {
+        ReconnectLimiter::new(DEFAULT_MAX_RECONNECTS, DEFAULT_RECONNECT_WINDOW)
+    }
+}
+
+impl PartialEq for ReconnectLimiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.max == other.max && self.window == other.window
+    }
+}
+
+impl ReconnectLimiter {
+    /// Allows each peer at most `max` handshakes within any `window`.
+    pub fn new(max: usize, window: Duration) -> Self {
+        ReconnectLimiter {
+            max,
+            window,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a handshake from the given peer.
+    ///
+    /// Returns false, without recording it, if the peer has already completed
+    /// the maximum number of handshakes within the window.
+    pub fn permits(&self, peer: &Hash) -> bool {
+        let window = self.window;
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, times| {
+            while let Some(t) = times.front() {
+                if t.elapsed() < window {
+                    break;
+                }
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = recent.entry(peer.clone()).or_default();
+        if times.len() >= self.max {
+            return false;
+        }
+        times.push_back(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::ReconnectLimiter;
+    use crate::data::Hash;
+
+    #[test]
+    fn permits() {
+        let a = Hash([1; 32]);
+        let b = Hash([2; 32]);
+        let limiter = ReconnectLimiter::new(2, Duration::from_millis(50));
+
+        assert!(limiter.permits(&a));
+        assert!(limiter.permits(&a));
+        assert!(!limiter.permits(&a));
+
+        // Other peers are counted separately
+        assert!(limiter.permits(&b));
+
+        // Handshakes older than the window are forgotten
+        thread::sleep(Duration::from_millis(60));
+        assert!(limiter.permits(&a));
+        assert_eq!(limiter.recent.lock().unwrap().len(), 1);
+    }
+}
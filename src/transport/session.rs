@@ -1,14 +1,114 @@
 //! Common structures for managing active sessions over individual transports.
 
-use futures::{sync::mpsc, Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::{
+    future::{self, Either},
+    sync::mpsc,
+    Async, AsyncSink, Future, Poll, StartSend, Stream,
+};
 use std::collections::HashMap;
 use std::fmt;
 use std::io;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use super::bandwidth::BandwidthLimiter;
+use super::stats::TrafficStats;
 use crate::data::Hash;
+use crate::i2np::{Message, MessagePayload};
 use crate::router::types::Distributor;
 
+/// Which side of a session opened the connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// The peer connected to us.
+    Inbound,
+    /// We connected to the peer.
+    Outbound,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Inbound => "inbound".fmt(f),
+            Direction::Outbound => "outbound".fmt(f),
+        }
+    }
+}
+
+/// What to do when a peer sends us an I2NP message type we don't know.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownMessagePolicy {
+    /// Drop the message silently.
+    Ignore,
+    /// Drop the message, and log it.
+    Log,
+    /// Close the session with the peer.
+    Disconnect,
+}
+
+impl Default for UnknownMessagePolicy {
+    fn default() -> Self {
+        UnknownMessagePolicy::Log
+    }
+}
+
+impl FromStr for UnknownMessagePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(UnknownMessagePolicy::Ignore),
+            "log" => Ok(UnknownMessagePolicy::Log),
+            "disconnect" => Ok(UnknownMessagePolicy::Disconnect),
+            _ => Err(format!("Unknown policy for unknown messages: {}", s)),
+        }
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for UnknownMessagePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnknownMessagePolicy::Ignore => "ignore".fmt(f),
+            UnknownMessagePolicy::Log => "log".fmt(f),
+            UnknownMessagePolicy::Disconnect => "disconnect".fmt(f),
+        }
+    }
+}
+
+impl UnknownMessagePolicy {
+    /// Applies the policy to a message received from `peer`.
+    ///
+    /// Returns the message if it should be distributed, or an error if the
+    /// session should be closed.
+    pub(super) fn filter(self, peer: &Hash, msg: Message) -> io::Result<Option<Message>> {
+        let msg_type = match msg.payload {
+            MessagePayload::Unknown(msg_type, _) => msg_type,
+            _ => return Ok(Some(msg)),
+        };
+
+        match self {
+            UnknownMessagePolicy::Ignore => {
+                trace!("Dropping unknown message type {} from {}", msg_type, peer);
+                Ok(None)
+            }
+            UnknownMessagePolicy::Log => {
+                warn!("Dropping unknown message type {} from {}", msg_type, peer);
+                Ok(None)
+            }
+            UnknownMessagePolicy::Disconnect => {
+                warn!("Peer {} sent unknown message type {}", peer, msg_type);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown message type {}", msg_type),
+                ))
+            }
+        }
+    }
+}
+
 //
 // Session state
 //
@@ -19,16 +119,70 @@ type SessionTx<Frame> = mpsc::UnboundedSender<Frame>;
 /// Shorthand for the receive half of a Session-bound message channel.
 pub(super) type SessionRx<Frame> = mpsc::UnboundedReceiver<Frame>;
 
+/// Called with the outcome of every connection we try to open to a peer: true
+/// once a session is established, false if connecting fails.
+pub type DialObserver = Arc<dyn Fn(&Hash, bool) + Send + Sync>;
+
+/// Sessions established in opposite directions within this long of each other
+/// are assumed to be from the peer and us connecting at the same time.
+const SIMULTANEOUS_CONNECT_WINDOW: Duration = Duration::from_secs(10);
+
+struct Entry<F> {
+    /// Each session has an ID, so that a session that has been replaced by a
+    /// newer one with the same peer doesn't remove it when it ends.
+    id: u64,
+    direction: Direction,
+    established: Instant,
+    tx: SessionTx<F>,
+}
+
+/// Returns true if a session that has just been established with `peer` should
+/// be closed in favour of the one we already have.
+///
+/// If we and the peer connect to each other at the same time, each of us ends
+/// up with two sessions. We both keep the one opened by the router with the
+/// lower hash, so that we don't each close a different one. Otherwise, a newer
+/// session replaces an older one.
+fn keep_existing<F>(
+    own_hash: Option<&Hash>,
+    peer: &Hash,
+    existing: &Entry<F>,
+    new: Direction,
+) -> bool {
+    let own_hash = match own_hash {
+        Some(own_hash) => own_hash,
+        None => return false,
+    };
+    if existing.direction == new || existing.established.elapsed() > SIMULTANEOUS_CONNECT_WINDOW {
+        return false;
+    }
+    match existing.direction {
+        Direction::Outbound => own_hash < peer,
+        Direction::Inbound => peer < own_hash,
+    }
+}
+
 struct Shared<F> {
-    sessions: HashMap<Hash, SessionTx<F>>,
+    sessions: HashMap<Hash, Entry<F>>,
+    next_id: u64,
     pending_sessions: HashMap<Hash, Vec<F>>,
+    /// Our router's hash, used to break ties between simultaneous sessions.
+    own_hash: Option<Hash>,
+    dial_observer: Option<DialObserver>,
+    /// Cloned into every session, so that we can tell when they have all ended.
+    /// Dropped once we start closing sessions.
+    open: Option<mpsc::UnboundedSender<()>>,
 }
 
 impl<F> Shared<F> {
-    fn new() -> Self {
+    fn new(open: mpsc::UnboundedSender<()>) -> Self {
         Shared {
             sessions: HashMap::new(),
+            next_id: 0,
             pending_sessions: HashMap::new(),
+            own_hash: None,
+            dial_observer: None,
+            open: Some(open),
         }
     }
 }
@@ -58,8 +212,8 @@ impl<F> SessionState<F> {
         let mut s = self.0.lock().unwrap();
 
         // If we have an established session, use it.
-        if let Some(mut session) = s.sessions.get(hash) {
-            session.start_send(frame)
+        if let Some(session) = s.sessions.get(hash) {
+            session.tx.unbounded_send(frame).map(|()| AsyncSink::Ready)
         } else {
             // Cache the frame for sending once we have a session.
             s.pending_sessions
@@ -74,50 +228,124 @@ impl<F> SessionState<F> {
         }
     }
 
-    fn new() -> Self {
-        SessionState(Arc::new(Mutex::new(Shared::new())))
+    /// Returns true if we are waiting for a session with the peer to be
+    /// established.
+    fn is_pending(&self, hash: &Hash) -> bool {
+        self.0.lock().unwrap().pending_sessions.contains_key(hash)
+    }
+
+    /// Drops the frames waiting for a session with the peer after we failed to
+    /// connect to it, so that the next frame for the peer tries again.
+    pub(super) fn connect_failed(&self, hash: &Hash) {
+        let mut s = self.0.lock().unwrap();
+        if let Some(frames) = s.pending_sessions.remove(hash) {
+            debug!("Dropping {} frames for unreachable peer {}", frames.len(), hash);
+        }
+        if let Some(observer) = s.dial_observer.as_ref() {
+            observer(hash, false);
+        }
+    }
+
+    /// Reports that the peer ended a session because it refuses to talk to us.
+    /// This is treated like a failed connection, so that we back off instead
+    /// of reconnecting straight away.
+    pub(super) fn rejected_by_peer(&self, hash: &Hash) {
+        if let Some(observer) = self.0.lock().unwrap().dial_observer.as_ref() {
+            observer(hash, false);
+        }
+    }
+
+    fn new(open: mpsc::UnboundedSender<()>) -> Self {
+        SessionState(Arc::new(Mutex::new(Shared::new(open))))
     }
 }
 
 pub(super) struct SessionContext<F> {
     pub hash: Hash,
+    id: u64,
     state: SessionState<F>,
+    _open: Option<mpsc::UnboundedSender<()>>,
 }
 
 impl<F: fmt::Debug> SessionContext<F> {
-    pub(super) fn new(hash: Hash, state: SessionState<F>, tx: SessionTx<F>) -> Self {
-        info!("Session established with {}", hash);
+    pub(super) fn new(
+        hash: Hash,
+        direction: Direction,
+        state: SessionState<F>,
+        tx: SessionTx<F>,
+    ) -> Self {
+        info!("Session established with {} ({})", hash, direction);
 
-        {
+        let (id, open) = {
             let mut s = state.0.lock().unwrap();
+            let id = s.next_id;
+            s.next_id += 1;
 
-            // If there were any pending messages waiting for the session to
-            // open, queue them now for sending.
-            if let Some(msgs) = s.pending_sessions.remove(&hash) {
-                for msg in msgs {
-                    debug!("Sending pending message: {:?}", msg);
-                    tx.unbounded_send(msg).unwrap();
+            if direction == Direction::Outbound {
+                if let Some(observer) = s.dial_observer.as_ref() {
+                    observer(&hash, true);
                 }
             }
 
-            // Store the session for future messages
-            s.sessions.insert(hash.clone(), tx);
-        }
+            let keep = match s.sessions.get(&hash) {
+                Some(existing) => keep_existing(s.own_hash.as_ref(), &hash, existing, direction),
+                None => false,
+            };
+            if keep {
+                // The new session closes once its channel is dropped
+                debug!("Closing duplicate session with {} ({})", hash, direction);
+            } else {
+                // If there were any pending messages waiting for the session
+                // to open, queue them now for sending.
+                if let Some(msgs) = s.pending_sessions.remove(&hash) {
+                    for msg in msgs {
+                        debug!("Sending pending message: {:?}", msg);
+                        tx.unbounded_send(msg).unwrap();
+                    }
+                }
+
+                // Store the session for future messages. If we already had a
+                // session with this peer, it closes once its channel is
+                // dropped.
+                s.sessions.insert(
+                    hash.clone(),
+                    Entry {
+                        id,
+                        direction,
+                        established: Instant::now(),
+                        tx,
+                    },
+                );
+            }
 
-        SessionContext { hash, state }
+            (id, s.open.clone())
+        };
+
+        SessionContext {
+            hash,
+            id,
+            state,
+            _open: open,
+        }
     }
 }
 
 impl<F> Drop for SessionContext<F> {
     fn drop(&mut self) {
         info!("Session ended with {}", self.hash);
-        self.state.0.lock().unwrap().sessions.remove(&self.hash);
+        let mut s = self.state.0.lock().unwrap();
+        if s.sessions.get(&self.hash).map(|session| session.id) == Some(self.id) {
+            s.sessions.remove(&self.hash);
+        }
     }
 }
 
 pub(super) struct SessionRefs<F, D: Distributor> {
     pub(super) state: SessionState<F>,
     pub(super) distributor: D,
+    pub(super) unknown_messages: UnknownMessagePolicy,
+    pub(super) traffic: TrafficStats,
+    pub(super) bandwidth: BandwidthLimiter,
 }
 
 impl<F, D: Distributor> Clone for SessionRefs<F, D> {
@@ -125,6 +353,9 @@ impl<F, D: Distributor> Clone for SessionRefs<F, D> {
         SessionRefs {
             state: self.state.clone(),
             distributor: self.distributor.clone(),
+            unknown_messages: self.unknown_messages,
+            traffic: self.traffic.clone(),
+            bandwidth: self.bandwidth.clone(),
         }
     }
 }
@@ -134,10 +365,7 @@ impl<F, D: Distributor> Stream for SessionRefs<F, D> {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        Ok(Async::Ready(Some(SessionRefs {
-            state: self.state.clone(),
-            distributor: self.distributor.clone(),
-        })))
+        Ok(Async::Ready(Some(self.clone())))
     }
 }
 
@@ -148,12 +376,21 @@ impl<F, D: Distributor> Stream for SessionRefs<F, D> {
 pub(super) struct SessionManager<F, D: Distributor> {
     state: SessionState<F>,
     distributor: D,
+    unknown_messages: UnknownMessagePolicy,
+    traffic: TrafficStats,
+    bandwidth: BandwidthLimiter,
+    ended: Option<mpsc::UnboundedReceiver<()>>,
 }
 
 pub(super) fn new_manager<F, D: Distributor>(distributor: D) -> SessionManager<F, D> {
+    let (open, ended) = mpsc::unbounded();
     SessionManager {
-        state: SessionState::new(),
+        state: SessionState::new(open),
         distributor,
+        unknown_messages: UnknownMessagePolicy::default(),
+        traffic: TrafficStats::default(),
+        bandwidth: BandwidthLimiter::default(),
+        ended: Some(ended),
     }
 }
 
@@ -162,10 +399,169 @@ impl<F, D: Distributor> SessionManager<F, D> {
         SessionRefs {
             state: self.state.clone(),
             distributor: self.distributor.clone(),
+            unknown_messages: self.unknown_messages,
+            traffic: self.traffic.clone(),
+            bandwidth: self.bandwidth.clone(),
         }
     }
 
+    /// Sets what sessions opened from now on do with unknown message types.
+    pub(super) fn set_unknown_message_policy(&mut self, policy: UnknownMessagePolicy) {
+        self.unknown_messages = policy;
+    }
+
+    /// Sets where sessions opened from now on record their traffic.
+    pub(super) fn set_traffic_stats(&mut self, traffic: TrafficStats) {
+        self.traffic = traffic;
+    }
+
+    /// Sets the bandwidth that sessions opened from now on share.
+    pub(super) fn set_bandwidth_limiter(&mut self, bandwidth: BandwidthLimiter) {
+        self.bandwidth = bandwidth;
+    }
+
     pub fn have_session(&self, hash: &Hash) -> bool {
         self.state.contains(hash)
     }
+
+    /// Returns true if we are connecting to the peer.
+    pub(super) fn have_pending_session(&self, hash: &Hash) -> bool {
+        self.state.is_pending(hash)
+    }
+
+    /// Sets our router's hash, which is used to decide which session to keep
+    /// if we and a peer connect to each other at the same time.
+    pub(super) fn set_own_hash(&self, hash: Hash) {
+        self.state.0.lock().unwrap().own_hash = Some(hash);
+    }
+
+    /// Sets the observer that is told whether our connections to peers succeed.
+    pub(super) fn set_dial_observer(&self, observer: DialObserver) {
+        self.state.0.lock().unwrap().dial_observer = Some(observer);
+    }
+
+    /// Returns the peers we have open sessions with.
+    pub(super) fn sessions(&self) -> Vec<(Hash, Direction)> {
+        let s = self.state.0.lock().unwrap();
+        s.sessions
+            .iter()
+            .map(|(hash, session)| (hash.clone(), session.direction))
+            .collect()
+    }
+
+    /// Queues a frame for every open session, such as a notice that the
+    /// session is about to be closed.
+    pub(super) fn send_all<G: Fn() -> F>(&self, frame: G) {
+        let s = self.state.0.lock().unwrap();
+        for session in s.sessions.values() {
+            // A session whose channel has closed is already ending
+            let _ = session.tx.unbounded_send(frame());
+        }
+    }
+
+    /// Asks every open session to close once it has sent its queued frames,
+    /// and drops any frames waiting for a session to be established.
+    ///
+    /// Returns a future that resolves once all sessions have ended, or
+    /// immediately if sessions were already closed.
+    pub(super) fn close_all(&mut self) -> impl Future<Item = (), Error = ()> {
+        {
+            let mut s = self.state.0.lock().unwrap();
+            info!("Closing {} sessions", s.sessions.len());
+            // Sessions end once their outbound channel is closed.
+            s.sessions.clear();
+            s.pending_sessions.clear();
+            s.open = None;
+        }
+
+        match self.ended.take() {
+            Some(ended) => Either::A(ended.for_each(|_| Ok(()))),
+            None => Either::B(future::ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Stream};
+    use std::sync::{Arc, Mutex};
+
+    use super::{new_manager, Direction, SessionContext};
+    use crate::data::Hash;
+    use crate::router::mock::MockDistributor;
+
+    #[test]
+    fn simultaneous_sessions() {
+        let low = Hash([1; 32]);
+        let high = Hash([2; 32]);
+
+        for &(own, peer, kept) in &[
+            (&low, &high, Direction::Outbound),
+            (&high, &low, Direction::Inbound),
+        ] {
+            let manager = new_manager::<u32, _>(MockDistributor::new());
+            manager.set_own_hash(own.clone());
+
+            let (ob_tx, ob_rx) = mpsc::unbounded();
+            let _ob = SessionContext::new(
+                peer.clone(),
+                Direction::Outbound,
+                manager.state.clone(),
+                ob_tx,
+            );
+            let (ib_tx, ib_rx) = mpsc::unbounded();
+            let _ib = SessionContext::new(
+                peer.clone(),
+                Direction::Inbound,
+                manager.state.clone(),
+                ib_tx,
+            );
+
+            // Both routers keep the session opened by the one with the lower
+            // hash, and the other session's channel is closed
+            assert_eq!(manager.sessions(), vec![(peer.clone(), kept)]);
+            manager.state.send(peer, 7, || panic!()).unwrap();
+            let (kept_rx, closed_rx) = match kept {
+                Direction::Outbound => (ob_rx, ib_rx),
+                Direction::Inbound => (ib_rx, ob_rx),
+            };
+            assert_eq!(kept_rx.wait().next(), Some(Ok(7)));
+            assert_eq!(closed_rx.wait().next(), None);
+        }
+    }
+
+    #[test]
+    fn failed_connection() {
+        let manager = new_manager::<u32, _>(MockDistributor::new());
+        let peer = Hash([1; 32]);
+        let mut dials = 0;
+        let outcomes = Arc::new(Mutex::new(vec![]));
+        {
+            let outcomes = outcomes.clone();
+            manager.set_dial_observer(Arc::new(move |hash: &Hash, success| {
+                outcomes.lock().unwrap().push((hash.clone(), success))
+            }));
+        }
+
+        // Only one connection is attempted at a time
+        manager.state.send(&peer, 1, || dials += 1).unwrap();
+        manager.state.send(&peer, 2, || dials += 1).unwrap();
+        assert_eq!(dials, 1);
+        assert!(manager.have_pending_session(&peer));
+
+        // Once it fails, the next frame tries again
+        manager.state.connect_failed(&peer);
+        assert!(!manager.have_pending_session(&peer));
+        manager.state.send(&peer, 3, || dials += 1).unwrap();
+        assert_eq!(dials, 2);
+
+        // Both outcomes are reported
+        let (tx, _rx) = mpsc::unbounded();
+        let _session =
+            SessionContext::new(peer.clone(), Direction::Outbound, manager.state.clone(), tx);
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            vec![(peer.clone(), false), (peer, true)]
+        );
+    }
 }
@@ -0,0 +1,202 @@
+//! Outbound connections through a SOCKS5 proxy.
+//!
+//! Only the parts of [RFC 1928] needed to open a TCP connection to an IP
+//! address are implemented: we offer no authentication, and send a CONNECT
+//! request. This is enough to run behind Tor, or a local proxy that forwards
+//! to an authenticating one.
+//!
+//! [RFC 1928]: https://tools.ietf.org/html/rfc1928
+
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+const SOCKS_VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+macro_rules! io_err {
+    ($err_kind:ident, $err_msg:expr) => {
+        Err(io::Error::new(io::ErrorKind::$err_kind, $err_msg))
+    };
+}
+
+/// Converts an unsuccessful reply code into an error.
+fn reply_error(code: u8) -> io::Error {
+    let (kind, reason) = match code {
+        0x01 => (io::ErrorKind::Other, "general SOCKS server failure"),
+        0x02 => (io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+        0x03 => (io::ErrorKind::Other, "network unreachable"),
+        0x04 => (io::ErrorKind::Other, "host unreachable"),
+        0x05 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (io::ErrorKind::TimedOut, "TTL expired"),
+        0x07 => (io::ErrorKind::InvalidInput, "command not supported"),
+        0x08 => (io::ErrorKind::InvalidInput, "address type not supported"),
+        _ => (io::ErrorKind::Other, "unknown error"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy: {}", reason))
+}
+
+/// Builds a CONNECT request for the given address.
+fn connect_request(target: &SocketAddr) -> Vec<u8> {
+    let mut buf = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    match target {
+        SocketAddr::V4(addr) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&target.port().to_be_bytes());
+    buf
+}
+
+/// Asks the proxy at the other end of `conn` to connect us to `target`.
+///
+/// Returns the connection once the proxy has connected, after which it carries
+/// data to and from the target.
+fn handshake<T>(conn: T, target: SocketAddr) -> impl Future<Item = T, Error = io::Error>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    io::write_all(conn, [SOCKS_VERSION, 1, METHOD_NO_AUTH])
+        .and_then(|(conn, _)| io::read_exact(conn, [0u8; 2]))
+        .and_then(|(conn, reply)| match reply {
+            [SOCKS_VERSION, METHOD_NO_AUTH] => Ok(conn),
+            [SOCKS_VERSION, METHOD_NONE_ACCEPTABLE] => io_err!(
+                PermissionDenied,
+                "SOCKS5 proxy requires authentication"
+            ),
+            _ => io_err!(InvalidData, "Invalid SOCKS5 method selection"),
+        })
+        .and_then(move |conn| io::write_all(conn, connect_request(&target)))
+        .and_then(|(conn, _)| io::read_exact(conn, [0u8; 4]))
+        .and_then(|(conn, reply)| {
+            if reply[0] != SOCKS_VERSION {
+                return io_err!(InvalidData, "Invalid SOCKS5 reply");
+            }
+            if reply[1] != REPLY_SUCCEEDED {
+                return Err(reply_error(reply[1]));
+            }
+            match reply[3] {
+                ATYP_IPV4 => Ok((conn, Some(4))),
+                ATYP_IPV6 => Ok((conn, Some(16))),
+                ATYP_DOMAIN => Ok((conn, None)),
+                _ => io_err!(InvalidData, "Invalid SOCKS5 address type"),
+            }
+        })
+        .and_then(|(conn, len)| match len {
+            Some(len) => Either::A(future::ok((conn, len))),
+            // The domain name is prefixed with its length
+            None => Either::B(
+                io::read_exact(conn, [0u8; 1]).map(|(conn, len)| (conn, usize::from(len[0]))),
+            ),
+        })
+        // Skip the address that the proxy bound to, and its port
+        .and_then(|(conn, len)| io::read_exact(conn, vec![0u8; len + 2]))
+        .map(|(conn, _)| conn)
+}
+
+/// Connects to `target` through the SOCKS5 proxy at `proxy`.
+pub(super) fn connect(
+    proxy: SocketAddr,
+    target: SocketAddr,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    debug!("Connecting to {} through SOCKS5 proxy {}", target, proxy);
+    TcpStream::connect(&proxy).and_then(move |conn| handshake(conn, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use tokio::{io, runtime::current_thread::Runtime};
+
+    use super::{connect, connect_request};
+
+    /// Runs a proxy that accepts a single connection, checks that it was asked
+    /// to connect to `target`, and replies with `reply_code`. On success, it
+    /// then echoes everything it receives.
+    fn mock_proxy(target: SocketAddr, reply_code: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+
+            let mut greeting = [0u8; 3];
+            conn.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            conn.write_all(&[0x05, 0x00]).unwrap();
+
+            let expected = connect_request(&target);
+            let mut request = vec![0u8; expected.len()];
+            conn.read_exact(&mut request).unwrap();
+            assert_eq!(request, expected);
+            conn.write_all(&[0x05, reply_code, 0x00, 0x01, 127, 0, 0, 1, 0x30, 0x39]).unwrap();
+
+            let mut buf = [0u8; 5];
+            if reply_code == 0 && conn.read_exact(&mut buf).is_ok() {
+                conn.write_all(&buf).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn connect_request_format() {
+        assert_eq!(
+            connect_request(&"1.2.3.4:12345".parse().unwrap()),
+            vec![0x05, 0x01, 0x00, 0x01, 1, 2, 3, 4, 0x30, 0x39]
+        );
+
+        let request = connect_request(&"[::1]:80".parse().unwrap());
+        assert_eq!(request.len(), 4 + 16 + 2);
+        assert_eq!(&request[..4], &[0x05, 0x01, 0x00, 0x04]);
+        assert_eq!(request[19], 1);
+        assert_eq!(&request[20..], &[0, 80]);
+    }
+
+    #[test]
+    fn connect_through_proxy() {
+        let target = "10.1.2.3:4567".parse().unwrap();
+        let proxy = mock_proxy(target, 0x00);
+
+        let mut rt = Runtime::new().unwrap();
+        let conn = rt.block_on(connect(proxy, target)).unwrap();
+        let (conn, _) = rt.block_on(io::write_all(conn, b"hello")).unwrap();
+        let (_, echoed) = rt.block_on(io::read_exact(conn, [0u8; 5])).unwrap();
+        assert_eq!(&echoed, b"hello");
+    }
+
+    #[test]
+    fn proxy_refuses_connection() {
+        let target = "10.1.2.3:4567".parse().unwrap();
+        let proxy = mock_proxy(target, 0x05);
+
+        let mut rt = Runtime::new().unwrap();
+        match rt.block_on(connect(proxy, target).map(|_| ())) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            Ok(_) => panic!("Proxy should have refused the connection"),
+        }
+    }
+}
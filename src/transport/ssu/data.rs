@@ -0,0 +1,622 @@
+//! Fragmentation, acknowledgement and retransmission of I2NP messages.
+//!
+//! Messages are split into fragments, which are retransmitted with exponential
+//! backoff until the receiver acknowledges them. The receiver acknowledges a
+//! message by its ID once it has every fragment, and may report the fragments
+//! of incomplete messages that it has received, so that only the missing ones
+//! are retransmitted.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::packet::{Data, DataFragment, PartialAck};
+use crate::i2np::{
+    frame::{gen_ssu_message, ssu_message},
+    Message,
+};
+use crate::util::serialize;
+
+/// Maximum number of bytes of a message that are sent in a single fragment.
+///
+/// This leaves room in each packet for the maximum number of acknowledgements.
+pub(super) const MAX_FRAGMENT_SIZE: usize = 1024;
+
+/// Fragments are numbered with seven bits.
+const MAX_FRAGMENTS: usize = 128;
+
+/// How long we wait for a message to be acknowledged before first resending
+/// the fragments that haven't been. The wait doubles with each resend.
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// How many times we send a message before giving up on it.
+const MAX_SENDS: u32 = 5;
+
+/// How long we keep the fragments of an incomplete message.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most fragment data of incomplete messages that we buffer by default.
+const DEFAULT_MAX_BUFFERED: usize = 256 * 1024;
+
+/// The most incomplete messages that we buffer fragments of by default.
+const DEFAULT_MAX_PARTIAL: usize = 64;
+
+/// Number of completed messages we remember, so that retransmissions of them
+/// can be acknowledged again instead of being delivered twice.
+const RECENT_MESSAGES: usize = 256;
+
+/// Maximum number of acknowledgements of each kind that we send in a packet.
+const MAX_ACKS: usize = 32;
+const MAX_PARTIAL_ACKS: usize = 8;
+
+/// Splits a message into fragments carrying at most `max_len` bytes of it.
+///
+/// Returns `None` if the message would need more fragments than SSU allows.
+pub fn fragment(msg: &Message, max_len: usize) -> Option<Vec<DataFragment>> {
+    let data = serialize(|input| gen_ssu_message(input, msg));
+    let chunks: Vec<_> = data.chunks(max_len).collect();
+    if chunks.len() > MAX_FRAGMENTS {
+        return None;
+    }
+
+    let count = chunks.len();
+    Some(
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(num, chunk)| DataFragment {
+                msg_id: msg.id,
+                num: num as u8,
+                last: num + 1 == count,
+                data: chunk.to_vec(),
+            })
+            .collect(),
+    )
+}
+
+/// Packs acknowledgements and fragments into Data payloads, with one fragment
+/// in each. The acknowledgements are sent in the first payload, which is sent
+/// even if there are no fragments.
+pub fn data_payloads(
+    acks: Vec<u32>,
+    partial_acks: Vec<PartialAck>,
+    fragments: Vec<DataFragment>,
+) -> Vec<Data> {
+    let mut payloads = vec![];
+    let mut acks = Some((acks, partial_acks));
+    for frag in fragments {
+        let (acks, partial_acks) = acks.take().unwrap_or_default();
+        payloads.push(Data {
+            acks,
+            partial_acks,
+            fragments: vec![frag],
+        });
+    }
+    if let Some((acks, partial_acks)) = acks {
+        if !acks.is_empty() || !partial_acks.is_empty() {
+            payloads.push(Data {
+                acks,
+                partial_acks,
+                fragments: vec![],
+            });
+        }
+    }
+    payloads
+}
+
+struct OutboundMessage {
+    msg_id: u32,
+    /// The fragments that have not been acknowledged.
+    fragments: BTreeMap<u8, DataFragment>,
+    sends: u32,
+    /// When the unacknowledged fragments are next due to be sent, or `None`
+    /// if they have not been sent yet.
+    next_send: Option<Instant>,
+}
+
+/// The messages we have sent, until they are acknowledged.
+#[derive(Default)]
+pub struct OutboundMessages {
+    messages: VecDeque<OutboundMessage>,
+}
+
+impl OutboundMessages {
+    pub fn new() -> Self {
+        OutboundMessages::default()
+    }
+
+    /// Queues a message to be sent. Returns `false` if the message is too
+    /// large to be sent.
+    pub fn push(&mut self, msg: &Message) -> bool {
+        match fragment(msg, MAX_FRAGMENT_SIZE) {
+            Some(fragments) => {
+                self.messages.push_back(OutboundMessage {
+                    msg_id: msg.id,
+                    fragments: fragments.into_iter().map(|f| (f.num, f)).collect(),
+                    sends: 0,
+                    next_send: None,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles the acknowledgements sent by the peer, returning the number of
+    /// messages that are now completely acknowledged.
+    pub fn ack(&mut self, data: &Data) -> usize {
+        let before = self.messages.len();
+        self.messages.retain(|m| !data.acks.contains(&m.msg_id));
+        let acked = before - self.messages.len();
+
+        for ack in &data.partial_acks {
+            if let Some(m) = self.messages.iter_mut().find(|m| m.msg_id == ack.msg_id) {
+                for (num, _) in ack.received.iter().enumerate().filter(|(_, r)| **r) {
+                    m.fragments.remove(&(num as u8));
+                }
+            }
+        }
+        acked
+    }
+
+    /// Returns the fragments that are due to be sent, and the IDs of the
+    /// messages that have been sent too many times without being acknowledged,
+    /// which are dropped.
+    pub fn poll_due(&mut self, now: Instant) -> (Vec<DataFragment>, Vec<u32>) {
+        let mut due = vec![];
+        let mut failed = vec![];
+        self.messages.retain(|m| {
+            if m.fragments.is_empty() {
+                // Every fragment was partially acknowledged
+                return false;
+            }
+            match m.next_send {
+                Some(next) if next > now => true,
+                _ if m.sends >= MAX_SENDS => {
+                    failed.push(m.msg_id);
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        for m in &mut self.messages {
+            if m.next_send.map(|next| next <= now).unwrap_or(true) {
+                due.extend(m.fragments.values().cloned());
+                m.next_send = Some(now + INITIAL_RTO * 2u32.pow(m.sends));
+                m.sends += 1;
+            }
+        }
+        (due, failed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+struct PartialMessage {
+    fragments: BTreeMap<u8, Vec<u8>>,
+    last: Option<u8>,
+    started: Instant,
+    /// Total length of the buffered fragments.
+    len: usize,
+}
+
+impl PartialMessage {
+    fn is_complete(&self) -> bool {
+        match self.last {
+            Some(last) => (0..=last).all(|num| self.fragments.contains_key(&num)),
+            None => false,
+        }
+    }
+
+    fn received(&self) -> Vec<bool> {
+        let highest = self.fragments.keys().next_back().cloned().unwrap_or(0);
+        (0..=highest)
+            .map(|num| self.fragments.contains_key(&num))
+            .collect()
+    }
+}
+
+/// Collects fragments until the messages they belong to are complete, and
+/// tracks what we need to acknowledge.
+///
+/// The fragments of incomplete messages are buffered up to limits on their
+/// total size and on the number of messages. Once either is reached, the
+/// oldest messages are dropped to make room for new fragments.
+pub struct InboundMessages {
+    partial: HashMap<u32, PartialMessage>,
+    buffered: usize,
+    max_buffered: usize,
+    max_partial: usize,
+    /// Incomplete messages that we have received fragments of since we last
+    /// acknowledged them.
+    updated: HashSet<u32>,
+    /// Complete messages that we have not acknowledged yet.
+    acks: VecDeque<u32>,
+    recent: VecDeque<u32>,
+}
+
+impl Default for InboundMessages {
+    fn default() -> Self {
+        InboundMessages::with_limits(DEFAULT_MAX_BUFFERED, DEFAULT_MAX_PARTIAL)
+    }
+}
+
+impl InboundMessages {
+    pub fn new() -> Self {
+        InboundMessages::default()
+    }
+
+    /// Buffers at most `max_buffered` bytes of fragments, belonging to at most
+    /// `max_partial` incomplete messages.
+    pub fn with_limits(max_buffered: usize, max_partial: usize) -> Self {
+        InboundMessages {
+            partial: HashMap::new(),
+            buffered: 0,
+            max_buffered,
+            max_partial,
+            updated: HashSet::new(),
+            acks: VecDeque::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Returns the total size of the fragments of incomplete messages.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    fn remove(&mut self, msg_id: u32) -> Option<PartialMessage> {
+        let partial = self.partial.remove(&msg_id)?;
+        self.buffered -= partial.len;
+        self.updated.remove(&msg_id);
+        Some(partial)
+    }
+
+    /// Makes room for `len` more bytes of fragments of the given message,
+    /// dropping the oldest other messages. Returns false if there can't be
+    /// enough room.
+    fn make_room(&mut self, msg_id: u32, len: usize) -> bool {
+        if len > self.max_buffered {
+            return false;
+        }
+        let new = !self.partial.contains_key(&msg_id);
+        while self.buffered + len > self.max_buffered
+            || (new && self.partial.len() >= self.max_partial)
+        {
+            let oldest = self
+                .partial
+                .iter()
+                .filter(|(id, _)| **id != msg_id)
+                .min_by_key(|(_, p)| p.started)
+                .map(|(&id, _)| id);
+            match oldest {
+                Some(oldest) => {
+                    debug!("Reassembly buffer is full, dropping message {}", oldest);
+                    self.remove(oldest);
+                }
+                None => return self.buffered + len <= self.max_buffered,
+            }
+        }
+        true
+    }
+
+    /// Adds a received fragment, in any order. Returns the message that the
+    /// fragment belongs to if it is now complete.
+    ///
+    /// Invalid fragments are ignored.
+    pub fn receive(&mut self, frag: DataFragment) -> Option<Message> {
+        if frag.num as usize >= MAX_FRAGMENTS {
+            return None;
+        }
+        if self.recent.contains(&frag.msg_id) {
+            // The peer didn't receive our acknowledgement
+            if !self.acks.contains(&frag.msg_id) {
+                self.acks.push_back(frag.msg_id);
+            }
+            return None;
+        }
+
+        let msg_id = frag.msg_id;
+        if let Some(partial) = self.partial.get(&msg_id) {
+            // Fragments after the last one can't be part of the message
+            if let Some(last) = partial.last {
+                if frag.num > last {
+                    debug!("Dropping fragment {} past the end of message {}", frag.num, msg_id);
+                    return None;
+                }
+            }
+            // and if the peer disagrees with itself about where the message
+            // ends, we can't reassemble it.
+            if frag.last && partial.fragments.keys().any(|&num| num > frag.num) {
+                debug!("Dropping message {} with inconsistent fragments", msg_id);
+                self.remove(msg_id);
+                return None;
+            }
+        }
+
+        let len = frag.data.len();
+        if !self.make_room(msg_id, len) {
+            debug!("Dropping fragment of message {}, too long", msg_id);
+            return None;
+        }
+        let partial = self
+            .partial
+            .entry(msg_id)
+            .or_insert_with(|| PartialMessage {
+                fragments: BTreeMap::new(),
+                last: None,
+                started: Instant::now(),
+                len: 0,
+            });
+        if frag.last {
+            partial.last = Some(frag.num);
+        }
+        let replaced = partial
+            .fragments
+            .insert(frag.num, frag.data)
+            .map_or(0, |d| d.len());
+        partial.len = partial.len + len - replaced;
+        self.buffered = self.buffered + len - replaced;
+        if !partial.is_complete() {
+            self.updated.insert(msg_id);
+            return None;
+        }
+
+        let partial = self.remove(msg_id).unwrap();
+        self.acks.push_back(msg_id);
+        if self.recent.len() == RECENT_MESSAGES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(msg_id);
+
+        let mut data = vec![];
+        for (_, chunk) in partial.fragments {
+            data.extend_from_slice(&chunk);
+        }
+        match ssu_message(&data, msg_id) {
+            Ok((_, msg)) => Some(msg),
+            Err(e) => {
+                debug!("Dropping invalid reassembled message: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Returns whether we have anything to acknowledge.
+    pub fn has_acks(&self) -> bool {
+        !self.acks.is_empty() || !self.updated.is_empty()
+    }
+
+    /// Returns the acknowledgements to send to the peer.
+    pub fn take_acks(&mut self) -> (Vec<u32>, Vec<PartialAck>) {
+        let count = self.acks.len().min(MAX_ACKS);
+        let acks = self.acks.drain(..count).collect();
+
+        let updated: Vec<_> = self.updated.iter().take(MAX_PARTIAL_ACKS).cloned().collect();
+        let partial_acks = updated
+            .into_iter()
+            .filter_map(|msg_id| {
+                self.updated.remove(&msg_id);
+                self.partial.get(&msg_id).map(|partial| PartialAck {
+                    msg_id,
+                    received: partial.received(),
+                })
+            })
+            .collect();
+        (acks, partial_acks)
+    }
+
+    /// Drops incomplete messages that we have been waiting on for too long.
+    pub fn expire(&mut self, now: Instant) {
+        let updated = &mut self.updated;
+        let buffered = &mut self.buffered;
+        self.partial.retain(|msg_id, partial| {
+            let keep = now.saturating_duration_since(partial.started) < REASSEMBLY_TIMEOUT;
+            if !keep {
+                debug!("Dropping incomplete message {}", msg_id);
+                updated.remove(msg_id);
+                *buffered -= partial.len;
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::i2np::MessagePayload;
+
+    #[test]
+    fn lossy_delivery() {
+        let payload: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        let msg = Message::from_payload(MessagePayload::Data(payload.clone()));
+        let mut outbound = OutboundMessages::new();
+        let mut inbound = InboundMessages::new();
+        assert!(outbound.push(&msg));
+
+        // The first fragment is lost, and the rest arrive out of order
+        let start = Instant::now();
+        let (mut fragments, failed) = outbound.poll_due(start);
+        assert_eq!(fragments.len(), 4);
+        assert!(failed.is_empty());
+        fragments.remove(0);
+        for frag in fragments.into_iter().rev() {
+            assert!(inbound.receive(frag).is_none());
+        }
+
+        // Nothing is resent until the retransmission timeout
+        assert!(outbound.poll_due(start).0.is_empty());
+
+        // The fragments that arrived are acknowledged
+        assert!(inbound.has_acks());
+        let (acks, partial_acks) = inbound.take_acks();
+        assert!(acks.is_empty());
+        assert_eq!(
+            partial_acks,
+            vec![PartialAck {
+                msg_id: msg.id,
+                received: vec![false, true, true, true],
+            }]
+        );
+        assert_eq!(
+            outbound.ack(&Data {
+                acks,
+                partial_acks,
+                fragments: vec![],
+            }),
+            0
+        );
+        assert!(!inbound.has_acks());
+
+        // Only the lost fragment is resent
+        let (fragments, _) = outbound.poll_due(start + INITIAL_RTO);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].num, 0);
+        match inbound.receive(fragments[0].clone()).map(|m| m.payload) {
+            Some(MessagePayload::Data(data)) => assert_eq!(data, payload),
+            _ => panic!("Message should have been reassembled"),
+        }
+
+        let (acks, partial_acks) = inbound.take_acks();
+        assert_eq!(acks, vec![msg.id]);
+        assert!(partial_acks.is_empty());
+
+        // A duplicate is acknowledged again, but not delivered twice
+        assert!(inbound.receive(fragments[0].clone()).is_none());
+        assert_eq!(inbound.take_acks().0, vec![msg.id]);
+
+        assert_eq!(
+            outbound.ack(&Data {
+                acks,
+                partial_acks,
+                fragments: vec![],
+            }),
+            1
+        );
+        assert!(outbound.is_empty());
+    }
+
+    #[test]
+    fn retransmission_backoff() {
+        let mut outbound = OutboundMessages::new();
+        let msg = Message::dummy_data();
+        assert!(outbound.push(&msg));
+
+        let start = Instant::now();
+        let mut sent_at = vec![];
+        for ms in (0..40_000).step_by(100) {
+            let now = start + Duration::from_millis(ms);
+            let (fragments, failed) = outbound.poll_due(now);
+            if !fragments.is_empty() {
+                sent_at.push(ms);
+            }
+            if !failed.is_empty() {
+                assert_eq!(failed, vec![msg.id]);
+                break;
+            }
+        }
+
+        // Each wait is twice as long as the last
+        assert_eq!(sent_at, vec![0, 1000, 3000, 7000, 15000]);
+        assert!(outbound.is_empty());
+    }
+
+    #[test]
+    fn payloads() {
+        let frag = |num| DataFragment {
+            msg_id: 1,
+            num,
+            last: false,
+            data: vec![num],
+        };
+
+        let payloads = data_payloads(vec![2], vec![], vec![frag(0), frag(1)]);
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].acks, vec![2]);
+        assert_eq!(payloads[0].fragments, vec![frag(0)]);
+        assert!(payloads[1].acks.is_empty());
+        assert_eq!(payloads[1].fragments, vec![frag(1)]);
+
+        // Acknowledgements are sent on their own if there are no fragments
+        assert_eq!(data_payloads(vec![2], vec![], vec![]).len(), 1);
+        assert!(data_payloads(vec![], vec![], vec![]).is_empty());
+    }
+
+    #[test]
+    fn reassembly_limits() {
+        let frag = |msg_id, len| DataFragment {
+            msg_id,
+            num: 1,
+            last: false,
+            data: vec![0; len],
+        };
+
+        // The oldest messages are dropped to stay within the buffer size
+        let mut inbound = InboundMessages::with_limits(100, 10);
+        assert!(inbound.receive(frag(1, 40)).is_none());
+        assert!(inbound.receive(frag(2, 40)).is_none());
+        assert_eq!(inbound.buffered(), 80);
+        assert!(inbound.receive(frag(3, 40)).is_none());
+        assert_eq!(inbound.buffered(), 80);
+        assert_eq!(inbound.partial.len(), 2);
+
+        // Fragments that could never fit are dropped
+        assert!(inbound.receive(frag(4, 101)).is_none());
+        assert_eq!(inbound.buffered(), 80);
+        assert!(!inbound.partial.contains_key(&4));
+
+        // and so are the oldest messages, to stay within the message limit
+        let mut inbound = InboundMessages::with_limits(1000, 2);
+        for msg_id in 1..=5 {
+            assert!(inbound.receive(frag(msg_id, 10)).is_none());
+        }
+        assert_eq!(inbound.partial.len(), 2);
+        assert_eq!(inbound.buffered(), 20);
+
+        // Expired messages no longer count towards the limits
+        inbound.expire(Instant::now() + REASSEMBLY_TIMEOUT);
+        assert!(inbound.partial.is_empty());
+        assert_eq!(inbound.buffered(), 0);
+    }
+
+    #[test]
+    fn fragments_past_last() {
+        let payload: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let msg = Message::from_payload(MessagePayload::Data(payload.clone()));
+        let fragments = fragment(&msg, MAX_FRAGMENT_SIZE).unwrap();
+        assert_eq!(fragments.len(), 3);
+        let bogus = DataFragment {
+            msg_id: msg.id,
+            num: 3,
+            last: false,
+            data: vec![0xff; 10],
+        };
+
+        // A fragment after the last one is dropped
+        let mut inbound = InboundMessages::new();
+        assert!(inbound.receive(fragments[2].clone()).is_none());
+        let buffered = inbound.buffered();
+        assert!(inbound.receive(bogus.clone()).is_none());
+        assert_eq!(inbound.buffered(), buffered);
+        assert!(inbound.receive(fragments[0].clone()).is_none());
+        match inbound.receive(fragments[1].clone()).map(|m| m.payload) {
+            Some(MessagePayload::Data(data)) => assert_eq!(data, payload),
+            _ => panic!("Message should have been reassembled"),
+        }
+        assert_eq!(inbound.buffered(), 0);
+
+        // If it arrives first, the message is dropped when the last fragment
+        // contradicts it
+        let mut inbound = InboundMessages::new();
+        assert!(inbound.receive(bogus).is_none());
+        assert!(inbound.receive(fragments[0].clone()).is_none());
+        assert!(inbound.receive(fragments[2].clone()).is_none());
+        assert!(inbound.partial.is_empty());
+        assert_eq!(inbound.buffered(), 0);
+    }
+}
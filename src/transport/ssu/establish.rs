@@ -0,0 +1,308 @@
+//! Session establishment.
+//!
+//! Alice sends Bob a SessionRequest containing her DH public value X. Bob
+//! replies with a SessionCreated containing his DH public value Y, and a
+//! signature over both values and both routers' addresses as Bob sees them.
+//! Alice then sends a SessionConfirmed containing her RouterIdentity and her
+//! own signature over the same data, after which both routers use the keys
+//! derived from the DH exchange.
+//!
+//! The SessionRequest and SessionCreated are protected with Bob's introduction
+//! key; the SessionConfirmed is protected with the new session keys.
+
+use rand::{rngs::OsRng, Rng};
+use std::net::{IpAddr, SocketAddr};
+
+use super::packet::{PacketKeys, SessionConfirmed, SessionCreated, SessionRequest, IV_LEN};
+use crate::crypto::{
+    self, dh::DHSessionKeyBuilder, Aes256, SessionKey, Signature, SigningPrivateKey,
+};
+use crate::data::{Hash, RouterIdentity};
+
+const AES_BLOCK_SIZE: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum EstablishError {
+    /// A message had a field of the wrong size.
+    Malformed,
+    /// A signature could not be created or verified.
+    Crypto(crypto::Error),
+}
+
+impl From<crypto::Error> for EstablishError {
+    fn from(e: crypto::Error) -> Self {
+        EstablishError::Crypto(e)
+    }
+}
+
+fn dh_value(value: &[u8]) -> Result<[u8; 256], EstablishError> {
+    if value.len() != 256 {
+        return Err(EstablishError::Malformed);
+    }
+    let mut buf = [0; 256];
+    buf.copy_from_slice(value);
+    Ok(buf)
+}
+
+fn ip_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// The data that both routers sign: X, Y, Alice's and Bob's addresses, the
+/// relay tag, and the time that the signer signed it.
+fn signed_data(
+    x: &[u8],
+    y: &[u8],
+    alice: SocketAddr,
+    bob: SocketAddr,
+    relay_tag: u32,
+    signed_on: u32,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(x.len() + y.len() + 2 * (16 + 2) + 8);
+    data.extend_from_slice(x);
+    data.extend_from_slice(y);
+    data.extend_from_slice(&ip_octets(alice.ip()));
+    data.extend_from_slice(&alice.port().to_be_bytes());
+    data.extend_from_slice(&ip_octets(bob.ip()));
+    data.extend_from_slice(&bob.port().to_be_bytes());
+    data.extend_from_slice(&relay_tag.to_be_bytes());
+    data.extend_from_slice(&signed_on.to_be_bytes());
+    data
+}
+
+/// Bob's signature is padded to the AES block size, and encrypted with the
+/// session key and the IV of the packet that carries it.
+fn padded_sig_len(sig_len: usize) -> usize {
+    (sig_len + AES_BLOCK_SIZE - 1) / AES_BLOCK_SIZE * AES_BLOCK_SIZE
+}
+
+/// Alice's side of an establishment.
+pub struct OutboundEstablishment {
+    dh: DHSessionKeyBuilder,
+    x: Vec<u8>,
+    bob: RouterIdentity,
+    bob_addr: SocketAddr,
+    intro_keys: PacketKeys,
+}
+
+impl OutboundEstablishment {
+    pub fn new(bob: RouterIdentity, bob_addr: SocketAddr, bob_intro_key: &SessionKey) -> Self {
+        let dh = DHSessionKeyBuilder::new();
+        let x = dh.get_pub();
+        OutboundEstablishment {
+            dh,
+            x,
+            bob,
+            bob_addr,
+            intro_keys: PacketKeys::intro(bob_intro_key),
+        }
+    }
+
+    /// Returns the hash of the router we are connecting to.
+    pub fn peer(&self) -> Hash {
+        self.bob.hash()
+    }
+
+    /// Returns the keys that Bob's handshake packets are protected with.
+    pub fn intro_keys(&self) -> &PacketKeys {
+        &self.intro_keys
+    }
+
+    pub fn session_request(&self) -> SessionRequest {
+        SessionRequest {
+            x: self.x.clone(),
+            bob_ip: self.bob_addr.ip(),
+        }
+    }
+
+    /// Handles Bob's SessionCreated, which was sent in a packet with the given
+    /// IV.
+    ///
+    /// Returns the SessionConfirmed to send Bob, the keys for the session, and
+    /// our address as Bob sees it.
+    pub fn session_created(
+        &self,
+        sc: &SessionCreated,
+        iv: &[u8; IV_LEN],
+        own_rid: &RouterIdentity,
+        own_key: &SigningPrivateKey,
+    ) -> Result<(SessionConfirmed, PacketKeys, SocketAddr), EstablishError> {
+        let (cipher, mac) = self.dh.build_ssu_keys(&dh_value(&sc.y)?);
+
+        let sig_type = self.bob.signing_key.sig_type();
+        let sig_len = sig_type.sig_len() as usize;
+        let padded_len = padded_sig_len(sig_len);
+        if sc.signature.len() < padded_len {
+            return Err(EstablishError::Malformed);
+        }
+        let mut sig = sc.signature[..padded_len].to_vec();
+        Aes256::new(&cipher, iv, iv).decrypt_blocks(&mut sig);
+        let sig = Signature::from_bytes(sig_type, &sig[..sig_len])?;
+
+        let alice_addr = SocketAddr::new(sc.alice_ip, sc.alice_port);
+        let data = signed_data(
+            &self.x,
+            &sc.y,
+            alice_addr,
+            self.bob_addr,
+            sc.relay_tag,
+            sc.signed_on,
+        );
+        self.bob.signing_key.verify(&data, &sig)?;
+
+        let signed_on = super::now_secs();
+        let data = signed_data(
+            &self.x,
+            &sc.y,
+            alice_addr,
+            self.bob_addr,
+            sc.relay_tag,
+            signed_on,
+        );
+        let signature = own_key.sign(&data)?;
+
+        Ok((
+            SessionConfirmed {
+                ri: own_rid.clone(),
+                signed_on,
+                signature,
+            },
+            PacketKeys { cipher, mac },
+            alice_addr,
+        ))
+    }
+}
+
+/// Bob's side of an establishment.
+pub struct InboundEstablishment {
+    x: Vec<u8>,
+    y: Vec<u8>,
+    alice_addr: SocketAddr,
+    bob_addr: SocketAddr,
+    relay_tag: u32,
+    keys: PacketKeys,
+}
+
+impl InboundEstablishment {
+    /// Handles Alice's SessionRequest, which she sent from `alice_addr` to our
+    /// port `bob_port`.
+    ///
+    /// Returns the establishment, and the SessionCreated to send Alice in a
+    /// packet with the given IV.
+    pub fn new(
+        sr: &SessionRequest,
+        alice_addr: SocketAddr,
+        bob_port: u16,
+        iv: &[u8; IV_LEN],
+        own_key: &SigningPrivateKey,
+    ) -> Result<(Self, SessionCreated), EstablishError> {
+        let dh = DHSessionKeyBuilder::new();
+        let y = dh.get_pub();
+        let (cipher, mac) = dh.build_ssu_keys(&dh_value(&sr.x)?);
+
+        // We don't act as an introducer for SSU peers
+        let relay_tag = 0;
+        let bob_addr = SocketAddr::new(sr.bob_ip, bob_port);
+        let signed_on = super::now_secs();
+        let data = signed_data(&sr.x, &y, alice_addr, bob_addr, relay_tag, signed_on);
+
+        let mut signature = own_key.sign(&data)?.to_bytes();
+        let sig_len = signature.len();
+        signature.resize(padded_sig_len(sig_len), 0);
+        OsRng.fill(&mut signature[sig_len..]);
+        Aes256::new(&cipher, iv, iv).encrypt_blocks(&mut signature);
+
+        let sc = SessionCreated {
+            y: y.clone(),
+            alice_ip: alice_addr.ip(),
+            alice_port: alice_addr.port(),
+            relay_tag,
+            signed_on,
+            signature,
+        };
+        Ok((
+            InboundEstablishment {
+                x: sr.x.clone(),
+                y,
+                alice_addr,
+                bob_addr,
+                relay_tag,
+                keys: PacketKeys { cipher, mac },
+            },
+            sc,
+        ))
+    }
+
+    /// Returns the keys that Alice's SessionConfirmed, and the rest of the
+    /// session, are protected with.
+    pub fn keys(&self) -> &PacketKeys {
+        &self.keys
+    }
+
+    /// Handles Alice's SessionConfirmed, returning her identity if she signed
+    /// the establishment.
+    pub fn session_confirmed(
+        &self,
+        sc: &SessionConfirmed,
+    ) -> Result<RouterIdentity, EstablishError> {
+        let data = signed_data(
+            &self.x,
+            &self.y,
+            self.alice_addr,
+            self.bob_addr,
+            self.relay_tag,
+            sc.signed_on,
+        );
+        sc.ri.signing_key.verify(&data, &sc.signature)?;
+        Ok(sc.ri.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::RouterSecretKeys;
+    use crate::transport::ssu::packet::random_iv;
+
+    #[test]
+    fn establishment() {
+        let alice_rsk = RouterSecretKeys::new();
+        let bob_rsk = RouterSecretKeys::new();
+        let alice_addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let bob_addr: SocketAddr = "10.0.0.2:5678".parse().unwrap();
+
+        let alice = OutboundEstablishment::new(bob_rsk.rid.clone(), bob_addr, &SessionKey([1; 32]));
+        assert_eq!(alice.peer(), bob_rsk.rid.hash());
+        let sr = alice.session_request();
+
+        let iv = random_iv();
+        let (bob, sc) = InboundEstablishment::new(
+            &sr,
+            alice_addr,
+            bob_addr.port(),
+            &iv,
+            &bob_rsk.signing_private_key,
+        )
+        .unwrap();
+
+        // Alice must use the IV that Bob encrypted his signature with
+        assert!(alice
+            .session_created(&sc, &random_iv(), &alice_rsk.rid, &alice_rsk.signing_private_key)
+            .is_err());
+        let (confirmed, keys, external) = alice
+            .session_created(&sc, &iv, &alice_rsk.rid, &alice_rsk.signing_private_key)
+            .unwrap();
+        assert_eq!(external, alice_addr);
+        assert_eq!(&keys, bob.keys());
+
+        assert_eq!(bob.session_confirmed(&confirmed), Ok(alice_rsk.rid.clone()));
+
+        // Alice's identity must match her signature
+        let mut forged = confirmed.clone();
+        forged.ri = RouterSecretKeys::new().rid;
+        assert!(bob.session_confirmed(&forged).is_err());
+    }
+}
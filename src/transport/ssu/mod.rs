@@ -0,0 +1,1060 @@
+//! A legacy transport protocol over UDP.
+//!
+//! SSU sessions are established with a signed DH exchange, after which I2NP
+//! messages are fragmented to fit in packets, and retransmitted until the peer
+//! acknowledges them.
+//!
+//! All sessions share a single socket, which is driven by an [`Engine`]. The
+//! engine routes each received packet by the address it came from: to an
+//! established session, to an establishment in progress, or (if it is
+//! authenticated with our introduction key) to a new inbound establishment.
+//!
+//! This module does not implement introductions, peer testing, or relaying, so
+//! only peers that publish a direct SSU address can be reached.
+//!
+//! [SSU specification](https://geti2p.net/en/docs/transport/ssu)
+
+use futures::{future, sync::mpsc, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use rand::rngs::OsRng;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::{executor::spawn, io, net::UdpSocket, timer::Interval};
+
+use super::{
+    bandwidth::BandwidthLimiter,
+    limiter::{prune_on_timer, HandshakePermit},
+    session::{self, DialObserver, SessionContext, SessionManager, SessionRefs, SessionRx},
+    stats::TrafficStats,
+    Bid, Direction, HandshakeLimiter, PeerFilter, Transport, UnknownMessagePolicy,
+};
+use crate::constants::I2P_BASE64;
+use crate::crypto::{SessionKey, SigningPrivateKey};
+use crate::data::{Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo, SSU_OPT_KEY};
+use crate::i2np::Message;
+use crate::router::{types::Distributor, Context};
+
+mod data;
+mod establish;
+mod packet;
+
+use self::data::{data_payloads, InboundMessages, OutboundMessages};
+use self::establish::{InboundEstablishment, OutboundEstablishment};
+use self::packet::{
+    packet_iv, random_iv, Data, Packet, PacketError, PacketKeys, Payload, SessionRequest,
+};
+
+lazy_static! {
+    pub(super) static ref SSU_STYLE: I2PString = I2PString::new("SSU");
+}
+
+/// The largest packet we accept.
+const MAX_PACKET_SIZE: usize = 1484;
+
+/// The largest I2NP message we send over SSU.
+const MAX_MESSAGE_SIZE: usize = 32 * 1024;
+
+/// The most establishments that peers can have in progress with us at once.
+const MAX_RESPONDING: usize = 256;
+
+/// How long we wait for an establishment to complete.
+const ESTABLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long we wait for a reply to a handshake packet before resending it.
+const ESTABLISH_RESEND: Duration = Duration::from_secs(2);
+
+/// How often the engine checks for retransmissions and expired state.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How long a session can go without receiving a packet before we close it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Returns the current time in seconds since the epoch.
+fn now_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Returns the introduction key published in an SSU address.
+fn intro_key(ra: &RouterAddress) -> Option<SessionKey> {
+    ra.option(&SSU_OPT_KEY)
+        .and_then(|key| I2P_BASE64.decode(key.0.as_bytes()).ok())
+        .filter(|key| key.len() == 32)
+        .map(|key| SessionKey(*array_ref![key, 0, 32]))
+}
+
+/// Returns whether we can connect directly to the address over SSU. We don't
+/// support SSU over IPv6, so only IPv4 addresses are usable.
+fn is_ssu_address(ra: &RouterAddress) -> bool {
+    ra.addr().map(|addr| addr.is_ipv4()).unwrap_or(false) && intro_key(ra).is_some()
+}
+
+/// Encrypts a packet carrying the given payload.
+fn encrypt(payload: Payload, keys: &PacketKeys) -> Vec<u8> {
+    Packet {
+        timestamp: now_secs(),
+        payload,
+    }
+    .encrypt(&random_iv(), keys)
+}
+
+/// A handshake packet that is resent until it is answered, or the
+/// establishment times out.
+struct Resend {
+    packet: Vec<u8>,
+    started: Instant,
+    next: Instant,
+}
+
+impl Resend {
+    fn new(packet: Vec<u8>, now: Instant) -> Self {
+        Resend {
+            packet,
+            started: now,
+            next: now + ESTABLISH_RESEND,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.started) >= ESTABLISH_TIMEOUT
+    }
+
+    /// Returns the packet if it is due to be resent.
+    fn due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if now < self.next || self.is_expired(now) {
+            return None;
+        }
+        self.next = now + ESTABLISH_RESEND;
+        Some(self.packet.clone())
+    }
+}
+
+/// An establishment that we started, waiting for a SessionCreated.
+struct Establishing {
+    est: OutboundEstablishment,
+    request: Resend,
+}
+
+/// An establishment that a peer started, waiting for a SessionConfirmed.
+struct Responding {
+    est: InboundEstablishment,
+    /// The DH public value from the peer's SessionRequest, so we can recognise
+    /// it if it is resent.
+    x: Vec<u8>,
+    /// Our SessionCreated, which is resent along with the SessionRequest.
+    created: Vec<u8>,
+    started: Instant,
+    _permit: HandshakePermit,
+}
+
+struct Session {
+    ctx: SessionContext<Message>,
+    keys: PacketKeys,
+    outbound: SessionRx<Message>,
+    ob_msgs: OutboundMessages,
+    ib_msgs: InboundMessages,
+    /// Our SessionConfirmed, resent until the peer sends us a packet within
+    /// the session.
+    unconfirmed: Option<Resend>,
+    last_received: Instant,
+    /// Set once the session's channel has been closed. The session ends once
+    /// the messages queued for it have been sent.
+    closing: bool,
+}
+
+//
+// Connection management engine
+//
+
+/// Sends and receives the packets of every SSU session.
+pub struct Engine<D: Distributor> {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    own_rid: RouterIdentity,
+    own_key: SigningPrivateKey,
+    intro_keys: PacketKeys,
+    session_refs: SessionRefs<Message, D>,
+    connect: mpsc::UnboundedReceiver<RouterInfo>,
+    establishing: HashMap<SocketAddr, Establishing>,
+    responding: HashMap<SocketAddr, Responding>,
+    handshake_limiter: Arc<HandshakeLimiter>,
+    peer_filter: Arc<PeerFilter>,
+    sessions: HashMap<SocketAddr, Session>,
+    send_queue: VecDeque<(Vec<u8>, SocketAddr)>,
+    timer: Interval,
+    /// Receives the addresses that peers see us at.
+    observed_addrs: Option<mpsc::UnboundedSender<(Hash, SocketAddr)>>,
+}
+
+impl<D: Distributor> Engine<D> {
+    /// Returns the address that the engine's socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Starts an establishment with a peer that we have messages for.
+    fn connect_to(&mut self, peer: RouterInfo, now: Instant) {
+        let hash = peer.router_id.hash();
+        let (addr, key) = match peer
+            .address(&SSU_STYLE, is_ssu_address)
+            .and_then(|ra| Some((ra.addr()?, intro_key(&ra)?)))
+        {
+            Some(ret) => ret,
+            None => {
+                debug!("No valid SSU address for {}", hash);
+                self.session_refs.state.connect_failed(&hash);
+                return;
+            }
+        };
+        if self.sessions.contains_key(&addr) || self.establishing.contains_key(&addr) {
+            return;
+        }
+
+        debug!("Connecting to {} at {}", hash, addr);
+        let est = OutboundEstablishment::new(peer.router_id, addr, &key);
+        let packet = encrypt(Payload::SessionRequest(est.session_request()), est.intro_keys());
+        self.send_queue.push_back((packet.clone(), addr));
+        self.establishing.insert(
+            addr,
+            Establishing {
+                est,
+                request: Resend::new(packet, now),
+            },
+        );
+    }
+
+    fn open_session(
+        &mut self,
+        addr: SocketAddr,
+        hash: Hash,
+        direction: Direction,
+        keys: PacketKeys,
+        unconfirmed: Option<Resend>,
+    ) {
+        let (tx, rx) = mpsc::unbounded();
+        let ctx = SessionContext::new(hash, direction, self.session_refs.state.clone(), tx);
+        self.sessions.insert(
+            addr,
+            Session {
+                ctx,
+                keys,
+                outbound: rx,
+                ob_msgs: OutboundMessages::new(),
+                ib_msgs: InboundMessages::new(),
+                unconfirmed,
+                last_received: Instant::now(),
+                closing: false,
+            },
+        );
+    }
+
+    /// Ends a session, telling the peer.
+    fn destroy_session(&mut self, addr: SocketAddr) {
+        if let Some(session) = self.sessions.remove(&addr) {
+            let packet = encrypt(Payload::SessionDestroyed, &session.keys);
+            self.send_queue.push_back((packet, addr));
+        }
+    }
+
+    fn handle_packet(&mut self, buf: &[u8], from: SocketAddr, now: Instant) {
+        let iv = match packet_iv(buf) {
+            Some(iv) => iv,
+            None => return,
+        };
+
+        // Packets within an established session
+        if let Some(session) = self.sessions.get(&from) {
+            match Packet::decrypt(buf, &session.keys) {
+                Ok(packet) => {
+                    self.session_packet(from, packet.payload, now);
+                    return;
+                }
+                // The peer might be establishing a new session
+                Err(PacketError::InvalidMac) => (),
+                Err(e) => {
+                    debug!("Invalid SSU packet from {}: {:?}", from, e);
+                    return;
+                }
+            }
+        }
+
+        // The SessionConfirmed for an establishment that a peer started
+        if let Some(responding) = self.responding.remove(&from) {
+            match Packet::decrypt(buf, responding.est.keys()) {
+                Ok(Packet {
+                    payload: Payload::SessionConfirmed(sc),
+                    ..
+                }) => {
+                    match responding.est.session_confirmed(&sc) {
+                        Ok(ri) if !self.peer_filter.permits(&ri.hash()) => {
+                            debug!("Rejected establishment from filtered peer {}", ri.hash());
+                            let packet = encrypt(Payload::SessionDestroyed, responding.est.keys());
+                            self.send_queue.push_back((packet, from));
+                        }
+                        Ok(ri) => {
+                            let keys = responding.est.keys().clone();
+                            // Let the peer know that we received it
+                            let packet = encrypt(Payload::Data(Data::default()), &keys);
+                            self.send_queue.push_back((packet, from));
+                            self.open_session(from, ri.hash(), Direction::Inbound, keys, None);
+                        }
+                        Err(e) => debug!("Invalid SessionConfirmed from {}: {:?}", from, e),
+                    }
+                    return;
+                }
+                Ok(_) => {
+                    debug!("Unexpected SSU packet from {}", from);
+                    self.responding.insert(from, responding);
+                    return;
+                }
+                // The peer might have resent its SessionRequest
+                Err(_) => {
+                    self.responding.insert(from, responding);
+                }
+            }
+        }
+
+        // The SessionCreated for an establishment that we started
+        if let Some(establishing) = self.establishing.remove(&from) {
+            match Packet::decrypt(buf, establishing.est.intro_keys()) {
+                Ok(Packet {
+                    payload: Payload::SessionCreated(sc),
+                    ..
+                }) => {
+                    match establishing.est.session_created(
+                        &sc,
+                        &iv,
+                        &self.own_rid,
+                        &self.own_key,
+                    ) {
+                        Ok((confirmed, keys, external)) => {
+                            let peer = establishing.est.peer();
+                            debug!("{} sees us at {}", peer, external);
+                            if let Some(observed) = &self.observed_addrs {
+                                let _ = observed.unbounded_send((peer, external));
+                            }
+                            let packet = encrypt(Payload::SessionConfirmed(confirmed), &keys);
+                            self.send_queue.push_back((packet.clone(), from));
+                            self.open_session(
+                                from,
+                                establishing.est.peer(),
+                                Direction::Outbound,
+                                keys,
+                                Some(Resend::new(packet, now)),
+                            );
+                        }
+                        Err(e) => debug!("Invalid SessionCreated from {}: {:?}", from, e),
+                    }
+                    return;
+                }
+                // The peer might be establishing a session with us
+                _ => {
+                    self.establishing.insert(from, establishing);
+                }
+            }
+        }
+
+        // A peer starting an establishment with us
+        match Packet::decrypt(buf, &self.intro_keys) {
+            Ok(Packet {
+                payload: Payload::SessionRequest(sr),
+                ..
+            }) => self.session_request(from, sr, now),
+            Ok(_) => debug!("Unexpected SSU packet from {}", from),
+            Err(e) => debug!("Invalid SSU packet from {}: {:?}", from, e),
+        }
+    }
+
+    fn session_request(&mut self, from: SocketAddr, sr: SessionRequest, now: Instant) {
+        if let Some(responding) = self.responding.get(&from) {
+            if responding.x == sr.x {
+                // The peer didn't receive our SessionCreated
+                self.send_queue.push_back((responding.created.clone(), from));
+                return;
+            }
+        }
+
+        if self.responding.len() >= MAX_RESPONDING {
+            debug!("Too many SSU establishments, dropping SessionRequest from {}", from);
+            return;
+        }
+        let permit = match self.handshake_limiter.permit(from.ip()) {
+            Some(permit) => permit,
+            None => {
+                debug!("Too many SSU establishments from {}", from.ip());
+                return;
+            }
+        };
+
+        let iv = random_iv();
+        match InboundEstablishment::new(&sr, from, self.local_addr.port(), &iv, &self.own_key) {
+            Ok((est, sc)) => {
+                let created = Packet {
+                    timestamp: now_secs(),
+                    payload: Payload::SessionCreated(sc),
+                }
+                .encrypt(&iv, &self.intro_keys);
+                self.send_queue.push_back((created.clone(), from));
+                self.responding.insert(
+                    from,
+                    Responding {
+                        est,
+                        x: sr.x,
+                        created,
+                        started: now,
+                        _permit: permit,
+                    },
+                );
+            }
+            Err(e) => debug!("Invalid SessionRequest from {}: {:?}", from, e),
+        }
+    }
+
+    fn session_packet(&mut self, from: SocketAddr, payload: Payload, now: Instant) {
+        let session = match self.sessions.get_mut(&from) {
+            Some(session) => session,
+            None => return,
+        };
+        session.last_received = now;
+        // Any packet in the session means the peer received our SessionConfirmed
+        session.unconfirmed = None;
+
+        match payload {
+            Payload::Data(data) => {
+                session.ob_msgs.ack(&data);
+                for frag in data.fragments {
+                    let msg = match session.ib_msgs.receive(frag) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    let hash = session.ctx.hash.clone();
+                    match self.session_refs.unknown_messages.filter(&hash, msg) {
+                        Ok(Some(msg)) => {
+                            self.session_refs.traffic.received_message();
+                            spawn(self.session_refs.distributor.handle(hash, msg).map_err(|_| {
+                                error!("A subsystem is down!");
+                            }));
+                        }
+                        Ok(None) => (),
+                        Err(e) => {
+                            debug!("Closing session with {}: {}", hash, e);
+                            self.destroy_session(from);
+                            return;
+                        }
+                    }
+                }
+            }
+            Payload::SessionConfirmed(_) => {
+                // The peer didn't receive our reply to its SessionConfirmed
+                let packet = encrypt(Payload::Data(Data::default()), &session.keys);
+                self.send_queue.push_back((packet, from));
+            }
+            Payload::SessionDestroyed => {
+                debug!("{} destroyed the session", session.ctx.hash);
+                self.sessions.remove(&from);
+            }
+            Payload::SessionRequest(_) | Payload::SessionCreated(_) => {
+                debug!("Unexpected SSU packet from {}", from)
+            }
+        }
+    }
+
+    /// Queues the packets that are due to be sent.
+    fn flush(&mut self, now: Instant) {
+        for (addr, establishing) in &mut self.establishing {
+            if let Some(packet) = establishing.request.due(now) {
+                self.send_queue.push_back((packet, *addr));
+            }
+        }
+
+        let mut ended = vec![];
+        for (addr, session) in &mut self.sessions {
+            if let Some(packet) = session.unconfirmed.as_mut().and_then(|r| r.due(now)) {
+                self.send_queue.push_back((packet, *addr));
+            }
+
+            // Fragment the messages that have been sent to the session
+            loop {
+                match session.outbound.poll().unwrap() {
+                    Async::Ready(Some(msg)) => {
+                        self.session_refs.traffic.sent_message();
+                        if !session.ob_msgs.push(&msg) {
+                            debug!("Dropping message too large for SSU: {}", msg.id);
+                        }
+                    }
+                    Async::Ready(None) => {
+                        session.closing = true;
+                        break;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+
+            let (fragments, failed) = session.ob_msgs.poll_due(now);
+            for msg_id in failed {
+                debug!("{} did not acknowledge message {}", session.ctx.hash, msg_id);
+            }
+            let (acks, partial_acks) = session.ib_msgs.take_acks();
+            for data in data_payloads(acks, partial_acks, fragments) {
+                let packet = encrypt(Payload::Data(data), &session.keys);
+                self.send_queue.push_back((packet, *addr));
+            }
+
+            if session.closing && session.ob_msgs.is_empty() {
+                ended.push(*addr);
+            }
+        }
+        for addr in ended {
+            self.destroy_session(addr);
+        }
+    }
+
+    /// Drops establishments that have timed out, and sessions that are idle.
+    fn expire(&mut self, now: Instant) {
+        let state = &self.session_refs.state;
+        self.establishing.retain(|addr, establishing| {
+            let keep = !establishing.request.is_expired(now);
+            if !keep {
+                debug!("Establishment with {} timed out", addr);
+                state.connect_failed(&establishing.est.peer());
+            }
+            keep
+        });
+        self.responding.retain(|_, responding| {
+            now.saturating_duration_since(responding.started) < ESTABLISH_TIMEOUT
+        });
+
+        let mut idle = vec![];
+        for (addr, session) in &mut self.sessions {
+            session.ib_msgs.expire(now);
+            if now.saturating_duration_since(session.last_received) >= IDLE_TIMEOUT {
+                idle.push(*addr);
+            }
+        }
+        for addr in idle {
+            debug!("Closing idle session with {}", addr);
+            self.destroy_session(addr);
+        }
+    }
+
+    /// Sends queued packets until the socket or the outbound bandwidth runs
+    /// out. Packets that are held back are tried again on the next tick.
+    fn send_packets(&mut self) {
+        while let Some((packet, addr)) = self.send_queue.front() {
+            if self
+                .session_refs
+                .bandwidth
+                .outbound
+                .consume(packet.len())
+                .is_err()
+            {
+                break;
+            }
+            match self.socket.poll_send_to(packet, addr) {
+                Ok(Async::Ready(n)) => {
+                    self.session_refs.traffic.sent_bytes(n);
+                    self.send_queue.pop_front();
+                }
+                Ok(Async::NotReady) => {
+                    self.session_refs.bandwidth.outbound.give_back(packet.len());
+                    break;
+                }
+                Err(e) => {
+                    // A packet that can't be sent is as good as lost
+                    debug!("Error sending SSU packet to {}: {}", addr, e);
+                    self.send_queue.pop_front();
+                }
+            }
+        }
+    }
+}
+
+impl<D: Distributor> Future for Engine<D> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut tick = false;
+        while let Async::Ready(Some(_)) = self
+            .timer
+            .poll()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        {
+            tick = true;
+        }
+        let now = Instant::now();
+
+        // Connect to peers that we have messages for
+        while let Async::Ready(Some(peer)) = self.connect.poll().unwrap() {
+            self.connect_to(peer, now);
+        }
+
+        let mut buf = [0; MAX_PACKET_SIZE];
+        loop {
+            match self.socket.poll_recv_from(&mut buf) {
+                Ok(Async::Ready((n, from))) => {
+                    self.session_refs.traffic.received_bytes(n);
+                    // Over the limit, we drop packets as a congested link
+                    // would, and the peer resends them
+                    if self.session_refs.bandwidth.inbound.consume(n).is_ok() {
+                        self.handle_packet(&buf[..n], from, now);
+                    }
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    // Errors such as ICMP port unreachable from one peer must
+                    // not stop the other sessions. We go back to reading on
+                    // the next tick.
+                    debug!("Error receiving SSU packet: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if tick {
+            self.expire(now);
+        }
+        self.flush(now);
+        self.send_packets();
+
+        // The engine runs until the listener is stopped
+        Ok(Async::NotReady)
+    }
+}
+
+pub struct Manager<D: Distributor> {
+    addr: SocketAddr,
+    intro_key: SessionKey,
+    session_manager: SessionManager<Message, D>,
+    connect_tx: mpsc::UnboundedSender<RouterInfo>,
+    connect_rx: Option<mpsc::UnboundedReceiver<RouterInfo>>,
+    observed_addrs: Option<mpsc::UnboundedSender<(Hash, SocketAddr)>>,
+    handshake_limiter: Arc<HandshakeLimiter>,
+    peer_filter: Arc<PeerFilter>,
+    /// Whether we connect to peers ourselves, or only send over sessions that
+    /// they opened.
+    dial_directly: bool,
+    /// Whether our address advertises that we are an introducer.
+    introducer: bool,
+}
+
+impl<D: Distributor> Manager<D> {
+    pub fn new(addr: SocketAddr, distributor: D) -> Self {
+        let mut rng = OsRng;
+        Manager::with_intro_key(addr, SessionKey::generate(&mut rng), distributor)
+    }
+
+    fn with_intro_key(addr: SocketAddr, intro_key: SessionKey, distributor: D) -> Self {
+        let (connect_tx, connect_rx) = mpsc::unbounded();
+        Manager {
+            addr,
+            intro_key,
+            session_manager: session::new_manager(distributor),
+            connect_tx,
+            connect_rx: Some(connect_rx),
+            observed_addrs: None,
+            handshake_limiter: Arc::new(HandshakeLimiter::default()),
+            peer_filter: Arc::new(PeerFilter::default()),
+            dial_directly: true,
+            introducer: false,
+        }
+    }
+
+    pub fn from_file(addr: SocketAddr, path: &str, distributor: D) -> io::Result<Self> {
+        let mut keys = File::open(path)?;
+        let mut data: Vec<u8> = Vec::new();
+        keys.read_to_end(&mut data)?;
+        if data.len() != 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SSU key file"));
+        }
+
+        Ok(Manager::with_intro_key(addr, SessionKey(*array_ref![data, 0, 32]), distributor))
+    }
+
+    pub fn to_file(&self, path: &str) -> io::Result<()> {
+        let mut keys = File::create(path)?;
+        keys.write_all(&self.intro_key.0)
+    }
+
+    /// Sets what sessions do when the peer sends an unknown message type.
+    pub fn set_unknown_message_policy(&mut self, policy: UnknownMessagePolicy) {
+        self.session_manager.set_unknown_message_policy(policy);
+    }
+
+    /// Records the traffic of sessions opened from now on in `traffic`.
+    pub(super) fn set_traffic_stats(&mut self, traffic: TrafficStats) {
+        self.session_manager.set_traffic_stats(traffic);
+    }
+
+    /// Limits the bandwidth that the engine uses. Must be called before
+    /// `listen`.
+    pub(super) fn set_bandwidth_limiter(&mut self, bandwidth: BandwidthLimiter) {
+        self.session_manager.set_bandwidth_limiter(bandwidth);
+    }
+
+    /// Stops us from connecting to peers ourselves, for example because all
+    /// of our outbound connections must go through a proxy. Sessions that
+    /// peers open to us are still used.
+    pub(super) fn disable_direct_dials(&mut self) {
+        self.dial_directly = false;
+    }
+
+    /// Advertises in our address that we introduce firewalled peers to routers
+    /// that connect to us.
+    pub fn set_introducer(&mut self, introducer: bool) {
+        self.introducer = introducer;
+    }
+
+    /// Sends the address that each peer we connect to sees us at, along with
+    /// the peer's hash, to `observed_addrs`.
+    pub(super) fn set_address_observer(
+        &mut self,
+        observed_addrs: mpsc::UnboundedSender<(Hash, SocketAddr)>,
+    ) {
+        self.observed_addrs = Some(observed_addrs);
+    }
+
+    /// Sets the limits on establishments that each IP address can start with
+    /// us.
+    pub fn set_handshake_limiter(&mut self, handshake_limiter: Arc<HandshakeLimiter>) {
+        self.handshake_limiter = handshake_limiter;
+    }
+
+    /// Sets the peers that may open sessions with us.
+    pub(super) fn set_peer_filter(&mut self, peer_filter: Arc<PeerFilter>) {
+        self.peer_filter = peer_filter;
+    }
+
+    pub fn sink(&self) -> OutboundSink<D> {
+        OutboundSink {
+            session_refs: self.session_manager.refs(),
+            connect: self.connect_tx.clone(),
+        }
+    }
+
+    pub fn address(&self) -> RouterAddress {
+        let mut ra = RouterAddress::new(&SSU_STYLE, self.addr);
+        ra.set_ssu(&self.intro_key.0);
+        if self.introducer {
+            ra.set_introducer();
+        }
+        ra
+    }
+
+    /// Binds our socket, returning the engine that drives it.
+    ///
+    /// If we were configured to listen on port 0, our address is updated with
+    /// the port that the OS picked.
+    pub fn listen(
+        &mut self,
+        own_rid: RouterIdentity,
+        own_key: SigningPrivateKey,
+    ) -> io::Result<Engine<D>> {
+        let connect = self
+            .connect_rx
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "SSU is already listening"))?;
+
+        let socket = UdpSocket::bind(&self.addr)?;
+        let local_addr = socket.local_addr()?;
+        self.addr = local_addr;
+        info!("Listening on {}", local_addr);
+
+        Ok(Engine {
+            socket,
+            local_addr,
+            own_rid,
+            own_key,
+            intro_keys: PacketKeys::intro(&self.intro_key),
+            session_refs: self.session_manager.refs(),
+            connect,
+            establishing: HashMap::new(),
+            responding: HashMap::new(),
+            handshake_limiter: self.handshake_limiter.clone(),
+            peer_filter: self.peer_filter.clone(),
+            sessions: HashMap::new(),
+            send_queue: VecDeque::new(),
+            timer: Interval::new_interval(TICK),
+            observed_addrs: self.observed_addrs.clone(),
+        })
+    }
+}
+
+impl<D: Distributor> Transport for Manager<D> {
+    fn style(&self) -> &I2PString {
+        &SSU_STYLE
+    }
+
+    fn address(&self) -> RouterAddress {
+        Manager::address(self)
+    }
+
+    fn listen(
+        &mut self,
+        ctx: Arc<Context>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        // Forget quiet addresses in the background
+        let prune = prune_on_timer(self.handshake_limiter.clone());
+        match Manager::listen(
+            self,
+            ctx.keys.rid.clone(),
+            ctx.keys.signing_private_key.clone(),
+        ) {
+            Ok(engine) => Box::new(engine.select(prune).map(|_| ()).map_err(|(e, _)| e)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn is_established(&self, hash: &Hash) -> bool {
+        self.session_manager.have_session(hash)
+    }
+
+    fn sessions(&self) -> Vec<(Hash, Direction)> {
+        self.session_manager.sessions()
+    }
+
+    fn set_dial_observer(&mut self, observer: DialObserver) {
+        self.session_manager.set_dial_observer(observer);
+    }
+
+    fn close_sessions(&mut self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(self.session_manager.close_all())
+    }
+
+    fn bid(&self, peer: &RouterInfo, msg: &Message) -> Option<Bid> {
+        if msg.size() > MAX_MESSAGE_SIZE {
+            return None;
+        }
+
+        if peer.address(&SSU_STYLE, is_ssu_address).is_none() {
+            return None;
+        }
+
+        let established = self.is_established(&peer.router_id.hash());
+        if !established && !self.dial_directly {
+            return None;
+        }
+        Some(Bid {
+            bid: if established { 15 } else { 80 },
+            sink: Box::new(self.sink()),
+        })
+    }
+}
+
+pub struct OutboundSink<D: Distributor> {
+    session_refs: SessionRefs<Message, D>,
+    connect: mpsc::UnboundedSender<RouterInfo>,
+}
+
+impl<D: Distributor> Sink for OutboundSink<D> {
+    type SinkItem = (RouterInfo, Message);
+    type SinkError = io::Error;
+
+    fn start_send(
+        &mut self,
+        (peer, msg): Self::SinkItem,
+    ) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let connect = &self.connect;
+        match self
+            .session_refs
+            .state
+            .send(&peer.router_id.hash(), msg, || {
+                // The engine establishes a session with the peer
+                if connect.unbounded_send(peer.clone()).is_err() {
+                    error!("SSU engine is not running");
+                }
+            }) {
+            Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
+            Ok(AsyncSink::NotReady(msg)) => Ok(AsyncSink::NotReady((peer, msg))),
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("Channel to session is broken: {}", e),
+            )),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        // Channels always complete immediately
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future::lazy, Future, Sink};
+    use std::iter::once;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+    use tokio::{runtime::current_thread::Runtime, timer::Delay};
+
+    use super::{Manager, SSU_STYLE};
+    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::i2np::{Message, MessagePayload};
+    use crate::router::mock::MockDistributor;
+    use crate::transport::{Direction, PeerFilter, Transport};
+
+    #[test]
+    fn keyfile_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("ssu.keys.dat");
+        let path = path.to_str().unwrap();
+
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let manager = Manager::new(addr, MockDistributor::new());
+        manager.to_file(path).unwrap();
+        let loaded = Manager::from_file(addr, path, MockDistributor::new()).unwrap();
+        assert_eq!(loaded.address(), manager.address());
+        assert_eq!(manager.address().transport_style(), &*SSU_STYLE);
+    }
+
+    #[test]
+    fn send_over_loopback() {
+        let alice_rsk = RouterSecretKeys::new();
+        let bob_rsk = RouterSecretKeys::new();
+        let addr = "127.0.0.1:0".parse().unwrap();
+
+        let mut alice = Manager::new(addr, MockDistributor::new());
+        let bob_distributor = MockDistributor::new();
+        let received = bob_distributor.received.clone();
+        let mut bob = Manager::new(addr, bob_distributor);
+
+        let mut rt = Runtime::new().unwrap();
+        let alice_engine = alice
+            .listen(alice_rsk.rid.clone(), alice_rsk.signing_private_key.clone())
+            .unwrap();
+        let bob_engine = bob
+            .listen(bob_rsk.rid.clone(), bob_rsk.signing_private_key.clone())
+            .unwrap();
+        rt.spawn(alice_engine.map_err(|_| ()));
+        rt.spawn(bob_engine.map_err(|_| ()));
+
+        let mut bob_ri = RouterInfo::new(bob_rsk.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        let msg = Message::dummy_data();
+        let bid = alice.bid(&bob_ri, &msg).unwrap();
+        assert_eq!(bid.bid, 80);
+        rt.block_on(lazy(move || bid.send((bob_ri, msg)))).unwrap();
+
+        // Wait for the session to be established and the message delivered
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while received.lock().unwrap().is_empty() && Instant::now() < deadline {
+            rt.block_on(Delay::new(Instant::now() + Duration::from_millis(10))).unwrap();
+        }
+
+        let r = received.lock().unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].0, alice_rsk.rid.hash());
+        match r[0].1.payload {
+            MessagePayload::Data(ref data) => assert_eq!(data, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            _ => panic!("Wrong message delivered"),
+        }
+
+        assert!(alice.is_established(&bob_rsk.rid.hash()));
+        assert_eq!(bob.sessions(), vec![(alice_rsk.rid.hash(), Direction::Inbound)]);
+    }
+
+    #[test]
+    fn filtered_peer() {
+        let alice_rsk = RouterSecretKeys::new();
+        let bob_rsk = RouterSecretKeys::new();
+        let addr = "127.0.0.1:0".parse().unwrap();
+
+        let mut alice = Manager::new(addr, MockDistributor::new());
+        let bob_distributor = MockDistributor::new();
+        let received = bob_distributor.received.clone();
+        let mut bob = Manager::new(addr, bob_distributor);
+        let blocklist = once(alice_rsk.rid.hash()).collect();
+        bob.set_peer_filter(Arc::new(PeerFilter::new(None, blocklist)));
+
+        let mut rt = Runtime::new().unwrap();
+        let alice_engine = alice
+            .listen(alice_rsk.rid.clone(), alice_rsk.signing_private_key.clone())
+            .unwrap();
+        let bob_engine = bob
+            .listen(bob_rsk.rid.clone(), bob_rsk.signing_private_key.clone())
+            .unwrap();
+        rt.spawn(alice_engine.map_err(|_| ()));
+        rt.spawn(bob_engine.map_err(|_| ()));
+
+        let mut bob_ri = RouterInfo::new(bob_rsk.rid.clone());
+        bob_ri.set_addresses(vec![bob.address()]);
+        let bob_hash = bob_rsk.rid.hash();
+        let sink = alice.sink();
+        rt.block_on(lazy(move || sink.send((bob_ri, Message::dummy_data()))))
+            .unwrap();
+
+        // Bob destroys the session as soon as Alice confirms it
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while (alice.session_manager.have_pending_session(&bob_hash)
+            || alice.is_established(&bob_hash))
+            && Instant::now() < deadline
+        {
+            rt.block_on(Delay::new(Instant::now() + Duration::from_millis(10)))
+                .unwrap();
+        }
+        assert!(!alice.is_established(&bob_hash));
+        assert!(bob.sessions().is_empty());
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreachable_peer() {
+        let alice_rsk = RouterSecretKeys::new();
+        let mut alice = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+
+        let mut rt = Runtime::new().unwrap();
+        let alice_engine = alice
+            .listen(alice_rsk.rid.clone(), alice_rsk.signing_private_key.clone())
+            .unwrap();
+        rt.spawn(alice_engine.map_err(|_| ()));
+
+        // Bob doesn't publish an SSU address
+        let bob_ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        let bob = bob_ri.router_id.hash();
+        let sink = alice.sink();
+        rt.block_on(lazy(move || sink.send((bob_ri, Message::dummy_data()))))
+            .unwrap();
+
+        // The engine gives up on the connection, so the next message for Bob
+        // will try again
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while alice.session_manager.have_pending_session(&bob) && Instant::now() < deadline {
+            rt.block_on(Delay::new(Instant::now() + Duration::from_millis(10)))
+                .unwrap();
+        }
+        assert!(!alice.session_manager.have_pending_session(&bob));
+    }
+
+    #[test]
+    fn ipv4_only() {
+        let manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let bid = |addr: &str| {
+            let mut peer_ri = RouterInfo::new(RouterSecretKeys::new().rid);
+            let peer = Manager::new(addr.parse().unwrap(), MockDistributor::new());
+            peer_ri.set_addresses(vec![peer.address()]);
+            manager.bid(&peer_ri, &Message::dummy_data()).is_some()
+        };
+
+        assert!(bid("127.0.0.1:1234"));
+        assert!(!bid("[::1]:1234"));
+    }
+
+    #[test]
+    fn no_direct_dials() {
+        let mut manager = Manager::new("127.0.0.1:0".parse().unwrap(), MockDistributor::new());
+        let mut peer_ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        let peer = Manager::new("127.0.0.1:1234".parse().unwrap(), MockDistributor::new());
+        peer_ri.set_addresses(vec![peer.address()]);
+        assert!(manager.bid(&peer_ri, &Message::dummy_data()).is_some());
+
+        // Without a session, we can't reach the peer
+        manager.disable_direct_dials();
+        assert!(manager.bid(&peer_ri, &Message::dummy_data()).is_none());
+    }
+}
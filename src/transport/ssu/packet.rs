@@ -0,0 +1,649 @@
+//! SSU packet encoding.
+//!
+//! Every packet starts with a 16-byte HMAC-MD5 and a 16-byte IV. The rest of
+//! the packet is encrypted with AES-256-CBC, padded to a multiple of the AES
+//! block size, and starts with a flag byte containing the payload type and a
+//! 4-byte timestamp.
+//!
+//! Until a session is established, packets are encrypted and authenticated
+//! with the receiver's introduction key, which it publishes in its address.
+//! After that, the session key and MAC key derived from the DH exchange are
+//! used.
+
+use cookie_factory::*;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use nom::*;
+use rand::{rngs::OsRng, Rng};
+use std::net::IpAddr;
+
+use crate::crypto::{
+    frame::{gen_signature, signature},
+    Aes256, SessionKey, Signature,
+};
+use crate::data::{
+    frame::{gen_router_identity, router_identity},
+    RouterIdentity,
+};
+use crate::util::serialize;
+
+const MAC_LEN: usize = 16;
+pub(super) const IV_LEN: usize = 16;
+const AES_BLOCK_SIZE: usize = 16;
+
+/// Length of the MAC and IV, which are not encrypted.
+pub(super) const HEADER_LEN: usize = MAC_LEN + IV_LEN;
+
+/// Length of the flag byte and timestamp that start the encrypted part.
+pub(super) const PAYLOAD_HEADER_LEN: usize = 5;
+
+// Payload types
+const SESSION_REQUEST: u8 = 0;
+const SESSION_CREATED: u8 = 1;
+const SESSION_CONFIRMED: u8 = 2;
+const DATA: u8 = 6;
+const SESSION_DESTROYED: u8 = 8;
+
+const FLAG_EXTENDED_OPTIONS: u8 = 0x04;
+
+// Data flags
+const DATA_FLAG_EXPLICIT_ACKS: u8 = 0x80;
+const DATA_FLAG_ACK_BITFIELDS: u8 = 0x40;
+const DATA_FLAG_EXTENDED: u8 = 0x02;
+
+/// The keys that a packet is encrypted and authenticated with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PacketKeys {
+    pub cipher: SessionKey,
+    pub mac: SessionKey,
+}
+
+impl PacketKeys {
+    /// Returns the keys used for packets sent to a peer before a session is
+    /// established with it, which are both its introduction key.
+    pub fn intro(key: &SessionKey) -> Self {
+        PacketKeys {
+            cipher: key.clone(),
+            mac: key.clone(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PacketError {
+    /// The packet is too short, or not a whole number of AES blocks.
+    InvalidLength,
+    /// The packet was not authenticated with the expected MAC key.
+    InvalidMac,
+    /// The packet was authenticated, but its contents could not be parsed.
+    Malformed,
+}
+
+/// The first message of an establishment, from Alice to Bob.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionRequest {
+    /// Alice's DH public value.
+    pub x: Vec<u8>,
+    /// Bob's IP address, as Alice sees it.
+    pub bob_ip: IpAddr,
+}
+
+/// Bob's reply to a SessionRequest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionCreated {
+    /// Bob's DH public value.
+    pub y: Vec<u8>,
+    /// Alice's address, as Bob sees it.
+    pub alice_ip: IpAddr,
+    pub alice_port: u16,
+    pub relay_tag: u32,
+    pub signed_on: u32,
+    /// Bob's signature, padded to the AES block size and encrypted with the
+    /// session key. Any padding of the packet follows it.
+    pub signature: Vec<u8>,
+}
+
+/// Alice's reply to a SessionCreated, which completes the establishment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionConfirmed {
+    pub ri: RouterIdentity,
+    pub signed_on: u32,
+    pub signature: Signature,
+}
+
+/// A piece of an I2NP message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataFragment {
+    pub msg_id: u32,
+    pub num: u8,
+    pub last: bool,
+    pub data: Vec<u8>,
+}
+
+/// Acknowledges some of the fragments of a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartialAck {
+    pub msg_id: u32,
+    /// Whether each fragment has been received, by fragment number.
+    pub received: Vec<bool>,
+}
+
+/// Acknowledgements and message fragments, sent within a session.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Data {
+    /// The IDs of messages that have been completely received.
+    pub acks: Vec<u32>,
+    pub partial_acks: Vec<PartialAck>,
+    pub fragments: Vec<DataFragment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    SessionRequest(SessionRequest),
+    SessionCreated(SessionCreated),
+    SessionConfirmed(SessionConfirmed),
+    Data(Data),
+    SessionDestroyed,
+}
+
+impl Payload {
+    fn payload_type(&self) -> u8 {
+        match self {
+            Payload::SessionRequest(_) => SESSION_REQUEST,
+            Payload::SessionCreated(_) => SESSION_CREATED,
+            Payload::SessionConfirmed(_) => SESSION_CONFIRMED,
+            Payload::Data(_) => DATA,
+            Payload::SessionDestroyed => SESSION_DESTROYED,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    /// Seconds since the epoch, when the packet was sent.
+    pub timestamp: u32,
+    pub payload: Payload,
+}
+
+//
+// Payloads
+//
+
+fn remainder(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    Ok((&input[input.len()..], input))
+}
+
+fn ip_address(input: &[u8]) -> IResult<&[u8], IpAddr> {
+    let (i, ip) = length_data!(input, be_u8)?;
+    match ip.len() {
+        4 => Ok((i, IpAddr::from(*array_ref![ip, 0, 4]))),
+        16 => Ok((i, IpAddr::from(*array_ref![ip, 0, 16]))),
+        _ => Err(Err::Error(error_position!(input, ErrorKind::Custom(1)))),
+    }
+}
+
+fn gen_ip_address<'a>(
+    input: (&'a mut [u8], usize),
+    ip: &IpAddr,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    match ip {
+        IpAddr::V4(ip) => do_gen!(input, gen_be_u8!(4) >> gen_slice!(&ip.octets()[..])),
+        IpAddr::V6(ip) => do_gen!(input, gen_be_u8!(16) >> gen_slice!(&ip.octets()[..])),
+    }
+}
+
+fn gen_random(input: (&mut [u8], usize), len: usize) -> Result<(&mut [u8], usize), GenError> {
+    let mut data = vec![0; len];
+    OsRng.fill(&mut data[..]);
+    gen_slice!(input, data)
+}
+
+/// Pads the payload that started at `start` so that, once `trailer_len` more
+/// bytes have been written, it is a whole number of AES blocks.
+fn gen_padding_before(
+    input: (&mut [u8], usize),
+    start: usize,
+    trailer_len: usize,
+) -> Result<(&mut [u8], usize), GenError> {
+    let len = input.1 - start + trailer_len;
+    gen_random(input, (AES_BLOCK_SIZE - len % AES_BLOCK_SIZE) % AES_BLOCK_SIZE)
+}
+
+// SessionRequest
+
+named!(
+    session_request<SessionRequest>,
+    do_parse!(
+        x: take!(256) >> bob_ip: ip_address >> (SessionRequest {
+            x: x.to_vec(),
+            bob_ip,
+        })
+    )
+);
+
+fn gen_session_request<'a>(
+    input: (&'a mut [u8], usize),
+    sr: &SessionRequest,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(input, gen_slice!(sr.x) >> gen_ip_address(&sr.bob_ip))
+}
+
+// SessionCreated
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(
+    session_created<SessionCreated>,
+    do_parse!(
+        y:          take!(256) >>
+        alice_ip:   ip_address >>
+        alice_port: be_u16 >>
+        relay_tag:  be_u32 >>
+        signed_on:  be_u32 >>
+        signature:  remainder >>
+        (SessionCreated {
+            y: y.to_vec(),
+            alice_ip,
+            alice_port,
+            relay_tag,
+            signed_on,
+            signature: signature.to_vec(),
+        })
+    )
+);
+
+fn gen_session_created<'a>(
+    input: (&'a mut [u8], usize),
+    sc: &SessionCreated,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_slice!(sc.y)
+            >> gen_ip_address(&sc.alice_ip)
+            >> gen_be_u16!(sc.alice_port)
+            >> gen_be_u32!(sc.relay_tag)
+            >> gen_be_u32!(sc.signed_on)
+            >> gen_slice!(sc.signature)
+    )
+}
+
+// SessionConfirmed
+
+// The signature is at the end of the packet, after any padding.
+fn session_confirmed(input: &[u8]) -> IResult<&[u8], SessionConfirmed> {
+    let (i, (ri, signed_on)) = do_parse!(
+        input,
+        // We only support RouterIdentities that fit in a single fragment
+        verify!(be_u8, |info| info == 0x01)
+            >> ri: length_value!(be_u16, router_identity)
+            >> signed_on: be_u32
+            >> ((ri, signed_on))
+    )?;
+
+    let sig_type = ri.signing_key.sig_type();
+    let sig_len = sig_type.sig_len() as usize;
+    if i.len() < sig_len {
+        return Err(Err::Error(error_position!(i, ErrorKind::Custom(1))));
+    }
+    let (i, signature) = signature(&i[i.len() - sig_len..], sig_type)?;
+    Ok((
+        i,
+        SessionConfirmed {
+            ri,
+            signed_on,
+            signature,
+        },
+    ))
+}
+
+fn gen_session_confirmed<'a>(
+    input: (&'a mut [u8], usize),
+    sc: &SessionConfirmed,
+    start: usize,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let sig_len = sc.signature.to_bytes().len();
+    do_gen!(
+        input,
+        gen_be_u8!(0x01)
+            >> size: gen_skip!(2)
+            >> ri_start: gen_router_identity(&sc.ri)
+            >> ri_end: gen_at_offset!(size, gen_be_u16!(ri_end - ri_start))
+            >> gen_be_u32!(sc.signed_on)
+            >> gen_padding_before(start, sig_len)
+            >> gen_signature(&sc.signature)
+    )
+}
+
+// Data
+
+fn partial_ack(input: &[u8]) -> IResult<&[u8], PartialAck> {
+    let (mut i, msg_id) = be_u32(input)?;
+    let mut received = vec![];
+    loop {
+        let (rest, bitfield) = be_u8(i)?;
+        i = rest;
+        received.extend((0..7).map(|bit| bitfield & (1 << bit) != 0));
+        // The high bit is set if another bitfield byte follows
+        if bitfield & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok((i, PartialAck { msg_id, received }))
+}
+
+fn gen_partial_ack<'a>(
+    input: (&'a mut [u8], usize),
+    ack: &PartialAck,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let mut bitfield: Vec<u8> = ack
+        .received
+        .chunks(7)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .filter(|(_, received)| **received)
+                .fold(0, |byte, (bit, _)| byte | 1 << bit)
+        })
+        .collect();
+    if bitfield.is_empty() {
+        bitfield.push(0);
+    }
+    let last = bitfield.len() - 1;
+    for byte in &mut bitfield[..last] {
+        *byte |= 0x80;
+    }
+    do_gen!(input, gen_be_u32!(ack.msg_id) >> gen_slice!(bitfield))
+}
+
+fn gen_ack<'a>(
+    input: (&'a mut [u8], usize),
+    msg_id: &u32,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    gen_be_u32!(input, *msg_id)
+}
+
+// Fragment number (7 bits), whether it is the last fragment (1 bit), 2 unused
+// bits, and the size of the fragment (14 bits).
+fn fragment_info(input: &[u8]) -> IResult<&[u8], (u8, bool, usize)> {
+    let (i, info) = take!(input, 3)?;
+    let num = info[0] >> 1;
+    let last = info[0] & 1 == 1;
+    let size = (usize::from(info[1] & 0x3f) << 8) | usize::from(info[2]);
+    Ok((i, (num, last, size)))
+}
+
+named!(
+    data_fragment<DataFragment>,
+    do_parse!(
+        msg_id: be_u32
+            >> info: fragment_info
+            >> data: take!(info.2)
+            >> (DataFragment {
+                msg_id,
+                num: info.0,
+                last: info.1,
+                data: data.to_vec(),
+            })
+    )
+);
+
+fn gen_data_fragment<'a>(
+    input: (&'a mut [u8], usize),
+    frag: &DataFragment,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let info = [
+        (frag.num << 1) | frag.last as u8,
+        (frag.data.len() >> 8) as u8 & 0x3f,
+        frag.data.len() as u8,
+    ];
+    do_gen!(
+        input,
+        gen_be_u32!(frag.msg_id) >> gen_slice!(&info[..]) >> gen_slice!(frag.data)
+    )
+}
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(
+    data<Data>,
+    do_parse!(
+        flags:        be_u8 >>
+        acks:         cond!(flags & DATA_FLAG_EXPLICIT_ACKS != 0, length_count!(be_u8, be_u32)) >>
+        partial_acks: cond!(flags & DATA_FLAG_ACK_BITFIELDS != 0,
+                            length_count!(be_u8, partial_ack)) >>
+                      cond!(flags & DATA_FLAG_EXTENDED != 0, length_data!(be_u8)) >>
+        fragments:    length_count!(be_u8, data_fragment) >>
+        (Data {
+            acks: acks.unwrap_or_default(),
+            partial_acks: partial_acks.unwrap_or_default(),
+            fragments,
+        })
+    )
+);
+
+fn gen_data<'a>(
+    input: (&'a mut [u8], usize),
+    data: &Data,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let mut flags = 0;
+    if !data.acks.is_empty() {
+        flags |= DATA_FLAG_EXPLICIT_ACKS;
+    }
+    if !data.partial_acks.is_empty() {
+        flags |= DATA_FLAG_ACK_BITFIELDS;
+    }
+    do_gen!(
+        input,
+        gen_be_u8!(flags)
+            >> gen_cond!(
+                !data.acks.is_empty(),
+                do_gen!(gen_be_u8!(data.acks.len() as u8) >> gen_many!(&data.acks, gen_ack))
+            )
+            >> gen_cond!(
+                !data.partial_acks.is_empty(),
+                do_gen!(
+                    gen_be_u8!(data.partial_acks.len() as u8)
+                        >> gen_many!(&data.partial_acks, gen_partial_ack)
+                )
+            )
+            >> gen_be_u8!(data.fragments.len() as u8)
+            >> gen_many!(&data.fragments, gen_data_fragment)
+    )
+}
+
+//
+// Packets
+//
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(
+    packet_body<Packet>,
+    do_parse!(
+        flag:      be_u8 >>
+        timestamp: be_u32 >>
+                   cond!(flag & FLAG_EXTENDED_OPTIONS != 0, length_data!(be_u8)) >>
+        payload:   switch!(value!(flag >> 4),
+            SESSION_REQUEST   => map!(session_request, Payload::SessionRequest) |
+            SESSION_CREATED   => map!(session_created, Payload::SessionCreated) |
+            SESSION_CONFIRMED => map!(session_confirmed, Payload::SessionConfirmed) |
+            DATA              => map!(data, Payload::Data) |
+            SESSION_DESTROYED => value!(Payload::SessionDestroyed)
+        ) >>
+        (Packet { timestamp, payload })
+    )
+);
+
+fn gen_packet_body<'a>(
+    input: (&'a mut [u8], usize),
+    packet: &Packet,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let start = input.1;
+    let input = do_gen!(
+        input,
+        gen_be_u8!(packet.payload.payload_type() << 4) >> gen_be_u32!(packet.timestamp)
+    )?;
+    match &packet.payload {
+        Payload::SessionRequest(sr) => gen_session_request(input, sr),
+        Payload::SessionCreated(sc) => gen_session_created(input, sc),
+        Payload::SessionConfirmed(sc) => gen_session_confirmed(input, sc, start),
+        Payload::Data(data) => gen_data(input, data),
+        Payload::SessionDestroyed => Ok(input),
+    }
+}
+
+/// HMAC-MD5 of the encrypted part of the packet, the IV, and the length of
+/// the encrypted part. The length would be modified by the protocol version
+/// and network ID, but is unmodified for version 0 on the main network.
+fn packet_mac(key: &SessionKey, body: &[u8], iv: &[u8]) -> Hmac<Md5> {
+    let mut mac = Hmac::<Md5>::new_varkey(&key.0).expect("HMAC can take a key of any size");
+    mac.input(body);
+    mac.input(iv);
+    mac.input(&(body.len() as u16).to_be_bytes());
+    mac
+}
+
+impl Packet {
+    /// Encrypts and authenticates the packet.
+    pub fn encrypt(&self, iv: &[u8; IV_LEN], keys: &PacketKeys) -> Vec<u8> {
+        let mut body = serialize(|input| gen_packet_body(input, self));
+        let padding = (AES_BLOCK_SIZE - body.len() % AES_BLOCK_SIZE) % AES_BLOCK_SIZE;
+        let start = body.len();
+        body.resize(start + padding, 0);
+        OsRng.fill(&mut body[start..]);
+        Aes256::new(&keys.cipher, iv, iv).encrypt_blocks(&mut body);
+
+        let mac = packet_mac(&keys.mac, &body, iv).result().code();
+        let mut packet = Vec::with_capacity(HEADER_LEN + body.len());
+        packet.extend_from_slice(&mac);
+        packet.extend_from_slice(iv);
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    /// Authenticates and decrypts a received packet.
+    pub fn decrypt(buf: &[u8], keys: &PacketKeys) -> Result<Self, PacketError> {
+        if buf.len() < HEADER_LEN + AES_BLOCK_SIZE || (buf.len() - HEADER_LEN) % AES_BLOCK_SIZE != 0
+        {
+            return Err(PacketError::InvalidLength);
+        }
+
+        let (mac, rest) = buf.split_at(MAC_LEN);
+        let (iv, body) = rest.split_at(IV_LEN);
+        packet_mac(&keys.mac, body, iv)
+            .verify(mac)
+            .map_err(|_| PacketError::InvalidMac)?;
+
+        let mut body = body.to_vec();
+        Aes256::new(&keys.cipher, iv, iv).decrypt_blocks(&mut body);
+        match packet_body(&body) {
+            Ok((_, packet)) => Ok(packet),
+            Err(_) => Err(PacketError::Malformed),
+        }
+    }
+}
+
+/// Returns the IV of an encrypted packet.
+pub fn packet_iv(buf: &[u8]) -> Option<[u8; IV_LEN]> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+    Some(*array_ref![buf, MAC_LEN, IV_LEN])
+}
+
+/// Generates a random IV for a packet.
+pub fn random_iv() -> [u8; IV_LEN] {
+    let mut iv = [0; IV_LEN];
+    OsRng.fill(&mut iv);
+    iv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::RouterSecretKeys;
+
+    fn round_trip(payload: Payload) {
+        let packet = Packet {
+            timestamp: 0x1234_5678,
+            payload,
+        };
+        let keys = PacketKeys {
+            cipher: SessionKey([1; 32]),
+            mac: SessionKey([2; 32]),
+        };
+        let buf = packet.encrypt(&random_iv(), &keys);
+        assert_eq!((buf.len() - HEADER_LEN) % AES_BLOCK_SIZE, 0);
+        assert_eq!(Packet::decrypt(&buf, &keys), Ok(packet));
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        round_trip(Payload::SessionRequest(SessionRequest {
+            x: vec![3; 256],
+            bob_ip: [127, 0, 0, 1].into(),
+        }));
+
+        round_trip(Payload::SessionCreated(SessionCreated {
+            y: vec![4; 256],
+            alice_ip: "::1".parse().unwrap(),
+            alice_port: 12345,
+            relay_tag: 0,
+            signed_on: 42,
+            signature: vec![5; 64],
+        }));
+
+        let rsk = RouterSecretKeys::new();
+        round_trip(Payload::SessionConfirmed(SessionConfirmed {
+            signature: rsk.signing_private_key.sign(&[1, 2, 3]).unwrap(),
+            ri: rsk.rid,
+            signed_on: 42,
+        }));
+
+        round_trip(Payload::Data(Data {
+            acks: vec![1, 2],
+            partial_acks: vec![PartialAck {
+                msg_id: 3,
+                received: (0..14).map(|i| i % 3 == 0).collect(),
+            }],
+            fragments: vec![
+                DataFragment {
+                    msg_id: 4,
+                    num: 0,
+                    last: false,
+                    data: vec![6; 300],
+                },
+                DataFragment {
+                    msg_id: 5,
+                    num: 127,
+                    last: true,
+                    data: vec![7; 3],
+                },
+            ],
+        }));
+
+        round_trip(Payload::Data(Data::default()));
+        round_trip(Payload::SessionDestroyed);
+    }
+
+    #[test]
+    fn packet_authentication() {
+        let keys = PacketKeys::intro(&SessionKey([1; 32]));
+        let packet = Packet {
+            timestamp: 0,
+            payload: Payload::SessionDestroyed,
+        };
+        let buf = packet.encrypt(&random_iv(), &keys);
+
+        // Packets authenticated with a different key are rejected
+        let other = PacketKeys::intro(&SessionKey([2; 32]));
+        assert_eq!(Packet::decrypt(&buf, &other), Err(PacketError::InvalidMac));
+
+        // As are modified packets
+        let mut modified = buf.clone();
+        modified[HEADER_LEN] ^= 1;
+        assert_eq!(Packet::decrypt(&modified, &keys), Err(PacketError::InvalidMac));
+        assert_eq!(
+            Packet::decrypt(&buf[..buf.len() - 1], &keys),
+            Err(PacketError::InvalidLength)
+        );
+    }
+}
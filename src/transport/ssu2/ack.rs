@@ -0,0 +1,306 @@
+//! Packet numbers and acknowledgements.
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+/// Number of packet numbers below the highest received one that we keep track
+/// of. Older packets are treated as duplicates.
+const RECEIVE_WINDOW: u32 = 512;
+
+/// Maximum number of ranges we put in an ACK block.
+const MAX_ACK_RANGES: usize = 32;
+
+/// Acknowledges a set of received packets.
+///
+/// `through` is the highest packet number received, and `acnt` is the number
+/// of packets directly below it that were also received. Counting downwards
+/// from there, each range is a number of packets that were not received,
+/// followed by a number of packets that were.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AckBlock {
+    pub through: u32,
+    pub acnt: u8,
+    pub ranges: Vec<(u8, u8)>,
+}
+
+impl AckBlock {
+    /// Returns whether this block acknowledges the given packet.
+    pub fn acks(&self, pn: u32) -> bool {
+        let pn = i64::from(pn);
+        let mut top = i64::from(self.through);
+        let mut bottom = top - i64::from(self.acnt);
+        if pn > top {
+            return false;
+        }
+        if pn >= bottom {
+            return true;
+        }
+        for &(nacks, acks) in &self.ranges {
+            top = bottom - 1 - i64::from(nacks);
+            bottom = top + 1 - i64::from(acks);
+            if (bottom..=top).contains(&pn) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Appends a range to an ACK block, splitting it if the counts don't fit.
+fn push_range(ranges: &mut Vec<(u8, u8)>, mut nacks: u32, mut acks: u32) {
+    while nacks > 255 {
+        ranges.push((255, 0));
+        nacks -= 255;
+    }
+    let first = cmp::min(acks, 255);
+    ranges.push((nacks as u8, first as u8));
+    acks -= first;
+    while acks > 0 {
+        let next = cmp::min(acks, 255);
+        ranges.push((0, next as u8));
+        acks -= next;
+    }
+}
+
+/// Tracks the packets we have received, so that we can acknowledge them and
+/// drop duplicates.
+#[derive(Default)]
+pub struct ReceivedPackets {
+    received: BTreeSet<u32>,
+}
+
+impl ReceivedPackets {
+    pub fn new() -> Self {
+        ReceivedPackets::default()
+    }
+
+    fn highest(&self) -> Option<u32> {
+        self.received.iter().next_back().cloned()
+    }
+
+    /// Records a received packet.
+    ///
+    /// Returns false if the packet is a duplicate, or too old to tell.
+    pub fn receive(&mut self, pn: u32) -> bool {
+        if let Some(floor) = self.highest().and_then(|h| h.checked_sub(RECEIVE_WINDOW)) {
+            if pn <= floor {
+                return false;
+            }
+        }
+        if !self.received.insert(pn) {
+            return false;
+        }
+
+        if let Some(floor) = self.highest().and_then(|h| h.checked_sub(RECEIVE_WINDOW)) {
+            self.received = self.received.split_off(&(floor + 1));
+        }
+        true
+    }
+
+    /// Returns an ACK block for the packets received so far, or `None` if we
+    /// haven't received any.
+    pub fn ack_block(&self) -> Option<AckBlock> {
+        let mut received = self.received.iter().rev().cloned();
+        let through = received.next()?;
+
+        let mut acnt = 0;
+        let mut runs: Vec<(u32, u32)> = vec![];
+        let mut prev = through;
+        for pn in received {
+            let gap = prev - pn - 1;
+            if gap > 0 {
+                runs.push((gap, 1));
+            } else {
+                match runs.last_mut() {
+                    Some(run) => run.1 += 1,
+                    None => acnt += 1,
+                }
+            }
+            prev = pn;
+        }
+
+        let mut ranges = vec![];
+        if acnt > 255 {
+            push_range(&mut ranges, 0, acnt - 255);
+        }
+        for (nacks, acks) in runs {
+            push_range(&mut ranges, nacks, acks);
+        }
+        // Older packets will be retransmitted if they weren't acknowledged
+        // in an earlier block.
+        ranges.truncate(MAX_ACK_RANGES);
+
+        Some(AckBlock {
+            through,
+            acnt: cmp::min(acnt, 255) as u8,
+            ranges,
+        })
+    }
+}
+
+/// Tracks the packets we have sent until they are acknowledged.
+pub struct SentPackets<T> {
+    next_pn: u32,
+    unacked: BTreeMap<u32, (T, Instant)>,
+}
+
+impl<T> Default for SentPackets<T> {
+    fn default() -> Self {
+        SentPackets {
+            next_pn: 0,
+            unacked: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> SentPackets<T> {
+    pub fn new() -> Self {
+        SentPackets::default()
+    }
+
+    /// Records a packet with the given contents, and returns the packet number
+    /// to send it with.
+    pub fn send(&mut self, contents: T) -> u32 {
+        let pn = self.next_pn;
+        self.next_pn += 1;
+        self.unacked.insert(pn, (contents, Instant::now()));
+        pn
+    }
+
+    /// Forgets the packets acknowledged by the given block.
+    ///
+    /// Returns the number of packets that were newly acknowledged.
+    pub fn ack(&mut self, block: &AckBlock) -> usize {
+        let acked: Vec<_> = self
+            .unacked
+            .range(..=block.through)
+            .map(|(pn, _)| *pn)
+            .filter(|pn| block.acks(*pn))
+            .collect();
+        for pn in &acked {
+            self.unacked.remove(pn);
+        }
+        acked.len()
+    }
+
+    /// Removes the packets that have not been acknowledged within `timeout`,
+    /// and returns their contents so they can be sent again in new packets.
+    pub fn timed_out(&mut self, timeout: Duration) -> Vec<T> {
+        let expired: Vec<_> = self
+            .unacked
+            .iter()
+            .filter(|(_, (_, sent))| sent.elapsed() >= timeout)
+            .map(|(pn, _)| *pn)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|pn| self.unacked.remove(&pn))
+            .map(|(contents, _)| contents)
+            .collect()
+    }
+
+    /// Returns the number of packets that have not been acknowledged.
+    pub fn in_flight(&self) -> usize {
+        self.unacked.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AckBlock, ReceivedPackets, SentPackets, RECEIVE_WINDOW};
+
+    #[test]
+    fn ack_block_ranges() {
+        let mut received = ReceivedPackets::new();
+        assert_eq!(received.ack_block(), None);
+
+        for pn in &[0, 1, 2, 5, 6, 9, 10, 11, 12] {
+            assert!(received.receive(*pn));
+        }
+        assert!(!received.receive(5));
+
+        let block = received.ack_block().unwrap();
+        assert_eq!(
+            block,
+            AckBlock {
+                through: 12,
+                acnt: 3,
+                ranges: vec![(2, 2), (2, 3)],
+            }
+        );
+        for pn in 0..15 {
+            assert_eq!(block.acks(pn), [0, 1, 2, 5, 6, 9, 10, 11, 12].contains(&pn));
+        }
+    }
+
+    #[test]
+    fn ack_block_long_runs() {
+        let mut received = ReceivedPackets::new();
+        for pn in 0..300 {
+            received.receive(pn);
+        }
+        assert_eq!(
+            received.ack_block(),
+            Some(AckBlock {
+                through: 299,
+                acnt: 255,
+                ranges: vec![(0, 44)],
+            })
+        );
+
+        // Packets below the window are forgotten
+        received.receive(600);
+        let block = received.ack_block().unwrap();
+        assert_eq!(
+            block,
+            AckBlock {
+                through: 600,
+                acnt: 0,
+                ranges: vec![(255, 0), (45, 211)],
+            }
+        );
+        assert!(block.acks(299));
+        assert!(!block.acks(300));
+        assert!(block.acks(600 - RECEIVE_WINDOW + 1));
+        assert!(!block.acks(600 - RECEIVE_WINDOW));
+    }
+
+    #[test]
+    fn receive_window() {
+        let mut received = ReceivedPackets::new();
+        assert!(received.receive(1000));
+        assert!(received.receive(1000 - RECEIVE_WINDOW + 1));
+        assert!(!received.receive(1000 - RECEIVE_WINDOW));
+
+        // Moving the window forgets older packets
+        assert!(received.receive(1001));
+        assert!(!received.receive(1000 - RECEIVE_WINDOW + 1));
+    }
+
+    #[test]
+    fn sent_packets() {
+        let mut sent = SentPackets::new();
+        for i in 0..5 {
+            assert_eq!(sent.send(i * 10), i);
+        }
+
+        let block = AckBlock {
+            through: 3,
+            acnt: 0,
+            ranges: vec![(1, 2)],
+        };
+        assert_eq!(sent.ack(&block), 3);
+        assert_eq!(sent.ack(&block), 0);
+        assert_eq!(sent.in_flight(), 2);
+
+        assert!(sent.timed_out(Duration::from_secs(60)).is_empty());
+        assert_eq!(sent.timed_out(Duration::from_secs(0)), vec![20, 40]);
+        assert_eq!(sent.in_flight(), 0);
+
+        // Retransmissions get new packet numbers
+        assert_eq!(sent.send(20), 5);
+    }
+}
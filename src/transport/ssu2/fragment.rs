@@ -0,0 +1,411 @@
+//! Fragmentation and reassembly of I2NP messages.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::i2np::{
+    frame::{gen_ntcp2_message, ntcp2_message},
+    Message,
+};
+use crate::util::serialize;
+
+/// Length of the short I2NP header: type, message ID and expiration.
+const SHORT_HEADER_LEN: usize = 9;
+
+/// Follow-on fragments are numbered with seven bits, starting from 1.
+const MAX_FOLLOW_ON_FRAGMENTS: usize = 127;
+
+/// The most fragment data that a [`Reassembler`] buffers by default.
+const DEFAULT_MAX_BUFFERED: usize = 256 * 1024;
+
+/// How long a [`Reassembler`] waits by default for the rest of a message.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// A piece of an I2NP message, carried in an SSU2 packet.
+///
+/// Messages are serialized with the same short header as in NTCP2, which
+/// contains the message ID.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fragment {
+    /// A message that fits in a single packet.
+    Whole(Vec<u8>),
+    /// The start of a message, including its header.
+    First(Vec<u8>),
+    /// A later part of the message with the given ID.
+    FollowOn {
+        msg_id: u32,
+        num: u8,
+        last: bool,
+        data: Vec<u8>,
+    },
+}
+
+/// Splits a message into fragments carrying at most `max_len` bytes of it.
+///
+/// Returns `None` if the message would need more fragments than SSU2 allows,
+/// or if `max_len` is too small to hold the message header.
+pub fn fragment(msg: &Message, max_len: usize) -> Option<Vec<Fragment>> {
+    let data = serialize(|input| gen_ntcp2_message(input, msg));
+    if data.len() <= max_len {
+        return Some(vec![Fragment::Whole(data)]);
+    }
+    if max_len < SHORT_HEADER_LEN {
+        return None;
+    }
+
+    let mut chunks = data.chunks(max_len);
+    let first = chunks.next().unwrap();
+    let rest: Vec<_> = chunks.collect();
+    if rest.len() > MAX_FOLLOW_ON_FRAGMENTS {
+        return None;
+    }
+
+    let count = rest.len();
+    let mut fragments = Vec::with_capacity(count + 1);
+    fragments.push(Fragment::First(first.to_vec()));
+    for (i, chunk) in rest.into_iter().enumerate() {
+        fragments.push(Fragment::FollowOn {
+            msg_id: msg.id,
+            num: (i + 1) as u8,
+            last: i + 1 == count,
+            data: chunk.to_vec(),
+        });
+    }
+    Some(fragments)
+}
+
+fn parse_message(data: &[u8]) -> Option<Message> {
+    match ntcp2_message(data) {
+        Ok((_, msg)) => Some(msg),
+        Err(e) => {
+            debug!("Dropping invalid reassembled message: {:?}", e);
+            None
+        }
+    }
+}
+
+struct PartialMessage {
+    first: Option<Vec<u8>>,
+    follow_on: BTreeMap<u8, Vec<u8>>,
+    last: Option<u8>,
+    started: Instant,
+    /// Total length of the buffered fragments.
+    len: usize,
+}
+
+impl PartialMessage {
+    fn is_complete(&self) -> bool {
+        match (&self.first, self.last) {
+            (Some(_), Some(last)) => (1..=last).all(|num| self.follow_on.contains_key(&num)),
+            _ => false,
+        }
+    }
+
+    fn into_message(self) -> Option<Message> {
+        let last = self.last?;
+        let mut data = self.first?;
+        for (_, chunk) in self.follow_on.into_iter().take_while(|(num, _)| *num <= last) {
+            data.extend_from_slice(&chunk);
+        }
+        parse_message(&data)
+    }
+}
+
+/// Collects fragments until the messages they belong to are complete.
+///
+/// The fragments of incomplete messages are buffered up to a limit on their
+/// total size, and for a limited time. Once the buffer is full, the oldest
+/// messages are dropped to make room for new fragments.
+pub struct Reassembler {
+    partial: HashMap<u32, PartialMessage>,
+    buffered: usize,
+    max_buffered: usize,
+    max_age: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(DEFAULT_MAX_BUFFERED, DEFAULT_MAX_AGE)
+    }
+}
+
+impl Reassembler {
+    /// Buffers at most `max_buffered` bytes of fragments, and drops messages
+    /// that are still incomplete after `max_age`.
+    pub fn new(max_buffered: usize, max_age: Duration) -> Self {
+        Reassembler {
+            partial: HashMap::new(),
+            buffered: 0,
+            max_buffered,
+            max_age,
+        }
+    }
+
+    /// Returns the total size of the fragments of incomplete messages.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    fn remove(&mut self, msg_id: u32) -> Option<PartialMessage> {
+        let partial = self.partial.remove(&msg_id)?;
+        self.buffered -= partial.len;
+        Some(partial)
+    }
+
+    /// Makes room for `len` more bytes of fragments, dropping stale messages
+    /// and then the oldest ones. Returns false if there can't be enough room.
+    fn make_room(&mut self, len: usize) -> bool {
+        if len > self.max_buffered {
+            return false;
+        }
+        if self.buffered + len > self.max_buffered {
+            let max_age = self.max_age;
+            self.expire(max_age);
+        }
+        while self.buffered + len > self.max_buffered {
+            let oldest = self
+                .partial
+                .iter()
+                .min_by_key(|(_, p)| p.started)
+                .map(|(&msg_id, _)| msg_id);
+            match oldest {
+                Some(msg_id) => {
+                    debug!("Reassembly buffer is full, dropping message {}", msg_id);
+                    self.remove(msg_id);
+                }
+                None => break,
+            }
+        }
+        true
+    }
+
+    /// Returns the partial message with the given ID, starting it over if it
+    /// has been incomplete for too long.
+    fn partial(&mut self, msg_id: u32) -> &mut PartialMessage {
+        let stale = self
+            .partial
+            .get(&msg_id)
+            .map(|p| p.started.elapsed() >= self.max_age)
+            .unwrap_or(false);
+        if stale {
+            debug!("Dropping stale fragments of message {}", msg_id);
+            self.remove(msg_id);
+        }
+        self.partial
+            .entry(msg_id)
+            .or_insert_with(|| PartialMessage {
+                first: None,
+                follow_on: BTreeMap::new(),
+                last: None,
+                started: Instant::now(),
+                len: 0,
+            })
+    }
+
+    /// Adds a received fragment, in any order. Returns the message that the
+    /// fragment belongs to if it is now complete.
+    ///
+    /// Invalid fragments are ignored.
+    pub fn receive(&mut self, fragment: Fragment) -> Option<Message> {
+        let msg_id = match fragment {
+            Fragment::Whole(data) => return parse_message(&data),
+            Fragment::First(data) => {
+                if data.len() < SHORT_HEADER_LEN {
+                    debug!("Dropping truncated first fragment");
+                    return None;
+                }
+                let msg_id = u32::from_be_bytes(*array_ref![data, 1, 4]);
+                let len = data.len();
+                if !self.make_room(len) {
+                    debug!("Dropping first fragment of message {}, too long", msg_id);
+                    return None;
+                }
+                let partial = self.partial(msg_id);
+                let replaced = mem::replace(&mut partial.first, Some(data)).map_or(0, |d| d.len());
+                partial.len = partial.len + len - replaced;
+                self.buffered = self.buffered + len - replaced;
+                msg_id
+            }
+            Fragment::FollowOn {
+                msg_id,
+                num,
+                last,
+                data,
+            } => {
+                if num == 0 || num as usize > MAX_FOLLOW_ON_FRAGMENTS {
+                    debug!("Dropping follow-on fragment with invalid number {}", num);
+                    return None;
+                }
+                let len = data.len();
+                if !self.make_room(len) {
+                    debug!("Dropping follow-on fragment of message {}, too long", msg_id);
+                    return None;
+                }
+                let partial = self.partial(msg_id);
+                if last {
+                    partial.last = Some(num);
+                }
+                let replaced = partial.follow_on.insert(num, data).map_or(0, |d| d.len());
+                partial.len = partial.len + len - replaced;
+                self.buffered = self.buffered + len - replaced;
+                msg_id
+            }
+        };
+
+        if self.partial[&msg_id].is_complete() {
+            self.remove(msg_id).and_then(PartialMessage::into_message)
+        } else {
+            None
+        }
+    }
+
+    /// Drops messages that have been incomplete for longer than `timeout`.
+    ///
+    /// Returns the number of messages dropped.
+    pub fn expire(&mut self, timeout: Duration) -> usize {
+        let before = self.partial.len();
+        let buffered = &mut self.buffered;
+        self.partial.retain(|_, p| {
+            let keep = p.started.elapsed() < timeout;
+            if !keep {
+                *buffered -= p.len;
+            }
+            keep
+        });
+        before - self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{fragment, Fragment, Reassembler};
+    use crate::i2np::{Message, MessagePayload};
+
+    fn data_message(len: usize) -> Message {
+        Message::from_payload(MessagePayload::Data((0..len).map(|i| i as u8).collect()))
+    }
+
+    fn assert_data(msg: Option<Message>, len: usize) {
+        match msg.map(|m| m.payload) {
+            Some(MessagePayload::Data(data)) => {
+                assert_eq!(data, (0..len).map(|i| i as u8).collect::<Vec<_>>())
+            }
+            _ => panic!("Expected a reassembled Data message"),
+        }
+    }
+
+    #[test]
+    fn fragment_sizes() {
+        let msg = data_message(1000);
+
+        // Header + Data length + data
+        let frags = fragment(&msg, 1013).unwrap();
+        assert_eq!(frags.len(), 1);
+        match frags[0] {
+            Fragment::Whole(ref data) => assert_eq!(data.len(), 1013),
+            _ => panic!("Expected a whole message"),
+        }
+
+        let frags = fragment(&msg, 100).unwrap();
+        assert_eq!(frags.len(), 11);
+        match (&frags[1], &frags[10]) {
+            (
+                Fragment::FollowOn {
+                    num: 1,
+                    last: false,
+                    ..
+                },
+                Fragment::FollowOn {
+                    num: 10,
+                    last: true,
+                    data,
+                    ..
+                },
+            ) => assert_eq!(data.len(), 13),
+            _ => panic!("Unexpected follow-on fragments"),
+        }
+
+        // Too many fragments, or no room for the header
+        assert!(fragment(&msg, 7).is_none());
+        assert!(fragment(&data_message(127 * 9), 9).is_none());
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let mut frags = fragment(&data_message(1000), 100).unwrap();
+        let first = frags.remove(0);
+        let mut reassembler = Reassembler::default();
+
+        // Follow-on fragments in reverse, with a duplicate
+        let dup = frags[3].clone();
+        for frag in frags.into_iter().rev() {
+            assert!(reassembler.receive(frag).is_none());
+        }
+        assert!(reassembler.receive(dup).is_none());
+
+        // The message is complete once the first fragment arrives
+        assert_data(reassembler.receive(first), 1000);
+        assert_eq!(reassembler.expire(Duration::from_secs(0)), 0);
+    }
+
+    #[test]
+    fn expire_incomplete() {
+        let mut reassembler = Reassembler::default();
+        let mut frags = fragment(&data_message(1000), 100).unwrap();
+        frags.pop();
+        for frag in frags {
+            assert!(reassembler.receive(frag).is_none());
+        }
+
+        assert_eq!(reassembler.expire(Duration::from_secs(60)), 0);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(reassembler.expire(Duration::from_millis(5)), 1);
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn buffer_size_limit() {
+        let mut reassembler = Reassembler::new(250, Duration::from_secs(60));
+        let mut frags_a = fragment(&data_message(1000), 100).unwrap();
+        let mut frags_b = fragment(&data_message(1000), 100).unwrap();
+
+        // Duplicates don't count twice
+        let first_a = frags_a.remove(0);
+        assert!(reassembler.receive(first_a.clone()).is_none());
+        assert!(reassembler.receive(first_a).is_none());
+        assert!(reassembler.receive(frags_a.remove(0)).is_none());
+        assert_eq!(reassembler.buffered(), 200);
+
+        // The oldest message is dropped to make room
+        assert!(reassembler.receive(frags_b.remove(0)).is_none());
+        assert!(reassembler.receive(frags_b.remove(0)).is_none());
+        assert_eq!(reassembler.buffered(), 200);
+        for frag in frags_a {
+            assert!(reassembler.receive(frag).is_none());
+        }
+
+        // Fragments that can never fit are dropped
+        let mut reassembler = Reassembler::new(50, Duration::from_secs(60));
+        assert!(reassembler.receive(frags_b.remove(0)).is_none());
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn buffer_age_limit() {
+        let mut reassembler = Reassembler::new(10_000, Duration::from_millis(5));
+        let mut frags = fragment(&data_message(1000), 100).unwrap();
+        let first = frags.remove(0);
+        for frag in frags {
+            assert!(reassembler.receive(frag).is_none());
+        }
+
+        // The rest of the message arrives too late
+        thread::sleep(Duration::from_millis(10));
+        assert!(reassembler.receive(first).is_none());
+        assert_eq!(reassembler.buffered(), 100);
+    }
+}
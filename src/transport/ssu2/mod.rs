@@ -0,0 +1,83 @@
+//! A transport protocol over UDP, using the same Noise-based key agreement as
+//! NTCP2.
+//!
+//! UDP provides neither reliability nor ordering, so SSU2 adds its own in the
+//! data phase:
+//!
+//! - I2NP messages that don't fit in a single packet are split into a first
+//!   fragment and up to 127 follow-on fragments, which may arrive in any order
+//!   and are reassembled by the receiver.
+//!
+//! - Every packet carries a packet number. The receiver reports the packet
+//!   numbers it has seen in ACK blocks, and the sender retransmits the contents
+//!   of packets that are not acknowledged in time. Retransmitted contents are
+//!   sent in new packets, with new packet numbers.
+//!
+//! Firewalled routers are reached through introducers: reachable routers that
+//! relay a connecting router's request to them.
+//!
+//! This module currently implements only the reliability layer, and the
+//! introducer's side of relaying.
+//!
+//! [SSU2 specification](https://geti2p.net/spec/ssu2)
+
+mod ack;
+mod fragment;
+mod relay;
+
+pub use self::ack::{AckBlock, ReceivedPackets, SentPackets};
+pub use self::fragment::{fragment, Fragment, Reassembler};
+pub use self::relay::{
+    Introducer, RelayAction, RelayIntro, RelayRequest, RelayResponse, RELAY_ACCEPT,
+    RELAY_REJECT_LIMIT_EXCEEDED, RELAY_REJECT_UNKNOWN_TAG,
+};
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{fragment, Fragment, Reassembler, ReceivedPackets, SentPackets};
+    use crate::i2np::{Message, MessagePayload};
+
+    #[test]
+    fn lossy_out_of_order_delivery() {
+        let payload: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        let msg = Message::from_payload(MessagePayload::Data(payload.clone()));
+
+        let mut sent = SentPackets::new();
+        let mut received = ReceivedPackets::new();
+        let mut reassembler = Reassembler::default();
+
+        // Send one fragment per packet, losing the second packet
+        let mut packets: Vec<(u32, Fragment)> = fragment(&msg, 1000)
+            .unwrap()
+            .into_iter()
+            .map(|frag| (sent.send(frag.clone()), frag))
+            .collect();
+        assert_eq!(packets.len(), 5);
+        packets.remove(1);
+
+        // The rest arrive in reverse order, with a duplicate
+        let dup = packets[0].clone();
+        for (pn, frag) in packets.into_iter().rev().chain(Some(dup)) {
+            if received.receive(pn) {
+                assert!(reassembler.receive(frag).is_none());
+            }
+        }
+
+        // The lost packet isn't acknowledged, so it is sent again
+        assert_eq!(sent.ack(&received.ack_block().unwrap()), 4);
+        let resend = sent.timed_out(Duration::from_secs(0));
+        assert_eq!(resend.len(), 1);
+        let pn = sent.send(resend[0].clone());
+        assert_eq!(pn, 5);
+
+        assert!(received.receive(pn));
+        match reassembler.receive(resend[0].clone()).map(|m| m.payload) {
+            Some(MessagePayload::Data(data)) => assert_eq!(data, payload),
+            _ => panic!("Message should have been reassembled"),
+        }
+        assert_eq!(sent.ack(&received.ack_block().unwrap()), 1);
+        assert_eq!(sent.in_flight(), 0);
+    }
+}
@@ -0,0 +1,301 @@
+//! Introductions of firewalled peers.
+//!
+//! A firewalled router (Charlie) can't receive unsolicited packets, so it asks
+//! a reachable router (Bob) for a relay tag, and publishes Bob and the tag as
+//! an introducer in its RouterInfo. A router that wants to connect to Charlie
+//! (Alice) sends Bob a RelayRequest containing the tag. Bob passes it on to
+//! Charlie in a RelayIntro, and forwards Charlie's RelayResponse back to Alice,
+//! after which Alice and Charlie can hole-punch a connection.
+//!
+//! This module implements Bob's side of the exchange, for the SSU2 session
+//! layer to drive. A router that services introductions advertises it with
+//! [`RouterAddress::set_introducer`] on its SSU2 address.
+//!
+//! [`RouterAddress::set_introducer`]: crate::data::RouterAddress::set_introducer
+
+use rand::{rngs::OsRng, Rng};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::data::Hash;
+
+/// Charlie accepted the introduction.
+pub const RELAY_ACCEPT: u8 = 0;
+
+/// Bob rejected the introduction, because he has too many in progress.
+pub const RELAY_REJECT_LIMIT_EXCEEDED: u8 = 3;
+
+/// Bob rejected the introduction, because he has no peer with the given tag.
+pub const RELAY_REJECT_UNKNOWN_TAG: u8 = 5;
+
+/// How long we wait for Charlie to respond to an introduction.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most introductions that we wait for responses to at once.
+const MAX_PENDING_INTROS: usize = 1024;
+
+/// A request from Alice to be introduced to the peer with the given relay tag.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayRequest {
+    pub nonce: u32,
+    pub tag: u32,
+    /// The rest of the request (Alice's address and signature), which only
+    /// Charlie needs to read.
+    pub data: Vec<u8>,
+}
+
+/// Alice's request, passed on to Charlie.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayIntro {
+    pub alice: Hash,
+    pub request: RelayRequest,
+}
+
+/// The outcome of an introduction, sent to Alice.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayResponse {
+    pub nonce: u32,
+    pub code: u8,
+    /// The rest of the response (Charlie's address and signature, and a
+    /// token), which only Alice needs to read.
+    pub data: Vec<u8>,
+}
+
+/// What to do with a RelayRequest.
+#[derive(Debug, PartialEq)]
+pub enum RelayAction {
+    /// Send the intro to Charlie.
+    Introduce { charlie: Hash, intro: RelayIntro },
+    /// Send the response to Alice.
+    Respond {
+        alice: Hash,
+        response: RelayResponse,
+    },
+}
+
+struct PendingIntro {
+    charlie: Hash,
+    sent: Instant,
+}
+
+/// The peers we are an introducer for, and the introductions in progress.
+pub struct Introducer {
+    tags: HashMap<u32, Hash>,
+    peers: HashMap<Hash, u32>,
+    /// Keyed by Alice and her nonce, so that one Alice can't interfere with
+    /// another's introductions by reusing its nonce.
+    pending: HashMap<(Hash, u32), PendingIntro>,
+    max_pending: usize,
+}
+
+impl Default for Introducer {
+    fn default() -> Self {
+        Introducer::new()
+    }
+}
+
+impl Introducer {
+    pub fn new() -> Self {
+        Introducer {
+            tags: HashMap::new(),
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+            max_pending: MAX_PENDING_INTROS,
+        }
+    }
+
+    /// Returns the relay tag for the given peer, assigning it a new one if
+    /// necessary.
+    pub fn assign_tag(&mut self, charlie: Hash) -> u32 {
+        if let Some(&tag) = self.peers.get(&charlie) {
+            return tag;
+        }
+
+        let mut rng = OsRng;
+        let tag = loop {
+            let tag: u32 = rng.gen();
+            if tag != 0 && !self.tags.contains_key(&tag) {
+                break tag;
+            }
+        };
+        self.tags.insert(tag, charlie.clone());
+        self.peers.insert(charlie, tag);
+        tag
+    }
+
+    /// Forgets the relay tag for a peer, when our session with it ends.
+    pub fn remove(&mut self, charlie: &Hash) {
+        if let Some(tag) = self.peers.remove(charlie) {
+            self.tags.remove(&tag);
+        }
+    }
+
+    /// Handles a RelayRequest from Alice.
+    pub fn relay_request(&mut self, alice: Hash, request: RelayRequest) -> RelayAction {
+        self.expire();
+
+        let reject = |alice, code| RelayAction::Respond {
+            alice,
+            response: RelayResponse {
+                nonce: request.nonce,
+                code,
+                data: vec![],
+            },
+        };
+        let charlie = match self.tags.get(&request.tag) {
+            Some(charlie) => charlie.clone(),
+            None => return reject(alice, RELAY_REJECT_UNKNOWN_TAG),
+        };
+        let key = (alice.clone(), request.nonce);
+        if self.pending.len() >= self.max_pending && !self.pending.contains_key(&key) {
+            return reject(alice, RELAY_REJECT_LIMIT_EXCEEDED);
+        }
+
+        self.pending.insert(
+            key,
+            PendingIntro {
+                charlie: charlie.clone(),
+                sent: Instant::now(),
+            },
+        );
+        RelayAction::Introduce {
+            charlie,
+            intro: RelayIntro { alice, request },
+        }
+    }
+
+    /// Handles a RelayResponse from Charlie, returning the peer to forward it
+    /// to.
+    ///
+    /// Returns `None` if we didn't send Charlie an intro with this nonce.
+    pub fn relay_response(&mut self, charlie: &Hash, response: &RelayResponse) -> Option<Hash> {
+        self.expire();
+
+        let key = self
+            .pending
+            .iter()
+            .find(|((_, nonce), pending)| *nonce == response.nonce && &pending.charlie == charlie)
+            .map(|(key, _)| key.clone())?;
+        self.pending.remove(&key);
+        Some(key.0)
+    }
+
+    fn expire(&mut self) {
+        self.pending.retain(|_, p| p.sent.elapsed() < RELAY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assign_tag() {
+        let charlie = Hash([3; 32]);
+        let mut introducer = Introducer::new();
+
+        let tag = introducer.assign_tag(charlie.clone());
+        assert_ne!(tag, 0);
+        assert_eq!(introducer.assign_tag(charlie.clone()), tag);
+        assert_ne!(introducer.assign_tag(Hash([4; 32])), tag);
+
+        introducer.remove(&charlie);
+        assert!(!introducer.tags.contains_key(&tag));
+    }
+
+    #[test]
+    fn relay_request() {
+        let alice = Hash([1; 32]);
+        let charlie = Hash([3; 32]);
+        let mut introducer = Introducer::new();
+        let tag = introducer.assign_tag(charlie.clone());
+
+        let request = RelayRequest {
+            nonce: 42,
+            tag,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(
+            introducer.relay_request(alice.clone(), request.clone()),
+            RelayAction::Introduce {
+                charlie: charlie.clone(),
+                intro: RelayIntro {
+                    alice: alice.clone(),
+                    request,
+                },
+            }
+        );
+
+        // Only Charlie's response is forwarded, and only once
+        let response = RelayResponse {
+            nonce: 42,
+            code: RELAY_ACCEPT,
+            data: vec![4, 5, 6],
+        };
+        assert_eq!(introducer.relay_response(&alice, &response), None);
+        assert_eq!(
+            introducer.relay_response(&charlie, &response),
+            Some(alice.clone())
+        );
+        assert_eq!(introducer.relay_response(&charlie, &response), None);
+
+        // Unknown tags are rejected
+        let request = RelayRequest {
+            nonce: 43,
+            tag: tag.wrapping_add(1),
+            data: vec![],
+        };
+        assert_eq!(
+            introducer.relay_request(alice.clone(), request),
+            RelayAction::Respond {
+                alice,
+                response: RelayResponse {
+                    nonce: 43,
+                    code: RELAY_REJECT_UNKNOWN_TAG,
+                    data: vec![],
+                },
+            }
+        );
+    }
+    #[test]
+    fn pending_intros() {
+        let alice = Hash([1; 32]);
+        let mallory = Hash([2; 32]);
+        let charlie = Hash([3; 32]);
+        let mut introducer = Introducer::new();
+        introducer.max_pending = 2;
+        let tag = introducer.assign_tag(charlie.clone());
+        let request = |nonce| RelayRequest {
+            nonce,
+            tag,
+            data: vec![],
+        };
+
+        // Another peer reusing Alice's nonce doesn't take over her introduction
+        introducer.relay_request(alice.clone(), request(42));
+        introducer.relay_request(mallory.clone(), request(42));
+        let response = RelayResponse {
+            nonce: 42,
+            code: RELAY_ACCEPT,
+            data: vec![],
+        };
+        let first = introducer.relay_response(&charlie, &response).unwrap();
+        let second = introducer.relay_response(&charlie, &response).unwrap();
+        assert_ne!(first, second);
+
+        // Once the limit is reached, further introductions are rejected
+        introducer.relay_request(alice.clone(), request(1));
+        introducer.relay_request(alice.clone(), request(2));
+        assert_eq!(
+            introducer.relay_request(alice.clone(), request(3)),
+            RelayAction::Respond {
+                alice,
+                response: RelayResponse {
+                    nonce: 3,
+                    code: RELAY_REJECT_LIMIT_EXCEEDED,
+                    data: vec![],
+                },
+            }
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Router-wide traffic statistics.
+//!
+//! Sessions on every transport feed the bytes and I2NP messages they send and
+//! receive into a shared [`TrafficStats`], which keeps a total for each of the
+//! last fifteen minutes' seconds, and averages them over rolling windows.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The longest window we average over, in seconds.
+const MAX_WINDOW: u64 = 15 * 60;
+
+/// Per-second totals of some quantity, over the last [`MAX_WINDOW`] seconds.
+struct RollingWindow {
+    /// The totals, oldest first. The last one is for the current second.
+    totals: VecDeque<u64>,
+    /// The second that the last total is for.
+    current: u64,
+}
+
+impl Default for RollingWindow {
+    fn default() -> Self {
+        let mut totals = VecDeque::with_capacity(MAX_WINDOW as usize);
+        totals.push_back(0);
+        RollingWindow { totals, current: 0 }
+    }
+}
+
+impl RollingWindow {
+    /// Starts new totals for each second up to and including `second`.
+    fn advance(&mut self, second: u64) {
+        let elapsed = cmp::min(second.saturating_sub(self.current), MAX_WINDOW);
+        for _ in 0..elapsed {
+            if self.totals.len() == MAX_WINDOW as usize {
+                self.totals.pop_front();
+            }
+            self.totals.push_back(0);
+        }
+        self.current = cmp::max(self.current, second);
+    }
+
+    fn add(&mut self, second: u64, n: u64) {
+        self.advance(second);
+        *self.totals.back_mut().unwrap() += n;
+    }
+
+    /// Returns the average per second over the `window` seconds ending with
+    /// `second`.
+    fn average(&mut self, second: u64, window: u64) -> f64 {
+        self.advance(second);
+        let total: u64 = self.totals.iter().rev().take(window as usize).sum();
+        total as f64 / window as f64
+    }
+
+    fn throughput(&mut self, second: u64) -> Throughput {
+        Throughput {
+            one_minute: self.average(second, 60),
+            five_minutes: self.average(second, 5 * 60),
+            fifteen_minutes: self.average(second, 15 * 60),
+        }
+    }
+}
+
+/// Average rates, per second, over the last one, five and fifteen minutes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Throughput {
+    pub one_minute: f64,
+    pub five_minutes: f64,
+    pub fifteen_minutes: f64,
+}
+
+/// The throughput of every session on the router.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrafficSummary {
+    pub bytes_received: Throughput,
+    pub bytes_sent: Throughput,
+    pub messages_received: Throughput,
+    pub messages_sent: Throughput,
+    /// How often sessions had to wait to read, because the inbound bandwidth
+    /// limit had been reached.
+    pub throttled_reads: Throughput,
+    /// How often sessions had to wait to write, because the outbound bandwidth
+    /// limit had been reached.
+    pub throttled_writes: Throughput,
+}
+
+struct Inner {
+    start: Instant,
+    bytes_received: RollingWindow,
+    bytes_sent: RollingWindow,
+    messages_received: RollingWindow,
+    messages_sent: RollingWindow,
+    throttled_reads: RollingWindow,
+    throttled_writes: RollingWindow,
+}
+
+impl Inner {
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs()
+    }
+}
+
+/// Traffic statistics, shared between every session on the router.
+#[derive(Clone)]
+pub(super) struct TrafficStats(Arc<Mutex<Inner>>);
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        TrafficStats::new(Instant::now())
+    }
+}
+
+impl TrafficStats {
+    fn new(start: Instant) -> Self {
+        TrafficStats(Arc::new(Mutex::new(Inner {
+            start,
+            bytes_received: RollingWindow::default(),
+            bytes_sent: RollingWindow::default(),
+            messages_received: RollingWindow::default(),
+            messages_sent: RollingWindow::default(),
+            throttled_reads: RollingWindow::default(),
+            throttled_writes: RollingWindow::default(),
+        })))
+    }
+
+    fn record<F>(&self, now: Instant, n: u64, window: F)
+    where
+        F: FnOnce(&mut Inner) -> &mut RollingWindow,
+    {
+        let mut inner = self.0.lock().unwrap();
+        let second = inner.second(now);
+        window(&mut inner).add(second, n);
+    }
+
+    pub(super) fn received_bytes(&self, n: usize) {
+        self.record(Instant::now(), n as u64, |s| &mut s.bytes_received);
+    }
+
+    pub(super) fn sent_bytes(&self, n: usize) {
+        self.record(Instant::now(), n as u64, |s| &mut s.bytes_sent);
+    }
+
+    pub(super) fn received_message(&self) {
+        self.record(Instant::now(), 1, |s| &mut s.messages_received);
+    }
+
+    pub(super) fn sent_message(&self) {
+        self.record(Instant::now(), 1, |s| &mut s.messages_sent);
+    }
+
+    pub(super) fn throttled_read(&self) {
+        self.record(Instant::now(), 1, |s| &mut s.throttled_reads);
+    }
+
+    pub(super) fn throttled_write(&self) {
+        self.record(Instant::now(), 1, |s| &mut s.throttled_writes);
+    }
+
+    fn summary_at(&self, now: Instant) -> TrafficSummary {
+        let mut inner = self.0.lock().unwrap();
+        let second = inner.second(now);
+        TrafficSummary {
+            bytes_received: inner.bytes_received.throughput(second),
+            bytes_sent: inner.bytes_sent.throughput(second),
+            messages_received: inner.messages_received.throughput(second),
+            messages_sent: inner.messages_sent.throughput(second),
+            throttled_reads: inner.throttled_reads.throughput(second),
+            throttled_writes: inner.throttled_writes.throughput(second),
+        }
+    }
+
+    /// Returns the average throughput over the last one, five and fifteen
+    /// minutes.
+    pub(super) fn summary(&self) -> TrafficSummary {
+        self.summary_at(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TrafficStats;
+
+    #[test]
+    fn rolling_averages() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let stats = TrafficStats::new(start);
+
+        // 1500 bytes a second for a minute
+        for secs in 0..60 {
+            stats.record(at(secs), 1000, |s| &mut s.bytes_received);
+            stats.record(at(secs), 500, |s| &mut s.bytes_received);
+        }
+        stats.record(at(59), 1, |s| &mut s.messages_sent);
+
+        let summary = stats.summary_at(at(59));
+        assert_eq!(summary.bytes_received.one_minute, 1500.0);
+        assert_eq!(summary.bytes_received.five_minutes, 300.0);
+        assert_eq!(summary.bytes_received.fifteen_minutes, 100.0);
+        assert_eq!(summary.bytes_sent.one_minute, 0.0);
+        assert_eq!(summary.messages_sent.one_minute, 1.0 / 60.0);
+
+        // Half of the traffic has left the 1-minute window
+        let summary = stats.summary_at(at(89));
+        assert_eq!(summary.bytes_received.one_minute, 750.0);
+        assert_eq!(summary.bytes_received.five_minutes, 300.0);
+
+        // All of it has left the 15-minute window
+        let summary = stats.summary_at(at(15 * 60 + 59));
+        assert_eq!(summary.bytes_received.one_minute, 0.0);
+        assert_eq!(summary.bytes_received.fifteen_minutes, 0.0);
+    }
+}
@@ -5,11 +5,16 @@ use std::time::SystemTime;
 use crate::data::{Hash, RouterInfo, TunnelId};
 
 mod acceptor;
+mod bandwidth;
 mod encryption;
 mod frame;
+mod gateway;
+mod pool;
 mod processor;
 
 pub use self::acceptor::Listener;
+pub use self::bandwidth::ParticipatingBandwidth;
+pub use self::pool::TunnelPool;
 pub use self::processor::Participant;
 
 /// The lifetime of a tunnel. Always 10 minutes for current I2P tunnels.
@@ -43,7 +48,7 @@ impl PartialEq for HopConfig {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 enum TunnelMessageDeliveryType {
     Local,
     Tunnel(TunnelId, Hash),
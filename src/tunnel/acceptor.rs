@@ -9,7 +9,9 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::{io, spawn};
 use tokio_threadpool::blocking;
 
-use super::{encryption::LayerCipher, HopConfig, HopData, TUNNEL_LIFETIME};
+use super::{
+    bandwidth::ParticipatingBandwidth, encryption::LayerCipher, HopConfig, HopData, TUNNEL_LIFETIME,
+};
 use crate::crypto::{elgamal, SessionKey};
 use crate::data::{Hash, RouterInfo, TunnelId};
 use crate::i2np::{
@@ -135,10 +137,12 @@ struct HopAcceptor<TB: TunnelBuildRequest> {
     decryptor: elgamal::Decryptor,
     filter: Arc<Mutex<DecayingBloomFilter>>,
     new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
+    bandwidth: ParticipatingBandwidth,
     ctx: Arc<Context>,
 }
 
 impl<TB: TunnelBuildRequest> HopAcceptor<TB> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         from: Hash,
         tb: TB,
@@ -146,6 +150,7 @@ impl<TB: TunnelBuildRequest> HopAcceptor<TB> {
         decryptor: elgamal::Decryptor,
         filter: Arc<Mutex<DecayingBloomFilter>>,
         new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
+        bandwidth: ParticipatingBandwidth,
         ctx: Arc<Context>,
     ) -> Self {
         HopAcceptor {
@@ -153,11 +158,21 @@ impl<TB: TunnelBuildRequest> HopAcceptor<TB> {
             decryptor,
             filter,
             new_participating_tx,
+            bandwidth,
             ctx,
         }
     }
 }
 
+/// Decides how to respond to a request to participate in a tunnel.
+fn build_reply(hop_type: &ParticipantType, bandwidth: &ParticipatingBandwidth) -> u8 {
+    match hop_type {
+        ParticipantType::Intermediate if bandwidth.is_saturated() => TUNNEL_REJECT_BANDWIDTH,
+        ParticipantType::Intermediate => TUNNEL_ACCEPT,
+        _ => TUNNEL_REJECT_CRIT,
+    }
+}
+
 impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
     type Item = ();
     type Error = ();
@@ -260,10 +275,7 @@ impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
 
                     // Decide whether to accept or reject
                     // TODO: Add support for IBGW, OBEP, metrics
-                    let reply = match brr.hop_type {
-                        ParticipantType::Intermediate => TUNNEL_ACCEPT,
-                        _ => TUNNEL_REJECT_CRIT,
-                    };
+                    let reply = build_reply(&brr.hop_type, &self.bandwidth);
 
                     // Prepare the information necessary to forward the response
                     let info = EncryptionInfo {
@@ -381,6 +393,7 @@ pub struct Listener {
     decryptor: elgamal::Decryptor,
     filter: Arc<Mutex<DecayingBloomFilter>>,
     new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
+    bandwidth: ParticipatingBandwidth,
     ib_rx: mpsc::Receiver<(Hash, Message)>,
     ctx: Arc<Context>,
 }
@@ -389,6 +402,7 @@ impl Listener {
     pub fn new(
         ctx: Arc<Context>,
         new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
+        bandwidth: ParticipatingBandwidth,
         ib_rx: mpsc::Receiver<(Hash, Message)>,
     ) -> Self {
         Listener {
@@ -396,6 +410,7 @@ impl Listener {
             decryptor: elgamal::Decryptor::from(&ctx.keys.private_key),
             filter: Arc::new(Mutex::new(DecayingBloomFilter::new(20_000))),
             new_participating_tx,
+            bandwidth,
             ib_rx,
             ctx,
         }
@@ -430,6 +445,7 @@ impl Future for Listener {
                                 self.decryptor.clone(),
                                 self.filter.clone(),
                                 self.new_participating_tx.clone(),
+                                self.bandwidth.clone(),
                                 self.ctx.clone(),
                             ));
                         }
@@ -444,6 +460,7 @@ impl Future for Listener {
                                 self.decryptor.clone(),
                                 self.filter.clone(),
                                 self.new_participating_tx.clone(),
+                                self.bandwidth.clone(),
                                 self.ctx.clone(),
                             ));
                         }
@@ -466,16 +483,45 @@ mod tests {
     use std::sync::{Arc, Mutex};
     use tokio_threadpool::Builder;
 
-    use super::HopAcceptor;
+    use super::{
+        build_reply, HopAcceptor, TUNNEL_ACCEPT, TUNNEL_REJECT_BANDWIDTH, TUNNEL_REJECT_CRIT,
+    };
     use crate::{
         crypto::elgamal,
         data::{Hash, RouterInfo, RouterSecretKeys, TunnelId},
         i2np::{BuildRequestRecord, ParticipantType},
         router::mock::{mock_context, mock_context_and_netdb},
-        tunnel::HopData,
+        tunnel::{HopData, ParticipatingBandwidth},
         util::DecayingBloomFilter,
     };
 
+    #[test]
+    fn build_reply_bandwidth() {
+        let bandwidth = ParticipatingBandwidth::new(100_000);
+        assert_eq!(
+            build_reply(&ParticipantType::Intermediate, &bandwidth),
+            TUNNEL_ACCEPT
+        );
+        assert_eq!(
+            build_reply(&ParticipantType::OutboundEndpoint, &bandwidth),
+            TUNNEL_REJECT_CRIT
+        );
+
+        // Use up the bandwidth with transit traffic
+        while bandwidth.consume(1028) {}
+        assert_eq!(
+            build_reply(&ParticipantType::Intermediate, &bandwidth),
+            TUNNEL_REJECT_BANDWIDTH
+        );
+
+        // Without a limit, participation isn't limited
+        let unlimited = ParticipatingBandwidth::default();
+        assert_eq!(
+            build_reply(&ParticipantType::Intermediate, &unlimited),
+            TUNNEL_ACCEPT
+        );
+    }
+
     #[test]
     #[ignore]
     fn accepted_intermediate_build_request() {
@@ -521,6 +567,7 @@ mod tests {
             decryptor,
             filter,
             new_participating_tx,
+            ParticipatingBandwidth::default(),
             ctx,
         );
 
@@ -594,6 +641,7 @@ mod tests {
             decryptor,
             filter,
             new_participating_tx,
+            ParticipatingBandwidth::default(),
             ctx,
         );
 
@@ -644,6 +692,7 @@ mod tests {
             decryptor,
             filter,
             new_participating_tx,
+            ParticipatingBandwidth::default(),
             ctx,
         );
 
@@ -0,0 +1,134 @@
+//! Limits on the bandwidth used by tunnels that we participate in.
+//!
+//! Transit traffic draws from a token bucket that refills at a configured share
+//! of the transport bandwidth limits. Once it is empty, transit messages are
+//! dropped, and we reject requests to participate in new tunnels until it has
+//! refilled. Traffic for our own tunnels doesn't count towards the limit, and
+//! always has the rest of the transport bandwidth to itself.
+
+use crate::router::config::{self, Config};
+use crate::util::TokenBucket;
+
+/// The percentage of the transport bandwidth that participating tunnels may
+/// use, if the config doesn't say.
+const DEFAULT_SHARE: u64 = 80;
+
+/// The bandwidth available for participating tunnels, shared between the
+/// tunnel build acceptor and the participant.
+#[derive(Clone, Default)]
+pub struct ParticipatingBandwidth {
+    bucket: TokenBucket,
+    /// Bytes per second, or 0 if unlimited.
+    rate: u64,
+}
+
+impl ParticipatingBandwidth {
+    /// Allows participating tunnels to use at most `rate` bytes per second.
+    /// At most a second's worth of unused bandwidth is saved up.
+    pub fn new(rate: u64) -> Self {
+        ParticipatingBandwidth {
+            bucket: TokenBucket::new(rate, rate),
+            rate,
+        }
+    }
+
+    /// Reads the share from the router config, and applies it to the lower of
+    /// the transport bandwidth limits, since transit traffic is both received
+    /// and sent. If the transports aren't limited, neither are participating
+    /// tunnels.
+    pub fn from_config(config: &Config) -> Self {
+        // Checked by config::Validate
+        let share = config
+            .get_int(config::TUNNEL_PARTICIPATING_SHARE)
+            .map(|percent| percent as u64)
+            .unwrap_or(DEFAULT_SHARE);
+        let limit = [
+            config::TRANSPORT_INBOUND_BANDWIDTH,
+            config::TRANSPORT_OUTBOUND_BANDWIDTH,
+        ]
+        .iter()
+        .filter_map(|&key| config.get_int(key).ok())
+        .map(|kbps| kbps as u64 * 1024)
+        .min();
+        match limit {
+            Some(rate) => ParticipatingBandwidth::new(rate * share / 100),
+            None => ParticipatingBandwidth::default(),
+        }
+    }
+
+    /// Records `bytes` of transit traffic.
+    ///
+    /// Returns false, without recording it, if it would exceed the limit.
+    pub(super) fn consume(&self, bytes: u64) -> bool {
+        self.bucket.consume(bytes as usize).is_ok()
+    }
+
+    /// Returns true if less than a tenth of a second's worth of bandwidth is
+    /// available, in which case we shouldn't take on any more tunnels.
+    pub(super) fn is_saturated(&self) -> bool {
+        self.bucket
+            .available()
+            .map(|tokens| tokens.saturating_mul(10) < self.rate)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParticipatingBandwidth;
+    use crate::router::config::{self, Config};
+    use crate::util::TokenBucket;
+
+    #[test]
+    fn unlimited() {
+        let bandwidth = ParticipatingBandwidth::default();
+        assert!(bandwidth.consume(u64::max_value()));
+        assert!(!bandwidth.is_saturated());
+    }
+
+    #[test]
+    fn saturate() {
+        let bandwidth = ParticipatingBandwidth::new(100_000);
+        assert!(!bandwidth.is_saturated());
+
+        // Shared between clones
+        let other = bandwidth.clone();
+        assert!(bandwidth.consume(50_000));
+        assert!(other.consume(45_000));
+        assert!(bandwidth.is_saturated());
+        assert!(other.is_saturated());
+
+        // More than is left
+        assert!(!bandwidth.consume(20_000));
+
+        // Low rates can be saturated too
+        let bandwidth = ParticipatingBandwidth::new(5);
+        assert!(!bandwidth.is_saturated());
+        assert!(bandwidth.consume(5));
+        assert!(bandwidth.is_saturated());
+    }
+
+    #[test]
+    fn share_of_transport_bandwidth() {
+        let mut cfg = Config::default();
+        assert_eq!(ParticipatingBandwidth::from_config(&cfg).rate, 0);
+
+        cfg.set(config::TRANSPORT_INBOUND_BANDWIDTH, 200).unwrap();
+        cfg.set(config::TRANSPORT_OUTBOUND_BANDWIDTH, 100).unwrap();
+        assert_eq!(ParticipatingBandwidth::from_config(&cfg).rate, 80 * 1024);
+        cfg.set(config::TUNNEL_PARTICIPATING_SHARE, 60).unwrap();
+        let bandwidth = ParticipatingBandwidth::from_config(&cfg);
+        assert_eq!(bandwidth.rate, 60 * 1024);
+
+        // Relay transit traffic, which the transports also send, until its
+        // share is used up
+        let outbound = TokenBucket::new(100 * 1024, 100 * 1024);
+        while bandwidth.consume(1028) {
+            assert!(outbound.consume(1028).is_ok());
+        }
+        assert!(bandwidth.is_saturated());
+
+        // Our own traffic still flows
+        assert!(outbound.consume(40 * 1024).is_ok());
+    }
+}
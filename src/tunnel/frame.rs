@@ -183,9 +183,9 @@ fn gen_tmdi<'a>(
 
 // TunnelMessage
 
-named!(
-    tunnel_message<TunnelMessage>,
+pub(super) fn tunnel_message(input: &[u8]) -> IResult<&[u8], TunnelMessage> {
     do_parse!(
+        input,
         iv: take!(16)
             >> checksum: be_u32
             >> padding: take_until_and_consume!(&b"\x00"[..])
@@ -194,7 +194,7 @@ named!(
             >> msg: many0!(complete!(pair!(tmdi, length_bytes!(be_u16))))
             >> (TunnelMessage(msg))
     )
-);
+}
 
 fn gen_tmdi_fragment_pair<'a>(
     input: (&'a mut [u8], usize),
@@ -206,7 +206,7 @@ fn gen_tmdi_fragment_pair<'a>(
     )
 }
 
-fn gen_tunnel_message<'a>(
+pub(super) fn gen_tunnel_message<'a>(
     input: (&'a mut [u8], usize),
     iv: &[u8],
     tm: &TunnelMessage,
@@ -0,0 +1,204 @@
+//! Sending messages into the router's own outbound tunnels.
+//!
+//! We are the gateway of our outbound tunnels, so instead of sending a
+//! TunnelGateway message to a peer, we fragment the message into tunnel
+//! messages ourselves, and remove every hop's layer of encryption in advance.
+//! Each hop then adds its layer back, so that the outbound endpoint reads the
+//! delivery instructions in the clear.
+
+use rand::{rngs::OsRng, Rng};
+use std::cmp;
+
+use super::{
+    encryption::LayerCipher, frame, FirstFragmentDeliveryInstructions,
+    FollowOnFragmentDeliveryInstructions, TunnelMessage, TunnelMessageDeliveryInstructions,
+    TunnelMessageDeliveryType,
+};
+use crate::data::TunnelId;
+use crate::i2np::{frame::gen_message, Message, MessagePayload, TunnelData};
+use crate::util::serialize;
+
+/// The space in a tunnel message for delivery instructions and fragments,
+/// after the checksum and the zero byte that ends the padding.
+const TUNNEL_MESSAGE_CAPACITY: usize = 1008 - 4 - 1;
+
+/// Follow-on fragment numbers are six bits, and start at 1.
+const MAX_FOLLOW_ON_FRAGMENTS: usize = 63;
+
+/// Splits a message into fragments that each fill at most one tunnel message.
+///
+/// Returns None if the message is too large to be fragmented.
+fn fragment(
+    delivery_type: TunnelMessageDeliveryType,
+    data: &[u8],
+) -> Option<Vec<(TunnelMessageDeliveryInstructions, &[u8])>> {
+    let unfragmented =
+        TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+            delivery_type: delivery_type.clone(),
+            msg_id: None,
+        });
+    if unfragmented.byte_len() + 2 + data.len() <= TUNNEL_MESSAGE_CAPACITY {
+        return Some(vec![(unfragmented, data)]);
+    }
+
+    let msg_id = OsRng.gen();
+    let first = TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+        delivery_type,
+        msg_id: Some(msg_id),
+    });
+    let (first_data, mut rest) = data.split_at(TUNNEL_MESSAGE_CAPACITY - first.byte_len() - 2);
+    let mut fragments = vec![(first, first_data)];
+
+    // Follow-on delivery instructions are a flag byte and the message ID
+    let follow_on_len = TUNNEL_MESSAGE_CAPACITY - 5 - 2;
+    if rest.len() > MAX_FOLLOW_ON_FRAGMENTS * follow_on_len {
+        return None;
+    }
+    let mut fragment_number = 1;
+    while !rest.is_empty() {
+        let (frag, remaining) = rest.split_at(cmp::min(follow_on_len, rest.len()));
+        rest = remaining;
+        fragments.push((
+            TunnelMessageDeliveryInstructions::FollowOn(FollowOnFragmentDeliveryInstructions {
+                fragment_number,
+                last_fragment: rest.is_empty(),
+                msg_id,
+            }),
+            frag,
+        ));
+        fragment_number += 1;
+    }
+    Some(fragments)
+}
+
+/// Creates the TunnelData messages that send `msg` through an outbound tunnel,
+/// for the outbound endpoint to deliver as instructed. `tid` is the tunnel ID
+/// at the first hop, and `layers` are the ciphers of the hops, starting with
+/// the first hop.
+///
+/// Returns None if the message is too large to be sent through a tunnel.
+pub(super) fn outbound_messages(
+    tid: TunnelId,
+    layers: &[LayerCipher],
+    delivery_type: TunnelMessageDeliveryType,
+    msg: &Message,
+) -> Option<Vec<Message>> {
+    let data = serialize(|input| gen_message(input, msg));
+    let fragments = fragment(delivery_type, &data)?;
+
+    let mut rng = OsRng;
+    Some(
+        fragments
+            .into_iter()
+            .map(|fragment| {
+                let mut iv = [0; 16];
+                rng.fill(&mut iv);
+
+                let mut td = TunnelData {
+                    tid,
+                    data: [0; 1024],
+                };
+                let tm = TunnelMessage(vec![fragment]);
+                frame::gen_tunnel_message((&mut td.data[..], 0), &iv[..], &tm)
+                    .expect("Fragment fits in a tunnel message");
+
+                // Each hop encrypts, so we decrypt in reverse order
+                for layer in layers.iter().rev() {
+                    layer.decrypt_layer(&mut td);
+                }
+                Message::from_payload(MessagePayload::TunnelData(td))
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::outbound_messages;
+    use crate::crypto::SessionKey;
+    use crate::data::{Hash, TunnelId};
+    use crate::i2np::{frame::gen_message, Message, MessagePayload};
+    use crate::tunnel::{
+        encryption::LayerCipher, frame::tunnel_message, FirstFragmentDeliveryInstructions,
+        FollowOnFragmentDeliveryInstructions, TunnelMessageDeliveryInstructions,
+        TunnelMessageDeliveryType,
+    };
+    use crate::util::serialize;
+
+    /// Passes the messages through the hops, returning what the outbound
+    /// endpoint receives.
+    fn through_hops(
+        msgs: Vec<Message>,
+        layers: &[LayerCipher],
+    ) -> Vec<(TunnelMessageDeliveryInstructions, Vec<u8>)> {
+        let mut received = vec![];
+        for msg in msgs {
+            let mut td = match msg.payload {
+                MessagePayload::TunnelData(td) => td,
+                ref payload => panic!("Unexpected payload: {:?}", payload),
+            };
+            assert_eq!(td.tid, TunnelId(42));
+            for layer in layers {
+                layer.encrypt_layer(&mut td);
+            }
+            let (_, tm) = tunnel_message(&td.data).expect("Hops should remove every layer");
+            received.extend(tm.0.into_iter().map(|(di, frag)| (di, frag.to_vec())));
+        }
+        received
+    }
+
+    #[test]
+    fn outbound_round_trip() {
+        let layers: Vec<_> = (0..3)
+            .map(|i| LayerCipher::new(&SessionKey([i; 32]), SessionKey([i + 10; 32])))
+            .collect();
+        let to = Hash([7; 32]);
+
+        // Small messages fit in a single tunnel message
+        let msg = Message::dummy_data();
+        let data = serialize(|input| gen_message(input, &msg));
+        let delivery_type = TunnelMessageDeliveryType::Router(to.clone());
+        let msgs = outbound_messages(TunnelId(42), &layers, delivery_type.clone(), &msg).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(
+            through_hops(msgs, &layers),
+            vec![(
+                TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                    delivery_type: delivery_type.clone(),
+                    msg_id: None,
+                }),
+                data
+            )]
+        );
+
+        // Larger messages are fragmented
+        let msg = Message::from_payload(MessagePayload::Data(vec![3; 2500]));
+        let data = serialize(|input| gen_message(input, &msg));
+        let msgs = outbound_messages(TunnelId(42), &layers, delivery_type.clone(), &msg).unwrap();
+        assert_eq!(msgs.len(), 3);
+        let received = through_hops(msgs, &layers);
+        let msg_id = match received[0].0 {
+            TunnelMessageDeliveryInstructions::First(ref di) => {
+                assert_eq!(di.delivery_type, delivery_type);
+                di.msg_id.unwrap()
+            }
+            ref di => panic!("Unexpected delivery instructions: {:?}", di),
+        };
+        for (i, (di, _)) in received.iter().enumerate().skip(1) {
+            assert_eq!(
+                di,
+                &TunnelMessageDeliveryInstructions::FollowOn(FollowOnFragmentDeliveryInstructions {
+                    fragment_number: i as u8,
+                    last_fragment: i == 2,
+                    msg_id,
+                })
+            );
+        }
+        let reassembled: Vec<u8> = received.into_iter().flat_map(|(_, frag)| frag).collect();
+        assert_eq!(reassembled, data);
+
+        // Messages that need too many fragments can't be sent
+        let msg = Message::from_payload(MessagePayload::Data(vec![3; 65_000]));
+        assert!(outbound_messages(TunnelId(42), &layers, delivery_type, &msg).is_none());
+    }
+}
@@ -0,0 +1,388 @@
+//! Management of the router's own outbound tunnels.
+
+use futures::{future, Future, Stream};
+use rand::{thread_rng, Rng};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::timer::Interval;
+
+use super::{encryption::LayerCipher, gateway, TunnelMessageDeliveryType, TUNNEL_LIFETIME};
+use crate::data::{Hash, I2PDate, RouterInfo, TunnelId};
+use crate::i2np::{DeliveryStatus, Message, MessagePayload};
+use crate::router::{types::OutboundTunnelPool, Context, DeliveryStatuses};
+
+/// How long we wait for a tunnel test to come back before marking the tunnel
+/// as failed.
+const TUNNEL_TEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How often the tunnels in a pool are tested.
+const TUNNEL_TEST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A test message that has been sent through a tunnel.
+struct PendingTest {
+    msg_id: u32,
+    deadline: Instant,
+}
+
+struct OutboundTunnel {
+    /// The first hop, and the ID of the tunnel there.
+    first_hop: RouterInfo,
+    tid: TunnelId,
+    /// The layer ciphers of the hops, starting with the first hop.
+    layers: Vec<LayerCipher>,
+    expires: SystemTime,
+    test: Option<PendingTest>,
+}
+
+struct Inner {
+    tunnels: Vec<OutboundTunnel>,
+    shut_down: bool,
+}
+
+/// A pool of outbound tunnels that is kept at a fixed size.
+///
+/// Once the pool has been shut down, its tunnels are released and it never
+/// asks for more to be built.
+pub struct TunnelPool {
+    size: usize,
+    inner: Mutex<Inner>,
+}
+
+impl TunnelPool {
+    /// Creates an empty pool that wants `size` live tunnels.
+    pub fn new(size: usize) -> Self {
+        TunnelPool {
+            size,
+            inner: Mutex::new(Inner {
+                tunnels: vec![],
+                shut_down: false,
+            }),
+        }
+    }
+
+    /// Adds a newly-built tunnel to the pool, given its first hop, the ID of
+    /// the tunnel at the first hop, and the layer ciphers of its hops.
+    ///
+    /// Returns false, dropping the tunnel, if the pool has been shut down.
+    pub fn add(&self, first_hop: RouterInfo, tid: TunnelId, layers: Vec<LayerCipher>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.shut_down {
+            return false;
+        }
+        inner.tunnels.push(OutboundTunnel {
+            first_hop,
+            tid,
+            layers,
+            expires: SystemTime::now() + Duration::from_secs(TUNNEL_LIFETIME),
+            test: None,
+        });
+        true
+    }
+
+    /// Tests the pool's tunnels every [`TUNNEL_TEST_INTERVAL`], until the pool
+    /// is shut down.
+    pub fn run_tests(self: Arc<Self>, ctx: Arc<Context>) -> impl Future<Item = (), Error = ()> {
+        let pool = self.clone();
+        Interval::new(Instant::now() + TUNNEL_TEST_INTERVAL, TUNNEL_TEST_INTERVAL)
+            .map_err(|e| error!("Tunnel test timer failed: {}", e))
+            .take_while(move |_| Ok(!pool.inner.lock().unwrap().shut_down))
+            .for_each(move |_| self.test_tunnels(&ctx))
+    }
+
+    /// Checks the results of earlier tunnel tests, and starts a new test for
+    /// each tunnel that isn't being tested.
+    ///
+    /// A test is a DeliveryStatus message sent through the tunnel, which the
+    /// outbound endpoint sends back to us directly. Tunnels whose
+    /// DeliveryStatus has not come back within [`TUNNEL_TEST_TIMEOUT`] are
+    /// marked as failed and dropped, so that they will be rebuilt.
+    pub fn test_tunnels(&self, ctx: &Context) -> impl Future<Item = (), Error = ()> {
+        let own_hash = ctx.keys.rid.hash();
+        let comms = ctx.comms.read().unwrap();
+        let sends: Vec<_> = self
+            .start_tests(&own_hash, &ctx.delivery_statuses, Instant::now())
+            .into_iter()
+            .flat_map(|(first_hop, msgs)| msgs.into_iter().map(move |msg| (first_hop.clone(), msg)))
+            .filter_map(|(first_hop, msg)| match comms.send(first_hop, msg) {
+                Ok(f) => Some(f.then(|res| {
+                    if let Err(e) = res {
+                        debug!("Failed to send tunnel test: {}", e);
+                    }
+                    Ok(())
+                })),
+                Err((first_hop, _)) => {
+                    debug!("Can't send tunnel test to {}", first_hop.router_id.hash());
+                    None
+                }
+            })
+            .collect();
+        future::join_all(sends).map(|_| ())
+    }
+
+    /// Drops tunnels whose tests have failed as of `now`, and returns the test
+    /// messages to send to the first hops of the tunnels that aren't waiting
+    /// for a test to come back. The tests are delivered to `own_hash`.
+    fn start_tests(
+        &self,
+        own_hash: &Hash,
+        delivery_statuses: &DeliveryStatuses,
+        now: Instant,
+    ) -> Vec<(RouterInfo, Vec<Message>)> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.shut_down {
+            return vec![];
+        }
+
+        let mut rng = thread_rng();
+        let mut tests = vec![];
+        for mut t in mem::replace(&mut inner.tunnels, vec![]) {
+            if let Some(test) = t.test.take() {
+                if delivery_statuses.take(test.msg_id).is_none() {
+                    if now < test.deadline {
+                        // Still waiting for it
+                        t.test = Some(test);
+                        inner.tunnels.push(t);
+                    } else {
+                        delivery_statuses.forget(test.msg_id);
+                        warn!(
+                            "Outbound tunnel {} at {} failed its test",
+                            t.tid,
+                            t.first_hop.router_id.hash()
+                        );
+                    }
+                    continue;
+                }
+            }
+
+            let msg_id = rng.gen();
+            delivery_statuses.expect(msg_id);
+            let status = Message::from_payload(MessagePayload::DeliveryStatus(DeliveryStatus {
+                msg_id,
+                time_stamp: I2PDate::now(),
+            }));
+            let delivery_type = TunnelMessageDeliveryType::Router(own_hash.clone());
+            let msgs = gateway::outbound_messages(t.tid, &t.layers, delivery_type, &status)
+                .expect("A DeliveryStatus fits in a tunnel message");
+            tests.push((t.first_hop.clone(), msgs));
+            t.test = Some(PendingTest {
+                msg_id,
+                deadline: now + TUNNEL_TEST_TIMEOUT,
+            });
+            inner.tunnels.push(t);
+        }
+        tests
+    }
+
+    /// Drops expired tunnels, and returns the number of tunnels that need to be
+    /// built to bring the pool back up to size.
+    pub fn tunnels_to_build(&self) -> usize {
+        let now = SystemTime::now();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.shut_down {
+            return 0;
+        }
+        inner.tunnels.retain(|t| t.expires > now);
+        self.size.saturating_sub(inner.tunnels.len())
+    }
+}
+
+impl OutboundTunnelPool for TunnelPool {
+    fn wrap_message(&self, to: &Hash, msg: &Message) -> Option<(RouterInfo, Vec<Message>)> {
+        let now = SystemTime::now();
+        let inner = self.inner.lock().unwrap();
+        let live: Vec<_> = inner.tunnels.iter().filter(|t| t.expires > now).collect();
+        if live.is_empty() {
+            return None;
+        }
+        let t = live[thread_rng().gen_range(0, live.len())];
+        let delivery_type = TunnelMessageDeliveryType::Router(to.clone());
+        match gateway::outbound_messages(t.tid, &t.layers, delivery_type, msg) {
+            Some(msgs) => Some((t.first_hop.clone(), msgs)),
+            None => {
+                warn!("Message {} is too large to send through a tunnel", msg.id);
+                None
+            }
+        }
+    }
+
+    fn live_tunnels(&self) -> usize {
+        let now = SystemTime::now();
+        let inner = self.inner.lock().unwrap();
+        inner.tunnels.iter().filter(|t| t.expires > now).count()
+    }
+
+    /// Releases every tunnel in the pool, and stops rebuilding them.
+    ///
+    /// I2P has no message for tearing down a tunnel, so the hops are not
+    /// notified; they will drop the tunnel when it expires.
+    fn shutdown(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.shut_down {
+            info!("Releasing {} outbound tunnels", inner.tunnels.len());
+            inner.shut_down = true;
+            inner.tunnels.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use std::time::Instant;
+
+    use super::{TunnelPool, TUNNEL_TEST_TIMEOUT};
+    use crate::crypto::SessionKey;
+    use crate::data::{Hash, I2PDate, RouterInfo, RouterSecretKeys, TunnelId};
+    use crate::i2np::{frame::message, Message, MessagePayload, TunnelData};
+    use crate::router::{mock::MockContextBuilder, types::OutboundTunnelPool};
+    use crate::tunnel::{
+        encryption::LayerCipher, frame::tunnel_message, FirstFragmentDeliveryInstructions,
+        TunnelMessageDeliveryInstructions, TunnelMessageDeliveryType,
+    };
+
+    /// Returns the tunnel that the pool would send a message through.
+    fn selected_tunnel(pool: &TunnelPool) -> Option<TunnelId> {
+        let (_, msgs) = pool.wrap_message(&Hash([0; 32]), &Message::dummy_data())?;
+        match msgs[0].payload {
+            MessagePayload::TunnelData(ref td) => Some(td.tid),
+            ref payload => panic!("Unexpected payload: {:?}", payload),
+        }
+    }
+
+    /// Passes a tunnel message through the hops, returning the message that
+    /// the outbound endpoint receives and the router it is delivered to.
+    fn through_hops(msg: &Message, layers: &[LayerCipher]) -> (Hash, Message) {
+        let mut td = match msg.payload {
+            MessagePayload::TunnelData(ref td) => TunnelData {
+                tid: td.tid,
+                data: td.data,
+            },
+            ref payload => panic!("Unexpected payload: {:?}", payload),
+        };
+        for layer in layers {
+            layer.encrypt_layer(&mut td);
+        }
+        let (_, mut tm) = tunnel_message(&td.data).unwrap();
+        assert_eq!(tm.0.len(), 1);
+        let (di, frag) = tm.0.remove(0);
+        let to = match di {
+            TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                delivery_type: TunnelMessageDeliveryType::Router(to),
+                msg_id: None,
+            }) => to,
+            di => panic!("Unexpected delivery instructions: {:?}", di),
+        };
+        (to, message(frag).unwrap().1)
+    }
+
+    #[test]
+    fn shutdown() {
+        let pool = TunnelPool::new(3);
+        assert_eq!(pool.tunnels_to_build(), 3);
+
+        let first_hop = RouterInfo::new(RouterSecretKeys::new().rid);
+        assert!(pool.add(first_hop.clone(), TunnelId(1), vec![]));
+        assert!(pool.add(first_hop.clone(), TunnelId(2), vec![]));
+        assert_eq!(pool.live_tunnels(), 2);
+        assert_eq!(pool.tunnels_to_build(), 1);
+        assert!(selected_tunnel(&pool).is_some());
+
+        pool.shutdown();
+        assert_eq!(pool.live_tunnels(), 0);
+        assert!(selected_tunnel(&pool).is_none());
+
+        // No more tunnels are built or accepted
+        assert_eq!(pool.tunnels_to_build(), 0);
+        assert!(!pool.add(first_hop, TunnelId(3), vec![]));
+        assert_eq!(pool.live_tunnels(), 0);
+    }
+
+    #[test]
+    fn successful_test() {
+        let (ctx, sent) = MockContextBuilder::new().build();
+        let own_hash = ctx.keys.rid.hash();
+        let layers: Vec<_> = (0..3)
+            .map(|i| LayerCipher::new(&SessionKey([i; 32]), SessionKey([i + 10; 32])))
+            .collect();
+        let pool = TunnelPool::new(1);
+        let first_hop = RouterInfo::new(RouterSecretKeys::new().rid);
+        assert!(pool.add(first_hop.clone(), TunnelId(1), layers.clone()));
+
+        // The test is sent to the first hop, and the outbound endpoint sends it
+        // back to us
+        pool.test_tunnels(&ctx).wait().unwrap();
+        let (to, test) = {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].0, first_hop.router_id.hash());
+            through_hops(&sent[0].1, &layers)
+        };
+        assert_eq!(to, own_hash);
+        match test.payload {
+            MessagePayload::DeliveryStatus(ds) => {
+                assert!(ctx.delivery_statuses.record(ds.msg_id, ds.time_stamp))
+            }
+            ref payload => panic!("Unexpected payload: {:?}", payload),
+        }
+
+        // The tunnel passed, so it is kept and tested again
+        let tests = pool.start_tests(
+            &own_hash,
+            &ctx.delivery_statuses,
+            Instant::now() + TUNNEL_TEST_TIMEOUT,
+        );
+        assert_eq!(tests.len(), 1);
+        assert_eq!(pool.live_tunnels(), 1);
+        assert_eq!(pool.tunnels_to_build(), 0);
+    }
+
+    #[test]
+    fn failed_test() {
+        let (ctx, sent) = MockContextBuilder::new().build();
+        let own_hash = ctx.keys.rid.hash();
+        let pool = TunnelPool::new(2);
+        let first_hop = RouterInfo::new(RouterSecretKeys::new().rid);
+        assert!(pool.add(first_hop.clone(), TunnelId(1), vec![]));
+        assert!(pool.add(first_hop.clone(), TunnelId(2), vec![]));
+        assert_eq!(pool.tunnels_to_build(), 0);
+
+        // A test is sent to the first hop of each tunnel
+        let now = Instant::now();
+        let tests = pool.start_tests(&own_hash, &ctx.delivery_statuses, now);
+        assert_eq!(tests.len(), 2);
+        for (i, (to, msgs)) in tests.iter().enumerate() {
+            assert_eq!(to.router_id.hash(), first_hop.router_id.hash());
+            assert_eq!(msgs.len(), 1);
+            match msgs[0].payload {
+                MessagePayload::TunnelData(ref td) => assert_eq!(td.tid, TunnelId(i as u32 + 1)),
+                ref payload => panic!("Unexpected payload: {:?}", payload),
+            }
+        }
+
+        // Nothing is sent while the tests are pending
+        assert!(pool.start_tests(&own_hash, &ctx.delivery_statuses, now).is_empty());
+
+        // Only the first tunnel's DeliveryStatus comes back
+        let msg_id = pool.inner.lock().unwrap().tunnels[0]
+            .test
+            .as_ref()
+            .unwrap()
+            .msg_id;
+        assert!(ctx.delivery_statuses.record(msg_id, I2PDate::now()));
+
+        // The second tunnel's test times out, so it is dropped and rebuilt,
+        // while the first tunnel is tested again
+        let tests = pool.start_tests(&own_hash, &ctx.delivery_statuses, now + TUNNEL_TEST_TIMEOUT);
+        assert_eq!(tests.len(), 1);
+        assert_eq!(pool.live_tunnels(), 1);
+        assert_eq!(selected_tunnel(&pool), Some(TunnelId(1)));
+        assert_eq!(pool.tunnels_to_build(), 1);
+        assert!(pool.add(first_hop, TunnelId(3), vec![]));
+        assert_eq!(pool.tunnels_to_build(), 0);
+
+        // Only the new tunnel needs testing
+        pool.test_tunnels(&ctx).wait().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+}
@@ -7,7 +7,9 @@ use std::time::{Duration, Instant, SystemTime};
 use tokio::{io, spawn, timer::Delay};
 use tokio_threadpool::blocking;
 
-use super::{encryption::LayerCipher, HopConfig, HopData, TUNNEL_LIFETIME};
+use super::{
+    bandwidth::ParticipatingBandwidth, encryption::LayerCipher, HopConfig, HopData, TUNNEL_LIFETIME,
+};
 use crate::data::{Hash, RouterInfo, TunnelId};
 use crate::i2np::{Message, MessagePayload, TunnelData};
 use crate::router::types::CommSystem;
@@ -18,6 +20,9 @@ type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 /// Interval on which we expire tunnels we are participating in.
 const EXPIRE_TUNNELS_INTERVAL: u64 = 10;
 
+/// Size of a TunnelData message: the tunnel ID and the encrypted data.
+const TUNNEL_DATA_LEN: u64 = 4 + 1024;
+
 macro_rules! try_poll {
     ($f:expr, $parent:expr, $state:expr) => {
         match $f {
@@ -122,6 +127,7 @@ pub struct Participant {
     decay_filter_timer: Delay,
     ib_rx: mpsc::Receiver<(Hash, Message)>,
     comms: Arc<RwLock<dyn CommSystem>>,
+    bandwidth: ParticipatingBandwidth,
 }
 
 impl Participant {
@@ -129,6 +135,7 @@ impl Participant {
         new_participating_rx: mpsc::Receiver<(TunnelId, HopConfig)>,
         ib_rx: mpsc::Receiver<(Hash, Message)>,
         comms: Arc<RwLock<dyn CommSystem>>,
+        bandwidth: ParticipatingBandwidth,
     ) -> Self {
         Participant {
             new_participating_rx,
@@ -140,6 +147,7 @@ impl Participant {
             decay_filter_timer: Delay::new(Instant::now() + Duration::from_secs(TUNNEL_LIFETIME)),
             ib_rx,
             comms,
+            bandwidth,
         }
     }
 }
@@ -207,6 +215,11 @@ impl Future for Participant {
                                 continue;
                             }
 
+                            if !self.bandwidth.consume(TUNNEL_DATA_LEN) {
+                                debug!("Dropping TunnelData message: over bandwidth limit");
+                                continue;
+                            }
+
                             // Okay, we want to process this message
                             match &config.hop_data {
                                 HopData::InboundGateway(_) => unimplemented!(),
@@ -3,14 +3,26 @@
 use bloom_filter_rs::{BloomFilter, Murmur3};
 use cookie_factory::GenError;
 use core::fmt;
-use std::iter::repeat;
+use std::cmp;
 use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The default bound on how far in the future a peer's timestamp may be.
+pub const DEFAULT_MAX_FUTURE_SKEW: Duration = Duration::from_secs(2 * 60);
+
+/// The shortest time that `TokenBucket::take` asks callers to wait for.
+const MIN_BANDWIDTH_WAIT: Duration = Duration::from_millis(5);
+
+/// Initial buffer size for `serialize`, large enough for most structures
+/// (including typical RouterInfos) to be generated in a single pass.
+const SERIALIZE_INITIAL_LEN: usize = 1024;
 
 pub fn serialize<S>(serializer: S) -> Vec<u8>
 where
     S: Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError>,
 {
-    let mut buf = vec![];
+    let mut buf = vec![0; SERIALIZE_INITIAL_LEN];
     loop {
         match serializer((&mut buf, 0)).map(|tup| tup.1) {
             Ok(sz) => {
@@ -19,8 +31,11 @@ where
             }
             Err(e) => match e {
                 GenError::BufferTooSmall(sz) => {
-                    let cur_len = buf.len();
-                    buf.extend(repeat(0).take(sz - cur_len));
+                    // The generator only reports how much space it needed to
+                    // write the field it failed on, so at least double the
+                    // buffer to avoid restarting once per remaining field.
+                    let new_len = cmp::max(sz, 2 * buf.len());
+                    buf.resize(new_len, 0);
                 }
                 _ => panic!("Couldn't serialize"),
             },
@@ -50,6 +65,26 @@ where
     Ok(())
 }
 
+/// Checks that `time` is no more than `max_skew` in the future.
+///
+/// Returns how far in the future `time` is if it exceeds the bound.
+pub fn check_future_skew(time: SystemTime, max_skew: Duration) -> Result<(), Duration> {
+    match time.duration_since(SystemTime::now()) {
+        Ok(ahead) if ahead > max_skew => Err(ahead),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `time` is no more than `max_skew` in the past.
+///
+/// Returns how far in the past `time` is if it exceeds the bound.
+pub fn check_past_skew(time: SystemTime, max_skew: Duration) -> Result<(), Duration> {
+    match SystemTime::now().duration_since(time) {
+        Ok(behind) if behind > max_skew => Err(behind),
+        _ => Ok(()),
+    }
+}
+
 /// A two-layer Bloom filter that can be decayed, meaning that it can be used continuously on
 /// real-time data while maintaining a reasonable false positive rate for a fixed filter size.
 ///
@@ -90,9 +125,139 @@ impl DecayingBloomFilter {
     }
 }
 
+struct Bucket {
+    /// Bytes per second.
+    rate: u64,
+    /// The most bytes that the bucket can hold.
+    burst: u64,
+    tokens: u64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let added = self.last_refill.elapsed().as_millis() as u64 * self.rate / 1000;
+        if added > 0 {
+            self.tokens = cmp::min(self.tokens + added, self.burst);
+            self.last_refill = Instant::now();
+        }
+    }
+
+    /// How long to wait until `bytes` are available, capped at a full bucket.
+    fn wait_for(&self, bytes: u64) -> Duration {
+        let needed = cmp::min(bytes, self.burst).saturating_sub(self.tokens);
+        let wait = Duration::from_millis(needed * 1000 / cmp::max(self.rate, 1));
+        cmp::max(wait, MIN_BANDWIDTH_WAIT)
+    }
+}
+
+/// A token bucket for limiting bandwidth, which refills at a fixed rate up to
+/// a maximum burst. Clones share the same bucket. The default bucket is
+/// unlimited.
+#[derive(Clone, Default)]
+pub(crate) struct TokenBucket(Option<Arc<Mutex<Bucket>>>);
+
+impl TokenBucket {
+    /// Allows `rate` bytes per second, in bursts of up to `burst` bytes. The
+    /// bucket starts full.
+    pub(crate) fn new(rate: u64, burst: u64) -> Self {
+        TokenBucket(Some(Arc::new(Mutex::new(Bucket {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }))))
+    }
+
+    /// Takes up to `max` bytes.
+    ///
+    /// Returns the number of bytes taken, or if none are available, how long
+    /// to wait before trying again.
+    pub(crate) fn take(&self, max: usize) -> Result<usize, Duration> {
+        let mut bucket = match self.0 {
+            Some(ref bucket) => bucket.lock().unwrap(),
+            None => return Ok(max),
+        };
+        bucket.refill();
+        if bucket.tokens == 0 && max > 0 {
+            return Err(bucket.wait_for(max as u64));
+        }
+        let taken = cmp::min(max as u64, bucket.tokens);
+        bucket.tokens -= taken;
+        Ok(taken as usize)
+    }
+
+    /// Takes all `n` bytes at once, for traffic that can't be split up.
+    ///
+    /// If they aren't available, returns how long to wait before trying
+    /// again, without taking any. More than the burst is allowed from a full
+    /// bucket, so that large messages aren't blocked forever.
+    pub(crate) fn consume(&self, n: usize) -> Result<(), Duration> {
+        let mut bucket = match self.0 {
+            Some(ref bucket) => bucket.lock().unwrap(),
+            None => return Ok(()),
+        };
+        bucket.refill();
+        if bucket.tokens < cmp::min(n as u64, bucket.burst) {
+            return Err(bucket.wait_for(n as u64));
+        }
+        bucket.tokens = bucket.tokens.saturating_sub(n as u64);
+        Ok(())
+    }
+
+    /// Returns bytes that were taken but not used.
+    pub(crate) fn give_back(&self, n: usize) {
+        if let Some(ref bucket) = self.0 {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.tokens = cmp::min(bucket.tokens + n as u64, bucket.burst);
+        }
+    }
+
+    /// Returns the number of bytes currently available, or None if the
+    /// bucket is unlimited.
+    pub(crate) fn available(&self) -> Option<u64> {
+        self.0.as_ref().map(|bucket| {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.refill();
+            bucket.tokens
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::DecayingBloomFilter;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use super::{check_future_skew, check_past_skew, DecayingBloomFilter, TokenBucket};
+
+    #[test]
+    fn future_skew() {
+        let max_skew = Duration::from_secs(60);
+        let now = SystemTime::now();
+
+        assert!(check_future_skew(now - Duration::from_secs(3600), max_skew).is_ok());
+        assert!(check_future_skew(now, max_skew).is_ok());
+        assert!(check_future_skew(now + Duration::from_secs(30), max_skew).is_ok());
+        match check_future_skew(now + Duration::from_secs(120), max_skew) {
+            Ok(()) => panic!("Timestamp should be too far in the future"),
+            Err(ahead) => assert!(ahead > max_skew),
+        }
+    }
+
+    #[test]
+    fn past_skew() {
+        let max_skew = Duration::from_secs(60);
+        let now = SystemTime::now();
+
+        assert!(check_past_skew(now + Duration::from_secs(3600), max_skew).is_ok());
+        assert!(check_past_skew(now, max_skew).is_ok());
+        assert!(check_past_skew(now - Duration::from_secs(30), max_skew).is_ok());
+        match check_past_skew(now - Duration::from_secs(120), max_skew) {
+            Ok(()) => panic!("Timestamp should be too far in the past"),
+            Err(behind) => assert!(behind > max_skew),
+        }
+    }
 
     #[test]
     fn decaying_bloom_filter() {
@@ -140,4 +305,51 @@ mod tests {
         assert!(filter.feed(&second[..]));
         assert!(!filter.feed(&third[..]));
     }
+    #[test]
+    fn unlimited_bucket() {
+        let bucket = TokenBucket::default();
+        assert_eq!(bucket.take(usize::max_value()), Ok(usize::max_value()));
+        assert_eq!(bucket.consume(usize::max_value()), Ok(()));
+        assert_eq!(bucket.available(), None);
+    }
+
+    #[test]
+    fn bucket_burst_and_refill() {
+        let bucket = TokenBucket::new(100, 2_000);
+
+        // The burst is available straight away, and shared between clones
+        let other = bucket.clone();
+        assert_eq!(bucket.take(1_500), Ok(1_500));
+        assert_eq!(other.take(1_500), Ok(500));
+        assert_eq!(bucket.take(1_000), Err(Duration::from_secs(10)));
+
+        // Unused bandwidth can be returned
+        bucket.give_back(300);
+        assert_eq!(bucket.available(), Some(300));
+        assert_eq!(bucket.take(1_000), Ok(300));
+
+        // The bucket refills at the rate, up to the burst
+        let bucket = TokenBucket::new(10_000, 2_000);
+        assert_eq!(bucket.take(5_000), Ok(2_000));
+        thread::sleep(Duration::from_millis(50));
+        let taken = bucket.take(1_000).unwrap();
+        assert!((500..=1_000).contains(&taken));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(bucket.take(5_000), Ok(2_000));
+    }
+
+    #[test]
+    fn bucket_consume() {
+        let bucket = TokenBucket::new(100, 2_000);
+
+        // Nothing is taken unless all of it is available
+        assert_eq!(bucket.consume(1_500), Ok(()));
+        assert_eq!(bucket.consume(1_000), Err(Duration::from_secs(5)));
+        assert_eq!(bucket.consume(500), Ok(()));
+
+        // A full bucket lets through more than the burst
+        bucket.give_back(2_000);
+        assert_eq!(bucket.consume(5_000), Ok(()));
+        assert_eq!(bucket.available(), Some(0));
+    }
 }